@@ -2,8 +2,9 @@
 
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::{Duration, Instant};
-use xpdk::{Config, Result, Xpdk};
+use std::time::Instant;
+use xpdk::bench::BenchHarness;
+use xpdk::{Config, Xpdk};
 
 /// Benchmark packet allocation latency
 fn bench_allocation_latency(c: &mut Criterion) {
@@ -32,9 +33,8 @@ fn bench_socket_creation_latency(c: &mut Criterion) {
         b.iter(|| {
             let config = Config::default();
             let mut xpdk = Xpdk::new(config).unwrap();
-            let udp_stack = unsafe { &mut *(xpdk.udp_stack() as *mut _) };
             let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-            let socket_id = udp_stack.create_socket(local_addr).unwrap();
+            let socket_id = xpdk.udp_stack_mut().create_socket(local_addr).unwrap();
             black_box(socket_id);
         });
     });
@@ -56,22 +56,11 @@ fn bench_packet_processing_latency(c: &mut Criterion) {
                     ..Default::default()
                 };
 
-                let mut xpdk = Xpdk::new(config).unwrap();
-                xpdk.start().unwrap();
-
-                let socket_id = {
-                    let udp_stack = unsafe { &mut *(xpdk.udp_stack() as *mut _) };
-                    let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-                    udp_stack.create_socket(local_addr).unwrap()
-                };
-
-                let test_data = vec![0u8; packet_size];
+                let mut harness = BenchHarness::new(&config, &[8080]).unwrap();
+                let packets = harness.packet_set(1, packet_size).unwrap();
 
                 b.iter(|| {
-                    // Allocate mbuf
-                    let mbuf = xpdk.memory_manager().alloc_mbuf().unwrap();
-                    black_box(mbuf);
-                    xpdk.memory_manager().free_mbuf(mbuf).unwrap();
+                    black_box(harness.run_rx(&packets));
                 });
             },
         );
@@ -140,7 +129,7 @@ fn bench_queue_latency(c: &mut Criterion) {
             |b, &queue_size| {
                 use lockfree_ringbuf::SpscRingBuffer;
 
-                let queue = SpscRingBuffer::new(queue_size).unwrap();
+                let queue = SpscRingBuffer::new(queue_size);
                 let test_data = vec![0u8; 1024];
 
                 // Pre-populate queue
@@ -223,7 +212,7 @@ fn bench_cache_prefetch(c: &mut Criterion) {
     group.bench_function("prefetch_l1", |b| {
         b.iter(|| {
             for i in (0..data_size).step_by(64) {
-                CpuPrefetch::prefetch_l1(black_box(data.as_ptr().add(i)));
+                CpuPrefetch::prefetch_l1(black_box(data.as_ptr().wrapping_add(i)));
             }
         });
     });
@@ -231,7 +220,7 @@ fn bench_cache_prefetch(c: &mut Criterion) {
     group.bench_function("prefetch_l2", |b| {
         b.iter(|| {
             for i in (0..data_size).step_by(64) {
-                CpuPrefetch::prefetch_l2(black_box(data.as_ptr().add(i)));
+                CpuPrefetch::prefetch_l2(black_box(data.as_ptr().wrapping_add(i)));
             }
         });
     });
@@ -1,9 +1,10 @@
 //! Throughput benchmark for XPDK
 
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::Duration;
-use xpdk::{Config, Result, Xpdk};
+use std::sync::Arc;
+use std::thread;
+use xpdk::bench::BenchHarness;
+use xpdk::{Config, Mbuf, MbufPool, Xpdk};
 
 /// Benchmark packet allocation and deallocation
 fn bench_mbuf_allocation(c: &mut Criterion) {
@@ -37,6 +38,59 @@ fn bench_mbuf_allocation(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark pool contention with single and bulk alloc/free
+fn bench_mbuf_bulk_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mbuf_bulk_contention");
+    const BURST: usize = 32;
+
+    for threads in [1, 2, 4].iter() {
+        let pool = Arc::new(MbufPool::new("bench".to_string(), 4096, 2048).unwrap());
+
+        group.bench_with_input(
+            BenchmarkId::new("single", threads),
+            threads,
+            |b, &threads| {
+                b.iter(|| {
+                    contend(&pool, threads, |pool| {
+                        let mut mbufs = [std::ptr::null_mut::<Mbuf>(); BURST];
+                        for mbuf in mbufs.iter_mut() {
+                            *mbuf = pool.alloc().unwrap();
+                        }
+                        for &mbuf in mbufs.iter() {
+                            pool.free(black_box(mbuf)).unwrap();
+                        }
+                    })
+                });
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("bulk", threads), threads, |b, &threads| {
+            b.iter(|| {
+                contend(&pool, threads, |pool| {
+                    let mut mbufs = [std::ptr::null_mut::<Mbuf>(); BURST];
+                    pool.alloc_bulk(&mut mbufs).unwrap();
+                    pool.free_bulk(black_box(&mbufs)).unwrap();
+                })
+            });
+        });
+    }
+
+    group.finish();
+}
+
+/// Run `burst` 256 times on each of `threads` threads sharing `pool`
+fn contend(pool: &Arc<MbufPool>, threads: usize, burst: fn(&MbufPool)) {
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..256 {
+                    burst(pool);
+                }
+            });
+        }
+    });
+}
+
 /// Benchmark UDP packet processing
 fn bench_udp_processing(c: &mut Criterion) {
     let mut group = c.benchmark_group("udp_processing");
@@ -51,22 +105,39 @@ fn bench_udp_processing(c: &mut Criterion) {
                     ..Default::default()
                 };
 
-                let mut xpdk = Xpdk::new(config).unwrap();
-                xpdk.start().unwrap();
+                let mut harness = BenchHarness::new(&config, &[8080]).unwrap();
+                let packets = harness.packet_set(1024, packet_size).unwrap();
+
+                b.iter(|| {
+                    black_box(harness.run_rx(&packets));
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
 
-                let socket_id = {
-                    let udp_stack = unsafe { &mut *(xpdk.udp_stack() as *mut _) };
-                    let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-                    udp_stack.create_socket(local_addr).unwrap()
+/// Benchmark socket lookup on dispatch with many bound sockets
+fn bench_socket_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("socket_lookup");
+
+    for sockets in [1, 100, 10_000, 20_000].iter() {
+        group.bench_with_input(
+            BenchmarkId::new("dispatch", sockets),
+            sockets,
+            |b, &sockets| {
+                let config = Config {
+                    pool_size: 4096,
+                    ..Default::default()
                 };
 
-                let test_data = vec![0u8; packet_size];
+                let ports: Vec<u16> = (0..sockets as u16).map(|i| 20_000 + i).collect();
+                let mut harness = BenchHarness::new(&config, &ports).unwrap();
+                let packets = harness.packet_set(1024, 64).unwrap();
 
                 b.iter(|| {
-                    // Simulate packet processing
-                    let mbuf = xpdk.memory_manager().alloc_mbuf().unwrap();
-                    black_box(mbuf);
-                    xpdk.memory_manager().free_mbuf(mbuf).unwrap();
+                    black_box(harness.run_rx(&packets));
                 });
             },
         );
@@ -86,7 +157,7 @@ fn bench_queue_operations(c: &mut Criterion) {
             |b, &queue_size| {
                 use lockfree_ringbuf::SpscRingBuffer;
 
-                let queue = SpscRingBuffer::new(queue_size).unwrap();
+                let queue = SpscRingBuffer::new(queue_size);
                 let test_data = vec![0u8; 1024];
 
                 b.iter(|| {
@@ -203,7 +274,9 @@ fn bench_timestamp_operations(c: &mut Criterion) {
 criterion_group!(
     benches,
     bench_mbuf_allocation,
+    bench_mbuf_bulk_contention,
     bench_udp_processing,
+    bench_socket_lookup,
     bench_queue_operations,
     bench_checksum_calculation,
     bench_rss_hash,
@@ -6,6 +6,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
+use xpdk::utils::backoff::Backoff;
 use xpdk::{Config, Result, UdpStack, Xpdk};
 
 fn main() -> Result<()> {
@@ -72,6 +73,9 @@ fn run_server(args: &[String]) -> Result<()> {
         ..Default::default()
     };
 
+    // Wait on an empty queue as configured
+    let mut backoff = Backoff::new(config.idle_strategy);
+
     // Create and start XPDK
     let mut xpdk = Xpdk::new(config)?;
     xpdk.start()?;
@@ -150,10 +154,7 @@ fn run_server(args: &[String]) -> Result<()> {
             last_report = Instant::now();
         }
 
-        // Sleep briefly if no packets processed
-        if batch_processed == 0 {
-            thread::sleep(Duration::from_micros(10));
-        }
+        backoff.after_poll(batch_processed as usize);
     }
 
     // Print final results
@@ -6,6 +6,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use xpdk::utils::backoff::Backoff;
 use xpdk::{Config, Result, UdpStack, Xpdk};
 
 fn main() -> Result<()> {
@@ -51,9 +52,6 @@ fn main() -> Result<()> {
     println!("Local:  {}", local_addr);
     println!("Interface: {}", interface);
 
-    // Save interface name before moving
-    let interface_name = interface.clone();
-
     // Create configuration
     let config = Config {
         interface,
@@ -68,6 +66,9 @@ fn main() -> Result<()> {
         ..Default::default()
     };
 
+    // Keep a copy for the preflight report if initialization fails
+    let preflight_config = config.clone();
+
     // Create XPDK instance
     let mut xpdk = match Xpdk::new(config) {
         Ok(xpdk) => {
@@ -76,10 +77,7 @@ fn main() -> Result<()> {
         }
         Err(e) => {
             eprintln!("✗ Failed to initialize XPDK: {}", e);
-            eprintln!("Make sure:");
-            eprintln!("  1. Network interface '{}' exists", interface_name);
-            eprintln!("  2. You have root privileges (required for libpcap)");
-            eprintln!("  3. libpcap development libraries are installed");
+            eprint!("{}", Xpdk::preflight_report(&preflight_config));
             return Ok(());
         }
     };
@@ -131,6 +129,7 @@ fn main() -> Result<()> {
 
     let start_time = std::time::Instant::now();
     let mut last_send_time = start_time;
+    let mut backoff = Backoff::new(preflight_config.idle_strategy);
 
     // Test data
     let test_message = b"Hello from XPDK UDP client!";
@@ -177,12 +176,7 @@ fn main() -> Result<()> {
             &mut packets_received,
             &mut bytes_received,
         ) {
-            Ok(received) => {
-                if received == 0 {
-                    // No packets, sleep briefly
-                    thread::sleep(Duration::from_millis(1));
-                }
-            }
+            Ok(received) => backoff.after_poll(received as usize),
             Err(e) => {
                 eprintln!("Error receiving packets: {}", e);
                 thread::sleep(Duration::from_millis(10));
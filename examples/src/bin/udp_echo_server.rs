@@ -6,6 +6,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use xpdk::utils::backoff::Backoff;
 use xpdk::{Config, Result, UdpStack, Xpdk};
 
 fn main() -> Result<()> {
@@ -44,8 +45,8 @@ fn main() -> Result<()> {
     println!("Using interface: {}", config.interface);
     println!("Listening on port: {}", port);
 
-    // Save interface name before moving config
-    let interface_name = config.interface.clone();
+    // Keep a copy for the preflight report if initialization fails
+    let preflight_config = config.clone();
 
     // Create XPDK instance
     let mut xpdk = match Xpdk::new(config) {
@@ -55,10 +56,7 @@ fn main() -> Result<()> {
         }
         Err(e) => {
             eprintln!("✗ Failed to initialize XPDK: {}", e);
-            eprintln!("Make sure:");
-            eprintln!("  1. Network interface '{}' exists", interface_name);
-            eprintln!("  2. You have root privileges (required for libpcap)");
-            eprintln!("  3. libpcap development libraries are installed");
+            eprint!("{}", Xpdk::preflight_report(&preflight_config));
             return Ok(());
         }
     };
@@ -109,6 +107,7 @@ fn main() -> Result<()> {
     let mut bytes_sent = 0u64;
 
     let start_time = std::time::Instant::now();
+    let mut backoff = Backoff::new(preflight_config.idle_strategy);
 
     while running.load(Ordering::Relaxed) {
         // Process packets
@@ -119,12 +118,7 @@ fn main() -> Result<()> {
             &mut bytes_received,
             &mut bytes_sent,
         ) {
-            Ok(processed) => {
-                if processed == 0 {
-                    // No packets, sleep briefly
-                    thread::sleep(Duration::from_millis(1));
-                }
-            }
+            Ok(processed) => backoff.after_poll(processed as usize),
             Err(e) => {
                 eprintln!("Error processing packets: {}", e);
                 thread::sleep(Duration::from_millis(10));
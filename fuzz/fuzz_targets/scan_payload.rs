@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+use xpdk::utils::pattern::PatternSet;
+
+static PATTERNS: OnceLock<PatternSet> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let patterns = PATTERNS.get_or_init(xpdk::fuzz::fuzz_pattern_set);
+    xpdk::fuzz::scan_payload(patterns, data);
+});
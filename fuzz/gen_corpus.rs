@@ -0,0 +1,19 @@
+//! Write the seed frames from `xpdk::fuzz::seed_corpus` into `corpus/<target>/`
+
+use std::fs;
+use std::path::Path;
+
+fn main() -> std::io::Result<()> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+
+    for target in ["parse_frame", "scan_payload", "check_sequences"] {
+        let dir = root.join(target);
+        fs::create_dir_all(&dir)?;
+        for (name, frame) in xpdk::fuzz::seed_corpus() {
+            fs::write(dir.join(name), &frame)?;
+        }
+    }
+
+    println!("Seed corpus written to {}", root.display());
+    Ok(())
+}
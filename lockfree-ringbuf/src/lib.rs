@@ -1,15 +1,18 @@
 extern crate alloc;
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 mod mpmc;
 mod mpsc;
+mod reserve;
 mod spmc;
 mod spsc;
 
 pub use mpmc::MpmcRingBuffer;
 pub use mpsc::MpscRingBuffer;
+pub use reserve::{Drain, ReserveOps, WriteGrant};
 pub use spmc::SpmcRingBuffer;
 pub use spsc::SpscRingBuffer;
 
@@ -22,14 +25,59 @@ pub enum Error {
     Empty,
 }
 
+/// Bytes between the writes that fault in slot memory, the smallest page size
+const PREFAULT_STRIDE: usize = 4096;
+
+/// Returns slot memory to whoever provided it
+type Release<T> = Box<dyn FnOnce(*mut T, usize) + Send + Sync>;
+
+/// Caller-provided memory for the slots of a ring buffer
+///
+/// Lets a ring buffer live in memory the caller chose, such as huge pages
+/// or a given NUMA node, instead of the global heap.
+pub struct SlotMemory<T> {
+    ptr: *mut T,
+    capacity: usize,
+    release: Release<T>,
+}
+
+impl<T> SlotMemory<T> {
+    /// Wrap `capacity` slots at `ptr`, handed to `release` when the ring buffer drops
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned for `T` and valid for reads and writes of
+    /// `capacity` values until `release` is called. `capacity` must be a
+    /// non-zero power of two.
+    pub unsafe fn from_raw_parts(
+        ptr: *mut T,
+        capacity: usize,
+        release: impl FnOnce(*mut T, usize) + Send + Sync + 'static,
+    ) -> Self {
+        debug_assert!(capacity.is_power_of_two());
+        Self {
+            ptr,
+            capacity,
+            release: Box::new(release),
+        }
+    }
+
+    /// Number of slots
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
 /// Core ring buffer storage
 struct RingBufferStorage<T> {
     /// The buffer storage
-    buffer: UnsafeCell<Vec<T>>,
+    buffer: *mut T,
     /// Capacity of the buffer (always a power of 2)
     capacity: usize,
     /// Mask for fast modulo operation (capacity - 1)
     mask: usize,
+    /// Owner of caller-provided memory; `None` when the buffer is a `Vec`
+    release: Option<Release<T>>,
 }
 
 impl<T> RingBufferStorage<T> {
@@ -42,15 +90,26 @@ impl<T> RingBufferStorage<T> {
             capacity.next_power_of_two()
         };
 
-        let mut buffer = Vec::with_capacity(capacity);
+        let mut buffer = core::mem::ManuallyDrop::new(Vec::with_capacity(capacity));
         unsafe {
             buffer.set_len(capacity);
         }
 
         Self {
-            buffer: UnsafeCell::new(buffer),
+            buffer: buffer.as_mut_ptr(),
             capacity,
             mask: capacity - 1,
+            release: None,
+        }
+    }
+
+    /// Use caller-provided slot memory
+    fn from_memory(memory: SlotMemory<T>) -> Self {
+        Self {
+            buffer: memory.ptr,
+            capacity: memory.capacity,
+            mask: memory.capacity - 1,
+            release: Some(memory.release),
         }
     }
 
@@ -60,27 +119,46 @@ impl<T> RingBufferStorage<T> {
         self.capacity
     }
 
+    /// Fault in every page of the slot memory, returning its size in bytes
+    ///
+    /// Each page is written with an atomic add of zero, which keeps the
+    /// slots' contents and is safe while other threads use the buffer.
+    fn prefault(&self) -> usize {
+        let bytes = self.capacity * core::mem::size_of::<T>();
+        let base = self.buffer as *const u8;
+        let touch = |offset: usize| unsafe {
+            (*(base.add(offset) as *const core::sync::atomic::AtomicU8))
+                .fetch_add(0, core::sync::atomic::Ordering::Relaxed);
+        };
+        let mut offset = 0;
+        while offset < bytes {
+            touch(offset);
+            offset += PREFAULT_STRIDE;
+        }
+        if bytes > 0 {
+            touch(bytes - 1);
+        }
+        bytes
+    }
+
     /// Read a value from the buffer at the given index
     #[inline]
     unsafe fn read(&self, index: usize) -> T {
-        let buffer = &*self.buffer.get();
-        core::ptr::read(buffer.get_unchecked(index & self.mask))
+        core::ptr::read(self.buffer.add(index & self.mask))
     }
 
     /// Write a value to the buffer at the given index
     #[inline]
     unsafe fn write(&self, index: usize, value: T) {
-        let buffer = &mut *self.buffer.get();
-        core::ptr::write(buffer.get_unchecked_mut(index & self.mask), value);
+        core::ptr::write(self.buffer.add(index & self.mask), value);
     }
 
     /// Read multiple values from the buffer
     unsafe fn read_batch(&self, start_index: usize, dst: &mut [T]) {
-        let buffer = &*self.buffer.get();
         let mask = self.mask;
 
         for (i, dst_item) in dst.iter_mut().enumerate() {
-            *dst_item = core::ptr::read(buffer.get_unchecked((start_index + i) & mask));
+            *dst_item = core::ptr::read(self.buffer.add((start_index + i) & mask));
         }
     }
 
@@ -89,11 +167,10 @@ impl<T> RingBufferStorage<T> {
     where
         T: Copy,
     {
-        let buffer = &mut *self.buffer.get();
         let mask = self.mask;
 
         for (i, &src_item) in src.iter().enumerate() {
-            core::ptr::write(buffer.get_unchecked_mut((start_index + i) & mask), src_item);
+            core::ptr::write(self.buffer.add((start_index + i) & mask), src_item);
         }
     }
 }
@@ -105,14 +182,71 @@ impl<T> Drop for RingBufferStorage<T> {
     fn drop(&mut self) {
         // Drop all elements in the buffer
         unsafe {
-            let buffer = &mut *self.buffer.get();
-            for item in buffer.iter_mut() {
-                core::ptr::drop_in_place(item);
+            for i in 0..self.capacity {
+                core::ptr::drop_in_place(self.buffer.add(i));
+            }
+            match self.release.take() {
+                Some(release) => release(self.buffer, self.capacity),
+                None => drop(Vec::from_raw_parts(self.buffer, 0, self.capacity)),
             }
         }
     }
 }
 
+/// Per-slot stamps that keep a producer off slots still being read
+///
+/// Where a consumer claims a slot before reading it, the head already
+/// counts the slot as free while the read is in progress, so the producer
+/// reusing it on its next lap waits for the stamp. A slot free for the
+/// item at index `i` is stamped `i`; once written, `i + 1`; once read
+/// again, `i + capacity`.
+struct SlotStamps {
+    stamps: Box<[AtomicUsize]>,
+    mask: usize,
+}
+
+impl SlotStamps {
+    fn new(capacity: usize) -> Self {
+        Self {
+            stamps: (0..capacity).map(AtomicUsize::new).collect(),
+            mask: capacity - 1,
+        }
+    }
+
+    /// Stamp every slot for a buffer holding the items from `head` to `tail`
+    fn reset(&self, head: usize, tail: usize) {
+        let capacity = self.stamps.len();
+        let len = tail.wrapping_sub(head);
+        for i in 0..capacity {
+            let index = tail.wrapping_add(i);
+            let previous = index.wrapping_sub(capacity);
+            let stamp = if tail.wrapping_sub(previous) <= len {
+                previous.wrapping_add(1)
+            } else {
+                index
+            };
+            self.stamps[index & self.mask].store(stamp, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait until the slot of `index` is read, then let the caller write it
+    fn wait_writable(&self, index: usize) {
+        let stamp = &self.stamps[index & self.mask];
+        while stamp.load(Ordering::Acquire) != index {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn written(&self, index: usize) {
+        self.stamps[index & self.mask].store(index.wrapping_add(1), Ordering::Release);
+    }
+
+    fn read(&self, index: usize) {
+        self.stamps[index & self.mask]
+            .store(index.wrapping_add(self.stamps.len()), Ordering::Release);
+    }
+}
+
 /// Helper trait for batch operations
 pub trait BatchOps<T> {
     /// Push multiple items to the queue
@@ -1,4 +1,5 @@
-use crate::{BatchOps, Error, RingBufferStorage};
+use crate::reserve::Reservable;
+use crate::{BatchOps, Drain, Error, ReserveOps, RingBufferStorage, SlotMemory, WriteGrant};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crossbeam_utils::Backoff;
 use crossbeam_utils::CachePadded;
@@ -12,16 +13,28 @@ pub struct MpmcRingBuffer<T> {
     storage: RingBufferStorage<T>,
     /// Head index (consumer position)
     head: CachePadded<AtomicUsize>,
-    /// Tail index (producer position)
+    /// Tail index (next slot to claim by producers)
     tail: CachePadded<AtomicUsize>,
+    /// Published index (slots below it are written and visible to consumers)
+    committed: CachePadded<AtomicUsize>,
+    /// Slots producers may still claim: capacity minus queued, in-flight
+    /// and reserved items. Claiming from this single counter first is what
+    /// makes reservations and pushes exclusive.
+    free: CachePadded<AtomicUsize>,
 }
 
 impl<T: Clone> Clone for MpmcRingBuffer<T> {
     fn clone(&self) -> Self {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.committed.load(Ordering::Relaxed);
         Self {
             storage: RingBufferStorage::new(self.storage.capacity()),
-            head: CachePadded::new(AtomicUsize::new(self.head.load(Ordering::Relaxed))),
-            tail: CachePadded::new(AtomicUsize::new(self.tail.load(Ordering::Relaxed))),
+            head: CachePadded::new(AtomicUsize::new(head)),
+            tail: CachePadded::new(AtomicUsize::new(tail)),
+            committed: CachePadded::new(AtomicUsize::new(tail)),
+            free: CachePadded::new(AtomicUsize::new(
+                self.storage.capacity() - tail.wrapping_sub(head),
+            )),
         }
     }
 }
@@ -30,11 +43,55 @@ impl<T> MpmcRingBuffer<T> {
     /// Create a new MPMC ring buffer with the given capacity
     /// Capacity will be rounded up to the next power of 2
     pub fn new(capacity: usize) -> Self {
+        Self::with_storage(RingBufferStorage::new(capacity))
+    }
+
+    /// Create a MPMC ring buffer in caller-provided slot memory
+    pub fn with_memory(memory: SlotMemory<T>) -> Self {
+        Self::with_storage(RingBufferStorage::from_memory(memory))
+    }
+
+    fn with_storage(storage: RingBufferStorage<T>) -> Self {
+        let free = storage.capacity();
         Self {
-            storage: RingBufferStorage::new(capacity),
+            storage,
             head: CachePadded::new(AtomicUsize::new(0)),
             tail: CachePadded::new(AtomicUsize::new(0)),
+            committed: CachePadded::new(AtomicUsize::new(0)),
+            free: CachePadded::new(AtomicUsize::new(free)),
+        }
+    }
+
+    /// Claim `n` free slots, failing if fewer are free
+    fn claim(&self, n: usize) -> Result<(), Error> {
+        self.free
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |free| {
+                free.checked_sub(n)
+            })
+            .map(|_| ())
+            .map_err(|_| Error::Full)
+    }
+
+    /// Write `items` into slots claimed earlier, then publish them
+    fn write_batch(&self, items: &[T])
+    where
+        T: Copy,
+    {
+        let start = self.tail.fetch_add(items.len(), Ordering::AcqRel);
+        unsafe {
+            self.storage.write_batch(start, items);
         }
+        self.publish(start, start.wrapping_add(items.len()));
+    }
+
+    /// Publish written slots `[start, end)` once earlier slots are published
+    #[inline]
+    fn publish(&self, start: usize, end: usize) {
+        let backoff = Backoff::new();
+        while self.committed.load(Ordering::Acquire) != start {
+            backoff.snooze();
+        }
+        self.committed.store(end, Ordering::Release);
     }
 
     /// Get the capacity of the ring buffer
@@ -45,36 +102,15 @@ impl<T> MpmcRingBuffer<T> {
     /// Try to push a value into the ring buffer
     /// Returns Ok(()) if successful, Err(Error::Full) if the buffer is full
     pub fn push(&self, value: T) -> Result<(), Error> {
-        let backoff = Backoff::new();
-
-        loop {
-            let tail = self.tail.load(Ordering::Relaxed);
-            let head = self.head.load(Ordering::Acquire);
-
-            if tail.wrapping_sub(head) >= self.storage.capacity() {
-                return Err(Error::Full);
-            }
+        self.claim(1)?;
 
-            // Try to reserve the slot
-            if self
-                .tail
-                .compare_exchange_weak(
-                    tail,
-                    tail.wrapping_add(1),
-                    Ordering::Release,
-                    Ordering::Relaxed,
-                )
-                .is_ok()
-            {
-                // Successfully reserved, write the value
-                unsafe {
-                    self.storage.write(tail, value);
-                }
-                return Ok(());
-            }
-
-            backoff.snooze();
+        // The slot is ours, so the tail can simply be bumped
+        let start = self.tail.fetch_add(1, Ordering::AcqRel);
+        unsafe {
+            self.storage.write(start, value);
         }
+        self.publish(start, start.wrapping_add(1));
+        Ok(())
     }
 
     /// Try to pop a value from the ring buffer
@@ -84,7 +120,7 @@ impl<T> MpmcRingBuffer<T> {
 
         loop {
             let head = self.head.load(Ordering::Relaxed);
-            let tail = self.tail.load(Ordering::Acquire);
+            let tail = self.committed.load(Ordering::Acquire);
 
             if head == tail {
                 return Err(Error::Empty);
@@ -101,8 +137,9 @@ impl<T> MpmcRingBuffer<T> {
                 )
                 .is_ok()
             {
-                // Successfully reserved, read the value
+                // Successfully reserved, read the value before freeing the slot
                 let value = unsafe { self.storage.read(head) };
+                self.free.fetch_add(1, Ordering::AcqRel);
                 return Ok(value);
             }
 
@@ -113,20 +150,18 @@ impl<T> MpmcRingBuffer<T> {
     /// Check if the ring buffer is empty
     pub fn is_empty(&self) -> bool {
         let head = self.head.load(Ordering::Relaxed);
-        let tail = self.tail.load(Ordering::Acquire);
+        let tail = self.committed.load(Ordering::Acquire);
         head == tail
     }
 
-    /// Check if the ring buffer is full
+    /// Check if the ring buffer is full, counting reserved slots as used
     pub fn is_full(&self) -> bool {
-        let tail = self.tail.load(Ordering::Relaxed);
-        let head = self.head.load(Ordering::Acquire);
-        tail.wrapping_sub(head) >= self.storage.capacity()
+        self.free.load(Ordering::Acquire) == 0
     }
 
     /// Get the number of items currently in the buffer
     pub fn len(&self) -> usize {
-        let tail = self.tail.load(Ordering::Relaxed);
+        let tail = self.committed.load(Ordering::Relaxed);
         let head = self.head.load(Ordering::Acquire);
         tail.wrapping_sub(head)
     }
@@ -138,37 +173,9 @@ impl<T: Copy> BatchOps<T> for MpmcRingBuffer<T> {
             return Ok(());
         }
 
-        let backoff = Backoff::new();
-
-        loop {
-            let tail = self.tail.load(Ordering::Relaxed);
-            let head = self.head.load(Ordering::Acquire);
-            let available = self.storage.capacity() - tail.wrapping_sub(head);
-
-            if items.len() > available {
-                return Err(Error::Full);
-            }
-
-            // Try to reserve the batch slots
-            if self
-                .tail
-                .compare_exchange_weak(
-                    tail,
-                    tail.wrapping_add(items.len()),
-                    Ordering::Release,
-                    Ordering::Relaxed,
-                )
-                .is_ok()
-            {
-                // Successfully reserved, write the batch
-                unsafe {
-                    self.storage.write_batch(tail, items);
-                }
-                return Ok(());
-            }
-
-            backoff.snooze();
-        }
+        self.claim(items.len())?;
+        self.write_batch(items);
+        Ok(())
     }
 
     fn pop_batch(&self, buf: &mut [T]) -> Result<usize, Error> {
@@ -180,7 +187,7 @@ impl<T: Copy> BatchOps<T> for MpmcRingBuffer<T> {
 
         loop {
             let head = self.head.load(Ordering::Relaxed);
-            let tail = self.tail.load(Ordering::Acquire);
+            let tail = self.committed.load(Ordering::Acquire);
             let available = tail.wrapping_sub(head);
 
             if available == 0 {
@@ -200,10 +207,11 @@ impl<T: Copy> BatchOps<T> for MpmcRingBuffer<T> {
                 )
                 .is_ok()
             {
-                // Successfully reserved, read the batch
+                // Successfully reserved, read the batch before freeing the slots
                 unsafe {
                     self.storage.read_batch(head, &mut buf[..count]);
                 }
+                self.free.fetch_add(count, Ordering::AcqRel);
                 return Ok(count);
             }
 
@@ -212,6 +220,34 @@ impl<T: Copy> BatchOps<T> for MpmcRingBuffer<T> {
     }
 }
 
+impl<T: Copy> Reservable<T> for MpmcRingBuffer<T> {
+    fn commit_reserved(&self, items: &[T], reserved: usize) {
+        if !items.is_empty() {
+            self.write_batch(items);
+        }
+        self.release_reserved(reserved - items.len());
+    }
+
+    fn release_reserved(&self, reserved: usize) {
+        self.free.fetch_add(reserved, Ordering::AcqRel);
+    }
+
+    fn pop_one(&self) -> Result<T, Error> {
+        self.pop()
+    }
+}
+
+impl<T: Copy> ReserveOps<T> for MpmcRingBuffer<T> {
+    fn try_reserve(&self, n: usize) -> Result<WriteGrant<'_, T>, Error> {
+        self.claim(n)?;
+        Ok(WriteGrant::new(self, n))
+    }
+
+    fn drain(&self, max: usize) -> Drain<'_, T> {
+        Drain::new(self, max)
+    }
+}
+
 unsafe impl<T: Send> Send for MpmcRingBuffer<T> {}
 unsafe impl<T: Sync> Sync for MpmcRingBuffer<T> {}
 
@@ -219,6 +255,7 @@ unsafe impl<T: Sync> Sync for MpmcRingBuffer<T> {}
 mod tests {
     use super::*;
     use alloc::vec;
+    use alloc::vec::Vec;
 
     #[test]
     fn test_basic_push_pop() {
@@ -247,4 +284,108 @@ mod tests {
 
         assert!(rb.is_empty());
     }
+
+    #[test]
+    fn test_reservations_exclude_other_producers() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let rb: Arc<MpmcRingBuffer<u32>> = Arc::new(MpmcRingBuffer::new(64));
+        let mut grant = rb.try_reserve(16).unwrap();
+
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let rb = rb.clone();
+                thread::spawn(move || (0..100).filter(|&i| rb.push(i).is_ok()).count())
+            })
+            .collect();
+        let pushed: usize = producers.into_iter().map(|p| p.join().unwrap()).sum();
+        assert_eq!(pushed, 48);
+
+        for i in 0..10 {
+            grant.push(1000 + i).unwrap();
+        }
+        grant.commit();
+        assert_eq!(rb.len(), 58);
+
+        // Unused reserved slots went back to the pool
+        assert!(rb.try_reserve(6).is_ok());
+        assert!(rb.try_reserve(7).is_err());
+        assert_eq!(rb.drain(100).filter(|&v| v >= 1000).count(), 10);
+        assert!(rb.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_producers_and_consumers_see_every_value_once() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+        use std::thread;
+
+        const PRODUCERS: u64 = 4;
+        const PER_PRODUCER: u64 = 20_000;
+        let rb: Arc<MpmcRingBuffer<u64>> = Arc::new(MpmcRingBuffer::new(64));
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Values carry their producer and sequence; single and batch pushes
+        // interleave so both publish paths race each other
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let rb = rb.clone();
+                thread::spawn(move || {
+                    let mut seq = 0;
+                    while seq < PER_PRODUCER {
+                        let value = producer << 32 | seq;
+                        let pushed = if seq % 3 == 0 && seq + 2 < PER_PRODUCER {
+                            rb.push_batch(&[value, value + 1, value + 2]).map(|_| 3)
+                        } else {
+                            rb.push(value).map(|_| 1)
+                        };
+                        match pushed {
+                            Ok(n) => seq += n,
+                            Err(_) => thread::yield_now(),
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..3)
+            .map(|consumer| {
+                let (rb, done) = (rb.clone(), done.clone());
+                thread::spawn(move || {
+                    let mut seen = Vec::new();
+                    let mut buf = [0u64; 5];
+                    loop {
+                        let finished = done.load(Ordering::Acquire);
+                        let popped = if consumer % 2 == 0 {
+                            rb.pop_batch(&mut buf)
+                                .map(|n| seen.extend_from_slice(&buf[..n]))
+                        } else {
+                            rb.pop().map(|value| seen.push(value))
+                        };
+                        match popped {
+                            Ok(()) => {}
+                            Err(_) if finished => break,
+                            Err(_) => thread::yield_now(),
+                        }
+                    }
+                    seen
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+
+        let mut seen: Vec<u64> = consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().unwrap())
+            .collect();
+        seen.sort_unstable();
+        let expected: Vec<u64> = (0..PRODUCERS)
+            .flat_map(|producer| (0..PER_PRODUCER).map(move |seq| producer << 32 | seq))
+            .collect();
+        assert_eq!(seen, expected);
+        assert!(rb.is_empty());
+    }
 }
@@ -12,8 +12,10 @@ pub struct MpscRingBuffer<T> {
     storage: RingBufferStorage<T>,
     /// Head index (consumer position)
     head: CachePadded<AtomicUsize>,
-    /// Tail index (producer position)
+    /// Tail index (next slot to reserve by producers)
     tail: CachePadded<AtomicUsize>,
+    /// Published index (slots below it are written and visible to the consumer)
+    committed: CachePadded<AtomicUsize>,
 }
 
 impl<T> MpscRingBuffer<T> {
@@ -24,6 +26,7 @@ impl<T> MpscRingBuffer<T> {
             storage: RingBufferStorage::new(capacity),
             head: CachePadded::new(AtomicUsize::new(0)),
             tail: CachePadded::new(AtomicUsize::new(0)),
+            committed: CachePadded::new(AtomicUsize::new(0)),
         }
     }
 
@@ -60,6 +63,7 @@ impl<T> MpscRingBuffer<T> {
                 unsafe {
                     self.storage.write(tail, value);
                 }
+                self.publish(tail, tail.wrapping_add(1));
                 return Ok(());
             }
 
@@ -71,7 +75,7 @@ impl<T> MpscRingBuffer<T> {
     /// Returns Ok(value) if successful, Err(Error::Empty) if the buffer is empty
     pub fn pop(&self) -> Result<T, Error> {
         let head = self.head.load(Ordering::Relaxed);
-        let tail = self.tail.load(Ordering::Acquire);
+        let tail = self.committed.load(Ordering::Acquire);
 
         if head == tail {
             return Err(Error::Empty);
@@ -86,7 +90,7 @@ impl<T> MpscRingBuffer<T> {
     /// Check if the ring buffer is empty
     pub fn is_empty(&self) -> bool {
         let head = self.head.load(Ordering::Relaxed);
-        let tail = self.tail.load(Ordering::Acquire);
+        let tail = self.committed.load(Ordering::Acquire);
         head == tail
     }
 
@@ -99,10 +103,20 @@ impl<T> MpscRingBuffer<T> {
 
     /// Get the number of items currently in the buffer
     pub fn len(&self) -> usize {
-        let tail = self.tail.load(Ordering::Relaxed);
+        let tail = self.committed.load(Ordering::Relaxed);
         let head = self.head.load(Ordering::Acquire);
         tail.wrapping_sub(head)
     }
+
+    /// Publish reserved slots `[start, end)` once earlier reservations are published
+    #[inline]
+    fn publish(&self, start: usize, end: usize) {
+        let backoff = Backoff::new();
+        while self.committed.load(Ordering::Acquire) != start {
+            backoff.snooze();
+        }
+        self.committed.store(end, Ordering::Release);
+    }
 }
 
 impl<T: Copy> BatchOps<T> for MpscRingBuffer<T> {
@@ -137,6 +151,7 @@ impl<T: Copy> BatchOps<T> for MpscRingBuffer<T> {
                 unsafe {
                     self.storage.write_batch(tail, items);
                 }
+                self.publish(tail, tail.wrapping_add(items.len()));
                 return Ok(());
             }
 
@@ -150,7 +165,7 @@ impl<T: Copy> BatchOps<T> for MpscRingBuffer<T> {
         }
 
         let head = self.head.load(Ordering::Relaxed);
-        let tail = self.tail.load(Ordering::Acquire);
+        let tail = self.committed.load(Ordering::Acquire);
         let available = tail.wrapping_sub(head);
 
         if available == 0 {
@@ -203,4 +218,39 @@ mod tests {
 
         assert!(rb.is_empty());
     }
+
+    #[test]
+    fn test_concurrent_producers() {
+        use std::sync::Arc;
+
+        let rb: Arc<MpscRingBuffer<usize>> = Arc::new(MpscRingBuffer::new(64));
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let rb = rb.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        while rb.push(p * 1000 + i + 1).is_err() {
+                            core::hint::spin_loop();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut sum = 0;
+        let mut received = 0;
+        while received < 4000 {
+            if let Ok(value) = rb.pop() {
+                // Unpublished slots would show up as zero or stale values
+                assert_ne!(value, 0);
+                sum += value;
+                received += 1;
+            }
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(sum, (1..=4000).sum::<usize>());
+    }
 }
@@ -0,0 +1,125 @@
+use crate::Error;
+use alloc::vec::Vec;
+
+/// Ring buffers that can hand out space reservations
+pub(crate) trait Reservable<T> {
+    /// Publish `items` into space reserved earlier and release the whole reservation
+    fn commit_reserved(&self, items: &[T], reserved: usize);
+
+    /// Give `reserved` slots back without publishing anything
+    fn release_reserved(&self, reserved: usize);
+
+    /// Pop one item
+    fn pop_one(&self) -> Result<T, Error>;
+}
+
+/// Space reserved in a ring buffer for a burst of items
+///
+/// Items pushed into the grant become visible to consumers all at once on
+/// [`WriteGrant::commit`]. Aborting, or dropping the grant, releases the
+/// space without publishing anything.
+pub struct WriteGrant<'a, T: Copy> {
+    ring: &'a dyn Reservable<T>,
+    items: Vec<T>,
+    reserved: usize,
+    settled: bool,
+}
+
+impl<'a, T: Copy> WriteGrant<'a, T> {
+    pub(crate) fn new(ring: &'a dyn Reservable<T>, reserved: usize) -> Self {
+        Self {
+            ring,
+            items: Vec::with_capacity(reserved),
+            reserved,
+            settled: false,
+        }
+    }
+
+    /// Stage an item; fails with `Error::Full` once the reservation is used up
+    pub fn push(&mut self, value: T) -> Result<(), Error> {
+        if self.items.len() >= self.reserved {
+            return Err(Error::Full);
+        }
+        self.items.push(value);
+        Ok(())
+    }
+
+    /// Number of reserved slots
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    /// Number of staged items
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Check if no items are staged
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Publish the staged items and release any unused slots
+    pub fn commit(mut self) {
+        self.settled = true;
+        self.ring.commit_reserved(&self.items, self.reserved);
+    }
+
+    /// Release the reservation without publishing anything
+    pub fn abort(mut self) {
+        self.settled = true;
+        self.ring.release_reserved(self.reserved);
+    }
+}
+
+impl<T: Copy> Drop for WriteGrant<'_, T> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.ring.release_reserved(self.reserved);
+        }
+    }
+}
+
+/// Iterator popping at most a given number of items, see [`ReserveOps::drain`]
+pub struct Drain<'a, T> {
+    ring: &'a dyn Reservable<T>,
+    remaining: usize,
+}
+
+impl<'a, T> Drain<'a, T> {
+    pub(crate) fn new(ring: &'a dyn Reservable<T>, max: usize) -> Self {
+        Self {
+            ring,
+            remaining: max,
+        }
+    }
+}
+
+impl<T> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.ring.pop_one() {
+            Ok(value) => {
+                self.remaining -= 1;
+                Some(value)
+            }
+            Err(_) => {
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+/// Helper trait for reservations and partial drains
+pub trait ReserveOps<T: Copy> {
+    /// Reserve space for `n` items, or fail with `Error::Full` if it is not free
+    fn try_reserve(&self, n: usize) -> Result<WriteGrant<'_, T>, Error>;
+
+    /// Pop up to `max` items, stopping early when the buffer runs empty
+    fn drain(&self, max: usize) -> Drain<'_, T>;
+}
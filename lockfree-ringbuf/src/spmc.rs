@@ -1,4 +1,4 @@
-use crate::{BatchOps, Error, RingBufferStorage};
+use crate::{BatchOps, Error, RingBufferStorage, SlotStamps};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crossbeam_utils::Backoff;
 use crossbeam_utils::CachePadded;
@@ -6,7 +6,14 @@ use crossbeam_utils::CachePadded;
 /// A lock-free Single Producer Multi Consumer (SPMC) ring buffer
 ///
 /// Only one thread can push, but multiple threads can pop concurrently.
-/// Uses atomic operations for coordination between consumers.
+/// Uses atomic operations for coordination between consumers. Consumers
+/// claim their slots with a compare-and-swap before reading them, so the
+/// producer waits, slot by slot, for a pop still reading a slot it reuses.
+///
+/// A buffer created with [`SpmcRingBuffer::with_overwrite`] drops its oldest
+/// items to make room instead of rejecting pushes when full.
+/// [`Self::push_evict`] and [`Self::push_batch_evict`] hand evicted items
+/// back instead of dropping them.
 pub struct SpmcRingBuffer<T> {
     /// Ring buffer storage
     storage: RingBufferStorage<T>,
@@ -14,19 +21,46 @@ pub struct SpmcRingBuffer<T> {
     head: CachePadded<AtomicUsize>,
     /// Tail index (producer position)
     tail: CachePadded<AtomicUsize>,
+    /// Evict the oldest items when full instead of rejecting pushes
+    overwrite: bool,
+    /// Items evicted in overwrite mode
+    evicted: AtomicUsize,
+    stamps: SlotStamps,
 }
 
 impl<T> SpmcRingBuffer<T> {
     /// Create a new SPMC ring buffer with the given capacity
-    /// Capacity will be rounded up to the next power of 2
+    /// Capacity will be rounded up to the next power of 2, and 0 to 1
     pub fn new(capacity: usize) -> Self {
+        let storage = RingBufferStorage::new(capacity);
         Self {
-            storage: RingBufferStorage::new(capacity),
+            stamps: SlotStamps::new(storage.capacity()),
+            storage,
             head: CachePadded::new(AtomicUsize::new(0)),
             tail: CachePadded::new(AtomicUsize::new(0)),
+            overwrite: false,
+            evicted: AtomicUsize::new(0),
         }
     }
 
+    /// Create a SPMC ring buffer that overwrites its oldest items when full
+    pub fn with_overwrite(capacity: usize) -> Self {
+        Self {
+            overwrite: true,
+            ..Self::new(capacity)
+        }
+    }
+
+    /// Check if pushes evict the oldest items instead of failing when full
+    pub fn is_overwrite(&self) -> bool {
+        self.overwrite
+    }
+
+    /// Number of items evicted to make room in overwrite mode
+    pub fn evicted(&self) -> usize {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
     /// Get the capacity of the ring buffer
     pub fn capacity(&self) -> usize {
         self.storage.capacity()
@@ -34,20 +68,47 @@ impl<T> SpmcRingBuffer<T> {
 
     /// Try to push a value into the ring buffer
     /// Returns Ok(()) if successful, Err(Error::Full) if the buffer is full
+    ///
+    /// Never fails in overwrite mode.
     pub fn push(&self, value: T) -> Result<(), Error> {
+        self.push_with(value, drop)
+    }
+
+    /// Push a value, handing back the oldest item if it had to be evicted
+    ///
+    /// Outside overwrite mode this fails when full.
+    pub fn push_evict(&self, value: T) -> Result<Option<T>, Error> {
+        let mut evicted = None;
+        self.push_with(value, |oldest| evicted = Some(oldest))?;
+        Ok(evicted)
+    }
+
+    fn push_with(&self, value: T, on_evict: impl FnMut(T)) -> Result<(), Error> {
         let tail = self.tail.load(Ordering::Relaxed);
-        let head = self.head.load(Ordering::Acquire);
 
-        if tail.wrapping_sub(head) >= self.storage.capacity() {
+        if !self.make_room(tail, 1, on_evict) {
             return Err(Error::Full);
         }
 
+        self.write_slot(tail, value);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Write the slot of `index` once no pop is reading it any more
+    fn write_slot(&self, index: usize, value: T) {
+        self.stamps.wait_writable(index);
         unsafe {
-            self.storage.write(tail, value);
+            self.storage.write(index, value);
         }
+        self.stamps.written(index);
+    }
 
-        self.tail.store(tail.wrapping_add(1), Ordering::Release);
-        Ok(())
+    /// Read the slot of `index`, claimed by the caller
+    fn read_slot(&self, index: usize) -> T {
+        let value = unsafe { self.storage.read(index) };
+        self.stamps.read(index);
+        value
     }
 
     /// Try to pop a value from the ring buffer
@@ -63,26 +124,63 @@ impl<T> SpmcRingBuffer<T> {
                 return Err(Error::Empty);
             }
 
-            // Try to reserve the slot for consumption
+            // Claim before reading: the slot is ours until its stamp says read
             if self
                 .head
                 .compare_exchange_weak(
                     head,
                     head.wrapping_add(1),
-                    Ordering::Release,
+                    Ordering::AcqRel,
                     Ordering::Relaxed,
                 )
                 .is_ok()
             {
-                // Successfully reserved, read the value
-                let value = unsafe { self.storage.read(head) };
-                return Ok(value);
+                return Ok(self.read_slot(head));
             }
 
             backoff.snooze();
         }
     }
 
+    /// Check for `n` free slots at `tail`, evicting the oldest items in overwrite mode
+    ///
+    /// Evicted items are passed to `on_evict`.
+    fn make_room(&self, tail: usize, n: usize, mut on_evict: impl FnMut(T)) -> bool {
+        let capacity = self.storage.capacity();
+        if n > capacity {
+            return false;
+        }
+
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let used = tail.wrapping_sub(head);
+            if used + n <= capacity {
+                return true;
+            }
+            if !self.overwrite {
+                return false;
+            }
+
+            let excess = used + n - capacity;
+            if self
+                .head
+                .compare_exchange(
+                    head,
+                    head.wrapping_add(excess),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                for i in 0..excess {
+                    on_evict(self.read_slot(head.wrapping_add(i)));
+                }
+                self.evicted.fetch_add(excess, Ordering::Relaxed);
+                return true;
+            }
+        }
+    }
+
     /// Check if the ring buffer is empty
     pub fn is_empty(&self) -> bool {
         let head = self.head.load(Ordering::Relaxed);
@@ -105,28 +203,34 @@ impl<T> SpmcRingBuffer<T> {
     }
 }
 
-impl<T: Copy> BatchOps<T> for SpmcRingBuffer<T> {
-    fn push_batch(&self, items: &[T]) -> Result<(), Error> {
+impl<T: Copy> SpmcRingBuffer<T> {
+    /// Push a batch, passing the items evicted to make room to `on_evict`
+    ///
+    /// Outside overwrite mode this fails when full.
+    pub fn push_batch_evict(&self, items: &[T], on_evict: impl FnMut(T)) -> Result<(), Error> {
         if items.is_empty() {
             return Ok(());
         }
 
         let tail = self.tail.load(Ordering::Relaxed);
-        let head = self.head.load(Ordering::Acquire);
-        let available = self.storage.capacity() - tail.wrapping_sub(head);
 
-        if items.len() > available {
+        if !self.make_room(tail, items.len(), on_evict) {
             return Err(Error::Full);
         }
 
-        unsafe {
-            self.storage.write_batch(tail, items);
+        for (i, &item) in items.iter().enumerate() {
+            self.write_slot(tail.wrapping_add(i), item);
         }
-
         self.tail
             .store(tail.wrapping_add(items.len()), Ordering::Release);
         Ok(())
     }
+}
+
+impl<T: Copy> BatchOps<T> for SpmcRingBuffer<T> {
+    fn push_batch(&self, items: &[T]) -> Result<(), Error> {
+        self.push_batch_evict(items, drop)
+    }
 
     fn pop_batch(&self, buf: &mut [T]) -> Result<usize, Error> {
         if buf.is_empty() {
@@ -146,20 +250,19 @@ impl<T: Copy> BatchOps<T> for SpmcRingBuffer<T> {
 
             let count = core::cmp::min(buf.len(), available);
 
-            // Try to reserve the batch slots
+            // Claim before reading, as in `pop`
             if self
                 .head
                 .compare_exchange_weak(
                     head,
                     head.wrapping_add(count),
-                    Ordering::Release,
+                    Ordering::AcqRel,
                     Ordering::Relaxed,
                 )
                 .is_ok()
             {
-                // Successfully reserved, read the batch
-                unsafe {
-                    self.storage.read_batch(head, &mut buf[..count]);
+                for (i, slot) in buf[..count].iter_mut().enumerate() {
+                    *slot = self.read_slot(head.wrapping_add(i));
                 }
                 return Ok(count);
             }
@@ -204,4 +307,35 @@ mod tests {
 
         assert!(rb.is_empty());
     }
+
+    #[test]
+    fn test_overwrite_mode() {
+        let rb: SpmcRingBuffer<i32> = SpmcRingBuffer::with_overwrite(2);
+        assert!(rb.is_overwrite());
+        for i in 1..=5 {
+            rb.push(i).unwrap();
+        }
+        assert_eq!((rb.len(), rb.evicted()), (2, 3));
+        rb.push_batch(&[6, 7]).unwrap();
+        assert_eq!(rb.evicted(), 5);
+
+        let mut buf = [0; 4];
+        assert_eq!(rb.pop_batch(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], &[6, 7]);
+
+        // Evicted items can be handed back instead of dropped
+        rb.push_batch(&[8, 9]).unwrap();
+        assert_eq!(rb.push_evict(10), Ok(Some(8)));
+        let mut evicted = vec![];
+        rb.push_batch_evict(&[11, 12], |item| evicted.push(item))
+            .unwrap();
+        assert_eq!((evicted, rb.evicted()), (vec![9, 10], 8));
+        assert_eq!(rb.pop(), Ok(11));
+
+        // Capacity 0 rounds up to a single slot
+        let rb: SpmcRingBuffer<i32> = SpmcRingBuffer::new(0);
+        assert_eq!(rb.capacity(), 1);
+        rb.push(1).unwrap();
+        assert_eq!(rb.push(2), Err(Error::Full));
+    }
 }
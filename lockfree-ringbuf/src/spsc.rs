@@ -1,4 +1,7 @@
-use crate::{BatchOps, Error, RingBufferStorage};
+use crate::reserve::Reservable;
+use crate::{
+    BatchOps, Drain, Error, ReserveOps, RingBufferStorage, SlotMemory, SlotStamps, WriteGrant,
+};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crossbeam_utils::CachePadded;
 
@@ -6,6 +9,14 @@ use crossbeam_utils::CachePadded;
 ///
 /// This is the fastest variant as it requires no atomic operations for coordination
 /// between producer and consumer once initialized.
+///
+/// A buffer created with [`SpscRingBuffer::with_overwrite`] drops its oldest
+/// items to make room instead of rejecting pushes when full. The producer
+/// then moves the head as well, so pops in that mode claim their slots with
+/// a compare-and-swap before reading them, and a push waits for a pop still
+/// reading the slot it reuses. [`Self::push`] and [`BatchOps::push_batch`]
+/// drop evicted items; [`Self::push_evict`] and [`Self::push_batch_evict`]
+/// hand them back, for items such as buffer pointers that must be freed.
 pub struct SpscRingBuffer<T> {
     /// Ring buffer storage
     storage: RingBufferStorage<T>,
@@ -13,16 +24,45 @@ pub struct SpscRingBuffer<T> {
     head: CachePadded<AtomicUsize>,
     /// Tail index (producer position)
     tail: CachePadded<AtomicUsize>,
+    /// Slots held by outstanding write grants
+    reserved: CachePadded<AtomicUsize>,
+    /// Evict the oldest items when full instead of rejecting pushes
+    overwrite: bool,
+    /// Items evicted in overwrite mode
+    evicted: AtomicUsize,
+    /// Slot stamps, kept up to date in overwrite mode
+    stamps: SlotStamps,
 }
 
 impl<T> SpscRingBuffer<T> {
     /// Create a new SPSC ring buffer with the given capacity
-    /// Capacity will be rounded up to the next power of 2
+    /// Capacity will be rounded up to the next power of 2, and 0 to 1
     pub fn new(capacity: usize) -> Self {
+        Self::with_storage(RingBufferStorage::new(capacity))
+    }
+
+    /// Create a SPSC ring buffer that overwrites its oldest items when full
+    pub fn with_overwrite(capacity: usize) -> Self {
         Self {
-            storage: RingBufferStorage::new(capacity),
+            overwrite: true,
+            ..Self::new(capacity)
+        }
+    }
+
+    /// Create a SPSC ring buffer in caller-provided slot memory
+    pub fn with_memory(memory: SlotMemory<T>) -> Self {
+        Self::with_storage(RingBufferStorage::from_memory(memory))
+    }
+
+    fn with_storage(storage: RingBufferStorage<T>) -> Self {
+        Self {
+            stamps: SlotStamps::new(storage.capacity()),
+            storage,
             head: CachePadded::new(AtomicUsize::new(0)),
             tail: CachePadded::new(AtomicUsize::new(0)),
+            reserved: CachePadded::new(AtomicUsize::new(0)),
+            overwrite: false,
+            evicted: AtomicUsize::new(0),
         }
     }
 
@@ -31,27 +71,93 @@ impl<T> SpscRingBuffer<T> {
         self.storage.capacity()
     }
 
+    /// Fault in the slot memory now instead of on the first pushes
+    ///
+    /// Returns the bytes touched. The contents are left as they are.
+    pub fn prefault(&self) -> usize {
+        self.storage.prefault()
+    }
+
+    /// Check if pushes evict the oldest items instead of failing when full
+    pub fn is_overwrite(&self) -> bool {
+        self.overwrite
+    }
+
+    /// Number of items evicted to make room in overwrite mode
+    pub fn evicted(&self) -> usize {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Switch overwrite mode on or off
+    pub fn set_overwrite(&mut self, overwrite: bool) {
+        if overwrite && !self.overwrite {
+            self.stamps.reset(
+                self.head.load(Ordering::Relaxed),
+                self.tail.load(Ordering::Relaxed),
+            );
+        }
+        self.overwrite = overwrite;
+    }
+
+    /// Push a value, handing back the oldest item if it had to be evicted
+    ///
+    /// Unlike [`Self::push`] the evicted item is returned rather than
+    /// dropped. Outside overwrite mode this fails when full.
+    pub fn push_evict(&self, value: T) -> Result<Option<T>, Error> {
+        let mut evicted = None;
+        self.push_with(value, |oldest| evicted = Some(oldest))?;
+        Ok(evicted)
+    }
+
     /// Try to push a value into the ring buffer
     /// Returns Ok(()) if successful, Err(Error::Full) if the buffer is full
+    ///
+    /// In overwrite mode only slots held by write grants can make it fail.
     pub fn push(&self, value: T) -> Result<(), Error> {
+        self.push_with(value, drop)
+    }
+
+    fn push_with(&self, value: T, on_evict: impl FnMut(T)) -> Result<(), Error> {
         let tail = self.tail.load(Ordering::Relaxed);
-        let head = self.head.load(Ordering::Acquire);
 
-        if tail.wrapping_sub(head) >= self.storage.capacity() {
+        if !self.make_room(tail, 1, on_evict) {
             return Err(Error::Full);
         }
 
+        self.write_slot(tail, value);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Write the slot of `index`, after any pop still reading it in overwrite mode
+    fn write_slot(&self, index: usize, value: T) {
+        if self.overwrite {
+            self.stamps.wait_writable(index);
+        }
         unsafe {
-            self.storage.write(tail, value);
+            self.storage.write(index, value);
+        }
+        if self.overwrite {
+            self.stamps.written(index);
         }
+    }
 
-        self.tail.store(tail.wrapping_add(1), Ordering::Release);
-        Ok(())
+    /// Read the slot of `index`, claimed by the caller
+    fn read_slot(&self, index: usize) -> T {
+        let value = unsafe { self.storage.read(index) };
+        if self.overwrite {
+            self.stamps.read(index);
+        }
+        value
     }
 
     /// Try to pop a value from the ring buffer
     /// Returns Ok(value) if successful, Err(Error::Empty) if the buffer is empty
     pub fn pop(&self) -> Result<T, Error> {
+        if self.overwrite {
+            return self.pop_contended();
+        }
+
         let head = self.head.load(Ordering::Relaxed);
         let tail = self.tail.load(Ordering::Acquire);
 
@@ -65,6 +171,72 @@ impl<T> SpscRingBuffer<T> {
         Ok(value)
     }
 
+    /// Pop while the producer may also move the head
+    fn pop_contended(&self) -> Result<T, Error> {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let tail = self.tail.load(Ordering::Acquire);
+
+            if head == tail {
+                return Err(Error::Empty);
+            }
+
+            // Claim before reading: the producer evicting meanwhile must not
+            // get the same item, and its next write waits for the stamp
+            if self
+                .head
+                .compare_exchange(
+                    head,
+                    head.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Ok(self.read_slot(head));
+            }
+        }
+    }
+
+    /// Check for `n` free slots at `tail`, evicting the oldest items in overwrite mode
+    ///
+    /// Evicted items are passed to `on_evict`.
+    fn make_room(&self, tail: usize, n: usize, mut on_evict: impl FnMut(T)) -> bool {
+        let free = self.free_capacity();
+        if n > free {
+            return false;
+        }
+
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let used = tail.wrapping_sub(head);
+            if used + n <= free {
+                return true;
+            }
+            if !self.overwrite {
+                return false;
+            }
+
+            let excess = used + n - free;
+            if self
+                .head
+                .compare_exchange(
+                    head,
+                    head.wrapping_add(excess),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                for i in 0..excess {
+                    on_evict(self.read_slot(head.wrapping_add(i)));
+                }
+                self.evicted.fetch_add(excess, Ordering::Relaxed);
+                return true;
+            }
+        }
+    }
+
     /// Check if the ring buffer is empty
     pub fn is_empty(&self) -> bool {
         let head = self.head.load(Ordering::Relaxed);
@@ -72,11 +244,17 @@ impl<T> SpscRingBuffer<T> {
         head == tail
     }
 
-    /// Check if the ring buffer is full
+    /// Check if the ring buffer is full, counting reserved slots as used
     pub fn is_full(&self) -> bool {
         let tail = self.tail.load(Ordering::Relaxed);
         let head = self.head.load(Ordering::Acquire);
-        tail.wrapping_sub(head) >= self.storage.capacity()
+        tail.wrapping_sub(head) >= self.free_capacity()
+    }
+
+    /// Capacity minus the slots held by write grants
+    #[inline]
+    fn free_capacity(&self) -> usize {
+        self.storage.capacity() - self.reserved.load(Ordering::Relaxed)
     }
 
     /// Get the number of items currently in the buffer
@@ -87,50 +265,123 @@ impl<T> SpscRingBuffer<T> {
     }
 }
 
-impl<T: Copy> BatchOps<T> for SpscRingBuffer<T> {
-    fn push_batch(&self, items: &[T]) -> Result<(), Error> {
+impl<T: Copy> SpscRingBuffer<T> {
+    /// Push a batch, passing the items evicted to make room to `on_evict`
+    ///
+    /// Unlike [`BatchOps::push_batch`] evicted items are handed back rather
+    /// than dropped. Outside overwrite mode this fails when full.
+    pub fn push_batch_evict(&self, items: &[T], on_evict: impl FnMut(T)) -> Result<(), Error> {
         if items.is_empty() {
             return Ok(());
         }
 
         let tail = self.tail.load(Ordering::Relaxed);
-        let head = self.head.load(Ordering::Acquire);
-        let available = self.storage.capacity() - tail.wrapping_sub(head);
 
-        if items.len() > available {
+        if !self.make_room(tail, items.len(), on_evict) {
             return Err(Error::Full);
         }
 
-        unsafe {
-            self.storage.write_batch(tail, items);
-        }
-
+        self.write_slots(tail, items);
         self.tail
             .store(tail.wrapping_add(items.len()), Ordering::Release);
         Ok(())
     }
 
+    fn write_slots(&self, start: usize, items: &[T]) {
+        if !self.overwrite {
+            unsafe {
+                self.storage.write_batch(start, items);
+            }
+            return;
+        }
+        for (i, &item) in items.iter().enumerate() {
+            self.write_slot(start.wrapping_add(i), item);
+        }
+    }
+}
+
+impl<T: Copy> BatchOps<T> for SpscRingBuffer<T> {
+    fn push_batch(&self, items: &[T]) -> Result<(), Error> {
+        self.push_batch_evict(items, drop)
+    }
+
     fn pop_batch(&self, buf: &mut [T]) -> Result<usize, Error> {
         if buf.is_empty() {
             return Ok(0);
         }
 
-        let head = self.head.load(Ordering::Relaxed);
-        let tail = self.tail.load(Ordering::Acquire);
-        let available = tail.wrapping_sub(head);
-
-        if available == 0 {
-            return Err(Error::Empty);
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let tail = self.tail.load(Ordering::Acquire);
+            let available = tail.wrapping_sub(head);
+
+            if available == 0 {
+                return Err(Error::Empty);
+            }
+
+            let count = core::cmp::min(buf.len(), available);
+
+            if !self.overwrite {
+                unsafe {
+                    self.storage.read_batch(head, &mut buf[..count]);
+                }
+                self.head.store(head.wrapping_add(count), Ordering::Release);
+                return Ok(count);
+            }
+            // Claim before reading, as in `pop`
+            if self
+                .head
+                .compare_exchange(
+                    head,
+                    head.wrapping_add(count),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                for (i, slot) in buf[..count].iter_mut().enumerate() {
+                    *slot = self.read_slot(head.wrapping_add(i));
+                }
+                return Ok(count);
+            }
         }
+    }
+}
 
-        let count = core::cmp::min(buf.len(), available);
+impl<T: Copy> Reservable<T> for SpscRingBuffer<T> {
+    fn commit_reserved(&self, items: &[T], reserved: usize) {
+        // The grant's slots are still free: only the producer fills them
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.write_slots(tail, items);
+        self.tail
+            .store(tail.wrapping_add(items.len()), Ordering::Release);
+        self.reserved.fetch_sub(reserved, Ordering::Relaxed);
+    }
 
-        unsafe {
-            self.storage.read_batch(head, &mut buf[..count]);
+    fn release_reserved(&self, reserved: usize) {
+        self.reserved.fetch_sub(reserved, Ordering::Relaxed);
+    }
+
+    fn pop_one(&self) -> Result<T, Error> {
+        self.pop()
+    }
+}
+
+impl<T: Copy> ReserveOps<T> for SpscRingBuffer<T> {
+    fn try_reserve(&self, n: usize) -> Result<WriteGrant<'_, T>, Error> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if n > self.free_capacity().saturating_sub(tail.wrapping_sub(head)) {
+            return Err(Error::Full);
         }
 
-        self.head.store(head.wrapping_add(count), Ordering::Release);
-        Ok(count)
+        self.reserved.fetch_add(n, Ordering::Relaxed);
+        Ok(WriteGrant::new(self, n))
+    }
+
+    fn drain(&self, max: usize) -> Drain<'_, T> {
+        Drain::new(self, max)
     }
 }
 
@@ -141,6 +392,7 @@ unsafe impl<T: Sync> Sync for SpscRingBuffer<T> {}
 mod tests {
     use super::*;
     use alloc::vec;
+    use alloc::vec::Vec;
 
     #[test]
     fn test_basic_push_pop() {
@@ -190,4 +442,167 @@ mod tests {
 
         assert!(rb.is_empty());
     }
+
+    #[test]
+    fn test_degenerate_capacities() {
+        // Capacity 0 rounds up to a single slot
+        let rb: SpscRingBuffer<i32> = SpscRingBuffer::new(0);
+        assert_eq!(rb.capacity(), 1);
+        assert!(rb.push(1).is_ok());
+        assert_eq!(rb.push(2), Err(Error::Full));
+        assert_eq!(rb.pop(), Ok(1));
+        assert_eq!(rb.pop(), Err(Error::Empty));
+
+        let rb: SpscRingBuffer<i32> = SpscRingBuffer::with_overwrite(1);
+        rb.push(1).unwrap();
+        rb.push(2).unwrap();
+        assert_eq!((rb.len(), rb.evicted()), (1, 1));
+        assert_eq!(rb.pop(), Ok(2));
+    }
+
+    #[test]
+    fn test_overwrite_mode() {
+        let rb: SpscRingBuffer<i32> = SpscRingBuffer::with_overwrite(4);
+        assert!(rb.is_overwrite());
+        for i in 1..=6 {
+            rb.push(i).unwrap();
+        }
+        assert_eq!(rb.evicted(), 2);
+        assert_eq!(rb.pop(), Ok(3));
+
+        rb.push_batch(&[7, 8]).unwrap();
+        assert_eq!(rb.evicted(), 3);
+        // Batches larger than the buffer still fail
+        assert_eq!(rb.push_batch(&[0; 5]), Err(Error::Full));
+
+        let mut buf = [0; 8];
+        assert_eq!(rb.pop_batch(&mut buf), Ok(4));
+        assert_eq!(&buf[..4], &[5, 6, 7, 8]);
+
+        // Reserved slots are never evicted
+        let grant = rb.try_reserve(4).unwrap();
+        assert_eq!(rb.push(9), Err(Error::Full));
+        drop(grant);
+    }
+
+    #[test]
+    fn test_push_evict() {
+        let mut rb: SpscRingBuffer<i32> = SpscRingBuffer::new(2);
+        assert_eq!(rb.push_evict(1), Ok(None));
+        rb.push(2).unwrap();
+        assert_eq!(rb.push_evict(3), Err(Error::Full));
+
+        rb.set_overwrite(true);
+        assert_eq!(rb.push_evict(3), Ok(Some(1)));
+        assert_eq!(rb.evicted(), 1);
+        assert_eq!(rb.pop(), Ok(2));
+        assert_eq!(rb.push_evict(4), Ok(None));
+        assert_eq!(rb.pop(), Ok(3));
+        assert_eq!(rb.pop(), Ok(4));
+    }
+
+    #[test]
+    fn test_overwrite_hands_out_each_item_once() {
+        extern crate std;
+        use alloc::sync::Arc;
+
+        // Pairs torn by a write during a read would not match
+        let rb: Arc<SpscRingBuffer<(u64, u64)>> = Arc::new(SpscRingBuffer::with_overwrite(8));
+        let consumer = {
+            let rb = rb.clone();
+            std::thread::spawn(move || {
+                let mut seen = Vec::new();
+                let mut buf = [(0, 0); 3];
+                while seen.last() != Some(&u64::MAX) {
+                    let batch = rb.pop_batch(&mut buf).unwrap_or(0);
+                    seen.extend(buf[..batch].iter().map(|&(a, b)| {
+                        assert_eq!(a, !b);
+                        a
+                    }));
+                    if let Ok((a, b)) = rb.pop() {
+                        assert_eq!(a, !b);
+                        seen.push(a);
+                    }
+                }
+                seen
+            })
+        };
+        let mut evicted = Vec::new();
+        for i in 0..20_000u64 {
+            if let Some((a, _)) = rb.push_evict((i, !i)).unwrap() {
+                evicted.push(a);
+            }
+            if i % 7 == 0 {
+                rb.push_batch_evict(&[(i, !i)], |(a, _)| evicted.push(a))
+                    .unwrap();
+            }
+        }
+        if let Some((a, _)) = rb.push_evict((u64::MAX, 0)).unwrap() {
+            evicted.push(a);
+        }
+        let seen = consumer.join().unwrap();
+
+        // Everything pushed was either popped or handed back, once
+        let mut all: Vec<u64> = seen.into_iter().chain(evicted).collect();
+        all.sort_unstable();
+        let mut expected: Vec<u64> = (0..20_000u64)
+            .chain((0..20_000u64).step_by(7))
+            .chain([u64::MAX])
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn test_caller_provided_memory() {
+        use alloc::sync::Arc;
+        use core::sync::atomic::AtomicBool;
+
+        let slots = core::mem::ManuallyDrop::new(Vec::<i32>::with_capacity(4));
+        let released = Arc::new(AtomicBool::new(false));
+        let flag = released.clone();
+        let memory = unsafe {
+            SlotMemory::from_raw_parts(slots.as_ptr() as *mut i32, 4, move |ptr, capacity| {
+                drop(Vec::from_raw_parts(ptr, 0, capacity));
+                flag.store(true, Ordering::Relaxed);
+            })
+        };
+
+        let rb = SpscRingBuffer::with_memory(memory);
+        assert_eq!(rb.capacity(), 4);
+        rb.push_batch(&[1, 2, 3, 4]).unwrap();
+        assert!(rb.push(5).is_err());
+        assert_eq!(rb.pop(), Ok(1));
+        drop(rb);
+        assert!(released.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_reserve_commit_and_abort() {
+        let rb: SpscRingBuffer<i32> = SpscRingBuffer::new(4);
+        rb.push(1).unwrap();
+
+        let mut grant = rb.try_reserve(2).unwrap();
+        assert!(rb.try_reserve(2).is_err());
+        rb.push(2).unwrap();
+        // Reserved slots are not available to plain pushes
+        assert!(rb.push(3).is_err());
+        assert!(rb.is_full());
+
+        grant.push(10).unwrap();
+        grant.push(11).unwrap();
+        assert_eq!(grant.push(12), Err(Error::Full));
+        assert_eq!(rb.len(), 2);
+        grant.commit();
+        assert_eq!(rb.len(), 4);
+
+        let drained: Vec<i32> = rb.drain(3).collect();
+        assert_eq!(drained, vec![1, 2, 10]);
+
+        let grant = rb.try_reserve(3).unwrap();
+        grant.abort();
+        drop(rb.try_reserve(3).unwrap());
+        assert_eq!(rb.drain(8).collect::<Vec<_>>(), vec![11]);
+        assert!(rb.try_reserve(4).is_ok());
+    }
 }
@@ -0,0 +1,253 @@
+//! Benchmark support
+//!
+//! [`BenchHarness`] sets up a running [`UdpStack`] with bound sockets and a
+//! private mbuf pool, with no capture device involved: frames are fed to the
+//! stack in-process, the way the RX path hands them over. Benchmarks build
+//! a [`PacketSet`] once, outside the measured loop, and then time
+//! [`BenchHarness::run_rx`] between [`BenchHarness::start_measurement`] and
+//! [`BenchHarness::stop_measurement`], so that setup and allocation noise
+//! stay out of the numbers.
+
+use crate::memory::{Mbuf, MbufPool, PacketType};
+use crate::packet::{EthernetBuilder, Ipv4Builder, UdpBuilder};
+use crate::udp::UdpStack;
+use crate::{Config, Error, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Address the harness sockets are bound to
+pub const BENCH_LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+/// Address the harness frames are sent from, on port 6000
+pub const BENCH_PEER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Frames loaded into mbufs ahead of a benchmark run
+///
+/// The mbufs go back to the harness pool when the set is dropped.
+pub struct PacketSet {
+    mbufs: Vec<*mut Mbuf>,
+    bytes: usize,
+    pool: Arc<MbufPool>,
+}
+
+impl PacketSet {
+    /// Number of frames
+    pub fn len(&self) -> usize {
+        self.mbufs.len()
+    }
+
+    /// Check if the set holds no frames
+    pub fn is_empty(&self) -> bool {
+        self.mbufs.is_empty()
+    }
+
+    /// Total frame bytes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for PacketSet {
+    fn drop(&mut self) {
+        for &mbuf in &self.mbufs {
+            let _ = self.pool.free(mbuf);
+        }
+    }
+}
+
+/// Throughput over one measurement window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub packets: usize,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    /// Packets per second
+    pub fn packets_per_sec(&self) -> f64 {
+        self.packets as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Bits per second
+    pub fn bits_per_sec(&self) -> f64 {
+        (self.bytes * 8) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Running stack with bound sockets for datapath benchmarks
+pub struct BenchHarness {
+    stack: UdpStack,
+    pool: Arc<MbufPool>,
+    sockets: Vec<u16>,
+    measure_start: Option<Instant>,
+    measured_packets: usize,
+    measured_bytes: usize,
+}
+
+impl BenchHarness {
+    /// Start a stack with one socket per port and a pool of `config.pool_size` mbufs
+    pub fn new(config: &Config, ports: &[u16]) -> Result<Self> {
+        let pool = Arc::new(MbufPool::new("bench".to_string(), config.pool_size, 2048)?);
+        let mut stack = UdpStack::new(config)?;
+        stack.set_rx_pool(pool.clone());
+        let sockets = ports
+            .iter()
+            .map(|&port| stack.create_socket(SocketAddr::new(IpAddr::V4(BENCH_LOCAL_ADDR), port)))
+            .collect::<Result<Vec<_>>>()?;
+        stack.start()?;
+
+        Ok(Self {
+            stack,
+            pool,
+            sockets,
+            measure_start: None,
+            measured_packets: 0,
+            measured_bytes: 0,
+        })
+    }
+
+    /// Get the stack
+    pub fn stack(&self) -> &UdpStack {
+        &self.stack
+    }
+
+    /// Get the stack (mutable)
+    pub fn stack_mut(&mut self) -> &mut UdpStack {
+        &mut self.stack
+    }
+
+    /// Get the mbuf pool
+    pub fn pool(&self) -> &Arc<MbufPool> {
+        &self.pool
+    }
+
+    /// IDs of the harness sockets, in port order
+    pub fn sockets(&self) -> &[u16] {
+        &self.sockets
+    }
+
+    /// Load `count` UDP frames of `payload_len` bytes, spread over the ports
+    pub fn packet_set(&self, count: usize, payload_len: usize) -> Result<PacketSet> {
+        if self.sockets.is_empty() {
+            return Err(Error::InvalidConfig("Harness has no sockets".to_string()));
+        }
+        if count > self.pool.stats().available {
+            return Err(Error::MemoryAllocation(format!(
+                "Packet set of {} frames exceeds the bench pool",
+                count
+            )));
+        }
+
+        let payload = vec![0xA5; payload_len];
+        let frames: Vec<EthernetBuilder> =
+            self.sockets
+                .iter()
+                .filter_map(|&id| self.stack.get_socket(id))
+                .map(|socket| {
+                    EthernetBuilder::default()
+                        .dst([0x02, 0, 0, 0, 0, 0x01])
+                        .src([0x02, 0, 0, 0, 0, 0x02])
+                        .ipv4(Ipv4Builder::new(BENCH_PEER_ADDR, BENCH_LOCAL_ADDR).udp(
+                            UdpBuilder::new(6000, socket.local_addr().port()).payload(&payload),
+                        ))
+                })
+                .collect();
+
+        let mut set = PacketSet {
+            mbufs: Vec::with_capacity(count),
+            bytes: 0,
+            pool: self.pool.clone(),
+        };
+        for frame in frames.iter().cycle().take(count) {
+            let mbuf = self.pool.alloc()?;
+            set.mbufs.push(mbuf);
+            frame.write_mbuf(unsafe { &mut *mbuf })?;
+            set.bytes += frame.wire_len();
+        }
+        Ok(set)
+    }
+
+    /// Dispatch every frame of `set` and drain the sockets, returning the frames delivered
+    ///
+    /// Frames are reclassified on every run. Delivered mbufs are taken back
+    /// into the set, so a set can be run any number of times.
+    pub fn run_rx(&mut self, set: &PacketSet) -> usize {
+        let mut delivered = 0;
+        for &mbuf in &set.mbufs {
+            Self::reset(mbuf);
+            if self.stack.dispatch(mbuf).is_delivered() {
+                delivered += 1;
+            }
+        }
+
+        let mut drained = 0;
+        for &id in &self.sockets {
+            if let Some(socket) = self.stack.get_socket(id) {
+                // The set keeps ownership of the mbufs
+                while socket.recv().is_ok() {
+                    drained += 1;
+                }
+            }
+        }
+        debug_assert!(drained <= delivered);
+
+        if self.measure_start.is_some() {
+            self.measured_packets += delivered;
+            self.measured_bytes += set.bytes;
+        }
+        delivered
+    }
+
+    /// Start a measurement window
+    pub fn start_measurement(&mut self) {
+        self.measured_packets = 0;
+        self.measured_bytes = 0;
+        self.measure_start = Some(Instant::now());
+    }
+
+    /// End the measurement window, `None` if none was started
+    pub fn stop_measurement(&mut self) -> Option<Measurement> {
+        let start = self.measure_start.take()?;
+        Some(Measurement {
+            packets: self.measured_packets,
+            bytes: self.measured_bytes,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    fn reset(mbuf: *mut Mbuf) {
+        let mbuf = unsafe { &mut *mbuf };
+        mbuf.packet_type = PacketType::Unknown;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_sets_can_be_rerun() {
+        let config = Config {
+            pool_size: 16,
+            ..Config::default()
+        };
+        let mut harness = BenchHarness::new(&config, &[5000, 5001]).unwrap();
+        let set = harness.packet_set(8, 64).unwrap();
+        assert_eq!(set.len(), 8);
+        assert!(harness.packet_set(9, 64).is_err());
+        assert!(harness.packet_set(1, 65_508).is_err());
+
+        harness.start_measurement();
+        assert_eq!(harness.run_rx(&set), 8);
+        assert_eq!(harness.run_rx(&set), 8);
+        let measurement = harness.stop_measurement().unwrap();
+        assert_eq!(measurement.packets, 16);
+        assert_eq!(measurement.bytes, 2 * set.bytes());
+        assert!(harness.stop_measurement().is_none());
+
+        drop(set);
+        assert_eq!(harness.pool().stats().available, 16);
+    }
+}
@@ -0,0 +1,370 @@
+//! `std::net` compatibility adapter
+//!
+//! [`UdpSocket`] mirrors the blocking API of [`std::net::UdpSocket`] on top
+//! of an XPDK stack, so an existing service can move to the fast path by
+//! changing its import and installing a runtime once at startup with
+//! [`install`], or [`install_stack`] for a stack shared with a
+//! [`crate::dispatch::Dispatcher`] that feeds it from its own thread. Calls
+//! return [`std::io::Error`] with the kinds the standard socket uses:
+//! reads on a non-blocking socket or past the read timeout fail with
+//! [`io::ErrorKind::WouldBlock`], and `recv` on an unconnected socket with
+//! [`io::ErrorKind::NotConnected`]. As with the standard socket, a datagram
+//! longer than the receive buffer is truncated. Received payloads are
+//! copied out of the mbufs, so the adapter trades zero-copy for the
+//! familiar API; sockets of the stack used directly keep the native API.
+
+use crate::dispatch::PollBudget;
+use crate::udp::{UdpStack, EPHEMERAL_PORTS};
+use crate::{Error, Result, Xpdk};
+use parking_lot::{Mutex, RwLock};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Packets polled per call while a blocking receive waits
+const POLL_BUDGET: usize = 32;
+
+/// Stack the adapter's sockets live on
+enum Runtime {
+    /// Stack of an engine the adapter polls while receives block
+    Xpdk(Box<Xpdk>),
+    /// Stack fed by someone else, e.g. registered with a dispatcher
+    Stack(Arc<RwLock<UdpStack>>),
+}
+
+impl Runtime {
+    fn with_stack<R>(&mut self, f: impl FnOnce(&mut UdpStack) -> R) -> R {
+        match self {
+            Runtime::Xpdk(xpdk) => f(xpdk.udp_stack_mut()),
+            Runtime::Stack(stack) => f(&mut stack.write()),
+        }
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        if let Runtime::Xpdk(xpdk) = self {
+            xpdk.poll_once(&PollBudget::new(POLL_BUDGET))?;
+        }
+        Ok(())
+    }
+}
+
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+/// Serve adapter sockets from a started engine, polled while receives block
+pub fn install(xpdk: Xpdk) -> Result<()> {
+    install_runtime(Runtime::Xpdk(Box::new(xpdk)))
+}
+
+/// Serve adapter sockets from a stack whose packets are dispatched elsewhere
+///
+/// Register the same stack with a [`crate::dispatch::Dispatcher`] to feed
+/// it; adapter calls take its lock like the dispatcher does.
+pub fn install_stack(stack: Arc<RwLock<UdpStack>>) -> Result<()> {
+    install_runtime(Runtime::Stack(stack))
+}
+
+fn install_runtime(runtime: Runtime) -> Result<()> {
+    let mut installed = RUNTIME.lock();
+    if installed.is_some() {
+        return Err(Error::InvalidConfig(
+            "Compatibility runtime already installed".to_string(),
+        ));
+    }
+    *installed = Some(runtime);
+    Ok(())
+}
+
+/// Drop the installed runtime; false if none was installed
+pub fn uninstall() -> bool {
+    RUNTIME.lock().take().is_some()
+}
+
+/// Run `f` against the installed stack
+pub fn with_stack<R>(f: impl FnOnce(&mut UdpStack) -> R) -> io::Result<R> {
+    with_runtime(|runtime| Ok(runtime.with_stack(f)))
+}
+
+fn with_runtime<R>(f: impl FnOnce(&mut Runtime) -> io::Result<R>) -> io::Result<R> {
+    match RUNTIME.lock().as_mut() {
+        Some(runtime) => f(runtime),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No compatibility runtime installed",
+        )),
+    }
+}
+
+/// Map a stack error to the closest I/O error
+fn io_error(error: Error) -> io::Error {
+    if let Error::IoError(e) = error {
+        return e;
+    }
+    let kind = match &error {
+        Error::InvalidConfig(_) => io::ErrorKind::InvalidInput,
+        Error::MemoryAllocation(_) | Error::BudgetExceeded(_) => io::ErrorKind::OutOfMemory,
+        Error::QueueError(_) => io::ErrorKind::WouldBlock,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, error.to_string())
+}
+
+fn socket_not_found(id: u16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("Socket {} not found", id),
+    )
+}
+
+fn resolve(addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No IPv4 address to use"))
+}
+
+/// UDP socket with the API of [`std::net::UdpSocket`]
+#[derive(Debug)]
+pub struct UdpSocket {
+    id: u16,
+    local_addr: SocketAddr,
+    peer: Mutex<Option<SocketAddr>>,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl UdpSocket {
+    /// Create a socket bound to `addr`, to a free ephemeral port for port 0
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<UdpSocket> {
+        let addr = resolve(addr)?;
+        with_stack(|stack| {
+            let (id, local_addr) = if addr.port() == 0 {
+                EPHEMERAL_PORTS
+                    .map(|port| SocketAddr::new(addr.ip(), port))
+                    .find_map(|local_addr| {
+                        let id = stack.create_socket(local_addr).ok()?;
+                        Some((id, local_addr))
+                    })
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::AddrInUse, "No free ephemeral port")
+                    })?
+            } else {
+                (stack.create_socket(addr).map_err(io_error)?, addr)
+            };
+            if let Some(socket) = stack.get_socket(id) {
+                socket.start().map_err(io_error)?;
+            }
+            Ok(UdpSocket {
+                id,
+                local_addr,
+                peer: Mutex::new(None),
+                nonblocking: AtomicBool::new(false),
+                read_timeout: Mutex::new(None),
+            })
+        })?
+    }
+
+    /// Set the default destination of [`UdpSocket::send`] and filter [`UdpSocket::recv`] to it
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        *self.peer.lock() = Some(resolve(addr)?);
+        Ok(())
+    }
+
+    /// Send a datagram to `addr`, returning the bytes sent
+    pub fn send_to(&self, buf: &[u8], addr: impl ToSocketAddrs) -> io::Result<usize> {
+        let dst_addr = resolve(addr)?;
+        with_stack(|stack| {
+            let socket = stack
+                .get_socket(self.id)
+                .ok_or_else(|| socket_not_found(self.id))?;
+            socket.send(dst_addr, buf).map_err(io_error)?;
+            Ok(buf.len())
+        })?
+    }
+
+    /// Send a datagram to the connected peer
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let peer = self.peer_addr()?;
+        self.send_to(buf, peer)
+    }
+
+    /// Receive a datagram into `buf`, returning its length and source
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self
+            .read_timeout
+            .lock()
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            let received = with_runtime(|runtime| {
+                let received = runtime.with_stack(|stack| {
+                    let socket = stack
+                        .get_socket(self.id)
+                        .ok_or_else(|| socket_not_found(self.id))?;
+                    match socket.recv_copied() {
+                        Ok((src_addr, payload)) => {
+                            let len = payload.len().min(buf.len());
+                            buf[..len].copy_from_slice(&payload[..len]);
+                            socket.recycle_buffer(payload);
+                            Ok(Some((len, src_addr)))
+                        }
+                        Err(Error::NetworkError(_)) => Ok(None),
+                        Err(e) => Err(io_error(e)),
+                    }
+                })?;
+                if received.is_none() {
+                    runtime.poll().map_err(io_error)?;
+                }
+                Ok(received)
+            })?;
+            if let Some(received) = received {
+                return Ok(received);
+            }
+            if self.nonblocking.load(Ordering::Relaxed)
+                || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "No datagram available",
+                ));
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Receive a datagram from the connected peer, discarding others
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let peer = self.peer_addr()?;
+        loop {
+            let (len, src_addr) = self.recv_from(buf)?;
+            if src_addr == peer {
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Address the socket is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Address of the connected peer
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer
+            .lock()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Socket is not connected"))
+    }
+
+    /// Make receives fail with `WouldBlock` instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Bound the time a blocking receive waits, forever for `None`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Zero read timeout",
+            ));
+        }
+        *self.read_timeout.lock() = timeout;
+        Ok(())
+    }
+
+    /// Time a blocking receive waits
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock())
+    }
+
+    /// Set the IPv4 TTL of sent datagrams
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        let ttl = u8::try_from(ttl)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "TTL above 255"))?;
+        with_stack(|stack| {
+            let socket = stack
+                .get_socket_mut(self.id)
+                .ok_or_else(|| socket_not_found(self.id))?;
+            socket.set_ttl(ttl);
+            Ok(())
+        })?
+    }
+
+    /// IPv4 TTL of sent datagrams
+    pub fn ttl(&self) -> io::Result<u32> {
+        with_stack(|stack| {
+            let socket = stack
+                .get_socket(self.id)
+                .ok_or_else(|| socket_not_found(self.id))?;
+            Ok(socket.ttl() as u32)
+        })?
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let _ = with_stack(|stack| stack.close_socket(self.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatch::Dispatcher;
+    use crate::memory::MbufPool;
+    use crate::poll::RxQueue;
+    use crate::udp::testing::FrameBuilder;
+    use crate::Config;
+
+    #[test]
+    fn test_std_api_over_stack() {
+        let pool = Arc::new(MbufPool::new("compat".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        stack.start().unwrap();
+        let stack = Arc::new(RwLock::new(stack));
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(5000..=5000, stack.clone()).unwrap();
+        install_stack(stack).unwrap();
+        let other = UdpStack::new(&Config::default()).unwrap();
+        assert!(install_stack(Arc::new(RwLock::new(other))).is_err());
+
+        // The dispatcher feeds the stack backing the adapter
+        let socket = UdpSocket::bind("10.0.0.1:5000").unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), 5000);
+        let builder = FrameBuilder::to_port(5000).payload(b"hello compat");
+        let rx_queue = RxQueue::in_memory(0, vec![builder.build()], pool.clone());
+        assert_eq!(dispatcher.process_rx_packets(&rx_queue).unwrap(), 1);
+
+        // Long datagrams are truncated to the buffer
+        let mut buf = [0u8; 5];
+        let (len, src_addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!((len, src_addr, &buf), (5, builder.src_addr(), b"hello"));
+        assert_eq!(pool.stats().available, 4);
+
+        socket.set_nonblocking(true).unwrap();
+        let error = socket.recv_from(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        socket.set_nonblocking(false).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        let error = socket.recv_from(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            socket.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+
+        assert_eq!(socket.ttl().unwrap(), 64);
+        socket.set_ttl(8).unwrap();
+        assert_eq!(socket.ttl().unwrap(), 8);
+        assert!(socket.set_ttl(256).is_err());
+
+        let id = socket.id;
+        drop(socket);
+        assert!(with_stack(|stack| stack.get_socket(id).is_none()).unwrap());
+        assert!(uninstall());
+    }
+}
@@ -0,0 +1,532 @@
+//! Control-plane command queue
+//!
+//! Management operations are queued through a [`ControlHandle`] and executed
+//! by the dispatcher between packet batches, so they never take `&mut`
+//! access racing with the datapath. Every command returns a [`Reply`] that
+//! can be waited on, polled, or awaited.
+//!
+//! Each command has a [`CommandPriority`]. Commands that must take effect
+//! promptly under load, such as closing a socket or stopping a stack, are
+//! high priority: the dispatcher runs them before each budgeted poll and
+//! again after every [`crate::dispatch::POLL_QUANTUM`] packets within it,
+//! so their latency is bounded by a quantum of packets rather than by the
+//! whole budget. At most [`CONTROL_BATCH`] normal and low priority commands
+//! run per poll, normal ones first, so a burst of management traffic cannot
+//! stall the datapath either. [`ControlStats`] records how long the
+//! commands of each priority waited.
+
+use crate::dispatch::{Dispatcher, StackId};
+use crate::udp::{DropEvent, UdpStack};
+use crate::utils::counter::Counter;
+use crate::utils::time::{monotonic_now, Timestamp};
+use crate::{Error, Result};
+use lockfree_ringbuf::MpscRingBuffer;
+use parking_lot::{Condvar, Mutex};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// Default number of pending control commands of each priority
+pub const CONTROL_QUEUE_SIZE: usize = 256;
+
+/// Normal and low priority commands run per poll at most
+pub const CONTROL_BATCH: usize = 32;
+
+/// Urgency of a control command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CommandPriority {
+    /// Run before every quantum of packets
+    High,
+    /// Run once per poll, up to the batch limit
+    Normal,
+    /// Run once per poll after the normal commands, within the same limit
+    Low,
+}
+
+impl CommandPriority {
+    /// Every priority, most urgent first
+    pub const ALL: [CommandPriority; 3] = [
+        CommandPriority::High,
+        CommandPriority::Normal,
+        CommandPriority::Low,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Latency counters of the commands of one priority
+#[derive(Debug, Default)]
+pub struct CommandStats {
+    pub executed: Counter,
+    /// Sum of the times commands waited between submission and execution
+    pub latency_total_ns: Counter,
+    latency_max_ns: AtomicU64,
+}
+
+impl CommandStats {
+    fn record(&self, latency_ns: u64) {
+        self.executed.inc();
+        self.latency_total_ns.add(latency_ns);
+        self.latency_max_ns.fetch_max(latency_ns, Ordering::Relaxed);
+    }
+
+    /// Mean time commands waited before running
+    pub fn mean_latency(&self) -> Duration {
+        match self.executed.get() {
+            0 => Duration::ZERO,
+            executed => Duration::from_nanos(self.latency_total_ns.get() / executed),
+        }
+    }
+
+    /// Longest time a command waited before running
+    pub fn max_latency(&self) -> Duration {
+        Duration::from_nanos(self.latency_max_ns.load(Ordering::Relaxed))
+    }
+}
+
+/// Latency counters of a control queue, by priority
+#[derive(Debug, Default)]
+pub struct ControlStats {
+    by_priority: [CommandStats; 3],
+}
+
+impl ControlStats {
+    /// Counters of the commands of `priority`
+    pub fn priority(&self, priority: CommandPriority) -> &CommandStats {
+        &self.by_priority[priority.index()]
+    }
+}
+
+/// Queued command executed against the dispatcher
+struct ControlJob {
+    run: Box<dyn FnOnce(&Dispatcher) + Send>,
+    /// When the command was submitted
+    queued: Timestamp,
+}
+
+/// Shared state between a reply and its sender
+struct ReplyShared<T> {
+    value: Mutex<Option<Result<T>>>,
+    ready: Condvar,
+    waker: Mutex<Option<Waker>>,
+}
+
+/// Pending response to a control command
+pub struct Reply<T> {
+    shared: Arc<ReplyShared<T>>,
+}
+
+/// Sending half of a [`Reply`]
+struct ReplySender<T> {
+    shared: Option<Arc<ReplyShared<T>>>,
+}
+
+/// Create a connected reply pair
+fn reply_channel<T>() -> (ReplySender<T>, Reply<T>) {
+    let shared = Arc::new(ReplyShared {
+        value: Mutex::new(None),
+        ready: Condvar::new(),
+        waker: Mutex::new(None),
+    });
+
+    (
+        ReplySender {
+            shared: Some(shared.clone()),
+        },
+        Reply { shared },
+    )
+}
+
+impl<T> ReplySender<T> {
+    /// Complete the reply, waking any waiter
+    fn send(mut self, value: Result<T>) {
+        if let Some(shared) = self.shared.take() {
+            *shared.value.lock() = Some(value);
+            shared.ready.notify_all();
+            if let Some(waker) = shared.waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for ReplySender<T> {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            ReplySender {
+                shared: Some(shared),
+            }
+            .send(Err(Error::QueueError(
+                "Control command dropped".to_string(),
+            )));
+        }
+    }
+}
+
+impl<T> Reply<T> {
+    /// Take the response if the command has completed
+    pub fn try_recv(&self) -> Option<Result<T>> {
+        self.shared.value.lock().take()
+    }
+
+    /// Block until the command has completed
+    pub fn wait(self) -> Result<T> {
+        let mut value = self.shared.value.lock();
+        loop {
+            if let Some(result) = value.take() {
+                return result;
+            }
+            self.shared.ready.wait(&mut value);
+        }
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.try_recv() {
+            return Poll::Ready(result);
+        }
+
+        *self.shared.waker.lock() = Some(cx.waker().clone());
+
+        // The sender may have completed before the waker was registered
+        match self.try_recv() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Multi-producer command queue drained by a single dispatcher
+pub(crate) struct ControlQueue {
+    /// Pending commands of each priority
+    rings: [MpscRingBuffer<*mut ControlJob>; 3],
+    draining: AtomicBool,
+    stats: ControlStats,
+}
+
+/// Clears the draining flag when a drain ends, even by a panicking job
+struct DrainGuard<'a>(&'a AtomicBool);
+
+impl Drop for DrainGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+// Jobs are boxed `Send` closures owned by the queue until executed.
+unsafe impl Send for ControlQueue {}
+unsafe impl Sync for ControlQueue {}
+
+impl ControlQueue {
+    /// Create a queue holding up to `capacity` commands of each priority
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            rings: std::array::from_fn(|_| MpscRingBuffer::new(capacity)),
+            draining: AtomicBool::new(false),
+            stats: ControlStats::default(),
+        }
+    }
+
+    /// Queue a job
+    fn push(&self, priority: CommandPriority, job: ControlJob) -> Result<()> {
+        let job = Box::into_raw(Box::new(job));
+        self.rings[priority.index()].push(job).map_err(|_| {
+            drop(unsafe { Box::from_raw(job) });
+            Error::QueueError(format!("Control queue full for {:?} commands", priority))
+        })
+    }
+
+    /// Run the high priority jobs, then up to [`CONTROL_BATCH`] others, returning how many ran
+    ///
+    /// Only one caller drains at a time; concurrent callers return 0.
+    pub(crate) fn drain(&self, dispatcher: &Dispatcher) -> usize {
+        self.exclusive(|| {
+            let executed = self.run(CommandPriority::High, usize::MAX, dispatcher);
+            let normal = self.run(CommandPriority::Normal, CONTROL_BATCH, dispatcher);
+            executed + normal + self.run(CommandPriority::Low, CONTROL_BATCH - normal, dispatcher)
+        })
+    }
+
+    /// Run only the high priority jobs, returning how many ran
+    pub(crate) fn drain_high(&self, dispatcher: &Dispatcher) -> usize {
+        if self.rings[CommandPriority::High.index()].is_empty() {
+            return 0;
+        }
+        self.exclusive(|| self.run(CommandPriority::High, usize::MAX, dispatcher))
+    }
+
+    fn exclusive(&self, drain: impl FnOnce() -> usize) -> usize {
+        if self.draining.swap(true, Ordering::Acquire) {
+            return 0;
+        }
+        let _guard = DrainGuard(&self.draining);
+        drain()
+    }
+
+    fn run(&self, priority: CommandPriority, limit: usize, dispatcher: &Dispatcher) -> usize {
+        let stats = self.stats.priority(priority);
+        let mut executed = 0;
+        while executed < limit {
+            let Ok(job) = self.rings[priority.index()].pop() else {
+                break;
+            };
+            let job = unsafe { Box::from_raw(job) };
+            stats.record(monotonic_now().saturating_sub(job.queued));
+            (job.run)(dispatcher);
+            executed += 1;
+        }
+        executed
+    }
+
+    /// Number of pending commands
+    pub(crate) fn len(&self) -> usize {
+        self.rings.iter().map(MpscRingBuffer::len).sum()
+    }
+
+    pub(crate) fn stats(&self) -> &ControlStats {
+        &self.stats
+    }
+}
+
+impl Drop for ControlQueue {
+    fn drop(&mut self) {
+        // Dropping pending jobs fails their replies
+        for ring in &self.rings {
+            while let Ok(job) = ring.pop() {
+                drop(unsafe { Box::from_raw(job) });
+            }
+        }
+    }
+}
+
+/// Cloneable handle for submitting control commands
+#[derive(Clone)]
+pub struct ControlHandle {
+    queue: Arc<ControlQueue>,
+}
+
+impl ControlHandle {
+    pub(crate) fn new(queue: Arc<ControlQueue>) -> Self {
+        Self { queue }
+    }
+
+    /// Run a closure against the dispatcher at the next safe point
+    pub fn submit<R, F>(&self, f: F) -> Result<Reply<R>>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Dispatcher) -> Result<R> + Send + 'static,
+    {
+        self.submit_with_priority(CommandPriority::Normal, f)
+    }
+
+    /// Run a closure against the dispatcher with the given priority
+    pub fn submit_with_priority<R, F>(&self, priority: CommandPriority, f: F) -> Result<Reply<R>>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Dispatcher) -> Result<R> + Send + 'static,
+    {
+        let (sender, reply) = reply_channel();
+        self.queue.push(
+            priority,
+            ControlJob {
+                run: Box::new(move |dispatcher| {
+                    sender.send(f(dispatcher));
+                }),
+                queued: monotonic_now(),
+            },
+        )?;
+        Ok(reply)
+    }
+
+    /// Run a closure with exclusive access to a registered stack
+    pub fn with_stack<R, F>(&self, stack_id: StackId, f: F) -> Result<Reply<R>>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut UdpStack) -> Result<R> + Send + 'static,
+    {
+        self.with_stack_priority(CommandPriority::Normal, stack_id, f)
+    }
+
+    /// Run a closure with exclusive access to a registered stack, with the given priority
+    pub fn with_stack_priority<R, F>(
+        &self,
+        priority: CommandPriority,
+        stack_id: StackId,
+        f: F,
+    ) -> Result<Reply<R>>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut UdpStack) -> Result<R> + Send + 'static,
+    {
+        self.submit_with_priority(priority, move |dispatcher| {
+            let stack = dispatcher
+                .stack(stack_id)
+                .ok_or_else(|| Error::InvalidConfig(format!("Stack {} not found", stack_id)))?;
+            let mut stack = stack.write();
+            f(&mut stack)
+        })
+    }
+
+    /// Create a socket on a registered stack
+    pub fn create_socket(&self, stack_id: StackId, local_addr: SocketAddr) -> Result<Reply<u16>> {
+        self.with_stack(stack_id, move |stack| stack.create_socket(local_addr))
+    }
+
+    /// Close a socket on a registered stack, with high priority
+    pub fn close_socket(&self, stack_id: StackId, socket_id: u16) -> Result<Reply<()>> {
+        self.with_stack_priority(CommandPriority::High, stack_id, move |stack| {
+            stack.close_socket(socket_id)
+        })
+    }
+
+    /// Start a registered stack
+    pub fn start_stack(&self, stack_id: StackId) -> Result<Reply<()>> {
+        self.with_stack(stack_id, |stack| stack.start())
+    }
+
+    /// Stop a registered stack, with high priority
+    pub fn stop_stack(&self, stack_id: StackId) -> Result<Reply<()>> {
+        self.with_stack_priority(CommandPriority::High, stack_id, |stack| stack.stop())
+    }
+
+    /// Recent drops of an RX queue attached with [`Dispatcher::attach_queues`], oldest first
+    ///
+    /// Diagnostics run with low priority.
+    pub fn drop_events(&self, queue_id: u16) -> Result<Reply<Vec<DropEvent>>> {
+        self.submit_with_priority(CommandPriority::Low, move |dispatcher| {
+            let drop_log = dispatcher.drop_log(queue_id).ok_or_else(|| {
+                Error::InvalidConfig(format!("Drop log of RX queue {} not found", queue_id))
+            })?;
+            Ok(drop_log.events())
+        })
+    }
+
+    /// Number of commands waiting to be processed
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Get the latency counters of the commands run so far
+    pub fn stats(&self) -> &ControlStats {
+        self.queue.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use parking_lot::RwLock;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_commands_run_at_safe_point() {
+        let mut dispatcher = Dispatcher::new();
+        let stack = Arc::new(RwLock::new(UdpStack::new(&Config::default()).unwrap()));
+        let stack_id = dispatcher.register(1000..=1999, stack.clone()).unwrap();
+
+        let handle = dispatcher.control_handle();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1000);
+        let reply = handle.create_socket(stack_id, local_addr).unwrap();
+        let missing = handle.start_stack(99).unwrap();
+
+        assert!(reply.try_recv().is_none());
+        assert_eq!(handle.pending(), 2);
+
+        assert_eq!(dispatcher.process_control(), 2);
+        let socket_id = reply.wait().unwrap();
+        assert!(stack.read().get_socket(socket_id).is_some());
+        assert!(missing.wait().is_err());
+    }
+
+    #[test]
+    fn test_reply_from_other_thread() {
+        let dispatcher = Arc::new(Dispatcher::new());
+        let handle = dispatcher.control_handle();
+
+        let client = std::thread::spawn(move || {
+            handle
+                .submit(|dispatcher| Ok(dispatcher.stack_count()))
+                .unwrap()
+                .wait()
+        });
+
+        while dispatcher.process_control() == 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(client.join().unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_high_priority_runs_first_and_batches_bound_the_rest() {
+        let dispatcher = Dispatcher::new();
+        let handle = dispatcher.control_handle();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let submit = |priority, tag| {
+            let order = order.clone();
+            handle
+                .submit_with_priority(priority, move |_| {
+                    order.lock().push(tag);
+                    Ok(())
+                })
+                .unwrap()
+        };
+        submit(CommandPriority::Low, 'l');
+        for _ in 0..CONTROL_BATCH + 2 {
+            submit(CommandPriority::Normal, 'n');
+        }
+        let urgent = submit(CommandPriority::High, 'h');
+
+        assert_eq!(dispatcher.process_control(), 1 + CONTROL_BATCH);
+        assert!(urgent.wait().is_ok());
+        assert_eq!(order.lock()[..2], ['h', 'n']);
+        // The rest wait for the next poll, normal commands first
+        assert_eq!(handle.pending(), 3);
+        assert_eq!(dispatcher.process_control(), 3);
+        assert_eq!(order.lock().last(), Some(&'l'));
+
+        let stats = handle.stats();
+        assert_eq!(stats.priority(CommandPriority::High).executed.get(), 1);
+        let normal = stats.priority(CommandPriority::Normal);
+        assert_eq!(normal.executed.get(), CONTROL_BATCH as u64 + 2);
+        assert!(normal.max_latency() >= normal.mean_latency());
+    }
+
+    #[test]
+    fn test_panicking_command_does_not_stop_draining() {
+        let dispatcher = Dispatcher::new();
+        let handle = dispatcher.control_handle();
+        let failed = handle
+            .submit(|_| -> Result<()> { panic!("command failed") })
+            .unwrap();
+        let drained = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            dispatcher.process_control()
+        }));
+        assert!(drained.is_err());
+        assert!(failed.wait().is_err());
+
+        let reply = handle
+            .submit(|dispatcher| Ok(dispatcher.stack_count()))
+            .unwrap();
+        assert_eq!(dispatcher.process_control(), 1);
+        assert_eq!(reply.wait().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dropped_command_fails_reply() {
+        let dispatcher = Dispatcher::new();
+        let reply = dispatcher.control_handle().submit(|_| Ok(())).unwrap();
+
+        drop(dispatcher);
+        assert!(matches!(reply.wait(), Err(Error::QueueError(_))));
+    }
+}
@@ -0,0 +1,172 @@
+//! Budgeted polling across RX queues
+//!
+//! Queues are served in weighted round-robin: each round a queue may take up
+//! to `weight * POLL_QUANTUM` packets, and rounds repeat until the budget is
+//! spent or every queue is empty. Budget a queue cannot use because it ran
+//! dry goes to the others, so a busy queue is never starved by idle ones.
+
+use crate::udp::Delivery;
+use crate::Result;
+use std::collections::HashMap;
+
+/// Packets a weight-1 queue may take per round
+pub const POLL_QUANTUM: usize = 32;
+
+/// Packet budget and per-queue weights for one poll call
+#[derive(Debug, Clone)]
+pub struct PollBudget {
+    total: usize,
+    weights: HashMap<u16, u32>,
+}
+
+impl PollBudget {
+    /// Budget of `total` packets with every queue weighted 1
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            weights: HashMap::new(),
+        }
+    }
+
+    /// Set the weight of a queue; weight 0 skips the queue
+    pub fn with_weight(mut self, queue: u16, weight: u32) -> Self {
+        self.weights.insert(queue, weight);
+        self
+    }
+
+    /// Total packet budget
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Weight of a queue
+    pub fn weight(&self, queue: u16) -> u32 {
+        self.weights.get(&queue).copied().unwrap_or(1)
+    }
+}
+
+/// Outcome of a budgeted poll
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PollSummary {
+    /// Packets delivered to sockets
+    pub processed: usize,
+    /// Packets received but dropped
+    pub dropped: usize,
+    /// Rounds run over the queues
+    pub rounds: usize,
+}
+
+impl PollSummary {
+    /// Packets taken from the queues
+    pub fn received(&self) -> usize {
+        self.processed + self.dropped
+    }
+}
+
+/// Run weighted rounds over `queues`, starting at index `start`
+///
+/// `recv` handles one packet from a queue and returns `None` once it is empty.
+pub(crate) fn run_rounds<F>(
+    queues: &[u16],
+    start: usize,
+    budget: &PollBudget,
+    mut recv: F,
+) -> Result<PollSummary>
+where
+    F: FnMut(u16) -> Result<Option<Delivery>>,
+{
+    let mut summary = PollSummary::default();
+    let mut active: Vec<u16> = (0..queues.len())
+        .map(|i| queues[(start + i) % queues.len()])
+        .filter(|&queue| budget.weight(queue) > 0)
+        .collect();
+    let mut remaining = budget.total();
+    let mut error = None;
+
+    while remaining > 0 && !active.is_empty() {
+        summary.rounds += 1;
+
+        active.retain(|&queue| {
+            if error.is_some() {
+                return true;
+            }
+            let quantum = (budget.weight(queue) as usize * POLL_QUANTUM).min(remaining);
+            let mut taken = 0;
+            while taken < quantum {
+                match recv(queue) {
+                    Ok(Some(delivery)) => {
+                        if delivery.is_delivered() {
+                            summary.processed += 1;
+                        } else {
+                            summary.dropped += 1;
+                        }
+                        taken += 1;
+                    }
+                    Ok(None) => {
+                        remaining -= taken;
+                        return false;
+                    }
+                    Err(e) => {
+                        remaining -= taken;
+                        error.get_or_insert(e);
+                        return false;
+                    }
+                }
+            }
+            remaining -= taken;
+            true
+        });
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::DropReason;
+
+    /// Fake queues holding a number of pending packets each
+    fn drain(pending: &mut HashMap<u16, usize>, queue: u16) -> Result<Option<Delivery>> {
+        let count = pending.get_mut(&queue).unwrap();
+        if *count == 0 {
+            return Ok(None);
+        }
+        *count -= 1;
+        Ok(Some(if count.is_multiple_of(4) {
+            Delivery::Dropped(DropReason::QueueFull)
+        } else {
+            Delivery::Delivered
+        }))
+    }
+
+    #[test]
+    fn test_budget_is_shared_by_weight() {
+        let mut pending: HashMap<u16, usize> = [(0, 1000), (1, 1000)].into_iter().collect();
+        let budget = PollBudget::new(128).with_weight(1, 3);
+
+        let summary = run_rounds(&[0, 1], 0, &budget, |queue| drain(&mut pending, queue)).unwrap();
+        assert_eq!(summary.received(), 128);
+        assert_eq!(summary.rounds, 1);
+        assert_eq!(1000 - pending[&0], 32);
+        assert_eq!(1000 - pending[&1], 96);
+    }
+
+    #[test]
+    fn test_idle_queues_leave_budget_to_busy_ones() {
+        let mut pending: HashMap<u16, usize> = [(0, 5), (1, 500), (2, 500)].into_iter().collect();
+        let budget = PollBudget::new(200).with_weight(2, 0);
+
+        let summary =
+            run_rounds(&[0, 1, 2], 1, &budget, |queue| drain(&mut pending, queue)).unwrap();
+        assert_eq!(summary.received(), 200);
+        assert_eq!(pending[&0], 0);
+        assert_eq!(pending[&1], 500 - 195);
+        assert_eq!(pending[&2], 500);
+        assert!(summary.dropped > 0);
+    }
+}
@@ -0,0 +1,998 @@
+//! Shared RX dispatcher for multiple UDP stacks
+//!
+//! This module lets several independent `UdpStack` instances share one
+//! poll mode driver. Each stack registers a disjoint range of local ports
+//! and the dispatcher hands every received frame to the owning stack.
+
+use crate::control::{ControlHandle, ControlQueue, ControlStats, CONTROL_QUEUE_SIZE};
+use crate::memory::{FreeBatch, Mbuf, MbufPool};
+use crate::poll::coordination::{CaptureCoordination, CaptureCoordinator};
+use crate::poll::{PollModeDriver, RxQueue};
+use crate::udp::{
+    ChecksumPolicy, ChecksumStats, ChecksumTrust, ChecksumValidator, Delivery, DropLog, DropReason,
+    MibSnapshot, ProtocolMib, Quarantine, UdpPacket, UdpStack,
+};
+use crate::utils::backoff::{Backoff, IdleStrategy};
+use crate::utils::cpu::CpuAffinity;
+use crate::utils::shutdown::ShutdownToken;
+use crate::utils::time::monotonic_now;
+use crate::{Error, Result};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod budget;
+mod rebalance;
+mod reta;
+
+pub(crate) use budget::run_rounds;
+pub use budget::{PollBudget, PollSummary, POLL_QUANTUM};
+use rebalance::RebalanceSchedule;
+pub use rebalance::{
+    QueueLoad, RebalanceConfig, RebalanceDecision, RebalanceListener, RssRebalancer,
+    REBALANCE_HISTORY,
+};
+pub use reta::{flow_hash, RetaBucketStats, RetaTable, DEFAULT_RETA_SIZE};
+
+/// Identifier of a stack registered with a dispatcher
+pub type StackId = u16;
+
+/// Dispatcher statistics
+#[derive(Debug, Default)]
+pub struct DispatcherStats {
+    pub packets_dispatched: AtomicUsize,
+    pub packets_unmatched: AtomicUsize,
+    pub packets_dropped: AtomicUsize,
+    /// Frames skipped by software RSS, handled by the queue owning their bucket
+    pub packets_steered_away: AtomicUsize,
+    /// Undelivered frames by [`DropReason::index`]
+    pub drop_reasons: [AtomicUsize; DropReason::COUNT],
+}
+
+impl DispatcherStats {
+    /// Frames dropped for a given reason
+    pub fn drops(&self, reason: DropReason) -> usize {
+        self.drop_reasons[reason.index()].load(Ordering::Relaxed)
+    }
+}
+
+/// Registered stack and the ports it owns
+struct StackEntry {
+    id: StackId,
+    ports: RangeInclusive<u16>,
+    stack: Arc<RwLock<UdpStack>>,
+}
+
+/// Dispatcher routing received frames to stacks by destination port
+pub struct Dispatcher {
+    /// Registered stacks
+    stacks: Vec<StackEntry>,
+    /// Next stack ID
+    next_stack_id: StackId,
+    /// Pending control-plane commands
+    control: Arc<ControlQueue>,
+    /// Flow hash to queue redirection table
+    reta: RetaTable,
+    /// Each queue handles only the frames of its RETA buckets
+    software_rss: bool,
+    /// Load-driven bucket moves, if set
+    rebalance: Option<RebalanceSchedule>,
+    /// CPU each queue is processed on
+    queue_cpus: RwLock<HashMap<u16, usize>>,
+    /// Drop logs of the RX queues polled, for the control plane
+    drop_logs: RwLock<HashMap<u16, Arc<DropLog>>>,
+    /// Queue served first by the next budgeted poll
+    next_poll_queue: AtomicUsize,
+    /// RX checksum validation stage
+    checksum: ChecksumValidator,
+    /// Protocol counters of every frame dispatched, whichever stack takes it
+    mib: ProtocolMib,
+    /// Copies of suspicious drops, if set
+    quarantine: Option<Arc<Quarantine>>,
+    /// Registry entry of this process, if coordinating captures
+    coordinator: Option<Mutex<CaptureCoordinator>>,
+    /// Cancelled when the dispatcher stops
+    shutdown: RwLock<ShutdownToken>,
+    /// Dispatcher statistics
+    stats: DispatcherStats,
+}
+
+impl Dispatcher {
+    /// Create an empty dispatcher
+    pub fn new() -> Self {
+        Self {
+            stacks: Vec::new(),
+            next_stack_id: 1,
+            control: Arc::new(ControlQueue::new(CONTROL_QUEUE_SIZE)),
+            reta: RetaTable::default(),
+            software_rss: false,
+            rebalance: None,
+            queue_cpus: RwLock::new(HashMap::new()),
+            drop_logs: RwLock::new(HashMap::new()),
+            next_poll_queue: AtomicUsize::new(0),
+            checksum: ChecksumValidator::default(),
+            mib: ProtocolMib::default(),
+            quarantine: None,
+            coordinator: None,
+            shutdown: RwLock::new(ShutdownToken::new()),
+            stats: DispatcherStats::default(),
+        }
+    }
+
+    /// Register a stack for a range of destination ports
+    pub fn register(
+        &mut self,
+        ports: RangeInclusive<u16>,
+        stack: Arc<RwLock<UdpStack>>,
+    ) -> Result<StackId> {
+        if ports.is_empty() {
+            return Err(Error::InvalidConfig("Empty port range".to_string()));
+        }
+
+        if let Some(entry) = self
+            .stacks
+            .iter()
+            .find(|entry| ports.start() <= entry.ports.end() && entry.ports.start() <= ports.end())
+        {
+            return Err(Error::InvalidConfig(format!(
+                "Port range {}-{} overlaps stack {}",
+                ports.start(),
+                ports.end(),
+                entry.id
+            )));
+        }
+
+        let id = self.next_stack_id;
+        self.next_stack_id += 1;
+        self.stacks.push(StackEntry { id, ports, stack });
+
+        Ok(id)
+    }
+
+    /// Remove a stack, returning it to the caller
+    pub fn unregister(&mut self, id: StackId) -> Option<Arc<RwLock<UdpStack>>> {
+        let index = self.stacks.iter().position(|entry| entry.id == id)?;
+        Some(self.stacks.remove(index).stack)
+    }
+
+    /// Get a registered stack by ID
+    pub fn stack(&self, id: StackId) -> Option<&Arc<RwLock<UdpStack>>> {
+        self.stacks
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| &entry.stack)
+    }
+
+    /// Find the stack owning a destination port
+    pub fn stack_for_port(&self, port: u16) -> Option<&Arc<RwLock<UdpStack>>> {
+        self.stacks
+            .iter()
+            .find(|entry| entry.ports.contains(&port))
+            .map(|entry| &entry.stack)
+    }
+
+    /// Number of registered stacks
+    pub fn stack_count(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Hand a received frame to its stack, freeing it to `pool` if nobody takes it
+    ///
+    /// Every call settles ownership exactly once: on `Delivered` the mbuf
+    /// belongs to a socket queue, on `Dropped` it has already been freed.
+    /// Stacks that are not running do not receive traffic. Checksums are
+    /// validated with the default trust of the checksum policy.
+    pub fn dispatch(&self, mbuf: *mut Mbuf, pool: &MbufPool) -> Result<Delivery> {
+        let mut dropped = FreeBatch::new(pool);
+        let delivery =
+            self.dispatch_with_trust(mbuf, &mut dropped, self.checksum.policy().default, None)?;
+        dropped.flush()?;
+        Ok(delivery)
+    }
+
+    /// Hand a frame received on RX queue `queue_id` to its stack, see [`Dispatcher::dispatch`]
+    ///
+    /// With software RSS on, a frame of a bucket another queue owns is
+    /// freed and `None` returned: that queue handles its own copy.
+    pub fn dispatch_on_queue(
+        &self,
+        queue_id: u16,
+        mbuf: *mut Mbuf,
+        pool: &MbufPool,
+    ) -> Result<Option<Delivery>> {
+        let mut dropped = FreeBatch::new(pool);
+        let delivery = self.receive(
+            queue_id,
+            mbuf,
+            &mut dropped,
+            self.checksum.policy().default,
+            None,
+        )?;
+        dropped.flush()?;
+        Ok(delivery)
+    }
+
+    /// Steer a frame received on `queue_id` and dispatch it if it is the queue's
+    fn receive(
+        &self,
+        queue_id: u16,
+        mbuf: *mut Mbuf,
+        dropped: &mut FreeBatch<'_>,
+        trust: ChecksumTrust,
+        drop_log: Option<&DropLog>,
+    ) -> Result<Option<Delivery>> {
+        let mut bucket = None;
+        if self.software_rss && !mbuf.is_null() {
+            let mbuf_ref = unsafe { &*mbuf };
+            let hash = mbuf_ref
+                .rss_hash()
+                .unwrap_or_else(|| flow_hash(mbuf_ref.data()));
+            bucket = self.reta.claim(hash, mbuf_ref.len, queue_id);
+            if bucket.is_none() {
+                self.stats
+                    .packets_steered_away
+                    .fetch_add(1, Ordering::Relaxed);
+                dropped.push(mbuf)?;
+                return Ok(None);
+            }
+        }
+        let started = self.rebalance.as_ref().map(|_| Instant::now());
+        let delivery = self.dispatch_with_trust(mbuf, dropped, trust, drop_log);
+        if let Some(bucket) = bucket {
+            self.reta.complete(bucket);
+        }
+        if let (Some(schedule), Some(started)) = (&self.rebalance, started) {
+            schedule.record(queue_id, started.elapsed());
+        }
+        delivery.map(Some)
+    }
+
+    /// Hand a received frame to its stack, queueing it on `dropped` if nobody takes it
+    fn dispatch_with_trust(
+        &self,
+        mbuf: *mut Mbuf,
+        dropped: &mut FreeBatch<'_>,
+        trust: ChecksumTrust,
+        drop_log: Option<&DropLog>,
+    ) -> Result<Delivery> {
+        let udp = self.mib.record_ingress(mbuf);
+        let delivery = match UdpPacket::from_mbuf(mbuf) {
+            Err(_) => {
+                if !mbuf.is_null() {
+                    // ICMP carries no port: every stack may learn a path MTU from it
+                    let mbuf = unsafe { &*mbuf };
+                    for entry in &self.stacks {
+                        entry.stack.read().handle_icmp(mbuf);
+                    }
+                }
+                Delivery::Dropped(DropReason::Malformed)
+            }
+            // Parsing succeeded, so the mbuf is non-null and classified
+            Ok(_) if !self.checksum.validate(unsafe { &mut *mbuf }, trust) => {
+                Delivery::Dropped(DropReason::BadChecksum)
+            }
+            Ok(packet) => match self.stack_for_port(packet.dst_addr().port()) {
+                None => Delivery::Dropped(DropReason::Unmatched),
+                Some(stack) => {
+                    let stack = stack.read();
+                    if stack.is_running() {
+                        stack.dispatch(mbuf)
+                    } else {
+                        Delivery::Dropped(DropReason::StackStopped)
+                    }
+                }
+            },
+        };
+
+        self.mib.record_delivery(udp, delivery);
+        match delivery {
+            Delivery::Delivered => {
+                self.stats
+                    .packets_dispatched
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Delivery::Dropped(reason) => {
+                let counter = match reason {
+                    DropReason::Malformed | DropReason::Unmatched => &self.stats.packets_unmatched,
+                    _ => &self.stats.packets_dropped,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                self.stats.drop_reasons[reason.index()].fetch_add(1, Ordering::Relaxed);
+                if let Some(drop_log) = drop_log.filter(|_| !mbuf.is_null()) {
+                    drop_log.record(reason, unsafe { &*mbuf });
+                }
+                if let Some(quarantine) = self.quarantine.as_ref().filter(|_| !mbuf.is_null()) {
+                    quarantine.offer(reason, unsafe { (*mbuf).data() });
+                }
+
+                debug_assert!(
+                    dropped.pool().contains(mbuf),
+                    "dropped mbuf does not belong to the RX pool"
+                );
+                dropped.push(mbuf)?;
+            }
+        }
+
+        Ok(delivery)
+    }
+
+    /// Process incoming packets from one RX queue
+    pub fn process_rx_packets(&self, rx_queue: &RxQueue) -> Result<usize> {
+        self.process_rx_with_trust(rx_queue, self.checksum.policy().default)
+    }
+
+    fn process_rx_with_trust(&self, rx_queue: &RxQueue, trust: ChecksumTrust) -> Result<usize> {
+        let mut processed = 0;
+        let max_batch = rx_queue.batch_size();
+        let mut poller = match rx_queue.poller() {
+            Ok(poller) => poller,
+            // Polled elsewhere, nothing for us
+            Err(Error::QueueError(_)) => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        // Drops of the whole batch go back to the pool together
+        let mut dropped = FreeBatch::new(rx_queue.get_pool());
+        for _ in 0..max_batch {
+            match poller.recv() {
+                Ok(mbuf) => {
+                    if self
+                        .receive(
+                            rx_queue.id(),
+                            mbuf,
+                            &mut dropped,
+                            trust,
+                            Some(rx_queue.drop_log()),
+                        )?
+                        .is_some_and(|delivery| delivery.is_delivered())
+                    {
+                        processed += 1;
+                    }
+                }
+                Err(Error::NetworkError(_)) => break, // No more packets
+                Err(e) => return Err(e),
+            }
+        }
+        dropped.flush()?;
+
+        Ok(processed)
+    }
+
+    /// Get a handle for queueing control-plane commands
+    pub fn control_handle(&self) -> ControlHandle {
+        ControlHandle::new(self.control.clone())
+    }
+
+    /// Run queued control commands, returning how many ran
+    ///
+    /// Called by [`Dispatcher::poll`] before each round of RX batches. Runs
+    /// every high priority command and up to
+    /// [`crate::control::CONTROL_BATCH`] others.
+    pub fn process_control(&self) -> usize {
+        self.control.drain(self)
+    }
+
+    /// Get the latency counters of the control commands run so far
+    pub fn control_stats(&self) -> &ControlStats {
+        self.control.stats()
+    }
+
+    /// Process incoming packets from every RX queue of a driver
+    pub fn poll(&self, pmd: &PollModeDriver) -> Result<usize> {
+        let mut processed = 0;
+        let mut queue_id = 0;
+        let trust = self.checksum_trust(pmd);
+
+        self.process_control();
+        self.rebalance_if_due();
+        self.run_stack_timers();
+
+        while let Some(rx_queue) = pmd.get_rx_queue(queue_id) {
+            processed += self.process_rx_with_trust(rx_queue, trust)?;
+            queue_id += 1;
+        }
+
+        Ok(processed)
+    }
+
+    /// Poll every RX queue of a driver in weighted round-robin within a budget
+    ///
+    /// The first queue served rotates between calls so no queue is always
+    /// polled first. High priority control commands run again after every
+    /// [`POLL_QUANTUM`] packets. A due rebalancing runs first, see
+    /// [`Dispatcher::set_rebalancer`], and so does the release of datagrams
+    /// reorder buffers held past their delay.
+    pub fn poll_budget(&self, pmd: &PollModeDriver, budget: &PollBudget) -> Result<PollSummary> {
+        self.process_control();
+        self.rebalance_if_due();
+        self.run_stack_timers();
+
+        let queues: Vec<u16> = pmd.rx_queues().map(|rx_queue| rx_queue.id()).collect();
+        if queues.is_empty() {
+            return Ok(PollSummary::default());
+        }
+
+        let trust = self.checksum_trust(pmd);
+        let start = self.next_poll_queue.fetch_add(1, Ordering::Relaxed) % queues.len();
+        let mut since_control = 0;
+        run_rounds(&queues, start, budget, |queue_id| {
+            since_control += 1;
+            if since_control == POLL_QUANTUM {
+                since_control = 0;
+                self.control.drain_high(self);
+            }
+            let rx_queue = match pmd.get_rx_queue(queue_id) {
+                Some(rx_queue) => rx_queue,
+                None => return Ok(None),
+            };
+            // Frames left to other queues do not count against the budget
+            loop {
+                match rx_queue.recv() {
+                    Ok(mbuf) => {
+                        let mut dropped = FreeBatch::new(rx_queue.get_pool());
+                        let delivery = self.receive(
+                            queue_id,
+                            mbuf,
+                            &mut dropped,
+                            trust,
+                            Some(rx_queue.drop_log()),
+                        )?;
+                        dropped.flush()?;
+                        if delivery.is_some() {
+                            return Ok(delivery);
+                        }
+                    }
+                    Err(Error::NetworkError(_)) => return Ok(None), // No more packets
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+
+    /// Copy the frames dropped for the reasons `quarantine` wants into it
+    pub fn set_quarantine(&mut self, quarantine: Arc<Quarantine>) {
+        self.quarantine = Some(quarantine);
+    }
+
+    /// Coordinate RX captures with other processes, see [`crate::poll::coordination`]
+    ///
+    /// [`Dispatcher::run`] then advertises the ports of every registered
+    /// stack and refreshes the filters every [`CaptureCoordination::refresh`].
+    pub fn set_capture_coordination(&mut self, coordination: &CaptureCoordination) -> Result<()> {
+        self.coordinator = Some(Mutex::new(CaptureCoordinator::new(coordination)?));
+        Ok(())
+    }
+
+    /// Advertise the ports of every stack and refilter the RX captures of `pmd`
+    ///
+    /// Does nothing without [`Dispatcher::set_capture_coordination`].
+    pub fn coordinate_capture(&self, pmd: &PollModeDriver) -> Result<()> {
+        let Some(coordinator) = &self.coordinator else {
+            return Ok(());
+        };
+        let ports: BTreeSet<u16> = self
+            .stacks
+            .iter()
+            .flat_map(|entry| entry.stack.read().local_ports())
+            .collect();
+        coordinator.lock().refresh(ports, pmd.rx_queues())?;
+        Ok(())
+    }
+
+    /// Set the checksum trust of each interface, resetting checksum counters
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.checksum = ChecksumValidator::new(policy);
+    }
+
+    /// Get checksum verdict counters
+    pub fn checksum_stats(&self) -> &ChecksumStats {
+        self.checksum.stats()
+    }
+
+    /// Get the per-layer protocol counters of dispatched frames
+    pub fn protocol_stats(&self) -> MibSnapshot {
+        self.mib.snapshot()
+    }
+
+    fn checksum_trust(&self, pmd: &PollModeDriver) -> ChecksumTrust {
+        self.checksum.policy().trust_for(&pmd.device_info().name)
+    }
+
+    /// Poll a driver within `budget` until the dispatcher or driver shuts down
+    ///
+    /// Polls that receive nothing are followed by a wait as eager as the
+    /// most eager idle strategy of the driver's RX queues, see
+    /// [`crate::utils::backoff`]. Capture coordination, if set, is
+    /// refreshed between polls. Returns the totals over every poll.
+    pub fn run(&self, pmd: &PollModeDriver, budget: &PollBudget) -> Result<PollSummary> {
+        self.attach_queues(pmd);
+        let shutdown = self.shutdown_token();
+        let mut total = PollSummary::default();
+        let strategy = pmd
+            .rx_queues()
+            .map(|rx_queue| rx_queue.idle_strategy())
+            .reduce(IdleStrategy::eager)
+            .unwrap_or_default();
+        let mut backoff = Backoff::new(strategy);
+
+        while !shutdown.is_cancelled() && !pmd.shutdown_token().is_cancelled() {
+            if let Some(coordinator) = &self.coordinator {
+                // Between polls, so no queue is claimed by a poller
+                if coordinator.lock().is_due() {
+                    if let Err(e) = self.coordinate_capture(pmd) {
+                        log::warn!("Capture coordination refresh failed: {}", e);
+                    }
+                }
+            }
+            let summary = self.poll_budget(pmd, budget)?;
+            total.processed += summary.processed;
+            total.dropped += summary.dropped;
+            total.rounds += summary.rounds;
+            backoff.after_poll(summary.received());
+        }
+
+        Ok(total)
+    }
+
+    /// Take on the drop logs and CPUs of a driver's RX queues
+    ///
+    /// Makes the drop logs readable through the control plane and assigns
+    /// the CPUs queues were configured with, see [`crate::QueueConfig`].
+    /// Done by [`Dispatcher::run`]; callers polling with
+    /// [`Dispatcher::poll`] or [`Dispatcher::poll_budget`] attach once.
+    pub fn attach_queues(&self, pmd: &PollModeDriver) {
+        let mut drop_logs = self.drop_logs.write();
+        for rx_queue in pmd.rx_queues() {
+            drop_logs.insert(rx_queue.id(), rx_queue.drop_log().clone());
+            if let Some(cpu) = rx_queue.cpu() {
+                self.set_queue_cpu(rx_queue.id(), cpu);
+            }
+        }
+    }
+
+    /// Drop log of an attached RX queue
+    pub fn drop_log(&self, queue_id: u16) -> Option<Arc<DropLog>> {
+        self.drop_logs.read().get(&queue_id).cloned()
+    }
+
+    /// Attach the dispatcher to a parent shutdown token
+    pub fn set_shutdown_token(&self, token: ShutdownToken) {
+        *self.shutdown.write() = token;
+    }
+
+    /// Get the token cancelled when the dispatcher stops
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        self.shutdown.read().clone()
+    }
+
+    /// Start all registered stacks
+    ///
+    /// A shutdown token cancelled by an earlier stop is renewed.
+    pub fn start(&self) -> Result<()> {
+        {
+            let mut shutdown = self.shutdown.write();
+            if shutdown.is_cancelled() {
+                *shutdown = shutdown.renew();
+            }
+        }
+        for entry in &self.stacks {
+            entry.stack.write().start()?;
+        }
+        Ok(())
+    }
+
+    /// Stop all registered stacks, cancelling the shutdown token
+    pub fn stop(&self) -> Result<()> {
+        self.shutdown.read().cancel();
+        for entry in &self.stacks {
+            entry.stack.write().stop()?;
+        }
+        Ok(())
+    }
+
+    /// Get the redirection table
+    pub fn reta(&self) -> &RetaTable {
+        &self.reta
+    }
+
+    /// Replace the redirection table with `size` buckets over `queue_count` queues
+    pub fn configure_reta(&mut self, size: usize, queue_count: u16) -> Result<()> {
+        self.reta = RetaTable::new(size, queue_count)?;
+        Ok(())
+    }
+
+    /// Let each RX queue handle only the frames of its RETA buckets
+    ///
+    /// For drivers whose queues each capture the whole interface: a flow is
+    /// then processed on one queue, the one [`Dispatcher::reta`] assigns its
+    /// bucket to, and moves with it.
+    pub fn set_software_rss(&mut self, enabled: bool) {
+        self.software_rss = enabled;
+    }
+
+    /// Move RETA buckets off busy queues every `interval`, see [`RssRebalancer`]
+    ///
+    /// Runs from [`Dispatcher::poll`] and [`Dispatcher::poll_budget`] on the
+    /// time spent on each queue's frames. Moves take effect on the frames
+    /// software RSS steers.
+    pub fn set_rebalancer(&mut self, rebalancer: RssRebalancer, interval: Duration) {
+        self.rebalance = Some(RebalanceSchedule::new(rebalancer, interval));
+    }
+
+    /// Get the rebalancer, if set
+    pub fn rebalancer(&self) -> Option<&RssRebalancer> {
+        self.rebalance
+            .as_ref()
+            .map(|schedule| schedule.rebalancer())
+    }
+
+    /// Rebalance the RETA now, returning the moves made
+    pub fn rebalance(&self) -> Vec<RebalanceDecision> {
+        self.rebalance
+            .as_ref()
+            .map_or_else(Vec::new, |schedule| schedule.run(&self.reta))
+    }
+
+    /// Queue the datagrams every stack's reorder buffers held too long
+    fn run_stack_timers(&self) {
+        let now = monotonic_now();
+        for entry in &self.stacks {
+            let stack = entry.stack.read();
+            stack.flush_reorder_buffers(now);
+            stack.expire_source_ports(now);
+            stack.run_neighbor_timers();
+        }
+    }
+
+    fn rebalance_if_due(&self) {
+        if let Some(schedule) = &self.rebalance {
+            for decision in schedule.run_if_due(&self.reta) {
+                log::debug!(
+                    "RSS bucket {} moves from queue {} to queue {}",
+                    decision.bucket,
+                    decision.from,
+                    decision.to
+                );
+            }
+        }
+    }
+
+    /// Assign the CPU a queue is processed on
+    pub fn set_queue_cpu(&self, queue: u16, cpu: usize) {
+        self.queue_cpus.write().insert(queue, cpu);
+    }
+
+    /// CPU assigned to a queue
+    pub fn queue_cpu(&self, queue: u16) -> Option<usize> {
+        self.queue_cpus.read().get(&queue).copied()
+    }
+
+    /// All queue to CPU assignments
+    pub fn queue_cpu_map(&self) -> HashMap<u16, usize> {
+        self.queue_cpus.read().clone()
+    }
+
+    /// Pin the calling thread to the CPU assigned to a queue
+    pub fn pin_to_queue_cpu(&self, queue: u16) -> Result<()> {
+        let cpu = self
+            .queue_cpu(queue)
+            .ok_or_else(|| Error::InvalidConfig(format!("No CPU assigned to queue {}", queue)))?;
+        CpuAffinity::new()?.set_thread_affinity(&[cpu])
+    }
+
+    /// Get dispatcher statistics
+    pub fn stats(&self) -> &DispatcherStats {
+        &self.stats
+    }
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::testing::{self, FrameBuilder, Malformation};
+    use crate::Config;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn stack() -> Arc<RwLock<UdpStack>> {
+        Arc::new(RwLock::new(UdpStack::new(&Config::default()).unwrap()))
+    }
+
+    fn udp_frame(pool: &MbufPool, dst_port: u16) -> *mut Mbuf {
+        testing::load(pool, &FrameBuilder::to_port(dst_port).build())
+    }
+
+    fn corrupt_frame(pool: &MbufPool, dst_port: u16) -> *mut Mbuf {
+        let frame = FrameBuilder::to_port(dst_port).malformed(Malformation::BadUdpChecksum);
+        testing::load(pool, &frame)
+    }
+
+    #[test]
+    fn test_own_frames_do_not_end_the_rx_batch() {
+        use crate::poll::SelfFilter;
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 8, 2048).unwrap());
+        let mut dispatcher = Dispatcher::new();
+        let stack = stack();
+        stack.write().set_rx_pool(pool.clone());
+        let id = stack
+            .write()
+            .create_socket("0.0.0.0:5000".parse().unwrap())
+            .unwrap();
+        stack.write().start().unwrap();
+        dispatcher.register(5000..=5000, stack.clone()).unwrap();
+
+        let frame = FrameBuilder::to_port(5000).build();
+        let own_mac = [0x02, 0, 0, 0, 0, 0x09];
+        let mut own = frame.clone();
+        own[6..12].copy_from_slice(&own_mac);
+        let rx_queue = RxQueue::in_memory(0, vec![frame.clone(), own, frame], pool.clone())
+            .with_self_filter(SelfFilter::new(vec![own_mac]));
+
+        // The frame behind our own looped-back frame comes in the same batch
+        assert_eq!(dispatcher.process_rx_packets(&rx_queue).unwrap(), 2);
+        assert_eq!(rx_queue.stats().self_filtered.get(), 1);
+        assert_eq!(rx_queue.stats().packets_received.get(), 2);
+        let stack = stack.read();
+        let socket = stack.get_socket(id).unwrap();
+        for _ in 0..2 {
+            socket.release(socket.recv().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_register_rejects_overlap() {
+        let mut dispatcher = Dispatcher::new();
+        let id = dispatcher.register(1000..=1999, stack()).unwrap();
+
+        assert!(dispatcher.register(1500..=2500, stack()).is_err());
+        assert!(dispatcher.register(2000..=2999, stack()).is_ok());
+        assert_eq!(dispatcher.stack_count(), 2);
+
+        assert!(dispatcher.unregister(id).is_some());
+        assert!(dispatcher.register(1500..=1600, stack()).is_ok());
+    }
+
+    #[test]
+    fn test_stop_cancels_shutdown_token() {
+        let dispatcher = Dispatcher::new();
+        let root = ShutdownToken::new();
+        dispatcher.set_shutdown_token(root.child());
+
+        let token = dispatcher.shutdown_token();
+        dispatcher.stop().unwrap();
+        assert!(token.is_cancelled());
+        assert!(!root.is_cancelled());
+
+        // Restarting renews the token under the same parent
+        dispatcher.start().unwrap();
+        let token = dispatcher.shutdown_token();
+        assert!(!token.is_cancelled());
+        root.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_reta_and_queue_cpus() {
+        let mut dispatcher = Dispatcher::new();
+        assert_eq!(dispatcher.reta().size(), DEFAULT_RETA_SIZE);
+
+        dispatcher.configure_reta(16, 4).unwrap();
+        assert_eq!(dispatcher.reta().get(6), Some(2));
+        assert!(dispatcher.configure_reta(10, 4).is_err());
+
+        dispatcher.set_queue_cpu(2, 0);
+        assert_eq!(dispatcher.queue_cpu(2), Some(0));
+        assert_eq!(dispatcher.queue_cpu_map().len(), 1);
+        assert!(dispatcher.pin_to_queue_cpu(3).is_err());
+    }
+
+    #[test]
+    fn test_software_rss_steers_and_rebalances() {
+        let pool = MbufPool::new("rx".to_string(), 8, 2048).unwrap();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.configure_reta(8, 2).unwrap();
+        dispatcher.set_software_rss(true);
+        let rebalancer = RssRebalancer::new(RebalanceConfig {
+            max_moves: 1,
+            ..RebalanceConfig::default()
+        });
+        dispatcher.set_rebalancer(rebalancer, Duration::from_secs(3600));
+
+        let port_of = |bucket: usize| {
+            (1024..)
+                .find(|&port| {
+                    flow_hash(&FrameBuilder::to_port(port).build()) as usize & 7 == bucket
+                })
+                .unwrap()
+        };
+        // Each queue sees every frame, and only one handles it
+        let handled_on = |port: u16| -> Vec<u16> {
+            (0..2)
+                .filter(|&queue| {
+                    let mbuf = udp_frame(&pool, port);
+                    dispatcher
+                        .dispatch_on_queue(queue, mbuf, &pool)
+                        .unwrap()
+                        .is_some()
+                })
+                .collect()
+        };
+        // Even buckets are on queue 0, odd ones on queue 1
+        for (bucket, count) in [(0, 20), (2, 40), (4, 10), (6, 10), (1, 10)] {
+            for _ in 0..count {
+                assert_eq!(handled_on(port_of(bucket)), [(bucket % 2) as u16]);
+            }
+        }
+        let stats = dispatcher.stats();
+        assert_eq!(stats.packets_steered_away.load(Ordering::Relaxed), 90);
+        assert_eq!(pool.stats().in_use, 0);
+
+        // A flow follows its bucket
+        dispatcher.reta().set(0, 1).unwrap();
+        assert_eq!(handled_on(port_of(0)), [1]);
+        dispatcher.reta().set(0, 0).unwrap();
+
+        // Queue 0 took most of the time: its bucket of 20 packets moves
+        let schedule = dispatcher.rebalance.as_ref().unwrap();
+        schedule.record(0, Duration::from_millis(90));
+        schedule.record(1, Duration::from_millis(10));
+        let decisions = dispatcher.rebalance();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(
+            (decisions[0].bucket, decisions[0].from, decisions[0].to),
+            (0, 0, 1)
+        );
+        assert_eq!(dispatcher.reta().get(0), Some(1));
+        assert_eq!(handled_on(port_of(0)), [1]);
+        assert_eq!(dispatcher.rebalancer().unwrap().recent(), decisions);
+    }
+
+    #[test]
+    fn test_dispatch_by_port_range() {
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 8, 2048).unwrap());
+        let tenant_a = stack();
+        let tenant_b = stack();
+
+        for (stack, port) in [(&tenant_a, 1000), (&tenant_b, 2000)] {
+            let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+            stack.write().create_socket(local).unwrap();
+        }
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(1000..=1999, tenant_a.clone()).unwrap();
+        dispatcher.register(2000..=2999, tenant_b.clone()).unwrap();
+
+        // Stopped stacks do not receive traffic
+        assert_eq!(
+            dispatcher.dispatch(udp_frame(&pool, 1000), &pool).unwrap(),
+            Delivery::Dropped(DropReason::StackStopped)
+        );
+
+        dispatcher.start().unwrap();
+        assert!(dispatcher
+            .dispatch(udp_frame(&pool, 1000), &pool)
+            .unwrap()
+            .is_delivered());
+        assert!(dispatcher
+            .dispatch(udp_frame(&pool, 2000), &pool)
+            .unwrap()
+            .is_delivered());
+        assert_eq!(
+            dispatcher.dispatch(udp_frame(&pool, 3000), &pool).unwrap(),
+            Delivery::Dropped(DropReason::Unmatched)
+        );
+
+        assert_eq!(tenant_a.read().stats().total_packets_received, 1);
+        assert_eq!(tenant_b.read().stats().total_packets_received, 1);
+        assert_eq!(
+            dispatcher
+                .stats()
+                .packets_dispatched
+                .load(Ordering::Relaxed),
+            2
+        );
+        assert_eq!(
+            dispatcher.stats().packets_unmatched.load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            dispatcher.stats().packets_dropped.load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(pool.stats().available, 6);
+    }
+
+    #[test]
+    fn test_queue_full_frees_once() {
+        let pool = MbufPool::new("rx".to_string(), 1100, 128).unwrap();
+        let tenant = stack();
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1000);
+        let socket_id = tenant.write().create_socket(local).unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(1000..=1000, tenant.clone()).unwrap();
+        dispatcher.start().unwrap();
+
+        let mut delivered = 0;
+        let mut dropped = 0;
+        for _ in 0..1050 {
+            match dispatcher.dispatch(udp_frame(&pool, 1000), &pool).unwrap() {
+                Delivery::Delivered => delivered += 1,
+                Delivery::Dropped(reason) => {
+                    assert_eq!(reason, DropReason::QueueFull);
+                    dropped += 1;
+                }
+            }
+        }
+        assert!(dropped > 0);
+
+        // Queued mbufs are still out, dropped ones are back in the pool
+        assert_eq!(pool.stats().available, 1100 - delivered);
+        assert_eq!(dispatcher.stats().drops(DropReason::QueueFull), dropped);
+        assert_eq!(
+            tenant
+                .read()
+                .get_socket(socket_id)
+                .unwrap()
+                .stats()
+                .packets_dropped
+                .get(),
+            dropped as u64
+        );
+    }
+
+    #[test]
+    fn test_checksum_policy_drops_bad_frames() {
+        let pool = MbufPool::new("rx".to_string(), 8, 2048).unwrap();
+        let tenant = stack();
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 1000);
+        tenant.write().create_socket(local).unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(1000..=1000, tenant.clone()).unwrap();
+        dispatcher.start().unwrap();
+
+        // Checksums are trusted by default
+        assert!(dispatcher
+            .dispatch(corrupt_frame(&pool, 1000), &pool)
+            .unwrap()
+            .is_delivered());
+
+        dispatcher.set_checksum_policy(ChecksumPolicy::new(ChecksumTrust::TrustHardware));
+        assert_eq!(
+            dispatcher
+                .dispatch(corrupt_frame(&pool, 1000), &pool)
+                .unwrap(),
+            Delivery::Dropped(DropReason::BadChecksum)
+        );
+
+        // Hardware verdicts skip software verification
+        let offloaded = corrupt_frame(&pool, 1000);
+        unsafe {
+            crate::udp::classify(&mut *offloaded);
+            (*offloaded).set_checksum_status(
+                crate::memory::ChecksumStatus::Good,
+                crate::memory::ChecksumStatus::Good,
+            );
+        }
+        assert!(dispatcher
+            .dispatch(offloaded, &pool)
+            .unwrap()
+            .is_delivered());
+
+        let checksum = dispatcher.checksum_stats();
+        assert_eq!(checksum.software_bad.load(Ordering::Relaxed), 1);
+        assert_eq!(checksum.hardware_good.load(Ordering::Relaxed), 1);
+        assert_eq!(dispatcher.stats().drops(DropReason::BadChecksum), 1);
+        assert_eq!(pool.stats().available, 8 - 2);
+    }
+}
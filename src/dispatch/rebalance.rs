@@ -0,0 +1,390 @@
+//! Load-driven RSS rebalancing
+//!
+//! A static bucket-to-queue mapping keeps a queue overloaded for as long as
+//! its flows stay busy. [`RssRebalancer::run`] takes a sample of each
+//! queue's occupancy and busy cycles, and when the busiest queue exceeds
+//! the mean load by [`RebalanceConfig::imbalance_threshold`] it moves hash
+//! buckets from the busiest to the idlest queue with
+//! [`RetaTable::rebalance`], so no flow runs on two queues at once. Buckets
+//! are picked by the packets they carried since the previous run, the
+//! largest that does not overshoot half the gap between the queues. A
+//! bucket that moved stays put for [`RebalanceConfig::cooldown`] so flows
+//! keep their cache affinity and do not oscillate between queues. Each move
+//! is published as a [`RebalanceDecision`] to the listeners added with
+//! [`RssRebalancer::subscribe`] and kept in a short history.
+//!
+//! Given to a dispatcher with [`super::Dispatcher::set_rebalancer`], the
+//! rebalancer runs from the poll loop at a fixed interval on the time the
+//! dispatcher spent on each queue's frames.
+
+use super::RetaTable;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Decisions kept for [`RssRebalancer::recent`]
+pub const REBALANCE_HISTORY: usize = 64;
+
+/// Listener notified of each bucket move
+pub type RebalanceListener = Box<dyn Fn(&RebalanceDecision) + Send + Sync>;
+
+/// Rebalancer settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RebalanceConfig {
+    /// Busiest to mean load ratio that triggers moves
+    pub imbalance_threshold: f64,
+    /// Time a moved bucket stays on its new queue
+    pub cooldown: Duration,
+    /// Buckets moved per run at most
+    pub max_moves: usize,
+}
+
+impl Default for RebalanceConfig {
+    fn default() -> Self {
+        Self {
+            imbalance_threshold: 1.25,
+            cooldown: Duration::from_secs(10),
+            max_moves: 4,
+        }
+    }
+}
+
+/// Load sample of one queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueLoad {
+    pub queue: u16,
+    /// Packets waiting on the queue
+    pub occupancy: usize,
+    /// Cycles, or nanoseconds, spent processing the queue since the previous sample
+    pub busy_cycles: u64,
+}
+
+/// Bucket move made by the rebalancer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebalanceDecision {
+    pub bucket: usize,
+    pub from: u16,
+    pub to: u16,
+    /// Packets the bucket carried since the previous run
+    pub packets: u64,
+    pub at: Instant,
+}
+
+#[derive(Default)]
+struct RebalanceState {
+    /// Bucket packet counters seen by the previous run
+    last_packets: Vec<u64>,
+    /// When each recently moved bucket may move again
+    frozen_until: HashMap<usize, Instant>,
+    history: VecDeque<RebalanceDecision>,
+}
+
+/// Moves RSS buckets off overloaded queues
+pub struct RssRebalancer {
+    config: RebalanceConfig,
+    state: Mutex<RebalanceState>,
+    listeners: Mutex<Vec<RebalanceListener>>,
+}
+
+impl RssRebalancer {
+    /// Create a rebalancer
+    pub fn new(config: RebalanceConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(RebalanceState::default()),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Notify `listener` of every bucket move
+    pub fn subscribe(&self, listener: RebalanceListener) {
+        self.listeners.lock().push(listener);
+    }
+
+    /// Latest decisions, oldest first
+    pub fn recent(&self) -> Vec<RebalanceDecision> {
+        self.state.lock().history.iter().copied().collect()
+    }
+
+    /// Rebalance `reta` for the queue loads sampled, returning the moves made
+    pub fn run(&self, reta: &RetaTable, loads: &[QueueLoad]) -> Vec<RebalanceDecision> {
+        self.run_at(reta, loads, Instant::now())
+    }
+
+    fn run_at(
+        &self,
+        reta: &RetaTable,
+        loads: &[QueueLoad],
+        now: Instant,
+    ) -> Vec<RebalanceDecision> {
+        let mut state = self.state.lock();
+        state.frozen_until.retain(|_, until| *until > now);
+
+        // Packets of each bucket since the previous run
+        let mut deltas: Vec<(usize, u16, u64)> = (0..reta.size())
+            .filter_map(|bucket| {
+                let stats = reta.bucket_stats(bucket)?;
+                let last = state.last_packets.get(bucket).copied().unwrap_or(0);
+                Some((bucket, stats.queue, stats.packets.saturating_sub(last)))
+            })
+            .collect();
+        state.last_packets = (0..reta.size())
+            .map(|bucket| reta.bucket_stats(bucket).map_or(0, |stats| stats.packets))
+            .collect();
+
+        let mut scores = load_scores(loads);
+        let mut decisions = Vec::new();
+        while decisions.len() < self.config.max_moves && scores.len() > 1 {
+            let mean = scores.iter().map(|(_, score)| score).sum::<f64>() / scores.len() as f64;
+            let (busiest, busiest_score) = max_by_score(&scores, |a, b| a > b);
+            let (idlest, _) = max_by_score(&scores, |a, b| a < b);
+            if mean <= 0.0 || busiest_score / mean < self.config.imbalance_threshold {
+                break;
+            }
+
+            let queue_packets = |queue: u16| -> u64 {
+                deltas
+                    .iter()
+                    .filter(|(_, q, _)| *q == queue)
+                    .map(|(_, _, packets)| packets)
+                    .sum()
+            };
+            let (from_packets, to_packets) = (queue_packets(busiest), queue_packets(idlest));
+            let half_gap = from_packets.saturating_sub(to_packets) / 2;
+            let Some(index) = deltas
+                .iter()
+                .enumerate()
+                .filter(|(_, (bucket, queue, packets))| {
+                    *queue == busiest
+                        && *packets > 0
+                        && *packets <= half_gap
+                        && !state.frozen_until.contains_key(bucket)
+                })
+                .max_by_key(|(_, (_, _, packets))| *packets)
+                .map(|(index, _)| index)
+            else {
+                break;
+            };
+
+            let (bucket, _, packets) = deltas[index];
+            if reta.rebalance(bucket, idlest).is_err() {
+                break;
+            }
+            deltas[index].1 = idlest;
+            state
+                .frozen_until
+                .insert(bucket, now + self.config.cooldown);
+
+            // Shift the moved share of load between the two scores
+            let share = packets as f64 / from_packets.max(1) as f64 * busiest_score;
+            for (queue, score) in scores.iter_mut() {
+                if *queue == busiest {
+                    *score -= share;
+                } else if *queue == idlest {
+                    *score += share;
+                }
+            }
+            decisions.push(RebalanceDecision {
+                bucket,
+                from: busiest,
+                to: idlest,
+                packets,
+                at: now,
+            });
+        }
+
+        for decision in &decisions {
+            if state.history.len() == REBALANCE_HISTORY {
+                state.history.pop_front();
+            }
+            state.history.push_back(*decision);
+        }
+        drop(state);
+        let listeners = self.listeners.lock();
+        for decision in &decisions {
+            for listener in listeners.iter() {
+                listener(decision);
+            }
+        }
+        decisions
+    }
+}
+
+impl Default for RssRebalancer {
+    fn default() -> Self {
+        Self::new(RebalanceConfig::default())
+    }
+}
+
+/// Rebalancer run by a dispatcher's poll loop, with the loads it samples
+pub(crate) struct RebalanceSchedule {
+    rebalancer: RssRebalancer,
+    interval: Duration,
+    /// Nanoseconds spent on each queue's frames since the previous run
+    busy: RwLock<HashMap<u16, AtomicU64>>,
+    last_run: Mutex<Instant>,
+}
+
+impl RebalanceSchedule {
+    pub(crate) fn new(rebalancer: RssRebalancer, interval: Duration) -> Self {
+        Self {
+            rebalancer,
+            interval,
+            busy: RwLock::new(HashMap::new()),
+            last_run: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn rebalancer(&self) -> &RssRebalancer {
+        &self.rebalancer
+    }
+
+    /// Count `busy` time spent on a frame of `queue`
+    pub(crate) fn record(&self, queue: u16, busy: Duration) {
+        let nanos = busy.as_nanos() as u64;
+        if let Some(counter) = self.busy.read().get(&queue) {
+            counter.fetch_add(nanos, Ordering::Relaxed);
+            return;
+        }
+        self.busy
+            .write()
+            .entry(queue)
+            .or_default()
+            .fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Run if the interval has passed since the previous run
+    pub(crate) fn run_if_due(&self, reta: &RetaTable) -> Vec<RebalanceDecision> {
+        {
+            let mut last_run = self.last_run.lock();
+            if last_run.elapsed() < self.interval {
+                return Vec::new();
+            }
+            *last_run = Instant::now();
+        }
+        self.run(reta)
+    }
+
+    /// Sample the queue loads and rebalance `reta` on them
+    pub(crate) fn run(&self, reta: &RetaTable) -> Vec<RebalanceDecision> {
+        let busy = self.busy.read();
+        let queues: BTreeSet<u16> = reta
+            .queues()
+            .into_iter()
+            .chain(busy.keys().copied())
+            .collect();
+        let loads: Vec<QueueLoad> = queues
+            .into_iter()
+            .map(|queue| QueueLoad {
+                queue,
+                occupancy: (0..reta.size())
+                    .filter_map(|bucket| reta.bucket_stats(bucket))
+                    .filter(|stats| stats.queue == queue)
+                    .map(|stats| stats.in_flight)
+                    .sum(),
+                busy_cycles: busy
+                    .get(&queue)
+                    .map_or(0, |counter| counter.swap(0, Ordering::Relaxed)),
+            })
+            .collect();
+        drop(busy);
+        self.rebalancer.run(reta, &loads)
+    }
+}
+
+/// Load of each queue relative to the mean, occupancy and cycles weighted alike
+fn load_scores(loads: &[QueueLoad]) -> Vec<(u16, f64)> {
+    let count = loads.len().max(1) as f64;
+    let mean_occupancy = loads.iter().map(|load| load.occupancy as f64).sum::<f64>() / count;
+    let mean_cycles = loads
+        .iter()
+        .map(|load| load.busy_cycles as f64)
+        .sum::<f64>()
+        / count;
+    let ratio = |value: f64, mean: f64| if mean > 0.0 { value / mean } else { 0.0 };
+    loads
+        .iter()
+        .map(|load| {
+            let score = ratio(load.occupancy as f64, mean_occupancy)
+                + ratio(load.busy_cycles as f64, mean_cycles);
+            (load.queue, score)
+        })
+        .collect()
+}
+
+fn max_by_score(scores: &[(u16, f64)], better: impl Fn(f64, f64) -> bool) -> (u16, f64) {
+    scores
+        .iter()
+        .copied()
+        .reduce(|best, candidate| {
+            if better(candidate.1, best.1) {
+                candidate
+            } else {
+                best
+            }
+        })
+        .expect("scores are not empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_moves_buckets_off_busy_queue_with_cooldown() {
+        let reta = RetaTable::new(8, 2).unwrap();
+        let replay = |traffic: &[(u32, usize)]| {
+            for &(hash, packets) in traffic {
+                for _ in 0..packets {
+                    let (bucket, _) = reta.steer(hash, 64);
+                    reta.complete(bucket);
+                }
+            }
+        };
+        // Queue 0 carries buckets 0, 2, 4 and 6, queue 1 the odd ones
+        let traffic = [(0, 20), (2, 40), (4, 10), (6, 10), (1, 10)];
+        let rebalancer = RssRebalancer::new(RebalanceConfig {
+            max_moves: 1,
+            ..RebalanceConfig::default()
+        });
+        let notified = Arc::new(AtomicUsize::new(0));
+        let counter = notified.clone();
+        rebalancer.subscribe(Box::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        let load = |queue, occupancy, busy_cycles| QueueLoad {
+            queue,
+            occupancy,
+            busy_cycles,
+        };
+        let balanced = [load(0, 10, 1000), load(1, 10, 1000)];
+        let skewed = [load(0, 90, 9000), load(1, 10, 1000)];
+        let now = Instant::now();
+        replay(&traffic);
+        assert!(rebalancer.run_at(&reta, &balanced, now).is_empty());
+
+        // Gap of 80 - 10 packets: the hot bucket of 40 would overshoot it
+        replay(&traffic);
+        let decisions = rebalancer.run_at(&reta, &skewed, now);
+        assert_eq!(decisions.len(), 1);
+        let decision = decisions[0];
+        assert_eq!((decision.bucket, decision.from, decision.to), (0, 0, 1));
+        assert_eq!(reta.get(0), Some(1));
+        assert_eq!(notified.load(Ordering::Relaxed), 1);
+
+        // Moved back by hand, the bucket stays frozen until the cooldown ends
+        reta.set(0, 0).unwrap();
+        replay(&[(0, 10), (2, 40)]);
+        assert!(rebalancer
+            .run_at(&reta, &skewed, now + Duration::from_secs(1))
+            .is_empty());
+        replay(&[(0, 10), (2, 40)]);
+        let later = rebalancer.run_at(&reta, &skewed, now + Duration::from_secs(11));
+        assert_eq!(later[0].bucket, 0);
+        assert_eq!(rebalancer.recent(), vec![decision, later[0]]);
+    }
+}
@@ -0,0 +1,331 @@
+//! RSS redirection table
+//!
+//! Maps the low bits of a flow hash to a queue, like a NIC RETA. Buckets can
+//! be moved to another queue immediately or after their in-flight packets
+//! have drained, so a flow is never processed on two queues at once.
+//!
+//! With software RSS on, see [`super::Dispatcher::set_software_rss`], every
+//! RX queue captures the whole interface and handles only the frames whose
+//! bucket the table assigns to it, hashed by [`flow_hash`] unless the mbuf
+//! carries an RSS hash already.
+
+use crate::udp::{ETHERTYPE_IPV4, ETHERTYPE_VLAN, IPPROTO_TCP, IPPROTO_UDP};
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Default number of buckets
+pub const DEFAULT_RETA_SIZE: usize = 128;
+
+/// Marker for a bucket without a pending move
+const NO_PENDING: u32 = u32::MAX;
+
+/// Per-bucket statistics snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetaBucketStats {
+    pub queue: u16,
+    pub packets: u64,
+    pub bytes: u64,
+    pub in_flight: usize,
+    pub pending_queue: Option<u16>,
+}
+
+/// Single redirection table entry
+#[derive(Debug)]
+struct RetaEntry {
+    queue: AtomicU32,
+    pending: AtomicU32,
+    in_flight: AtomicUsize,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl RetaEntry {
+    fn new(queue: u16) -> Self {
+        Self {
+            queue: AtomicU32::new(queue as u32),
+            pending: AtomicU32::new(NO_PENDING),
+            in_flight: AtomicUsize::new(0),
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Hash bucket to queue redirection table
+#[derive(Debug)]
+pub struct RetaTable {
+    entries: Vec<RetaEntry>,
+    mask: usize,
+}
+
+impl RetaTable {
+    /// Create a table of `size` buckets spread round-robin over `queue_count` queues
+    pub fn new(size: usize, queue_count: u16) -> Result<Self> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err(Error::InvalidConfig(format!(
+                "RETA size {} is not a power of two",
+                size
+            )));
+        }
+        if queue_count == 0 {
+            return Err(Error::InvalidConfig(
+                "RETA needs at least one queue".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            entries: (0..size)
+                .map(|bucket| RetaEntry::new((bucket % queue_count as usize) as u16))
+                .collect(),
+            mask: size - 1,
+        })
+    }
+
+    /// Number of buckets
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Bucket a hash falls into
+    pub fn bucket(&self, hash: u32) -> usize {
+        hash as usize & self.mask
+    }
+
+    /// Queue currently assigned to a bucket
+    pub fn get(&self, bucket: usize) -> Option<u16> {
+        self.entries
+            .get(bucket)
+            .map(|entry| entry.queue.load(Ordering::Acquire) as u16)
+    }
+
+    /// Queue assignments of all buckets
+    pub fn queues(&self) -> Vec<u16> {
+        self.entries
+            .iter()
+            .map(|entry| entry.queue.load(Ordering::Acquire) as u16)
+            .collect()
+    }
+
+    /// Move a bucket to a queue immediately
+    ///
+    /// Packets of the bucket still queued on the old queue may be processed
+    /// concurrently with new ones; use [`RetaTable::rebalance`] to avoid that.
+    pub fn set(&self, bucket: usize, queue: u16) -> Result<()> {
+        let entry = self.entry(bucket)?;
+        entry.pending.store(NO_PENDING, Ordering::Release);
+        entry.queue.store(queue as u32, Ordering::Release);
+        Ok(())
+    }
+
+    /// Move a bucket to a queue once its in-flight packets have completed
+    ///
+    /// The move takes effect on the first lookup after the in-flight count
+    /// reaches zero; until then the bucket keeps its current queue.
+    pub fn rebalance(&self, bucket: usize, queue: u16) -> Result<()> {
+        let entry = self.entry(bucket)?;
+        entry.pending.store(queue as u32, Ordering::Release);
+        self.try_commit(entry);
+        Ok(())
+    }
+
+    /// Spread all buckets round-robin over `queue_count` queues, draining each
+    pub fn rebalance_all(&self, queue_count: u16) -> Result<()> {
+        if queue_count == 0 {
+            return Err(Error::InvalidConfig(
+                "RETA needs at least one queue".to_string(),
+            ));
+        }
+        for bucket in 0..self.entries.len() {
+            self.rebalance(bucket, (bucket % queue_count as usize) as u16)?;
+        }
+        Ok(())
+    }
+
+    /// Select the queue for a packet and count it as in flight
+    ///
+    /// Returns the bucket and queue; the consumer must call
+    /// [`RetaTable::complete`] with the bucket once the packet is processed.
+    pub fn steer(&self, hash: u32, bytes: usize) -> (usize, u16) {
+        let bucket = self.bucket(hash);
+        let entry = &self.entries[bucket];
+
+        self.try_commit(entry);
+        entry.in_flight.fetch_add(1, Ordering::AcqRel);
+        entry.packets.fetch_add(1, Ordering::Relaxed);
+        entry.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        (bucket, entry.queue.load(Ordering::Acquire) as u16)
+    }
+
+    /// Steer a packet every queue sees, counting it only on the one it belongs to
+    ///
+    /// Returns the bucket if the packet is for `queue`; the consumer must
+    /// then call [`RetaTable::complete`] with it.
+    pub fn claim(&self, hash: u32, bytes: usize, queue: u16) -> Option<usize> {
+        let bucket = self.bucket(hash);
+        let entry = &self.entries[bucket];
+
+        self.try_commit(entry);
+        if entry.queue.load(Ordering::Acquire) != queue as u32 {
+            return None;
+        }
+        entry.in_flight.fetch_add(1, Ordering::AcqRel);
+        entry.packets.fetch_add(1, Ordering::Relaxed);
+        entry.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        Some(bucket)
+    }
+
+    /// Mark a packet steered to `bucket` as processed
+    pub fn complete(&self, bucket: usize) {
+        if let Some(entry) = self.entries.get(bucket) {
+            let _ = entry
+                .in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                    count.checked_sub(1)
+                });
+        }
+    }
+
+    /// Statistics of a bucket
+    pub fn bucket_stats(&self, bucket: usize) -> Option<RetaBucketStats> {
+        self.entries.get(bucket).map(|entry| {
+            let pending = entry.pending.load(Ordering::Acquire);
+            RetaBucketStats {
+                queue: entry.queue.load(Ordering::Acquire) as u16,
+                packets: entry.packets.load(Ordering::Relaxed),
+                bytes: entry.bytes.load(Ordering::Relaxed),
+                in_flight: entry.in_flight.load(Ordering::Relaxed),
+                pending_queue: (pending != NO_PENDING).then_some(pending as u16),
+            }
+        })
+    }
+
+    /// Buckets ordered by packet count, busiest first
+    pub fn hotspots(&self, count: usize) -> Vec<(usize, RetaBucketStats)> {
+        let mut buckets: Vec<_> = (0..self.entries.len())
+            .filter_map(|bucket| self.bucket_stats(bucket).map(|stats| (bucket, stats)))
+            .collect();
+        buckets.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.packets));
+        buckets.truncate(count);
+        buckets
+    }
+
+    fn entry(&self, bucket: usize) -> Result<&RetaEntry> {
+        self.entries.get(bucket).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "RETA bucket {} out of range (size {})",
+                bucket,
+                self.entries.len()
+            ))
+        })
+    }
+
+    /// Apply a pending move if the bucket has drained
+    fn try_commit(&self, entry: &RetaEntry) {
+        let pending = entry.pending.load(Ordering::Acquire);
+        if pending != NO_PENDING
+            && entry.in_flight.load(Ordering::Acquire) == 0
+            && entry
+                .pending
+                .compare_exchange(pending, NO_PENDING, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            entry.queue.store(pending, Ordering::Release);
+        }
+    }
+}
+
+/// Flow hash of an Ethernet frame for software RSS
+///
+/// Covers the addresses and ports of unfragmented TCP and UDP, and only the
+/// addresses of fragments and other IPv4 traffic, so every fragment of a
+/// datagram falls into one bucket. Frames other than IPv4 hash to 0.
+pub fn flow_hash(frame: &[u8]) -> u32 {
+    let mut ip = frame.get(12..).unwrap_or_default();
+    if ip.len() >= 6 && u16::from_be_bytes([ip[0], ip[1]]) == ETHERTYPE_VLAN {
+        ip = &ip[4..];
+    }
+    if ip.len() < 22 || u16::from_be_bytes([ip[0], ip[1]]) != ETHERTYPE_IPV4 {
+        return 0;
+    }
+    let ip = &ip[2..];
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+    let ports = match ip[9] {
+        IPPROTO_TCP | IPPROTO_UDP if !fragmented && ihl >= 20 => ip.get(ihl..ihl + 4),
+        _ => None,
+    };
+    // FNV-1a over protocol, addresses and ports
+    [&ip[9..10], &ip[12..20], ports.unwrap_or_default()]
+        .concat()
+        .iter()
+        .fold(0x811c_9dc5, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+impl Default for RetaTable {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETA_SIZE, 1).expect("default RETA size is a power of two")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_and_set() {
+        let reta = RetaTable::new(8, 3).unwrap();
+        assert_eq!(reta.queues(), vec![0, 1, 2, 0, 1, 2, 0, 1]);
+        assert!(RetaTable::new(6, 3).is_err());
+
+        reta.set(5, 7).unwrap();
+        assert_eq!(reta.get(5), Some(7));
+        assert_eq!(reta.steer(5 + 8 * 4, 100).1, 7);
+        assert!(reta.set(8, 0).is_err());
+    }
+
+    #[test]
+    fn test_rebalance_waits_for_drain() {
+        let reta = RetaTable::new(4, 2).unwrap();
+        let (bucket, queue) = reta.steer(1, 64);
+        assert_eq!((bucket, queue), (1, 1));
+
+        // In-flight packet keeps the bucket on its old queue
+        reta.rebalance(bucket, 0).unwrap();
+        assert_eq!(reta.steer(1, 64).1, 1);
+        assert_eq!(reta.bucket_stats(bucket).unwrap().pending_queue, Some(0));
+
+        reta.complete(bucket);
+        reta.complete(bucket);
+        assert_eq!(reta.steer(1, 64).1, 0);
+
+        let stats = reta.bucket_stats(bucket).unwrap();
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.bytes, 192);
+        assert_eq!(stats.pending_queue, None);
+        assert_eq!(reta.hotspots(1)[0].0, bucket);
+
+        // Only the owning queue claims, and counts, a packet
+        assert_eq!(reta.claim(2, 64, 1), None);
+        assert_eq!(reta.claim(2, 64, 0), Some(2));
+        assert_eq!(reta.bucket_stats(2).unwrap().packets, 1);
+    }
+
+    #[test]
+    fn test_fragments_share_a_flow_hash() {
+        use crate::udp::testing::FrameBuilder;
+        let frame = FrameBuilder::to_port(5000).build();
+        let other = FrameBuilder::to_port(5001).build();
+        assert_ne!(flow_hash(&frame), flow_hash(&other));
+
+        // A fragment hashes by addresses alone, whatever its offset and ports
+        let mut first = frame.clone();
+        first[20] |= 0x20;
+        let mut later = other.clone();
+        later[21] = 0xB9;
+        assert_eq!(flow_hash(&first), flow_hash(&later));
+        assert_eq!(flow_hash(&[0; 10]), 0);
+    }
+}
@@ -0,0 +1,206 @@
+//! Parser entry points for fuzzing
+//!
+//! Enabled by the `fuzzing` feature. Every function takes untrusted bytes,
+//! runs them through the same code the RX path uses and asserts the
+//! invariants later stages rely on, so a fuzzer only needs to look for
+//! panics. The targets in `fuzz/` call these, and [`seed_corpus`] provides
+//! well-formed starting inputs.
+
+use crate::memory::{Mbuf, PacketType};
+use crate::udp::{
+    self, SequenceWindow, UdpPacket, ETHERTYPE_IPV4, ETHERTYPE_IPV6, IPPROTO_ICMP, IPPROTO_TCP,
+    IPPROTO_UDP, IPV6_HEADER_LEN,
+};
+use crate::utils::pattern::{MatchAction, PatternSet, PatternSetBuilder};
+
+/// Largest frame the entry points accept, matching the default mbuf size
+pub const MAX_FRAME_LEN: usize = 2048;
+
+/// Classify a frame and parse it as UDP when possible
+pub fn parse_frame(data: &[u8]) -> PacketType {
+    let mut buffer = [0u8; MAX_FRAME_LEN];
+    let mut mbuf = Mbuf::new(buffer.as_mut_ptr(), buffer.len());
+    if mbuf.append(&data[..data.len().min(MAX_FRAME_LEN)]).is_err() {
+        return PacketType::Unknown;
+    }
+
+    let packet_type = udp::classify(&mut mbuf);
+    let len = mbuf.data().len();
+
+    if packet_type != PacketType::Unknown && packet_type != PacketType::Ethernet {
+        assert!(mbuf.l3_offset as usize <= len, "l3 offset past frame end");
+        assert!(mbuf.l4_offset as usize <= len, "l4 offset past frame end");
+        assert!(
+            mbuf.payload_offset as usize <= len,
+            "payload offset past frame end"
+        );
+    }
+
+    if let Ok(packet) = UdpPacket::from_mbuf(&mut mbuf) {
+        assert_eq!(packet_type, PacketType::Udp);
+        let payload = packet.payload();
+        assert!(packet.payload_offset + payload.len() <= len);
+        let _ = (packet.src_addr(), packet.dst_addr());
+    }
+
+    packet_type
+}
+
+/// Pattern set exercised by [`scan_payload`]
+pub fn fuzz_pattern_set() -> PatternSet {
+    let mut builder = PatternSetBuilder::new();
+    builder.add(b"GET ", MatchAction::Mark(1));
+    builder.add(b"\x00\x00\x00\x01", MatchAction::Mark(2));
+    builder.add(b"attack", MatchAction::Drop);
+    builder.add(b"a", MatchAction::Mirror);
+    builder.build().expect("fuzz pattern set is valid")
+}
+
+/// Scan a payload with a pattern set and cross-check the match positions
+pub fn scan_payload(patterns: &PatternSet, data: &[u8]) {
+    let matches = patterns.find_all(data);
+    assert_eq!(patterns.is_match(data), !matches.is_empty());
+    for found in matches {
+        assert!(found.end <= data.len(), "match ends past the haystack");
+    }
+}
+
+/// Feed big-endian u64 sequence numbers from `data` to a replay window
+pub fn check_sequences(data: &[u8]) {
+    let window_size = data.first().copied().unwrap_or(0) as u64 * 16 + 1;
+    let mut window = SequenceWindow::new(window_size);
+
+    for chunk in data.get(1..).unwrap_or_default().chunks_exact(8) {
+        let seq = u64::from_be_bytes(chunk.try_into().expect("chunk is 8 bytes"));
+        match window.check_and_record(seq) {
+            udp::SequenceCheck::Accepted => {
+                assert_eq!(window.check(seq), udp::SequenceCheck::Replay);
+                assert!(window.highest().is_some_and(|highest| highest >= seq));
+            }
+            udp::SequenceCheck::Replay | udp::SequenceCheck::TooOld => {}
+        }
+    }
+}
+
+/// Well-formed and edge-case frames for seeding a corpus
+pub fn seed_corpus() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("ipv4_udp", ipv4_frame(IPPROTO_UDP, 5, 0, b"ping")),
+        ("ipv4_udp_options", ipv4_frame(IPPROTO_UDP, 6, 0, b"ping")),
+        ("ipv4_udp_empty", ipv4_frame(IPPROTO_UDP, 5, 0, b"")),
+        (
+            "ipv4_first_fragment",
+            ipv4_frame(IPPROTO_UDP, 5, 0x2000, b"ping"),
+        ),
+        (
+            "ipv4_later_fragment",
+            ipv4_frame(IPPROTO_UDP, 5, 0x0010, b"ping"),
+        ),
+        ("ipv4_tcp", ipv4_frame(IPPROTO_TCP, 5, 0, b"")),
+        (
+            "ipv4_icmp",
+            ipv4_frame(IPPROTO_ICMP, 5, 0, b"\x08\x00\x00\x00"),
+        ),
+        ("ipv6_udp", ipv6_frame(IPPROTO_UDP, b"ping")),
+        (
+            "truncated_ipv4",
+            ipv4_frame(IPPROTO_UDP, 5, 0, b"ping")[..20].to_vec(),
+        ),
+        ("runt", vec![0u8; 13]),
+    ]
+}
+
+/// Build an Ethernet/IPv4 frame carrying a UDP header and payload
+fn ipv4_frame(protocol: u8, ihl: u8, flags_fragment: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = ethernet_header(ETHERTYPE_IPV4);
+
+    let header_len = ihl as usize * 4;
+    let mut ip = vec![0u8; header_len];
+    ip[0] = 0x40 | ihl;
+    ip[2..4].copy_from_slice(&((header_len + 8 + payload.len()) as u16).to_be_bytes());
+    ip[6..8].copy_from_slice(&flags_fragment.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = protocol;
+    ip[12..16].copy_from_slice(&[10, 0, 0, 2]);
+    ip[16..20].copy_from_slice(&[10, 0, 0, 1]);
+    frame.extend_from_slice(&ip);
+
+    frame.extend_from_slice(&udp_header(payload));
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Build an Ethernet/IPv6 frame carrying a UDP header and payload
+fn ipv6_frame(next_header: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = ethernet_header(ETHERTYPE_IPV6);
+
+    let mut ip = vec![0u8; IPV6_HEADER_LEN];
+    ip[0] = 0x60;
+    ip[4..6].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    ip[6] = next_header;
+    ip[7] = 64;
+    ip[23] = 2;
+    ip[39] = 1;
+    frame.extend_from_slice(&ip);
+
+    frame.extend_from_slice(&udp_header(payload));
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn ethernet_header(ether_type: u16) -> Vec<u8> {
+    let mut header = vec![0u8; 14];
+    header[0..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    header[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    header[12..14].copy_from_slice(&ether_type.to_be_bytes());
+    header
+}
+
+fn udp_header(payload: &[u8]) -> [u8; 8] {
+    let mut header = [0u8; 8];
+    header[0..2].copy_from_slice(&6000u16.to_be_bytes());
+    header[2..4].copy_from_slice(&5000u16.to_be_bytes());
+    header[4..6].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seed_corpus_classifies() {
+        let expected = [
+            PacketType::Udp,
+            PacketType::Udp,
+            PacketType::Udp,
+            PacketType::Udp,
+            PacketType::Ipv4,
+            PacketType::Tcp,
+        ];
+        let corpus = seed_corpus();
+        for ((name, frame), expected) in corpus.iter().zip(expected) {
+            assert_eq!(parse_frame(frame), expected, "seed {}", name);
+        }
+        for (_, frame) in &corpus {
+            parse_frame(frame);
+        }
+    }
+
+    #[test]
+    fn test_entry_points_survive_mutations() {
+        let patterns = fuzz_pattern_set();
+        for (_, seed) in seed_corpus() {
+            for cut in 0..=seed.len() {
+                parse_frame(&seed[..cut]);
+                scan_payload(&patterns, &seed[..cut]);
+                check_sequences(&seed[..cut]);
+            }
+            for i in 0..seed.len() {
+                let mut mutated = seed.clone();
+                mutated[i] ^= 0xFF;
+                parse_frame(&mutated);
+            }
+        }
+    }
+}
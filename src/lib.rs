@@ -3,7 +3,11 @@
 //! A DPDK-inspired userspace networking implementation using libpcap,
 //! featuring lock-free concurrency, huge pages, and hardware offloading.
 
+pub mod compat;
+pub mod control;
+pub mod dispatch;
 pub mod memory;
+pub mod packet;
 pub mod poll;
 pub mod queue;
 pub mod udp;
@@ -15,13 +19,50 @@ pub mod numa;
 #[cfg(feature = "hardware-offload")]
 pub mod offload;
 
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+#[cfg(feature = "bench-support")]
+pub mod bench;
+
 // Re-export key components
-pub use memory::{Mbuf, MbufPool, MemoryManager};
-pub use poll::{PollModeDriver, RxQueue, TxQueue};
-pub use queue::{MpmcQueue, RingBuffer, SpscQueue};
-pub use udp::{UdpPacket, UdpSocket, UdpStack};
+pub use control::{CommandPriority, ControlHandle, Reply};
+pub use dispatch::Dispatcher;
+pub use memory::{Mbuf, MbufHandle, MbufPool, MemoryBudget, MemoryManager, ResetPolicy};
+pub use poll::{CaptureDirection, PollModeDriver, QueueConfig, RxPoller, RxQueue, TxQueue};
+pub use queue::{
+    MbufQueue, MpmcQueue, MpmcRingBuffer, MpscRingBuffer, QueueFlavor, RingBuffer, SpmcRingBuffer,
+    SpscQueue, SpscRingBuffer,
+};
+pub use udp::{
+    Bridge, BridgeConfig, Delivery, DropReason, EarlyDropConfig, Forwarder, ForwardingConfig,
+    Quarantine, QuarantineConfig, TrafficClass, TrafficClassMap, TxBuffer, UdpPacket, UdpSocket,
+    UdpStack,
+};
 
+use dispatch::{PollBudget, PollSummary};
+use poll::coordination::{CaptureCoordination, CaptureCoordinator};
+use queue::{QueueWorker, WorkerStats};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use thiserror::Error;
+use udp::{BridgeVerdict, FlowKey, ForwardVerdict};
+use utils::alarm::AlarmSet;
+use utils::backoff::IdleStrategy;
+use utils::config::LayeredConfig;
+use utils::ifstats::{InterfaceMonitor, KernelCounters, PmdCounters};
+use utils::metrics::{self, MetricsRegistry};
+use utils::persist::{
+    InterfaceStats, StatsPersistence, StatsPersister, StatsSnapshot, WorkerCounters,
+};
+use utils::placement::{PlacementEnforcement, PlacementReport};
+use utils::preflight::PreflightReport;
+use utils::sampler::{SamplerConfig, StatsSample, StatsSampler};
+use utils::sflow::{InterfaceCounters, SflowExporter};
+use utils::shutdown::ShutdownToken;
+use utils::sizing::{self, PoolSizing, SizingTargets};
+use utils::time::monotonic_now;
+use utils::warmup::{WarmupConfig, WarmupReport};
 
 /// XPDK error types
 #[derive(Error, Debug)]
@@ -53,8 +94,20 @@ pub enum Error {
     #[error("Hardware offload error: {0}")]
     OffloadError(String),
 
+    #[error("Preflight check failed: {0}")]
+    PreflightError(String),
+
+    #[error("Shutdown error: {0}")]
+    ShutdownError(String),
+
     #[error("PCAP error: {0}")]
     Pcap(#[from] pcap::Error),
+
+    #[error("Memory budget exceeded: {0}")]
+    BudgetExceeded(String),
+
+    #[error("Neighbor unresolved: {0}")]
+    NeighborUnresolved(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -80,20 +133,77 @@ pub struct Config {
     /// TX queue size
     pub tx_queue_size: usize,
 
+    /// Overrides of the RX queue settings by queue, see [`QueueConfig`]
+    pub queues: Vec<QueueConfig>,
+
     /// Enable huge pages
     pub enable_hugepages: bool,
 
     /// Enable NUMA awareness
     pub enable_numa: bool,
 
+    /// What pools clear when an mbuf is freed
+    pub mbuf_reset: ResetPolicy,
+
     /// CPU affinity settings
     pub cpu_affinity: Option<Vec<usize>>,
 
     /// Network interface name
     pub interface: String,
 
+    /// Port ID stamped on frames received from `interface`
+    pub port_id: u16,
+
     /// Hardware offload features
     pub enable_offload: bool,
+
+    /// Link MTU, the path MTU of destinations without a learned one
+    pub mtu: u16,
+
+    /// Strip 802.1Q tags on receive into [`Mbuf::vlan_tci`]
+    pub vlan_strip: bool,
+
+    /// Timestamp received frames on the NIC where the interface supports it
+    pub hw_timestamps: bool,
+
+    /// Frames RX captures see, inbound only unless our own transmissions are wanted
+    pub capture_direction: CaptureDirection,
+
+    /// Recent drops each RX queue keeps for postmortem, see [`udp::DropLog`]
+    pub drop_log_size: usize,
+
+    /// Forward routed IPv4 frames between ports; disabled if `None`
+    pub forwarding: Option<ForwardingConfig>,
+
+    /// Bridge every frame between ports at L2 instead of delivering it; disabled if `None`
+    pub bridge: Option<BridgeConfig>,
+
+    /// Drop early for sockets holding the most buffers while the pool runs low; disabled if `None`
+    pub early_drop: Option<EarlyDropConfig>,
+
+    /// Keep copies of frames dropped as suspicious for inspection; disabled if `None`
+    pub quarantine: Option<QuarantineConfig>,
+
+    /// Traffic class of sent datagrams by DSCP, all best effort by default
+    pub traffic_classes: TrafficClassMap,
+
+    /// Fault in pools and queues on start; disabled if `None`
+    pub warmup: Option<WarmupConfig>,
+
+    /// Audit the NUMA placement of RX queues on start; disabled if `None`
+    pub placement: Option<PlacementEnforcement>,
+
+    /// Bytes pools, socket queues and flow tables may take together, `None` for no limit
+    pub memory_budget: Option<usize>,
+
+    /// Persist interface counters across restarts; disabled if `None`
+    pub stats_persistence: Option<StatsPersistence>,
+
+    /// How polling loops wait on empty queues, unless a [`QueueConfig`] says otherwise
+    pub idle_strategy: IdleStrategy,
+
+    /// Share the interface with other XPDK processes by port; disabled if `None`
+    pub capture_coordination: Option<CaptureCoordination>,
 }
 
 impl Default for Config {
@@ -105,15 +215,234 @@ impl Default for Config {
             tx_queue_count: 4,
             rx_queue_size: 4096,
             tx_queue_size: 4096,
+            queues: Vec::new(),
             enable_hugepages: true,
             enable_numa: true,
+            mbuf_reset: ResetPolicy::Metadata,
             cpu_affinity: None,
             interface: "eth0".to_string(),
+            port_id: 0,
             enable_offload: true,
+            mtu: 1500,
+            vlan_strip: false,
+            hw_timestamps: false,
+            capture_direction: CaptureDirection::Inbound,
+            drop_log_size: udp::DEFAULT_DROP_LOG_SIZE,
+            forwarding: None,
+            bridge: None,
+            early_drop: None,
+            quarantine: None,
+            traffic_classes: TrafficClassMap::default(),
+            warmup: None,
+            placement: None,
+            memory_budget: None,
+            stats_persistence: None,
+            idle_strategy: IdleStrategy::default(),
+            capture_coordination: None,
         }
     }
 }
 
+impl Config {
+    /// Start a [`ConfigBuilder`] from the defaults
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+/// Builder for [`Config`]
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+    targets: Option<SizingTargets>,
+}
+
+impl ConfigBuilder {
+    /// Start from `config` instead of the defaults
+    pub(crate) fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            targets: None,
+        }
+    }
+
+    /// Use the network interface `interface`
+    pub fn with_interface(mut self, interface: &str) -> Self {
+        self.config.interface = interface.to_string();
+        self
+    }
+
+    /// Use `rx` RX queues and `tx` TX queues
+    pub fn with_queues(mut self, rx: usize, tx: usize) -> Self {
+        self.config.rx_queue_count = rx;
+        self.config.tx_queue_count = tx;
+        self
+    }
+
+    /// Override the settings of one RX queue, see [`QueueConfig`]
+    pub fn with_queue(mut self, queue: QueueConfig) -> Self {
+        self.config.queues.push(queue);
+        self
+    }
+
+    /// Use `count` memory pools of `size` mbufs each
+    pub fn with_pools(mut self, count: usize, size: usize) -> Self {
+        self.config.pool_count = count;
+        self.config.pool_size = size;
+        self
+    }
+
+    /// Enable or disable huge pages
+    pub fn with_hugepages(mut self, enable: bool) -> Self {
+        self.config.enable_hugepages = enable;
+        self
+    }
+
+    /// Clear freed mbufs according to `policy`, see [`memory::reset`]
+    pub fn with_mbuf_reset(mut self, policy: ResetPolicy) -> Self {
+        self.config.mbuf_reset = policy;
+        self
+    }
+
+    /// Take receive timestamps on the NIC, see [`poll::RxTimestamping`]
+    pub fn with_hw_timestamps(mut self, enable: bool) -> Self {
+        self.config.hw_timestamps = enable;
+        self
+    }
+
+    /// Capture frames in `direction` on RX, see [`poll::CaptureDirection`]
+    pub fn with_capture_direction(mut self, direction: CaptureDirection) -> Self {
+        self.config.capture_direction = direction;
+        self
+    }
+
+    /// Keep the last `size` drops of each RX queue
+    pub fn with_drop_log_size(mut self, size: usize) -> Self {
+        self.config.drop_log_size = size;
+        self
+    }
+
+    /// Forward routed IPv4 frames according to `forwarding`
+    pub fn with_forwarding(mut self, forwarding: ForwardingConfig) -> Self {
+        self.config.forwarding = Some(forwarding);
+        self
+    }
+
+    /// Act as a transparent learning bridge according to `bridge`
+    pub fn with_bridge(mut self, bridge: BridgeConfig) -> Self {
+        self.config.bridge = Some(bridge);
+        self
+    }
+
+    /// Drop early for the sockets holding the most buffers according to `early_drop`
+    pub fn with_early_drop(mut self, early_drop: EarlyDropConfig) -> Self {
+        self.config.early_drop = Some(early_drop);
+        self
+    }
+
+    /// Quarantine frames dropped as suspicious according to `quarantine`
+    pub fn with_quarantine(mut self, quarantine: QuarantineConfig) -> Self {
+        self.config.quarantine = Some(quarantine);
+        self
+    }
+
+    /// Map the DSCP of sent datagrams to traffic classes with `map`
+    pub fn with_traffic_classes(mut self, map: TrafficClassMap) -> Self {
+        self.config.traffic_classes = map;
+        self
+    }
+
+    /// Warm pools and queues up on start according to `warmup`
+    pub fn with_warmup(mut self, warmup: WarmupConfig) -> Self {
+        self.config.warmup = Some(warmup);
+        self
+    }
+
+    /// Audit queue placement on start, warning or refusing on mismatches
+    pub fn with_placement(mut self, enforcement: PlacementEnforcement) -> Self {
+        self.config.placement = Some(enforcement);
+        self
+    }
+
+    /// Cap the memory of pools, socket queues and flow tables at `bytes`
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.config.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Persist interface counters as set by `persistence`, see [`utils::persist`]
+    pub fn with_stats_persistence(mut self, persistence: StatsPersistence) -> Self {
+        self.config.stats_persistence = Some(persistence);
+        self
+    }
+
+    /// Wait on empty queues according to `strategy`, see [`utils::backoff`]
+    pub fn with_idle_strategy(mut self, strategy: IdleStrategy) -> Self {
+        self.config.idle_strategy = strategy;
+        self
+    }
+
+    /// Capture only this process's share of the interface, see [`poll::coordination`]
+    pub fn with_capture_coordination(mut self, coordination: CaptureCoordination) -> Self {
+        self.config.capture_coordination = Some(coordination);
+        self
+    }
+
+    /// Derive pool and RX queue sizes and the MTU from `targets` on build
+    ///
+    /// Sizes set explicitly are overridden. See [`utils::sizing`].
+    pub fn auto_size(mut self, targets: SizingTargets) -> Self {
+        self.targets = Some(targets);
+        self
+    }
+
+    /// Finish the configuration
+    ///
+    /// Fails with [`Error::InvalidConfig`] if a queue override does not fit
+    /// the queues configured. With [`ConfigBuilder::auto_size`], also fails
+    /// if the targets cannot be met or the pools would not fit in the free
+    /// huge pages.
+    pub fn build(self) -> Result<Config> {
+        let mut config = self.config;
+        if let Some(targets) = self.targets {
+            let sizing = PoolSizing::derive(&targets, &config)?;
+            sizing.check_hugepages(&config, sizing::free_hugepage_bytes())?;
+            sizing.apply(&mut config);
+            config.mtu = targets.mtu;
+        }
+        poll::validate_queue_configs(&config)?;
+        poll::coordination::validate(&config)?;
+        udp::validate_early_drop(&config)?;
+        Ok(config)
+    }
+}
+
+/// Pool, queue, socket or worker found by [`Xpdk::find_by_name`]
+#[derive(Clone)]
+pub enum NamedEntity<'a> {
+    Pool(&'a MbufPool),
+    RxQueue(&'a Arc<RxQueue>),
+    TxQueue(&'a Arc<TxQueue>),
+    Socket(&'a UdpSocket),
+    /// Counters of a worker registered with [`Xpdk::register_worker`]
+    Worker(Arc<WorkerStats>),
+}
+
+/// Names and counters of the workers registered with [`Xpdk::register_worker`]
+type WorkerRegistry = Arc<parking_lot::RwLock<Vec<(String, Arc<WorkerStats>)>>>;
+
+fn worker_counters(workers: &WorkerRegistry) -> Vec<WorkerCounters> {
+    workers
+        .read()
+        .iter()
+        .map(|(name, stats)| WorkerCounters {
+            name: name.clone(),
+            processed: stats.processed.load(Ordering::Relaxed) as u64,
+            errors: stats.errors.load(Ordering::Relaxed) as u64,
+        })
+        .collect()
+}
+
 /// Main XPDK context
 pub struct Xpdk {
     #[allow(dead_code)]
@@ -121,23 +450,157 @@ pub struct Xpdk {
     memory_manager: MemoryManager,
     pmd: PollModeDriver,
     udp_stack: UdpStack,
+    /// L3 forwarder, if forwarding is enabled
+    forwarder: Option<Forwarder>,
+    /// L2 bridge, if bridging is enabled
+    bridge: Option<Bridge>,
+    /// Copies of suspicious drops, if quarantine is enabled
+    quarantine: Option<Arc<Quarantine>>,
+    /// Root of every component's shutdown token
+    shutdown: ShutdownToken,
+    /// Index of the queue served first by the next `poll_once`
+    next_poll_queue: usize,
+    /// Counters restored from the previous run, added to the live ones
+    stats_baseline: InterfaceStats,
+    /// Workers named in lookups and snapshots
+    workers: WorkerRegistry,
+    /// Registry entry of this process, if coordinating captures
+    coordinator: Option<CaptureCoordinator>,
+    /// Outcome of the last warm-up, if one ran
+    warmup: Option<WarmupReport>,
 }
 
 impl Xpdk {
     /// Create a new XPDK instance
+    ///
+    /// Fails with [`Error::PreflightError`] if the environment cannot run
+    /// XPDK; see [`Xpdk::preflight_report`] for the full list of checks.
     pub fn new(config: Config) -> Result<Self> {
-        let memory_manager = MemoryManager::new(&config)?;
-        let pmd = PollModeDriver::new(&config)?;
-        let udp_stack = UdpStack::new(&config)?;
+        Self::preflight_report(&config).check()?;
+
+        let budget = Arc::new(MemoryBudget::new(config.memory_budget));
+        let memory_manager = MemoryManager::with_budget(&config, budget.clone())?;
+        let shutdown = ShutdownToken::new();
+        let mut pmd = PollModeDriver::with_budget(&config, &budget)?;
+        pmd.set_shutdown_token(shutdown.child());
+        let mut udp_stack = UdpStack::new(&config)?;
+        udp_stack.set_memory_budget(budget.clone())?;
+        udp_stack.set_tx_pool(pmd.get_pool().clone());
+        udp_stack.set_rx_pool(pmd.get_pool().clone());
+        let quarantine = config.quarantine.clone().map(Quarantine::new).map(Arc::new);
+        let forwarder = match &config.forwarding {
+            Some(forwarding) => {
+                let mut forwarder = Forwarder::new(forwarding)?;
+                if let Some(quarantine) = &quarantine {
+                    forwarder = forwarder.with_quarantine(quarantine.clone());
+                }
+                if let Some(tx_queue) = pmd.tx_queues().next() {
+                    forwarder.add_port(tx_queue.clone());
+                }
+                Some(forwarder)
+            }
+            None => None,
+        };
+        let bridge = match &config.bridge {
+            Some(bridge_config) => {
+                let bridge = Bridge::new(bridge_config)?.with_budget(budget.clone());
+                if let Some(tx_queue) = pmd.tx_queues().next() {
+                    bridge.add_port(tx_queue.clone());
+                }
+                Some(bridge)
+            }
+            None => None,
+        };
+        let stats_baseline = Self::restore_stats(&config);
+        let coordinator = config
+            .capture_coordination
+            .as_ref()
+            .map(CaptureCoordinator::new)
+            .transpose()?;
 
         Ok(Self {
             config,
             memory_manager,
             pmd,
             udp_stack,
+            forwarder,
+            bridge,
+            quarantine,
+            shutdown,
+            next_poll_queue: 0,
+            stats_baseline,
+            workers: WorkerRegistry::default(),
+            coordinator,
+            warmup: None,
         })
     }
 
+    /// Counters of the configured interface saved by the previous run, zero if none
+    fn restore_stats(config: &Config) -> InterfaceStats {
+        let empty = InterfaceStats {
+            interface: config.interface.clone(),
+            port_id: config.port_id,
+            ..InterfaceStats::default()
+        };
+        let Some(persistence) = config.stats_persistence.as_ref().filter(|p| p.restore) else {
+            return empty;
+        };
+        if !persistence.path.exists() {
+            return empty;
+        }
+        match StatsSnapshot::load(&persistence.path) {
+            Ok(snapshot) => match snapshot.interface(&config.interface) {
+                Some(stats) => empty.combined(stats),
+                None => empty,
+            },
+            Err(e) => {
+                log::warn!(
+                    "Not restoring stats from {}: {}",
+                    persistence.path.display(),
+                    e
+                );
+                empty
+            }
+        }
+    }
+
+    /// Create an instance from layered configuration
+    ///
+    /// Logs the effective configuration and the source of each value
+    /// before starting; see [`LayeredConfig`] for the layers.
+    pub fn from_layers(layers: &LayeredConfig) -> Result<Self> {
+        let resolved = layers.resolve()?;
+        resolved.log();
+        Self::new(resolved.config)
+    }
+
+    /// Check privileges, huge pages, interface state and memlock limits
+    pub fn preflight_report(config: &Config) -> PreflightReport {
+        PreflightReport::run(config)
+    }
+
+    /// Preflight checks of the running instance, with the warm-up once it ran
+    pub fn startup_report(&self) -> PreflightReport {
+        let mut report = Self::preflight_report(&self.config);
+        if let Some(warmup) = &self.warmup {
+            report.checks.push(warmup.check());
+        }
+        if self.config.placement.is_some() {
+            report.checks.push(self.placement_report().check());
+        }
+        report
+    }
+
+    /// Audit the NUMA nodes of each RX queue's pool, polling CPU and NIC
+    pub fn placement_report(&self) -> PlacementReport {
+        PlacementReport::audit(&self.pmd, &self.config.interface)
+    }
+
+    /// Get the report of the warm-up run by the last start, if any
+    pub fn warmup_report(&self) -> Option<&WarmupReport> {
+        self.warmup.as_ref()
+    }
+
     /// Get the UDP stack
     pub fn udp_stack(&self) -> &UdpStack {
         &self.udp_stack
@@ -148,29 +611,433 @@ impl Xpdk {
         &mut self.udp_stack
     }
 
+    /// Get the L3 forwarder, if [`Config::forwarding`] is set
+    ///
+    /// It sends on the driver's first TX queue; add the TX queues of other
+    /// ports with [`Forwarder::add_port`].
+    pub fn forwarder(&self) -> Option<&Forwarder> {
+        self.forwarder.as_ref()
+    }
+
+    /// Get the L2 bridge, if [`Config::bridge`] is set
+    ///
+    /// It bridges the driver's own port; add the ports of other drivers
+    /// with [`Bridge::add_driver`] and poll them with [`Bridge::poll`].
+    pub fn bridge(&self) -> Option<&Bridge> {
+        self.bridge.as_ref()
+    }
+
+    /// Get the quarantine, if enabled
+    ///
+    /// Drain it from a user thread with [`Quarantine::drain`] or write it
+    /// out with [`Quarantine::dump_to_file`].
+    pub fn quarantine(&self) -> Option<&Arc<Quarantine>> {
+        self.quarantine.as_ref()
+    }
+
     /// Get the poll mode driver
     pub fn pmd(&self) -> &PollModeDriver {
         &self.pmd
     }
 
+    /// Get the per-layer protocol counters of the UDP stack
+    pub fn protocol_stats(&self) -> udp::MibSnapshot {
+        self.udp_stack.protocol_stats()
+    }
+
     /// Get the memory manager
     pub fn memory_manager(&self) -> &MemoryManager {
         &self.memory_manager
     }
 
+    /// Look up a pool, queue, socket or worker by the name it shows in logs and telemetry
+    ///
+    /// Pools are searched first, then RX and TX queues, then sockets, then
+    /// registered workers.
+    pub fn find_by_name(&self, name: &str) -> Option<NamedEntity<'_>> {
+        let pmd_pool = Some(self.pmd.get_pool().as_ref()).filter(|pool| pool.name() == name);
+        pmd_pool
+            .or_else(|| self.memory_manager.pool_by_name(name))
+            .map(NamedEntity::Pool)
+            .or_else(|| self.pmd.rx_queue_by_name(name).map(NamedEntity::RxQueue))
+            .or_else(|| self.pmd.tx_queue_by_name(name).map(NamedEntity::TxQueue))
+            .or_else(|| self.udp_stack.socket_by_name(name).map(NamedEntity::Socket))
+            .or_else(|| {
+                let workers = self.workers.read();
+                let (_, stats) = workers.iter().find(|(worker, _)| worker == name)?;
+                Some(NamedEntity::Worker(stats.clone()))
+            })
+    }
+
+    /// Name `worker` in [`Xpdk::find_by_name`] and stats snapshots
+    ///
+    /// Fails with [`Error::InvalidConfig`] if another registered worker has its name.
+    pub fn register_worker(&self, worker: &QueueWorker) -> Result<()> {
+        let mut workers = self.workers.write();
+        if workers.iter().any(|(name, _)| name == worker.name()) {
+            return Err(Error::InvalidConfig(format!(
+                "Worker name '{}' is taken",
+                worker.name()
+            )));
+        }
+        workers.push((worker.name().to_string(), worker.shared_stats()));
+        Ok(())
+    }
+
+    /// Forget the worker registered as `name`, returning whether there was one
+    pub fn unregister_worker(&self, name: &str) -> bool {
+        let mut workers = self.workers.write();
+        let before = workers.len();
+        workers.retain(|(worker, _)| worker != name);
+        workers.len() != before
+    }
+
+    /// Get the memory budget shared by pools, socket queues and flow tables
+    ///
+    /// Charge relay tables to it with [`udp::RelayTable::with_budget`], and
+    /// other stateful tables through [`MemoryBudget::register_table`].
+    pub fn memory_budget(&self) -> &Arc<MemoryBudget> {
+        self.memory_manager.budget()
+    }
+
     /// Start packet processing
+    ///
+    /// With [`Config::placement`] set to refuse, fails with
+    /// [`Error::NumaError`] and stops again if a queue is misplaced.
     pub fn start(&mut self) -> Result<()> {
         self.pmd.start()?;
         self.udp_stack.start()?;
+        if let Some(warmup) = &self.config.warmup {
+            self.warm_up(*warmup);
+        }
+        if let Some(enforcement) = self.config.placement {
+            if let Err(e) = self.placement_report().enforce(enforcement) {
+                self.udp_stack.stop()?;
+                self.pmd.stop()?;
+                return Err(e);
+            }
+        }
+        self.coordinate_capture()?;
         Ok(())
     }
 
+    /// Fault in every pool and socket queue before the first poll
+    fn warm_up(&mut self, warmup: WarmupConfig) {
+        let mut pools: Vec<&MbufPool> = self.memory_manager.pools().collect();
+        for pool in
+            std::iter::once(self.pmd.get_pool()).chain(self.pmd.rx_queues().map(|q| q.get_pool()))
+        {
+            if !pools
+                .iter()
+                .any(|&known| std::ptr::eq(known, pool.as_ref()))
+            {
+                pools.push(pool);
+            }
+        }
+        let report = WarmupReport::run(&warmup, pools, &mut self.udp_stack);
+        match &report.self_test {
+            Some(Err(e)) => log::warn!("Warm-up: {}, self-test failed: {}", report, e),
+            _ => log::info!("Warm-up: {}", report),
+        }
+        self.warmup = Some(report);
+    }
+
     /// Stop packet processing
+    ///
+    /// With capture coordination, the process leaves the registry until started again.
     pub fn stop(&mut self) -> Result<()> {
         self.udp_stack.stop()?;
         self.pmd.stop()?;
+        if let Some(coordinator) = &mut self.coordinator {
+            coordinator.withdraw()?;
+        }
+        Ok(())
+    }
+
+    /// Advertise the stack's ports and refilter RX captures, see [`poll::coordination`]
+    ///
+    /// Does nothing without [`Config::capture_coordination`]. Call after
+    /// binding sockets for other processes to stop capturing their ports
+    /// before the next periodic refresh.
+    pub fn coordinate_capture(&mut self) -> Result<()> {
+        let Some(coordinator) = &mut self.coordinator else {
+            return Ok(());
+        };
+        coordinator.refresh(self.udp_stack.local_ports(), self.pmd.rx_queues())?;
         Ok(())
     }
+
+    /// Run one bounded iteration of the datapath and return
+    ///
+    /// Receives at most `budget` packets across the RX queues, handing each
+    /// to the bridge when bridging is enabled, else to the forwarder when
+    /// forwarding is enabled and to the UDP stack otherwise or if it is not
+    /// routed, then expires idle sockets, releases
+    /// datagrams held too long by reorder buffers, sends due keep-alives and
+    /// sends the frames scheduled with [`TxQueue::send_at`] that are due.
+    /// Nothing blocks and no thread is started, so callers running inside
+    /// their own thread pool can interleave this with other work. Other
+    /// sends go out synchronously from [`UdpSocket::send`] and need no
+    /// servicing here.
+    /// Does nothing while stopped or after shutdown.
+    pub fn poll_once(&mut self, budget: &PollBudget) -> Result<PollSummary> {
+        if self.shutdown.is_cancelled() || !self.udp_stack.is_running() {
+            return Ok(PollSummary::default());
+        }
+
+        if self.coordinator.as_ref().is_some_and(|c| c.is_due()) {
+            if let Err(e) = self.coordinate_capture() {
+                log::warn!("Capture coordination refresh failed: {}", e);
+            }
+        }
+
+        let queues: Vec<u16> = self.pmd.rx_queues().map(|rx_queue| rx_queue.id()).collect();
+        let mut summary = PollSummary::default();
+        if !queues.is_empty() {
+            let start = self.next_poll_queue % queues.len();
+            self.next_poll_queue = self.next_poll_queue.wrapping_add(1);
+
+            let (pmd, udp_stack, forwarder, bridge, quarantine) = (
+                &self.pmd,
+                &self.udp_stack,
+                &self.forwarder,
+                &self.bridge,
+                &self.quarantine,
+            );
+            summary = dispatch::run_rounds(&queues, start, budget, |queue_id| {
+                let Some(rx_queue) = pmd.get_rx_queue(queue_id) else {
+                    return Ok(None);
+                };
+                match rx_queue.recv() {
+                    Ok(mbuf) => {
+                        if let Some(bridge) = bridge {
+                            let pool = rx_queue.get_pool();
+                            let flow = FlowKey::from_frame(unsafe { (*mbuf).data() });
+                            return match bridge.process(unsafe { &mut *mbuf }, pool)? {
+                                BridgeVerdict::Dropped(reason) => {
+                                    rx_queue
+                                        .drop_log()
+                                        .record_flow(reason, flow, monotonic_now());
+                                    Ok(Some(Delivery::Dropped(reason)))
+                                }
+                                _ => Ok(Some(Delivery::Delivered)),
+                            };
+                        }
+                        if let Some(forwarder) = forwarder {
+                            let pool = rx_queue.get_pool();
+                            // The forwarder frees what it drops, so note the flow first
+                            let flow = FlowKey::from_frame(unsafe { (*mbuf).data() });
+                            match forwarder.process(unsafe { &mut *mbuf }, pool)? {
+                                ForwardVerdict::Local => {}
+                                ForwardVerdict::Forwarded(_) => {
+                                    return Ok(Some(Delivery::Delivered));
+                                }
+                                ForwardVerdict::Dropped(reason) => {
+                                    rx_queue
+                                        .drop_log()
+                                        .record_flow(reason, flow, monotonic_now());
+                                    return Ok(Some(Delivery::Dropped(reason)));
+                                }
+                            }
+                        }
+                        let delivery = udp_stack.dispatch(mbuf);
+                        if let Delivery::Dropped(reason) = delivery {
+                            rx_queue.drop_log().record(reason, unsafe { &*mbuf });
+                            if let Some(quarantine) = quarantine {
+                                quarantine.offer(reason, unsafe { (*mbuf).data() });
+                            }
+                            rx_queue.get_pool().free(mbuf)?;
+                        }
+                        Ok(Some(delivery))
+                    }
+                    Err(Error::NetworkError(_)) => Ok(None), // No more packets
+                    Err(e) => Err(e),
+                }
+            })?;
+        }
+
+        let now = monotonic_now();
+        self.udp_stack.expire_idle(now)?;
+        self.udp_stack.flush_reorder_buffers(now);
+        self.udp_stack.expire_source_ports(now);
+        self.udp_stack.run_neighbor_timers();
+        self.udp_stack.run_keepalives(now);
+        for tx_queue in self.pmd.tx_queues() {
+            tx_queue.flush_scheduled();
+        }
+        Ok(summary)
+    }
+
+    /// Get the root shutdown token
+    ///
+    /// Hand children of it to dispatchers, workers and application loops so
+    /// that [`Xpdk::shutdown`] reaches them.
+    pub fn shutdown_token(&self) -> &ShutdownToken {
+        &self.shutdown
+    }
+
+    /// Signal shutdown to every component and stop packet processing
+    ///
+    /// The recent drops of every RX queue are logged for postmortem.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.shutdown.cancel();
+        self.log_drop_events();
+        self.stop()
+    }
+
+    fn log_drop_events(&self) {
+        for rx_queue in self.pmd.rx_queues() {
+            let drop_log = rx_queue.drop_log();
+            let events = drop_log.events();
+            if events.is_empty() {
+                continue;
+            }
+            log::info!(
+                "RX queue {}: last {} of {} drops",
+                rx_queue.name(),
+                events.len(),
+                drop_log.recorded()
+            );
+            for event in events {
+                log::info!("  {}", event);
+            }
+        }
+    }
+
+    /// Log a summary of RX traffic and pool usage every `config.interval`
+    ///
+    /// The summary comes from a background thread that stops on
+    /// [`Xpdk::shutdown`].
+    pub fn spawn_stats_sampler(
+        &self,
+        config: SamplerConfig,
+    ) -> Result<std::thread::JoinHandle<()>> {
+        self.spawn_stats_sampler_with_alarms(config, AlarmSet::new())
+    }
+
+    /// Like [`Xpdk::spawn_stats_sampler`], evaluating `alarms` over every summary
+    ///
+    /// Listeners of `alarms` run on the sampler thread, see [`utils::alarm`].
+    pub fn spawn_stats_sampler_with_alarms(
+        &self,
+        config: SamplerConfig,
+        alarms: AlarmSet,
+    ) -> Result<std::thread::JoinHandle<()>> {
+        let rx_queues: Vec<_> = self.pmd.rx_queues().cloned().collect();
+        let pool = self.pmd.get_pool().clone();
+        let source = move || {
+            let pool = pool.stats();
+            let mut sample = StatsSample {
+                pool_in_use: pool.in_use,
+                pool_size: pool.size,
+                ..StatsSample::default()
+            };
+            for rx_queue in &rx_queues {
+                let stats = rx_queue.stats();
+                sample.packets += stats.packets_received.get();
+                sample.bytes += stats.bytes_received.get();
+                sample.drops += stats.drops.get();
+                sample.errors += stats.errors.get();
+            }
+            sample
+        };
+        Ok(StatsSampler::new(config, source)
+            .with_alarms(alarms)
+            .spawn(self.shutdown.child())?)
+    }
+
+    /// Send frames scheduled with [`TxQueue::send_at`] on time from a background thread
+    ///
+    /// The thread stops on [`Xpdk::shutdown`], see [`poll::schedule`].
+    pub fn spawn_tx_scheduler(&self) -> Result<std::thread::JoinHandle<()>> {
+        let tx_queues: Vec<_> = self.pmd.tx_queues().cloned().collect();
+        let shutdown = self.shutdown.child();
+        Ok(std::thread::Builder::new()
+            .name("xpdk-tx-sched".to_string())
+            .spawn(move || poll::schedule::run_scheduler(&tx_queues, &shutdown))?)
+    }
+
+    /// Compare the interface's kernel RX counters with the RX queues' every `interval`
+    ///
+    /// Discrepancies are logged at WARN from a background thread that stops
+    /// on [`Xpdk::shutdown`], see [`utils::ifstats`].
+    pub fn spawn_interface_monitor(
+        &self,
+        interval: std::time::Duration,
+    ) -> Result<std::thread::JoinHandle<()>> {
+        let rx_queues: Vec<_> = self.pmd.rx_queues().cloned().collect();
+        let source = move || {
+            let mut counters = PmdCounters::default();
+            for rx_queue in &rx_queues {
+                let stats = rx_queue.stats();
+                counters.packets += stats.packets_received.get();
+                // Frames captured but not delivered
+                counters.drops += stats.drops.get() + stats.errors.get();
+            }
+            counters
+        };
+        // Fail early on an interface without sysfs counters
+        KernelCounters::read(&self.config.interface)?;
+        Ok(InterfaceMonitor::new(&self.config.interface, source)
+            .spawn(interval, self.shutdown.child())?)
+    }
+
+    /// Counters of the interface since the first run with stats persistence
+    pub fn interface_stats(&self) -> InterfaceStats {
+        let rx_queues: Vec<_> = self.pmd.rx_queues().cloned().collect();
+        let tx_queues: Vec<_> = self.pmd.tx_queues().cloned().collect();
+        self.stats_baseline.with_queues(&rx_queues, &tx_queues)
+    }
+
+    /// Interface counters and application metrics in the Prometheus text format
+    ///
+    /// Application metrics are those registered with
+    /// [`utils::metrics::MetricsRegistry::global`]. The usage of the tables
+    /// registered with the memory budget follows them.
+    pub fn prometheus_metrics(&self) -> String {
+        let mut out =
+            metrics::render_prometheus(&[self.interface_stats()], MetricsRegistry::global());
+        out.push_str(&metrics::render_tables(&self.memory_budget().tables()));
+        out
+    }
+
+    /// Persist [`Xpdk::interface_stats`] as set by [`Config::stats_persistence`]
+    ///
+    /// Snapshots are written from a background thread that writes a last
+    /// one and stops on [`Xpdk::shutdown`]. They also carry the counters of
+    /// the open sockets and the registered workers.
+    pub fn spawn_stats_persister(&self) -> Result<std::thread::JoinHandle<()>> {
+        let persistence = self.config.stats_persistence.clone().ok_or_else(|| {
+            Error::InvalidConfig("Stats persistence is not configured".to_string())
+        })?;
+        let rx_queues: Vec<_> = self.pmd.rx_queues().cloned().collect();
+        let tx_queues: Vec<_> = self.pmd.tx_queues().cloned().collect();
+        let baseline = self.stats_baseline.clone();
+        let sockets = self.udp_stack.socket_directory();
+        let workers = self.workers.clone();
+        let source = move || {
+            StatsSnapshot::new(vec![baseline.with_queues(&rx_queues, &tx_queues)])
+                .with_entities(sockets.counters(), worker_counters(&workers))
+        };
+        Ok(StatsPersister::new(persistence, source).spawn(self.shutdown.child())?)
+    }
+
+    /// Send sampled frames and the driver's interface counters to an sFlow collector
+    ///
+    /// Datagrams go out through the stack socket `socket_id`; returns how
+    /// many were sent. Call periodically, e.g. every counter interval.
+    pub fn export_sflow(&self, exporter: &SflowExporter, socket_id: u16) -> Result<usize> {
+        let socket = self
+            .udp_stack
+            .get_socket(socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        exporter.export(socket, &[InterfaceCounters::from_pmd(&self.pmd)])
+    }
+
+    /// Block until the root token is cancelled, e.g. through a clone held elsewhere
+    pub fn wait_for_shutdown(&self) {
+        self.shutdown.wait_for_shutdown();
+    }
 }
 
 #[cfg(test)]
@@ -184,6 +1051,24 @@ mod tests {
         assert_eq!(config.pool_size, 8192);
     }
 
+    #[test]
+    fn test_config_builder_auto_size() {
+        let config = Config::builder()
+            .with_interface("lo")
+            .with_queues(1, 1)
+            .with_hugepages(false)
+            .auto_size(SizingTargets {
+                mtu: 1400,
+                ..SizingTargets::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.interface, "lo");
+        assert_eq!(config.rx_queue_size, 1024);
+        assert_eq!(config.pool_size, 2560);
+        assert_eq!(config.mtu, 1400);
+    }
+
     #[test]
     fn test_xpdk_creation() {
         let config = Config::default();
@@ -0,0 +1,340 @@
+//! Fixed-size object arena for control-plane state
+//!
+//! Objects live in a single hugepage-backed slab sized at creation time, so
+//! allocating flow entries, timers or neighbour entries never touches the
+//! global allocator. Free slots are kept in a shared list plus a small cache
+//! per thread shard that is refilled and spilled in batches.
+
+use super::HugePageAllocator;
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Default number of free slots cached per thread shard
+pub const DEFAULT_ARENA_CACHE_SIZE: usize = 32;
+
+/// Source of arena identifiers
+static NEXT_ARENA_ID: AtomicUsize = AtomicUsize::new(1);
+/// Source of thread shard identifiers
+static NEXT_THREAD_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_SHARD: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+/// Shard index of the current thread
+pub(super) fn thread_shard() -> usize {
+    THREAD_SHARD.with(|shard| {
+        if shard.get() == usize::MAX {
+            shard.set(NEXT_THREAD_SHARD.fetch_add(1, Ordering::Relaxed));
+        }
+        shard.get()
+    })
+}
+
+/// Owning handle to an object in an [`ObjectArena`]
+///
+/// Handles cannot be cloned, so holding one grants exclusive access to its
+/// object. Dropping a handle without freeing it leaks the slot.
+#[derive(Debug)]
+pub struct ArenaHandle<T> {
+    arena: usize,
+    index: u32,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ArenaHandle<T> {
+    /// Slot index inside the arena
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+/// Arena usage statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    pub capacity: usize,
+    pub in_use: usize,
+    pub peak_usage: usize,
+    pub object_size: usize,
+    pub cache_hits: usize,
+    pub cache_refills: usize,
+}
+
+/// Slab of fixed-size objects with per-thread free caches
+pub struct ObjectArena<T> {
+    /// Identifier checked against handles
+    id: usize,
+    /// Object storage
+    slots: *mut MaybeUninit<T>,
+    /// Number of slots
+    capacity: usize,
+    /// Bytes requested from the allocator
+    mapped_size: usize,
+    /// Backing memory allocator
+    allocator: HugePageAllocator,
+    /// Slots currently holding an object
+    live: Box<[AtomicBool]>,
+    /// Free slots not cached by any shard
+    shared: Mutex<Vec<u32>>,
+    /// Free slot caches, one per thread shard
+    caches: Box<[Mutex<Vec<u32>>]>,
+    /// Maximum slots per cache before spilling
+    cache_size: usize,
+    in_use: AtomicUsize,
+    peak_usage: AtomicUsize,
+    cache_hits: AtomicUsize,
+    cache_refills: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for ObjectArena<T> {}
+unsafe impl<T: Send + Sync> Sync for ObjectArena<T> {}
+
+impl<T> ObjectArena<T> {
+    /// Create an arena holding up to `capacity` objects
+    pub fn new(capacity: usize) -> Result<Self> {
+        Self::with_cache_size(capacity, DEFAULT_ARENA_CACHE_SIZE)
+    }
+
+    /// Create an arena with a custom per-thread cache size
+    pub fn with_cache_size(capacity: usize, cache_size: usize) -> Result<Self> {
+        if capacity == 0 || capacity > u32::MAX as usize {
+            return Err(Error::InvalidConfig(format!(
+                "Arena capacity {} out of range",
+                capacity
+            )));
+        }
+        if std::mem::align_of::<T>() > 4096 {
+            return Err(Error::InvalidConfig(
+                "Arena objects may not be aligned beyond a page".to_string(),
+            ));
+        }
+
+        let allocator = HugePageAllocator::new()?;
+        let mapped_size = capacity * std::mem::size_of::<T>().max(1);
+        let slots = allocator.allocate(mapped_size)? as *mut MaybeUninit<T>;
+
+        let shard_count = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        Ok(Self {
+            id: NEXT_ARENA_ID.fetch_add(1, Ordering::Relaxed),
+            slots,
+            capacity,
+            mapped_size,
+            allocator,
+            live: (0..capacity).map(|_| AtomicBool::new(false)).collect(),
+            shared: Mutex::new((0..capacity as u32).rev().collect()),
+            caches: (0..shard_count).map(|_| Mutex::new(Vec::new())).collect(),
+            cache_size: cache_size.max(1),
+            in_use: AtomicUsize::new(0),
+            peak_usage: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+            cache_refills: AtomicUsize::new(0),
+        })
+    }
+
+    /// Move `value` into the arena
+    pub fn alloc(&self, value: T) -> Result<ArenaHandle<T>> {
+        let index = self.take_slot().ok_or_else(|| {
+            Error::MemoryAllocation(format!("Arena exhausted ({} objects)", self.capacity))
+        })?;
+
+        unsafe { (*self.slots.add(index as usize)).write(value) };
+        let was_live = self.live[index as usize].swap(true, Ordering::AcqRel);
+        debug_assert!(!was_live, "arena slot {} handed out twice", index);
+
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_usage.fetch_max(in_use, Ordering::Relaxed);
+
+        Ok(ArenaHandle {
+            arena: self.id,
+            index,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Remove an object from the arena and return it
+    pub fn free(&self, handle: ArenaHandle<T>) -> T {
+        self.check_handle(&handle);
+        let index = handle.index;
+
+        self.live[index as usize].store(false, Ordering::Release);
+        let value = unsafe { (*self.slots.add(index as usize)).assume_init_read() };
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        self.return_slot(index);
+
+        value
+    }
+
+    /// Shared access to an object
+    pub fn get<'a>(&'a self, handle: &'a ArenaHandle<T>) -> &'a T {
+        self.check_handle(handle);
+        unsafe { (*self.slots.add(handle.index as usize)).assume_init_ref() }
+    }
+
+    /// Exclusive access to an object
+    pub fn get_mut<'a>(&'a self, handle: &'a mut ArenaHandle<T>) -> &'a mut T {
+        self.check_handle(handle);
+        unsafe { (*self.slots.add(handle.index as usize)).assume_init_mut() }
+    }
+
+    /// Number of objects the arena can hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get arena statistics
+    pub fn stats(&self) -> ArenaStats {
+        ArenaStats {
+            capacity: self.capacity,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak_usage: self.peak_usage.load(Ordering::Relaxed),
+            object_size: std::mem::size_of::<T>(),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_refills: self.cache_refills.load(Ordering::Relaxed),
+        }
+    }
+
+    fn check_handle(&self, handle: &ArenaHandle<T>) {
+        assert_eq!(
+            handle.arena, self.id,
+            "arena handle used with a different arena"
+        );
+        debug_assert!(
+            self.live[handle.index as usize].load(Ordering::Acquire),
+            "arena slot {} accessed while free",
+            handle.index
+        );
+    }
+
+    fn cache(&self) -> &Mutex<Vec<u32>> {
+        &self.caches[thread_shard() % self.caches.len()]
+    }
+
+    /// Pop a free slot, refilling the thread cache from the shared list
+    fn take_slot(&self) -> Option<u32> {
+        let mut cache = self.cache().lock();
+        if let Some(index) = cache.pop() {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(index);
+        }
+
+        let mut shared = self.shared.lock();
+        let batch = (self.cache_size / 2).max(1).min(shared.len());
+        if batch > 0 {
+            let start = shared.len() - batch;
+            cache.extend(shared.drain(start..));
+            self.cache_refills.fetch_add(1, Ordering::Relaxed);
+            return cache.pop();
+        }
+        drop(shared);
+
+        // Shared list is empty; take a slot cached by another shard
+        drop(cache);
+        self.caches.iter().find_map(|other| other.lock().pop())
+    }
+
+    /// Push a free slot, spilling half the thread cache when it is full
+    fn return_slot(&self, index: u32) {
+        let mut cache = self.cache().lock();
+        cache.push(index);
+
+        if cache.len() > self.cache_size {
+            let keep = self.cache_size / 2;
+            self.shared.lock().extend(cache.drain(keep..));
+        }
+    }
+}
+
+impl<T> Drop for ObjectArena<T> {
+    fn drop(&mut self) {
+        if std::mem::needs_drop::<T>() {
+            for (index, live) in self.live.iter().enumerate() {
+                if live.load(Ordering::Acquire) {
+                    unsafe { ptr::drop_in_place((*self.slots.add(index)).as_mut_ptr()) };
+                }
+            }
+        }
+        let _ = self
+            .allocator
+            .deallocate(self.slots as *mut libc::c_void, self.mapped_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_alloc_free_and_stats() {
+        let arena = ObjectArena::with_cache_size(4, 2).unwrap();
+
+        let mut first = arena.alloc(10u64).unwrap();
+        let second = arena.alloc(20u64).unwrap();
+        *arena.get_mut(&mut first) += 1;
+        assert_eq!(*arena.get(&first), 11);
+        assert_eq!(*arena.get(&second), 20);
+
+        let handles: Vec<_> = (0..2).map(|i| arena.alloc(i).unwrap()).collect();
+        assert!(arena.alloc(99).is_err());
+
+        let stats = arena.stats();
+        assert_eq!(stats.in_use, 4);
+        assert_eq!(stats.peak_usage, 4);
+        assert_eq!(stats.object_size, 8);
+
+        assert_eq!(arena.free(first), 11);
+        assert_eq!(arena.free(second), 20);
+        for handle in handles {
+            arena.free(handle);
+        }
+        assert_eq!(arena.stats().in_use, 0);
+        assert!(arena.alloc(1).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "different arena")]
+    fn test_foreign_handle_rejected() {
+        let a = ObjectArena::new(1).unwrap();
+        let b = ObjectArena::new(1).unwrap();
+        let handle = a.alloc(1u32).unwrap();
+        let _ = b.get(&handle);
+    }
+
+    #[test]
+    fn test_concurrent_threads_and_drop() {
+        let arena = Arc::new(ObjectArena::with_cache_size(64, 8).unwrap());
+
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let arena = Arc::clone(&arena);
+                std::thread::spawn(move || {
+                    for round in 0..100 {
+                        let handles: Vec<_> = (0..16)
+                            .map(|i| arena.alloc(format!("{}-{}-{}", t, round, i)).unwrap())
+                            .collect();
+                        for (i, handle) in handles.into_iter().enumerate() {
+                            assert_eq!(arena.free(handle), format!("{}-{}-{}", t, round, i));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(arena.stats().in_use, 0);
+        assert!(arena.stats().cache_hits > 0);
+
+        // Live objects are dropped with the arena
+        let _leaked = arena.alloc("leaked".to_string()).unwrap();
+    }
+}
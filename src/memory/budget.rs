@@ -0,0 +1,344 @@
+//! Global memory budget
+//!
+//! A [`MemoryBudget`] caps the bytes XPDK allocates for mbuf pools, socket
+//! receive queues and per-flow tables together, as set by
+//! [`crate::Config::memory_budget`]. Each subsystem charges the budget
+//! before allocating and gives the bytes back when the memory is released;
+//! a charge that would exceed the limit fails with
+//! [`Error::BudgetExceeded`] and nothing is allocated. Usage per subsystem
+//! is reported in [`super::MemoryStats`].
+//!
+//! Stateful subsystems register a [`TableAccount`] with the budget and
+//! report their entries and bytes through it as they grow and shrink: relay
+//! sessions and the bridge's address table, and on the UDP stack the
+//! neighbor table, the flows of randomized source ports, the path MTU cache
+//! and each socket's reorder flows and replay windows. Growth is charged
+//! to [`Subsystem::Tables`], so a table is refused entries rather than
+//! overrunning the budget, and [`MemoryBudget::tables`] lists the usage of
+//! every live table for [`super::MemoryStats`] and telemetry.
+
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+/// Consumer of budgeted memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    /// Mbuf pools of the memory manager and the poll mode driver
+    Pools,
+    /// Socket receive queues
+    Queues,
+    /// Flow and session tables
+    Tables,
+}
+
+impl Subsystem {
+    /// Number of subsystems
+    pub const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Subsystem::Pools => "pools",
+            Subsystem::Queues => "queues",
+            Subsystem::Tables => "tables",
+        })
+    }
+}
+
+/// Bytes in use per subsystem
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BudgetUsage {
+    /// Budget limit, `None` if unlimited
+    pub limit: Option<usize>,
+    pub pools: usize,
+    pub queues: usize,
+    pub tables: usize,
+}
+
+impl BudgetUsage {
+    /// Bytes in use over every subsystem
+    pub fn total(&self) -> usize {
+        self.pools + self.queues + self.tables
+    }
+}
+
+/// Memory limit shared by every subsystem
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    total: AtomicUsize,
+    used: [AtomicUsize; Subsystem::COUNT],
+    /// Registered tables, pruned as they are dropped
+    tables: Mutex<Vec<Weak<TableAccount>>>,
+}
+
+impl MemoryBudget {
+    /// Budget of `limit` bytes, `None` to only account usage
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Take `bytes` from the budget for `subsystem`
+    pub fn reserve(&self, subsystem: Subsystem, bytes: usize) -> Result<()> {
+        let reserved = self
+            .total
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+                let total = total.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if total > limit => None,
+                    _ => Some(total),
+                }
+            });
+        if let Err(total) = reserved {
+            return Err(Error::BudgetExceeded(format!(
+                "{} bytes for {} with {} of {} bytes in use",
+                bytes,
+                subsystem,
+                total,
+                self.limit.unwrap_or(usize::MAX)
+            )));
+        }
+        self.used[subsystem.index()].fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Give back `bytes` reserved for `subsystem`
+    ///
+    /// Giving back more than `subsystem` holds only releases what it holds.
+    pub fn release(&self, subsystem: Subsystem, bytes: usize) {
+        let released = saturating_take(&self.used[subsystem.index()], bytes);
+        saturating_take(&self.total, released);
+    }
+
+    /// Reserve `bytes` released when the returned charge is dropped
+    pub fn charge(self: &Arc<Self>, subsystem: Subsystem, bytes: usize) -> Result<BudgetCharge> {
+        self.reserve(subsystem, bytes)?;
+        Ok(BudgetCharge {
+            budget: self.clone(),
+            subsystem,
+            bytes,
+        })
+    }
+
+    /// Budget limit, `None` if unlimited
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Bytes in use per subsystem
+    pub fn usage(&self) -> BudgetUsage {
+        let used = |subsystem: Subsystem| self.used[subsystem.index()].load(Ordering::Relaxed);
+        BudgetUsage {
+            limit: self.limit,
+            pools: used(Subsystem::Pools),
+            queues: used(Subsystem::Queues),
+            tables: used(Subsystem::Tables),
+        }
+    }
+
+    /// Register a table whose growth is charged to [`Subsystem::Tables`]
+    pub fn register_table(self: &Arc<Self>, name: &str) -> Arc<TableAccount> {
+        let account = Arc::new(TableAccount {
+            name: name.to_string(),
+            budget: self.clone(),
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        });
+        let mut tables = self.tables.lock();
+        tables.retain(|table| table.strong_count() > 0);
+        tables.push(Arc::downgrade(&account));
+        account
+    }
+
+    /// Usage of every live registered table, in registration order
+    pub fn tables(&self) -> Vec<TableUsage> {
+        self.tables
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|table| table.usage())
+            .collect()
+    }
+}
+
+/// Take up to `amount` from `counter` without going below zero, returning what was taken
+fn saturating_take(counter: &AtomicUsize, amount: usize) -> usize {
+    let previous = counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |value| {
+            Some(value.saturating_sub(amount))
+        })
+        .unwrap_or_else(|value| value);
+    previous.min(amount)
+}
+
+/// Entries and bytes of a registered table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableUsage {
+    pub name: String,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Usage report of one stateful table
+///
+/// Bytes still held when the account is dropped go back to the budget.
+#[derive(Debug)]
+pub struct TableAccount {
+    name: String,
+    budget: Arc<MemoryBudget>,
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl TableAccount {
+    /// Account `entries` new entries of `bytes` together
+    ///
+    /// Fails with [`Error::BudgetExceeded`] and changes nothing if the
+    /// budget cannot hold them.
+    pub fn grow(&self, entries: usize, bytes: usize) -> Result<()> {
+        self.budget.reserve(Subsystem::Tables, bytes)?;
+        self.entries.fetch_add(entries, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Account the removal of `entries` entries of `bytes` together
+    ///
+    /// Counts stop at zero, and only bytes the table held go back.
+    pub fn shrink(&self, entries: usize, bytes: usize) {
+        saturating_take(&self.entries, entries);
+        let released = saturating_take(&self.bytes, bytes);
+        self.budget.release(Subsystem::Tables, released);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn usage(&self) -> TableUsage {
+        TableUsage {
+            name: self.name.clone(),
+            entries: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for TableAccount {
+    fn drop(&mut self) {
+        self.budget
+            .release(Subsystem::Tables, *self.bytes.get_mut());
+    }
+}
+
+/// Bytes held from a budget for as long as the charge lives
+#[derive(Debug)]
+pub struct BudgetCharge {
+    budget: Arc<MemoryBudget>,
+    subsystem: Subsystem,
+    bytes: usize,
+}
+
+impl BudgetCharge {
+    /// Bytes held
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for BudgetCharge {
+    fn drop(&mut self) {
+        self.budget.release(self.subsystem, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_limits_and_releases() {
+        let budget = Arc::new(MemoryBudget::new(Some(1000)));
+        let pools = budget.charge(Subsystem::Pools, 600).unwrap();
+        budget.reserve(Subsystem::Tables, 300).unwrap();
+        assert!(matches!(
+            budget.reserve(Subsystem::Queues, 200),
+            Err(Error::BudgetExceeded(_))
+        ));
+        assert_eq!(
+            budget.usage(),
+            BudgetUsage {
+                limit: Some(1000),
+                pools: 600,
+                queues: 0,
+                tables: 300,
+            }
+        );
+
+        drop(pools);
+        let queues = budget.charge(Subsystem::Queues, 700).unwrap();
+        budget.release(Subsystem::Tables, 300);
+        drop(queues);
+        assert_eq!(budget.usage().total(), 0);
+
+        // Over-releasing stops at zero instead of wrapping
+        budget.reserve(Subsystem::Pools, 100).unwrap();
+        budget.release(Subsystem::Tables, 50);
+        budget.release(Subsystem::Pools, 150);
+        assert_eq!(budget.usage().total(), 0);
+        budget.charge(Subsystem::Queues, 1000).unwrap();
+
+        let unlimited = MemoryBudget::new(None);
+        unlimited.reserve(Subsystem::Pools, usize::MAX / 2).unwrap();
+        assert_eq!(unlimited.usage().pools, usize::MAX / 2);
+    }
+
+    #[test]
+    fn test_tables_report_and_charge_usage() {
+        let budget = Arc::new(MemoryBudget::new(Some(1000)));
+        let flows = budget.register_table("flows");
+        let arp = budget.register_table("arp");
+        flows.grow(10, 640).unwrap();
+        arp.grow(2, 96).unwrap();
+        assert!(matches!(flows.grow(5, 320), Err(Error::BudgetExceeded(_))));
+        flows.shrink(4, 256);
+        assert_eq!(
+            budget.tables(),
+            vec![
+                TableUsage {
+                    name: "flows".to_string(),
+                    entries: 6,
+                    bytes: 384,
+                },
+                TableUsage {
+                    name: "arp".to_string(),
+                    entries: 2,
+                    bytes: 96,
+                },
+            ]
+        );
+        assert_eq!(budget.usage().tables, 480);
+
+        // Dropping a table releases what it still holds
+        drop(flows);
+        assert_eq!(budget.tables().len(), 1);
+        assert_eq!(budget.usage().tables, 96);
+
+        // Shrinking past zero gives back only what the table held
+        budget.reserve(Subsystem::Tables, 100).unwrap();
+        arp.shrink(3, 200);
+        assert_eq!((arp.usage().entries, arp.usage().bytes), (0, 0));
+        assert_eq!(budget.usage().tables, 100);
+    }
+}
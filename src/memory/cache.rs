@@ -0,0 +1,305 @@
+//! Per-core mbuf caches with remote free routing
+//!
+//! With [`super::MbufPool::with_core_caches`], each thread shard, one per
+//! core for pinned poll threads, allocates from its own cache of free
+//! mbufs, refilled from and spilled to the pool's shared free list in
+//! batches. An mbuf remembers the cache it was allocated from. Freed on the
+//! same core it goes straight back to that cache; freed on another core,
+//! as when an RX core hands a packet to a worker, it is pushed onto the
+//! owner's return ring, and the owner takes back everything on its ring in
+//! one batch on its next allocation. Buffers thus keep cycling through the
+//! cache of the core that allocates them instead of migrating to whichever
+//! core frees them. A full return ring falls back to the shared list, and
+//! a core that runs out takes what the shared list has left, then the
+//! caches and return rings of the other cores.
+//!
+//! [`CoreCacheStats`] counts local and remote frees, the returns taken
+//! back and the fallbacks.
+
+use super::arena::thread_shard;
+use super::stack::next_in_chain;
+use super::{Mbuf, MbufPool};
+use crate::utils::counter::Counter;
+use crate::{Error, Result};
+use lockfree_ringbuf::{BatchOps, MpscRingBuffer};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+/// Default number of free mbufs a core caches before spilling half
+pub const DEFAULT_CORE_CACHE_SIZE: usize = 256;
+
+/// Mbufs a bulk free routes at once, from an array on the stack
+pub(super) const FREE_CHUNK: usize = 32;
+
+/// Free counters of a pool's core caches
+#[derive(Debug, Default)]
+pub struct CoreCacheStats {
+    /// Batches taken from the shared list into a cache
+    pub refills: Counter,
+    /// Batches spilled from a full cache to the shared list
+    pub spills: Counter,
+    /// Mbufs freed on the core that allocated them
+    pub local_frees: Counter,
+    /// Mbufs freed on another core and routed back to their owner
+    pub remote_frees: Counter,
+    /// Remote frees taken back into the owner's cache
+    pub returns: Counter,
+    /// Remote frees sent to the shared list with the owner's ring full
+    pub fallbacks: Counter,
+}
+
+/// Cache of one core
+struct CoreCache {
+    /// Indices of the free mbufs cached
+    free: Mutex<Vec<u32>>,
+    /// Indices freed by other cores, drained under the `free` lock of the
+    /// cache, by its core or one stealing from it
+    returns: MpscRingBuffer<u32>,
+}
+
+/// Core caches of one pool
+pub(crate) struct CoreCaches {
+    cores: Box<[CoreCache]>,
+    /// Cache each mbuf was allocated from
+    owners: Box<[AtomicU16]>,
+    cache_size: usize,
+    stats: CoreCacheStats,
+}
+
+impl CoreCaches {
+    pub(crate) fn new(pool_size: usize, cores: usize, cache_size: usize) -> Self {
+        let cache_size = cache_size.max(1);
+        Self {
+            cores: (0..cores.clamp(1, u16::MAX as usize))
+                .map(|_| CoreCache {
+                    free: Mutex::new(Vec::with_capacity(cache_size + FREE_CHUNK)),
+                    returns: MpscRingBuffer::new(2 * cache_size),
+                })
+                .collect(),
+            owners: (0..pool_size).map(|_| AtomicU16::new(0)).collect(),
+            cache_size,
+            stats: CoreCacheStats::default(),
+        }
+    }
+
+    /// Cache of the calling thread
+    fn core(&self) -> usize {
+        thread_shard() % self.cores.len()
+    }
+
+    /// Move what other cores returned to `cache` into `free`, its locked free list
+    fn take_returns(&self, cache: &CoreCache, free: &mut Vec<u32>) {
+        let mut returned = 0;
+        while let Ok(index) = cache.returns.pop() {
+            free.push(index);
+            returned += 1;
+        }
+        self.stats.returns.add(returned);
+    }
+
+    pub(crate) fn stats(&self) -> &CoreCacheStats {
+        &self.stats
+    }
+}
+
+impl MbufPool {
+    /// Fill `mbufs` from the calling core's cache, all or none
+    ///
+    /// A cache short of mbufs refills from the shared list, then takes
+    /// what the caches and return rings of other cores hold.
+    pub(super) fn alloc_cached(&self, caches: &CoreCaches, mbufs: &mut [*mut Mbuf]) -> Result<()> {
+        let core = caches.core();
+        let cache = &caches.cores[core];
+        let mut free = cache.free.lock();
+        caches.take_returns(cache, &mut free);
+
+        if free.len() < mbufs.len() {
+            // Refill past the request, leaving room for frees before spilling
+            let needed = mbufs.len() - free.len();
+            let batch = needed + caches.cache_size / 2;
+            let chain = self
+                .unlink_chain(batch)
+                .map(|head| (head, batch))
+                .or_else(|_| self.unlink_chain(needed).map(|head| (head, needed)));
+            match chain {
+                Ok((mut index, count)) => {
+                    free.push(index);
+                    for _ in 1..count {
+                        index = next_in_chain(&self.links, index);
+                        free.push(index);
+                    }
+                    caches.stats.refills.inc();
+                }
+                // Fewer left than needed: take them all, the rest is stolen
+                Err(_) => {
+                    while free.len() < mbufs.len() {
+                        let Ok(index) = self.unlink_chain(1) else {
+                            break;
+                        };
+                        free.push(index);
+                    }
+                }
+            }
+        }
+        if free.len() < mbufs.len() {
+            // Take what other cores hold, one cache at a time
+            let wanted = mbufs.len() - free.len();
+            drop(free);
+            let mut stolen = Vec::with_capacity(wanted);
+            for (other, cache) in caches.cores.iter().enumerate() {
+                if other != core && stolen.len() < wanted {
+                    let mut free = cache.free.lock();
+                    caches.take_returns(cache, &mut free);
+                    let take = free.len().min(wanted - stolen.len());
+                    let start = free.len() - take;
+                    stolen.extend(free.drain(start..));
+                }
+            }
+            free = cache.free.lock();
+            free.extend(stolen);
+            if free.len() < mbufs.len() {
+                return Err(Error::MemoryAllocation("Pool exhausted".to_string()));
+            }
+        }
+
+        let start = free.len() - mbufs.len();
+        for (slot, index) in mbufs.iter_mut().zip(free.drain(start..)) {
+            caches.owners[index as usize].store(core as u16, Ordering::Relaxed);
+            *slot = unsafe { self.mbufs_base.add(index as usize) };
+        }
+        Ok(())
+    }
+
+    /// Return the indices of released mbufs to the cores that allocated them
+    ///
+    /// Mbufs spilled from a full cache or refused by a full return ring go
+    /// to the shared list. `indices` is reordered in place, so a free never
+    /// reaches the allocator.
+    pub(super) fn free_cached(&self, caches: &CoreCaches, indices: &mut [u32]) {
+        let core = caches.core();
+        let owner = |index: u32| caches.owners[index as usize].load(Ordering::Relaxed) as usize;
+        // Local frees to the front, remote ones grouped by owner behind them
+        let mut local = 0;
+        for i in 0..indices.len() {
+            if owner(indices[i]) == core {
+                indices.swap(local, i);
+                local += 1;
+            }
+        }
+        let (local, remote) = indices.split_at_mut(local);
+
+        if !local.is_empty() {
+            caches.stats.local_frees.add(local.len() as u64);
+            let mut free = caches.cores[core].free.lock();
+            free.extend_from_slice(local);
+            if free.len() > caches.cache_size {
+                let start = caches.cache_size / 2;
+                self.free_list.push_chain(&self.links, &free[start..]);
+                free.truncate(start);
+                caches.stats.spills.inc();
+            }
+        }
+
+        remote.sort_unstable_by_key(|&index| owner(index));
+        for group in remote.chunk_by(|&a, &b| owner(a) == owner(b)) {
+            let ring = &caches.cores[owner(group[0])].returns;
+            if ring.push_batch(group).is_ok() {
+                caches.stats.remote_frees.add(group.len() as u64);
+            } else {
+                caches.stats.fallbacks.add(group.len() as u64);
+                self.free_list.push_chain(&self.links, group);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_remote_frees_return_to_their_owner() {
+        let mut pool = MbufPool::new("cached".to_string(), 32, 256).unwrap();
+        // More caches than threads, so each test thread has its own
+        pool.core_caches = Some(CoreCaches::new(32, 64, 8));
+        let pool = Arc::new(pool);
+
+        let mut mbufs = [std::ptr::null_mut(); 4];
+        pool.alloc_bulk(&mut mbufs).unwrap();
+        assert_eq!(pool.available(), 28);
+        let addrs: Vec<usize> = mbufs.iter().map(|&mbuf| mbuf as usize).collect();
+
+        // A worker frees them, and they go back to this thread's cache
+        let worker = pool.clone();
+        let freed = addrs.clone();
+        std::thread::spawn(move || {
+            let mbufs: Vec<_> = freed.iter().map(|&addr| addr as *mut Mbuf).collect();
+            worker.free_bulk(&mbufs).unwrap();
+        })
+        .join()
+        .unwrap();
+        let stats = pool.core_cache_stats().unwrap();
+        assert_eq!(stats.remote_frees.get(), 4);
+        assert_eq!(pool.available(), 32);
+
+        pool.alloc_bulk(&mut mbufs).unwrap();
+        assert_eq!(stats.returns.get(), 4);
+        assert!(mbufs.iter().all(|&mbuf| addrs.contains(&(mbuf as usize))));
+        pool.free_bulk(&mbufs).unwrap();
+        assert_eq!(stats.local_frees.get(), 4);
+        assert_eq!(stats.refills.get(), 1);
+
+        // Frees past the cache size spill to the shared list
+        let mut all = [std::ptr::null_mut(); 32];
+        pool.alloc_bulk(&mut all).unwrap();
+        assert!(pool.alloc().is_err());
+        pool.free_bulk(&all).unwrap();
+        assert!(stats.spills.get() >= 1);
+        assert_eq!(stats.fallbacks.get(), 0);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_exhausted_core_takes_from_shared_list_caches_and_returns() {
+        let mut pool = MbufPool::new("stealing".to_string(), 8, 256).unwrap();
+        pool.core_caches = Some(CoreCaches::new(8, 64, 4));
+        let pool = Arc::new(pool);
+        let on_worker = |work: fn(&MbufPool)| {
+            let pool = pool.clone();
+            std::thread::spawn(move || work(&pool)).join().unwrap();
+        };
+
+        // This core caches 6 of 8 and hands out 4: the other core combines
+        // the 2 left on the shared list with 2 taken from this cache
+        let mut mbufs = [std::ptr::null_mut(); 4];
+        pool.alloc_bulk(&mut mbufs).unwrap();
+        on_worker(|pool| {
+            let mut mbufs = [std::ptr::null_mut(); 4];
+            pool.alloc_bulk(&mut mbufs).unwrap();
+            pool.free_bulk(&mbufs).unwrap();
+        });
+        pool.free_bulk(&mbufs).unwrap();
+        let stats = pool.core_cache_stats().unwrap();
+        assert_eq!(stats.local_frees.get(), 8);
+
+        // Frees routed to this core's ring are taken from it too
+        let mut all = [std::ptr::null_mut(); 8];
+        pool.alloc_bulk(&mut all).unwrap();
+        let addrs: Vec<usize> = all.iter().map(|&mbuf| mbuf as usize).collect();
+        std::thread::spawn({
+            let pool = pool.clone();
+            move || {
+                let all: Vec<_> = addrs.iter().map(|&addr| addr as *mut Mbuf).collect();
+                pool.free_bulk(&all).unwrap();
+                let mut again = [std::ptr::null_mut(); 8];
+                pool.alloc_bulk(&mut again).unwrap();
+                pool.free_bulk(&again).unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+        assert_eq!((stats.remote_frees.get(), stats.returns.get()), (8, 8));
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
@@ -0,0 +1,129 @@
+//! Mbuf lifetime checks for debug builds
+//!
+//! Enabled by the `mbuf-debug` feature and compiled out of release builds.
+//! Freed buffers are poisoned and every mbuf carries an allocation state, so
+//! double frees, writes after free and accesses after free panic with the
+//! backtrace of the last free.
+
+use super::Mbuf;
+use parking_lot::Mutex;
+use std::backtrace::Backtrace;
+
+/// Byte written over freed data buffers
+pub const POISON_BYTE: u8 = 0x6B;
+
+/// Mbuf was not allocated from a pool
+pub(crate) const STATE_UNTRACKED: u8 = 0;
+/// Mbuf is on its pool's free list
+pub(crate) const STATE_FREE: u8 = 0xF7;
+/// Mbuf is owned by the application
+pub(crate) const STATE_ALLOCATED: u8 = 0xA1;
+
+/// Per-pool debug bookkeeping
+pub(crate) struct PoolDebug {
+    /// Backtrace of the last free of each mbuf
+    last_free: Mutex<Vec<Option<Backtrace>>>,
+}
+
+impl PoolDebug {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            last_free: Mutex::new((0..size).map(|_| None).collect()),
+        }
+    }
+
+    /// Mark a freshly built mbuf as free and poison its buffer
+    pub(crate) fn init(mbuf: &mut Mbuf, data: &mut [u8]) {
+        data.fill(POISON_BYTE);
+        mbuf.debug_state = STATE_FREE;
+    }
+
+    /// Validate an mbuf leaving the free list
+    pub(crate) fn on_alloc(&self, pool: &str, index: usize, mbuf: &mut Mbuf, data: &[u8]) {
+        if mbuf.debug_state != STATE_FREE {
+            panic!(
+                "mbuf {} in pool '{}' handed out while not free (state {:#04x})",
+                index, pool, mbuf.debug_state
+            );
+        }
+
+        if let Some(offset) = data.iter().position(|&byte| byte != POISON_BYTE) {
+            panic!(
+                "mbuf {} in pool '{}' was written after free at offset {}\nlast freed at:\n{}",
+                index,
+                pool,
+                offset,
+                self.last_free_backtrace(index)
+            );
+        }
+
+        mbuf.debug_state = STATE_ALLOCATED;
+    }
+
+    /// Validate an mbuf returning to the free list and poison it
+    pub(crate) fn on_free(&self, pool: &str, index: usize, mbuf: &mut Mbuf, data: &mut [u8]) {
+        if mbuf.debug_state == STATE_FREE {
+            panic!(
+                "double free of mbuf {} in pool '{}'\nlast freed at:\n{}",
+                index,
+                pool,
+                self.last_free_backtrace(index)
+            );
+        }
+
+        data.fill(POISON_BYTE);
+        mbuf.debug_state = STATE_FREE;
+        self.last_free.lock()[index] = Some(Backtrace::force_capture());
+    }
+
+    fn last_free_backtrace(&self, index: usize) -> String {
+        match &self.last_free.lock()[index] {
+            Some(backtrace) => backtrace.to_string(),
+            None => "<never freed>".to_string(),
+        }
+    }
+}
+
+/// Panic if an mbuf is accessed while on its pool's free list
+#[track_caller]
+pub(crate) fn check_live(mbuf: &Mbuf) {
+    if mbuf.debug_state == STATE_FREE {
+        panic!("use after free of mbuf at {:p}", mbuf as *const Mbuf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::MbufPool;
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn test_double_free_panics() {
+        let pool = MbufPool::new("debug".to_string(), 2, 256).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        pool.free(mbuf).unwrap();
+        pool.free(mbuf).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "written after free")]
+    fn test_write_after_free_detected_on_alloc() {
+        let pool = MbufPool::new("debug".to_string(), 1, 256).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        let data = unsafe { (*mbuf).data };
+        pool.free(mbuf).unwrap();
+
+        unsafe { *data.add(10) = 0 };
+        let _ = pool.alloc();
+    }
+
+    #[test]
+    #[should_panic(expected = "use after free")]
+    fn test_access_after_free_panics() {
+        let pool = MbufPool::new("debug".to_string(), 2, 256).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        pool.free(mbuf).unwrap();
+
+        let _ = unsafe { (*mbuf).data().len() };
+    }
+}
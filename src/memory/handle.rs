@@ -0,0 +1,96 @@
+//! Owned mbufs
+//!
+//! An [`MbufHandle`] owns one mbuf together with the pool it came from and
+//! frees it when dropped, so a buffer passed between threads or parked in
+//! a queue cannot leak or be freed twice. Code that still works on raw
+//! `*mut Mbuf` converts at the boundary with [`MbufHandle::from_raw`] and
+//! [`MbufHandle::into_raw`].
+
+use super::{Mbuf, MbufPool};
+use crate::Result;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// Mbuf owned by a handle, returned to its pool on drop
+pub struct MbufHandle {
+    mbuf: *mut Mbuf,
+    pool: Arc<MbufPool>,
+}
+
+// The handle is the only owner of its mbuf
+unsafe impl Send for MbufHandle {}
+unsafe impl Sync for MbufHandle {}
+
+impl MbufHandle {
+    /// Allocate an mbuf from `pool`
+    pub fn alloc(pool: &Arc<MbufPool>) -> Result<Self> {
+        Ok(Self {
+            mbuf: pool.alloc()?,
+            pool: pool.clone(),
+        })
+    }
+
+    /// Take ownership of an mbuf allocated from `pool`
+    ///
+    /// # Safety
+    ///
+    /// `mbuf` must come from `pool`, be in use, and not be freed or owned elsewhere.
+    pub unsafe fn from_raw(mbuf: *mut Mbuf, pool: Arc<MbufPool>) -> Self {
+        Self { mbuf, pool }
+    }
+
+    /// Take a shared reference to an mbuf of `pool` owned elsewhere, see [`MbufPool::share`]
+    pub fn share(pool: &Arc<MbufPool>, mbuf: *mut Mbuf) -> Result<Self> {
+        pool.share(mbuf)?;
+        Ok(Self {
+            mbuf,
+            pool: pool.clone(),
+        })
+    }
+
+    /// Give up ownership, returning the mbuf and its pool without freeing it
+    pub fn into_raw(self) -> (*mut Mbuf, Arc<MbufPool>) {
+        let this = std::mem::ManuallyDrop::new(self);
+        (this.mbuf, unsafe { std::ptr::read(&this.pool) })
+    }
+
+    /// Raw pointer to the mbuf, still owned by the handle
+    pub fn as_ptr(&self) -> *mut Mbuf {
+        self.mbuf
+    }
+
+    /// Pool the mbuf returns to
+    pub fn pool(&self) -> &Arc<MbufPool> {
+        &self.pool
+    }
+}
+
+impl Deref for MbufHandle {
+    type Target = Mbuf;
+
+    fn deref(&self) -> &Mbuf {
+        unsafe { &*self.mbuf }
+    }
+}
+
+impl DerefMut for MbufHandle {
+    fn deref_mut(&mut self) -> &mut Mbuf {
+        unsafe { &mut *self.mbuf }
+    }
+}
+
+impl fmt::Debug for MbufHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MbufHandle")
+            .field("mbuf", &self.mbuf)
+            .field("pool", &self.pool.name())
+            .finish()
+    }
+}
+
+impl Drop for MbufHandle {
+    fn drop(&mut self) {
+        let _ = self.pool.free(self.mbuf);
+    }
+}
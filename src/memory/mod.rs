@@ -1,17 +1,51 @@
 //! Memory management module with huge pages support and cache-line optimization
 
+use crate::utils::counter::Counter;
 use crate::{Config, Error, Result};
 use libc::{c_void, MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_PRIVATE, PROT_READ, PROT_WRITE};
 use nix::unistd::sysconf;
 use nix::unistd::SysconfVar;
 use parking_lot::Mutex;
-use std::cell::UnsafeCell;
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+pub mod arena;
+pub mod budget;
+pub mod cache;
+#[cfg(all(feature = "mbuf-debug", debug_assertions))]
+pub mod debug;
+pub mod handle;
+pub mod object;
+pub mod reserve;
+pub mod reset;
+mod stack;
+
+pub use arena::{ArenaHandle, ArenaStats, ObjectArena};
+pub use budget::{BudgetCharge, BudgetUsage, MemoryBudget, Subsystem, TableAccount, TableUsage};
+pub use cache::{CoreCacheStats, DEFAULT_CORE_CACHE_SIZE};
+pub use handle::MbufHandle;
+pub use object::{ObjectPool, ObjectPoolStats, Pooled, DEFAULT_OBJECT_CACHE_SIZE};
+pub use reserve::{AllocClass, ClassStats};
+pub use reset::{ResetPolicy, ResetStats};
+
+use cache::CoreCaches;
+use reserve::Reservations;
+use stack::{next_in_chain, FreeStack};
 
 /// Cache line size for optimization (typically 64 bytes)
 pub const CACHE_LINE_SIZE: usize = 64;
 
+/// Mbufs a [`FreeBatch`] collects before returning them to the pool
+pub const FREE_BATCH_SIZE: usize = 32;
+
+/// Bytes of `Mbuf` used by debug state
+#[cfg(all(feature = "mbuf-debug", debug_assertions))]
+const MBUF_DEBUG_BYTES: usize = 1;
+#[cfg(not(all(feature = "mbuf-debug", debug_assertions)))]
+const MBUF_DEBUG_BYTES: usize = 0;
+
 /// Page size information
 #[derive(Debug, Clone)]
 pub struct PageInfo {
@@ -58,6 +92,13 @@ impl HugePageAllocator {
 
     /// Allocate memory using huge pages
     pub fn allocate(&self, size: usize) -> Result<*mut c_void> {
+        self.allocate_backed(size).map(|(ptr, _)| ptr)
+    }
+
+    /// Allocate memory, reporting whether huge pages back it
+    ///
+    /// Falls back to regular pages when no huge pages are available.
+    pub fn allocate_backed(&self, size: usize) -> Result<(*mut c_void, bool)> {
         // Round up to page size
         let aligned_size = ((size + self.page_size - 1) / self.page_size) * self.page_size;
 
@@ -94,12 +135,12 @@ impl HugePageAllocator {
             self.allocated_blocks.fetch_add(1, Ordering::Relaxed);
             self.total_allocated
                 .fetch_add(aligned_size, Ordering::Relaxed);
-            Ok(fallback_ptr)
+            Ok((fallback_ptr, false))
         } else {
             self.allocated_blocks.fetch_add(1, Ordering::Relaxed);
             self.total_allocated
                 .fetch_add(aligned_size, Ordering::Relaxed);
-            Ok(ptr)
+            Ok((ptr, true))
         }
     }
 
@@ -156,10 +197,37 @@ pub struct Mbuf {
     pub timestamp: u64,
     /// Queue ID
     pub queue_id: u16,
+    /// Interface (driver port) the frame was received on
+    pub port_id: u16,
+    /// Offset of the L3 (IP) header, valid once classified
+    pub l3_offset: u16,
+    /// Offset of the L4 header, valid once classified
+    pub l4_offset: u16,
+    /// Offset of the L4 payload, valid once classified
+    pub payload_offset: u16,
+    /// User mark set by processing stages
+    pub mark: u32,
+    /// Packet trace ID, 0 if the packet is not traced
+    pub trace_id: u32,
+    /// RSS hash of the flow, valid with `RX_RSS_HASH`
+    pub rss_hash: u32,
+    /// VLAN TCI stripped on receive (`RX_VLAN_STRIPPED`) or to insert on send (`TX_VLAN_INSERT`)
+    pub vlan_tci: u16,
+    /// Color given by the packet coloring rules, 0 if uncolored
+    pub color: u8,
+    /// Allocation state tracked by the debug checks
+    #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+    pub(crate) debug_state: u8,
     /// Reserved for future use
-    _padding: [u8; 64 - 56], // Pad to cache line size
+    _padding: [u8; MBUF_PADDING], // Pad to two cache lines
 }
 
+/// Bytes of `Mbuf` after its fields
+const MBUF_PADDING: usize = 2 * CACHE_LINE_SIZE - 67 - MBUF_DEBUG_BYTES;
+
+/// Length of an 802.1Q tag
+const VLAN_TAG_LEN: usize = 4;
+
 impl Mbuf {
     /// Create a new mbuf
     pub fn new(data: *mut u8, buf_len: usize) -> Self {
@@ -171,22 +239,39 @@ impl Mbuf {
             offload_flags: OffloadFlags::empty(),
             timestamp: 0,
             queue_id: 0,
-            _padding: [0; 8],
+            port_id: 0,
+            l3_offset: 0,
+            l4_offset: 0,
+            payload_offset: 0,
+            mark: 0,
+            trace_id: 0,
+            rss_hash: 0,
+            vlan_tci: 0,
+            color: 0,
+            #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+            debug_state: debug::STATE_UNTRACKED,
+            _padding: [0; MBUF_PADDING],
         }
     }
 
     /// Get data as slice
     pub fn data(&self) -> &[u8] {
+        #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+        debug::check_live(self);
         unsafe { std::slice::from_raw_parts(self.data, self.len) }
     }
 
     /// Get mutable data as slice
     pub fn data_mut(&mut self) -> &mut [u8] {
+        #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+        debug::check_live(self);
         unsafe { std::slice::from_raw_parts_mut(self.data, self.len) }
     }
 
     /// Append data to mbuf
     pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+        debug::check_live(self);
         if self.len + data.len() > self.buf_len {
             return Err(Error::MemoryAllocation("Mbuf overflow".to_string()));
         }
@@ -198,6 +283,111 @@ impl Mbuf {
         Ok(())
     }
 
+    /// IPv4 header checksum verdict
+    pub fn l3_checksum(&self) -> ChecksumStatus {
+        ChecksumStatus::from_flags(
+            &self.offload_flags,
+            OffloadFlags::RX_L3_CKSUM_GOOD,
+            OffloadFlags::RX_L3_CKSUM_BAD,
+        )
+    }
+
+    /// L4 checksum verdict
+    pub fn l4_checksum(&self) -> ChecksumStatus {
+        ChecksumStatus::from_flags(
+            &self.offload_flags,
+            OffloadFlags::RX_L4_CKSUM_GOOD,
+            OffloadFlags::RX_L4_CKSUM_BAD,
+        )
+    }
+
+    /// Record checksum verdicts, replacing earlier ones
+    pub fn set_checksum_status(&mut self, l3: ChecksumStatus, l4: ChecksumStatus) {
+        self.offload_flags
+            .remove(OffloadFlags::RX_L3_CKSUM_NONE | OffloadFlags::RX_L4_CKSUM_NONE);
+        self.offload_flags |= l3.to_flags(
+            OffloadFlags::RX_L3_CKSUM_GOOD,
+            OffloadFlags::RX_L3_CKSUM_BAD,
+        ) | l4.to_flags(
+            OffloadFlags::RX_L4_CKSUM_GOOD,
+            OffloadFlags::RX_L4_CKSUM_BAD,
+        );
+    }
+
+    /// Whether the receive timestamp was taken by the NIC
+    pub fn hardware_timestamp(&self) -> bool {
+        self.offload_flags.contains(OffloadFlags::RX_TIMESTAMP_HW)
+    }
+
+    /// RSS hash of the flow, if one was computed
+    pub fn rss_hash(&self) -> Option<u32> {
+        self.offload_flags
+            .contains(OffloadFlags::RX_RSS_HASH)
+            .then_some(self.rss_hash)
+    }
+
+    /// Record the RSS hash of the flow
+    pub fn set_rss_hash(&mut self, hash: u32) {
+        self.rss_hash = hash;
+        self.offload_flags |= OffloadFlags::RX_RSS_HASH;
+    }
+
+    /// TCI of the VLAN tag stripped on receive
+    pub fn vlan_tci(&self) -> Option<u16> {
+        self.offload_flags
+            .contains(OffloadFlags::RX_VLAN_STRIPPED)
+            .then_some(self.vlan_tci)
+    }
+
+    /// Move an 802.1Q tag from the frame into `vlan_tci`, returning whether there was one
+    ///
+    /// Header offsets are stale afterwards and the mbuf is left unclassified.
+    pub fn strip_vlan(&mut self) -> bool {
+        let frame = self.data_mut();
+        if frame.len() < 12 + VLAN_TAG_LEN + 2
+            || u16::from_be_bytes([frame[12], frame[13]]) != crate::udp::ETHERTYPE_VLAN
+        {
+            return false;
+        }
+        let tci = u16::from_be_bytes([frame[14], frame[15]]);
+        frame.copy_within(12 + VLAN_TAG_LEN.., 12);
+        self.len -= VLAN_TAG_LEN;
+        self.vlan_tci = tci;
+        self.offload_flags |= OffloadFlags::RX_VLAN_STRIPPED;
+        self.packet_type = PacketType::Unknown;
+        true
+    }
+
+    /// Ask for an 802.1Q tag with `tci` to be inserted on send
+    pub fn request_vlan_insert(&mut self, tci: u16) {
+        self.vlan_tci = tci;
+        self.offload_flags |= OffloadFlags::TX_VLAN_INSERT;
+    }
+
+    /// Insert the tag requested with `TX_VLAN_INSERT` into the frame
+    ///
+    /// Software fallback for interfaces without VLAN insertion; the request is
+    /// cleared once the tag is in place.
+    pub fn insert_vlan(&mut self) -> Result<()> {
+        if !self.offload_flags.contains(OffloadFlags::TX_VLAN_INSERT) {
+            return Ok(());
+        }
+        if self.len < 12 || self.len + VLAN_TAG_LEN > self.buf_len {
+            return Err(Error::OffloadError(
+                "No room to insert a VLAN tag".to_string(),
+            ));
+        }
+        self.len += VLAN_TAG_LEN;
+        let tci = self.vlan_tci;
+        let frame = self.data_mut();
+        frame.copy_within(12..frame.len() - VLAN_TAG_LEN, 12 + VLAN_TAG_LEN);
+        frame[12..14].copy_from_slice(&crate::udp::ETHERTYPE_VLAN.to_be_bytes());
+        frame[14..16].copy_from_slice(&tci.to_be_bytes());
+        self.offload_flags.remove(OffloadFlags::TX_VLAN_INSERT);
+        self.packet_type = PacketType::Unknown;
+        Ok(())
+    }
+
     /// Reset mbuf
     pub fn reset(&mut self) {
         self.len = 0;
@@ -205,6 +395,14 @@ impl Mbuf {
         self.offload_flags = OffloadFlags::empty();
         self.timestamp = 0;
         self.queue_id = 0;
+        self.port_id = 0;
+        self.l3_offset = 0;
+        self.l4_offset = 0;
+        self.payload_offset = 0;
+        self.mark = 0;
+        self.trace_id = 0;
+        self.rss_hash = 0;
+        self.vlan_tci = 0;
     }
 }
 
@@ -229,14 +427,113 @@ impl Default for PacketType {
     }
 }
 
-// Offload flags
+// Offload flags: what receive found in the low 16 bits, what send is asked
+// to do in the high 16 bits. A checksum with both its GOOD and BAD bits set
+// is NONE: the packet carries no such checksum.
 bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct OffloadFlags: u32 {
-        const CHECKSUM_OFFLOAD = 0x01;
-        const TCP_SEGMENTATION_OFFLOAD = 0x02;
-        const UDP_SEGMENTATION_OFFLOAD = 0x04;
-        const RSS_HASH = 0x08;
-        const TIMESTAMP = 0x10;
+        /// IPv4 header checksum verified good
+        const RX_L3_CKSUM_GOOD = 1 << 0;
+        /// IPv4 header checksum verified bad
+        const RX_L3_CKSUM_BAD = 1 << 1;
+        /// No L3 checksum in the packet
+        const RX_L3_CKSUM_NONE = Self::RX_L3_CKSUM_GOOD.bits() | Self::RX_L3_CKSUM_BAD.bits();
+        /// L4 checksum verified good
+        const RX_L4_CKSUM_GOOD = 1 << 2;
+        /// L4 checksum verified bad
+        const RX_L4_CKSUM_BAD = 1 << 3;
+        /// No L4 checksum in the packet (UDP checksum 0)
+        const RX_L4_CKSUM_NONE = Self::RX_L4_CKSUM_GOOD.bits() | Self::RX_L4_CKSUM_BAD.bits();
+        /// `rss_hash` holds the flow's RSS hash
+        const RX_RSS_HASH = 1 << 4;
+        /// A VLAN tag was removed from the frame, its TCI is in `vlan_tci`
+        const RX_VLAN_STRIPPED = 1 << 5;
+        /// `timestamp` holds the receive time
+        const RX_TIMESTAMP = 1 << 6;
+        /// `timestamp` was taken by the NIC rather than the host
+        const RX_TIMESTAMP_HW = 1 << 7;
+        /// The capture ends before the UDP datagram its headers claim
+        const RX_TRUNCATED = 1 << 8;
+        /// Every receive flag
+        const RX_MASK = Self::RX_L3_CKSUM_NONE.bits()
+            | Self::RX_L4_CKSUM_NONE.bits()
+            | Self::RX_RSS_HASH.bits()
+            | Self::RX_VLAN_STRIPPED.bits()
+            | Self::RX_TIMESTAMP.bits()
+            | Self::RX_TIMESTAMP_HW.bits()
+            | Self::RX_TRUNCATED.bits();
+
+        /// Compute the IPv4 header checksum
+        const TX_IP_CKSUM = 1 << 16;
+        /// Compute the UDP checksum
+        const TX_UDP_CKSUM = 1 << 17;
+        /// Compute the TCP checksum
+        const TX_TCP_CKSUM = 1 << 18;
+        /// Segment the TCP payload (TSO)
+        const TX_TCP_SEG = 1 << 19;
+        /// Segment the UDP payload (USO)
+        const TX_UDP_SEG = 1 << 20;
+        /// Insert a VLAN tag with the TCI in `vlan_tci`
+        const TX_VLAN_INSERT = 1 << 21;
+        /// Every send flag
+        const TX_MASK = Self::TX_IP_CKSUM.bits()
+            | Self::TX_UDP_CKSUM.bits()
+            | Self::TX_TCP_CKSUM.bits()
+            | Self::TX_TCP_SEG.bits()
+            | Self::TX_UDP_SEG.bits()
+            | Self::TX_VLAN_INSERT.bits();
+    }
+}
+
+impl OffloadFlags {
+    /// Receive flags only
+    pub fn rx(self) -> Self {
+        self & Self::RX_MASK
+    }
+
+    /// Send flags only
+    pub fn tx(self) -> Self {
+        self & Self::TX_MASK
+    }
+}
+
+/// Checksum verdict carried in the offload flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// Not verified yet
+    Unknown,
+    Good,
+    Bad,
+    /// The packet carries no checksum
+    None,
+}
+
+impl ChecksumStatus {
+    /// Whether the packet may be accepted: good or without a checksum
+    pub fn is_acceptable(self) -> bool {
+        matches!(self, ChecksumStatus::Good | ChecksumStatus::None)
+    }
+
+    fn from_flags(flags: &OffloadFlags, good: OffloadFlags, bad: OffloadFlags) -> Self {
+        if flags.contains(good | bad) {
+            ChecksumStatus::None
+        } else if flags.contains(bad) {
+            ChecksumStatus::Bad
+        } else if flags.contains(good) {
+            ChecksumStatus::Good
+        } else {
+            ChecksumStatus::Unknown
+        }
+    }
+
+    fn to_flags(self, good: OffloadFlags, bad: OffloadFlags) -> OffloadFlags {
+        match self {
+            ChecksumStatus::Unknown => OffloadFlags::empty(),
+            ChecksumStatus::Good => good,
+            ChecksumStatus::Bad => bad,
+            ChecksumStatus::None => good | bad,
+        }
     }
 }
 
@@ -251,30 +548,91 @@ pub struct MbufPool {
     /// Memory allocator
     #[allow(dead_code)]
     allocator: HugePageAllocator,
+    /// Base address of the mbuf headers
+    mbufs_base: *mut Mbuf,
+    /// Base address of the data buffers
+    data_base: *mut u8,
     /// Free list (using atomic stack for lock-free access)
-    free_list: AtomicPtr<Mbuf>,
+    free_list: FreeStack,
+    /// Next free mbuf of each free mbuf on the free list, plus one
+    links: Box<[AtomicU32]>,
     /// Pool metadata
-    metadata: UnsafeCell<PoolMetadata>,
+    metadata: PoolMetadata,
+    /// Buffers held back for control traffic
+    reservations: Reservations,
+    /// Extra references to each buffer taken with [`MbufPool::share`]
+    shares: Box<[AtomicU16]>,
+    /// What freeing an mbuf clears
+    reset_policy: ResetPolicy,
+    /// Cost of zeroing under [`ResetPolicy::Zero`]
+    reset_stats: ResetStats,
+    /// Lifetime allocation counters
+    counters: PoolCounters,
+    /// Per-core free caches, if enabled
+    core_caches: Option<CoreCaches>,
+    /// Lifetime bookkeeping for debug checks
+    #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+    debug: debug::PoolDebug,
     /// Mutex for thread-safe operations
     #[allow(dead_code)]
     mutex: Mutex<()>,
+    /// Memory budget held for the pool
+    #[allow(dead_code)]
+    charge: Option<BudgetCharge>,
+}
+
+/// Allocations, frees and failed allocations over the pool's lifetime
+#[derive(Debug, Default)]
+struct PoolCounters {
+    allocs: Counter,
+    frees: Counter,
+    alloc_failures: Counter,
 }
 
 #[derive(Debug)]
 struct PoolMetadata {
     /// Total allocated mbufs
     allocated: usize,
-    /// Available mbufs
-    available: usize,
+    /// Free mbufs not yet claimed by an allocation
+    available: AtomicUsize,
     /// Peak usage
-    peak_usage: usize,
+    peak_usage: AtomicUsize,
 }
 
 impl MbufPool {
     /// Create a new mbuf pool
     pub fn new(name: String, size: usize, buf_size: usize) -> Result<Self> {
+        Self::create(name, size, buf_size, None)
+    }
+
+    /// Create an mbuf pool charged to `budget`
+    ///
+    /// Fails with [`Error::BudgetExceeded`] if the pool does not fit.
+    pub fn with_budget(
+        name: String,
+        size: usize,
+        buf_size: usize,
+        budget: &Arc<MemoryBudget>,
+    ) -> Result<Self> {
+        let charge = budget.charge(Subsystem::Pools, Self::memory_size(size, buf_size))?;
+        Self::create(name, size, buf_size, Some(charge))
+    }
+
+    /// Bytes taken by a pool of `size` buffers of `buf_size` bytes, `usize::MAX` past it
+    pub fn memory_size(size: usize, buf_size: usize) -> usize {
+        buf_size
+            .saturating_add(std::mem::size_of::<Mbuf>())
+            .saturating_mul(size)
+    }
+
+    fn create(
+        name: String,
+        size: usize,
+        buf_size: usize,
+        charge: Option<BudgetCharge>,
+    ) -> Result<Self> {
         let allocator = HugePageAllocator::new()?;
-        let total_memory = size * (std::mem::size_of::<Mbuf>() + buf_size);
+        let total_memory = Self::memory_size(size, buf_size);
 
         // Allocate memory for mbufs and data buffers
         let memory_base = allocator.allocate(total_memory)? as *mut u8;
@@ -283,8 +641,6 @@ impl MbufPool {
         let mbufs_ptr = memory_base as *mut Mbuf;
         let data_ptr = unsafe { memory_base.add(size * std::mem::size_of::<Mbuf>()) };
 
-        // Build free list
-        let mut free_head: *mut Mbuf = ptr::null_mut();
         for i in 0..size {
             let mbuf_ptr = unsafe { mbufs_ptr.add(i) };
             let mbuf_data = unsafe { data_ptr.add(i * buf_size) };
@@ -293,96 +649,511 @@ impl MbufPool {
                 ptr::write(mbuf_ptr, Mbuf::new(mbuf_data, buf_size));
             }
 
-            // Add to free list (push to front)
-            unsafe {
-                (*mbuf_ptr).data = mbuf_data;
-                let _next_ptr = (*mbuf_ptr).data as *mut Mbuf;
-                ptr::write(mbuf_ptr as *mut *mut Mbuf, free_head);
-                free_head = mbuf_ptr;
-            }
+            #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+            debug::PoolDebug::init(unsafe { &mut *mbuf_ptr }, unsafe {
+                std::slice::from_raw_parts_mut(mbuf_data, buf_size)
+            });
+        }
+        if size >= u32::MAX as usize {
+            return Err(Error::InvalidConfig(format!(
+                "Pool size {} out of range",
+                size
+            )));
         }
 
+        // Build free list, the first mbuf on top
+        let free_list = FreeStack::default();
+        let links: Box<[AtomicU32]> = (0..size).map(|_| AtomicU32::new(0)).collect();
+        let indices: Vec<u32> = (0..size as u32).collect();
+        free_list.push_chain(&links, &indices);
+
         Ok(Self {
             name,
             size,
             buf_size,
             allocator,
-            free_list: AtomicPtr::new(free_head),
-            metadata: UnsafeCell::new(PoolMetadata {
+            mbufs_base: mbufs_ptr,
+            data_base: data_ptr,
+            free_list,
+            links,
+            metadata: PoolMetadata {
                 allocated: size,
-                available: size,
-                peak_usage: 0,
-            }),
+                available: AtomicUsize::new(size),
+                peak_usage: AtomicUsize::new(0),
+            },
+            reservations: Reservations::new(size),
+            shares: (0..size).map(|_| AtomicU16::new(0)).collect(),
+            reset_policy: ResetPolicy::default(),
+            reset_stats: ResetStats::default(),
+            counters: PoolCounters::default(),
+            core_caches: None,
             mutex: Mutex::new(()),
+            charge,
+            #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+            debug: debug::PoolDebug::new(size),
         })
     }
 
-    /// Allocate an mbuf from the pool
+    /// Clear freed mbufs according to `policy`
+    pub fn with_reset_policy(mut self, policy: ResetPolicy) -> Self {
+        self.reset_policy = policy;
+        self
+    }
+
+    /// Cache up to `cache_size` free mbufs per core
+    ///
+    /// Frees on another core than the allocating one are routed back to
+    /// the allocating core's cache; see [`cache`].
+    pub fn with_core_caches(mut self, cache_size: usize) -> Self {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        self.core_caches = Some(CoreCaches::new(self.size, cores, cache_size));
+        self
+    }
+
+    /// Free counters of the core caches, if enabled
+    pub fn core_cache_stats(&self) -> Option<&CoreCacheStats> {
+        self.core_caches.as_ref().map(CoreCaches::stats)
+    }
+
+    /// Get the reset policy
+    pub fn reset_policy(&self) -> ResetPolicy {
+        self.reset_policy
+    }
+
+    /// Cost of zeroing freed buffers
+    pub fn reset_stats(&self) -> &ResetStats {
+        &self.reset_stats
+    }
+
+    /// Allocate an mbuf from the pool for [`AllocClass::Data`]
     pub fn alloc(&self) -> Result<*mut Mbuf> {
-        loop {
-            let current_head = self.free_list.load(Ordering::Acquire);
-            if current_head.is_null() {
-                return Err(Error::MemoryAllocation("Pool exhausted".to_string()));
+        self.alloc_with_class(AllocClass::Data)
+    }
+
+    /// Allocate an mbuf for `class`, leaving the buffers reserved for other classes
+    pub fn alloc_with_class(&self, class: AllocClass) -> Result<*mut Mbuf> {
+        let mut mbuf = [ptr::null_mut()];
+        self.alloc_bulk_with_class(&mut mbuf, class)?;
+        Ok(mbuf[0])
+    }
+
+    /// Fill `mbufs` with mbufs for [`AllocClass::Data`], all or none
+    ///
+    /// The mbufs are unlinked from the free list with one compare-and-swap
+    /// instead of one each. Fails with [`Error::MemoryAllocation`] without
+    /// allocating any if fewer are available.
+    pub fn alloc_bulk(&self, mbufs: &mut [*mut Mbuf]) -> Result<()> {
+        self.alloc_bulk_with_class(mbufs, AllocClass::Data)
+    }
+
+    fn alloc_bulk_with_class(&self, mbufs: &mut [*mut Mbuf], class: AllocClass) -> Result<()> {
+        if mbufs.is_empty() {
+            return Ok(());
+        }
+        if !self.claim(class, mbufs.len()) {
+            self.reservations.on_failure(class);
+            self.counters.alloc_failures.inc();
+            return Err(Error::MemoryAllocation(format!(
+                "Pool exhausted for {} traffic",
+                class
+            )));
+        }
+        let taken = match &self.core_caches {
+            Some(caches) => self.alloc_cached(caches, mbufs),
+            None => self.unlink_chain(mbufs.len()).map(|mut index| {
+                for (i, slot) in mbufs.iter_mut().enumerate() {
+                    if i > 0 {
+                        index = next_in_chain(&self.links, index);
+                    }
+                    *slot = unsafe { self.mbufs_base.add(index as usize) };
+                }
+            }),
+        };
+        match taken {
+            Ok(()) => {
+                for &mut mbuf in mbufs.iter_mut() {
+                    self.take(mbuf);
+                    if self.reset_policy == ResetPolicy::None {
+                        unsafe { (*mbuf).reset() };
+                    }
+                    let index = self.contains(mbuf).then(|| self.index_of(mbuf));
+                    self.reservations.on_alloc(class, index);
+                }
+                self.counters.allocs.add(mbufs.len() as u64);
+                Ok(())
+            }
+            Err(e) => {
+                self.add_available(mbufs.len());
+                self.reservations.on_failure(class);
+                self.counters.alloc_failures.inc();
+                Err(e)
             }
+        }
+    }
 
-            let next = unsafe { *(current_head as *const *mut Mbuf) };
+    /// Claim `count` free mbufs for `class`, all or none
+    ///
+    /// Admission and the claim are one compare-and-swap on the free count,
+    /// so concurrent allocations never take the reserves of other classes
+    /// between them: the last mbuf claimed must still leave those.
+    fn claim(&self, class: AllocClass, count: usize) -> bool {
+        let claimed = self.metadata.available.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |available| {
+                (available >= count && self.reservations.admits(class, available - (count - 1)))
+                    .then(|| available - count)
+            },
+        );
+        match claimed {
+            Ok(available) => {
+                let in_use = self.size - (available - count);
+                self.metadata
+                    .peak_usage
+                    .fetch_max(in_use, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
 
-            if self
-                .free_list
-                .compare_exchange_weak(current_head, next, Ordering::Release, Ordering::Relaxed)
-                .is_ok()
-            {
-                let metadata = unsafe { &mut *self.metadata.get() };
-                metadata.available = metadata.available.saturating_sub(1);
-                metadata.peak_usage = metadata.peak_usage.max(self.size - metadata.available);
-                return Ok(current_head);
+    /// Hold back `count` buffers for allocations of `class`
+    ///
+    /// Fails with [`Error::InvalidConfig`] if the reservations of all
+    /// classes would exceed the pool size.
+    pub fn set_reservation(&self, class: AllocClass, count: usize) -> Result<()> {
+        let others = self.reservations.total() - self.reservations.stats(class).reserved;
+        if others + count > self.size {
+            return Err(Error::InvalidConfig(format!(
+                "Reserving {} {} buffers exceeds pool {} of {}",
+                count, class, self.name, self.size
+            )));
+        }
+        self.reservations.set(class, count);
+        Ok(())
+    }
+
+    /// Counters of one allocation class
+    pub fn class_stats(&self, class: AllocClass) -> ClassStats {
+        self.reservations.stats(class)
+    }
+
+    /// Count `count` more free mbufs, freed or given back by a failed allocation
+    fn add_available(&self, count: usize) {
+        self.metadata.available.fetch_add(count, Ordering::AcqRel);
+    }
+
+    /// Unlink `count` mbufs claimed with [`MbufPool::claim`] from the free list
+    /// with one compare-and-swap
+    ///
+    /// Returns the index of the first; the others follow it through the
+    /// free list links.
+    fn unlink_chain(&self, count: usize) -> Result<u32> {
+        self.free_list
+            .pop_chain(&self.links, count)
+            .ok_or_else(|| Error::MemoryAllocation("Pool exhausted".to_string()))
+    }
+
+    /// Make an mbuf unlinked from the free list usable
+    fn take(&self, mbuf: *mut Mbuf) {
+        // Point the data back at the start of the buffer
+        if self.contains(mbuf) {
+            unsafe {
+                (*mbuf).data = self.data_ptr_for(mbuf);
             }
+
+            #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+            self.debug.on_alloc(
+                &self.name,
+                self.index_of(mbuf),
+                unsafe { &mut *mbuf },
+                unsafe { std::slice::from_raw_parts(self.data_ptr_for(mbuf), self.buf_size) },
+            );
+        }
+    }
+
+    /// Take an extra reference to an mbuf in use
+    ///
+    /// Each reference is released by one [`MbufPool::free`], and the mbuf
+    /// returns to the pool with the last. Holders of a shared mbuf must not
+    /// modify it. Fails with [`Error::MemoryAllocation`] for an mbuf of
+    /// another pool.
+    pub fn share(&self, mbuf: *mut Mbuf) -> Result<()> {
+        if !self.contains(mbuf) {
+            return Err(Error::MemoryAllocation(format!(
+                "Mbuf does not belong to pool {}",
+                self.name
+            )));
+        }
+        let shares = &self.shares[self.index_of(mbuf)];
+        shares
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |count| {
+                count.checked_add(1)
+            })
+            .map_err(|_| Error::MemoryAllocation("Mbuf shared too often".to_string()))?;
+        Ok(())
+    }
+
+    /// Holders of an mbuf besides its owner
+    pub fn share_count(&self, mbuf: *const Mbuf) -> usize {
+        if self.contains(mbuf) {
+            self.shares[self.index_of(mbuf)].load(Ordering::Acquire) as usize
+        } else {
+            0
         }
     }
 
     /// Free an mbuf back to the pool
+    ///
+    /// An mbuf with extra references only loses one.
     pub fn free(&self, mbuf: *mut Mbuf) -> Result<()> {
-        if mbuf.is_null() {
+        self.free_bulk(&[mbuf])
+    }
+
+    /// Free `mbufs` back to the pool, skipping null ones
+    ///
+    /// The mbufs are linked into one chain and returned to the free list
+    /// with one compare-and-swap. Mbufs with extra references only lose one.
+    /// Fails with [`Error::MemoryAllocation`], freeing none, if an mbuf is
+    /// of another pool.
+    pub fn free_bulk(&self, mbufs: &[*mut Mbuf]) -> Result<()> {
+        if let Some(&foreign) = mbufs
+            .iter()
+            .find(|&&mbuf| !mbuf.is_null() && !self.contains(mbuf))
+        {
+            return Err(Error::MemoryAllocation(format!(
+                "Mbuf {:p} does not belong to pool {}",
+                foreign, self.name
+            )));
+        }
+        if let Some(caches) = &self.core_caches {
+            for chunk in mbufs.chunks(cache::FREE_CHUNK) {
+                let mut indices = [0u32; cache::FREE_CHUNK];
+                let mut count = 0;
+                for &mbuf in chunk {
+                    if self.release(mbuf) {
+                        indices[count] = self.index_of(mbuf) as u32;
+                        count += 1;
+                    }
+                }
+                // Every released mbuf is free, cached or not
+                self.add_available(count);
+                self.counters.frees.add(count as u64);
+                self.free_cached(caches, &mut indices[..count]);
+            }
             return Ok(());
         }
 
-        // Reset mbuf
-        unsafe {
-            (*mbuf).reset();
+        let mut top = 0;
+        let mut bottom = None;
+        let mut count = 0;
+        for &mbuf in mbufs {
+            if !self.release(mbuf) {
+                continue;
+            }
+            let index = self.index_of(mbuf) as u32;
+            match bottom {
+                Some(above) => self.links[above as usize].store(index + 1, Ordering::Relaxed),
+                None => top = index,
+            }
+            bottom = Some(index);
+            count += 1;
         }
+        let Some(bottom) = bottom else {
+            return Ok(());
+        };
+        self.free_list.push_linked(&self.links, top, bottom, count);
+        self.add_available(count);
+        self.counters.frees.add(count as u64);
+        Ok(())
+    }
 
-        loop {
-            let current_head = self.free_list.load(Ordering::Acquire);
+    /// Drop a reference to `mbuf`, readying it for the free list if it was the last
+    fn release(&self, mbuf: *mut Mbuf) -> bool {
+        if mbuf.is_null() {
+            return false;
+        }
+        if self.contains(mbuf)
+            && self.shares[self.index_of(mbuf)]
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                    count.checked_sub(1)
+                })
+                .is_ok()
+        {
+            return false;
+        }
 
+        if self.reset_policy != ResetPolicy::None {
             unsafe {
-                *(mbuf as *mut *mut Mbuf) = current_head;
+                (*mbuf).reset();
             }
+        }
+        if self.reset_policy == ResetPolicy::Zero && self.contains(mbuf) {
+            let started = Instant::now();
+            reset::zero(unsafe {
+                std::slice::from_raw_parts_mut(self.data_ptr_for(mbuf), self.buf_size)
+            });
+            self.reset_stats
+                .record(self.buf_size, started.elapsed().as_nanos() as u64);
+        }
 
-            if self
-                .free_list
-                .compare_exchange_weak(current_head, mbuf, Ordering::Release, Ordering::Relaxed)
-                .is_ok()
-            {
-                let metadata = unsafe { &mut *self.metadata.get() };
-                metadata.available = metadata.available.saturating_add(1);
-                return Ok(());
+        #[cfg(all(feature = "mbuf-debug", debug_assertions))]
+        if self.contains(mbuf) {
+            self.debug.on_free(
+                &self.name,
+                self.index_of(mbuf),
+                unsafe { &mut *mbuf },
+                unsafe { std::slice::from_raw_parts_mut(self.data_ptr_for(mbuf), self.buf_size) },
+            );
+        }
+        self.reservations
+            .on_free(self.contains(mbuf).then(|| self.index_of(mbuf)));
+        true
+    }
+
+    /// Check whether an mbuf belongs to this pool
+    pub fn contains(&self, mbuf: *const Mbuf) -> bool {
+        let start = self.mbufs_base as usize;
+        let end = start + self.size * std::mem::size_of::<Mbuf>();
+        let addr = mbuf as usize;
+
+        addr >= start && addr < end && (addr - start).is_multiple_of(std::mem::size_of::<Mbuf>())
+    }
+
+    /// Fault in every page of the pool now instead of on the first packets
+    ///
+    /// Asks the kernel to populate the pages writable, falling back to
+    /// writing each page with an atomic add of zero, which leaves buffer
+    /// contents as they are. Returns the bytes touched.
+    pub fn prefault(&self) -> usize {
+        let base = self.mbufs_base as *mut u8;
+        let len = Self::memory_size(self.size, self.buf_size);
+        let populated =
+            unsafe { libc::madvise(base as *mut c_void, len, libc::MADV_POPULATE_WRITE) } == 0;
+        if !populated {
+            let page_size = PageInfo::new().map_or(4096, |info| info.regular_size.max(1));
+            for offset in (0..len).step_by(page_size) {
+                unsafe { (*(base.add(offset) as *const AtomicU8)).fetch_add(0, Ordering::Relaxed) };
             }
         }
+        len
+    }
+
+    /// NUMA node holding the pool's first data buffer, if known
+    ///
+    /// Unknown without the `numa` feature or before the page is faulted in,
+    /// see [`MbufPool::prefault`].
+    pub fn memory_node(&self) -> Option<usize> {
+        #[cfg(feature = "numa")]
+        {
+            crate::utils::numa::memory_node(self.data_base as *const c_void).ok()
+        }
+        #[cfg(not(feature = "numa"))]
+        {
+            None
+        }
+    }
+
+    /// Get the data buffer size of each mbuf
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    /// Get the number of mbufs in the pool
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the number of free mbufs, without building [`PoolStats`]
+    pub fn available(&self) -> usize {
+        self.metadata.available.load(Ordering::Relaxed)
+    }
+
+    /// Get pool name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Index of an mbuf of this pool
+    fn index_of(&self, mbuf: *const Mbuf) -> usize {
+        (mbuf as usize - self.mbufs_base as usize) / std::mem::size_of::<Mbuf>()
+    }
+
+    /// Compute the data buffer address belonging to an mbuf of this pool
+    fn data_ptr_for(&self, mbuf: *const Mbuf) -> *mut u8 {
+        unsafe { self.data_base.add(self.index_of(mbuf) * self.buf_size) }
     }
 
     /// Get pool statistics
     pub fn stats(&self) -> PoolStats {
-        let metadata = unsafe { &*self.metadata.get() };
+        let metadata = &self.metadata;
+        let available = metadata.available.load(Ordering::Relaxed);
         PoolStats {
             name: self.name.clone(),
             size: self.size,
             buf_size: self.buf_size,
             allocated: metadata.allocated,
-            available: metadata.available,
-            in_use: metadata.allocated - metadata.available,
-            peak_usage: metadata.peak_usage,
+            available,
+            in_use: metadata.allocated.saturating_sub(available),
+            peak_usage: metadata.peak_usage.load(Ordering::Relaxed),
+            allocs: self.counters.allocs.get(),
+            frees: self.counters.frees.get(),
+            alloc_failures: self.counters.alloc_failures.get(),
+        }
+    }
+}
+
+unsafe impl Send for MbufPool {}
+unsafe impl Sync for MbufPool {}
+
+/// Mbufs waiting to be freed to one pool with [`MbufPool::free_bulk`]
+///
+/// Datapath loops that drop many frames push them here instead of
+/// freeing each, so the pool's free list is updated once per
+/// [`FREE_BATCH_SIZE`] mbufs. Whatever is left is freed on drop.
+pub(crate) struct FreeBatch<'a> {
+    pool: &'a MbufPool,
+    mbufs: [*mut Mbuf; FREE_BATCH_SIZE],
+    len: usize,
+}
+
+impl<'a> FreeBatch<'a> {
+    pub(crate) fn new(pool: &'a MbufPool) -> Self {
+        Self {
+            pool,
+            mbufs: [ptr::null_mut(); FREE_BATCH_SIZE],
+            len: 0,
         }
     }
+
+    /// Pool the mbufs are freed to
+    pub(crate) fn pool(&self) -> &'a MbufPool {
+        self.pool
+    }
+
+    /// Queue `mbuf` to be freed, freeing the batch once full
+    pub(crate) fn push(&mut self, mbuf: *mut Mbuf) -> Result<()> {
+        self.mbufs[self.len] = mbuf;
+        self.len += 1;
+        if self.len == FREE_BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Free the queued mbufs
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        let len = std::mem::take(&mut self.len);
+        self.pool.free_bulk(&self.mbufs[..len])
+    }
+}
+
+impl Drop for FreeBatch<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
 }
 
 /// Pool statistics
@@ -395,6 +1166,12 @@ pub struct PoolStats {
     pub available: usize,
     pub in_use: usize,
     pub peak_usage: usize,
+    /// Allocations since the pool was created
+    pub allocs: u64,
+    /// Frees returning an mbuf to the pool, not counting released shares
+    pub frees: u64,
+    /// Allocations refused because the pool or the class reserve ran out
+    pub alloc_failures: u64,
 }
 
 /// Memory manager for the entire system
@@ -403,20 +1180,28 @@ pub struct MemoryManager {
     config: Config,
     pools: Vec<MbufPool>,
     allocator: HugePageAllocator,
+    budget: Arc<MemoryBudget>,
 }
 
 impl MemoryManager {
-    /// Create a new memory manager
+    /// Create a new memory manager with a budget of [`Config::memory_budget`]
     pub fn new(config: &Config) -> Result<Self> {
+        Self::with_budget(config, Arc::new(MemoryBudget::new(config.memory_budget)))
+    }
+
+    /// Create a memory manager whose pools are charged to `budget`
+    pub fn with_budget(config: &Config, budget: Arc<MemoryBudget>) -> Result<Self> {
         let allocator = HugePageAllocator::new()?;
         let mut pools = Vec::with_capacity(config.pool_count);
 
         for i in 0..config.pool_count {
-            let pool = MbufPool::new(
+            let pool = MbufPool::with_budget(
                 format!("pool_{}", i),
                 config.pool_size,
                 2048, // Default buffer size: 2KB
-            )?;
+                &budget,
+            )?
+            .with_reset_policy(config.mbuf_reset);
             pools.push(pool);
         }
 
@@ -424,14 +1209,30 @@ impl MemoryManager {
             config: config.clone(),
             pools,
             allocator,
+            budget,
         })
     }
 
+    /// Get the memory budget shared with the other subsystems
+    pub fn budget(&self) -> &Arc<MemoryBudget> {
+        &self.budget
+    }
+
     /// Get a memory pool by index
     pub fn get_pool(&self, index: usize) -> Option<&MbufPool> {
         self.pools.get(index)
     }
 
+    /// Iterate over the memory pools
+    pub fn pools(&self) -> impl Iterator<Item = &MbufPool> {
+        self.pools.iter()
+    }
+
+    /// Get a memory pool by name
+    pub fn pool_by_name(&self, name: &str) -> Option<&MbufPool> {
+        self.pools.iter().find(|pool| pool.name() == name)
+    }
+
     /// Allocate an mbuf from the best available pool
     pub fn alloc_mbuf(&self) -> Result<*mut Mbuf> {
         for pool in &self.pools {
@@ -468,6 +1269,8 @@ impl MemoryManager {
         MemoryStats {
             allocation: alloc_stats,
             pools: pool_stats,
+            budget: self.budget.usage(),
+            tables: self.budget.tables(),
         }
     }
 }
@@ -477,6 +1280,10 @@ impl MemoryManager {
 pub struct MemoryStats {
     pub allocation: AllocationStats,
     pub pools: Vec<PoolStats>,
+    /// Budgeted bytes in use per subsystem
+    pub budget: BudgetUsage,
+    /// Usage of the tables registered with the budget
+    pub tables: Vec<TableUsage>,
 }
 
 #[cfg(test)]
@@ -511,6 +1318,36 @@ mod tests {
         assert_eq!(mbuf.len, 0);
     }
 
+    #[test]
+    fn test_mbuf_vlan_and_metadata_flags() {
+        assert_eq!(std::mem::size_of::<Mbuf>(), 2 * CACHE_LINE_SIZE);
+        let mut data = vec![0u8; 64];
+        let mut mbuf = Mbuf::new(data.as_mut_ptr(), data.len());
+        let untagged: Vec<u8> = (0..30).collect();
+        mbuf.append(&untagged).unwrap();
+        assert!(!mbuf.strip_vlan());
+
+        mbuf.request_vlan_insert(0x2064);
+        assert!(mbuf.offload_flags.rx().is_empty());
+        mbuf.insert_vlan().unwrap();
+        assert_eq!(mbuf.len, 34);
+        assert_eq!(&mbuf.data()[12..16], &[0x81, 0x00, 0x20, 0x64]);
+        assert_eq!(&mbuf.data()[16..], &untagged[12..]);
+        assert!(mbuf.offload_flags.tx().is_empty());
+
+        assert!(mbuf.strip_vlan());
+        assert_eq!(mbuf.data(), &untagged[..]);
+        assert_eq!(mbuf.vlan_tci(), Some(0x2064));
+        assert_eq!(mbuf.rss_hash(), None);
+        mbuf.set_rss_hash(7);
+        assert_eq!(
+            mbuf.offload_flags.rx(),
+            OffloadFlags::RX_VLAN_STRIPPED | OffloadFlags::RX_RSS_HASH
+        );
+        mbuf.reset();
+        assert_eq!((mbuf.vlan_tci(), mbuf.rss_hash()), (None, None));
+    }
+
     #[test]
     fn test_mbuf_pool() {
         let pool = MbufPool::new("test".to_string(), 16, 1024).unwrap();
@@ -521,5 +1358,161 @@ mod tests {
         let stats = pool.stats();
         assert_eq!(stats.size, 16);
         assert_eq!(stats.available, 16);
+        assert_eq!((stats.allocs, stats.frees, stats.alloc_failures), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_mbuf_pool_data_pointers() {
+        let pool = MbufPool::new("test".to_string(), 4, 256).unwrap();
+        let first = pool.alloc().unwrap();
+        let second = pool.alloc().unwrap();
+
+        unsafe {
+            assert!(!pool.contains((*first).data as *const Mbuf));
+            assert_ne!((*first).data, (*second).data);
+            (*first).append(&[0xAA; 256]).unwrap();
+            (*second).append(&[0x55; 256]).unwrap();
+            assert!((*first).data().iter().all(|&b| b == 0xAA));
+        }
+
+        assert!(pool.contains(first));
+        pool.free(first).unwrap();
+        pool.free(second).unwrap();
+
+        let again = pool.alloc().unwrap();
+        assert_eq!(unsafe { (*again).len }, 0);
+        assert_eq!(unsafe { (*again).data }, pool.data_ptr_for(again));
+        pool.free(again).unwrap();
+    }
+
+    #[test]
+    fn test_reset_policies() {
+        let pool = MbufPool::new("zero".to_string(), 1, 2048)
+            .unwrap()
+            .with_reset_policy(ResetPolicy::Zero);
+        let mbuf = pool.alloc().unwrap();
+        let data = unsafe { (*mbuf).data };
+        unsafe { (*mbuf).append(b"secret").unwrap() };
+        pool.free(mbuf).unwrap();
+        // Debug builds poison freed buffers over the zeroes
+        #[cfg(not(all(feature = "mbuf-debug", debug_assertions)))]
+        assert!(unsafe { std::slice::from_raw_parts(data, 2048) }
+            .iter()
+            .all(|&b| b == 0));
+        let _ = data;
+        let stats = pool.reset_stats();
+        assert_eq!(stats.zeroed_buffers.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.zeroed_bytes.load(Ordering::Relaxed), 2048);
+
+        // Without a reset on free, the next allocation still starts clean
+        let pool = MbufPool::new("lazy".to_string(), 1, 256)
+            .unwrap()
+            .with_reset_policy(ResetPolicy::None);
+        let mbuf = pool.alloc().unwrap();
+        unsafe {
+            (*mbuf).append(b"stale").unwrap();
+            (*mbuf).mark = 7;
+        }
+        pool.free(mbuf).unwrap();
+        assert_eq!(unsafe { (*mbuf).mark }, 7);
+        let mbuf = pool.alloc().unwrap();
+        assert_eq!(unsafe { ((*mbuf).len, (*mbuf).mark) }, (0, 0));
+        assert_eq!(pool.reset_stats().zeroed_buffers.load(Ordering::Relaxed), 0);
+        pool.free(mbuf).unwrap();
+    }
+
+    #[test]
+    fn test_reservations_survive_data_exhaustion() {
+        let pool = MbufPool::new("reserve".to_string(), 4, 256).unwrap();
+        pool.set_reservation(AllocClass::Icmp, 1).unwrap();
+        pool.set_reservation(AllocClass::Keepalive, 1).unwrap();
+        assert!(pool.set_reservation(AllocClass::Arp, 3).is_err());
+
+        // Data stops with the reserved buffers still free
+        let data = [pool.alloc().unwrap(), pool.alloc().unwrap()];
+        assert!(pool.alloc().is_err());
+        let icmp = pool.alloc_with_class(AllocClass::Icmp).unwrap();
+        assert!(pool.alloc_with_class(AllocClass::Icmp).is_err());
+        let keepalive = pool.alloc_with_class(AllocClass::Keepalive).unwrap();
+        assert!(pool.alloc_with_class(AllocClass::Keepalive).is_err());
+
+        let stats = pool.class_stats(AllocClass::Data);
+        assert_eq!((stats.in_use, stats.allocated, stats.failures), (2, 2, 1));
+        pool.free(icmp).unwrap();
+        assert_eq!(pool.class_stats(AllocClass::Icmp).in_use, 0);
+        assert!(pool.alloc().is_err());
+        for mbuf in data.into_iter().chain([keepalive]) {
+            pool.free(mbuf).unwrap();
+        }
+        assert_eq!(pool.class_stats(AllocClass::Keepalive).allocated, 1);
+        assert_eq!(pool.class_stats(AllocClass::Data).in_use, 0);
+
+        // Racing data allocations never dip into the reserve between them
+        let pool = Arc::new(MbufPool::new("race".to_string(), 16, 256).unwrap());
+        pool.set_reservation(AllocClass::Arp, 4).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..2000 {
+                        let mut mbufs = [ptr::null_mut(); 3];
+                        if pool.alloc_bulk(&mut mbufs).is_ok() {
+                            assert!(pool.class_stats(AllocClass::Data).in_use <= 12);
+                            pool.free_bulk(&mbufs).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(pool.stats().peak_usage <= 12);
+        assert_eq!(pool.stats().available, 16);
+    }
+
+    #[test]
+    fn test_bulk_alloc_and_free() {
+        let pool = MbufPool::new("bulk".to_string(), 8, 256).unwrap();
+        pool.set_reservation(AllocClass::Icmp, 1).unwrap();
+
+        let mut mbufs = [ptr::null_mut(); 5];
+        pool.alloc_bulk(&mut mbufs).unwrap();
+        let mut seen: Vec<_> = mbufs.iter().map(|&mbuf| mbuf as usize).collect();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), 5);
+        for &mbuf in &mbufs {
+            assert!(pool.contains(mbuf));
+            assert_eq!(unsafe { (*mbuf).data }, pool.data_ptr_for(mbuf));
+        }
+
+        // All or nothing, and never into another class's reserve
+        let mut more = [ptr::null_mut(); 3];
+        assert!(pool.alloc_bulk(&mut more).is_err());
+        assert!(more.iter().all(|mbuf| mbuf.is_null()));
+        pool.alloc_bulk(&mut more[..2]).unwrap();
+        assert_eq!(pool.stats().available, 1);
+
+        // Shared mbufs only lose a reference; nulls are skipped
+        pool.share(mbufs[0]).unwrap();
+        pool.free_bulk(&mbufs).unwrap();
+        pool.free_bulk(&[more[0], ptr::null_mut(), more[1]])
+            .unwrap();
+        assert_eq!(pool.stats().available, 7);
+        pool.free(mbufs[0]).unwrap();
+
+        let stats = pool.stats();
+        assert_eq!((stats.available, stats.allocs, stats.frees), (8, 7, 7));
+        assert_eq!(stats.alloc_failures, 1);
+        let mut all = [ptr::null_mut(); 7];
+        pool.alloc_bulk(&mut all).unwrap();
+        // An mbuf of another pool fails the whole free
+        let other = MbufPool::new("other".to_string(), 1, 256).unwrap();
+        let foreign = other.alloc().unwrap();
+        assert!(pool.free_bulk(&[all[0], foreign]).is_err());
+        assert_eq!(pool.stats().available, 1);
+        other.free(foreign).unwrap();
+        pool.free_bulk(&all).unwrap();
     }
 }
@@ -0,0 +1,266 @@
+//! Pool of reusable hot-path objects
+//!
+//! Scratch vectors and similar objects an application needs per packet are
+//! cheap to reuse and costly to allocate every time. An [`ObjectPool`]
+//! builds a fixed number of objects up front and lends them out as
+//! [`Pooled`] guards, which reset the object and hand it back when dropped,
+//! so taking one never reaches the global allocator. An object keeps what
+//! it grew, such as the capacity of a vector, from one use to the next.
+//! The stack's own hot paths use fixed arrays and do not draw from a pool.
+//!
+//! Free objects are linked by index into lock-free stacks, one shared and
+//! one cache per thread shard: a thread takes from its own cache, then the
+//! shared stack, then the caches of other shards, and returns objects to
+//! its cache until [`ObjectPool::with_cache_size`] objects sit there.
+
+use super::arena::thread_shard;
+use super::stack::FreeStack;
+use crate::utils::counter::Counter;
+use crate::{Error, Result};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Default number of free objects cached per thread shard
+pub const DEFAULT_OBJECT_CACHE_SIZE: usize = 16;
+
+/// Resets an object on its way back to the pool
+type ResetFn<T> = Box<dyn Fn(&mut T) + Send + Sync>;
+
+/// Counters of an object pool
+#[derive(Debug, Default)]
+pub struct ObjectPoolStats {
+    pub gets: Counter,
+    /// Objects taken from the calling thread's cache
+    pub cache_hits: Counter,
+    /// Objects taken from the cache of another thread shard
+    pub steals: Counter,
+    /// Gets failed with every object lent out
+    pub exhausted: Counter,
+    /// Objects returned to the shared stack with the cache full
+    pub spills: Counter,
+}
+
+/// Fixed set of reusable objects with per-thread free caches
+pub struct ObjectPool<T> {
+    objects: Box<[UnsafeCell<T>]>,
+    /// Next free slot of each free slot, plus one
+    links: Box<[AtomicU32]>,
+    shared: FreeStack,
+    caches: Box<[FreeStack]>,
+    cache_size: usize,
+    reset: Option<ResetFn<T>>,
+    in_use: AtomicUsize,
+    stats: ObjectPoolStats,
+}
+
+// A slot is only reached through the one guard that took it off a stack
+unsafe impl<T: Send> Send for ObjectPool<T> {}
+unsafe impl<T: Send> Sync for ObjectPool<T> {}
+
+impl<T> ObjectPool<T> {
+    /// Create a pool of `capacity` objects built with `init`
+    pub fn new(capacity: usize, init: impl FnMut() -> T) -> Result<Self> {
+        Self::with_cache_size(capacity, DEFAULT_OBJECT_CACHE_SIZE, init)
+    }
+
+    /// Create a pool caching up to `cache_size` free objects per thread shard
+    pub fn with_cache_size(
+        capacity: usize,
+        cache_size: usize,
+        mut init: impl FnMut() -> T,
+    ) -> Result<Self> {
+        if capacity == 0 || capacity >= u32::MAX as usize {
+            return Err(Error::InvalidConfig(format!(
+                "Object pool capacity {} out of range",
+                capacity
+            )));
+        }
+        let shard_count = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let pool = Self {
+            objects: (0..capacity).map(|_| UnsafeCell::new(init())).collect(),
+            links: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            shared: FreeStack::default(),
+            caches: (0..shard_count).map(|_| FreeStack::default()).collect(),
+            cache_size,
+            reset: None,
+            in_use: AtomicUsize::new(0),
+            stats: ObjectPoolStats::default(),
+        };
+        // Slot 0 ends up on top
+        for index in (0..capacity as u32).rev() {
+            pool.shared.push(&pool.links, index);
+        }
+        Ok(pool)
+    }
+
+    /// Run `reset` on every object handed back, before it is lent out again
+    pub fn with_reset(mut self, reset: impl Fn(&mut T) + Send + Sync + 'static) -> Self {
+        self.reset = Some(Box::new(reset));
+        self
+    }
+
+    /// Borrow a free object
+    ///
+    /// Fails with [`Error::MemoryAllocation`] while every object is lent out.
+    pub fn get(&self) -> Result<Pooled<'_, T>> {
+        self.stats.gets.inc();
+        // Claim an object first: the stacks then hold one for every claim
+        if self.in_use.fetch_add(1, Ordering::Relaxed) >= self.objects.len() {
+            self.in_use.fetch_sub(1, Ordering::Relaxed);
+            self.stats.exhausted.inc();
+            return Err(Error::MemoryAllocation(format!(
+                "Object pool exhausted ({} objects)",
+                self.objects.len()
+            )));
+        }
+        let shard = thread_shard() % self.caches.len();
+        if let Some(index) = self.caches[shard].pop(&self.links) {
+            self.stats.cache_hits.inc();
+            return Ok(Pooled::new(self, index));
+        }
+        loop {
+            if let Some(index) = self.shared.pop(&self.links) {
+                return Ok(Pooled::new(self, index));
+            }
+            if let Some(index) = self.caches.iter().find_map(|cache| cache.pop(&self.links)) {
+                self.stats.steals.inc();
+                return Ok(Pooled::new(self, index));
+            }
+            // The claimed object is still being pushed back
+            std::hint::spin_loop();
+        }
+    }
+
+    fn put(&self, index: u32) {
+        if let Some(reset) = &self.reset {
+            reset(unsafe { &mut *self.objects[index as usize].get() });
+        }
+        let cache = &self.caches[thread_shard() % self.caches.len()];
+        if cache.len() < self.cache_size {
+            cache.push(&self.links, index);
+        } else {
+            self.stats.spills.inc();
+            self.shared.push(&self.links, index);
+        }
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Number of objects in the pool
+    pub fn capacity(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Objects lent out
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> &ObjectPoolStats {
+        &self.stats
+    }
+}
+
+/// Object borrowed from an [`ObjectPool`], reset and returned on drop
+///
+/// A guard is shared across threads only if its object may be:
+///
+/// ```compile_fail
+/// fn shared<T: Sync>(_: &T) {}
+/// let pool = xpdk::memory::ObjectPool::new(1, || std::cell::Cell::new(0)).unwrap();
+/// shared(&pool.get().unwrap());
+/// ```
+pub struct Pooled<'a, T> {
+    pool: &'a ObjectPool<T>,
+    index: u32,
+    /// Shared like a `&mut T`: a guard is `Sync` only if `T` is
+    _object: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> Pooled<'a, T> {
+    fn new(pool: &'a ObjectPool<T>, index: u32) -> Self {
+        Self {
+            pool,
+            index,
+            _object: PhantomData,
+        }
+    }
+
+    /// Slot of the object in its pool
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+impl<T> Deref for Pooled<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.pool.objects[self.index as usize].get() }
+    }
+}
+
+impl<T> DerefMut for Pooled<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.pool.objects[self.index as usize].get() }
+    }
+}
+
+impl<T> Drop for Pooled<'_, T> {
+    fn drop(&mut self) {
+        self.pool.put(self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_objects_are_reused_and_reset() {
+        let pool = ObjectPool::with_cache_size(2, 1, || Vec::<u32>::with_capacity(4))
+            .unwrap()
+            .with_reset(Vec::clear);
+        let mut first = pool.get().unwrap();
+        first.extend([1, 2, 3, 4, 5]);
+        let index = first.index();
+        let second = pool.get().unwrap();
+        assert!(pool.get().is_err());
+        assert_eq!((pool.in_use(), pool.stats().exhausted.get()), (2, 1));
+        drop(first);
+        drop(second);
+
+        // The grown vector comes back empty and from this thread's cache
+        let again = pool.get().unwrap();
+        assert_eq!(again.index(), index);
+        assert!(again.is_empty() && again.capacity() >= 5);
+        assert_eq!(pool.stats().cache_hits.get(), 1);
+        assert_eq!(pool.stats().spills.get(), 1);
+        drop(again);
+
+        let pool = Arc::new(ObjectPool::with_cache_size(8, 2, || 0u64).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut objects: Vec<_> = (0..2).map(|_| pool.get().unwrap()).collect();
+                        for object in &mut objects {
+                            **object += 1;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(pool.in_use(), 0);
+        let objects: Vec<_> = (0..8).map(|_| pool.get().unwrap()).collect();
+        assert_eq!(objects.iter().map(|object| **object).sum::<u64>(), 8000);
+    }
+}
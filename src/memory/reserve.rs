@@ -0,0 +1,161 @@
+//! Pool reservations for control traffic
+//!
+//! Under data-plane overload an [`super::MbufPool`] runs dry, and the ARP,
+//! ICMP and keep-alive packets that keep the stack reachable would fail to
+//! allocate with the rest. A pool can hold back a number of its buffers for
+//! each [`AllocClass`] with [`super::MbufPool::set_reservation`]: an
+//! allocation with [`super::MbufPool::alloc_with_class`] may only take a
+//! buffer if the free buffers left afterwards still cover the unused
+//! reservations of every other class. Plain allocations are
+//! [`AllocClass::Data`], which normally reserves nothing, so they stop
+//! while control classes still find buffers. Each class counts what it has
+//! in use, allocated and failed to allocate; see
+//! [`super::MbufPool::class_stats`].
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Traffic class an mbuf is allocated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AllocClass {
+    /// Application datagrams and received frames
+    #[default]
+    Data,
+    /// Address resolution requests and replies
+    Arp,
+    /// ICMP errors and echo replies
+    Icmp,
+    /// Socket keep-alives
+    Keepalive,
+}
+
+impl AllocClass {
+    /// Number of classes
+    pub const COUNT: usize = 4;
+
+    /// Every class in index order
+    pub const ALL: [AllocClass; AllocClass::COUNT] = [
+        AllocClass::Data,
+        AllocClass::Arp,
+        AllocClass::Icmp,
+        AllocClass::Keepalive,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for AllocClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AllocClass::Data => "data",
+            AllocClass::Arp => "arp",
+            AllocClass::Icmp => "icmp",
+            AllocClass::Keepalive => "keepalive",
+        })
+    }
+}
+
+/// Point-in-time counters of one allocation class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassStats {
+    pub class: AllocClass,
+    /// Buffers held back for the class
+    pub reserved: usize,
+    /// Buffers of the class not yet freed
+    pub in_use: usize,
+    /// Successful allocations
+    pub allocated: usize,
+    /// Allocations refused for want of a buffer
+    pub failures: usize,
+}
+
+#[derive(Debug, Default)]
+struct ClassCounters {
+    reserved: AtomicUsize,
+    in_use: AtomicUsize,
+    allocated: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+/// Reservations and per-class accounting of one pool
+#[derive(Debug)]
+pub(crate) struct Reservations {
+    classes: [ClassCounters; AllocClass::COUNT],
+    /// Class each buffer was allocated for, by buffer index
+    owners: Box<[AtomicU8]>,
+}
+
+impl Reservations {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            classes: Default::default(),
+            owners: (0..size).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn set(&self, class: AllocClass, count: usize) {
+        self.classes[class.index()]
+            .reserved
+            .store(count, Ordering::Relaxed);
+    }
+
+    /// Buffers reserved over every class
+    pub(crate) fn total(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|counters| counters.reserved.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Whether `class` may take one of `available` free buffers
+    pub(crate) fn admits(&self, class: AllocClass, available: usize) -> bool {
+        let held_for_others: usize = AllocClass::ALL
+            .iter()
+            .filter(|&&other| other != class)
+            .map(|&other| {
+                let counters = &self.classes[other.index()];
+                counters
+                    .reserved
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(counters.in_use.load(Ordering::Relaxed))
+            })
+            .sum();
+        available > held_for_others
+    }
+
+    pub(crate) fn on_alloc(&self, class: AllocClass, index: Option<usize>) {
+        let counters = &self.classes[class.index()];
+        counters.in_use.fetch_add(1, Ordering::Relaxed);
+        counters.allocated.fetch_add(1, Ordering::Relaxed);
+        if let Some(owner) = index.and_then(|index| self.owners.get(index)) {
+            owner.store(class as u8, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn on_failure(&self, class: AllocClass) {
+        self.classes[class.index()]
+            .failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_free(&self, index: Option<usize>) {
+        let class = index
+            .and_then(|index| self.owners.get(index))
+            .map_or(0, |owner| owner.load(Ordering::Relaxed) as usize);
+        let in_use = &self.classes[class].in_use;
+        let _ = in_use.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub(crate) fn stats(&self, class: AllocClass) -> ClassStats {
+        let counters = &self.classes[class.index()];
+        ClassStats {
+            class,
+            reserved: counters.reserved.load(Ordering::Relaxed),
+            in_use: counters.in_use.load(Ordering::Relaxed),
+            allocated: counters.allocated.load(Ordering::Relaxed),
+            failures: counters.failures.load(Ordering::Relaxed),
+        }
+    }
+}
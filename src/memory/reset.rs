@@ -0,0 +1,106 @@
+//! What a pool clears when an mbuf is freed
+//!
+//! Clearing metadata on every free is cheap, and received frames are
+//! copied over whatever the buffer held before, so by default
+//! [`ResetPolicy::Metadata`] leaves the data alone. [`ResetPolicy::None`]
+//! keeps the free path from touching the mbuf and resets its metadata when
+//! it is next allocated instead, which is where the header is about to be
+//! written anyway. [`ResetPolicy::Zero`] also zeroes the whole data buffer
+//! so no payload outlives its mbuf, at a cost proportional to the buffer
+//! size: buffers of [`NON_TEMPORAL_THRESHOLD`] bytes or more are zeroed
+//! with non-temporal stores that bypass the cache, since a freed buffer is
+//! not read again soon, and the bytes and time spent zeroing are counted in
+//! [`ResetStats`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Buffer size from which zeroing uses non-temporal stores
+pub const NON_TEMPORAL_THRESHOLD: usize = 1024;
+
+/// What freeing an mbuf clears
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResetPolicy {
+    /// Nothing on free; metadata is reset on the next allocation
+    None,
+    /// Metadata on free, data left as is
+    #[default]
+    Metadata,
+    /// Metadata and the whole data buffer on free
+    Zero,
+}
+
+/// Cost of zeroing freed buffers
+#[derive(Debug, Default)]
+pub struct ResetStats {
+    /// Buffers zeroed
+    pub zeroed_buffers: AtomicU64,
+    /// Bytes zeroed
+    pub zeroed_bytes: AtomicU64,
+    /// Nanoseconds spent zeroing
+    pub zeroing_nanos: AtomicU64,
+}
+
+impl ResetStats {
+    /// Mean nanoseconds per zeroed buffer
+    pub fn nanos_per_buffer(&self) -> f64 {
+        let buffers = self.zeroed_buffers.load(Ordering::Relaxed);
+        if buffers == 0 {
+            return 0.0;
+        }
+        self.zeroing_nanos.load(Ordering::Relaxed) as f64 / buffers as f64
+    }
+
+    pub(crate) fn record(&self, bytes: usize, nanos: u64) {
+        self.zeroed_buffers.fetch_add(1, Ordering::Relaxed);
+        self.zeroed_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.zeroing_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Zero `buf`, bypassing the cache for large buffers where supported
+pub(crate) fn zero(buf: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    if buf.len() >= NON_TEMPORAL_THRESHOLD {
+        use std::arch::x86_64::{__m128i, _mm_setzero_si128, _mm_sfence, _mm_stream_si128};
+
+        // Plain stores up to the first 16-byte boundary and after the last
+        let head = buf.as_ptr().align_offset(16).min(buf.len());
+        let (start, rest) = buf.split_at_mut(head);
+        start.fill(0);
+        let body = rest.len() / 16 * 16;
+        let (aligned, tail) = rest.split_at_mut(body);
+        unsafe {
+            let zero = _mm_setzero_si128();
+            let base = aligned.as_mut_ptr() as *mut __m128i;
+            for i in 0..body / 16 {
+                _mm_stream_si128(base.add(i), zero);
+            }
+            // Order the streamed stores before the buffer is handed out again
+            _mm_sfence();
+        }
+        tail.fill(0);
+        return;
+    }
+    buf.fill(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_covers_unaligned_edges() {
+        let mut buf = vec![0xAAu8; NON_TEMPORAL_THRESHOLD + 64];
+        for (start, end) in [
+            (1, buf.len() - 3),
+            (0, 100),
+            (7, NON_TEMPORAL_THRESHOLD + 9),
+        ] {
+            buf.fill(0xAA);
+            zero(&mut buf[start..end]);
+            assert!(buf[start..end].iter().all(|&b| b == 0));
+            assert!(buf[..start].iter().all(|&b| b == 0xAA));
+            assert!(buf[end..].iter().all(|&b| b == 0xAA));
+        }
+    }
+}
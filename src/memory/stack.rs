@@ -0,0 +1,171 @@
+//! Tagged lock-free stack of free slot indices
+//!
+//! Free mbufs and pooled objects are linked by index through a links array
+//! owned by their pool, and a [`FreeStack`] holds the index of the top one.
+//! The head also carries a tag bumped on every pop, so a compare-and-swap
+//! never succeeds on a head that was popped and pushed back in between:
+//! links read on the way down may be stale, but then the tag moved on and
+//! the swap fails. This holds for chains of several slots as well, which
+//! the mbuf pool takes and returns with one swap.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Slot index in the low half of a stack head, plus one so that 0 is empty
+const HEAD_INDEX_MASK: u64 = 0xFFFF_FFFF;
+
+/// Lock-free stack of free slot indices, linked through a pool's `links`
+///
+/// `links[i]` holds the slot below slot `i` plus one, 0 at the bottom.
+#[derive(Default)]
+pub(crate) struct FreeStack {
+    /// Top slot index plus one in the low half, pop count in the high half
+    head: AtomicU64,
+    /// Slots on the stack, never below the true count
+    len: AtomicUsize,
+}
+
+impl FreeStack {
+    pub(crate) fn push(&self, links: &[AtomicU32], index: u32) {
+        self.push_chain(links, &[index]);
+    }
+
+    /// Push `indices` with one compare-and-swap, the first on top
+    pub(crate) fn push_chain(&self, links: &[AtomicU32], indices: &[u32]) {
+        let (Some(&top), Some(&bottom)) = (indices.first(), indices.last()) else {
+            return;
+        };
+        for pair in indices.windows(2) {
+            links[pair[0] as usize].store(pair[1] + 1, Ordering::Relaxed);
+        }
+        self.push_linked(links, top, bottom, indices.len());
+    }
+
+    /// Push the `count` slots already linked from `top` down to `bottom`
+    pub(crate) fn push_linked(&self, links: &[AtomicU32], top: u32, bottom: u32, count: usize) {
+        self.len.fetch_add(count, Ordering::Relaxed);
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            links[bottom as usize].store(head as u32, Ordering::Relaxed);
+            let new = (head & !HEAD_INDEX_MASK) | (top as u64 + 1);
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Acquire)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub(crate) fn pop(&self, links: &[AtomicU32]) -> Option<u32> {
+        self.pop_chain(links, 1)
+    }
+
+    /// Pop `count` slots with one compare-and-swap, all or none
+    ///
+    /// Returns the top one; the others follow it through `links`, which
+    /// stay as they are until the slots are pushed again.
+    pub(crate) fn pop_chain(&self, links: &[AtomicU32], count: usize) -> Option<u32> {
+        if count == 0 {
+            return None;
+        }
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let top = (head & HEAD_INDEX_MASK) as u32;
+            let mut bottom = top;
+            for _ in 1..count {
+                if bottom == 0 {
+                    break;
+                }
+                // Stale if a slot was taken meanwhile, but then the tag moved on
+                bottom = links[bottom as usize - 1].load(Ordering::Relaxed);
+            }
+            if bottom == 0 {
+                // Cut short by a concurrent pop rather than a short stack
+                let current = self.head.load(Ordering::Acquire);
+                if current != head {
+                    head = current;
+                    continue;
+                }
+                return None;
+            }
+            let next = links[bottom as usize - 1].load(Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match self.head.compare_exchange_weak(
+                head,
+                tag << 32 | next as u64,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.len.fetch_sub(count, Ordering::Relaxed);
+                    return Some(top - 1);
+                }
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+}
+
+/// Slot below `index` in a chain popped from a [`FreeStack`]
+pub(crate) fn next_in_chain(links: &[AtomicU32], index: u32) -> u32 {
+    links[index as usize].load(Ordering::Relaxed) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_chains_are_never_handed_out_twice() {
+        const SLOTS: usize = 64;
+        let links: Arc<[AtomicU32]> = (0..SLOTS).map(|_| AtomicU32::new(0)).collect();
+        let stack = Arc::new(FreeStack::default());
+        let all: Vec<u32> = (0..SLOTS as u32).collect();
+        stack.push_chain(&links, &all);
+        assert_eq!(stack.len(), SLOTS);
+        assert!(stack.pop_chain(&links, SLOTS + 1).is_none());
+
+        let held: Arc<[AtomicBool]> = (0..SLOTS).map(|_| AtomicBool::new(false)).collect();
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let (links, stack, held) = (links.clone(), stack.clone(), held.clone());
+                std::thread::spawn(move || {
+                    let count = 1 + thread % 3;
+                    for _ in 0..20_000 {
+                        let Some(top) = stack.pop_chain(&links, count) else {
+                            continue;
+                        };
+                        let mut chain = vec![top];
+                        while chain.len() < count {
+                            chain.push(next_in_chain(&links, *chain.last().unwrap()));
+                        }
+                        for &index in &chain {
+                            assert!(!held[index as usize].swap(true, Ordering::Relaxed));
+                        }
+                        for &index in &chain {
+                            held[index as usize].store(false, Ordering::Relaxed);
+                        }
+                        stack.push_chain(&links, &chain);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let top = stack.pop_chain(&links, SLOTS).unwrap();
+        let mut seen = vec![top];
+        while seen.len() < SLOTS {
+            seen.push(next_in_chain(&links, *seen.last().unwrap()));
+        }
+        seen.sort_unstable();
+        assert_eq!(seen, all);
+    }
+}
@@ -0,0 +1,493 @@
+//! Fluent builders for Ethernet/IPv4/UDP frames
+//!
+//! An [`EthernetBuilder`] wraps an [`Ipv4Builder`], which wraps a
+//! [`UdpBuilder`]; each sets its own header fields and leaves lengths and
+//! checksums to be computed when the frame is written, into a `Vec<u8>`
+//! with [`EthernetBuilder::build`], a caller's buffer or an [`Mbuf`].
+//! Writing fails on a payload over [`MAX_UDP_PAYLOAD`], whose length the
+//! IPv4 header could not hold. The
+//! same builders parse frames back, validating lengths and checksums, so a
+//! frame round-trips: parsing what a builder wrote returns an equal
+//! builder.
+//!
+//! ```
+//! use std::net::Ipv4Addr;
+//! use xpdk::packet::builder::{EthernetBuilder, Ipv4Builder, UdpBuilder};
+//!
+//! let frame = EthernetBuilder::default()
+//!     .src([0x02, 0, 0, 0, 0, 0x02])
+//!     .dst([0x02, 0, 0, 0, 0, 0x01])
+//!     .ipv4(
+//!         Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+//!             .ttl(32)
+//!             .udp(UdpBuilder::new(6000, 5000).payload(b"hello")),
+//!     );
+//! let bytes = frame.build().unwrap();
+//! assert_eq!(bytes.len(), frame.wire_len());
+//! assert_eq!(EthernetBuilder::parse(&bytes).unwrap(), frame);
+//! ```
+
+use crate::memory::Mbuf;
+use crate::udp::{
+    ones_complement, EthernetHeader, Ipv4Header, UdpHeader, ETHERTYPE_IPV4, ETHERTYPE_VLAN,
+    IPPROTO_UDP, IPV4_DF,
+};
+use crate::{Error, Result};
+use std::net::Ipv4Addr;
+
+/// Length of an 802.1Q tag
+const VLAN_TAG_LEN: usize = 4;
+
+/// Largest payload of an IPv4 packet without options carrying UDP
+pub const MAX_UDP_PAYLOAD: usize = u16::MAX as usize - Ipv4Header::LEN - UdpHeader::LEN;
+
+/// UDP header and payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpBuilder {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: Vec<u8>,
+    /// Whether to fill in the checksum, which is optional over IPv4
+    pub checksum: bool,
+}
+
+impl Default for UdpBuilder {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+impl UdpBuilder {
+    pub fn new(src_port: u16, dst_port: u16) -> Self {
+        Self {
+            src_port,
+            dst_port,
+            payload: Vec::new(),
+            checksum: true,
+        }
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Leave the checksum zero when `false`
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Bytes of the header and payload
+    pub fn wire_len(&self) -> usize {
+        UdpHeader::LEN + self.payload.len()
+    }
+
+    /// Write the datagram sent from `src` to `dst` into `buf`, exactly `wire_len` bytes
+    ///
+    /// The caller checks the length against [`MAX_UDP_PAYLOAD`].
+    fn write(&self, buf: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr) {
+        let len = self.wire_len() as u16;
+        buf[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        buf[4..6].copy_from_slice(&len.to_be_bytes());
+        buf[6..8].copy_from_slice(&[0, 0]);
+        buf[UdpHeader::LEN..].copy_from_slice(&self.payload);
+        if self.checksum {
+            // A computed zero is sent as all ones, zero meaning no checksum
+            let sum = match !udp_sum(buf, src, dst) {
+                0 => 0xFFFF,
+                sum => sum,
+            };
+            buf[6..8].copy_from_slice(&sum.to_be_bytes());
+        }
+    }
+
+    /// Parse a datagram sent from `src` to `dst`, checking its length and checksum
+    pub fn parse(datagram: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> Result<Self> {
+        if datagram.len() < UdpHeader::LEN {
+            return Err(Error::NetworkError(
+                "Packet too small for UDP header".to_string(),
+            ));
+        }
+        let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+        if len < UdpHeader::LEN || len > datagram.len() {
+            return Err(Error::NetworkError(format!(
+                "UDP length {} does not match the {} bytes received",
+                len,
+                datagram.len()
+            )));
+        }
+        let datagram = &datagram[..len];
+        let checksum = u16::from_be_bytes([datagram[6], datagram[7]]) != 0;
+        if checksum && udp_sum(datagram, src, dst) != 0xFFFF {
+            return Err(Error::NetworkError("Bad UDP checksum".to_string()));
+        }
+        Ok(Self {
+            src_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            dst_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+            payload: datagram[UdpHeader::LEN..].to_vec(),
+            checksum,
+        })
+    }
+}
+
+/// Ones' complement sum of a datagram and its pseudo-header
+fn udp_sum(datagram: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.octets());
+    pseudo[4..8].copy_from_slice(&dst.octets());
+    pseudo[9] = IPPROTO_UDP;
+    pseudo[10..12].copy_from_slice(&(datagram.len() as u16).to_be_bytes());
+    ones_complement(ones_complement(0, &pseudo), datagram)
+}
+
+/// IPv4 header without options, carrying UDP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv4Builder {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub dscp: u8,
+    pub ttl: u8,
+    pub identification: u16,
+    pub dont_fragment: bool,
+    pub udp: UdpBuilder,
+}
+
+impl Default for Ipv4Builder {
+    fn default() -> Self {
+        Self::new(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+impl Ipv4Builder {
+    /// Packet from `src` to `dst` with a TTL of 64 and an empty datagram
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr) -> Self {
+        Self {
+            src,
+            dst,
+            dscp: 0,
+            ttl: 64,
+            identification: 0,
+            dont_fragment: false,
+            udp: UdpBuilder::default(),
+        }
+    }
+
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn identification(mut self, identification: u16) -> Self {
+        self.identification = identification;
+        self
+    }
+
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> Self {
+        self.dont_fragment = dont_fragment;
+        self
+    }
+
+    pub fn udp(mut self, udp: UdpBuilder) -> Self {
+        self.udp = udp;
+        self
+    }
+
+    /// Bytes of the header and datagram
+    pub fn wire_len(&self) -> usize {
+        Ipv4Header::LEN + self.udp.wire_len()
+    }
+
+    /// The packet as bytes
+    pub fn build(&self) -> Result<Vec<u8>> {
+        self.check_len()?;
+        let mut packet = vec![0u8; self.wire_len()];
+        self.write_exact(&mut packet);
+        Ok(packet)
+    }
+
+    /// Write the packet to the start of `buf`, returning its length
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize> {
+        self.check_len()?;
+        let len = self.wire_len();
+        fits(len, buf.len())?;
+        self.write_exact(&mut buf[..len]);
+        Ok(len)
+    }
+
+    /// Fail if the total length does not fit the header's 16 bits
+    fn check_len(&self) -> Result<()> {
+        if self.udp.payload.len() > MAX_UDP_PAYLOAD {
+            return Err(Error::NetworkError(format!(
+                "UDP payload of {} bytes exceeds the {}-byte maximum",
+                self.udp.payload.len(),
+                MAX_UDP_PAYLOAD
+            )));
+        }
+        Ok(())
+    }
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        let (header, datagram) = buf.split_at_mut(Ipv4Header::LEN);
+        header[0] = 0x45;
+        header[1] = self.dscp << 2;
+        header[2..4].copy_from_slice(&(self.wire_len() as u16).to_be_bytes());
+        header[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        let flags = if self.dont_fragment { IPV4_DF } else { 0 };
+        header[6..8].copy_from_slice(&flags.to_be_bytes());
+        header[8] = self.ttl;
+        header[9] = IPPROTO_UDP;
+        header[10..12].copy_from_slice(&[0, 0]);
+        header[12..16].copy_from_slice(&self.src.octets());
+        header[16..20].copy_from_slice(&self.dst.octets());
+        let sum = !ones_complement(0, header);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+        self.udp.write(datagram, self.src, self.dst);
+    }
+
+    /// Parse an unfragmented IPv4/UDP packet, checking lengths and checksums
+    ///
+    /// Bytes past the IPv4 total length, such as Ethernet padding, are ignored.
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < Ipv4Header::LEN {
+            return Err(Error::NetworkError(
+                "Packet too small for IPv4 header".to_string(),
+            ));
+        }
+        if packet[0] != 0x45 {
+            return Err(Error::NetworkError(format!(
+                "Not an IPv4 header without options: {:#04x}",
+                packet[0]
+            )));
+        }
+        let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if total_length < Ipv4Header::LEN || total_length > packet.len() {
+            return Err(Error::NetworkError(format!(
+                "IPv4 total length {} does not match the {} bytes received",
+                total_length,
+                packet.len()
+            )));
+        }
+        let header = &packet[..Ipv4Header::LEN];
+        if ones_complement(0, header) != 0xFFFF {
+            return Err(Error::NetworkError("Bad IPv4 header checksum".to_string()));
+        }
+        let flags = u16::from_be_bytes([header[6], header[7]]);
+        if flags & !IPV4_DF != 0 {
+            return Err(Error::NetworkError("Fragmented IPv4 packet".to_string()));
+        }
+        if header[9] != IPPROTO_UDP {
+            return Err(Error::NetworkError("Not a UDP packet".to_string()));
+        }
+        let src = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+        let dst = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+        let datagram = &packet[Ipv4Header::LEN..total_length];
+        Ok(Self {
+            src,
+            dst,
+            dscp: header[1] >> 2,
+            ttl: header[8],
+            identification: u16::from_be_bytes([header[4], header[5]]),
+            dont_fragment: flags & IPV4_DF != 0,
+            udp: UdpBuilder::parse(datagram, src, dst)?,
+        })
+    }
+}
+
+/// Ethernet frame, optionally VLAN tagged, carrying IPv4
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EthernetBuilder {
+    pub dst: [u8; 6],
+    pub src: [u8; 6],
+    /// VLAN ID of an 802.1Q tag, untagged if `None`
+    pub vlan: Option<u16>,
+    pub ipv4: Ipv4Builder,
+}
+
+impl EthernetBuilder {
+    pub fn dst(mut self, dst: [u8; 6]) -> Self {
+        self.dst = dst;
+        self
+    }
+
+    pub fn src(mut self, src: [u8; 6]) -> Self {
+        self.src = src;
+        self
+    }
+
+    pub fn vlan(mut self, vlan: Option<u16>) -> Self {
+        self.vlan = vlan;
+        self
+    }
+
+    pub fn ipv4(mut self, ipv4: Ipv4Builder) -> Self {
+        self.ipv4 = ipv4;
+        self
+    }
+
+    fn header_len(&self) -> usize {
+        EthernetHeader::LEN + self.vlan.map_or(0, |_| VLAN_TAG_LEN)
+    }
+
+    /// Bytes of the whole frame
+    pub fn wire_len(&self) -> usize {
+        self.header_len() + self.ipv4.wire_len()
+    }
+
+    /// The frame as bytes
+    pub fn build(&self) -> Result<Vec<u8>> {
+        self.ipv4.check_len()?;
+        let mut frame = vec![0u8; self.wire_len()];
+        self.write_exact(&mut frame);
+        Ok(frame)
+    }
+
+    /// Write the frame to the start of `buf`, returning its length
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize> {
+        self.ipv4.check_len()?;
+        let len = self.wire_len();
+        fits(len, buf.len())?;
+        self.write_exact(&mut buf[..len]);
+        Ok(len)
+    }
+
+    /// Replace the contents of `mbuf` with the frame
+    ///
+    /// The mbuf is reset first, so it is classified again on dispatch.
+    pub fn write_mbuf(&self, mbuf: &mut Mbuf) -> Result<()> {
+        self.ipv4.check_len()?;
+        fits(self.wire_len(), mbuf.buf_len)?;
+        mbuf.reset();
+        let buf = unsafe { std::slice::from_raw_parts_mut(mbuf.data, mbuf.buf_len) };
+        mbuf.len = self.write(buf)?;
+        Ok(())
+    }
+
+    fn write_exact(&self, buf: &mut [u8]) {
+        buf[0..6].copy_from_slice(&self.dst);
+        buf[6..12].copy_from_slice(&self.src);
+        let mut offset = 12;
+        if let Some(vlan) = self.vlan {
+            buf[12..14].copy_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+            buf[14..16].copy_from_slice(&(vlan & 0x0FFF).to_be_bytes());
+            offset += VLAN_TAG_LEN;
+        }
+        buf[offset..offset + 2].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        self.ipv4.write_exact(&mut buf[offset + 2..]);
+    }
+
+    /// Parse an Ethernet/IPv4/UDP frame, checking lengths and checksums
+    pub fn parse(frame: &[u8]) -> Result<Self> {
+        if frame.len() < EthernetHeader::LEN {
+            return Err(Error::NetworkError(
+                "Packet too small for Ethernet header".to_string(),
+            ));
+        }
+        let mut ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+        let mut offset = EthernetHeader::LEN;
+        let mut vlan = None;
+        if ether_type == ETHERTYPE_VLAN {
+            if frame.len() < offset + VLAN_TAG_LEN {
+                return Err(Error::NetworkError(
+                    "Packet too small for VLAN tag".to_string(),
+                ));
+            }
+            vlan = Some(u16::from_be_bytes([frame[14], frame[15]]) & 0x0FFF);
+            ether_type = u16::from_be_bytes([frame[16], frame[17]]);
+            offset += VLAN_TAG_LEN;
+        }
+        if ether_type != ETHERTYPE_IPV4 {
+            return Err(Error::NetworkError("Not an IPv4 packet".to_string()));
+        }
+        Ok(Self {
+            dst: frame[0..6].try_into().unwrap(),
+            src: frame[6..12].try_into().unwrap(),
+            vlan,
+            ipv4: Ipv4Builder::parse(&frame[offset..])?,
+        })
+    }
+}
+
+fn fits(len: usize, room: usize) -> Result<()> {
+    if len > room {
+        return Err(Error::MemoryAllocation(format!(
+            "Frame of {} bytes does not fit in {} bytes",
+            len, room
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::UdpPacket;
+
+    fn frame() -> EthernetBuilder {
+        EthernetBuilder::default()
+            .src([0x02, 0, 0, 0, 0, 0x02])
+            .dst([0x02, 0, 0, 0, 0, 0x01])
+            .ipv4(
+                Ipv4Builder::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1))
+                    .dscp(46)
+                    .identification(7)
+                    .dont_fragment(true)
+                    .udp(UdpBuilder::new(6000, 5000).payload(b"round trip")),
+            )
+    }
+
+    #[test]
+    fn test_frames_round_trip_through_mbufs() {
+        let pool = MbufPool::new("builder".to_string(), 2, 2048).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        let built = frame();
+        built.write_mbuf(unsafe { &mut *mbuf }).unwrap();
+
+        // The receive path accepts it
+        let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+        assert!(!packet.checksum_bad());
+        assert_eq!(packet.payload(), b"round trip");
+        assert_eq!(packet.dst_addr(), "10.0.0.1:5000".parse().unwrap());
+        let bytes = unsafe { (*mbuf).data() }.to_vec();
+        pool.free(mbuf).unwrap();
+
+        assert_eq!(EthernetBuilder::parse(&bytes).unwrap(), built);
+        let tagged = built.clone().vlan(Some(100));
+        assert_eq!(
+            EthernetBuilder::parse(&tagged.build().unwrap()).unwrap(),
+            tagged
+        );
+        let bare = built
+            .ipv4
+            .clone()
+            .udp(UdpBuilder::new(1, 2).checksum(false));
+        assert_eq!(Ipv4Builder::parse(&bare.build().unwrap()).unwrap(), bare);
+        // Padding past the IPv4 length is ignored
+        let mut padded = bytes.clone();
+        padded.resize(128, 0);
+        assert_eq!(EthernetBuilder::parse(&padded).unwrap(), built);
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 0xFF;
+        assert!(EthernetBuilder::parse(&corrupt).is_err());
+        assert!(EthernetBuilder::parse(&bytes[..30]).is_err());
+        assert!(built.write(&mut [0u8; 16]).is_err());
+
+        // The IPv4 total length holds the largest payload, not one more
+        let mut largest = built.clone();
+        largest.ipv4.udp.payload = vec![0xA5; MAX_UDP_PAYLOAD];
+        let bytes = largest.build().unwrap();
+        assert_eq!(EthernetBuilder::parse(&bytes).unwrap(), largest);
+        largest.ipv4.udp.payload.push(0);
+        assert!(largest.build().is_err());
+        assert!(largest.ipv4.build().is_err());
+        assert!(largest.write(&mut vec![0u8; 70_000]).is_err());
+    }
+}
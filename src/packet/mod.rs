@@ -0,0 +1,10 @@
+//! Packet construction and parsing
+//!
+//! Tests, benchmarks, traffic generators and documentation examples build
+//! frames with the fluent builders of [`builder`] instead of hand-rolled
+//! byte arrays, and parse received frames back with them to check what
+//! was sent.
+
+pub mod builder;
+
+pub use builder::{EthernetBuilder, Ipv4Builder, UdpBuilder};
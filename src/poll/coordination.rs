@@ -0,0 +1,394 @@
+//! Capture coordination between processes sharing an interface
+//!
+//! Every XPDK process capturing on an interface sees all of its traffic,
+//! so two processes on one interface each receive the other's datagrams
+//! too. With [`Config::capture_coordination`] set, processes advertise the
+//! UDP ports they receive on in a registry directory, by default under
+//! `/dev/shm`, one file per process ID, and narrow their RX captures with
+//! BPF filters derived from it: a process captures the datagrams for its
+//! own ports, and the one process set to [`CaptureCoordination::claim_unowned`]
+//! captures everything no other process claims. Every process also
+//! captures ARP, ICMP and the non-first fragments of IPv4 datagrams, which
+//! carry no ports: its neighbor table, path MTU cache and reassembly need
+//! them, and fragments of another process's datagrams expire unassembled.
+//! Filters match untagged and VLAN-tagged frames alike and are combined
+//! with the queue's own filter.
+//!
+//! The registry is refreshed by [`crate::Xpdk::start`], every
+//! [`CaptureCoordination::refresh`] by [`crate::Xpdk::poll_once`] and by
+//! [`crate::dispatch::Dispatcher::run`] once given
+//! [`crate::dispatch::Dispatcher::set_capture_coordination`], and on
+//! demand by [`crate::Xpdk::coordinate_capture`], which binding a socket
+//! should be followed by. Entries of processes that died are removed by
+//! the next process to read them, and unreadable entries are skipped. Source ports drawn for randomized flows
+//! are advertised as well, but only from the next refresh, and processes
+//! do not keep each other from drawing the same one.
+
+use super::RxQueue;
+use crate::{Config, Error, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Default time between registry refreshes
+pub const DEFAULT_COORDINATION_REFRESH: Duration = Duration::from_secs(1);
+
+/// Frames every process captures: ARP, ICMP and non-first IPv4 fragments
+const SHARED_TRAFFIC: &str = "arp or icmp or (ip[6:2] & 0x1fff != 0)";
+
+/// How a process coordinates captures with others on the interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureCoordination {
+    /// Directory holding one port list per process
+    pub registry: PathBuf,
+    /// Also capture what no process claims
+    pub claim_unowned: bool,
+    /// Time between registry refreshes while polling
+    pub refresh: Duration,
+}
+
+impl CaptureCoordination {
+    /// Coordinate through `/dev/shm/xpdk-<interface>`, capturing own ports only
+    pub fn new(interface: &str) -> Self {
+        Self {
+            registry: PathBuf::from(format!("/dev/shm/xpdk-{}", interface)),
+            claim_unowned: false,
+            refresh: DEFAULT_COORDINATION_REFRESH,
+        }
+    }
+
+    pub fn with_registry(mut self, registry: impl Into<PathBuf>) -> Self {
+        self.registry = registry.into();
+        self
+    }
+
+    pub fn with_claim_unowned(mut self, claim_unowned: bool) -> Self {
+        self.claim_unowned = claim_unowned;
+        self
+    }
+
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+}
+
+/// Ports advertised by another process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerPorts {
+    pub pid: u32,
+    pub ports: BTreeSet<u16>,
+}
+
+/// One process's entry in a coordination registry
+#[derive(Debug)]
+pub struct CaptureRegistry {
+    dir: PathBuf,
+    pid: u32,
+}
+
+impl CaptureRegistry {
+    /// Join the registry in `dir`, creating it if needed
+    pub fn open(dir: &Path) -> Result<Self> {
+        Self::open_as(dir, std::process::id())
+    }
+
+    fn open_as(dir: &Path, pid: u32) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            pid,
+        })
+    }
+
+    fn entry(&self, pid: u32) -> PathBuf {
+        self.dir.join(pid.to_string())
+    }
+
+    /// Replace this process's advertised ports with `ports`
+    pub fn advertise(&self, ports: &BTreeSet<u16>) -> Result<()> {
+        let tmp = self.dir.join(format!(".{}.tmp", self.pid));
+        fs::write(&tmp, format_ports(ports, "\n"))?;
+        // Readers see the old list or the new one, never part of one
+        fs::rename(&tmp, self.entry(self.pid))?;
+        Ok(())
+    }
+
+    /// Ports of the other live processes, removing entries of dead ones
+    pub fn peers(&self) -> Result<Vec<PeerPorts>> {
+        let mut peers = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Some(pid) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            if pid == self.pid {
+                continue;
+            }
+            if !process_alive(pid) {
+                log::debug!("Removing capture registry entry of exited process {}", pid);
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            // An entry removed since the listing is a process gone
+            let Ok(list) = fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            // One bad entry must not stop coordinating with the others
+            match parse_ports(&list) {
+                Ok(ports) => peers.push(PeerPorts { pid, ports }),
+                Err(what) => log::warn!(
+                    "Skipping capture registry entry {}: {}",
+                    entry.path().display(),
+                    what
+                ),
+            }
+        }
+        peers.sort_by_key(|peer| peer.pid);
+        Ok(peers)
+    }
+
+    /// Remove this process's entry
+    pub fn withdraw(&self) -> Result<()> {
+        match fs::remove_file(self.entry(self.pid)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for CaptureRegistry {
+    fn drop(&mut self) {
+        let _ = self.withdraw();
+    }
+}
+
+/// Check if process `pid` exists
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process can be signalled
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Ports as runs, `5000` and `40000-40010`, joined by `separator`
+fn format_ports(ports: &BTreeSet<u16>, separator: &str) -> String {
+    let mut runs: Vec<(u16, u16)> = Vec::new();
+    for &port in ports {
+        match runs.last_mut() {
+            Some((_, high)) if *high as u32 + 1 == port as u32 => *high = port,
+            _ => runs.push((port, port)),
+        }
+    }
+    runs.iter()
+        .map(|&(low, high)| match low == high {
+            true => low.to_string(),
+            false => format!("{}-{}", low, high),
+        })
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn parse_ports(list: &str) -> std::result::Result<BTreeSet<u16>, String> {
+    let mut ports = BTreeSet::new();
+    for run in list.split_whitespace() {
+        let bad = || format!("bad port range '{}'", run);
+        let (low, high) = run.split_once('-').unwrap_or((run, run));
+        let low: u16 = low.parse().map_err(|_| bad())?;
+        let high: u16 = high.parse().map_err(|_| bad())?;
+        if low > high {
+            return Err(bad());
+        }
+        ports.extend(low..=high);
+    }
+    Ok(ports)
+}
+
+/// BPF expression matching UDP datagrams to any of `ports`
+fn ports_filter(ports: &BTreeSet<u16>) -> String {
+    let terms = format_ports(ports, " ")
+        .split(' ')
+        .map(|run| match run.contains('-') {
+            true => format!("dst portrange {}", run),
+            false => format!("dst port {}", run),
+        })
+        .collect::<Vec<_>>();
+    format!("udp and ({})", terms.join(" or "))
+}
+
+/// BPF expression matching frames `filter` matches, with or without a VLAN tag
+fn tagged_or_not(filter: &str) -> String {
+    // `vlan` shifts the offsets of what follows it, so it comes last
+    format!("({}) or (vlan and ({}))", filter, filter)
+}
+
+/// Capture filter of a process receiving on `own` beside `peers`, `None` to capture everything
+pub fn coordination_filter(
+    own: &BTreeSet<u16>,
+    peers: &[PeerPorts],
+    claim_unowned: bool,
+) -> Option<String> {
+    if !claim_unowned {
+        return Some(tagged_or_not(&match own.is_empty() {
+            true => SHARED_TRAFFIC.to_string(),
+            false => format!("{} or ({})", SHARED_TRAFFIC, ports_filter(own)),
+        }));
+    }
+    // Ports we also receive on stay ours
+    let claimed: BTreeSet<u16> = peers
+        .iter()
+        .flat_map(|peer| peer.ports.iter().copied())
+        .filter(|port| !own.contains(port))
+        .collect();
+    (!claimed.is_empty()).then(|| format!("not ({})", tagged_or_not(&ports_filter(&claimed))))
+}
+
+/// Keeps a process's RX capture filters in line with the registry
+#[derive(Debug)]
+pub struct CaptureCoordinator {
+    config: CaptureCoordination,
+    registry: CaptureRegistry,
+    /// Ports last advertised
+    advertised: Option<BTreeSet<u16>>,
+    /// Coordination filter last installed, `None` for everything
+    installed: Option<Option<String>>,
+    last_refresh: Option<Instant>,
+}
+
+impl CaptureCoordinator {
+    /// Join the registry of `config`
+    pub fn new(config: &CaptureCoordination) -> Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            registry: CaptureRegistry::open(&config.registry)?,
+            advertised: None,
+            installed: None,
+            last_refresh: None,
+        })
+    }
+
+    /// Check if a refresh is due
+    pub fn is_due(&self) -> bool {
+        self.last_refresh
+            .is_none_or(|last| last.elapsed() >= self.config.refresh)
+    }
+
+    /// Advertise `ports` and refilter `rx_queues`, returning the coordination filter
+    ///
+    /// Queues are refiltered only when the filter changes. Fails with
+    /// [`Error::QueueError`] while a queue is polled elsewhere; the next
+    /// refresh tries again.
+    pub fn refresh<'a>(
+        &mut self,
+        ports: BTreeSet<u16>,
+        rx_queues: impl IntoIterator<Item = &'a Arc<RxQueue>>,
+    ) -> Result<Option<String>> {
+        self.last_refresh = Some(Instant::now());
+        if self.advertised.as_ref() != Some(&ports) {
+            self.registry.advertise(&ports)?;
+            self.advertised = Some(ports);
+        }
+        let own = self.advertised.as_ref().expect("ports advertised above");
+        let peers = self.registry.peers()?;
+        for peer in &peers {
+            if let Some(port) = peer.ports.intersection(own).next() {
+                log::warn!(
+                    "UDP port {} is also claimed by process {}, both capture it",
+                    port,
+                    peer.pid
+                );
+            }
+        }
+
+        let filter = coordination_filter(own, &peers, self.config.claim_unowned);
+        if self.installed.as_ref() != Some(&filter) {
+            for rx_queue in rx_queues {
+                rx_queue.set_coordination_filter(filter.as_deref())?;
+            }
+            log::info!(
+                "Capture coordination filter: {}",
+                filter.as_deref().unwrap_or("everything")
+            );
+            self.installed = Some(filter.clone());
+        }
+        Ok(filter)
+    }
+
+    /// Remove this process from the registry
+    pub fn withdraw(&mut self) -> Result<()> {
+        self.advertised = None;
+        self.installed = None;
+        self.registry.withdraw()
+    }
+}
+
+/// Check the coordination settings of `config`, failing with [`Error::InvalidConfig`]
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    match &config.capture_coordination {
+        Some(coordination) if coordination.registry.as_os_str().is_empty() => Err(
+            Error::InvalidConfig("Capture coordination registry path is empty".to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_and_filters() {
+        let dir = std::env::temp_dir().join(format!("xpdk-coord-{}", std::process::id()));
+        let ours = CaptureRegistry::open(&dir).unwrap();
+        let peer = CaptureRegistry::open_as(&dir, 1).unwrap();
+        // Beyond the largest PID, so never alive
+        let dead = CaptureRegistry::open_as(&dir, 1 << 23).unwrap();
+
+        let own: BTreeSet<u16> = [5000, 40000, 40001, 40002].into();
+        ours.advertise(&own).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join(std::process::id().to_string())).unwrap(),
+            "5000\n40000-40002"
+        );
+        peer.advertise(&[53, 5000].into()).unwrap();
+        dead.advertise(&[7].into()).unwrap();
+        let peers = ours.peers().unwrap();
+        assert_eq!(
+            peers,
+            [PeerPorts {
+                pid: 1,
+                ports: [53, 5000].into()
+            }]
+        );
+        assert!(!dir.join((1u32 << 23).to_string()).exists());
+
+        let ports = "udp and (dst port 5000 or dst portrange 40000-40002)";
+        assert_eq!(
+            coordination_filter(&own, &peers, false).unwrap(),
+            tagged_or_not(&format!("{} or ({})", SHARED_TRAFFIC, ports))
+        );
+        assert_eq!(
+            coordination_filter(&own, &peers, true).unwrap(),
+            "not ((udp and (dst port 53)) or (vlan and (udp and (dst port 53))))"
+        );
+        assert_eq!(coordination_filter(&own, &[], true), None);
+        assert_eq!(
+            coordination_filter(&BTreeSet::new(), &peers, false).unwrap(),
+            tagged_or_not(SHARED_TRAFFIC)
+        );
+
+        // A corrupt entry is skipped, not fatal
+        fs::write(dir.join("1"), "5000 junk").unwrap();
+        assert!(ours.peers().unwrap().is_empty());
+        drop(peer);
+        assert!(ours.peers().unwrap().is_empty());
+        drop(ours);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+        assert!(parse_ports("9-1").is_err());
+    }
+}
@@ -2,47 +2,200 @@
 //!
 //! This module implements a DPDK-inspired poll mode driver using libpcap,
 //! supporting multi-queue, RSS, and batch operations for maximum throughput.
+//!
+//! An RX queue has a single consumer. Its capture handle is owned by
+//! whoever holds the queue's [`RxPoller`], which receives without locking;
+//! [`RxQueue::recv`] is the shared entry point for callers that poll a
+//! queue from more than one place, and claims the poller for one packet.
+//! TX queues are shared by every socket sending on them and keep their
+//! capture behind a mutex. RX queues take their mbufs [`RX_ALLOC_BULK`] at
+//! a time with [`MbufPool::alloc_bulk`], so each holds up to that many
+//! ahead, counted as in use by the pool.
+//!
+//! With [`Config::hw_timestamps`], RX captures ask libpcap for adapter
+//! timestamps, which it takes through `SO_TIMESTAMPING`. Timestamps of an
+//! adapter clock not synced to the system clock are converted through a
+//! [`PhcSync`] estimate of the interface's PTP hardware clock; interfaces
+//! without hardware timestamps keep software ones. See [`RxTimestamping`].
+//!
+//! RX captures see only inbound frames by default, so the frames our TX
+//! queues send do not loop back into classification. Where libpcap cannot
+//! set the capture direction, frames sourced from the interface's own MAC
+//! address are dropped by a [`SelfFilter`] instead and counted in
+//! [`RxQueueStats::self_filtered`].
 
 use crate::{
-    memory::{Mbuf, MbufPool},
+    memory::{Mbuf, MbufHandle, MbufPool, MemoryBudget, OffloadFlags},
+    udp,
+    utils::backoff::IdleStrategy,
+    utils::counter::Counter,
+    utils::profile::TrafficProfiler,
+    utils::sflow::FlowSampler,
+    utils::shutdown::ShutdownToken,
+    utils::time::{PhcClock, PhcSync, Timestamp},
+    utils::trace::{PacketTracer, TraceStage},
     Config, Error, Result,
 };
 use parking_lot::Mutex;
-use pcap::{Active, Capture, Device};
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use pcap::{Active, Capture, Device, Direction, Inactive, Packet, Precision, TimestampType};
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+pub mod coordination;
+mod queue_config;
+pub mod schedule;
+
+pub use queue_config::QueueConfig;
+pub(crate) use queue_config::{validate as validate_queue_configs, QueueSettings};
+use schedule::{DepartureHook, TxSchedule, TxScheduleStats, DEFAULT_TX_SCHEDULE_CAPACITY};
+
 /// Default packet buffer size
 pub const DEFAULT_PACKET_SIZE: usize = 2048;
 
+/// Mbufs an RX queue allocates ahead at most, in one bulk allocation
+pub const RX_ALLOC_BULK: usize = 32;
+
 /// Maximum batch size for packet operations
 pub const MAX_BATCH_SIZE: usize = 32;
 
 /// Receive queue statistics
 #[derive(Debug, Default)]
 pub struct RxQueueStats {
-    pub packets_received: AtomicUsize,
-    pub bytes_received: AtomicUsize,
-    pub errors: AtomicUsize,
-    pub drops: AtomicUsize,
+    pub packets_received: Counter,
+    pub bytes_received: Counter,
+    pub errors: Counter,
+    pub drops: Counter,
+    /// Frames we transmitted that looped back into the capture and were dropped
+    pub self_filtered: Counter,
 }
 
 /// Transmit queue statistics
 #[derive(Debug, Default)]
 pub struct TxQueueStats {
-    pub packets_sent: AtomicUsize,
-    pub bytes_sent: AtomicUsize,
-    pub errors: AtomicUsize,
-    pub drops: AtomicUsize,
+    pub packets_sent: Counter,
+    pub bytes_sent: Counter,
+    pub errors: Counter,
+    pub drops: Counter,
+}
+
+/// Where the receive timestamps of an RX queue come from
+#[derive(Clone, Default)]
+pub enum RxTimestamping {
+    /// Taken by the host when libpcap saw the frame
+    #[default]
+    Software,
+    /// Taken by the NIC on a clock synced to the system clock
+    Adapter,
+    /// Taken by the NIC on its PTP hardware clock, converted to the system clock
+    Phc(Arc<PhcSync>),
+}
+
+impl RxTimestamping {
+    /// Whether timestamps are taken by the NIC
+    pub fn is_hardware(&self) -> bool {
+        !matches!(self, RxTimestamping::Software)
+    }
+}
+
+/// Which frames an RX capture sees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureDirection {
+    /// Frames received by the interface only
+    #[default]
+    Inbound,
+    /// Frames received and sent, including our own transmissions
+    Both,
+}
+
+/// Source MAC addresses of frames this host transmits
+///
+/// Drops our own frames on RX where the capture direction cannot be set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfFilter {
+    macs: Vec<[u8; 6]>,
+}
+
+impl SelfFilter {
+    /// Filter frames sent from `macs`
+    pub fn new(macs: Vec<[u8; 6]>) -> Self {
+        Self { macs }
+    }
+
+    /// Filter frames sent from the hardware address of `interface`
+    ///
+    /// Empty if the address cannot be read.
+    pub fn for_interface(interface: &str) -> Self {
+        let path = format!("/sys/class/net/{}/address", interface);
+        let macs = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|address| parse_mac(address.trim()))
+            .into_iter()
+            .collect();
+        Self { macs }
+    }
+
+    /// Also filter frames sent from `mac`
+    pub fn add(&mut self, mac: [u8; 6]) {
+        if !self.macs.contains(&mac) {
+            self.macs.push(mac);
+        }
+    }
+
+    /// Whether no address is filtered
+    pub fn is_empty(&self) -> bool {
+        self.macs.is_empty()
+    }
+
+    /// Whether the Ethernet `frame` was sent from one of our addresses
+    pub fn is_own(&self, frame: &[u8]) -> bool {
+        frame
+            .get(6..12)
+            .is_some_and(|src| self.macs.iter().any(|mac| mac == src))
+    }
+}
+
+/// Parse a colon separated MAC address
+fn parse_mac(address: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut octets = address.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(octets.next()?, 16).ok()?;
+    }
+    octets.next().is_none().then_some(mac)
 }
 
 /// Receive queue
 pub struct RxQueue {
     /// Queue ID
     id: u16,
-    /// libpcap capture handle
-    capture: Arc<Mutex<Capture<Active>>>,
+    /// Name in logs and telemetry
+    name: String,
+    /// Port ID of the interface captured from
+    port_id: u16,
+    /// Strip 802.1Q tags before classification
+    vlan_strip: bool,
+    /// Source of receive timestamps, nanosecond precision unless software
+    timestamping: RxTimestamping,
+    /// Drops our own transmissions the capture could not exclude
+    self_filter: SelfFilter,
+    /// Last frames dropped after receive
+    drop_log: Arc<udp::DropLog>,
+    /// Frames received per poll
+    batch_size: usize,
+    /// CPU the queue is polled on, if pinned
+    cpu: Option<usize>,
+    /// How loops polling the queue wait while it is empty
+    idle_strategy: IdleStrategy,
+    /// BPF filter the queue was configured with
+    filter: Option<String>,
+    /// Frames received, only touched by the holder of the poller
+    capture: UnsafeCell<RxSource>,
+    /// Mbufs allocated ahead for the next frames, only touched by the holder of the poller
+    spare: UnsafeCell<Vec<*mut Mbuf>>,
+    /// Set while an [`RxPoller`] exists
+    claimed: AtomicBool,
     /// Memory pool for mbuf allocation
     pool: Arc<MbufPool>,
     /// Queue statistics
@@ -54,15 +207,158 @@ pub struct RxQueue {
 impl RxQueue {
     /// Create a new receive queue
     pub fn new(id: u16, capture: Capture<Active>, pool: Arc<MbufPool>) -> Result<Self> {
-        let capture = Arc::new(Mutex::new(capture));
+        Ok(Self::with_source(id, RxSource::Capture(capture), pool))
+    }
 
-        Ok(Self {
+    /// Queue receiving `frames` in order, then reporting no packet
+    #[cfg(test)]
+    pub(crate) fn in_memory(id: u16, frames: Vec<Vec<u8>>, pool: Arc<MbufPool>) -> Self {
+        Self::with_source(
+            id,
+            RxSource::Memory {
+                frames: frames.into(),
+                current: None,
+            },
+            pool,
+        )
+    }
+
+    fn with_source(id: u16, capture: RxSource, pool: Arc<MbufPool>) -> Self {
+        Self {
             id,
-            capture,
+            name: format!("rx{}", id),
+            port_id: 0,
+            vlan_strip: false,
+            timestamping: RxTimestamping::Software,
+            self_filter: SelfFilter::default(),
+            drop_log: Arc::new(udp::DropLog::new(udp::DEFAULT_DROP_LOG_SIZE)),
+            batch_size: MAX_BATCH_SIZE,
+            cpu: None,
+            idle_strategy: IdleStrategy::default(),
+            filter: None,
+            capture: UnsafeCell::new(capture),
+            spare: UnsafeCell::new(Vec::with_capacity(RX_ALLOC_BULK)),
+            claimed: AtomicBool::new(false),
             pool,
             stats: RxQueueStats::default(),
             running: AtomicBool::new(false),
-        })
+        }
+    }
+
+    /// Name the queue in logs and telemetry, `rx<id>` by default
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Stamp received frames with `port_id`
+    pub fn with_port(mut self, port_id: u16) -> Self {
+        self.port_id = port_id;
+        self
+    }
+
+    /// Move 802.1Q tags of received frames into [`Mbuf::vlan_tci`]
+    pub fn with_vlan_strip(mut self, vlan_strip: bool) -> Self {
+        self.vlan_strip = vlan_strip;
+        self
+    }
+
+    /// Take timestamps from `timestamping`, which the capture was opened for
+    pub fn with_timestamping(mut self, timestamping: RxTimestamping) -> Self {
+        self.timestamping = timestamping;
+        self
+    }
+
+    /// Drop received frames `self_filter` identifies as our own
+    pub fn with_self_filter(mut self, self_filter: SelfFilter) -> Self {
+        self.self_filter = self_filter;
+        self
+    }
+
+    /// Keep the last `capacity` drops of received frames
+    pub fn with_drop_log(mut self, capacity: usize) -> Self {
+        self.drop_log = Arc::new(udp::DropLog::new(capacity));
+        self
+    }
+
+    /// Get the log of recent drops of received frames
+    pub fn drop_log(&self) -> &Arc<udp::DropLog> {
+        &self.drop_log
+    }
+
+    /// Receive up to `batch_size` frames per poll
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Poll the queue on `cpu`
+    pub fn with_cpu(mut self, cpu: Option<usize>) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Wait while the queue is empty according to `strategy`
+    pub fn with_idle_strategy(mut self, strategy: IdleStrategy) -> Self {
+        self.idle_strategy = strategy;
+        self
+    }
+
+    /// Apply `filter` to the capture, also applied under any coordination filter
+    pub fn with_filter(mut self, filter: Option<String>) -> Result<Self> {
+        if let Some(filter) = &filter {
+            self.capture.get_mut().filter(filter)?;
+        }
+        self.filter = filter;
+        Ok(self)
+    }
+
+    /// Get the BPF filter the queue was configured with
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_deref()
+    }
+
+    /// Narrow the capture to `filter` on top of the queue's own, see [`coordination`]
+    ///
+    /// Fails with [`Error::QueueError`] while another poller exists.
+    pub fn set_coordination_filter(&self, filter: Option<&str>) -> Result<()> {
+        let program = match (self.filter.as_deref(), filter) {
+            (Some(own), Some(filter)) => format!("({}) and ({})", own, filter),
+            (Some(own), None) => own.to_string(),
+            (None, Some(filter)) => filter.to_string(),
+            // An empty program accepts every frame
+            (None, None) => String::new(),
+        };
+        let poller = self.poller()?;
+        // The claim made in `RxQueue::poller` makes this the only reference
+        let capture = unsafe { &mut *poller.queue.capture.get() };
+        capture.filter(&program)?;
+        Ok(())
+    }
+
+    /// Get the number of frames received per poll
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Get the CPU the queue is polled on, if pinned
+    pub fn cpu(&self) -> Option<usize> {
+        self.cpu
+    }
+
+    /// Get how loops polling the queue wait while it is empty
+    pub fn idle_strategy(&self) -> IdleStrategy {
+        self.idle_strategy
+    }
+
+    /// Get the port ID of the interface captured from
+    pub fn port_id(&self) -> u16 {
+        self.port_id
+    }
+
+    /// Get the source of receive timestamps
+    pub fn timestamping(&self) -> &RxTimestamping {
+        &self.timestamping
     }
 
     /// Get memory pool
@@ -70,59 +366,143 @@ impl RxQueue {
         &self.pool
     }
 
+    /// Get the queue ID
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Get the queue name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Take exclusive use of the capture handle
+    ///
+    /// Fails with [`Error::QueueError`] while another poller exists.
+    pub fn poller(&self) -> Result<RxPoller<'_>> {
+        if self
+            .claimed
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(Error::QueueError(format!(
+                "RX queue {} is already being polled",
+                self.id
+            )));
+        }
+        Ok(RxPoller { queue: self })
+    }
+
     /// Receive a single packet
+    ///
+    /// Reports no packet while another context holds the poller. Loops
+    /// receiving many packets should hold an [`RxPoller`] instead.
     pub fn recv(&self) -> Result<*mut Mbuf> {
-        let mut capture = self.capture.lock();
+        match self.poller() {
+            Ok(mut poller) => poller.recv(),
+            Err(_) => Err(Error::NetworkError(format!(
+                "RX queue {} is polled elsewhere",
+                self.id
+            ))),
+        }
+    }
+
+    /// Next mbuf for a received frame, refilling `spare` in bulk once it runs out
+    fn next_mbuf(&self, spare: &mut Vec<*mut Mbuf>) -> Result<*mut Mbuf> {
+        if let Some(mbuf) = spare.pop() {
+            return Ok(mbuf);
+        }
+        let mut mbufs = [std::ptr::null_mut(); RX_ALLOC_BULK];
+        let mbufs = &mut mbufs[..self.batch_size.clamp(1, RX_ALLOC_BULK)];
+        match self.pool.alloc_bulk(mbufs) {
+            Ok(()) => {
+                spare.extend_from_slice(&mbufs[1..]);
+                Ok(mbufs[0])
+            }
+            // Too few left for a whole refill
+            Err(_) => self.pool.alloc(),
+        }
+    }
 
-        match capture.next_packet() {
-            Ok(packet) => {
-                let mbuf = self.pool.alloc()?;
+    /// Receive a packet from a capture handle the caller has exclusive use of
+    ///
+    /// Our own transmissions looped back are counted and skipped, so they
+    /// do not end a batch as an empty queue would.
+    fn receive(&self, capture: &mut RxSource, spare: &mut Vec<*mut Mbuf>) -> Result<*mut Mbuf> {
+        loop {
+            let packet = match capture.next_packet() {
+                Ok(packet) => packet,
+                Err(pcap::Error::TimeoutExpired) => {
+                    return Err(Error::NetworkError("No packet available".to_string()))
+                }
+                Err(e) => {
+                    self.stats.errors.inc();
+                    return Err(Error::PcapError(e.to_string()));
+                }
+            };
+            if self.self_filter.is_own(packet.data) {
+                self.stats.self_filtered.inc();
+                continue;
+            }
+            let mbuf = self.next_mbuf(spare)?;
 
-                unsafe {
-                    let mbuf_ref = &mut *mbuf;
-                    let data_len = packet.data.len();
+            unsafe {
+                let mbuf_ref = &mut *mbuf;
+                let data_len = packet.data.len();
 
-                    if data_len > mbuf_ref.buf_len {
-                        self.pool.free(mbuf)?;
-                        self.stats.errors.fetch_add(1, Ordering::Relaxed);
-                        return Err(Error::NetworkError("Packet too large for mbuf".to_string()));
-                    }
+                if data_len > mbuf_ref.buf_len {
+                    spare.push(mbuf);
+                    self.stats.errors.inc();
+                    return Err(Error::NetworkError("Packet too large for mbuf".to_string()));
+                }
 
-                    // Copy packet data to mbuf
-                    std::ptr::copy_nonoverlapping(packet.data.as_ptr(), mbuf_ref.data, data_len);
+                // Copy packet data to mbuf
+                std::ptr::copy_nonoverlapping(packet.data.as_ptr(), mbuf_ref.data, data_len);
 
-                    mbuf_ref.len = data_len;
-                    mbuf_ref.timestamp = packet.header.ts.tv_sec as u64 * 1_000_000_000
-                        + packet.header.ts.tv_usec as u64 * 1000;
-                    mbuf_ref.queue_id = self.id;
+                mbuf_ref.len = data_len;
+                let seconds = packet.header.ts.tv_sec as u64 * 1_000_000_000;
+                let fraction = packet.header.ts.tv_usec as u64;
+                match &self.timestamping {
+                    RxTimestamping::Software => {
+                        mbuf_ref.timestamp = seconds + fraction * 1000;
+                    }
+                    RxTimestamping::Adapter => {
+                        mbuf_ref.timestamp = seconds + fraction;
+                        mbuf_ref.offload_flags |= OffloadFlags::RX_TIMESTAMP_HW;
+                    }
+                    RxTimestamping::Phc(sync) => {
+                        mbuf_ref.timestamp = sync.to_system(seconds + fraction).unwrap_or(0);
+                        mbuf_ref.offload_flags |= OffloadFlags::RX_TIMESTAMP_HW;
+                    }
                 }
+                mbuf_ref.offload_flags |= OffloadFlags::RX_TIMESTAMP;
+                mbuf_ref.queue_id = self.id;
+                mbuf_ref.port_id = self.port_id;
 
-                self.stats.packets_received.fetch_add(1, Ordering::Relaxed);
-                self.stats
-                    .bytes_received
-                    .fetch_add(packet.data.len(), Ordering::Relaxed);
+                let tracer = PacketTracer::global();
+                let captured = mbuf_ref.timestamp;
+                tracer.begin(mbuf_ref, captured);
 
-                Ok(mbuf)
-            }
-            Err(pcap::Error::TimeoutExpired) => {
-                Err(Error::NetworkError("No packet available".to_string()))
-            }
-            Err(e) => {
-                self.stats.errors.fetch_add(1, Ordering::Relaxed);
-                Err(Error::PcapError(e.to_string()))
+                if self.vlan_strip {
+                    mbuf_ref.strip_vlan();
+                }
+                // Classify once so later stages don't re-parse headers
+                udp::classify(mbuf_ref);
+                tracer.record(mbuf_ref.trace_id, TraceStage::Classify);
+                TrafficProfiler::global().sample(mbuf_ref);
+                FlowSampler::global().sample(mbuf_ref);
             }
+
+            self.stats.packets_received.inc();
+            self.stats.bytes_received.add(packet.data.len() as u64);
+
+            return Ok(mbuf);
         }
     }
 
     /// Start the receive queue
     pub fn start(&self) -> Result<()> {
         self.running.store(true, Ordering::Relaxed);
-
-        // Set capture mode to non-blocking
-        {
-            let _ = self.capture.lock();
-        }
-
         Ok(())
     }
 
@@ -138,15 +518,115 @@ impl RxQueue {
     }
 }
 
+// The capture handle and spare mbufs are only reached through the poller,
+// which the `claimed` flag makes exclusive; everything else is atomics or
+// immutable. Spare mbufs are owned by the queue wherever it moves.
+unsafe impl Send for RxQueue {}
+unsafe impl Sync for RxQueue {}
+
+impl Drop for RxQueue {
+    fn drop(&mut self) {
+        let _ = self.pool.free_bulk(self.spare.get_mut());
+    }
+}
+
+/// Exclusive receive handle of an [`RxQueue`]
+///
+/// Receives without locking. Dropping it lets another context poll the queue.
+pub struct RxPoller<'a> {
+    queue: &'a RxQueue,
+}
+
+impl RxPoller<'_> {
+    /// Receive a single packet
+    pub fn recv(&mut self) -> Result<*mut Mbuf> {
+        // The claim made in `RxQueue::poller` makes these the only references
+        let capture = unsafe { &mut *self.queue.capture.get() };
+        let spare = unsafe { &mut *self.queue.spare.get() };
+        self.queue.receive(capture, spare)
+    }
+
+    /// Get the queue polled
+    pub fn queue(&self) -> &RxQueue {
+        self.queue
+    }
+}
+
+impl Drop for RxPoller<'_> {
+    fn drop(&mut self) {
+        self.queue.claimed.store(false, Ordering::Release);
+    }
+}
+
+/// Where a receive queue's frames come from
+enum RxSource {
+    /// libpcap capture handle
+    Capture(Capture<Active>),
+    /// Frames queued up front, then no packet
+    #[cfg(test)]
+    Memory {
+        frames: std::collections::VecDeque<Vec<u8>>,
+        /// Frame last handed out, which the returned packet borrows
+        current: Option<(pcap::PacketHeader, Vec<u8>)>,
+    },
+}
+
+impl RxSource {
+    fn next_packet(&mut self) -> std::result::Result<Packet<'_>, pcap::Error> {
+        match self {
+            RxSource::Capture(capture) => capture.next_packet(),
+            #[cfg(test)]
+            RxSource::Memory { frames, current } => {
+                let data = frames.pop_front().ok_or(pcap::Error::TimeoutExpired)?;
+                let header = pcap::PacketHeader {
+                    ts: libc::timeval {
+                        tv_sec: 0,
+                        tv_usec: 0,
+                    },
+                    caplen: data.len() as u32,
+                    len: data.len() as u32,
+                };
+                let (header, data) = current.insert((header, data));
+                Ok(Packet::new(header, data))
+            }
+        }
+    }
+
+    fn filter(&mut self, program: &str) -> Result<()> {
+        match self {
+            RxSource::Capture(capture) => capture.filter(program, true)?,
+            #[cfg(test)]
+            RxSource::Memory { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Where a transmit queue's frames go
+enum TxSink {
+    /// libpcap capture handle (for sending)
+    Capture(Arc<Mutex<Capture<Active>>>),
+    /// Frames kept for inspection, refused while `failing` is set
+    #[cfg(test)]
+    Memory {
+        frames: Mutex<Vec<Vec<u8>>>,
+        failing: AtomicBool,
+    },
+}
+
 /// Transmit queue
 pub struct TxQueue {
     /// Queue ID
-    #[allow(dead_code)]
     id: u16,
-    /// libpcap capture handle (for sending)
-    capture: Arc<Mutex<Capture<Active>>>,
+    /// Name in logs and telemetry
+    name: String,
+    /// Port ID of the interface sent on
+    port_id: u16,
+    sink: TxSink,
     /// Queue statistics
     stats: TxQueueStats,
+    /// Frames held for a departure time
+    schedule: TxSchedule,
     /// Running flag
     running: AtomicBool,
 }
@@ -154,39 +634,207 @@ pub struct TxQueue {
 impl TxQueue {
     /// Create a new transmit queue
     pub fn new(id: u16, capture: Capture<Active>) -> Result<Self> {
-        let capture = Arc::new(Mutex::new(capture));
+        Ok(Self::with_sink(
+            id,
+            TxSink::Capture(Arc::new(Mutex::new(capture))),
+        ))
+    }
 
-        Ok(Self {
+    fn with_sink(id: u16, sink: TxSink) -> Self {
+        Self {
             id,
-            capture,
+            name: format!("tx{}", id),
+            port_id: 0,
+            sink,
             stats: TxQueueStats::default(),
+            schedule: TxSchedule::new(DEFAULT_TX_SCHEDULE_CAPACITY),
             running: AtomicBool::new(false),
-        })
+        }
+    }
+
+    /// Queue keeping the frames it sends in memory, see [`TxQueue::sent_frames`]
+    #[cfg(test)]
+    pub(crate) fn in_memory(id: u16) -> Self {
+        Self::with_sink(
+            id,
+            TxSink::Memory {
+                frames: Mutex::new(Vec::new()),
+                failing: AtomicBool::new(false),
+            },
+        )
+    }
+
+    /// Frames sent by an in-memory queue, oldest first
+    #[cfg(test)]
+    pub(crate) fn sent_frames(&self) -> Vec<Vec<u8>> {
+        match &self.sink {
+            TxSink::Memory { frames, .. } => frames.lock().clone(),
+            TxSink::Capture(_) => Vec::new(),
+        }
+    }
+
+    /// Make an in-memory queue refuse frames, as a failing device would
+    #[cfg(test)]
+    pub(crate) fn set_failing(&self, fail: bool) {
+        if let TxSink::Memory { failing, .. } = &self.sink {
+            failing.store(fail, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the queue ID
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Name the queue in logs and telemetry, `tx<id>` by default
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Get the queue name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Mark the queue as sending on the interface of `port_id`
+    pub fn with_port(mut self, port_id: u16) -> Self {
+        self.port_id = port_id;
+        self
+    }
+
+    /// Get the port ID of the interface sent on
+    pub fn port_id(&self) -> u16 {
+        self.port_id
     }
 
     /// Transmit a single packet
+    ///
+    /// VLAN insertion and IPv4/UDP checksums requested in the TX offload
+    /// flags are done in software; segmentation requests are not, and the
+    /// frame is sent as is.
     pub fn send(&self, mbuf: *mut Mbuf) -> Result<()> {
         if mbuf.is_null() {
             return Err(Error::NetworkError("Null mbuf".to_string()));
         }
 
-        let mbuf_ref = unsafe { &*mbuf };
+        let mbuf_ref = unsafe { &mut *mbuf };
+        if !mbuf_ref.offload_flags.tx().is_empty() {
+            udp::fill_tx_checksums(mbuf_ref);
+            if let Err(e) = mbuf_ref.insert_vlan() {
+                self.stats.errors.inc();
+                return Err(e);
+            }
+        }
         let data = unsafe { std::slice::from_raw_parts(mbuf_ref.data, mbuf_ref.len) };
 
-        let mut capture = self.capture.lock();
-        match capture.sendpacket(data) {
-            Ok(_) => {
-                self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
-                self.stats
-                    .bytes_sent
-                    .fetch_add(mbuf_ref.len, Ordering::Relaxed);
+        let sent = match &self.sink {
+            TxSink::Capture(capture) => capture
+                .lock()
+                .sendpacket(data)
+                .map_err(|e| Error::PcapError(e.to_string())),
+            #[cfg(test)]
+            TxSink::Memory { frames, failing } => {
+                if failing.load(Ordering::Relaxed) {
+                    Err(Error::PcapError("Device refused the frame".to_string()))
+                } else {
+                    frames.lock().push(data.to_vec());
+                    Ok(())
+                }
+            }
+        };
+        match sent {
+            Ok(()) => {
+                self.stats.packets_sent.inc();
+                self.stats.bytes_sent.add(mbuf_ref.len as u64);
                 Ok(())
             }
             Err(e) => {
-                self.stats.errors.fetch_add(1, Ordering::Relaxed);
-                Err(Error::PcapError(e.to_string()))
+                self.stats.errors.inc();
+                Err(e)
+            }
+        }
+    }
+
+    /// Hold up to `capacity` frames for later departure, dropping those held
+    pub fn with_schedule_capacity(mut self, capacity: usize) -> Self {
+        self.schedule = TxSchedule::new(capacity);
+        self
+    }
+
+    /// Send `mbuf` at `at` on the [`monotonic_now`](crate::utils::time::monotonic_now) clock, see [`schedule`]
+    ///
+    /// The mbuf returns to its pool once sent. Departures already due are
+    /// sent at once. Fails with [`Error::QueueError`] while the schedule is
+    /// full.
+    pub fn send_at(&self, mbuf: MbufHandle, at: Timestamp) -> Result<()> {
+        self.schedule_frame(mbuf, at, None)
+    }
+
+    /// Send `mbuf` at `at` like [`TxQueue::send_at`], running `on_departure`
+    /// with the outcome once it is sent, fails to be, or is refused
+    pub fn send_at_then(
+        &self,
+        mbuf: MbufHandle,
+        at: Timestamp,
+        on_departure: impl FnOnce(&Result<()>) + Send + 'static,
+    ) -> Result<()> {
+        self.schedule_frame(mbuf, at, Some(Box::new(on_departure)))
+    }
+
+    fn schedule_frame(
+        &self,
+        mbuf: MbufHandle,
+        at: Timestamp,
+        hook: Option<DepartureHook>,
+    ) -> Result<()> {
+        if at <= self.schedule.now() {
+            self.schedule.stats().scheduled.inc();
+            return self.depart(at, mbuf, hook);
+        }
+        self.schedule.push(mbuf, at, hook)
+    }
+
+    /// Send the scheduled frames that are due, returning how many were sent
+    ///
+    /// Frames failing to send are dropped, counted in the queue's errors
+    /// and the schedule's drops, and reported to their hook.
+    pub fn flush_scheduled(&self) -> usize {
+        let mut sent = 0;
+        while let Some((at, mbuf, hook)) = self.schedule.pop_due(self.schedule.now()) {
+            match self.depart(at, mbuf, hook) {
+                Ok(()) => sent += 1,
+                Err(e) => log::warn!("Scheduled send on {} failed: {}", self.name, e),
             }
         }
+        sent
+    }
+
+    fn depart(&self, at: Timestamp, mbuf: MbufHandle, hook: Option<DepartureHook>) -> Result<()> {
+        let result = self.send(mbuf.as_ptr());
+        match &result {
+            Ok(()) => self.schedule.record(at, self.schedule.now()),
+            Err(_) => self.schedule.record_drop(),
+        }
+        if let Some(hook) = hook {
+            hook(&result);
+        }
+        result
+    }
+
+    /// Get the earliest departure time scheduled
+    pub fn next_departure(&self) -> Option<Timestamp> {
+        self.schedule.next_departure()
+    }
+
+    /// Get the number of frames waiting for their departure time
+    pub fn scheduled(&self) -> usize {
+        self.schedule.len()
+    }
+
+    /// Get the counters of scheduled sends
+    pub fn schedule_stats(&self) -> &TxScheduleStats {
+        self.schedule.stats()
     }
 
     /// Start the transmit queue
@@ -207,26 +855,78 @@ impl TxQueue {
     }
 }
 
+/// Open an RX capture, with NIC timestamps if configured and supported
+///
+/// Unsynced adapter timestamps are only used with a hardware clock to
+/// convert them through.
+fn open_rx_capture(
+    device: &Device,
+    config: &Config,
+    settings: &QueueSettings,
+    phc: Option<&Arc<PhcSync>>,
+) -> Result<(Capture<Active>, RxTimestamping)> {
+    let inactive = || -> Result<Capture<Inactive>> {
+        let capture = Capture::from_device(device.clone())?
+            .promisc(true)
+            .snaplen(DEFAULT_PACKET_SIZE as i32)
+            .timeout(1); // Non-blocking with 1ms timeout
+        Ok(match settings.pcap_buffer {
+            Some(bytes) => capture.buffer_size(bytes as i32),
+            None => capture,
+        })
+    };
+
+    if config.hw_timestamps {
+        let attempts = [
+            (
+                TimestampType::AdapterUnsynced,
+                phc.map(|sync| RxTimestamping::Phc(sync.clone())),
+            ),
+            (TimestampType::Adapter, Some(RxTimestamping::Adapter)),
+        ];
+        for (tstamp_type, timestamping) in attempts {
+            let Some(timestamping) = timestamping else {
+                continue;
+            };
+            // Activation fails on an unsupported timestamp type
+            if let Ok(capture) = inactive()?
+                .tstamp_type(tstamp_type)
+                .precision(Precision::Nano)
+                .open()
+            {
+                return Ok((capture, timestamping));
+            }
+        }
+    }
+    Ok((inactive()?.open()?, RxTimestamping::Software))
+}
+
 /// Poll Mode Driver
 pub struct PollModeDriver {
     /// Driver configuration
-    #[allow(dead_code)]
     config: Config,
     /// Network device
     device: Device,
-    /// Receive queues
-    rx_queues: HashMap<u16, RxQueue>,
-    /// Transmit queues
-    tx_queues: HashMap<u16, TxQueue>,
+    /// Receive queues, shared with the threads polling them
+    rx_queues: BTreeMap<u16, Arc<RxQueue>>,
+    /// Transmit queues, shared with the threads sending on them
+    tx_queues: BTreeMap<u16, Arc<TxQueue>>,
     /// Memory pool
     pool: Arc<MbufPool>,
     /// Running flag
     running: AtomicBool,
+    /// Cancelled when the driver stops
+    shutdown: ShutdownToken,
 }
 
 impl PollModeDriver {
-    /// Create a new poll mode driver
+    /// Create a new poll mode driver with a budget of [`Config::memory_budget`]
     pub fn new(config: &Config) -> Result<Self> {
+        Self::with_budget(config, &Arc::new(MemoryBudget::new(config.memory_budget)))
+    }
+
+    /// Create a poll mode driver whose pool is charged to `budget`
+    pub fn with_budget(config: &Config, budget: &Arc<MemoryBudget>) -> Result<Self> {
         // Find the specified network device
         let device = Device::lookup()
             .unwrap_or_default()
@@ -237,25 +937,60 @@ impl PollModeDriver {
             })?;
 
         // Create memory pool
-        let pool = Arc::new(MbufPool::new(
-            "pmd_pool".to_string(),
-            config.pool_size,
-            DEFAULT_PACKET_SIZE,
-        )?);
+        let pool = Arc::new(
+            MbufPool::with_budget(
+                "pmd_pool".to_string(),
+                config.pool_size,
+                DEFAULT_PACKET_SIZE,
+                budget,
+            )?
+            .with_reset_policy(config.mbuf_reset),
+        );
 
-        let mut rx_queues = HashMap::new();
-        let mut tx_queues = HashMap::new();
+        let mut rx_queues = BTreeMap::new();
+        let mut tx_queues = BTreeMap::new();
+
+        let phc = if config.hw_timestamps {
+            PhcClock::for_interface(&config.interface)
+                .ok()
+                .map(|clock| Arc::new(PhcSync::new(clock)))
+        } else {
+            None
+        };
 
         // Create RX queues
         for i in 0..config.rx_queue_count {
-            let capture = Capture::from_device(device.clone())?
-                .promisc(true)
-                .snaplen(DEFAULT_PACKET_SIZE as i32)
-                .timeout(1) // Non-blocking with 1ms timeout
-                .open()?;
+            let settings = QueueSettings::resolve(config, i as u16);
+            let (capture, timestamping) =
+                open_rx_capture(&device, config, &settings, phc.as_ref())?;
+            let self_filter = match config.capture_direction {
+                CaptureDirection::Inbound => match capture.direction(Direction::In) {
+                    Ok(()) => SelfFilter::default(),
+                    Err(e) => {
+                        log::debug!(
+                            "Capture direction not supported on {} for {}, filtering own frames: {}",
+                            config.interface,
+                            settings.name,
+                            e
+                        );
+                        SelfFilter::for_interface(&config.interface)
+                    }
+                },
+                CaptureDirection::Both => SelfFilter::default(),
+            };
 
-            let rx_queue = RxQueue::new(i as u16, capture, pool.clone())?;
-            rx_queues.insert(i as u16, rx_queue);
+            let rx_queue = RxQueue::new(i as u16, capture, pool.clone())?
+                .with_name(settings.name)
+                .with_port(config.port_id)
+                .with_vlan_strip(config.vlan_strip)
+                .with_timestamping(timestamping)
+                .with_self_filter(self_filter)
+                .with_drop_log(config.drop_log_size)
+                .with_batch_size(settings.batch_size)
+                .with_cpu(settings.cpu)
+                .with_idle_strategy(settings.idle_strategy)
+                .with_filter(settings.filter)?;
+            rx_queues.insert(i as u16, Arc::new(rx_queue));
         }
 
         // Create TX queues
@@ -265,8 +1000,8 @@ impl PollModeDriver {
                 .snaplen(DEFAULT_PACKET_SIZE as i32)
                 .open()?;
 
-            let tx_queue = TxQueue::new(i as u16, capture)?;
-            tx_queues.insert(i as u16, tx_queue);
+            let tx_queue = TxQueue::new(i as u16, capture)?.with_port(config.port_id);
+            tx_queues.insert(i as u16, Arc::new(tx_queue));
         }
 
         Ok(Self {
@@ -276,11 +1011,17 @@ impl PollModeDriver {
             tx_queues,
             pool,
             running: AtomicBool::new(false),
+            shutdown: ShutdownToken::new(),
         })
     }
 
     /// Start the PMD
+    ///
+    /// A shutdown token cancelled by an earlier stop is renewed.
     pub fn start(&mut self) -> Result<()> {
+        if self.shutdown.is_cancelled() {
+            self.shutdown = self.shutdown.renew();
+        }
         self.running.store(true, Ordering::Relaxed);
 
         // Start all RX queues
@@ -296,9 +1037,10 @@ impl PollModeDriver {
         Ok(())
     }
 
-    /// Stop the PMD
+    /// Stop the PMD, cancelling its shutdown token
     pub fn stop(&mut self) -> Result<()> {
         self.running.store(false, Ordering::Relaxed);
+        self.shutdown.cancel();
 
         // Stop all RX queues
         for rx_queue in self.rx_queues.values() {
@@ -315,12 +1057,56 @@ impl PollModeDriver {
 
     /// Get a receive queue by ID
     pub fn get_rx_queue(&self, id: u16) -> Option<&RxQueue> {
-        self.rx_queues.get(&id)
+        self.rx_queues.get(&id).map(Arc::as_ref)
     }
 
     /// Get a transmit queue by ID
     pub fn get_tx_queue(&self, id: u16) -> Option<&TxQueue> {
-        self.tx_queues.get(&id)
+        self.tx_queues.get(&id).map(Arc::as_ref)
+    }
+
+    /// Get a shared handle to a receive queue, e.g. to move into a worker thread
+    pub fn rx_queue(&self, id: u16) -> Option<Arc<RxQueue>> {
+        self.rx_queues.get(&id).cloned()
+    }
+
+    /// Get a shared handle to a transmit queue
+    pub fn tx_queue(&self, id: u16) -> Option<Arc<TxQueue>> {
+        self.tx_queues.get(&id).cloned()
+    }
+
+    /// Get a receive queue by name
+    pub fn rx_queue_by_name(&self, name: &str) -> Option<&Arc<RxQueue>> {
+        self.rx_queues
+            .values()
+            .find(|rx_queue| rx_queue.name() == name)
+    }
+
+    /// Get a transmit queue by name
+    pub fn tx_queue_by_name(&self, name: &str) -> Option<&Arc<TxQueue>> {
+        self.tx_queues
+            .values()
+            .find(|tx_queue| tx_queue.name() == name)
+    }
+
+    /// Iterate over receive queues in ID order
+    pub fn rx_queues(&self) -> impl Iterator<Item = &Arc<RxQueue>> {
+        self.rx_queues.values()
+    }
+
+    /// Iterate over transmit queues in ID order
+    pub fn tx_queues(&self) -> impl Iterator<Item = &Arc<TxQueue>> {
+        self.tx_queues.values()
+    }
+
+    /// Number of receive queues
+    pub fn rx_queue_count(&self) -> usize {
+        self.rx_queues.len()
+    }
+
+    /// Number of transmit queues
+    pub fn tx_queue_count(&self) -> usize {
+        self.tx_queues.len()
     }
 
     /// Get the memory pool
@@ -328,6 +1114,31 @@ impl PollModeDriver {
         &self.pool
     }
 
+    /// Get the hardware clock sync of RX queues with [`RxTimestamping::Phc`]
+    pub fn phc_sync(&self) -> Option<&Arc<PhcSync>> {
+        self.rx_queues
+            .values()
+            .find_map(|rx_queue| match rx_queue.timestamping() {
+                RxTimestamping::Phc(sync) => Some(sync),
+                _ => None,
+            })
+    }
+
+    /// Attach the driver to a parent shutdown token
+    pub fn set_shutdown_token(&mut self, token: ShutdownToken) {
+        self.shutdown = token;
+    }
+
+    /// Get the token cancelled when the driver stops or its parent shuts down
+    pub fn shutdown_token(&self) -> &ShutdownToken {
+        &self.shutdown
+    }
+
+    /// Get the port ID of the driven interface
+    pub fn port_id(&self) -> u16 {
+        self.config.port_id
+    }
+
     /// Get device information
     pub fn device_info(&self) -> &Device {
         &self.device
@@ -338,6 +1149,36 @@ impl PollModeDriver {
 mod tests {
     use super::*;
 
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_queues_are_send_sync() {
+        assert_send_sync::<RxQueue>();
+        assert_send_sync::<TxQueue>();
+        assert_send_sync::<Arc<RxQueue>>();
+    }
+
+    #[test]
+    fn test_self_filter_matches_source_mac() {
+        let own = parse_mac("02:00:5e:10:00:01").unwrap();
+        assert_eq!(own, [0x02, 0x00, 0x5e, 0x10, 0x00, 0x01]);
+        assert!(parse_mac("02:00:5e:10:00").is_none());
+        assert!(parse_mac("02:00:5e:10:00:01:ff").is_none());
+
+        let mut filter = SelfFilter::default();
+        let mut frame = [0u8; 14];
+        frame[6..12].copy_from_slice(&own);
+        assert!(!filter.is_own(&frame));
+        filter.add(own);
+        filter.add(own);
+        assert!(filter.is_own(&frame));
+        // Frames addressed to us are not ours, nor are runts
+        frame[..6].copy_from_slice(&own);
+        frame[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        assert!(!filter.is_own(&frame));
+        assert!(!filter.is_own(&own));
+    }
+
     #[test]
     fn test_pmd_creation() {
         let config = Config::default();
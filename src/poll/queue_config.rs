@@ -0,0 +1,271 @@
+//! Per-queue overrides of the RX queue settings
+//!
+//! Every RX queue gets the sizes of [`Config`] unless a [`QueueConfig`]
+//! for its ID in [`Config::queues`] says otherwise, so one queue taking a
+//! dedicated high-rate flow can have a large capture buffer, long batches
+//! and its own CPU while small control queues stay cheap. Settings left
+//! `None` fall back to the global ones. TX queues send synchronously
+//! through libpcap from the caller's thread and have nothing to size.
+//! RX queues are named `rx<id>` and TX queues `tx<id>` in logs and
+//! telemetry, next to the interface, unless an RX queue is given a name of
+//! its own that no other queue has, and wait while empty according to
+//! [`Config::idle_strategy`] unless given a strategy of their own.
+
+use super::{DEFAULT_PACKET_SIZE, MAX_BATCH_SIZE};
+use crate::utils::backoff::IdleStrategy;
+use crate::utils::sizing::MAX_QUEUE_SIZE;
+use crate::{Config, Error, Result};
+use std::collections::HashSet;
+
+/// Overrides for one RX queue
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// RX queue the overrides apply to
+    pub queue: u16,
+    /// Frames the capture buffers, sizing the libpcap buffer unless `pcap_buffer` is set
+    pub size: Option<usize>,
+    /// Frames received per poll of the queue
+    pub batch_size: Option<usize>,
+    /// libpcap buffer size in bytes
+    pub pcap_buffer: Option<usize>,
+    /// BPF filter applied to the capture
+    pub filter: Option<String>,
+    /// CPU the queue is polled on
+    pub cpu: Option<usize>,
+    /// Name in logs and telemetry
+    pub name: Option<String>,
+    /// How loops polling the queue wait while it is empty
+    pub idle_strategy: Option<IdleStrategy>,
+}
+
+impl QueueConfig {
+    /// No overrides for RX queue `queue`
+    pub fn new(queue: u16) -> Self {
+        Self {
+            queue,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    pub fn with_pcap_buffer(mut self, bytes: usize) -> Self {
+        self.pcap_buffer = Some(bytes);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: &str) -> Self {
+        self.filter = Some(filter.to_string());
+        self
+    }
+
+    pub fn with_cpu(mut self, cpu: usize) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn with_idle_strategy(mut self, strategy: IdleStrategy) -> Self {
+        self.idle_strategy = Some(strategy);
+        self
+    }
+}
+
+/// Settings an RX queue is opened with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct QueueSettings {
+    pub batch_size: usize,
+    /// libpcap buffer in bytes, its default if `None`
+    pub pcap_buffer: Option<usize>,
+    pub filter: Option<String>,
+    pub cpu: Option<usize>,
+    pub name: String,
+    pub idle_strategy: IdleStrategy,
+}
+
+impl QueueSettings {
+    /// Settings of RX queue `queue` under `config`
+    pub(crate) fn resolve(config: &Config, queue: u16) -> Self {
+        let overrides = config.queues.iter().find(|q| q.queue == queue);
+        let get = |f: fn(&QueueConfig) -> Option<usize>| overrides.and_then(f);
+        Self {
+            batch_size: get(|q| q.batch_size).unwrap_or(MAX_BATCH_SIZE),
+            pcap_buffer: get(|q| q.pcap_buffer)
+                .or_else(|| get(|q| q.size).map(|size| size * DEFAULT_PACKET_SIZE)),
+            filter: overrides.and_then(|q| q.filter.clone()),
+            cpu: get(|q| q.cpu),
+            name: overrides
+                .and_then(|q| q.name.clone())
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|| format!("rx{}", queue)),
+            idle_strategy: overrides
+                .and_then(|q| q.idle_strategy)
+                .unwrap_or(config.idle_strategy),
+        }
+    }
+}
+
+/// Check the queue overrides of `config`, failing with [`Error::InvalidConfig`]
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    let mut seen = HashSet::new();
+    for overrides in &config.queues {
+        let queue = overrides.queue;
+        let invalid = |what: &str| {
+            Err(Error::InvalidConfig(format!(
+                "RX queue {} config: {}",
+                queue, what
+            )))
+        };
+        if queue as usize >= config.rx_queue_count {
+            return invalid(&format!("only {} RX queues", config.rx_queue_count));
+        }
+        if !seen.insert(queue) {
+            return invalid("configured twice");
+        }
+        let size = overrides.size.unwrap_or(config.rx_queue_size);
+        if overrides.size.is_some() && !(1..=MAX_QUEUE_SIZE).contains(&size) {
+            return invalid(&format!("size must be 1 to {}", MAX_QUEUE_SIZE));
+        }
+        if overrides
+            .batch_size
+            .is_some_and(|batch| batch == 0 || batch > size)
+        {
+            return invalid("batch size must be 1 to the queue size");
+        }
+        if overrides
+            .pcap_buffer
+            .is_some_and(|bytes| bytes == 0 || bytes > i32::MAX as usize)
+        {
+            return invalid("pcap buffer must be 1 byte to 2 GiB");
+        }
+        if overrides
+            .filter
+            .as_ref()
+            .is_some_and(|filter| filter.trim().is_empty())
+        {
+            return invalid("empty filter");
+        }
+        if let Some(cpu) = overrides.cpu.filter(|&cpu| cpu >= num_cpus::get()) {
+            return invalid(&format!("CPU {} does not exist", cpu));
+        }
+        if overrides
+            .name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            return invalid("empty name");
+        }
+    }
+
+    // Names chosen must not take the default name of another queue either
+    let mut names: HashSet<String> = (0..config.tx_queue_count)
+        .map(|queue| format!("tx{}", queue))
+        .collect();
+    for queue in 0..config.rx_queue_count as u16 {
+        let name = QueueSettings::resolve(config, queue).name;
+        if names.contains(&name) {
+            return Err(Error::InvalidConfig(format!(
+                "RX queue {} config: name '{}' taken by another queue",
+                queue, name
+            )));
+        }
+        names.insert(name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_overrides_resolve_and_validate() {
+        let config = Config::builder()
+            .with_queues(3, 1)
+            .with_hugepages(false)
+            .with_queue(
+                QueueConfig::new(0)
+                    .with_size(16384)
+                    .with_batch_size(256)
+                    .with_cpu(0),
+            )
+            .with_queue(
+                QueueConfig::new(2)
+                    .with_pcap_buffer(1 << 16)
+                    .with_filter("udp port 53")
+                    .with_name("dns")
+                    .with_idle_strategy(IdleStrategy::sleep(Duration::from_millis(1))),
+            )
+            .build()
+            .unwrap();
+
+        let fast = QueueSettings::resolve(&config, 0);
+        assert_eq!(fast.batch_size, 256);
+        assert_eq!(fast.pcap_buffer, Some(16384 * DEFAULT_PACKET_SIZE));
+        assert_eq!(fast.cpu, Some(0));
+        let default = QueueSettings::resolve(&config, 1);
+        assert_eq!(default.batch_size, MAX_BATCH_SIZE);
+        assert_eq!(default.pcap_buffer, None);
+        let control = QueueSettings::resolve(&config, 2);
+        assert_eq!(control.pcap_buffer, Some(1 << 16));
+        assert_eq!(control.filter.as_deref(), Some("udp port 53"));
+        assert_eq!(control.name, "dns");
+        assert_eq!(control.idle_strategy.max_sleep, Duration::from_millis(1));
+        assert_eq!(default.idle_strategy, config.idle_strategy);
+        assert_eq!(default.name, "rx1");
+
+        for bad in [
+            QueueConfig::new(3),
+            QueueConfig::new(0).with_size(0),
+            QueueConfig::new(0).with_batch_size(8192),
+            QueueConfig::new(0).with_pcap_buffer(0),
+            QueueConfig::new(0).with_filter(" "),
+            QueueConfig::new(0).with_cpu(usize::MAX),
+            QueueConfig::new(0).with_name(""),
+        ] {
+            assert!(Config::builder()
+                .with_queues(3, 1)
+                .with_queue(bad)
+                .build()
+                .is_err());
+        }
+        assert!(Config::builder()
+            .with_queues(3, 1)
+            .with_queue(QueueConfig::new(1))
+            .with_queue(QueueConfig::new(1))
+            .build()
+            .is_err());
+        assert!(Config::builder()
+            .with_queues(3, 1)
+            .with_queue(QueueConfig::new(0).with_name("dns"))
+            .with_queue(QueueConfig::new(1).with_name("dns"))
+            .build()
+            .is_err());
+        // Nor may a name be the default of another queue
+        for taken in ["rx1", "tx0"] {
+            assert!(Config::builder()
+                .with_queues(3, 1)
+                .with_queue(QueueConfig::new(0).with_name(taken))
+                .build()
+                .is_err());
+        }
+        assert!(Config::builder()
+            .with_queues(3, 1)
+            .with_queue(QueueConfig::new(0).with_name("rx0"))
+            .build()
+            .is_ok());
+    }
+}
@@ -0,0 +1,269 @@
+//! Deadline-scheduled transmission
+//!
+//! [`super::TxQueue::send_at`] holds a frame until a departure time on
+//! the [`monotonic_now`] clock, for pacers that compute exact departure
+//! times themselves. Frames wait in a time-ordered queue per TX queue,
+//! frames with the same departure time in the order they were scheduled,
+//! and are sent by whichever flush loop reaches their time first:
+//! [`crate::Xpdk::poll_once`] flushes on every poll, and the thread of
+//! [`crate::Xpdk::spawn_tx_scheduler`] sleeps until shortly before the
+//! next departure and spins the rest of the way, for departures accurate
+//! to a few microseconds. Departures already due are sent at once.
+//!
+//! [`TxScheduleStats`] counts how late frames left against their
+//! scheduled time. A frame scheduled with [`super::TxQueue::send_at_then`]
+//! also carries a [`DepartureHook`], run with the outcome once the frame
+//! leaves or fails to, so callers account for a send when it happens.
+
+use super::TxQueue;
+use crate::memory::MbufHandle;
+use crate::utils::counter::Counter;
+use crate::utils::shutdown::ShutdownToken;
+use crate::utils::time::{monotonic_now, HighResTimer, Timestamp, TimestampSource};
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Frames a TX queue holds for later departure by default
+pub const DEFAULT_TX_SCHEDULE_CAPACITY: usize = 4096;
+
+/// Time before a departure the scheduler thread stops sleeping and spins
+pub const SCHEDULER_SPIN: Duration = Duration::from_micros(50);
+
+/// Longest the scheduler thread sleeps with nothing scheduled
+pub const SCHEDULER_IDLE_SLEEP: Duration = Duration::from_micros(500);
+
+/// Run with the outcome of a scheduled send when the frame departs, fails
+/// to, or is refused with the schedule full
+pub type DepartureHook = Box<dyn FnOnce(&Result<()>) + Send>;
+
+/// Counters of a TX queue's scheduled sends
+#[derive(Debug, Default)]
+pub struct TxScheduleStats {
+    pub scheduled: Counter,
+    pub sent: Counter,
+    /// Frames refused with the schedule full or failing to send
+    pub dropped: Counter,
+    /// Sum of the delays of sent frames past their departure time
+    pub delay_total_ns: Counter,
+    delay_max_ns: AtomicU64,
+}
+
+impl TxScheduleStats {
+    fn record_departure(&self, delay_ns: u64) {
+        self.sent.inc();
+        self.delay_total_ns.add(delay_ns);
+        self.delay_max_ns.fetch_max(delay_ns, Ordering::Relaxed);
+    }
+
+    /// Mean time frames left after their departure time
+    pub fn mean_delay(&self) -> Duration {
+        match self.sent.get() {
+            0 => Duration::ZERO,
+            sent => Duration::from_nanos(self.delay_total_ns.get() / sent),
+        }
+    }
+
+    /// Longest time a frame left after its departure time
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_nanos(self.delay_max_ns.load(Ordering::Relaxed))
+    }
+}
+
+/// Frame waiting for its departure time
+struct Scheduled {
+    at: Timestamp,
+    /// Order of scheduling, breaking ties between equal times
+    seq: u64,
+    mbuf: MbufHandle,
+    hook: Option<DepartureHook>,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        // Reversed, so the max-heap yields the earliest departure
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+/// Time-ordered frames of one TX queue
+pub(crate) struct TxSchedule {
+    queue: Mutex<(BinaryHeap<Scheduled>, u64)>,
+    capacity: usize,
+    timer: HighResTimer,
+    stats: TxScheduleStats,
+}
+
+impl TxSchedule {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            queue: Mutex::new((BinaryHeap::new(), 0)),
+            capacity,
+            timer: HighResTimer::new(TimestampSource::MonotonicClock),
+            stats: TxScheduleStats::default(),
+        }
+    }
+
+    pub(crate) fn now(&self) -> Timestamp {
+        self.timer.now()
+    }
+
+    /// Hold `mbuf` until `at`, failing with [`Error::QueueError`] when full
+    pub(crate) fn push(
+        &self,
+        mbuf: MbufHandle,
+        at: Timestamp,
+        hook: Option<DepartureHook>,
+    ) -> Result<()> {
+        let mut queue = self.queue.lock();
+        let (heap, next_seq) = &mut *queue;
+        if heap.len() >= self.capacity {
+            self.stats.dropped.inc();
+            let full = Err(Error::QueueError(format!(
+                "TX schedule full with {} frames",
+                heap.len()
+            )));
+            if let Some(hook) = hook {
+                hook(&full);
+            }
+            return full;
+        }
+        heap.push(Scheduled {
+            at,
+            seq: *next_seq,
+            mbuf,
+            hook,
+        });
+        *next_seq += 1;
+        self.stats.scheduled.inc();
+        Ok(())
+    }
+
+    /// Take the earliest frame if due by `now`, with its departure time and hook
+    pub(crate) fn pop_due(
+        &self,
+        now: Timestamp,
+    ) -> Option<(Timestamp, MbufHandle, Option<DepartureHook>)> {
+        let mut queue = self.queue.lock();
+        if queue.0.peek()?.at > now {
+            return None;
+        }
+        queue
+            .0
+            .pop()
+            .map(|scheduled| (scheduled.at, scheduled.mbuf, scheduled.hook))
+    }
+
+    pub(crate) fn next_departure(&self) -> Option<Timestamp> {
+        self.queue.lock().0.peek().map(|scheduled| scheduled.at)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queue.lock().0.len()
+    }
+
+    /// Count the departure of a frame due at `at`, sent at `sent`
+    pub(crate) fn record(&self, at: Timestamp, sent: Timestamp) {
+        self.stats.record_departure(sent.saturating_sub(at));
+    }
+
+    pub(crate) fn record_drop(&self) {
+        self.stats.dropped.inc();
+    }
+
+    pub(crate) fn stats(&self) -> &TxScheduleStats {
+        &self.stats
+    }
+}
+
+/// Send the scheduled frames of `tx_queues` on time until `shutdown` is cancelled
+pub fn run_scheduler(tx_queues: &[Arc<TxQueue>], shutdown: &ShutdownToken) {
+    while !shutdown.is_cancelled() {
+        let mut next = None::<Timestamp>;
+        for tx_queue in tx_queues {
+            tx_queue.flush_scheduled();
+            if let Some(at) = tx_queue.next_departure() {
+                next = Some(next.map_or(at, |next| next.min(at)));
+            }
+        }
+
+        let wait = match next {
+            Some(at) => Duration::from_nanos(at.saturating_sub(monotonic_now())),
+            None => SCHEDULER_IDLE_SLEEP,
+        };
+        if wait > SCHEDULER_SPIN {
+            // Frames scheduled meanwhile for sooner wait out the sleep
+            let sleep = (wait - SCHEDULER_SPIN).min(SCHEDULER_IDLE_SLEEP);
+            std::thread::sleep(sleep);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+
+    #[test]
+    fn test_schedule_orders_by_departure() {
+        let pool = Arc::new(MbufPool::new("schedule".to_string(), 8, 256).unwrap());
+        let schedule = TxSchedule::new(3);
+        let frame = |mark| {
+            let mut mbuf = MbufHandle::alloc(&pool).unwrap();
+            mbuf.mark = mark;
+            mbuf
+        };
+        schedule.push(frame(1), 300, None).unwrap();
+        schedule.push(frame(2), 100, None).unwrap();
+        schedule.push(frame(3), 100, None).unwrap();
+        // A refused frame's hook hears of it at once
+        let refused = Arc::new(AtomicU64::new(0));
+        let hook = {
+            let refused = refused.clone();
+            Box::new(move |result: &Result<()>| {
+                refused.store(result.is_err() as u64, Ordering::Relaxed);
+            })
+        };
+        assert!(schedule.push(frame(4), 50, Some(hook)).is_err());
+        assert_eq!(refused.load(Ordering::Relaxed), 1);
+        assert_eq!(schedule.next_departure(), Some(100));
+
+        assert!(schedule.pop_due(99).is_none());
+        let due: Vec<_> =
+            std::iter::from_fn(|| schedule.pop_due(200).map(|(at, mbuf, _)| (at, mbuf.mark)))
+                .collect();
+        assert_eq!(due, [(100, 2), (100, 3)]);
+        assert_eq!(schedule.len(), 1);
+
+        schedule.record(100, 110);
+        schedule.record(100, 130);
+        let stats = schedule.stats();
+        assert_eq!((stats.scheduled.get(), stats.dropped.get()), (3, 1));
+        assert_eq!(stats.mean_delay(), Duration::from_nanos(20));
+        assert_eq!(stats.max_delay(), Duration::from_nanos(30));
+
+        // Frames still scheduled return to the pool
+        drop(schedule);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
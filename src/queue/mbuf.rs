@@ -0,0 +1,200 @@
+//! Queues of owned mbufs
+//!
+//! [`MbufQueue`] carries [`MbufHandle`]s over any of the four lock-free
+//! ring flavours instead of raw `*mut Mbuf`. A push hands the mbuf to the
+//! queue and gives it back if the queue is full, a pop hands it to the
+//! caller, and mbufs still queued when the queue is dropped are freed to
+//! their pools, so ownership is never lost on the way between threads.
+
+use super::QueueStats;
+use crate::memory::{Mbuf, MbufHandle, MbufPool};
+use crate::{Error, Result};
+use lockfree_ringbuf::{MpmcRingBuffer, MpscRingBuffer, SpmcRingBuffer, SpscRingBuffer};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Producer and consumer concurrency of a queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFlavor {
+    /// One producer and one consumer thread
+    Spsc,
+    /// Many producers, one consumer
+    Mpsc,
+    /// One producer, many consumers
+    Spmc,
+    /// Many producers and consumers
+    Mpmc,
+}
+
+/// Handle taken apart for the ring; the pool reference is owned by the slot
+#[derive(Clone, Copy)]
+struct Slot {
+    mbuf: *mut Mbuf,
+    pool: *const MbufPool,
+}
+
+impl Slot {
+    fn from_handle(handle: MbufHandle) -> Self {
+        let (mbuf, pool) = handle.into_raw();
+        Self {
+            mbuf,
+            pool: Arc::into_raw(pool),
+        }
+    }
+
+    fn into_handle(self) -> MbufHandle {
+        unsafe { MbufHandle::from_raw(self.mbuf, Arc::from_raw(self.pool)) }
+    }
+}
+
+enum Ring {
+    Spsc(SpscRingBuffer<Slot>),
+    Mpsc(MpscRingBuffer<Slot>),
+    Spmc(SpmcRingBuffer<Slot>),
+    Mpmc(MpmcRingBuffer<Slot>),
+}
+
+/// Lock-free queue of owned mbufs
+pub struct MbufQueue {
+    ring: Ring,
+    stats: QueueStats,
+}
+
+// Slots are owned by the queue until popped, like the handles they came from
+unsafe impl Send for MbufQueue {}
+unsafe impl Sync for MbufQueue {}
+
+impl MbufQueue {
+    /// Create a queue of `flavor` for `capacity` mbufs, rounded up to a power of two
+    pub fn new(flavor: QueueFlavor, capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            return Err(Error::InvalidConfig(
+                "Queue capacity must be non-zero".to_string(),
+            ));
+        }
+        let ring = match flavor {
+            QueueFlavor::Spsc => Ring::Spsc(SpscRingBuffer::new(capacity)),
+            QueueFlavor::Mpsc => Ring::Mpsc(MpscRingBuffer::new(capacity)),
+            QueueFlavor::Spmc => Ring::Spmc(SpmcRingBuffer::new(capacity)),
+            QueueFlavor::Mpmc => Ring::Mpmc(MpmcRingBuffer::new(capacity)),
+        };
+        Ok(Self {
+            ring,
+            stats: QueueStats::default(),
+        })
+    }
+
+    /// Concurrency the queue was created for
+    pub fn flavor(&self) -> QueueFlavor {
+        match self.ring {
+            Ring::Spsc(_) => QueueFlavor::Spsc,
+            Ring::Mpsc(_) => QueueFlavor::Mpsc,
+            Ring::Spmc(_) => QueueFlavor::Spmc,
+            Ring::Mpmc(_) => QueueFlavor::Mpmc,
+        }
+    }
+
+    /// Queue an mbuf, handing it back if the queue is full
+    pub fn push(&self, mbuf: MbufHandle) -> std::result::Result<(), MbufHandle> {
+        let slot = Slot::from_handle(mbuf);
+        let pushed = match &self.ring {
+            Ring::Spsc(ring) => ring.push(slot),
+            Ring::Mpsc(ring) => ring.push(slot),
+            Ring::Spmc(ring) => ring.push(slot),
+            Ring::Mpmc(ring) => ring.push(slot),
+        };
+        match pushed {
+            Ok(()) => {
+                self.stats.enqueued.inc();
+                let current_size = self.stats.current_size.fetch_add(1, Ordering::Relaxed) + 1;
+                self.stats
+                    .peak_size
+                    .fetch_max(current_size, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(_) => {
+                self.stats.drops.inc();
+                Err(slot.into_handle())
+            }
+        }
+    }
+
+    /// Take the oldest mbuf
+    pub fn pop(&self) -> Option<MbufHandle> {
+        let slot = match &self.ring {
+            Ring::Spsc(ring) => ring.pop(),
+            Ring::Mpsc(ring) => ring.pop(),
+            Ring::Spmc(ring) => ring.pop(),
+            Ring::Mpmc(ring) => ring.pop(),
+        }
+        .ok()?;
+        self.stats.dequeued.inc();
+        self.stats.current_size.fetch_sub(1, Ordering::Relaxed);
+        Some(slot.into_handle())
+    }
+
+    /// Get queue capacity
+    pub fn capacity(&self) -> usize {
+        match &self.ring {
+            Ring::Spsc(ring) => ring.capacity(),
+            Ring::Mpsc(ring) => ring.capacity(),
+            Ring::Spmc(ring) => ring.capacity(),
+            Ring::Mpmc(ring) => ring.capacity(),
+        }
+    }
+
+    /// Mbufs queued
+    pub fn len(&self) -> usize {
+        self.stats.current_size.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get queue statistics
+    pub fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+}
+
+impl Drop for MbufQueue {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ownership_survives_full_and_dropped_queues() {
+        let pool = Arc::new(MbufPool::new("mbufq".to_string(), 8, 256).unwrap());
+        for flavor in [
+            QueueFlavor::Spsc,
+            QueueFlavor::Mpsc,
+            QueueFlavor::Spmc,
+            QueueFlavor::Mpmc,
+        ] {
+            let queue = MbufQueue::new(flavor, 2).unwrap();
+            assert_eq!(queue.flavor(), flavor);
+            let mut first = MbufHandle::alloc(&pool).unwrap();
+            first.append(b"first").unwrap();
+            queue.push(first).unwrap();
+            queue.push(MbufHandle::alloc(&pool).unwrap()).unwrap();
+
+            // A full queue hands the mbuf back instead of losing it
+            let rejected = queue.push(MbufHandle::alloc(&pool).unwrap()).unwrap_err();
+            assert_eq!(pool.stats().in_use, 3);
+            drop(rejected);
+            assert_eq!(queue.pop().unwrap().data(), b"first");
+            assert_eq!(queue.len(), 1);
+
+            // Dropping the queue frees what it still holds
+            drop(queue);
+            assert_eq!(pool.stats().in_use, 0);
+        }
+        assert!(MbufQueue::new(QueueFlavor::Mpsc, 0).is_err());
+    }
+}
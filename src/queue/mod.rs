@@ -3,23 +3,38 @@
 //! This module wraps the existing lockfree-ringbuf crate and provides additional
 //! queue implementations optimized for the XPDK use case.
 
+use crate::utils::backoff::{Backoff, IdleStrategy};
+use crate::utils::counter::Counter;
+use crate::utils::shutdown::{join_with_deadline, ShutdownToken};
 use crate::{memory::Mbuf, Error, Result};
-use lockfree_ringbuf::{BatchOps, MpmcRingBuffer, SpscRingBuffer};
+use lockfree_ringbuf::{BatchOps, ReserveOps, WriteGrant};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+mod mbuf;
+mod placement;
+mod release;
+
+pub use lockfree_ringbuf::{MpmcRingBuffer, MpscRingBuffer, SpmcRingBuffer, SpscRingBuffer};
+pub use mbuf::{MbufQueue, QueueFlavor};
+pub(crate) use placement::slot_memory;
+pub use placement::QueuePlacement;
+pub use release::{ReleaseQueue, ReleaseSchedule, ReleaseStats};
 
 /// Queue statistics
 #[derive(Debug, Default)]
 pub struct QueueStats {
-    pub enqueued: AtomicUsize,
-    pub dequeued: AtomicUsize,
-    pub drops: AtomicUsize,
-    pub errors: AtomicUsize,
+    pub enqueued: Counter,
+    pub dequeued: Counter,
+    pub drops: Counter,
+    pub errors: Counter,
     pub current_size: AtomicUsize,
     pub peak_size: AtomicUsize,
+    /// Where the queue slots were placed
+    pub placement: QueuePlacement,
 }
 
 /// Generic ring buffer trait
@@ -54,6 +69,71 @@ pub trait RingBuffer<T> {
 
     /// Get queue statistics
     fn stats(&self) -> &QueueStats;
+
+    /// Reserve space for `n` items, published together on commit
+    fn try_reserve(&self, n: usize) -> Result<Reservation<'_, T>>
+    where
+        T: Copy;
+
+    /// Pop up to `max` items into `items`, returning how many were taken
+    fn drain(&self, max: usize, items: &mut Vec<T>) -> usize
+    where
+        T: Copy;
+}
+
+/// Space reserved in a queue by [`RingBuffer::try_reserve`]
+///
+/// Dropping the reservation without committing releases the space.
+pub struct Reservation<'a, T: Copy> {
+    grant: WriteGrant<'a, T>,
+    stats: &'a QueueStats,
+}
+
+impl<T: Copy> Reservation<'_, T> {
+    /// Stage an item in the reserved space
+    pub fn push(&mut self, item: T) -> Result<()> {
+        self.grant
+            .push(item)
+            .map_err(|_| Error::QueueError("Reservation exhausted".to_string()))
+    }
+
+    /// Number of reserved slots
+    pub fn reserved(&self) -> usize {
+        self.grant.reserved()
+    }
+
+    /// Number of staged items
+    pub fn len(&self) -> usize {
+        self.grant.len()
+    }
+
+    /// Check if no items are staged
+    pub fn is_empty(&self) -> bool {
+        self.grant.is_empty()
+    }
+
+    /// Publish the staged items and release unused slots
+    pub fn commit(self) {
+        let count = self.grant.len();
+        self.grant.commit();
+        record_enqueued(self.stats, count);
+    }
+
+    /// Release the reservation without publishing anything
+    pub fn abort(self) {
+        self.grant.abort();
+    }
+}
+
+fn record_enqueued(stats: &QueueStats, count: usize) {
+    stats.enqueued.add(count as u64);
+    let current_size = stats.current_size.fetch_add(count, Ordering::Relaxed) + count;
+    stats.peak_size.fetch_max(current_size, Ordering::Relaxed);
+}
+
+fn record_dequeued(stats: &QueueStats, count: usize) {
+    stats.dequeued.add(count as u64);
+    stats.current_size.fetch_sub(count, Ordering::Relaxed);
 }
 
 /// SPSC (Single Producer Single Consumer) queue wrapper
@@ -67,11 +147,23 @@ pub struct SpscQueue<T> {
 impl<T> SpscQueue<T> {
     /// Create a new SPSC queue
     pub fn new(capacity: usize) -> Result<Self> {
-        let inner = SpscRingBuffer::new(capacity);
+        Self::with_placement(capacity, QueuePlacement::Heap)
+    }
+
+    /// Create a new SPSC queue with its slots placed as requested
+    pub fn with_placement(capacity: usize, placement: QueuePlacement) -> Result<Self> {
+        let (memory, placement) = placement::slot_memory(capacity, placement)?;
+        let inner = match memory {
+            Some(memory) => SpscRingBuffer::with_memory(memory),
+            None => SpscRingBuffer::new(capacity),
+        };
 
         Ok(Self {
             inner,
-            stats: QueueStats::default(),
+            stats: QueueStats {
+                placement,
+                ..QueueStats::default()
+            },
         })
     }
 }
@@ -80,7 +172,7 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
     fn push(&self, item: T) -> Result<()> {
         match self.inner.push(item) {
             Ok(_) => {
-                self.stats.enqueued.fetch_add(1, Ordering::Relaxed);
+                self.stats.enqueued.inc();
                 let current_size = self.stats.current_size.fetch_add(1, Ordering::Relaxed) + 1;
                 self.stats
                     .peak_size
@@ -88,7 +180,7 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
                 Ok(())
             }
             Err(_) => {
-                self.stats.drops.fetch_add(1, Ordering::Relaxed);
+                self.stats.drops.inc();
                 Err(Error::QueueError("Queue full".to_string()))
             }
         }
@@ -97,12 +189,12 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
     fn pop(&self) -> Result<T> {
         match self.inner.pop() {
             Ok(item) => {
-                self.stats.dequeued.fetch_add(1, Ordering::Relaxed);
+                self.stats.dequeued.inc();
                 self.stats.current_size.fetch_sub(1, Ordering::Relaxed);
                 Ok(item)
             }
             Err(_) => {
-                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                self.stats.errors.inc();
                 Err(Error::QueueError("Queue empty".to_string()))
             }
         }
@@ -115,7 +207,7 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
         match self.inner.push_batch(items) {
             Ok(_) => {
                 let count = items.len();
-                self.stats.enqueued.fetch_add(count, Ordering::Relaxed);
+                self.stats.enqueued.add(count as u64);
                 let current_size =
                     self.stats.current_size.fetch_add(count, Ordering::Relaxed) + count;
                 self.stats
@@ -124,7 +216,7 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
                 Ok(())
             }
             Err(_) => {
-                self.stats.drops.fetch_add(items.len(), Ordering::Relaxed);
+                self.stats.drops.add(items.len() as u64);
                 Err(Error::QueueError("Queue full".to_string()))
             }
         }
@@ -135,12 +227,12 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
     {
         match self.inner.pop_batch(items) {
             Ok(count) => {
-                self.stats.dequeued.fetch_add(count, Ordering::Relaxed);
+                self.stats.dequeued.add(count as u64);
                 self.stats.current_size.fetch_sub(count, Ordering::Relaxed);
                 Ok(count)
             }
             Err(_) => {
-                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                self.stats.errors.inc();
                 Err(Error::QueueError("Queue empty".to_string()))
             }
         }
@@ -165,6 +257,33 @@ impl<T> RingBuffer<T> for SpscQueue<T> {
     fn stats(&self) -> &QueueStats {
         &self.stats
     }
+
+    fn try_reserve(&self, n: usize) -> Result<Reservation<'_, T>>
+    where
+        T: Copy,
+    {
+        match self.inner.try_reserve(n) {
+            Ok(grant) => Ok(Reservation {
+                grant,
+                stats: &self.stats,
+            }),
+            Err(_) => {
+                self.stats.drops.add(n as u64);
+                Err(Error::QueueError(format!("No room to reserve {} items", n)))
+            }
+        }
+    }
+
+    fn drain(&self, max: usize, items: &mut Vec<T>) -> usize
+    where
+        T: Copy,
+    {
+        let before = items.len();
+        items.extend(self.inner.drain(max));
+        let count = items.len() - before;
+        record_dequeued(&self.stats, count);
+        count
+    }
 }
 
 /// MPMC (Multi Producer Multi Consumer) queue wrapper
@@ -178,11 +297,23 @@ pub struct MpmcQueue<T> {
 impl<T> MpmcQueue<T> {
     /// Create a new MPMC queue
     pub fn new(capacity: usize) -> Result<Self> {
-        let inner = MpmcRingBuffer::new(capacity);
+        Self::with_placement(capacity, QueuePlacement::Heap)
+    }
+
+    /// Create a new MPMC queue with its slots placed as requested
+    pub fn with_placement(capacity: usize, placement: QueuePlacement) -> Result<Self> {
+        let (memory, placement) = placement::slot_memory(capacity, placement)?;
+        let inner = match memory {
+            Some(memory) => MpmcRingBuffer::with_memory(memory),
+            None => MpmcRingBuffer::new(capacity),
+        };
 
         Ok(Self {
             inner,
-            stats: QueueStats::default(),
+            stats: QueueStats {
+                placement,
+                ..QueueStats::default()
+            },
         })
     }
 }
@@ -191,7 +322,7 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
     fn push(&self, item: T) -> Result<()> {
         match self.inner.push(item) {
             Ok(_) => {
-                self.stats.enqueued.fetch_add(1, Ordering::Relaxed);
+                self.stats.enqueued.inc();
                 let current_size = self.stats.current_size.fetch_add(1, Ordering::Relaxed) + 1;
                 self.stats
                     .peak_size
@@ -199,7 +330,7 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
                 Ok(())
             }
             Err(_) => {
-                self.stats.drops.fetch_add(1, Ordering::Relaxed);
+                self.stats.drops.inc();
                 Err(Error::QueueError("Queue full".to_string()))
             }
         }
@@ -208,12 +339,12 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
     fn pop(&self) -> Result<T> {
         match self.inner.pop() {
             Ok(item) => {
-                self.stats.dequeued.fetch_add(1, Ordering::Relaxed);
+                self.stats.dequeued.inc();
                 self.stats.current_size.fetch_sub(1, Ordering::Relaxed);
                 Ok(item)
             }
             Err(_) => {
-                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                self.stats.errors.inc();
                 Err(Error::QueueError("Queue empty".to_string()))
             }
         }
@@ -226,7 +357,7 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
         match self.inner.push_batch(items) {
             Ok(_) => {
                 let count = items.len();
-                self.stats.enqueued.fetch_add(count, Ordering::Relaxed);
+                self.stats.enqueued.add(count as u64);
                 let current_size =
                     self.stats.current_size.fetch_add(count, Ordering::Relaxed) + count;
                 self.stats
@@ -235,7 +366,7 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
                 Ok(())
             }
             Err(_) => {
-                self.stats.drops.fetch_add(items.len(), Ordering::Relaxed);
+                self.stats.drops.add(items.len() as u64);
                 Err(Error::QueueError("Queue full".to_string()))
             }
         }
@@ -247,12 +378,12 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
     {
         match self.inner.pop_batch(items) {
             Ok(count) => {
-                self.stats.dequeued.fetch_add(count, Ordering::Relaxed);
+                self.stats.dequeued.add(count as u64);
                 self.stats.current_size.fetch_sub(count, Ordering::Relaxed);
                 Ok(count)
             }
             Err(_) => {
-                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                self.stats.errors.inc();
                 Err(Error::QueueError("Queue empty".to_string()))
             }
         }
@@ -277,8 +408,42 @@ impl<T> RingBuffer<T> for MpmcQueue<T> {
     fn stats(&self) -> &QueueStats {
         &self.stats
     }
+
+    fn try_reserve(&self, n: usize) -> Result<Reservation<'_, T>>
+    where
+        T: Copy,
+    {
+        match self.inner.try_reserve(n) {
+            Ok(grant) => Ok(Reservation {
+                grant,
+                stats: &self.stats,
+            }),
+            Err(_) => {
+                self.stats.drops.add(n as u64);
+                Err(Error::QueueError(format!("No room to reserve {} items", n)))
+            }
+        }
+    }
+
+    fn drain(&self, max: usize, items: &mut Vec<T>) -> usize
+    where
+        T: Copy,
+    {
+        let before = items.len();
+        items.extend(self.inner.drain(max));
+        let count = items.len() - before;
+        record_dequeued(&self.stats, count);
+        count
+    }
 }
 
+// Mbufs travel between threads by ownership: whoever pops a pointer owns it,
+// so queues of mbuf pointers can be shared with worker threads.
+unsafe impl Send for SpscQueue<*mut Mbuf> {}
+unsafe impl Sync for SpscQueue<*mut Mbuf> {}
+unsafe impl Send for MpmcQueue<*mut Mbuf> {}
+unsafe impl Sync for MpmcQueue<*mut Mbuf> {}
+
 /// Queue manager for handling multiple queues
 pub struct QueueManager {
     /// SPSC queues
@@ -316,7 +481,17 @@ impl QueueManager {
         name: String,
         capacity: usize,
     ) -> Result<Arc<SpscQueue<*mut Mbuf>>> {
-        let queue = Arc::new(SpscQueue::new(capacity)?);
+        self.create_spsc_queue_on(name, capacity, QueuePlacement::Heap)
+    }
+
+    /// Create a new SPSC queue with its slots placed as requested
+    pub fn create_spsc_queue_on(
+        &mut self,
+        name: String,
+        capacity: usize,
+        placement: QueuePlacement,
+    ) -> Result<Arc<SpscQueue<*mut Mbuf>>> {
+        let queue = Arc::new(SpscQueue::with_placement(capacity, placement)?);
         self.spsc_queues.insert(name.clone(), queue.clone());
 
         self.stats.total_queues.fetch_add(1, Ordering::Relaxed);
@@ -331,7 +506,17 @@ impl QueueManager {
         name: String,
         capacity: usize,
     ) -> Result<Arc<MpmcQueue<*mut Mbuf>>> {
-        let queue = Arc::new(MpmcQueue::new(capacity)?);
+        self.create_mpmc_queue_on(name, capacity, QueuePlacement::Heap)
+    }
+
+    /// Create a new MPMC queue with its slots placed as requested
+    pub fn create_mpmc_queue_on(
+        &mut self,
+        name: String,
+        capacity: usize,
+        placement: QueuePlacement,
+    ) -> Result<Arc<MpmcQueue<*mut Mbuf>>> {
+        let queue = Arc::new(MpmcQueue::with_placement(capacity, placement)?);
         self.mpmc_queues.insert(name.clone(), queue.clone());
 
         self.stats.total_queues.fetch_add(1, Ordering::Relaxed);
@@ -372,21 +557,26 @@ impl QueueManager {
         let mut total_enqueued = 0;
         let mut total_dequeued = 0;
         let mut total_drops = 0;
+        let mut placements = Vec::new();
 
-        for queue in self.spsc_queues.values() {
+        for (name, queue) in &self.spsc_queues {
             let stats = queue.stats();
-            total_enqueued += stats.enqueued.load(Ordering::Relaxed);
-            total_dequeued += stats.dequeued.load(Ordering::Relaxed);
-            total_drops += stats.drops.load(Ordering::Relaxed);
+            placements.push((name.clone(), stats.placement));
+            total_enqueued += stats.enqueued.get();
+            total_dequeued += stats.dequeued.get();
+            total_drops += stats.drops.get();
         }
 
-        for queue in self.mpmc_queues.values() {
+        for (name, queue) in &self.mpmc_queues {
             let stats = queue.stats();
-            total_enqueued += stats.enqueued.load(Ordering::Relaxed);
-            total_dequeued += stats.dequeued.load(Ordering::Relaxed);
-            total_drops += stats.drops.load(Ordering::Relaxed);
+            placements.push((name.clone(), stats.placement));
+            total_enqueued += stats.enqueued.get();
+            total_dequeued += stats.dequeued.get();
+            total_drops += stats.drops.get();
         }
 
+        placements.sort_by(|a, b| a.0.cmp(&b.0));
+
         QueueManagerStatsView {
             total_queues: self.stats.total_queues.load(Ordering::Relaxed),
             spsc_queues: self.stats.spsc_queues.load(Ordering::Relaxed),
@@ -394,6 +584,7 @@ impl QueueManager {
             total_enqueued,
             total_dequeued,
             total_drops,
+            placements,
         }
     }
 }
@@ -404,9 +595,11 @@ pub struct QueueManagerStatsView {
     pub total_queues: usize,
     pub spsc_queues: usize,
     pub mpmc_queues: usize,
-    pub total_enqueued: usize,
-    pub total_dequeued: usize,
-    pub total_drops: usize,
+    pub total_enqueued: u64,
+    pub total_dequeued: u64,
+    pub total_drops: u64,
+    /// Slot placement of each queue, by name
+    pub placements: Vec<(String, QueuePlacement)>,
 }
 
 /// Worker thread for processing queues
@@ -414,6 +607,8 @@ pub struct QueueWorker {
     /// Worker ID
     #[allow(dead_code)]
     id: usize,
+    /// Name of the worker thread, in logs and telemetry
+    name: String,
     /// Queue to process
     queue: Arc<dyn RingBuffer<*mut Mbuf> + Send + Sync>,
     /// Processing function
@@ -424,6 +619,10 @@ pub struct QueueWorker {
     thread_handle: Option<JoinHandle<Result<()>>>,
     /// Worker statistics
     stats: Arc<WorkerStats>,
+    /// Cancelled when the worker stops
+    shutdown: ShutdownToken,
+    /// How the worker waits while the queue is empty
+    idle_strategy: IdleStrategy,
 }
 
 /// Worker statistics
@@ -443,38 +642,77 @@ impl QueueWorker {
     ) -> Self {
         Self {
             id,
+            name: format!("worker{}", id),
             queue,
             processor,
             running: Arc::new(AtomicBool::new(false)),
             thread_handle: None,
             stats: Arc::new(WorkerStats::default()),
+            shutdown: ShutdownToken::new(),
+            idle_strategy: IdleStrategy::default(),
         }
     }
 
+    /// Wait while the queue is empty according to `strategy`
+    pub fn with_idle_strategy(mut self, strategy: IdleStrategy) -> Self {
+        self.idle_strategy = strategy;
+        self
+    }
+
+    /// Name the worker and its thread, `worker<id>` by default
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Get the worker name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Stop the worker when `token` is cancelled
+    pub fn with_shutdown(mut self, token: ShutdownToken) -> Self {
+        self.shutdown = token;
+        self
+    }
+
+    /// Get the token cancelled when the worker stops
+    pub fn shutdown_token(&self) -> &ShutdownToken {
+        &self.shutdown
+    }
+
     /// Start the worker
     pub fn start(&mut self) -> Result<()> {
         if self.running.load(Ordering::Relaxed) {
             return Ok(());
         }
 
+        if self.shutdown.is_cancelled() {
+            self.shutdown = self.shutdown.renew();
+        }
         self.running.store(true, Ordering::Relaxed);
 
         let queue = self.queue.clone();
         // Note: We can't clone Fn closures, so we use Arc for sharing
         let processor = std::sync::Arc::clone(&self.processor);
         let running = self.running.clone();
+        let shutdown = self.shutdown.clone();
         let stats = Arc::new(std::mem::take(&mut self.stats));
+        let name = self.name.clone();
+        let mut backoff = Backoff::new(self.idle_strategy);
 
-        let thread_handle = thread::spawn(move || -> Result<()> {
+        let worker = move || -> Result<()> {
+            log::debug!("Worker {} started", name);
             let start_time = std::time::Instant::now();
             let batch_size = 32;
             let mut batch = Vec::with_capacity(batch_size);
 
-            while running.load(Ordering::Relaxed) {
+            while running.load(Ordering::Relaxed) && !shutdown.is_cancelled() {
                 // Try to pop a batch of items
                 batch.clear();
                 match queue.pop_batch(&mut batch) {
                     Ok(count) => {
+                        backoff.after_poll(count);
                         if count > 0 {
                             // Process each item
                             for &mbuf in &batch {
@@ -487,31 +725,49 @@ impl QueueWorker {
                                     }
                                 }
                             }
-                        } else {
-                            // No items available, sleep briefly
-                            thread::sleep(Duration::from_micros(10));
                         }
                     }
                     Err(_) => {
-                        // Queue empty or error, sleep briefly
-                        thread::sleep(Duration::from_micros(10));
+                        // Queue empty or error, wait as when empty
+                        backoff.idle();
                     }
                 }
             }
 
             let runtime = start_time.elapsed().as_millis() as usize;
             stats.runtime.store(runtime, Ordering::Relaxed);
+            running.store(false, Ordering::Relaxed);
+            log::debug!("Worker {} stopped after {} ms", name, runtime);
 
             Ok(())
-        });
+        };
 
+        let thread_handle = match thread::Builder::new().name(self.name.clone()).spawn(worker) {
+            Ok(handle) => handle,
+            Err(e) => {
+                self.running.store(false, Ordering::Relaxed);
+                return Err(e.into());
+            }
+        };
         self.thread_handle = Some(thread_handle);
         Ok(())
     }
 
+    /// Stop the worker, waiting at most `timeout` for its thread to exit
+    pub fn stop_within(&mut self, timeout: Duration) -> Result<()> {
+        self.running.store(false, Ordering::Relaxed);
+        self.shutdown.cancel();
+
+        match self.thread_handle.take() {
+            Some(handle) => join_with_deadline(handle, Instant::now() + timeout)?.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
     /// Stop the worker
     pub fn stop(&mut self) -> Result<()> {
         self.running.store(false, Ordering::Relaxed);
+        self.shutdown.cancel();
 
         if let Some(handle) = self.thread_handle.take() {
             match handle.join() {
@@ -530,6 +786,10 @@ impl QueueWorker {
         &self.stats
     }
 
+    pub(crate) fn shared_stats(&self) -> Arc<WorkerStats> {
+        self.stats.clone()
+    }
+
     /// Check if worker is running
     pub fn is_running(&self) -> bool {
         self.running.load(Ordering::Relaxed)
@@ -577,6 +837,31 @@ mod tests {
         assert!(queue.is_empty());
     }
 
+    #[test]
+    fn test_reserve_and_drain() {
+        let queue = MpmcQueue::<u32>::new(4).unwrap();
+
+        let mut reservation = queue.try_reserve(3).unwrap();
+        reservation.push(1).unwrap();
+        reservation.push(2).unwrap();
+        assert!(queue.try_reserve(2).is_err());
+        assert!(queue.is_empty());
+        reservation.commit();
+        assert_eq!(queue.stats().enqueued.get(), 2);
+
+        // An aborted reservation publishes nothing and frees its space
+        let mut aborted = queue.try_reserve(2).unwrap();
+        aborted.push(9).unwrap();
+        aborted.abort();
+        queue.push(3).unwrap();
+
+        let mut items = Vec::new();
+        assert_eq!(queue.drain(2, &mut items), 2);
+        assert_eq!(queue.drain(8, &mut items), 1);
+        assert_eq!(items, vec![1, 2, 3]);
+        assert_eq!(queue.stats().current_size.load(Ordering::Relaxed), 0);
+    }
+
     #[test]
     fn test_queue_manager() {
         let mut manager = QueueManager::new();
@@ -603,6 +888,31 @@ mod tests {
         assert_eq!(stats.mpmc_queues, 1);
     }
 
+    #[test]
+    fn test_queue_placement() {
+        let mut manager = QueueManager::new();
+        let heap = manager.create_spsc_queue("heap".to_string(), 64).unwrap();
+        let mapped = manager
+            .create_mpmc_queue_on("mapped".to_string(), 100, QueuePlacement::HugePages)
+            .unwrap();
+        assert_eq!(heap.stats().placement, QueuePlacement::Heap);
+        // Without free huge pages the slots fall back to regular pages
+        assert!(matches!(
+            mapped.stats().placement,
+            QueuePlacement::HugePages | QueuePlacement::RegularPages
+        ));
+
+        assert_eq!(mapped.capacity(), 128);
+        let mbufs: Vec<*mut Mbuf> = (1..=128).map(|i| i as *mut Mbuf).collect();
+        mapped.push_batch(&mbufs).unwrap();
+        assert!(mapped.is_full());
+        assert_eq!(mapped.pop().unwrap(), mbufs[0]);
+
+        let placements = manager.stats().placements;
+        assert_eq!(placements[0], ("heap".to_string(), QueuePlacement::Heap));
+        assert_eq!(placements[1].0, "mapped");
+    }
+
     #[test]
     fn test_batch_operations() {
         let queue = SpscQueue::<*mut Mbuf>::new(1024).unwrap();
@@ -618,4 +928,23 @@ mod tests {
         assert_eq!(count, 10);
         assert!(queue.is_empty());
     }
+
+    #[test]
+    fn test_worker_observes_shutdown_token() {
+        let queue: Arc<dyn RingBuffer<*mut Mbuf> + Send + Sync> =
+            Arc::new(MpmcQueue::<*mut Mbuf>::new(64).unwrap());
+        let root = ShutdownToken::new();
+        let mut worker =
+            QueueWorker::new(0, queue, Arc::new(|_| Ok(()))).with_shutdown(root.child());
+        assert_eq!(worker.name(), "worker0");
+        worker = worker.with_name("decoder");
+
+        worker.start().unwrap();
+        assert!(worker.is_running());
+
+        root.cancel();
+        assert!(worker.shutdown_token().is_cancelled());
+        worker.stop_within(Duration::from_secs(5)).unwrap();
+        assert!(!worker.is_running());
+    }
 }
@@ -0,0 +1,70 @@
+//! Queue storage placement
+//!
+//! Ring buffer slots normally come from the global heap, which may sit on
+//! another NUMA node than the threads using the queue and is backed by
+//! regular pages. A [`QueuePlacement`] asks for the slots to be mapped from
+//! huge pages or bound to a NUMA node instead; queues report the placement
+//! they actually got in their stats.
+
+use crate::memory::HugePageAllocator;
+use crate::utils::numa::NumaAllocator;
+use crate::Result;
+use lockfree_ringbuf::SlotMemory;
+use std::ffi::c_void;
+use std::mem::size_of;
+
+/// Where the slots of a queue live
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePlacement {
+    /// Global heap
+    #[default]
+    Heap,
+    /// Huge pages
+    HugePages,
+    /// Regular pages, reported when huge pages were requested but none were free
+    RegularPages,
+    /// Memory bound to a NUMA node
+    Numa(usize),
+}
+
+/// Map slot memory for `capacity` items as `placement` asks
+///
+/// Returns `None` for heap placement, where the ring buffer allocates its
+/// own slots, along with the placement obtained.
+pub(crate) fn slot_memory<T>(
+    capacity: usize,
+    placement: QueuePlacement,
+) -> Result<(Option<SlotMemory<T>>, QueuePlacement)> {
+    let capacity = capacity.next_power_of_two();
+    // Mappings are page aligned, which covers the alignment of any slot type
+    let size = (capacity * size_of::<T>()).max(1);
+
+    match placement {
+        QueuePlacement::Heap => Ok((None, QueuePlacement::Heap)),
+        QueuePlacement::HugePages | QueuePlacement::RegularPages => {
+            let allocator = HugePageAllocator::new()?;
+            let (ptr, huge) = allocator.allocate_backed(size)?;
+            let memory = unsafe {
+                SlotMemory::from_raw_parts(ptr as *mut T, capacity, move |ptr, _| {
+                    let _ = allocator.deallocate(ptr as *mut c_void, size);
+                })
+            };
+            let placement = if huge {
+                QueuePlacement::HugePages
+            } else {
+                QueuePlacement::RegularPages
+            };
+            Ok((Some(memory), placement))
+        }
+        QueuePlacement::Numa(node) => {
+            let allocator = NumaAllocator::new(node)?;
+            let ptr = allocator.allocate(size)?;
+            let memory = unsafe {
+                SlotMemory::from_raw_parts(ptr as *mut T, capacity, move |ptr, _| {
+                    let _ = allocator.deallocate(ptr as *mut c_void, size);
+                })
+            };
+            Ok((Some(memory), placement))
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Hold-and-release queue for timestamp-paced packets
+//!
+//! Mbufs are held until the release time derived from their
+//! `Mbuf::timestamp` is reached and are then handed, in release order, to a
+//! callback that feeds the dispatch or TX path. A [`ReleaseSchedule`] maps
+//! capture timestamps to release times, so a pcap can be replayed at its
+//! original pace, faster or slower, and tests can drive the clock by hand.
+
+use crate::memory::Mbuf;
+use crate::{Error, Result};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mapping from mbuf timestamps to release times, in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReleaseSchedule {
+    /// Added to the scaled timestamp
+    pub offset_ns: i64,
+    /// Timestamp multiplier; 0.5 releases twice as fast
+    pub scale: f64,
+}
+
+impl ReleaseSchedule {
+    /// Release each mbuf at its own timestamp
+    pub fn identity() -> Self {
+        Self {
+            offset_ns: 0,
+            scale: 1.0,
+        }
+    }
+
+    /// Replay a capture starting at `first_timestamp` from `start_ns` on
+    ///
+    /// `speed` 2.0 plays twice as fast as the capture was taken.
+    pub fn replay(first_timestamp: u64, start_ns: u64, speed: f64) -> Result<Self> {
+        if !(speed.is_finite() && speed > 0.0) {
+            return Err(Error::InvalidConfig(format!(
+                "Replay speed {} must be positive",
+                speed
+            )));
+        }
+
+        let scale = 1.0 / speed;
+        Ok(Self {
+            offset_ns: start_ns as i64 - (first_timestamp as f64 * scale) as i64,
+            scale,
+        })
+    }
+
+    /// Release time of a timestamp, clamped at 0
+    pub fn release_time(&self, timestamp: u64) -> u64 {
+        ((timestamp as f64 * self.scale) as i64)
+            .saturating_add(self.offset_ns)
+            .max(0) as u64
+    }
+}
+
+impl Default for ReleaseSchedule {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+/// Release queue counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReleaseStats {
+    pub held: usize,
+    pub released: usize,
+    /// Holds refused because the queue was full
+    pub rejected: usize,
+    /// Release callbacks that failed
+    pub errors: usize,
+}
+
+/// Held mbuf ordered by release time, then arrival
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Held {
+    release_ns: u64,
+    seq: u64,
+    mbuf: *mut Mbuf,
+}
+
+/// Queue holding mbufs until their scheduled release time
+///
+/// The queue does not own a pool: mbufs still held when it is dropped must
+/// be taken with [`ReleaseQueue::drain`] and freed by the caller.
+pub struct ReleaseQueue {
+    schedule: ReleaseSchedule,
+    capacity: usize,
+    heap: BinaryHeap<Reverse<Held>>,
+    next_seq: u64,
+    stats: ReleaseStats,
+}
+
+impl ReleaseQueue {
+    /// Create a queue holding up to `capacity` mbufs
+    pub fn new(schedule: ReleaseSchedule, capacity: usize) -> Self {
+        Self {
+            schedule,
+            capacity,
+            heap: BinaryHeap::with_capacity(capacity),
+            next_seq: 0,
+            stats: ReleaseStats::default(),
+        }
+    }
+
+    /// Hold an mbuf until the release time of its timestamp
+    ///
+    /// The queue owns the mbuf until it is released or drained. When full,
+    /// fails with [`Error::QueueError`] and hands the mbuf back.
+    pub fn hold(&mut self, mbuf: *mut Mbuf) -> std::result::Result<(), (Error, *mut Mbuf)> {
+        if self.heap.len() >= self.capacity {
+            self.stats.rejected += 1;
+            return Err((
+                Error::QueueError(format!("Release queue full ({} mbufs)", self.capacity)),
+                mbuf,
+            ));
+        }
+
+        self.heap.push(Reverse(Held {
+            release_ns: self.release_time_of(mbuf),
+            seq: self.next_seq,
+            mbuf,
+        }));
+        self.next_seq += 1;
+        self.stats.held += 1;
+        Ok(())
+    }
+
+    /// Release time of a held mbuf's timestamp
+    fn release_time_of(&self, mbuf: *mut Mbuf) -> u64 {
+        // SAFETY: `hold` is passed a valid mbuf whose ownership it takes
+        self.schedule.release_time(unsafe { (*mbuf).timestamp })
+    }
+
+    /// Release every mbuf due at `now_ns` to `release`, in release order
+    ///
+    /// An mbuf is passed to `release` exactly once, which then owns it even
+    /// if it returns an error. Returns how many were released.
+    pub fn release_due<F>(&mut self, now_ns: u64, mut release: F) -> usize
+    where
+        F: FnMut(*mut Mbuf) -> Result<()>,
+    {
+        let mut released = 0;
+        while let Some(held) = self.pop_due(now_ns) {
+            if release(held).is_err() {
+                self.stats.errors += 1;
+            }
+            released += 1;
+        }
+        released
+    }
+
+    /// Release every mbuf due by the wall clock, the clock of capture timestamps
+    pub fn release_ready<F>(&mut self, release: F) -> usize
+    where
+        F: FnMut(*mut Mbuf) -> Result<()>,
+    {
+        let now_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0);
+        self.release_due(now_ns, release)
+    }
+
+    /// Take the next mbuf if it is due at `now_ns`
+    pub fn pop_due(&mut self, now_ns: u64) -> Option<*mut Mbuf> {
+        if self.heap.peek()?.0.release_ns > now_ns {
+            return None;
+        }
+        self.stats.released += 1;
+        self.heap.pop().map(|held| held.0.mbuf)
+    }
+
+    /// Release time of the next held mbuf
+    pub fn next_release_ns(&self) -> Option<u64> {
+        self.heap.peek().map(|held| held.0.release_ns)
+    }
+
+    /// Take every held mbuf regardless of its release time, in release order
+    pub fn drain(&mut self) -> Vec<*mut Mbuf> {
+        let mut mbufs = Vec::with_capacity(self.heap.len());
+        while let Some(held) = self.heap.pop() {
+            mbufs.push(held.0.mbuf);
+        }
+        mbufs
+    }
+
+    /// Number of held mbufs
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Whether no mbufs are held
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Get the schedule
+    pub fn schedule(&self) -> &ReleaseSchedule {
+        &self.schedule
+    }
+
+    /// Get queue counters
+    pub fn stats(&self) -> ReleaseStats {
+        self.stats
+    }
+}
+
+// Held mbufs are owned by the queue until released.
+unsafe impl Send for ReleaseQueue {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mbufs(timestamps: &[u64]) -> Vec<Mbuf> {
+        timestamps
+            .iter()
+            .map(|&timestamp| {
+                let mut mbuf = Mbuf::new(std::ptr::null_mut(), 0);
+                mbuf.timestamp = timestamp;
+                mbuf
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_releases_in_timestamp_order() {
+        let mut held = mbufs(&[300, 100, 200, 100]);
+        let ptrs: Vec<*mut Mbuf> = held.iter_mut().map(|m| m as *mut Mbuf).collect();
+        let mut queue = ReleaseQueue::new(ReleaseSchedule::identity(), 4);
+        for &mbuf in &ptrs {
+            queue.hold(mbuf).unwrap();
+        }
+        let (err, returned) = queue.hold(ptrs[0]).unwrap_err();
+        assert!(matches!(err, Error::QueueError(_)));
+        assert_eq!(returned, ptrs[0]);
+
+        let mut out = Vec::new();
+        let mut collect = |mbuf| {
+            out.push(mbuf);
+            Ok(())
+        };
+        assert_eq!(queue.release_due(99, &mut collect), 0);
+        assert_eq!(queue.release_due(200, &mut collect), 3);
+        // Equal timestamps keep their arrival order
+        assert_eq!(out, vec![ptrs[1], ptrs[3], ptrs[2]]);
+        assert_eq!(queue.next_release_ns(), Some(300));
+
+        assert_eq!(queue.drain(), vec![ptrs[0]]);
+        let stats = queue.stats();
+        assert_eq!((stats.held, stats.released, stats.rejected), (4, 3, 1));
+    }
+
+    #[test]
+    fn test_replay_schedule_scales_gaps() {
+        let schedule = ReleaseSchedule::replay(1_000_000, 50, 2.0).unwrap();
+        assert_eq!(schedule.release_time(1_000_000), 50);
+        assert_eq!(schedule.release_time(1_000_400), 250);
+        assert!(ReleaseSchedule::replay(0, 0, 0.0).is_err());
+
+        let mut held = mbufs(&[1_000_000, 1_000_400]);
+        let mut queue = ReleaseQueue::new(schedule, 8);
+        for mbuf in &mut held {
+            queue.hold(mbuf as *mut Mbuf).unwrap();
+        }
+        assert_eq!(queue.release_due(249, |_| Ok(())), 1);
+        assert_eq!(
+            queue.release_due(250, |_| Err(Error::QueueError("tx".to_string()))),
+            1
+        );
+        assert_eq!(queue.stats().errors, 1);
+        assert!(queue.is_empty());
+    }
+}
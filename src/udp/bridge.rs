@@ -0,0 +1,507 @@
+//! Transparent L2 bridging between ports
+//!
+//! A [`Bridge`] joins the ports of several poll mode drivers into one
+//! Ethernet segment, as a learning switch: the source MAC address of every
+//! frame is learnt against the port it came in on, frames to a learnt
+//! address go out on its port, and frames to unknown, broadcast and
+//! multicast addresses are flooded to every other port. Frames whose
+//! destination sits on the port they came in on are filtered. Frames are
+//! passed on unmodified, whatever their EtherType.
+//!
+//! Addresses not seen for [`BridgeConfig::aging`] are forgotten. A full
+//! table makes room by forgetting its stalest address, so a flood of
+//! spoofed sources degrades the bridge into a hub rather than stopping it
+//! learning. The table keeps its addresses in the order they were last
+//! seen, so neither takes a scan of the table. Each port counts what it received, sent and filtered in its
+//! [`BridgePortStats`]. With [`Bridge::with_budget`], learnt addresses
+//! are charged to the memory budget as the `bridge` table, and addresses
+//! the budget cannot hold are not learnt.
+//!
+//! libpcap copies a frame on send, so a flooded frame goes out on every
+//! port from the one received mbuf.
+
+use super::DropReason;
+use crate::memory::{Mbuf, MbufPool, MemoryBudget, TableAccount};
+use crate::poll::{PollModeDriver, TxQueue};
+use crate::{Error, Result};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Default number of addresses a bridge learns
+pub const DEFAULT_BRIDGE_TABLE_SIZE: usize = 4096;
+
+/// Default time after which an address not seen is forgotten
+pub const DEFAULT_BRIDGE_AGING: Duration = Duration::from_secs(300);
+
+/// Budgeted bytes of one learnt address
+pub const BRIDGE_ENTRY_BYTES: usize =
+    std::mem::size_of::<[u8; 6]>() + std::mem::size_of::<(u16, Instant)>();
+
+/// Bridge settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeConfig {
+    /// Addresses the table holds at most
+    pub max_entries: usize,
+    /// Time after which an address not seen is forgotten
+    pub aging: Duration,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_BRIDGE_TABLE_SIZE,
+            aging: DEFAULT_BRIDGE_AGING,
+        }
+    }
+}
+
+/// What the bridge did with a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeVerdict {
+    /// Sent out on the port of its learnt destination
+    Forwarded(u16),
+    /// Sent out on this many ports
+    Flooded(usize),
+    /// Destination on the ingress port, not sent
+    Filtered,
+    /// Not sent anywhere
+    Dropped(DropReason),
+}
+
+/// Counters of one bridge port
+#[derive(Debug, Default)]
+pub struct BridgePortStats {
+    pub rx: AtomicUsize,
+    /// Frames sent to a learnt address on the port
+    pub forwarded: AtomicUsize,
+    /// Frames flooded out of the port
+    pub flooded: AtomicUsize,
+    /// Frames received for an address on the same port
+    pub filtered: AtomicUsize,
+    pub tx_errors: AtomicUsize,
+}
+
+/// Counters of the address table
+#[derive(Debug, Default)]
+pub struct BridgeStats {
+    pub learned: AtomicUsize,
+    /// Addresses seen again on another port
+    pub moved: AtomicUsize,
+    pub aged: AtomicUsize,
+    /// Addresses forgotten to make room
+    pub evicted: AtomicUsize,
+    /// Frames from ports not added to the bridge
+    pub unknown_port: AtomicUsize,
+    /// Addresses not learnt for lack of budget
+    pub budget_exceeded: AtomicUsize,
+}
+
+/// Learnt location of an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeEntry {
+    pub mac: [u8; 6],
+    pub port: u16,
+    pub last_seen: Instant,
+}
+
+/// Decision for one frame, before any I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Forward(u16),
+    Flood,
+    Filter,
+}
+
+/// Learnt addresses, with the order they were last seen in
+#[derive(Default)]
+struct AddressTable {
+    entries: HashMap<[u8; 6], (u16, Instant)>,
+    /// Addresses by last seen, stalest first; an item is out of date once
+    /// its address was seen again or forgotten, and skipped
+    order: VecDeque<([u8; 6], Instant)>,
+}
+
+impl AddressTable {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn get(&self, mac: &[u8; 6]) -> Option<&(u16, Instant)> {
+        self.entries.get(mac)
+    }
+
+    /// Record `mac` on `port` as seen at `now`, returning its previous port
+    fn touch(&mut self, mac: [u8; 6], port: u16, now: Instant) -> Option<u16> {
+        let previous = self.entries.insert(mac, (port, now));
+        if previous.is_none_or(|(_, seen)| seen != now) {
+            self.order.push_back((mac, now));
+            self.compact();
+        }
+        previous.map(|(port, _)| port)
+    }
+
+    fn is_current(&self, &(mac, seen): &([u8; 6], Instant)) -> bool {
+        self.entries.get(&mac).is_some_and(|&(_, at)| at == seen)
+    }
+
+    /// Forget the stalest address, if `stale` holds for when it was last seen
+    fn pop_stalest(&mut self, stale: impl Fn(Instant) -> bool) -> Option<[u8; 6]> {
+        while let Some(&item) = self.order.front() {
+            if !self.is_current(&item) {
+                self.order.pop_front();
+                continue;
+            }
+            if !stale(item.1) {
+                return None;
+            }
+            self.order.pop_front();
+            self.entries.remove(&item.0);
+            return Some(item.0);
+        }
+        None
+    }
+
+    /// Forget the addresses learnt on `port`, returning how many
+    fn remove_port(&mut self, port: u16) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, (at, _)| *at != port);
+        self.compact();
+        before - self.entries.len()
+    }
+
+    /// Drop out of date items once they outnumber the addresses
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.entries.len() + 16 {
+            let order = std::mem::take(&mut self.order);
+            self.order = order
+                .into_iter()
+                .filter(|item| self.is_current(item))
+                .collect();
+        }
+    }
+}
+
+struct BridgePort {
+    tx_queue: Arc<TxQueue>,
+    stats: Arc<BridgePortStats>,
+}
+
+/// MAC learning switch between ports
+pub struct Bridge {
+    config: BridgeConfig,
+    table: Mutex<AddressTable>,
+    /// Ports by port ID
+    ports: RwLock<BTreeMap<u16, BridgePort>>,
+    account: Option<Arc<TableAccount>>,
+    stats: BridgeStats,
+}
+
+impl Bridge {
+    /// Create a bridge without ports, failing on an empty table
+    pub fn new(config: &BridgeConfig) -> Result<Self> {
+        if config.max_entries == 0 {
+            return Err(Error::InvalidConfig(
+                "Bridge table must hold an address".to_string(),
+            ));
+        }
+        Ok(Self {
+            config: *config,
+            table: Mutex::default(),
+            ports: RwLock::new(BTreeMap::new()),
+            account: None,
+            stats: BridgeStats::default(),
+        })
+    }
+
+    /// Charge each learnt address [`BRIDGE_ENTRY_BYTES`] of `budget`, as the `bridge` table
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.account = Some(budget.register_table("bridge"));
+        self
+    }
+
+    /// Bridge the port of `tx_queue`, sending the frames for it through the queue
+    pub fn add_port(&self, tx_queue: Arc<TxQueue>) {
+        self.ports.write().insert(
+            tx_queue.port_id(),
+            BridgePort {
+                tx_queue,
+                stats: Arc::default(),
+            },
+        );
+    }
+
+    /// Bridge the port of `pmd` through its first TX queue
+    pub fn add_driver(&self, pmd: &PollModeDriver) -> Result<()> {
+        let tx_queue = pmd.tx_queues().next().ok_or_else(|| {
+            Error::InvalidConfig(format!("Port {} has no TX queue", pmd.port_id()))
+        })?;
+        self.add_port(tx_queue.clone());
+        Ok(())
+    }
+
+    /// Remove a port and forget the addresses learnt on it
+    pub fn remove_port(&self, port: u16) -> bool {
+        let removed = self.table.lock().remove_port(port);
+        if let Some(account) = &self.account {
+            account.shrink(removed, removed * BRIDGE_ENTRY_BYTES);
+        }
+        self.ports.write().remove(&port).is_some()
+    }
+
+    /// Bridge a frame received on port `mbuf.port_id` and free it to `pool`
+    pub fn process(&self, mbuf: &mut Mbuf, pool: &MbufPool) -> Result<BridgeVerdict> {
+        let verdict = self.process_at(mbuf, Instant::now());
+        pool.free(mbuf)?;
+        Ok(verdict)
+    }
+
+    fn process_at(&self, mbuf: &mut Mbuf, now: Instant) -> BridgeVerdict {
+        let frame = mbuf.data();
+        let ingress = mbuf.port_id;
+        if frame.len() < 14 {
+            return BridgeVerdict::Dropped(DropReason::Malformed);
+        }
+        let ports = self.ports.read();
+        let Some(rx_port) = ports.get(&ingress) else {
+            self.stats.unknown_port.fetch_add(1, Ordering::Relaxed);
+            return BridgeVerdict::Dropped(DropReason::Unroutable);
+        };
+        rx_port.stats.rx.fetch_add(1, Ordering::Relaxed);
+
+        match self.step(frame, ingress, now) {
+            Step::Filter => {
+                rx_port.stats.filtered.fetch_add(1, Ordering::Relaxed);
+                BridgeVerdict::Filtered
+            }
+            Step::Forward(port) => match ports.get(&port) {
+                Some(egress) if self.send(egress, mbuf) => {
+                    egress.stats.forwarded.fetch_add(1, Ordering::Relaxed);
+                    BridgeVerdict::Forwarded(port)
+                }
+                _ => BridgeVerdict::Dropped(DropReason::Unroutable),
+            },
+            Step::Flood => {
+                let mut flooded = 0;
+                for egress in ports
+                    .iter()
+                    .filter(|(&port, _)| port != ingress)
+                    .map(|(_, egress)| egress)
+                {
+                    if self.send(egress, mbuf) {
+                        egress.stats.flooded.fetch_add(1, Ordering::Relaxed);
+                        flooded += 1;
+                    }
+                }
+                BridgeVerdict::Flooded(flooded)
+            }
+        }
+    }
+
+    fn send(&self, egress: &BridgePort, mbuf: &mut Mbuf) -> bool {
+        let sent = egress.tx_queue.send(mbuf).is_ok();
+        if !sent {
+            egress.stats.tx_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Learn the source of `frame` on `ingress` and decide where the frame goes
+    fn step(&self, frame: &[u8], ingress: u16, now: Instant) -> Step {
+        let dst: [u8; 6] = frame[0..6].try_into().unwrap();
+        let src: [u8; 6] = frame[6..12].try_into().unwrap();
+        let mut table = self.table.lock();
+        // Group addresses are never sources
+        if src[0] & 1 == 0 {
+            self.learn(&mut table, src, ingress, now);
+        }
+        if dst[0] & 1 != 0 {
+            return Step::Flood;
+        }
+        match table.get(&dst) {
+            Some(&(port, seen)) if now.duration_since(seen) < self.config.aging => {
+                if port == ingress {
+                    Step::Filter
+                } else {
+                    Step::Forward(port)
+                }
+            }
+            _ => Step::Flood,
+        }
+    }
+
+    fn learn(&self, table: &mut AddressTable, mac: [u8; 6], port: u16, now: Instant) {
+        if table.get(&mac).is_some() {
+            if table.touch(mac, port, now) != Some(port) {
+                self.stats.moved.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+        if table.len() >= self.config.max_entries {
+            if table.pop_stalest(|_| true).is_some() {
+                self.stats.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        } else if let Some(account) = &self.account {
+            if account.grow(1, BRIDGE_ENTRY_BYTES).is_err() {
+                self.stats.budget_exceeded.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        table.touch(mac, port, now);
+        self.stats.learned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forget addresses not seen for the aging time, returning how many
+    pub fn age(&self) -> usize {
+        self.age_at(Instant::now())
+    }
+
+    fn age_at(&self, now: Instant) -> usize {
+        let mut table = self.table.lock();
+        let mut aged = 0;
+        while table
+            .pop_stalest(|seen| now.duration_since(seen) >= self.config.aging)
+            .is_some()
+        {
+            aged += 1;
+        }
+        if let Some(account) = &self.account {
+            account.shrink(aged, aged * BRIDGE_ENTRY_BYTES);
+        }
+        self.stats.aged.fetch_add(aged, Ordering::Relaxed);
+        aged
+    }
+
+    /// Receive up to `budget` frames from the RX queues of each driver and bridge them
+    ///
+    /// Also forgets aged addresses. Returns the number of frames bridged.
+    pub fn poll(&self, pmds: &[&PollModeDriver], budget: usize) -> Result<usize> {
+        self.age();
+        let mut bridged = 0;
+        for pmd in pmds {
+            for rx_queue in pmd.rx_queues() {
+                let Ok(mut poller) = rx_queue.poller() else {
+                    continue;
+                };
+                for _ in 0..budget {
+                    match poller.recv() {
+                        Ok(mbuf) => {
+                            self.process(unsafe { &mut *mbuf }, rx_queue.get_pool())?;
+                            bridged += 1;
+                        }
+                        Err(Error::NetworkError(_)) => break, // No more packets
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        Ok(bridged)
+    }
+
+    /// Learnt addresses, in no particular order
+    pub fn entries(&self) -> Vec<BridgeEntry> {
+        self.table
+            .lock()
+            .entries
+            .iter()
+            .map(|(&mac, &(port, last_seen))| BridgeEntry {
+                mac,
+                port,
+                last_seen,
+            })
+            .collect()
+    }
+
+    /// Port `mac` was learnt on
+    pub fn lookup(&self, mac: &[u8; 6]) -> Option<u16> {
+        self.table.lock().get(mac).map(|&(port, _)| port)
+    }
+
+    /// Get the counters of a port
+    pub fn port_stats(&self, port: u16) -> Option<Arc<BridgePortStats>> {
+        self.ports.read().get(&port).map(|p| p.stats.clone())
+    }
+
+    /// Get the address table counters
+    pub fn stats(&self) -> &BridgeStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::EthernetBuilder;
+
+    fn frame(dst: u8, src: u8) -> Vec<u8> {
+        EthernetBuilder::default()
+            .dst([2, 0, 0, 0, 0, dst])
+            .src([2, 0, 0, 0, 0, src])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_learning_flooding_and_aging() {
+        let budget = Arc::new(MemoryBudget::new(None));
+        let bridge = Bridge::new(&BridgeConfig {
+            max_entries: 3,
+            aging: Duration::from_secs(10),
+        })
+        .unwrap()
+        .with_budget(budget.clone());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Unknown destination floods, and the source is learnt
+        assert_eq!(bridge.step(&frame(2, 1), 0, at(0)), Step::Flood);
+        assert_eq!(bridge.lookup(&[2, 0, 0, 0, 0, 1]), Some(0));
+        assert_eq!(bridge.step(&frame(1, 2), 1, at(1)), Step::Forward(0));
+        assert_eq!(bridge.step(&frame(2, 1), 0, at(2)), Step::Forward(1));
+        assert_eq!(bridge.step(&frame(2, 3), 1, at(3)), Step::Filter);
+
+        let mut broadcast = frame(0, 3);
+        broadcast[0..6].copy_from_slice(&[0xff; 6]);
+        assert_eq!(bridge.step(&broadcast, 1, at(3)), Step::Flood);
+
+        // Station 1 moves to port 2, then a fourth station pushes the
+        // stalest out of the full table
+        bridge.step(&frame(9, 1), 2, at(5));
+        assert_eq!(bridge.lookup(&[2, 0, 0, 0, 0, 1]), Some(2));
+        assert_eq!(bridge.stats().moved.load(Ordering::Relaxed), 1);
+        bridge.step(&frame(9, 4), 2, at(6));
+        assert_eq!(bridge.lookup(&[2, 0, 0, 0, 0, 2]), None);
+        assert_eq!(bridge.stats().evicted.load(Ordering::Relaxed), 1);
+        assert_eq!(bridge.entries().len(), 3);
+
+        assert_eq!(bridge.age_at(at(14)), 1);
+        assert_eq!(bridge.entries().len(), 2);
+        assert_eq!(budget.tables()[0].entries, 2);
+        assert_eq!(budget.usage().tables, 2 * BRIDGE_ENTRY_BYTES);
+        // Stale entries are not used even before aging removes them
+        assert_eq!(bridge.step(&frame(1, 5), 0, at(16)), Step::Flood);
+        // Removing a port forgets and uncharges its addresses
+        bridge.remove_port(2);
+        assert_eq!(bridge.entries().len(), 1);
+        assert_eq!(budget.tables()[0].entries, 1);
+
+        let mbuf_frame = frame(1, 2);
+        let pool = MbufPool::new("bridge".to_string(), 2, 256).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        let mbuf = unsafe { &mut *mbuf };
+        mbuf.append(&mbuf_frame).unwrap();
+        // Frames from ports not bridged are dropped
+        assert_eq!(
+            bridge.process(mbuf, &pool).unwrap(),
+            BridgeVerdict::Dropped(DropReason::Unroutable)
+        );
+        assert_eq!(bridge.stats().unknown_port.load(Ordering::Relaxed), 1);
+        assert!(Bridge::new(&BridgeConfig {
+            max_entries: 0,
+            ..BridgeConfig::default()
+        })
+        .is_err());
+    }
+}
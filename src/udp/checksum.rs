@@ -0,0 +1,383 @@
+//! Receive checksum validation policy
+//!
+//! Each interface is given a trust level. Verdicts already present in the
+//! mbuf offload flags (set by hardware) are honored unless the interface is
+//! configured to always re-verify, and interfaces fed by a trusted upstream
+//! skip verification entirely, which is also the default so that frames are
+//! only verified where it has been asked for. Socket delivery drops frames
+//! marked bad. On send, [`fill_tx_checksums`] computes the checksums a frame
+//! requests through its TX offload flags for interfaces without offload.
+//! Paths that rewrite a few header fields, like relaying and forwarding,
+//! patch the existing checksums with [`checksum_update_u16`] and
+//! [`checksum_update_u32`] (RFC 1624) instead of recomputing them.
+
+use crate::memory::{ChecksumStatus, Mbuf, OffloadFlags, PacketType};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::IPPROTO_UDP;
+
+/// How far checksums from an interface are trusted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumTrust {
+    /// Always verify in software, ignoring hardware verdicts
+    Verify,
+    /// Use hardware verdicts, verify in software when there are none
+    TrustHardware,
+    /// Treat every checksum as good without looking
+    #[default]
+    TrustUpstream,
+}
+
+/// Checksum trust per interface
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumPolicy {
+    /// Trust for interfaces without an explicit entry
+    pub default: ChecksumTrust,
+    /// Trust by interface name
+    pub interfaces: HashMap<String, ChecksumTrust>,
+}
+
+impl ChecksumPolicy {
+    /// Policy applying `trust` to every interface
+    pub fn new(default: ChecksumTrust) -> Self {
+        Self {
+            default,
+            interfaces: HashMap::new(),
+        }
+    }
+
+    /// Override the trust of one interface
+    pub fn with_interface(mut self, interface: &str, trust: ChecksumTrust) -> Self {
+        self.interfaces.insert(interface.to_string(), trust);
+        self
+    }
+
+    /// Trust applied to an interface
+    pub fn trust_for(&self, interface: &str) -> ChecksumTrust {
+        self.interfaces
+            .get(interface)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Who produced a checksum verdict
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumSource {
+    Hardware,
+    Software,
+    Trusted,
+}
+
+/// Verdict counters by source
+#[derive(Debug, Default)]
+pub struct ChecksumStats {
+    pub hardware_good: AtomicUsize,
+    pub hardware_bad: AtomicUsize,
+    pub software_good: AtomicUsize,
+    pub software_bad: AtomicUsize,
+    pub trusted: AtomicUsize,
+}
+
+impl ChecksumStats {
+    fn count(&self, source: ChecksumSource, good: bool) {
+        let counter = match (source, good) {
+            (ChecksumSource::Hardware, true) => &self.hardware_good,
+            (ChecksumSource::Hardware, false) => &self.hardware_bad,
+            (ChecksumSource::Software, true) => &self.software_good,
+            (ChecksumSource::Software, false) => &self.software_bad,
+            (ChecksumSource::Trusted, _) => &self.trusted,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// RX checksum validation stage
+#[derive(Debug, Default)]
+pub struct ChecksumValidator {
+    policy: ChecksumPolicy,
+    stats: ChecksumStats,
+}
+
+impl ChecksumValidator {
+    /// Create a validator for a policy
+    pub fn new(policy: ChecksumPolicy) -> Self {
+        Self {
+            policy,
+            stats: ChecksumStats::default(),
+        }
+    }
+
+    /// Get the policy
+    pub fn policy(&self) -> &ChecksumPolicy {
+        &self.policy
+    }
+
+    /// Validate a classified IPv4/UDP frame, recording verdicts in its flags
+    ///
+    /// Returns whether both checksums are good. Other frames are left alone
+    /// and reported good.
+    pub fn validate(&self, mbuf: &mut Mbuf, trust: ChecksumTrust) -> bool {
+        if mbuf.packet_type != PacketType::Udp {
+            return true;
+        }
+
+        let (l3, l4, source) = match trust {
+            ChecksumTrust::TrustUpstream => (
+                ChecksumStatus::Good,
+                ChecksumStatus::Good,
+                ChecksumSource::Trusted,
+            ),
+            ChecksumTrust::TrustHardware
+                if mbuf.l3_checksum() != ChecksumStatus::Unknown
+                    && mbuf.l4_checksum() != ChecksumStatus::Unknown =>
+            {
+                (
+                    mbuf.l3_checksum(),
+                    mbuf.l4_checksum(),
+                    ChecksumSource::Hardware,
+                )
+            }
+            _ => {
+                let (l3, l4) = verify(mbuf);
+                (l3, l4, ChecksumSource::Software)
+            }
+        };
+
+        mbuf.set_checksum_status(l3, l4);
+        let good = l3.is_acceptable() && l4.is_acceptable();
+        self.stats.count(source, good);
+        good
+    }
+
+    /// Get verdict counters
+    pub fn stats(&self) -> &ChecksumStats {
+        &self.stats
+    }
+}
+
+/// Verify the IPv4 and UDP checksums of a classified frame in software
+fn verify(mbuf: &Mbuf) -> (ChecksumStatus, ChecksumStatus) {
+    let frame = mbuf.data();
+    let l3 = mbuf.l3_offset as usize;
+    let l4 = mbuf.l4_offset as usize;
+
+    let l3_status = status(ones_complement(0, &frame[l3..l4]) == 0xFFFF);
+
+    let udp_len = u16::from_be_bytes([frame[l4 + 4], frame[l4 + 5]]) as usize;
+    if mbuf.offload_flags.contains(OffloadFlags::RX_TRUNCATED) {
+        // Part of the datagram was not captured, so there is nothing to check
+        return (l3_status, ChecksumStatus::None);
+    }
+    if udp_len < 8 || l4 + udp_len > frame.len() {
+        return (l3_status, ChecksumStatus::Bad);
+    }
+    if frame[l4 + 6..l4 + 8] == [0, 0] {
+        // Checksum not computed by the sender
+        return (l3_status, ChecksumStatus::None);
+    }
+
+    let mut pseudo = [0u8; 12];
+    pseudo[..8].copy_from_slice(&frame[l3 + 12..l3 + 20]);
+    pseudo[9] = IPPROTO_UDP;
+    pseudo[10..].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    let sum = ones_complement(ones_complement(0, &pseudo), &frame[l4..l4 + udp_len]);
+
+    (l3_status, status(sum == 0xFFFF))
+}
+
+/// Compute the IPv4 and UDP checksums requested with `TX_IP_CKSUM` and `TX_UDP_CKSUM`
+///
+/// The requests are cleared; frames that are not IPv4 are sent unchanged.
+pub(crate) fn fill_tx_checksums(mbuf: &mut Mbuf) {
+    let requested = mbuf.offload_flags & (OffloadFlags::TX_IP_CKSUM | OffloadFlags::TX_UDP_CKSUM);
+    if requested.is_empty() {
+        return;
+    }
+    mbuf.offload_flags.remove(requested);
+    if mbuf.packet_type == PacketType::Unknown {
+        super::classify(mbuf);
+    }
+    let udp = mbuf.packet_type == PacketType::Udp;
+    let l3 = mbuf.l3_offset as usize;
+    let l4 = mbuf.l4_offset as usize;
+    let frame = mbuf.data_mut();
+    if l3 == 0 || l4 <= l3 || frame[l3] >> 4 != 4 {
+        return;
+    }
+
+    if requested.contains(OffloadFlags::TX_IP_CKSUM) {
+        frame[l3 + 10..l3 + 12].fill(0);
+        let sum = !ones_complement(0, &frame[l3..l4]);
+        frame[l3 + 10..l3 + 12].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    if requested.contains(OffloadFlags::TX_UDP_CKSUM) && udp {
+        let udp_len = u16::from_be_bytes([frame[l4 + 4], frame[l4 + 5]]) as usize;
+        if udp_len < 8 || l4 + udp_len > frame.len() {
+            return;
+        }
+        frame[l4 + 6..l4 + 8].fill(0);
+        let mut pseudo = [0u8; 12];
+        pseudo[..8].copy_from_slice(&frame[l3 + 12..l3 + 20]);
+        pseudo[9] = IPPROTO_UDP;
+        pseudo[10..].copy_from_slice(&(udp_len as u16).to_be_bytes());
+        let sum = !ones_complement(ones_complement(0, &pseudo), &frame[l4..l4 + udp_len]);
+        // A computed 0 is sent as all ones, 0 means no checksum
+        let sum = if sum == 0 { 0xFFFF } else { sum };
+        frame[l4 + 6..l4 + 8].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+fn status(good: bool) -> ChecksumStatus {
+    if good {
+        ChecksumStatus::Good
+    } else {
+        ChecksumStatus::Bad
+    }
+}
+
+/// Fold `data` into a ones' complement sum
+pub(crate) fn ones_complement(initial: u16, data: &[u8]) -> u16 {
+    let mut sum = initial as u32;
+    for chunk in data.chunks(2) {
+        sum += u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Update a checksum for a 16-bit field changed from `old` to `new` (RFC 1624)
+pub fn checksum_update_u16(checksum: u16, old: u16, new: u16) -> u16 {
+    // HC' = ~(~HC + ~m + m'), eqn. 3
+    let mut sum = !checksum as u32 + !old as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Update a checksum for a 32-bit field at an even offset changed from `old` to `new`
+pub fn checksum_update_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let checksum = checksum_update_u16(checksum, (old >> 16) as u16, (new >> 16) as u16);
+    checksum_update_u16(checksum, old as u16, new as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::classify;
+    use crate::udp::testing::{FrameBuilder, Malformation};
+    use crate::utils::rand::Rng;
+
+    fn frame(corrupt: bool) -> Vec<u8> {
+        let builder = FrameBuilder::to_port(5000).payload(b"ping");
+        if corrupt {
+            builder.malformed(Malformation::BadUdpChecksum)
+        } else {
+            builder.build()
+        }
+    }
+
+    fn mbuf(frame: &mut [u8]) -> Mbuf {
+        let mut mbuf = Mbuf::new(frame.as_mut_ptr(), frame.len());
+        mbuf.len = frame.len();
+        classify(&mut mbuf);
+        mbuf
+    }
+
+    #[test]
+    fn test_software_verification() {
+        let validator = ChecksumValidator::default();
+
+        let mut good = frame(false);
+        let mut good_mbuf = mbuf(&mut good);
+        assert!(validator.validate(&mut good_mbuf, ChecksumTrust::Verify));
+        assert_eq!(good_mbuf.l4_checksum(), ChecksumStatus::Good);
+
+        let mut bad = frame(true);
+        let mut bad_mbuf = mbuf(&mut bad);
+        assert!(!validator.validate(&mut bad_mbuf, ChecksumTrust::TrustHardware));
+        assert_eq!(bad_mbuf.l3_checksum(), ChecksumStatus::Good);
+        assert_eq!(bad_mbuf.l4_checksum(), ChecksumStatus::Bad);
+
+        assert_eq!(validator.stats().software_good.load(Ordering::Relaxed), 1);
+        assert_eq!(validator.stats().software_bad.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_trust_levels() {
+        let policy = ChecksumPolicy::new(ChecksumTrust::TrustHardware)
+            .with_interface("veth0", ChecksumTrust::TrustUpstream);
+        assert_eq!(policy.trust_for("veth0"), ChecksumTrust::TrustUpstream);
+        assert_eq!(policy.trust_for("eth0"), ChecksumTrust::TrustHardware);
+        let validator = ChecksumValidator::new(policy);
+
+        // Hardware verdicts are honored, even against the actual bytes
+        let mut bad = frame(true);
+        let mut bad_mbuf = mbuf(&mut bad);
+        bad_mbuf.set_checksum_status(ChecksumStatus::Good, ChecksumStatus::Good);
+        assert!(validator.validate(&mut bad_mbuf, ChecksumTrust::TrustHardware));
+        assert!(!validator.validate(&mut bad_mbuf, ChecksumTrust::Verify));
+        assert!(validator.validate(&mut bad_mbuf, ChecksumTrust::TrustUpstream));
+
+        let stats = validator.stats();
+        assert_eq!(stats.hardware_good.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.software_bad.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.trusted.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_incremental_updates_match_recomputation() {
+        // Fixed seed so failures reproduce
+        let mut rng = Rng::seeded(0x9E37_79B9_7F4A_7C15);
+        let mut next = move || rng.next_u64();
+        let full = |header: &[u8]| !ones_complement(0, header);
+
+        for _ in 0..10_000 {
+            // IPv4-like header: never all zero, checksum in bytes 10..12
+            let mut header = [0u8; 20];
+            header.iter_mut().for_each(|byte| *byte = next() as u8);
+            header[0] = 0x45;
+            header[10..12].fill(0);
+            let checksum = full(&header);
+
+            // Rewrite a random word or double word outside the checksum
+            let offset = [0, 2, 4, 6, 8, 12, 14, 16][next() as usize % 8];
+            let updated = if next() % 2 == 0 && offset != 8 {
+                let old = u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+                let new = next() as u32;
+                header[offset..offset + 4].copy_from_slice(&new.to_be_bytes());
+                checksum_update_u32(checksum, old, new)
+            } else {
+                let old = u16::from_be_bytes([header[offset], header[offset + 1]]);
+                let new = next() as u16;
+                header[offset..offset + 2].copy_from_slice(&new.to_be_bytes());
+                checksum_update_u16(checksum, old, new)
+            };
+            assert_eq!(updated, full(&header), "{:02x?}", header);
+        }
+    }
+
+    #[test]
+    fn test_tx_checksum_fill_and_missing_checksum() {
+        let mut frame = frame(true);
+        frame[24] ^= 0xFF; // IPv4 header checksum
+        let mut tx = mbuf(&mut frame);
+        tx.offload_flags |= OffloadFlags::TX_IP_CKSUM | OffloadFlags::TX_UDP_CKSUM;
+        fill_tx_checksums(&mut tx);
+        assert!(tx.offload_flags.tx().is_empty());
+        assert_eq!(verify(&tx), (ChecksumStatus::Good, ChecksumStatus::Good));
+
+        // A zero UDP checksum is absent, not bad
+        frame[40..42].fill(0);
+        let mut rx = mbuf(&mut frame);
+        let validator = ChecksumValidator::default();
+        assert!(validator.validate(&mut rx, ChecksumTrust::Verify));
+        assert_eq!(rx.l4_checksum(), ChecksumStatus::None);
+        assert!(rx.offload_flags.contains(OffloadFlags::RX_L4_CKSUM_NONE));
+    }
+}
@@ -0,0 +1,225 @@
+//! Send completion notifications
+//!
+//! A socket with a completion queue, see
+//! [`super::UdpSocket::set_completion_queue`], reports the outcome of each
+//! send made with a cookie through [`super::UdpSocket::send_with_cookie`]
+//! or [`super::UdpSocket::send_prepared_with_cookie`]. Once the frame has
+//! been handed to the wire, or the send has failed or been dropped, a
+//! [`SendCompletion`] carrying the cookie, the status and the time is queued
+//! for the application to take with [`super::UdpSocket::poll_completion`].
+//! Sends held for an unresolved next hop complete when they are flushed or
+//! expire, and sends scheduled for later when their last frame departs.
+//! Every cookie send reserves its completion slot up front, so a
+//! queue the application does not drain pushes back: sends fail with
+//! [`Error::QueueError`] while all slots are taken.
+
+use super::mib::ProtocolMib;
+use crate::utils::time::{monotonic_now, Timestamp};
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Outcome of a send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompletionStatus {
+    /// The frame was handed to the wire
+    Sent,
+    /// The send failed with the error given
+    Failed(String),
+    /// The datagram was discarded before it was sent, e.g. when its next hop did not resolve
+    Dropped,
+}
+
+/// Completion event of one send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendCompletion {
+    /// Cookie passed with the send
+    pub cookie: u64,
+    pub status: CompletionStatus,
+    /// Time the send completed
+    pub timestamp: Timestamp,
+}
+
+/// Completion queue counters
+#[derive(Debug, Default)]
+pub struct CompletionStats {
+    /// Sends completed as sent
+    pub sent: AtomicUsize,
+    /// Sends completed as failed or dropped
+    pub failed: AtomicUsize,
+    /// Sends refused because every completion slot was taken
+    pub backpressured: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Slots {
+    events: VecDeque<SendCompletion>,
+    /// Sends in flight holding a slot
+    reserved: usize,
+}
+
+/// Completion events of one socket
+#[derive(Debug)]
+pub(crate) struct CompletionQueue {
+    slots: Mutex<Slots>,
+    capacity: usize,
+    stats: CompletionStats,
+}
+
+impl CompletionQueue {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            slots: Mutex::new(Slots {
+                events: VecDeque::with_capacity(capacity),
+                reserved: 0,
+            }),
+            capacity,
+            stats: CompletionStats::default(),
+        }
+    }
+
+    /// Reserve the slot a send with `cookie` completes into
+    pub(crate) fn reserve(self: &Arc<Self>, cookie: u64) -> Result<CompletionToken> {
+        let mut slots = self.slots.lock();
+        if slots.events.len() + slots.reserved >= self.capacity {
+            self.stats.backpressured.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::QueueError(format!(
+                "Completion queue full with {} events",
+                self.capacity
+            )));
+        }
+        slots.reserved += 1;
+        Ok(CompletionToken {
+            queue: Some(self.clone()),
+            cookie,
+        })
+    }
+
+    fn complete(&self, cookie: u64, status: CompletionStatus) {
+        let counter = match status {
+            CompletionStatus::Sent => &self.stats.sent,
+            _ => &self.stats.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let mut slots = self.slots.lock();
+        slots.reserved -= 1;
+        slots.events.push_back(SendCompletion {
+            cookie,
+            status,
+            timestamp: monotonic_now(),
+        });
+    }
+
+    pub(crate) fn pop(&self) -> Option<SendCompletion> {
+        self.slots.lock().events.pop_front()
+    }
+
+    /// Events waiting plus sends in flight
+    pub(crate) fn len(&self) -> usize {
+        let slots = self.slots.lock();
+        slots.events.len() + slots.reserved
+    }
+
+    pub(crate) fn stats(&self) -> &CompletionStats {
+        &self.stats
+    }
+}
+
+/// Reserved completion slot of a send in flight
+///
+/// Dropping the token without completing it reports the send as dropped.
+pub(crate) struct CompletionToken {
+    queue: Option<Arc<CompletionQueue>>,
+    cookie: u64,
+}
+
+impl CompletionToken {
+    pub(crate) fn complete(mut self, status: CompletionStatus) {
+        if let Some(queue) = self.queue.take() {
+            queue.complete(self.cookie, status);
+        }
+    }
+}
+
+/// Datagram scheduled for later departure, accounted as its frames leave
+///
+/// Each frame departing is counted in the MIB; the completion reports the
+/// first failure, or success once the last frame has left.
+pub(crate) struct Departure {
+    mib: Arc<ProtocolMib>,
+    completion: Mutex<Option<CompletionToken>>,
+}
+
+impl Departure {
+    pub(crate) fn new(mib: Arc<ProtocolMib>, completion: Option<CompletionToken>) -> Arc<Self> {
+        Arc::new(Self {
+            mib,
+            completion: Mutex::new(completion),
+        })
+    }
+
+    /// Hook accounting one frame of `frame_len` bytes, `last` of the datagram
+    pub(crate) fn frame(
+        self: &Arc<Self>,
+        frame_len: usize,
+        last: bool,
+    ) -> impl FnOnce(&Result<()>) + Send + 'static {
+        let departure = self.clone();
+        move |result| {
+            let status = match result {
+                Ok(()) => {
+                    departure.mib.record_tx(frame_len);
+                    if !last {
+                        return;
+                    }
+                    CompletionStatus::Sent
+                }
+                Err(e) => CompletionStatus::Failed(e.to_string()),
+            };
+            if let Some(completion) = departure.completion.lock().take() {
+                completion.complete(status);
+            }
+        }
+    }
+}
+
+impl Drop for CompletionToken {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.complete(self.cookie, CompletionStatus::Dropped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_push_back_until_drained() {
+        let queue = Arc::new(CompletionQueue::new(2));
+        let first = queue.reserve(1).unwrap();
+        let second = queue.reserve(2).unwrap();
+        assert!(matches!(queue.reserve(3), Err(Error::QueueError(_))));
+
+        first.complete(CompletionStatus::Sent);
+        drop(second);
+        assert!(queue.reserve(3).is_err());
+        assert_eq!(
+            queue.pop().map(|c| (c.cookie, c.status)),
+            Some((1, CompletionStatus::Sent))
+        );
+        let dropped = queue.pop().unwrap();
+        assert_eq!(
+            (dropped.cookie, dropped.status),
+            (2, CompletionStatus::Dropped)
+        );
+        assert_eq!(queue.len(), 0);
+
+        assert!(queue.reserve(3).is_ok());
+        assert_eq!(queue.stats().backpressured.load(Ordering::Relaxed), 2);
+        assert_eq!(queue.stats().failed.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,429 @@
+//! LZ4 compression of UDP payloads
+//!
+//! Slow satellite and WAN links carry more when payloads are compressed.
+//! A [`PayloadCompressor`] bound with [`super::UdpStack::set_compression`]
+//! compresses the payloads a socket sends and decompresses those it
+//! receives whenever the local or the peer port falls in one of its
+//! [`CompressionConfig::ports`] ranges. Payloads are compressed before a
+//! [`super::PayloadTransform`] encrypts them and decompressed after it
+//! decrypts them, so encryption works on the compressed bytes.
+//!
+//! Every compressed datagram starts with a tag byte. [`TAG_LZ4`] is
+//! followed by the payload length as a big-endian `u16` and one LZ4 block.
+//! Payloads that do not shrink are sent as they are behind [`TAG_RAW`], so
+//! incompressible traffic costs one byte per datagram. Both ways work in
+//! place: sends keep [`MAX_OVERHEAD`] bytes of tailroom, and a received
+//! payload is decompressed into the tailroom of its mbuf. Datagrams that do
+//! not decompress are dropped with [`super::DropReason::Decompress`].
+
+use crate::utils::counter::Counter;
+use crate::{Error, Result};
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+/// Tag of a payload sent uncompressed
+pub const TAG_RAW: u8 = 0xc0;
+
+/// Tag of an LZ4-compressed payload
+pub const TAG_LZ4: u8 = 0xc1;
+
+/// Bytes a compressed payload is longer than the original at most
+pub const MAX_OVERHEAD: usize = 1;
+
+/// Bytes before the LZ4 block: tag and original length
+const LZ4_HEADER_LEN: usize = 3;
+
+/// Shortest match LZ4 encodes
+const MIN_MATCH: usize = 4;
+
+/// Bytes at the end of a block that are always literals
+const LAST_LITERALS: usize = 5;
+
+/// Matches start at least this many bytes before the end of a block
+const MF_LIMIT: usize = 12;
+
+/// Bits of the match finder's hash table
+const HASH_LOG: u32 = 12;
+
+thread_local! {
+    /// Copy of the payload being compressed or decompressed
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Ports whose datagrams are compressed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Port ranges, matched against both the local and the peer port
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl CompressionConfig {
+    pub fn with_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+}
+
+/// Compression counters
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    /// Payloads sent compressed
+    pub compressed: Counter,
+    /// Payloads sent uncompressed because they did not shrink
+    pub passthrough: Counter,
+    /// Payloads received and decompressed, raw ones included
+    pub decompressed: Counter,
+    /// Received payloads that failed to decompress
+    pub errors: Counter,
+    /// Payload bytes before compression
+    pub bytes_in: Counter,
+    /// Payload bytes after compression, tags included
+    pub bytes_out: Counter,
+    /// Time spent compressing
+    pub compress_nanos: Counter,
+    /// Time spent decompressing
+    pub decompress_nanos: Counter,
+}
+
+impl CompressionStats {
+    /// Bytes sent per byte of payload, 1.0 before anything was sent
+    pub fn ratio(&self) -> f64 {
+        match self.bytes_in.get() {
+            0 => 1.0,
+            bytes_in => self.bytes_out.get() as f64 / bytes_in as f64,
+        }
+    }
+
+    /// Payload bytes compressed per second of compression time
+    pub fn compress_throughput(&self) -> f64 {
+        match self.compress_nanos.get() {
+            0 => 0.0,
+            nanos => self.bytes_in.get() as f64 * 1e9 / nanos as f64,
+        }
+    }
+}
+
+/// Compression stage shared by the sockets of a stack
+#[derive(Debug, Default)]
+pub struct PayloadCompressor {
+    config: CompressionConfig,
+    stats: CompressionStats,
+}
+
+impl PayloadCompressor {
+    pub fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CompressionConfig {
+        &self.config
+    }
+
+    /// Get compression counters
+    pub fn stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    /// Whether datagrams between `local_port` and `peer_port` are compressed
+    pub fn applies(&self, local_port: u16, peer_port: u16) -> bool {
+        self.config
+            .ports
+            .iter()
+            .any(|ports| ports.contains(&local_port) || ports.contains(&peer_port))
+    }
+
+    /// Compress the first `len` bytes of `buffer` in place, returning the new length
+    ///
+    /// `buffer` holds at least `len + MAX_OVERHEAD` bytes.
+    pub(crate) fn compress(&self, buffer: &mut [u8], len: usize) -> usize {
+        let started = Instant::now();
+        let compressed = SCRATCH.with(|scratch| {
+            let mut scratch = scratch.borrow_mut();
+            scratch.clear();
+            scratch.extend_from_slice(&buffer[..len]);
+            // Worth it only if the block and its header beat the raw tag
+            let room = len.saturating_sub(LZ4_HEADER_LEN);
+            let block = match u16::try_from(len) {
+                Ok(original) if room > 0 => compress_block(&scratch, &mut buffer[3..3 + room])
+                    .map(|block| (original, block)),
+                _ => None,
+            };
+            match block {
+                Some((original, block)) => {
+                    buffer[0] = TAG_LZ4;
+                    buffer[1..3].copy_from_slice(&original.to_be_bytes());
+                    Some(LZ4_HEADER_LEN + block)
+                }
+                None => {
+                    buffer[0] = TAG_RAW;
+                    buffer[1..=len].copy_from_slice(&scratch);
+                    None
+                }
+            }
+        });
+        let out = match compressed {
+            Some(out) => {
+                self.stats.compressed.inc();
+                out
+            }
+            None => {
+                self.stats.passthrough.inc();
+                len + 1
+            }
+        };
+        self.stats.bytes_in.add(len as u64);
+        self.stats.bytes_out.add(out as u64);
+        self.stats
+            .compress_nanos
+            .add(started.elapsed().as_nanos() as u64);
+        out
+    }
+
+    /// Decompress the first `len` bytes of `buffer` in place, returning the payload length
+    ///
+    /// Fails with [`Error::NetworkError`] for an unknown tag, a corrupt
+    /// block, or a payload that does not fit `buffer`.
+    pub(crate) fn decompress(&self, buffer: &mut [u8], len: usize) -> Result<usize> {
+        let started = Instant::now();
+        let result = decompress_tagged(buffer, len);
+        match result {
+            Ok(_) => self.stats.decompressed.inc(),
+            Err(_) => self.stats.errors.inc(),
+        }
+        self.stats
+            .decompress_nanos
+            .add(started.elapsed().as_nanos() as u64);
+        result
+    }
+}
+
+fn decompress_tagged(buffer: &mut [u8], len: usize) -> Result<usize> {
+    let corrupt = |what: &str| Err(Error::NetworkError(format!("Compressed payload {}", what)));
+    match buffer[..len] {
+        [TAG_RAW, ..] => {
+            buffer.copy_within(1..len, 0);
+            Ok(len - 1)
+        }
+        [TAG_LZ4, high, low, ..] => {
+            let original = u16::from_be_bytes([high, low]) as usize;
+            if original > buffer.len() {
+                return corrupt("too large for the buffer");
+            }
+            SCRATCH.with(|scratch| {
+                let mut scratch = scratch.borrow_mut();
+                scratch.clear();
+                scratch.extend_from_slice(&buffer[LZ4_HEADER_LEN..len]);
+                match decompress_block(&scratch, &mut buffer[..original]) {
+                    Some(out) if out == original => Ok(out),
+                    _ => corrupt("is corrupt"),
+                }
+            })
+        }
+        _ => corrupt("has no compression tag"),
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Output of an LZ4 block, refusing writes past its end
+struct BlockWriter<'a> {
+    out: &'a mut [u8],
+    pos: usize,
+}
+
+impl BlockWriter<'_> {
+    fn push(&mut self, byte: u8) -> Option<()> {
+        *self.out.get_mut(self.pos)? = byte;
+        self.pos += 1;
+        Some(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Option<()> {
+        self.out
+            .get_mut(self.pos..self.pos + bytes.len())?
+            .copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Some(())
+    }
+
+    /// Length bytes following a nibble of 15
+    fn length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+        self.push(len as u8)
+    }
+
+    /// Literals followed by a match of `len` bytes `offset` back, or by nothing
+    fn sequence(&mut self, literals: &[u8], matched: Option<(usize, usize)>) -> Option<()> {
+        let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+        self.push((literals.len().min(15) as u8) << 4 | match_len.min(15) as u8)?;
+        if literals.len() >= 15 {
+            self.length(literals.len() - 15)?;
+        }
+        self.extend(literals)?;
+        if let Some((offset, _)) = matched {
+            self.extend(&(offset as u16).to_le_bytes())?;
+            if match_len >= 15 {
+                self.length(match_len - 15)?;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Compress `input` into `output` as one LZ4 block, `None` if it does not fit
+fn compress_block(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut writer = BlockWriter {
+        out: output,
+        pos: 0,
+    };
+    let mut table = [0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut i = 0;
+    let match_end = input.len().saturating_sub(LAST_LITERALS);
+    while i + MF_LIMIT < input.len() {
+        let sequence = read_u32(input, i);
+        let slot = &mut table[hash(sequence)];
+        let candidate = *slot as usize;
+        *slot = i as u32;
+        if candidate >= i
+            || i - candidate > u16::MAX as usize
+            || read_u32(input, candidate) != sequence
+        {
+            i += 1;
+            continue;
+        }
+        let mut len = MIN_MATCH;
+        while i + len < match_end && input[candidate + len] == input[i + len] {
+            len += 1;
+        }
+        writer.sequence(&input[anchor..i], Some((i - candidate, len)))?;
+        i += len;
+        anchor = i;
+    }
+    writer.sequence(&input[anchor..], None)?;
+    Some(writer.pos)
+}
+
+/// Decompress an LZ4 block into `output`, `None` if corrupt or too large
+fn decompress_block(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let length = |ip: &mut usize| -> Option<usize> {
+        let mut len = 0;
+        loop {
+            let byte = *input.get(*ip)?;
+            *ip += 1;
+            len += byte as usize;
+            if byte != 255 {
+                return Some(len);
+            }
+        }
+    };
+    let (mut ip, mut op) = (0, 0);
+    loop {
+        let token = *input.get(ip)?;
+        ip += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += length(&mut ip)?;
+        }
+        output
+            .get_mut(op..op + literals)?
+            .copy_from_slice(input.get(ip..ip + literals)?);
+        ip += literals;
+        op += literals;
+        if ip == input.len() {
+            return Some(op);
+        }
+
+        let offset = u16::from_le_bytes([*input.get(ip)?, *input.get(ip + 1)?]) as usize;
+        ip += 2;
+        let mut len = (token & 0x0f) as usize + MIN_MATCH;
+        if token & 0x0f == 0x0f {
+            len += length(&mut ip)?;
+        }
+        if offset == 0 || offset > op || op + len > output.len() {
+            return None;
+        }
+        // Matches may overlap what they produce, so copy bytewise
+        for k in op..op + len {
+            output[k] = output[k - offset];
+        }
+        op += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips_and_passes_through() {
+        let compressor =
+            PayloadCompressor::new(CompressionConfig::default().with_ports(5000..=5010));
+        assert!(compressor.applies(5005, 40000));
+        assert!(compressor.applies(40000, 5000));
+        assert!(!compressor.applies(4999, 5011));
+
+        let text: Vec<u8> = b"telemetry frame 0042 status=ok; "
+            .iter()
+            .cycle()
+            .take(1200)
+            .copied()
+            .collect();
+        // Pseudo-random bytes do not compress
+        let mut state = 0x2545_f491u32;
+        let noise: Vec<u8> = (0..300)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+
+        for (payload, tag) in [
+            (&text, TAG_LZ4),
+            (&noise, TAG_RAW),
+            (&vec![7u8; 3], TAG_RAW),
+        ] {
+            let mut buffer = vec![0u8; 2048];
+            buffer[..payload.len()].copy_from_slice(payload);
+            let sent = compressor.compress(&mut buffer, payload.len());
+            assert_eq!(buffer[0], tag);
+            assert!(sent <= payload.len() + MAX_OVERHEAD);
+            assert_eq!(
+                compressor.decompress(&mut buffer, sent).unwrap(),
+                payload.len()
+            );
+            assert_eq!(&buffer[..payload.len()], &payload[..]);
+        }
+
+        let stats = compressor.stats();
+        assert_eq!(stats.compressed.get(), 1);
+        assert_eq!(stats.passthrough.get(), 2);
+        assert_eq!(stats.decompressed.get(), 3);
+        assert!(stats.ratio() < 0.5);
+
+        // Corrupt blocks and unknown tags fail instead of panicking
+        let mut buffer = vec![0u8; 64];
+        buffer[..6].copy_from_slice(&[TAG_LZ4, 0, 40, 0x1f, 0xff, 0xff]);
+        assert!(compressor.decompress(&mut buffer, 6).is_err());
+        buffer[..4].copy_from_slice(&[TAG_LZ4, 0, 8, 0x04]);
+        assert!(compressor.decompress(&mut buffer, 4).is_err());
+        buffer[0] = 0x17;
+        assert!(compressor.decompress(&mut buffer, 4).is_err());
+        assert_eq!(stats.errors.get(), 3);
+    }
+}
@@ -0,0 +1,95 @@
+//! Owned payload buffers for copy-out receive
+//!
+//! `UdpSocket::recv_copied` copies the payload into a `Vec<u8>` and recycles
+//! the mbuf at once. The vectors come from a small per-socket cache so that
+//! consumers returning them with `recycle_buffer` avoid an allocation per
+//! packet.
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Buffers kept by each socket's copy-out cache
+pub const DEFAULT_COPY_BUFFERS: usize = 64;
+
+/// Copy-out buffer cache counters
+#[derive(Debug, Default)]
+pub struct CopyBufferStats {
+    /// Buffers served from the cache
+    pub reused: AtomicUsize,
+    /// Buffers newly allocated
+    pub allocated: AtomicUsize,
+}
+
+/// Cache of payload vectors
+pub(crate) struct CopyBufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_cached: usize,
+    stats: CopyBufferStats,
+}
+
+impl CopyBufferPool {
+    pub(crate) fn new(max_cached: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::new()),
+            max_cached,
+            stats: CopyBufferStats::default(),
+        }
+    }
+
+    /// Copy `data` into a cached or new vector
+    pub(crate) fn copy(&self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = self.take(data.len());
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    /// Take an empty cached or new vector
+    pub(crate) fn take(&self, capacity: usize) -> Vec<u8> {
+        let mut buffer = match self.free.lock().pop() {
+            Some(buffer) => {
+                self.stats.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.stats.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(capacity)
+            }
+        };
+        buffer.clear();
+        buffer
+    }
+
+    /// Return a vector for reuse; beyond the cache size it is dropped
+    pub(crate) fn recycle(&self, buffer: Vec<u8>) {
+        let mut free = self.free.lock();
+        if free.len() < self.max_cached {
+            free.push(buffer);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> &CopyBufferStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recycled_buffers_are_reused() {
+        let pool = CopyBufferPool::new(1);
+        let first = pool.copy(b"hello");
+        assert_eq!(first, b"hello");
+        let ptr = first.as_ptr();
+
+        pool.recycle(first);
+        pool.recycle(vec![0; 4]); // cache full, dropped
+
+        let second = pool.copy(b"abc");
+        assert_eq!(second, b"abc");
+        assert_eq!(second.as_ptr(), ptr);
+        assert_eq!(pool.stats().reused.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.stats().allocated.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,188 @@
+//! Copy and zero-copy delivery
+//!
+//! A socket in [`DeliveryMode::ZeroCopy`] queues received mbufs and hands
+//! them to the application as they are, which holds pool buffers for as long
+//! as a slow consumer keeps the packets. In [`DeliveryMode::Copy`] the
+//! payload is copied into a byte ring owned by the socket on arrival and the
+//! mbuf is recycled at once; the copies are read with
+//! `UdpSocket::recv_copied`. The mode can be switched at any time, and a
+//! zero-copy socket with a fallback threshold copies instead while the
+//! application holds that many zero-copy packets.
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// Bytes of each socket's copy ring by default
+pub const DEFAULT_COPY_RING_BYTES: usize = 256 * 1024;
+
+/// Source address and payload length stored before each copied payload
+const RECORD_HEADER: usize = 8;
+
+/// How a socket hands received datagrams to the application
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeliveryMode {
+    /// Queue the mbufs themselves
+    #[default]
+    ZeroCopy,
+    /// Copy payloads into the socket's byte ring and recycle the mbufs
+    Copy,
+}
+
+/// Delivery mode counters
+#[derive(Debug, Default)]
+pub struct DeliveryStats {
+    /// Datagrams copied into the ring
+    pub copied: AtomicUsize,
+    /// Datagrams copied because the fallback threshold was crossed
+    pub fallback_copies: AtomicUsize,
+    /// Times the fallback threshold was crossed
+    pub fallbacks: AtomicUsize,
+    /// Datagrams dropped because the ring was full
+    pub ring_full: AtomicUsize,
+}
+
+/// Copied datagrams of one socket
+pub(crate) struct CopyRing {
+    records: Mutex<VecDeque<u8>>,
+    capacity: usize,
+}
+
+impl CopyRing {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            records: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    /// Append a datagram; false if the ring has no room for it
+    pub(crate) fn push(&self, src: SocketAddrV4, payload: &[u8]) -> bool {
+        let mut records = self.records.lock();
+        if records.len() + RECORD_HEADER + payload.len() > self.capacity {
+            return false;
+        }
+        records.extend(src.ip().octets());
+        records.extend(src.port().to_be_bytes());
+        records.extend((payload.len() as u16).to_be_bytes());
+        records.extend(payload);
+        true
+    }
+
+    /// Take the oldest datagram, appending its payload to `payload`
+    pub(crate) fn pop(&self, payload: &mut Vec<u8>) -> Option<SocketAddr> {
+        let mut records = self.records.lock();
+        if records.is_empty() {
+            return None;
+        }
+        let mut header = [0u8; RECORD_HEADER];
+        for (byte, record) in header.iter_mut().zip(records.drain(..RECORD_HEADER)) {
+            *byte = record;
+        }
+        let len = u16::from_be_bytes([header[6], header[7]]) as usize;
+        payload.extend(records.drain(..len));
+        let ip = Ipv4Addr::new(header[0], header[1], header[2], header[3]);
+        let port = u16::from_be_bytes([header[4], header[5]]);
+        Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    }
+
+    /// Bytes in use, including record headers
+    pub(crate) fn used(&self) -> usize {
+        self.records.lock().len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.records.lock().is_empty()
+    }
+}
+
+/// Run-time delivery settings of a socket
+#[derive(Debug, Default)]
+pub(crate) struct DeliveryControl {
+    mode: AtomicU8,
+    /// Outstanding zero-copy packets at which delivery falls back to copying, 0 for never
+    fallback_threshold: AtomicUsize,
+    /// Set while falling back, so each crossing is counted once
+    falling_back: AtomicBool,
+    pub(crate) stats: DeliveryStats,
+}
+
+impl DeliveryControl {
+    pub(crate) fn set_mode(&self, mode: DeliveryMode) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mode(&self) -> DeliveryMode {
+        match self.mode.load(Ordering::Relaxed) {
+            0 => DeliveryMode::ZeroCopy,
+            _ => DeliveryMode::Copy,
+        }
+    }
+
+    pub(crate) fn set_fallback_threshold(&self, threshold: Option<usize>) {
+        self.fallback_threshold
+            .store(threshold.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub(crate) fn fallback_threshold(&self) -> Option<usize> {
+        match self.fallback_threshold.load(Ordering::Relaxed) {
+            0 => None,
+            threshold => Some(threshold),
+        }
+    }
+
+    /// Whether a datagram arriving with `outstanding` zero-copy packets is copied
+    pub(crate) fn copies(&self, outstanding: usize) -> bool {
+        if self.mode() == DeliveryMode::Copy {
+            return true;
+        }
+        let over = self
+            .fallback_threshold()
+            .is_some_and(|threshold| outstanding >= threshold);
+        if over != self.falling_back.swap(over, Ordering::Relaxed) && over {
+            self.stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+        }
+        if over {
+            self.stats.fallback_copies.fetch_add(1, Ordering::Relaxed);
+        }
+        over
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_and_fallback() {
+        let ring = CopyRing::new(2 * RECORD_HEADER + 8);
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 4000);
+        assert!(ring.push(src, b"abcd"));
+        assert!(ring.push(src, b"efgh"));
+        assert!(!ring.push(src, b"i"));
+
+        let mut payload = Vec::new();
+        assert_eq!(ring.pop(&mut payload), Some(SocketAddr::V4(src)));
+        assert_eq!(payload, b"abcd");
+        assert!(ring.push(src, b"ij"));
+        payload.clear();
+        ring.pop(&mut payload);
+        ring.pop(&mut payload);
+        assert_eq!(payload, b"efghij");
+        assert!(ring.is_empty());
+
+        let control = DeliveryControl::default();
+        control.set_fallback_threshold(Some(2));
+        assert!(!control.copies(1));
+        assert!(control.copies(2));
+        assert!(control.copies(3));
+        assert!(!control.copies(0));
+        assert!(control.copies(2));
+        assert_eq!(control.stats.fallbacks.load(Ordering::Relaxed), 2);
+        assert_eq!(control.stats.fallback_copies.load(Ordering::Relaxed), 3);
+
+        control.set_mode(DeliveryMode::Copy);
+        assert!(control.copies(0));
+    }
+}
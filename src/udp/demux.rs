@@ -0,0 +1,255 @@
+//! Sub-channels of a socket by payload tag
+//!
+//! Services sharing one port often tell their datagrams apart by a short
+//! tag at a fixed place in the payload. A socket given a [`DemuxConfig`]
+//! with [`super::UdpSocket::set_demux`] reads the tag of every datagram as
+//! it is queued and puts the mbuf on the ring of the sub-channel added for
+//! that tag with [`super::UdpSocket::add_channel`], so each service takes
+//! its own datagrams with [`super::UdpSocket::recv_channel`] without a copy
+//! or a demultiplexing thread. Datagrams with an unknown tag, or too short
+//! to hold one, go to the socket's own queue.
+//!
+//! Channels deliver without copying whatever the socket's delivery mode,
+//! and count what they were given in their [`ChannelStats`].
+
+use crate::memory::Mbuf;
+use crate::utils::counter::Counter;
+use crate::{Error, Result};
+use lockfree_ringbuf::SpscRingBuffer;
+use std::collections::HashMap;
+
+/// Longest tag a socket demultiplexes on
+pub const MAX_DEMUX_TAG_LEN: usize = 8;
+
+/// Where the tag is in the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DemuxConfig {
+    /// Offset of the tag into the payload
+    pub offset: usize,
+    /// Length of the tag, up to [`MAX_DEMUX_TAG_LEN`]
+    pub len: usize,
+    /// Datagrams each channel queues
+    pub channel_size: usize,
+}
+
+impl DemuxConfig {
+    pub fn new(offset: usize, len: usize, channel_size: usize) -> Result<Self> {
+        if len == 0 || len > MAX_DEMUX_TAG_LEN {
+            return Err(Error::InvalidConfig(format!(
+                "Demux tag length {} out of range",
+                len
+            )));
+        }
+        Ok(Self {
+            offset,
+            len,
+            channel_size,
+        })
+    }
+}
+
+/// Counters of a sub-channel
+#[derive(Debug, Default)]
+pub struct ChannelStats {
+    pub enqueued: Counter,
+    pub bytes: Counter,
+    /// Datagrams refused with the channel full
+    pub dropped: Counter,
+    pub received: Counter,
+}
+
+struct Channel {
+    queue: SpscRingBuffer<*mut Mbuf>,
+    stats: ChannelStats,
+}
+
+/// Sub-channels of one socket
+pub(crate) struct Demux {
+    config: DemuxConfig,
+    channels: HashMap<u64, Channel>,
+    /// Datagrams left to the socket's own queue
+    unmatched: Counter,
+}
+
+impl Demux {
+    pub(crate) fn new(config: DemuxConfig) -> Self {
+        Self {
+            config,
+            channels: HashMap::new(),
+            unmatched: Counter::default(),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &DemuxConfig {
+        &self.config
+    }
+
+    /// Channel key of a tag of the configured length
+    fn key(&self, tag: &[u8]) -> Result<u64> {
+        if tag.len() != self.config.len {
+            return Err(Error::InvalidConfig(format!(
+                "Tag of {} bytes on a demux of {}-byte tags",
+                tag.len(),
+                self.config.len
+            )));
+        }
+        Ok(tag.iter().fold(0, |key, &byte| key << 8 | u64::from(byte)))
+    }
+
+    pub(crate) fn add(&mut self, tag: &[u8]) -> Result<()> {
+        let key = self.key(tag)?;
+        if self.channels.contains_key(&key) {
+            return Err(Error::NetworkError(format!(
+                "Channel {:02x?} already exists",
+                tag
+            )));
+        }
+        self.channels.insert(
+            key,
+            Channel {
+                queue: SpscRingBuffer::new(self.config.channel_size),
+                stats: ChannelStats::default(),
+            },
+        );
+        Ok(())
+    }
+
+    fn channel(&self, tag: &[u8]) -> Result<&Channel> {
+        let key = self.key(tag)?;
+        self.channels
+            .get(&key)
+            .ok_or_else(|| Error::NetworkError(format!("No channel {:02x?}", tag)))
+    }
+
+    /// Queue `mbuf` on the channel of the tag in `payload`
+    ///
+    /// Returns `None` for the socket's own queue, else whether the channel
+    /// took the mbuf; refused, it stays with the caller.
+    pub(crate) fn push(&self, mbuf: *mut Mbuf, payload: &[u8]) -> Option<bool> {
+        let channel = payload
+            .get(self.config.offset..self.config.offset + self.config.len)
+            .and_then(|tag| self.channels.get(&self.key(tag).ok()?));
+        let Some(channel) = channel else {
+            self.unmatched.inc();
+            return None;
+        };
+        if channel.queue.push(mbuf).is_err() {
+            channel.stats.dropped.inc();
+            return Some(false);
+        }
+        channel.stats.enqueued.inc();
+        channel.stats.bytes.add(payload.len() as u64);
+        Some(true)
+    }
+
+    /// Take the next mbuf of the channel for `tag`
+    pub(crate) fn pop(&self, tag: &[u8]) -> Result<Option<*mut Mbuf>> {
+        let channel = self.channel(tag)?;
+        let mbuf = channel.queue.pop().ok();
+        if mbuf.is_some() {
+            channel.stats.received.inc();
+        }
+        Ok(mbuf)
+    }
+
+    pub(crate) fn stats(&self, tag: &[u8]) -> Result<&ChannelStats> {
+        self.channel(tag).map(|channel| &channel.stats)
+    }
+
+    pub(crate) fn unmatched(&self) -> u64 {
+        self.unmatched.get()
+    }
+
+    /// Take every mbuf queued across the channels
+    pub(crate) fn drain(&self) -> Vec<*mut Mbuf> {
+        self.channels
+            .values()
+            .flat_map(|channel| std::iter::from_fn(|| channel.queue.pop().ok()))
+            .collect()
+    }
+
+    /// Mbufs queued across the channels
+    pub(crate) fn len(&self) -> usize {
+        self.channels
+            .values()
+            .map(|channel| channel.queue.len())
+            .sum()
+    }
+
+    /// Fault in the rings of every channel, returning the bytes touched
+    pub(crate) fn prefault(&self) -> usize {
+        self.channels
+            .values()
+            .map(|channel| channel.queue.prefault())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::testing::{load, FrameBuilder};
+    use crate::udp::{DeliveryMode, UdpStack};
+    use crate::Config;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_channels_split_one_port_by_tag() {
+        let pool = Arc::new(MbufPool::new("demux".to_string(), 8, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        let id = stack
+            .create_socket("0.0.0.0:7000".parse().unwrap())
+            .unwrap();
+        let socket = stack.get_socket_mut(id).unwrap();
+        assert!(DemuxConfig::new(0, 9, 4).is_err());
+        socket
+            .set_demux(DemuxConfig::new(1, 2, 1).unwrap())
+            .unwrap();
+        socket.add_channel(b"AB").unwrap();
+        socket.add_channel(b"CD").unwrap();
+        assert!(socket.add_channel(b"AB").is_err());
+        assert!(socket.add_channel(b"ABC").is_err());
+        // Channels stay zero-copy
+        socket.set_delivery_mode(DeliveryMode::Copy);
+
+        for payload in [&b"xAB1"[..], b"xCD2", b"xEF3", b"x"] {
+            let mbuf = load(&pool, &FrameBuilder::to_port(7000).payload(payload).build());
+            assert!(stack.dispatch(mbuf).is_delivered());
+        }
+        // The channel is full
+        let mbuf = load(&pool, &FrameBuilder::to_port(7000).payload(b"xAB4").build());
+        assert!(!stack.dispatch(mbuf).is_delivered());
+        pool.free(mbuf).unwrap();
+
+        let socket = stack.get_socket(id).unwrap();
+        let packet = socket.recv_channel(b"AB").unwrap();
+        assert_eq!(packet.payload(), b"xAB1");
+        socket.release(packet).unwrap();
+        assert!(socket.recv_channel(b"AB").is_err());
+        assert!(socket.recv_channel(b"ZZ").is_err());
+        let packet = socket.recv_channel(b"CD").unwrap();
+        socket.release(packet).unwrap();
+        // Unknown tags and short payloads end up on the socket's own queue
+        assert_eq!(socket.recv_copied().unwrap().1, b"xEF3");
+        assert_eq!(socket.recv_copied().unwrap().1, b"x");
+        assert_eq!(socket.demux_unmatched(), 2);
+
+        let stats = socket.channel_stats(b"AB").unwrap();
+        assert_eq!((stats.enqueued.get(), stats.bytes.get()), (1, 4));
+        assert_eq!((stats.dropped.get(), stats.received.get()), (1, 1));
+        assert_eq!(socket.held_buffers(), 0);
+        assert_eq!(pool.stats().in_use, 0);
+
+        // Replacing the demux frees what its channels still held
+        let mbuf = load(&pool, &FrameBuilder::to_port(7000).payload(b"xAB5").build());
+        assert!(stack.dispatch(mbuf).is_delivered());
+        let socket = stack.get_socket_mut(id).unwrap();
+        socket
+            .set_demux(DemuxConfig::new(0, 1, 4).unwrap())
+            .unwrap();
+        assert!(socket.recv_channel(b"AB").is_err());
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
@@ -0,0 +1,552 @@
+//! Minimal DNS client over the userspace UDP path
+//!
+//! [`DnsResolver`] sends A/AAAA queries through a [`UdpSocket`] and matches
+//! the replies read back from it, so names resolve without touching the
+//! kernel stack. It never blocks on its own: [`DnsResolver::query`] starts a
+//! lookup and [`DnsResolver::poll`] consumes replies, retries timed-out
+//! queries on the next server and reports finished lookups, which lets an
+//! event loop or async task drive it. [`DnsResolver::resolve`] is the
+//! blocking convenience built on the two.
+//!
+//! The socket should be dedicated to the resolver: `poll` consumes every
+//! packet queued on it.
+//!
+//! Against spoofed replies (RFC 5452), query IDs are random, each query to
+//! a server with no other lookup outstanding leaves from a fresh random
+//! source port, and a reply is only taken if it comes from the server
+//! asked and repeats the question that was sent.
+
+use super::UdpSocket;
+use crate::utils::rand::Rng;
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// DNS header length
+const HEADER_LEN: usize = 12;
+
+/// Class IN
+const CLASS_IN: u16 = 1;
+
+/// Recursion desired header flag
+const FLAG_RD: u16 = 0x0100;
+
+/// Response header flag
+const FLAG_QR: u16 = 0x8000;
+
+/// Longest encoded name
+const MAX_NAME_LEN: usize = 255;
+
+/// Interval at which [`DnsResolver::resolve`] polls the socket
+const RESOLVE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Queried record type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DnsRecordType {
+    /// IPv4 address
+    A,
+    /// IPv6 address
+    Aaaa,
+}
+
+impl DnsRecordType {
+    fn code(self) -> u16 {
+        match self {
+            DnsRecordType::A => 1,
+            DnsRecordType::Aaaa => 28,
+        }
+    }
+}
+
+/// Resolver configuration
+#[derive(Debug, Clone)]
+pub struct DnsConfig {
+    /// Servers, used round-robin
+    pub servers: Vec<SocketAddr>,
+    /// Time to wait for each attempt
+    pub timeout: Duration,
+    /// Attempts after the first, each on the next server
+    pub retries: u32,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            timeout: Duration::from_secs(2),
+            retries: 2,
+        }
+    }
+}
+
+/// Handle of a lookup started with [`DnsResolver::query`]
+pub type DnsQueryId = u16;
+
+/// Lookup in flight
+struct PendingQuery {
+    message: Vec<u8>,
+    server: usize,
+    attempts: u32,
+    deadline: Instant,
+}
+
+/// Parsed reply
+struct DnsReply {
+    id: u16,
+    rcode: u8,
+    /// Where the question section is in the message
+    question: Range<usize>,
+    addrs: Vec<IpAddr>,
+}
+
+/// Non-blocking DNS client
+pub struct DnsResolver {
+    config: DnsConfig,
+    pending: HashMap<DnsQueryId, PendingQuery>,
+    /// Source of query IDs
+    rng: Rng,
+    next_server: usize,
+}
+
+impl DnsResolver {
+    /// Create a resolver; at least one server is required
+    pub fn new(config: DnsConfig) -> Result<Self> {
+        if config.servers.is_empty() {
+            return Err(Error::InvalidConfig(
+                "DNS resolver needs at least one server".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            config,
+            pending: HashMap::new(),
+            rng: Rng::for_component("dns"),
+            next_server: 0,
+        })
+    }
+
+    /// Send a query for `name` and return its handle
+    pub fn query(
+        &mut self,
+        socket: &UdpSocket,
+        name: &str,
+        record_type: DnsRecordType,
+    ) -> Result<DnsQueryId> {
+        let server = self.config.servers[self.next_server];
+        // Replies to lookups still waiting on the server arrive on the current port
+        if !self.asks(server) {
+            // Servers over IPv6 are asked from the bound port
+            let _ = socket.cycle_source_port(server);
+        }
+        self.start(name, record_type, Instant::now(), |server, message| {
+            socket.send(server, message)
+        })
+    }
+
+    /// Handle replies and timeouts, returning the lookups that finished
+    ///
+    /// A lookup that ran out of attempts finishes with a timeout error.
+    pub fn poll(&mut self, socket: &UdpSocket) -> Vec<(DnsQueryId, Result<Vec<IpAddr>>)> {
+        let mut finished = Vec::new();
+        while let Ok((src_addr, payload)) = socket.recv_copied() {
+            finished.extend(self.on_reply(src_addr, &payload));
+            socket.recycle_buffer(payload);
+        }
+        finished.extend(self.expire(Instant::now(), |server, message| {
+            socket.send(server, message)
+        }));
+        finished
+    }
+
+    /// Resolve `name`, blocking until it finishes
+    pub fn resolve(
+        &mut self,
+        socket: &UdpSocket,
+        name: &str,
+        record_type: DnsRecordType,
+    ) -> Result<Vec<IpAddr>> {
+        let id = self.query(socket, name, record_type)?;
+        loop {
+            // Other lookups finishing meanwhile are dropped
+            if let Some((_, result)) = self
+                .poll(socket)
+                .into_iter()
+                .find(|(finished, _)| *finished == id)
+            {
+                return result;
+            }
+            std::thread::sleep(RESOLVE_POLL_INTERVAL);
+        }
+    }
+
+    /// Number of lookups in flight
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Cancel a lookup; a late reply to it is ignored
+    pub fn cancel(&mut self, id: DnsQueryId) -> bool {
+        self.pending.remove(&id).is_some()
+    }
+
+    /// Whether a lookup in flight waits on `server`
+    fn asks(&self, server: SocketAddr) -> bool {
+        self.pending
+            .values()
+            .any(|query| self.config.servers[query.server] == server)
+    }
+
+    fn start<F>(
+        &mut self,
+        name: &str,
+        record_type: DnsRecordType,
+        now: Instant,
+        mut send: F,
+    ) -> Result<DnsQueryId>
+    where
+        F: FnMut(SocketAddr, &[u8]) -> Result<()>,
+    {
+        let id = self.allocate_id()?;
+        let message = encode_query(id, name, record_type)?;
+        let server = self.next_server;
+        self.next_server = (self.next_server + 1) % self.config.servers.len();
+
+        send(self.config.servers[server], &message)?;
+        self.pending.insert(
+            id,
+            PendingQuery {
+                message,
+                server,
+                attempts: 1,
+                deadline: now + self.config.timeout,
+            },
+        );
+        Ok(id)
+    }
+
+    fn allocate_id(&mut self) -> Result<DnsQueryId> {
+        if self.pending.len() > u16::MAX as usize {
+            return Err(Error::NetworkError("All DNS query IDs in use".to_string()));
+        }
+        loop {
+            let id = self.rng.next_u32() as u16;
+            if !self.pending.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Match a reply against the pending lookups
+    fn on_reply(
+        &mut self,
+        src_addr: SocketAddr,
+        payload: &[u8],
+    ) -> Option<(DnsQueryId, Result<Vec<IpAddr>>)> {
+        let reply = parse_reply(payload).ok()?;
+        let query = self.pending.get(&reply.id)?;
+        // Replies must come from the server last asked, about what was asked
+        let question = &query.message[HEADER_LEN..];
+        if self.config.servers[query.server] != src_addr
+            || !payload[reply.question].eq_ignore_ascii_case(question)
+        {
+            return None;
+        }
+        self.pending.remove(&reply.id);
+
+        let result = match reply.rcode {
+            0 => Ok(reply.addrs),
+            3 => Err(Error::NetworkError("DNS name does not exist".to_string())),
+            rcode => Err(Error::NetworkError(format!(
+                "DNS server answered with rcode {}",
+                rcode
+            ))),
+        };
+        Some((reply.id, result))
+    }
+
+    /// Retry or fail every lookup past its deadline at `now`
+    fn expire<F>(&mut self, now: Instant, mut send: F) -> Vec<(DnsQueryId, Result<Vec<IpAddr>>)>
+    where
+        F: FnMut(SocketAddr, &[u8]) -> Result<()>,
+    {
+        let mut failed = Vec::new();
+        for (&id, query) in self.pending.iter_mut() {
+            if query.deadline > now {
+                continue;
+            }
+            if query.attempts > self.config.retries {
+                failed.push(id);
+                continue;
+            }
+
+            query.attempts += 1;
+            query.server = (query.server + 1) % self.config.servers.len();
+            query.deadline = now + self.config.timeout;
+            // A failed resend is retried like a lost reply
+            let _ = send(self.config.servers[query.server], &query.message);
+        }
+
+        failed
+            .into_iter()
+            .map(|id| {
+                self.pending.remove(&id);
+                let error = Error::NetworkError(format!("DNS query {} timed out", id));
+                (id, Err(error))
+            })
+            .collect()
+    }
+}
+
+/// Encode a recursive query for `name`
+fn encode_query(id: u16, name: &str, record_type: DnsRecordType) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RD.to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    message.extend_from_slice(&[0; 6]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    let name = name.strip_suffix('.').unwrap_or(name);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::NetworkError(format!(
+                "Invalid DNS name label {:?}",
+                label
+            )));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    if message.len() - HEADER_LEN > MAX_NAME_LEN {
+        return Err(Error::NetworkError("DNS name too long".to_string()));
+    }
+
+    message.extend_from_slice(&record_type.code().to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Parse the header and address answers of a reply
+fn parse_reply(message: &[u8]) -> Result<DnsReply> {
+    let malformed = || Error::NetworkError("Malformed DNS reply".to_string());
+    let read_u16 = |offset: usize| -> Result<u16> {
+        message
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(malformed)
+    };
+
+    let flags = read_u16(2)?;
+    if flags & FLAG_QR == 0 {
+        return Err(malformed());
+    }
+    // Every query asks one question, which the reply repeats
+    if read_u16(4)? != 1 {
+        return Err(malformed());
+    }
+    let answers = read_u16(6)?;
+
+    let mut offset = skip_name(message, HEADER_LEN)? + 4;
+    if offset > message.len() {
+        return Err(malformed());
+    }
+    let question = HEADER_LEN..offset;
+
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let record_type = read_u16(offset)?;
+        let class = read_u16(offset + 2)?;
+        let data_len = read_u16(offset + 8)? as usize;
+        let data = message
+            .get(offset + 10..offset + 10 + data_len)
+            .ok_or_else(malformed)?;
+        offset += 10 + data_len;
+
+        if class != CLASS_IN {
+            continue;
+        }
+        match (record_type, data_len) {
+            (1, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(
+                data[0], data[1], data[2], data[3],
+            ))),
+            (28, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            // CNAMEs and other records are skipped
+            _ => {}
+        }
+    }
+
+    Ok(DnsReply {
+        id: read_u16(0)?,
+        rcode: (flags & 0x000F) as u8,
+        question,
+        addrs,
+    })
+}
+
+/// Offset just past the (possibly compressed) name at `offset`
+fn skip_name(message: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        let len = *message
+            .get(offset)
+            .ok_or_else(|| Error::NetworkError("Truncated DNS name".to_string()))?;
+        match len {
+            0 => return Ok(offset + 1),
+            // A compression pointer ends the name
+            len if len & 0xC0 == 0xC0 => return Ok(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn server(last: u8) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), 53)
+    }
+
+    /// Reply to `query` with the given answers, names compressed to the question
+    fn reply(query: &[u8], rcode: u8, answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut message = query.to_vec();
+        message[2] = 0x81;
+        message[3] = 0x80 | rcode;
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (record_type, data) in answers {
+            message.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+            message.extend_from_slice(&record_type.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&300u32.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+        message
+    }
+
+    #[test]
+    fn test_query_encoding_and_reply_parsing() {
+        let query = encode_query(0x1234, "example.com.", DnsRecordType::Aaaa).unwrap();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[HEADER_LEN..HEADER_LEN + 8], b"\x07example");
+        assert_eq!(&query[query.len() - 4..], &[0, 28, 0, 1]);
+        assert!(encode_query(1, "bad..name", DnsRecordType::A).is_err());
+
+        let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let message = reply(
+            &query,
+            0,
+            &[
+                (5, b"\x03www\x00"),
+                (1, &[192, 0, 2, 1]),
+                (28, &v6.octets()),
+            ],
+        );
+        let parsed = parse_reply(&message).unwrap();
+        assert_eq!(parsed.id, 0x1234);
+        assert_eq!(
+            parsed.addrs,
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V6(v6)]
+        );
+        assert!(parse_reply(&message[..message.len() - 1]).is_err());
+        assert!(parse_reply(&query).is_err());
+    }
+
+    #[test]
+    fn test_round_robin_retries_and_timeouts() {
+        let config = DnsConfig {
+            servers: vec![server(1), server(2)],
+            timeout: Duration::from_millis(100),
+            retries: 1,
+        };
+        let mut resolver = DnsResolver::new(config).unwrap();
+        let sent = RefCell::new(Vec::new());
+        let send = |server, message: &[u8]| {
+            sent.borrow_mut().push((server, message.to_vec()));
+            Ok(())
+        };
+
+        let now = Instant::now();
+        let first = resolver
+            .start("a.test", DnsRecordType::A, now, send)
+            .unwrap();
+        let second = resolver
+            .start("b.test", DnsRecordType::A, now, send)
+            .unwrap();
+        assert_eq!(sent.borrow()[0].0, server(1));
+        assert_eq!(sent.borrow()[1].0, server(2));
+
+        // First lookup answered, but only the server asked may answer, and
+        // only about the question asked, whatever the case of the name
+        let mut answer = reply(&sent.borrow()[0].1, 0, &[(1, &[192, 0, 2, 7])]);
+        assert!(resolver.on_reply(server(2), &answer).is_none());
+        answer[HEADER_LEN + 9] = 28; // AAAA instead of A
+        assert!(resolver.on_reply(server(1), &answer).is_none());
+        answer[HEADER_LEN + 9] = 1;
+        answer[HEADER_LEN + 1] = b'A';
+        let (id, result) = resolver.on_reply(server(1), &answer).unwrap();
+        assert_eq!(id, first);
+        assert_eq!(
+            result.unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))]
+        );
+
+        // Second lookup is retried once on the other server, then fails
+        let later = now + Duration::from_millis(150);
+        assert!(resolver.expire(later, send).is_empty());
+        assert_eq!(sent.borrow()[2].0, server(1));
+        let failed = resolver.expire(later + Duration::from_millis(150), send);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, second);
+        assert!(failed[0].1.is_err());
+        assert_eq!(resolver.pending(), 0);
+    }
+
+    #[test]
+    fn test_queries_leave_from_fresh_source_ports() {
+        use crate::memory::MbufPool;
+        use crate::poll::TxQueue;
+        use crate::udp::UdpStack;
+        use crate::Config;
+        use std::sync::Arc;
+
+        let pool = Arc::new(MbufPool::new("dns".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_tx_pool(pool);
+        let id = stack.create_socket("10.0.0.9:0".parse().unwrap()).unwrap();
+        let tx_queue = Arc::new(TxQueue::in_memory(0));
+        stack
+            .get_socket_mut(id)
+            .unwrap()
+            .bind_tx_queue(tx_queue.clone());
+        let socket = stack.get_socket(id).unwrap();
+        let mut resolver = DnsResolver::new(DnsConfig {
+            servers: vec![server(1)],
+            ..DnsConfig::default()
+        })
+        .unwrap();
+        let query = |resolver: &mut DnsResolver| {
+            let id = resolver.query(socket, "a.test", DnsRecordType::A).unwrap();
+            let frame = tx_queue.sent_frames().pop().unwrap();
+            (id, u16::from_be_bytes([frame[34], frame[35]]))
+        };
+
+        // A second lookup shares the port of the first, still in flight
+        let (first, first_port) = query(&mut resolver);
+        let (second, second_port) = query(&mut resolver);
+        assert_ne!(first, second);
+        assert_eq!(first_port, second_port);
+        resolver.cancel(first);
+        resolver.cancel(second);
+        let (_, third_port) = query(&mut resolver);
+        assert_ne!(third_port, first_port);
+        assert_eq!(socket.source_port(server(1)).unwrap(), third_port);
+    }
+}
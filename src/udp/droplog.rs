@@ -0,0 +1,228 @@
+//! Recent drop events of an RX queue
+//!
+//! Counters say how many frames were dropped and why, but a postmortem
+//! also wants to know which flows. Each RX queue keeps a [`DropLog`], a
+//! fixed ring of the last drops with their reason, 5-tuple and time,
+//! written from the datapath without locks: a writer claims a slot with
+//! one atomic add and fills it in under a per-slot sequence number, and
+//! readers skip slots caught mid-write. An event takes 32 bytes, so the
+//! default [`DEFAULT_DROP_LOG_SIZE`] costs 8 KiB per queue. The logs are
+//! read with [`crate::ControlHandle::drop_events`] and dumped to the log on
+//! [`crate::Xpdk::shutdown`].
+
+use super::{DropReason, ETHERTYPE_IPV4, IPPROTO_TCP, IPPROTO_UDP};
+use crate::memory::Mbuf;
+use crate::utils::time::{monotonic_now, Timestamp};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// Drop events kept per RX queue by default
+pub const DEFAULT_DROP_LOG_SIZE: usize = 256;
+
+/// Protocol and addresses of a dropped frame, zero where not parseable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FlowKey {
+    protocol: u8,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+}
+
+impl FlowKey {
+    /// Read the flow of an Ethernet frame; ports only from unfragmented TCP and UDP
+    pub(crate) fn from_frame(frame: &[u8]) -> Self {
+        let mut key = Self {
+            protocol: 0,
+            src: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            dst: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+        if frame.len() < 34 || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_IPV4 {
+            return key;
+        }
+        let ip = &frame[14..];
+        let ihl = (ip[0] & 0x0f) as usize * 4;
+        key.protocol = ip[9];
+        key.src
+            .set_ip(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]));
+        key.dst
+            .set_ip(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]));
+        let fragment_offset = u16::from_be_bytes([ip[6], ip[7]]) & 0x1fff;
+        if matches!(key.protocol, IPPROTO_TCP | IPPROTO_UDP)
+            && fragment_offset == 0
+            && ihl >= 20
+            && ip.len() >= ihl + 4
+        {
+            key.src.set_port(u16::from_be_bytes([ip[ihl], ip[ihl + 1]]));
+            key.dst
+                .set_port(u16::from_be_bytes([ip[ihl + 2], ip[ihl + 3]]));
+        }
+        key
+    }
+}
+
+/// One dropped frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DropEvent {
+    pub reason: DropReason,
+    /// IP protocol, 0 if the frame was not IPv4
+    pub protocol: u8,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    /// Monotonic time of the drop
+    pub timestamp: Timestamp,
+}
+
+impl fmt::Display for DropEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} proto {} {} -> {}",
+            self.timestamp,
+            self.reason.as_str(),
+            self.protocol,
+            self.src,
+            self.dst
+        )
+    }
+}
+
+/// Event slot; `seq` is odd while written and `2 * (n + 1)` once event `n` is in
+#[derive(Default)]
+struct Slot {
+    seq: AtomicU64,
+    timestamp: AtomicU64,
+    /// Source address in the high half, destination in the low half
+    addrs: AtomicU64,
+    /// Source port, destination port, protocol and reason index
+    ports: AtomicU64,
+}
+
+/// Lock-free ring of the last drops of a queue
+pub struct DropLog {
+    slots: Box<[Slot]>,
+    mask: u64,
+    /// Events ever recorded
+    next: AtomicU64,
+}
+
+impl DropLog {
+    /// Log keeping the last `capacity` drops, rounded up to a power of two
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        Self {
+            slots: (0..capacity).map(|_| Slot::default()).collect(),
+            mask: capacity as u64 - 1,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// Events kept at most
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Events ever recorded, including those overwritten since
+    pub fn recorded(&self) -> u64 {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    /// Record that `mbuf` was dropped for `reason`
+    pub fn record(&self, reason: DropReason, mbuf: &Mbuf) {
+        self.record_flow(reason, FlowKey::from_frame(mbuf.data()), monotonic_now());
+    }
+
+    pub(crate) fn record_flow(&self, reason: DropReason, flow: FlowKey, now: Timestamp) {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[(n & self.mask) as usize];
+        slot.seq.store(2 * n + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.timestamp.store(now, Ordering::Relaxed);
+        slot.addrs.store(
+            (u32::from(*flow.src.ip()) as u64) << 32 | u32::from(*flow.dst.ip()) as u64,
+            Ordering::Relaxed,
+        );
+        slot.ports.store(
+            (flow.src.port() as u64) << 48
+                | (flow.dst.port() as u64) << 32
+                | (flow.protocol as u64) << 8
+                | reason.index() as u64,
+            Ordering::Relaxed,
+        );
+        slot.seq.store(2 * n + 2, Ordering::Release);
+    }
+
+    /// Kept events, oldest first
+    ///
+    /// Slots being written while read are left out.
+    pub fn events(&self) -> Vec<DropEvent> {
+        let mut events: Vec<(u64, DropEvent)> = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                let seq = slot.seq.load(Ordering::Acquire);
+                let timestamp = slot.timestamp.load(Ordering::Relaxed);
+                let addrs = slot.addrs.load(Ordering::Relaxed);
+                let ports = slot.ports.load(Ordering::Relaxed);
+                fence(Ordering::Acquire);
+                if seq == 0 || seq % 2 == 1 || slot.seq.load(Ordering::Relaxed) != seq {
+                    return None;
+                }
+                let reason = *DropReason::ALL.get((ports & 0xff) as usize)?;
+                let event = DropEvent {
+                    reason,
+                    protocol: (ports >> 8) as u8,
+                    src: SocketAddrV4::new(
+                        Ipv4Addr::from((addrs >> 32) as u32),
+                        (ports >> 48) as u16,
+                    ),
+                    dst: SocketAddrV4::new(Ipv4Addr::from(addrs as u32), (ports >> 32) as u16),
+                    timestamp,
+                };
+                Some((seq, event))
+            })
+            .collect();
+        events.sort_unstable_by_key(|(seq, _)| *seq);
+        events.into_iter().map(|(_, event)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::testing::FrameBuilder;
+
+    #[test]
+    fn test_keeps_last_drops_in_order() {
+        let log = DropLog::new(3);
+        assert_eq!(log.capacity(), 4);
+        assert!(log.events().is_empty());
+
+        for port in 1000..1006 {
+            let flow = FlowKey::from_frame(&FrameBuilder::to_port(port).payload(b"x").build());
+            log.record_flow(DropReason::NoSocket, flow, port as u64);
+        }
+        log.record_flow(DropReason::Malformed, FlowKey::from_frame(&[0u8; 20]), 2000);
+
+        let events = log.events();
+        assert_eq!(log.recorded(), 7);
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[0],
+            DropEvent {
+                reason: DropReason::NoSocket,
+                protocol: IPPROTO_UDP,
+                src: "10.0.0.2:6000".parse().unwrap(),
+                dst: "10.0.0.1:1003".parse().unwrap(),
+                timestamp: 1003,
+            }
+        );
+        assert_eq!(events[2].dst.port(), 1005);
+        assert_eq!(events[3].reason, DropReason::Malformed);
+        assert_eq!(events[3].protocol, 0);
+        assert!(events[3].src.ip().is_unspecified());
+        assert_eq!(
+            events[0].to_string(),
+            "1003 no_socket proto 17 10.0.0.2:6000 -> 10.0.0.1:1003"
+        );
+    }
+}
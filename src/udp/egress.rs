@@ -0,0 +1,220 @@
+//! DSCP-based egress traffic classes
+//!
+//! A sent datagram is marked with the DSCP of its socket, set with
+//! [`super::UdpSocket::set_dscp`], or of the buffer itself, set with
+//! [`super::TxBuffer::set_dscp`], and the [`TrafficClassMap`] of the socket
+//! turns that DSCP into a [`TrafficClass`]. A socket sends each class on
+//! the TX queue bound for it with [`super::UdpSocket::bind_class_queue`],
+//! or on its own TX queue, so voice and control traffic can leave on a
+//! queue of their own ahead of bulk sends. The map comes from
+//! [`crate::Config::traffic_classes`], where every DSCP is best effort by
+//! default.
+//!
+//! Sent datagrams are counted by class in the stack's [`EgressStats`], to
+//! check end to end that marking and queue mapping do what was intended.
+
+use crate::utils::counter::Counter;
+use crate::{Error, Result};
+
+/// Number of traffic classes
+pub const TRAFFIC_CLASSES: usize = 5;
+
+/// Number of DSCP code points
+const DSCP_COUNT: usize = 64;
+
+/// Traffic class of sent datagrams, lowest priority first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TrafficClass {
+    Background,
+    #[default]
+    BestEffort,
+    Video,
+    Voice,
+    /// Network control and signalling
+    Control,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; TRAFFIC_CLASSES] = [
+        TrafficClass::Background,
+        TrafficClass::BestEffort,
+        TrafficClass::Video,
+        TrafficClass::Voice,
+        TrafficClass::Control,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TrafficClass::Background => "background",
+            TrafficClass::BestEffort => "best-effort",
+            TrafficClass::Video => "video",
+            TrafficClass::Voice => "voice",
+            TrafficClass::Control => "control",
+        }
+    }
+}
+
+/// Mapping from DSCP code point to traffic class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficClassMap {
+    map: [TrafficClass; DSCP_COUNT],
+}
+
+impl Default for TrafficClassMap {
+    fn default() -> Self {
+        Self {
+            map: [TrafficClass::BestEffort; DSCP_COUNT],
+        }
+    }
+}
+
+impl TrafficClassMap {
+    /// Classes along RFC 4594: CS1 background, AF2x-AF4x and CS3-CS4 video,
+    /// EF and CS5 voice, CS6-CS7 control, the rest best effort
+    pub fn standard() -> Self {
+        let mut map = Self::default();
+        map.map[8] = TrafficClass::Background;
+        for class in 2..=4u8 {
+            map.map[(class << 3) as usize] = TrafficClass::Video;
+            for drop_precedence in 1..=3u8 {
+                map.map[(class << 3 | drop_precedence << 1) as usize] = TrafficClass::Video;
+            }
+        }
+        for dscp in [super::DSCP_EF, 40] {
+            map.map[dscp as usize] = TrafficClass::Voice;
+        }
+        for dscp in [48, 56] {
+            map.map[dscp as usize] = TrafficClass::Control;
+        }
+        map
+    }
+
+    /// Assign a DSCP code point to a class
+    pub fn with_dscp(mut self, dscp: u8, class: TrafficClass) -> Result<Self> {
+        if dscp as usize >= DSCP_COUNT {
+            return Err(Error::InvalidConfig(format!("DSCP {} out of range", dscp)));
+        }
+        self.map[dscp as usize] = class;
+        Ok(self)
+    }
+
+    /// Class datagrams marked `dscp` are sent in
+    pub fn class_for(&self, dscp: u8) -> TrafficClass {
+        self.map[(dscp & 0x3F) as usize]
+    }
+}
+
+/// Counters of one traffic class
+#[derive(Debug, Default)]
+pub struct ClassCounters {
+    pub packets: Counter,
+    /// Payload bytes
+    pub bytes: Counter,
+}
+
+/// Sent datagrams by traffic class
+#[derive(Debug, Default)]
+pub struct EgressStats {
+    classes: [ClassCounters; TRAFFIC_CLASSES],
+}
+
+impl EgressStats {
+    pub fn class(&self, class: TrafficClass) -> &ClassCounters {
+        &self.classes[class.index()]
+    }
+
+    pub(crate) fn record(&self, class: TrafficClass, bytes: usize) {
+        let counters = self.class(class);
+        counters.packets.inc();
+        counters.bytes.add(bytes as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::poll::TxQueue;
+    use crate::udp::{ones_complement, HeaderTemplate, UdpSocket, DSCP_EF, TX_HEADROOM};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_standard_classes_and_marking() {
+        let map = TrafficClassMap::standard();
+        assert_eq!(map.class_for(0), TrafficClass::BestEffort);
+        assert_eq!(map.class_for(DSCP_EF), TrafficClass::Voice);
+        assert_eq!(map.class_for(34), TrafficClass::Video); // AF41
+        assert_eq!(map.class_for(48), TrafficClass::Control);
+        assert_eq!(map.class_for(8), TrafficClass::Background);
+        assert_eq!(
+            TrafficClassMap::default().class_for(DSCP_EF),
+            TrafficClass::BestEffort
+        );
+        let map = map.with_dscp(34, TrafficClass::Voice).unwrap();
+        assert_eq!(map.class_for(34), TrafficClass::Voice);
+        assert!(map.with_dscp(64, TrafficClass::Voice).is_err());
+
+        // The marking is in the TOS byte and covered by the header checksum
+        let template = HeaderTemplate::new(
+            [0; 6],
+            [0xFF; 6],
+            "10.0.0.1:5060".parse().unwrap(),
+            "10.0.0.2:5060".parse().unwrap(),
+            false,
+        )
+        .with_dscp(DSCP_EF);
+        let mut frame = [0; TX_HEADROOM];
+        template.write(&mut frame, 3, 7);
+        assert_eq!(frame[15] >> 2, DSCP_EF);
+        assert_eq!(ones_complement(0, &frame[14..34]), 0xFFFF);
+
+        let stats = EgressStats::default();
+        stats.record(TrafficClass::Voice, 3);
+        stats.record(TrafficClass::Voice, 5);
+        let voice = stats.class(TrafficClass::Voice);
+        assert_eq!((voice.packets.get(), voice.bytes.get()), (2, 8));
+        assert_eq!(stats.class(TrafficClass::BestEffort).packets.get(), 0);
+    }
+
+    #[test]
+    fn test_classes_leave_on_their_queues() {
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 4, 2048).unwrap());
+        let default_queue = Arc::new(TxQueue::in_memory(0));
+        let voice_queue = Arc::new(TxQueue::in_memory(1));
+        let mut socket = UdpSocket::new("10.0.0.1:5060".parse().unwrap(), 16, 1).unwrap();
+        socket.bind_tx_pool(pool.clone());
+        socket.bind_tx_queue(default_queue.clone());
+        socket.bind_class_queue(TrafficClass::Voice, voice_queue.clone());
+        socket.set_traffic_classes(TrafficClassMap::standard());
+        let dst = "10.0.0.2:5060".parse().unwrap();
+
+        // Unmarked, then marked EF by the socket, then CS6 by the buffer
+        socket.send(dst, b"bulk").unwrap();
+        socket.set_dscp(DSCP_EF);
+        socket.send(dst, b"voice").unwrap();
+        let mut buffer = socket.alloc_tx_buffer(7).unwrap();
+        buffer.payload_mut().copy_from_slice(b"control");
+        buffer.set_dscp(48);
+        socket.send_prepared(buffer, dst).unwrap();
+
+        let tos = |frames: Vec<Vec<u8>>| frames.iter().map(|f| f[15] >> 2).collect::<Vec<_>>();
+        assert_eq!(tos(voice_queue.sent_frames()), [DSCP_EF]);
+        // Control has no queue of its own and falls back to the socket's
+        assert_eq!(tos(default_queue.sent_frames()), [0, 48]);
+
+        let stats = socket.egress_stats();
+        let counters = |class| {
+            let counters = stats.class(class);
+            (counters.packets.get(), counters.bytes.get())
+        };
+        assert_eq!(counters(TrafficClass::BestEffort), (1, 4));
+        assert_eq!(counters(TrafficClass::Voice), (1, 5));
+        assert_eq!(counters(TrafficClass::Control), (1, 7));
+        assert_eq!(counters(TrafficClass::Video), (0, 0));
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
@@ -0,0 +1,372 @@
+//! Randomized source ports
+//!
+//! A socket with source port randomization sends to each destination from
+//! its own port drawn from [`EPHEMERAL_PORTS`] instead of its bound port, so
+//! that its flows cannot be linked by port and do not collide behind NAT
+//! (RFC 6056). [`EphemeralPorts`] is shared by the sockets of a stack: it
+//! never hands out a port a socket is bound to or another flow holds, and
+//! tells the stack which socket receives replies to a port. Ports can also
+//! be pinned for a destination or cycled to a fresh random one.
+//!
+//! A random port goes back to the range once its flow has neither sent nor
+//! received for the flow idle timeout, [`DEFAULT_EPHEMERAL_FLOW_IDLE`] unless
+//! set with [`EphemeralPorts::set_flow_idle`], so a client talking to many
+//! destinations does not run the range dry. Pinned ports are kept until
+//! cycled or released. Flows are charged to the stack's memory budget as
+//! the `ephemeral-ports` table, see [`EphemeralPorts::set_budget`].
+
+use crate::memory::{MemoryBudget, TableAccount};
+use crate::utils::rand::Rng;
+use crate::utils::time::{monotonic_now, Timestamp};
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddrV4;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Ports source ports are drawn from (RFC 6335 dynamic range)
+pub const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// Time without traffic after which a flow's random port is freed, the
+/// shortest UDP mapping timeout RFC 4787 allows
+pub const DEFAULT_EPHEMERAL_FLOW_IDLE: Duration = Duration::from_secs(120);
+
+/// Budgeted bytes of one flow and its owner entry
+pub const EPHEMERAL_FLOW_BYTES: usize = std::mem::size_of::<((u16, SocketAddrV4), Flow)>()
+    + std::mem::size_of::<(u16, (u16, SocketAddrV4))>();
+
+/// Random picks tried before scanning for a free port
+const RANDOM_ATTEMPTS: usize = 32;
+
+#[derive(Debug)]
+struct Flow {
+    port: u16,
+    /// Last send or reply, a [`monotonic_now`] timestamp
+    last_used: Timestamp,
+    pinned: bool,
+}
+
+#[derive(Debug)]
+struct PortState {
+    /// Sockets bound to each port
+    bound: HashMap<u16, usize>,
+    /// Socket and destination of each allocated port
+    owners: HashMap<u16, (u16, SocketAddrV4)>,
+    /// Source port of each socket and destination
+    flows: HashMap<(u16, SocketAddrV4), Flow>,
+    account: Option<Arc<TableAccount>>,
+    rng: Rng,
+}
+
+impl PortState {
+    fn is_free(&self, port: u16) -> bool {
+        !self.bound.contains_key(&port) && !self.owners.contains_key(&port)
+    }
+
+    fn allocate(&mut self, range: &RangeInclusive<u16>) -> Result<u16> {
+        let start = *range.start() as u64;
+        let len = *range.end() as u64 - start + 1;
+        for _ in 0..RANDOM_ATTEMPTS {
+            let port = (start + self.rng.below(len)) as u16;
+            if self.is_free(port) {
+                return Ok(port);
+            }
+        }
+        let offset = self.rng.below(len);
+        (0..len)
+            .map(|i| (start + (offset + i) % len) as u16)
+            .find(|&port| self.is_free(port))
+            .ok_or_else(|| Error::NetworkError("No free source port".to_string()))
+    }
+
+    fn release(&mut self, socket_id: u16, dst: SocketAddrV4) {
+        if let Some(flow) = self.flows.remove(&(socket_id, dst)) {
+            self.owners.remove(&flow.port);
+            self.shrink(1);
+        }
+    }
+
+    fn shrink(&self, flows: usize) {
+        if let Some(account) = &self.account {
+            account.shrink(flows, flows * EPHEMERAL_FLOW_BYTES);
+        }
+    }
+
+    /// Add a flow the state does not hold yet
+    fn insert(&mut self, socket_id: u16, dst: SocketAddrV4, port: u16, pinned: bool) -> Result<()> {
+        if let Some(account) = &self.account {
+            account.grow(1, EPHEMERAL_FLOW_BYTES)?;
+        }
+        self.owners.insert(port, (socket_id, dst));
+        self.flows.insert(
+            (socket_id, dst),
+            Flow {
+                port,
+                last_used: monotonic_now(),
+                pinned,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Source ports allocated to the flows of a stack's sockets
+#[derive(Debug)]
+pub struct EphemeralPorts {
+    range: RangeInclusive<u16>,
+    /// Flow idle timeout in nanoseconds
+    flow_idle: AtomicU64,
+    state: Mutex<PortState>,
+}
+
+impl EphemeralPorts {
+    /// Allocate source ports from `range`
+    pub fn new(range: RangeInclusive<u16>) -> Result<Self> {
+        if range.is_empty() {
+            return Err(Error::InvalidConfig("Empty source port range".to_string()));
+        }
+        Ok(Self {
+            range,
+            flow_idle: AtomicU64::new(DEFAULT_EPHEMERAL_FLOW_IDLE.as_nanos() as u64),
+            state: Mutex::new(PortState {
+                bound: HashMap::new(),
+                owners: HashMap::new(),
+                flows: HashMap::new(),
+                account: None,
+                rng: Rng::for_component("ephemeral-ports"),
+            }),
+        })
+    }
+
+    /// Draw ports from a generator seeded with `seed`, for reproducible runs
+    pub fn reseed(&self, seed: u64) {
+        self.state.lock().rng = Rng::seeded(seed);
+    }
+
+    /// Charge each flow [`EPHEMERAL_FLOW_BYTES`] of `budget`, as the
+    /// `ephemeral-ports` table
+    ///
+    /// The flows already held move to the new budget; fails with
+    /// [`Error::BudgetExceeded`] and keeps the old one if it cannot hold them.
+    pub fn set_budget(&self, budget: &Arc<MemoryBudget>) -> Result<()> {
+        let mut state = self.state.lock();
+        let account = budget.register_table("ephemeral-ports");
+        let flows = state.flows.len();
+        account.grow(flows, flows * EPHEMERAL_FLOW_BYTES)?;
+        state.account = Some(account);
+        Ok(())
+    }
+
+    /// Free random ports of flows idle for `idle`
+    pub fn set_flow_idle(&self, idle: Duration) {
+        self.flow_idle
+            .store(idle.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn flow_idle(&self) -> Duration {
+        Duration::from_nanos(self.flow_idle.load(Ordering::Relaxed))
+    }
+
+    /// Socket receiving datagrams sent to source port `port`
+    pub fn owner(&self, port: u16) -> Option<u16> {
+        self.state.lock().owners.get(&port).map(|&(owner, _)| owner)
+    }
+
+    /// Socket a datagram to source port `port` is delivered to, keeping
+    /// the flow alive
+    pub(crate) fn receive(&self, port: u16) -> Option<u16> {
+        let mut state = self.state.lock();
+        let &(owner, dst) = state.owners.get(&port)?;
+        if let Some(flow) = state.flows.get_mut(&(owner, dst)) {
+            flow.last_used = monotonic_now();
+        }
+        Some(owner)
+    }
+
+    /// Free the random ports of flows idle at `now`, a [`monotonic_now`]
+    /// timestamp, returning their sockets and destinations
+    pub(crate) fn expire(&self, now: Timestamp) -> Vec<(u16, SocketAddrV4)> {
+        let idle = self.flow_idle.load(Ordering::Relaxed);
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let mut expired = Vec::new();
+        state.flows.retain(|&key, flow| {
+            if flow.pinned || now.saturating_sub(flow.last_used) < idle {
+                return true;
+            }
+            state.owners.remove(&flow.port);
+            expired.push(key);
+            false
+        });
+        state.shrink(expired.len());
+        expired
+    }
+
+    /// Number of allocated source ports
+    pub fn in_use(&self) -> usize {
+        self.state.lock().owners.len()
+    }
+
+    /// Ports sockets are bound to and source ports allocated to flows
+    pub fn local_ports(&self) -> BTreeSet<u16> {
+        let state = self.state.lock();
+        state
+            .bound
+            .keys()
+            .chain(state.owners.keys())
+            .copied()
+            .collect()
+    }
+
+    /// Keep `port` from being handed out while a socket is bound to it
+    pub(crate) fn reserve_bound(&self, port: u16) {
+        *self.state.lock().bound.entry(port).or_insert(0) += 1;
+    }
+
+    pub(crate) fn release_bound(&self, port: u16) {
+        let mut state = self.state.lock();
+        if let Some(count) = state.bound.get_mut(&port) {
+            *count -= 1;
+            if *count == 0 {
+                state.bound.remove(&port);
+            }
+        }
+    }
+
+    /// Source port of the flow towards `dst`, if one is allocated or pinned
+    pub(crate) fn get(&self, socket_id: u16, dst: SocketAddrV4) -> Option<u16> {
+        self.state
+            .lock()
+            .flows
+            .get(&(socket_id, dst))
+            .map(|flow| flow.port)
+    }
+
+    /// Source port of the flow towards `dst`, allocating one if needed
+    pub(crate) fn get_or_allocate(&self, socket_id: u16, dst: SocketAddrV4) -> Result<u16> {
+        let mut state = self.state.lock();
+        if let Some(flow) = state.flows.get_mut(&(socket_id, dst)) {
+            flow.last_used = monotonic_now();
+            return Ok(flow.port);
+        }
+        let port = state.allocate(&self.range)?;
+        state.insert(socket_id, dst, port, false)?;
+        Ok(port)
+    }
+
+    /// Replace the source port towards `dst` with a fresh random one
+    pub(crate) fn cycle(&self, socket_id: u16, dst: SocketAddrV4) -> Result<u16> {
+        let mut state = self.state.lock();
+        let port = state.allocate(&self.range)?;
+        state.release(socket_id, dst);
+        state.insert(socket_id, dst, port, false)?;
+        Ok(port)
+    }
+
+    /// Send to `dst` from `port`, which may lie outside the random range
+    pub(crate) fn pin(&self, socket_id: u16, dst: SocketAddrV4, port: u16) -> Result<()> {
+        let mut state = self.state.lock();
+        if let Some(flow) = state.flows.get_mut(&(socket_id, dst)) {
+            if flow.port == port {
+                flow.pinned = true;
+                return Ok(());
+            }
+        }
+        if !state.is_free(port) {
+            return Err(Error::NetworkError(format!(
+                "Source port {} is already in use",
+                port
+            )));
+        }
+        state.release(socket_id, dst);
+        state.insert(socket_id, dst, port, true)
+    }
+
+    /// Free every source port of a socket
+    pub(crate) fn release_socket(&self, socket_id: u16) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let before = state.flows.len();
+        state.flows.retain(|&(owner, _), flow| {
+            if owner == socket_id {
+                state.owners.remove(&flow.port);
+            }
+            owner != socket_id
+        });
+        state.shrink(before - state.flows.len());
+    }
+}
+
+impl Default for EphemeralPorts {
+    fn default() -> Self {
+        Self::new(EPHEMERAL_PORTS).expect("ephemeral port range is not empty")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_ports_avoid_bound_and_allocated() {
+        let ports = EphemeralPorts::new(40000..=40003).unwrap();
+        let dst = |port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port);
+        ports.reserve_bound(40000);
+
+        let first = ports.get_or_allocate(1, dst(53)).unwrap();
+        assert_ne!(first, 40000);
+        assert_eq!(ports.get_or_allocate(1, dst(53)).unwrap(), first);
+        assert_eq!(ports.owner(first), Some(1));
+        let second = ports.get_or_allocate(2, dst(53)).unwrap();
+        let third = ports.get_or_allocate(1, dst(54)).unwrap();
+        assert_eq!(
+            [first, second, third, 40000]
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            4
+        );
+        assert!(ports.get_or_allocate(1, dst(55)).is_err());
+
+        // Pinning takes a port outside the range, cycling frees the old one
+        assert!(ports.pin(2, dst(53), 40000).is_err());
+        ports.pin(2, dst(53), 5353).unwrap();
+        assert_eq!((ports.owner(second), ports.owner(5353)), (None, Some(2)));
+        assert_eq!(ports.cycle(1, dst(53)).unwrap(), second);
+        assert_eq!(ports.owner(first), None);
+
+        ports.release_socket(1);
+        assert_eq!(ports.in_use(), 1);
+        ports.release_bound(40000);
+        assert!((40000..=40003).contains(&ports.get_or_allocate(3, dst(53)).unwrap()));
+    }
+
+    #[test]
+    fn test_idle_flows_free_their_ports() {
+        let ports = EphemeralPorts::new(40000..=40001).unwrap();
+        ports.set_flow_idle(Duration::from_secs(1));
+        let dst = |port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port);
+        let quiet = ports.get_or_allocate(1, dst(53)).unwrap();
+        let answered = ports.get_or_allocate(1, dst(54)).unwrap();
+        ports.pin(2, dst(53), 5353).unwrap();
+        assert!(ports.get_or_allocate(1, dst(55)).is_err());
+
+        // Nothing is idle yet; a reply keeps its flow alive
+        let later = monotonic_now() + Duration::from_millis(1500).as_nanos() as u64;
+        assert!(ports.expire(monotonic_now()).is_empty());
+        assert_eq!(ports.receive(answered), Some(1));
+        {
+            let mut state = ports.state.lock();
+            let flow = state.flows.get_mut(&(1, dst(54))).unwrap();
+            flow.last_used = later - 100;
+        }
+        assert_eq!(ports.expire(later), vec![(1, dst(53))]);
+        assert_eq!((ports.owner(quiet), ports.owner(5353)), (None, Some(2)));
+        assert_eq!(ports.get_or_allocate(1, dst(55)).unwrap(), quiet);
+        assert_eq!(ports.get(1, dst(53)), None);
+    }
+}
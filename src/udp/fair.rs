@@ -0,0 +1,149 @@
+//! Weighted fair receive across sockets
+//!
+//! `UdpStack::recv_fair` fills one application batch from every socket in
+//! deficit round robin, so a flooding socket cannot starve the others in a
+//! single-threaded receive loop. A socket visited with no credit left is
+//! credited `weight * FAIR_QUANTUM` payload bytes and dequeues until the
+//! credit is spent; the last packet may overdraw it, and the debt is carried
+//! into the next credit. A socket found empty forfeits its credit. Credits
+//! and the socket to resume at persist between calls, so batches that fill
+//! mid-round pick up where the previous one stopped.
+
+use std::collections::HashMap;
+
+/// Payload bytes a weight-1 socket is credited per round
+pub const FAIR_QUANTUM: usize = 1500;
+
+/// Deficit round robin state over socket IDs
+#[derive(Debug, Default)]
+pub(crate) struct FairScheduler {
+    weights: HashMap<u16, u32>,
+    deficits: HashMap<u16, i64>,
+    /// Socket the next batch starts at
+    resume: u16,
+}
+
+impl FairScheduler {
+    /// Set the weight of a socket; weight 0 skips it
+    pub(crate) fn set_weight(&mut self, socket_id: u16, weight: u32) {
+        self.weights.insert(socket_id, weight);
+    }
+
+    pub(crate) fn weight(&self, socket_id: u16) -> u32 {
+        self.weights.get(&socket_id).copied().unwrap_or(1)
+    }
+
+    pub(crate) fn remove(&mut self, socket_id: u16) {
+        self.weights.remove(&socket_id);
+        self.deficits.remove(&socket_id);
+    }
+
+    /// Dequeue up to `batch` items from `sockets`
+    ///
+    /// `pop` takes the next item of a socket, `None` once it is empty, and
+    /// `cost` gives the bytes an item is charged.
+    pub(crate) fn run<T, P, C>(
+        &mut self,
+        sockets: &[u16],
+        batch: usize,
+        mut pop: P,
+        cost: C,
+    ) -> Vec<(u16, T)>
+    where
+        P: FnMut(u16) -> Option<T>,
+        C: Fn(&T) -> usize,
+    {
+        let mut active: Vec<u16> = sockets
+            .iter()
+            .copied()
+            .filter(|&socket_id| self.weight(socket_id) > 0)
+            .collect();
+        active.sort_unstable();
+        let start = active.partition_point(|&socket_id| socket_id < self.resume);
+        active.rotate_left(start);
+
+        let mut out = Vec::with_capacity(batch);
+        while out.len() < batch && !active.is_empty() {
+            let mut stopped_at = None;
+            active.retain(|&socket_id| {
+                if out.len() >= batch {
+                    stopped_at.get_or_insert(socket_id);
+                    return true;
+                }
+                let quantum = self.weight(socket_id) as i64 * FAIR_QUANTUM as i64;
+                let deficit = self.deficits.entry(socket_id).or_insert(0);
+                if *deficit <= 0 {
+                    *deficit += quantum;
+                }
+                while *deficit > 0 {
+                    if out.len() >= batch {
+                        stopped_at.get_or_insert(socket_id);
+                        return true;
+                    }
+                    match pop(socket_id) {
+                        Some(item) => {
+                            *deficit -= cost(&item).max(1) as i64;
+                            out.push((socket_id, item));
+                        }
+                        None => {
+                            *deficit = 0;
+                            return false;
+                        }
+                    }
+                }
+                true
+            });
+            if let Some(socket_id) = stopped_at {
+                self.resume = socket_id;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_weighted_deficit_round_robin() {
+        let mut queues: HashMap<u16, VecDeque<usize>> = HashMap::new();
+        queues.insert(1, vec![500; 100].into());
+        queues.insert(2, vec![500; 100].into());
+        queues.insert(3, vec![1000; 2].into());
+        let mut scheduler = FairScheduler::default();
+        scheduler.set_weight(2, 2);
+
+        let mut run = |scheduler: &mut FairScheduler, batch| {
+            let mut counts = HashMap::new();
+            for (socket_id, _) in scheduler.run(
+                &[3, 1, 2],
+                batch,
+                |socket_id| queues.get_mut(&socket_id)?.pop_front(),
+                |&len| len,
+            ) {
+                *counts.entry(socket_id).or_insert(0) += 1;
+            }
+            counts
+        };
+
+        // One round: 3 packets of socket 1, 6 of socket 2, both of socket 3
+        let counts = run(&mut scheduler, 11);
+        assert_eq!((counts[&1], counts[&2], counts[&3]), (3, 6, 2));
+
+        // Socket 3 ran dry, the flooding sockets share 1:2
+        let counts = run(&mut scheduler, 90);
+        assert_eq!((counts[&1], counts[&2]), (30, 60));
+
+        // A batch filling mid-socket resumes there with the credit left
+        let counts = run(&mut scheduler, 1);
+        assert_eq!(counts[&1], 1);
+        let counts = run(&mut scheduler, 3);
+        assert_eq!((counts[&1], counts[&2]), (2, 1));
+
+        scheduler.set_weight(1, 0);
+        let counts = run(&mut scheduler, 10);
+        assert_eq!((counts.get(&1), counts[&2]), (None, 10));
+    }
+}
@@ -0,0 +1,414 @@
+//! Basic IPv4 forwarding between ports
+//!
+//! With [`crate::Config::forwarding`] set, received IPv4 frames that are not
+//! addressed to the router are looked up in a longest-prefix-match
+//! [`Route`] table. A routed frame has its TTL decremented, the IPv4 checksum
+//! patched incrementally (RFC 1624) and its MAC addresses rewritten for the
+//! next hop, and is sent out on the TX queue of the route's port. A frame
+//! whose TTL would reach zero is dropped and answered with an ICMP time
+//! exceeded message from the router address. Frames for the router itself,
+//! and frames no route matches, are left to the UDP stack.
+
+use super::checksum::{checksum_update_u16, ones_complement};
+use super::quarantine::Quarantine;
+use super::{
+    DropReason, EthernetHeader, Ipv4Header, ETHERTYPE_IPV4, ICMP_DEST_UNREACHABLE, IPPROTO_ICMP,
+};
+use crate::memory::{AllocClass, Mbuf, MbufPool, PacketType};
+use crate::poll::TxQueue;
+use crate::{Error, Result};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// ICMP time exceeded message type
+const ICMP_TIME_EXCEEDED: u8 = 11;
+
+/// ICMP error message types, which are never answered with an error
+const ICMP_ERROR_TYPES: [u8; 5] = [ICMP_DEST_UNREACHABLE, 4, 5, ICMP_TIME_EXCEEDED, 12];
+
+/// Bytes of the offending datagram quoted past its IP header
+const ICMP_QUOTE_LEN: usize = 8;
+
+/// Forwarding settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardingConfig {
+    /// Address of the router, the source of ICMP messages it sends
+    pub router_addr: Ipv4Addr,
+    /// Initial routes
+    pub routes: Vec<Route>,
+}
+
+/// Route towards an IPv4 prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub prefix: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Port frames are sent out on
+    pub port: u16,
+    /// MAC address of the sending port
+    pub src_mac: [u8; 6],
+    /// MAC address of the next hop
+    pub next_hop_mac: [u8; 6],
+}
+
+impl Route {
+    /// Route `prefix/prefix_len` out of `port` towards `next_hop_mac`
+    pub fn new(
+        prefix: Ipv4Addr,
+        prefix_len: u8,
+        port: u16,
+        src_mac: [u8; 6],
+        next_hop_mac: [u8; 6],
+    ) -> Self {
+        Self {
+            prefix,
+            prefix_len,
+            port,
+            src_mac,
+            next_hop_mac,
+        }
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    fn matches(&self, addr: Ipv4Addr) -> bool {
+        (u32::from(addr) ^ u32::from(self.prefix)) & self.mask() == 0
+    }
+}
+
+/// What the forwarder did with a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardVerdict {
+    /// Not forwarded; the caller still owns the mbuf
+    Local,
+    /// Sent out on this port and freed
+    Forwarded(u16),
+    /// Freed without being forwarded
+    Dropped(DropReason),
+}
+
+/// Forwarding counters
+#[derive(Debug, Default)]
+pub struct ForwardStats {
+    pub forwarded: AtomicUsize,
+    pub local: AtomicUsize,
+    pub ttl_exceeded: AtomicUsize,
+    pub icmp_sent: AtomicUsize,
+    /// Routed frames whose port has no TX queue or failed to send
+    pub unroutable: AtomicUsize,
+}
+
+/// Decision for one frame, before any I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Local,
+    Forward(Route),
+    Expired,
+}
+
+/// Route table and TX ports of an L3 forwarder
+pub struct Forwarder {
+    router_addr: Ipv4Addr,
+    /// Routes, longest prefix first
+    routes: RwLock<Vec<Route>>,
+    ports: RwLock<HashMap<u16, Arc<TxQueue>>>,
+    quarantine: Option<Arc<Quarantine>>,
+    stats: ForwardStats,
+}
+
+impl Forwarder {
+    /// Create a forwarder with the routes of `config`
+    pub fn new(config: &ForwardingConfig) -> Result<Self> {
+        let forwarder = Self {
+            router_addr: config.router_addr,
+            routes: RwLock::new(Vec::new()),
+            ports: RwLock::new(HashMap::new()),
+            quarantine: None,
+            stats: ForwardStats::default(),
+        };
+        for route in &config.routes {
+            forwarder.add_route(*route)?;
+        }
+        Ok(forwarder)
+    }
+
+    /// Copy the frames it drops into `quarantine` before freeing them
+    pub fn with_quarantine(mut self, quarantine: Arc<Quarantine>) -> Self {
+        self.quarantine = Some(quarantine);
+        self
+    }
+
+    /// Add a route, replacing one for the same prefix
+    pub fn add_route(&self, route: Route) -> Result<()> {
+        if route.prefix_len > 32 {
+            return Err(Error::InvalidConfig(format!(
+                "Invalid prefix length {} for {}",
+                route.prefix_len, route.prefix
+            )));
+        }
+
+        let mut routes = self.routes.write();
+        remove_route(&mut routes, route.prefix, route.prefix_len);
+        let index = routes.partition_point(|other| other.prefix_len >= route.prefix_len);
+        routes.insert(index, route);
+        Ok(())
+    }
+
+    /// Remove the route for `prefix/prefix_len`, returning whether one existed
+    pub fn remove_route(&self, prefix: Ipv4Addr, prefix_len: u8) -> bool {
+        remove_route(&mut self.routes.write(), prefix, prefix_len)
+    }
+
+    /// Longest-prefix route towards `dst`
+    pub fn lookup(&self, dst: Ipv4Addr) -> Option<Route> {
+        self.routes
+            .read()
+            .iter()
+            .find(|route| route.matches(dst))
+            .copied()
+    }
+
+    /// Send frames routed to the port of `tx_queue` through it
+    pub fn add_port(&self, tx_queue: Arc<TxQueue>) {
+        self.ports.write().insert(tx_queue.port_id(), tx_queue);
+    }
+
+    /// Forward a received frame if it is routed
+    ///
+    /// On anything but [`ForwardVerdict::Local`] the mbuf has been freed to
+    /// `pool`. Time exceeded messages go out on the port the frame came in
+    /// on, with buffers from `pool`.
+    pub fn process(&self, mbuf: &mut Mbuf, pool: &MbufPool) -> Result<ForwardVerdict> {
+        let verdict = match self.step(mbuf) {
+            Step::Local => {
+                self.stats.local.fetch_add(1, Ordering::Relaxed);
+                return Ok(ForwardVerdict::Local);
+            }
+            Step::Forward(route) => match self.transmit(route.port, mbuf) {
+                Ok(()) => {
+                    self.stats.forwarded.fetch_add(1, Ordering::Relaxed);
+                    ForwardVerdict::Forwarded(route.port)
+                }
+                Err(_) => {
+                    self.stats.unroutable.fetch_add(1, Ordering::Relaxed);
+                    ForwardVerdict::Dropped(DropReason::Unroutable)
+                }
+            },
+            Step::Expired => {
+                self.stats.ttl_exceeded.fetch_add(1, Ordering::Relaxed);
+                if self.send_time_exceeded(mbuf, pool).is_ok() {
+                    self.stats.icmp_sent.fetch_add(1, Ordering::Relaxed);
+                }
+                ForwardVerdict::Dropped(DropReason::TtlExceeded)
+            }
+        };
+
+        if let (ForwardVerdict::Dropped(reason), Some(quarantine)) = (verdict, &self.quarantine) {
+            quarantine.offer(reason, mbuf.data());
+        }
+        pool.free(mbuf)?;
+        Ok(verdict)
+    }
+
+    /// Decide what to do with a frame, rewriting it if it is forwarded
+    fn step(&self, mbuf: &mut Mbuf) -> Step {
+        if mbuf.packet_type == PacketType::Unknown {
+            super::classify(mbuf);
+        }
+        let l3 = mbuf.l3_offset as usize;
+        let frame = mbuf.data_mut();
+        if l3 == 0 || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_IPV4 {
+            return Step::Local;
+        }
+
+        let dst = Ipv4Addr::new(
+            frame[l3 + 16],
+            frame[l3 + 17],
+            frame[l3 + 18],
+            frame[l3 + 19],
+        );
+        let route = match self.lookup(dst) {
+            Some(route) if dst != self.router_addr => route,
+            _ => return Step::Local,
+        };
+        if frame[l3 + 8] <= 1 {
+            return Step::Expired;
+        }
+
+        // TTL shares a checksummed word with the protocol
+        let old = u16::from_be_bytes([frame[l3 + 8], frame[l3 + 9]]);
+        frame[l3 + 8] -= 1;
+        let checksum = u16::from_be_bytes([frame[l3 + 10], frame[l3 + 11]]);
+        let checksum = checksum_update_u16(checksum, old, old - 0x100);
+        frame[l3 + 10..l3 + 12].copy_from_slice(&checksum.to_be_bytes());
+
+        frame[0..6].copy_from_slice(&route.next_hop_mac);
+        frame[6..12].copy_from_slice(&route.src_mac);
+        Step::Forward(route)
+    }
+
+    fn transmit(&self, port: u16, mbuf: *mut Mbuf) -> Result<()> {
+        let ports = self.ports.read();
+        let tx_queue = ports
+            .get(&port)
+            .ok_or_else(|| Error::NetworkError(format!("No TX queue for port {}", port)))?;
+        tx_queue.send(mbuf)
+    }
+
+    fn send_time_exceeded(&self, mbuf: &Mbuf, pool: &MbufPool) -> Result<()> {
+        let reply = pool.alloc_with_class(AllocClass::Icmp)?;
+        let result = match time_exceeded(mbuf.data(), mbuf.l3_offset as usize, self.router_addr) {
+            None => Err(Error::NetworkError("ICMP error not answered".to_string())),
+            Some(frame) => {
+                let reply_ref = unsafe { &mut *reply };
+                reply_ref.reset();
+                reply_ref
+                    .append(&frame)
+                    .and_then(|()| self.transmit(mbuf.port_id, reply))
+            }
+        };
+        pool.free(reply)?;
+        result
+    }
+
+    /// Get forwarding counters
+    pub fn stats(&self) -> &ForwardStats {
+        &self.stats
+    }
+}
+
+fn remove_route(routes: &mut Vec<Route>, prefix: Ipv4Addr, prefix_len: u8) -> bool {
+    let before = routes.len();
+    routes.retain(|route| route.prefix != prefix || route.prefix_len != prefix_len);
+    routes.len() != before
+}
+
+/// ICMP time exceeded frame answering the IPv4 frame `frame`
+///
+/// Returns `None` if the frame is itself an ICMP error.
+fn time_exceeded(frame: &[u8], l3: usize, router_addr: Ipv4Addr) -> Option<Vec<u8>> {
+    let ihl = (frame[l3] & 0x0F) as usize * 4;
+    if frame[l3 + 9] == IPPROTO_ICMP && ICMP_ERROR_TYPES.contains(frame.get(l3 + ihl)?) {
+        return None;
+    }
+
+    let quoted = &frame[l3..frame.len().min(l3 + ihl + ICMP_QUOTE_LEN)];
+    let mut icmp = vec![ICMP_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+    icmp.extend_from_slice(quoted);
+    let checksum = !ones_complement(0, &icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let src = Ipv4Addr::new(
+        frame[l3 + 12],
+        frame[l3 + 13],
+        frame[l3 + 14],
+        frame[l3 + 15],
+    );
+    let mut ip_header = Ipv4Header::new(router_addr, src, icmp.len() as u16);
+    ip_header.protocol = IPPROTO_ICMP;
+    ip_header.checksum = ip_header.compute_checksum().to_be();
+
+    let mut dst_mac = [0; 6];
+    dst_mac.copy_from_slice(&frame[6..12]);
+    let mut src_mac = [0; 6];
+    src_mac.copy_from_slice(&frame[0..6]);
+
+    let mut reply = EthernetHeader::new(src_mac, dst_mac, ETHERTYPE_IPV4)
+        .to_bytes()
+        .to_vec();
+    reply.extend_from_slice(&ip_header.to_bytes());
+    reply.extend_from_slice(&icmp);
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::testing::FrameBuilder;
+    use std::net::SocketAddrV4;
+
+    fn forwarder() -> Forwarder {
+        Forwarder::new(&ForwardingConfig {
+            router_addr: Ipv4Addr::new(10, 0, 0, 1),
+            routes: vec![
+                Route::new(Ipv4Addr::new(192, 168, 0, 0), 16, 1, [0xa; 6], [0xb; 6]),
+                Route::new(Ipv4Addr::new(192, 168, 7, 0), 24, 2, [0xc; 6], [0xd; 6]),
+                Route::new(Ipv4Addr::new(10, 0, 0, 0), 8, 1, [0xa; 6], [0xe; 6]),
+            ],
+        })
+        .unwrap()
+    }
+
+    fn step(forwarder: &Forwarder, frame: &mut Vec<u8>) -> Step {
+        let mut mbuf = Mbuf::new(frame.as_mut_ptr(), frame.len());
+        mbuf.len = frame.len();
+        forwarder.step(&mut mbuf)
+    }
+
+    fn to(dst: [u8; 4], ttl: u8) -> Vec<u8> {
+        FrameBuilder::new(
+            SocketAddrV4::new(Ipv4Addr::new(172, 16, 0, 9), 4000),
+            SocketAddrV4::new(Ipv4Addr::from(dst), 53),
+        )
+        .ttl(ttl)
+        .payload(b"query")
+        .build()
+    }
+
+    #[test]
+    fn test_forward_decrements_ttl_and_picks_longest_prefix() {
+        let forwarder = forwarder();
+        assert!(forwarder
+            .add_route(Route::new(Ipv4Addr::UNSPECIFIED, 33, 0, [0; 6], [0; 6]))
+            .is_err());
+        assert_eq!(forwarder.lookup(Ipv4Addr::new(8, 8, 8, 8)), None);
+        assert_eq!(
+            forwarder
+                .lookup(Ipv4Addr::new(192, 168, 7, 3))
+                .unwrap()
+                .port,
+            2
+        );
+
+        let mut frame = to([192, 168, 7, 3], 64);
+        match step(&forwarder, &mut frame) {
+            Step::Forward(route) => assert_eq!(route.port, 2),
+            other => panic!("unexpected step {:?}", other),
+        }
+        assert_eq!(frame[22], 63);
+        assert_eq!(!ones_complement(0, &frame[14..34]), 0);
+        assert_eq!(frame[0..12], [[0xd; 6], [0xc; 6]].concat()[..]);
+
+        // Unrouted frames and frames for the router stay local
+        assert_eq!(step(&forwarder, &mut to([8, 8, 8, 8], 64)), Step::Local);
+        assert_eq!(step(&forwarder, &mut to([10, 0, 0, 1], 1)), Step::Local);
+
+        let mut expiring = to([192, 168, 1, 1], 1);
+        assert_eq!(step(&forwarder, &mut expiring), Step::Expired);
+        let reply = time_exceeded(&expiring, 14, Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+        assert_eq!(reply[0..6], expiring[6..12]);
+        assert_eq!(reply[23], IPPROTO_ICMP);
+        assert_eq!(reply[30..34], [172, 16, 0, 9]);
+        assert_eq!(reply[34], ICMP_TIME_EXCEEDED);
+        assert_eq!(!ones_complement(0, &reply[14..34]), 0);
+        assert_eq!(!ones_complement(0, &reply[34..]), 0);
+        assert_eq!(reply[42..], expiring[14..14 + 20 + 8]);
+        assert!(time_exceeded(&reply, 14, Ipv4Addr::new(10, 0, 0, 1)).is_none());
+
+        assert!(forwarder.remove_route(Ipv4Addr::new(192, 168, 7, 0), 24));
+        assert_eq!(
+            forwarder
+                .lookup(Ipv4Addr::new(192, 168, 7, 3))
+                .unwrap()
+                .port,
+            1
+        );
+    }
+}
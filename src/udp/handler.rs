@@ -0,0 +1,339 @@
+//! Protocol handlers above UDP
+//!
+//! Protocols run directly over UDP, such as RTP or a telemetry feed, often
+//! have no use for a socket queue: they want each datagram as it arrives.
+//! A [`ProtocolHandler`] registered with
+//! [`super::UdpStack::register_handler`] is handed the parsed [`UdpPacket`]
+//! of every datagram it matches, by destination port or by magic bytes at
+//! an offset into the payload, before the stack looks for a socket. Port
+//! handlers are found in one lookup and win over magic handlers, which are
+//! tried in the order they were registered.
+//!
+//! The [`HandlerVerdict`] decides what happens next: a consumed datagram is
+//! recycled to the stack's receive pool, a passed one goes on to the
+//! sockets as if no handler had matched, and a rejected one is dropped with
+//! [`super::DropReason::Handler`]. A stack without a receive pool has
+//! nowhere to recycle a consumed datagram, so it drops what a handler
+//! matches the same way, without calling the handler. Handlers are
+//! started and stopped with the stack, and each counts what it was given
+//! in its [`HandlerStats`].
+
+use super::UdpPacket;
+use crate::utils::counter::Counter;
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// User protocol served straight from the dispatcher
+pub trait ProtocolHandler: Send + Sync {
+    /// Name in logs
+    fn name(&self) -> &str;
+
+    /// Called when the stack starts, or on registration with the stack running
+    fn on_start(&self) {}
+
+    /// Called when the stack stops, or on removal with the stack running
+    fn on_stop(&self) {}
+
+    /// Handle a datagram, which is only borrowed for the call
+    fn handle(&self, packet: &UdpPacket) -> HandlerVerdict;
+}
+
+/// What the dispatcher does with a datagram after its handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerVerdict {
+    /// Done with; the mbuf is recycled
+    Consumed,
+    /// Deliver to the sockets instead
+    Pass,
+    /// Drop as rejected
+    Reject,
+}
+
+/// Datagrams a handler is registered for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerMatch {
+    /// Every datagram to this destination port
+    Port(u16),
+    /// Datagrams whose payload holds `bytes` at `offset`
+    Magic { offset: usize, bytes: Vec<u8> },
+}
+
+impl HandlerMatch {
+    fn matches_payload(&self, payload: &[u8]) -> bool {
+        match self {
+            HandlerMatch::Port(_) => false,
+            HandlerMatch::Magic { offset, bytes } => payload
+                .get(*offset..offset + bytes.len())
+                .is_some_and(|found| found == bytes.as_slice()),
+        }
+    }
+}
+
+/// Counters of a protocol handler
+#[derive(Debug, Default)]
+pub struct HandlerStats {
+    pub packets: Counter,
+    pub bytes: Counter,
+    pub consumed: Counter,
+    pub passed: Counter,
+    pub rejected: Counter,
+}
+
+pub(crate) struct Registered {
+    id: u16,
+    matcher: HandlerMatch,
+    handler: Arc<dyn ProtocolHandler>,
+    stats: Arc<HandlerStats>,
+}
+
+/// Handlers registered with a stack
+#[derive(Default)]
+pub(crate) struct ProtocolHandlers {
+    ports: HashMap<u16, Registered>,
+    magic: Vec<Registered>,
+    next_id: u16,
+}
+
+impl ProtocolHandlers {
+    pub(crate) fn register(
+        &mut self,
+        matcher: HandlerMatch,
+        handler: Arc<dyn ProtocolHandler>,
+    ) -> Result<u16> {
+        if let HandlerMatch::Magic { bytes, .. } = &matcher {
+            if bytes.is_empty() {
+                return Err(Error::InvalidConfig(format!(
+                    "Handler {} matches on empty magic",
+                    handler.name()
+                )));
+            }
+        }
+        if let HandlerMatch::Port(port) = &matcher {
+            if let Some(existing) = self.ports.get(port) {
+                return Err(Error::NetworkError(format!(
+                    "Port {} already handled by {}",
+                    port,
+                    existing.handler.name()
+                )));
+            }
+        }
+        let id = self.free_id()?;
+        let registered = Registered {
+            id,
+            matcher,
+            handler,
+            stats: Arc::default(),
+        };
+        match registered.matcher {
+            HandlerMatch::Port(port) => {
+                self.ports.insert(port, registered);
+            }
+            HandlerMatch::Magic { .. } => self.magic.push(registered),
+        }
+        Ok(id)
+    }
+
+    /// Next ID after the last one handed out that no handler holds, never 0
+    fn free_id(&mut self) -> Result<u16> {
+        if self.registered().count() >= u16::MAX as usize {
+            return Err(Error::InvalidConfig(
+                "No handler ID left to register with".to_string(),
+            ));
+        }
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            let id = self.next_id;
+            if id != 0 && !self.registered().any(|registered| registered.id == id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn registered(&self) -> impl Iterator<Item = &Registered> {
+        self.ports.values().chain(&self.magic)
+    }
+
+    /// Remove a handler, returning it
+    pub(crate) fn remove(&mut self, id: u16) -> Option<Arc<dyn ProtocolHandler>> {
+        if let Some(port) = self
+            .ports
+            .iter()
+            .find_map(|(&port, registered)| (registered.id == id).then_some(port))
+        {
+            return self
+                .ports
+                .remove(&port)
+                .map(|registered| registered.handler);
+        }
+        let index = self
+            .magic
+            .iter()
+            .position(|registered| registered.id == id)?;
+        Some(self.magic.remove(index).handler)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ports.is_empty() && self.magic.is_empty()
+    }
+
+    pub(crate) fn handlers(&self) -> impl Iterator<Item = &Arc<dyn ProtocolHandler>> {
+        self.registered().map(|registered| &registered.handler)
+    }
+
+    pub(crate) fn stats(&self, id: u16) -> Option<Arc<HandlerStats>> {
+        self.registered()
+            .find(|registered| registered.id == id)
+            .map(|registered| registered.stats.clone())
+    }
+
+    /// Handler matching `packet`, if any
+    pub(crate) fn lookup(&self, packet: &UdpPacket) -> Option<&Registered> {
+        if self.is_empty() {
+            return None;
+        }
+        self.ports.get(&packet.dst_addr().port()).or_else(|| {
+            let payload = packet.payload();
+            self.magic
+                .iter()
+                .find(|registered| registered.matcher.matches_payload(payload))
+        })
+    }
+}
+
+impl Registered {
+    /// Hand `packet` to the handler
+    pub(crate) fn handle(&self, packet: &UdpPacket) -> HandlerVerdict {
+        let stats = &self.stats;
+        stats.packets.inc();
+        stats.bytes.add(packet.payload().len() as u64);
+        let verdict = self.handler.handle(packet);
+        match verdict {
+            HandlerVerdict::Consumed => &stats.consumed,
+            HandlerVerdict::Pass => &stats.passed,
+            HandlerVerdict::Reject => &stats.rejected,
+        }
+        .inc();
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::testing::{load, FrameBuilder};
+    use crate::udp::{Delivery, DropReason, UdpStack};
+    use crate::Config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Echo {
+        verdict: HandlerVerdict,
+        running: AtomicUsize,
+    }
+
+    impl ProtocolHandler for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn on_start(&self) {
+            self.running.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_stop(&self) {
+            self.running.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        fn handle(&self, _packet: &UdpPacket) -> HandlerVerdict {
+            self.verdict
+        }
+    }
+
+    fn echo(verdict: HandlerVerdict) -> Arc<Echo> {
+        Arc::new(Echo {
+            verdict,
+            running: AtomicUsize::new(0),
+        })
+    }
+
+    #[test]
+    fn test_handlers_by_port_and_magic() {
+        let pool = Arc::new(MbufPool::new("handler".to_string(), 8, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        let socket = stack
+            .create_socket("0.0.0.0:6000".parse().unwrap())
+            .unwrap();
+
+        let rtp = echo(HandlerVerdict::Consumed);
+        let rtp_id = stack
+            .register_handler(HandlerMatch::Port(5004), rtp.clone())
+            .unwrap();
+        assert!(stack
+            .register_handler(HandlerMatch::Port(5004), rtp.clone())
+            .is_err());
+        let telemetry = echo(HandlerVerdict::Reject);
+        let magic = HandlerMatch::Magic {
+            offset: 1,
+            bytes: b"TLM".to_vec(),
+        };
+        let telemetry_id = stack.register_handler(magic, telemetry.clone()).unwrap();
+        stack.start().unwrap();
+        assert_eq!(rtp.running.load(Ordering::Relaxed), 1);
+
+        let mbuf = load(&pool, &FrameBuilder::to_port(5004).payload(b"rtp").build());
+        assert_eq!(stack.dispatch(mbuf), Delivery::Delivered);
+        let mbuf = load(&pool, &FrameBuilder::to_port(6000).payload(b"xTLM").build());
+        assert_eq!(stack.dispatch(mbuf), Delivery::Dropped(DropReason::Handler));
+        pool.free(mbuf).unwrap();
+        // Unmatched datagrams go to the sockets
+        let mbuf = load(&pool, &FrameBuilder::to_port(6000).payload(b"TLM").build());
+        assert!(stack.dispatch(mbuf).is_delivered());
+        let packet = stack.get_socket(socket).unwrap().recv().unwrap();
+        pool.free(packet.mbuf).unwrap();
+        assert_eq!(pool.stats().in_use, 0);
+
+        let stats = stack.handler_stats(rtp_id).unwrap();
+        assert_eq!((stats.packets.get(), stats.bytes.get()), (1, 3));
+        assert_eq!(stats.consumed.get(), 1);
+        assert_eq!(stack.handler_stats(telemetry_id).unwrap().rejected.get(), 1);
+
+        stack.unregister_handler(rtp_id).unwrap();
+        assert_eq!(rtp.running.load(Ordering::Relaxed), 0);
+        assert!(stack.unregister_handler(rtp_id).is_err());
+        stack.stop().unwrap();
+        assert_eq!(telemetry.running.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_ids_wrap_and_consumes_need_a_pool() {
+        let mut handlers = ProtocolHandlers::default();
+        let first = handlers
+            .register(HandlerMatch::Port(1), echo(HandlerVerdict::Pass))
+            .unwrap();
+        assert_eq!(first, 1);
+        handlers.next_id = u16::MAX - 1;
+        let last = handlers
+            .register(HandlerMatch::Port(2), echo(HandlerVerdict::Pass))
+            .unwrap();
+        assert_eq!(last, u16::MAX);
+        // Wrapping skips 0 and the ID still held
+        let wrapped = handlers
+            .register(HandlerMatch::Port(3), echo(HandlerVerdict::Pass))
+            .unwrap();
+        assert_eq!(wrapped, 2);
+
+        // Without a pool the handler is not called, the caller keeps the mbuf
+        let pool = MbufPool::new("handler".to_string(), 2, 2048).unwrap();
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let id = stack
+            .register_handler(HandlerMatch::Port(5004), echo(HandlerVerdict::Consumed))
+            .unwrap();
+        let mbuf = load(&pool, &FrameBuilder::to_port(5004).payload(b"rtp").build());
+        assert_eq!(stack.dispatch(mbuf), Delivery::Dropped(DropReason::Handler));
+        pool.free(mbuf).unwrap();
+        assert_eq!(stack.handler_stats(id).unwrap().packets.get(), 0);
+        assert_eq!(stack.stats().total_packets_received, 0);
+    }
+}
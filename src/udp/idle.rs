@@ -0,0 +1,131 @@
+//! Socket inactivity timeouts
+//!
+//! Sockets with an idle timeout are armed on a [`TimerWheel`] at their last
+//! RX/TX activity plus the timeout. `UdpStack::expire_idle` advances the
+//! wheel; a socket whose timer fires but saw traffic in the meantime is
+//! re-armed, otherwise the stack's [`IdleAction`] is applied to it. The
+//! same per-socket timers drive keep-alives.
+
+use crate::utils::time::{TimerId, TimerWheel, Timestamp};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Idle timer resolution
+pub const IDLE_TIMER_TICK: Duration = Duration::from_millis(10);
+
+/// Idle timer wheel slots, about ten seconds per revolution
+const IDLE_TIMER_SLOTS: usize = 1024;
+
+/// Callback told about an idle socket by ID and local address
+pub type IdleCallback = Box<dyn FnMut(u16, SocketAddr) + Send + Sync>;
+
+/// What the stack does with a socket that timed out
+#[derive(Default)]
+pub enum IdleAction {
+    /// Close the socket
+    #[default]
+    Close,
+    /// Call back and keep the socket; it fires again after another timeout
+    Notify(IdleCallback),
+}
+
+impl std::fmt::Debug for IdleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Close => f.write_str("Close"),
+            Self::Notify(_) => f.write_str("Notify(..)"),
+        }
+    }
+}
+
+/// One armed timer per socket
+pub(crate) struct SocketTimers {
+    wheel: TimerWheel<u16>,
+    timers: HashMap<u16, TimerId>,
+}
+
+impl SocketTimers {
+    pub(crate) fn new(start: Timestamp) -> Self {
+        Self {
+            wheel: TimerWheel::new(IDLE_TIMER_TICK, IDLE_TIMER_SLOTS, start),
+            timers: HashMap::new(),
+        }
+    }
+
+    /// Arm the timer of a socket, replacing any earlier one
+    pub(crate) fn arm(&mut self, socket_id: u16, deadline: Timestamp) {
+        self.disarm(socket_id);
+        let timer = self.wheel.schedule(deadline, socket_id);
+        self.timers.insert(socket_id, timer);
+    }
+
+    pub(crate) fn disarm(&mut self, socket_id: u16) {
+        if let Some(timer) = self.timers.remove(&socket_id) {
+            self.wheel.cancel(timer);
+        }
+    }
+
+    /// Sockets whose timers fired by `now`
+    pub(crate) fn fired(&mut self, now: Timestamp) -> Vec<u16> {
+        let fired = self.wheel.advance(now);
+        for socket_id in &fired {
+            self.timers.remove(socket_id);
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::UdpStack;
+    use crate::Config;
+    use parking_lot::Mutex;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_idle_sockets_close_or_notify() {
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let addr = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+        let short = stack.create_socket(addr(5000)).unwrap();
+        let long = stack.create_socket(addr(5001)).unwrap();
+        let kept = stack.create_socket(addr(5002)).unwrap();
+        stack
+            .set_idle_timeout(short, Some(Duration::from_millis(50)))
+            .unwrap();
+        stack
+            .set_idle_timeout(long, Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let oldest = stack.sockets_by_activity()[0].1;
+        let at = |offset: Duration| oldest + offset.as_nanos() as u64;
+        assert!(stack
+            .expire_idle(at(Duration::from_millis(20)))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            stack.expire_idle(at(Duration::from_millis(80))).unwrap(),
+            vec![short]
+        );
+        assert!(stack.get_socket(short).is_none());
+
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let sink = notified.clone();
+        stack.set_idle_action(IdleAction::Notify(Box::new(move |id, local| {
+            sink.lock().push((id, local.port()))
+        })));
+        assert_eq!(
+            stack.expire_idle(at(Duration::from_millis(1100))).unwrap(),
+            vec![long]
+        );
+        assert_eq!(*notified.lock(), vec![(long, 5001)]);
+        assert!(stack.get_socket(long).is_some());
+
+        // Sockets without a timeout are never expired
+        let remaining: Vec<u16> = stack.sockets_by_activity().iter().map(|s| s.0).collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.contains(&kept));
+    }
+}
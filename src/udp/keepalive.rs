@@ -0,0 +1,135 @@
+//! Application-level keep-alives
+//!
+//! A socket with a [`KeepAliveConfig`] sends a fixed payload to a fixed
+//! destination whenever it has been quiet for the configured interval, which
+//! keeps NAT bindings along the path open. Keep-alives are armed on the
+//! stack's socket timers and sent from `UdpStack::run_keepalives`; a timer
+//! firing while real RX/TX traffic flowed within the interval is suppressed
+//! and re-armed from the last activity instead. Keep-alive sends themselves
+//! do not count as activity, so they never hold off an idle timeout.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Keep-alive settings of a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepAliveConfig {
+    /// Quiet time after which a keep-alive is sent
+    pub interval: Duration,
+    /// Keep-alive datagram payload
+    pub payload: Vec<u8>,
+    /// Where keep-alives are sent
+    pub destination: SocketAddr,
+}
+
+impl KeepAliveConfig {
+    /// Send `payload` to `destination` after every `interval` without traffic
+    pub fn new(destination: SocketAddr, interval: Duration, payload: &[u8]) -> Self {
+        Self {
+            interval,
+            payload: payload.to_vec(),
+            destination,
+        }
+    }
+}
+
+/// Keep-alive counters
+#[derive(Debug, Default)]
+pub struct KeepAliveStats {
+    /// Keep-alives sent
+    pub sent: AtomicUsize,
+    /// Keep-alives skipped because of recent traffic
+    pub suppressed: AtomicUsize,
+    /// Keep-alives that failed to send
+    pub errors: AtomicUsize,
+}
+
+/// Keep-alive state of a socket
+#[derive(Debug)]
+pub(crate) struct KeepAlive {
+    pub(crate) config: KeepAliveConfig,
+    pub(crate) enabled: AtomicBool,
+    pub(crate) stats: KeepAliveStats,
+}
+
+impl KeepAlive {
+    pub(crate) fn new(config: KeepAliveConfig) -> Self {
+        Self {
+            config,
+            enabled: AtomicBool::new(true),
+            stats: KeepAliveStats::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn interval_nanos(&self) -> u64 {
+        self.config.interval.as_nanos() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::UdpStack;
+    use crate::utils::time::monotonic_now;
+    use crate::Config;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_keepalives_suppressed_by_traffic() {
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let addr = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+        let id = stack.create_socket(addr(5000)).unwrap();
+        let config = KeepAliveConfig::new(addr(6000), Duration::from_millis(50), b"ka");
+        assert!(stack
+            .set_keepalive(
+                id,
+                Some(KeepAliveConfig::new(addr(6000), Duration::ZERO, b""))
+            )
+            .is_err());
+
+        let start = monotonic_now();
+        stack.set_keepalive(id, Some(config)).unwrap();
+        let at = |ms: u64| start + Duration::from_millis(ms).as_nanos() as u64;
+        let counts = |stack: &UdpStack| {
+            let stats = stack.get_socket(id).unwrap().keepalive_stats().unwrap();
+            (
+                stats.sent.load(Ordering::Relaxed),
+                stats.suppressed.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed),
+            )
+        };
+
+        // Due, but the socket has no transmit pool to send with
+        assert_eq!(stack.run_keepalives(at(100)), 0);
+        assert_eq!(counts(&stack), (0, 0, 1));
+
+        // Traffic just before the next keep-alive holds it off
+        let socket = stack.get_socket(id).unwrap();
+        socket.last_activity.store(at(180), Ordering::Relaxed);
+        stack.run_keepalives(at(200));
+        assert_eq!(counts(&stack), (0, 1, 1));
+
+        stack
+            .get_socket(id)
+            .unwrap()
+            .set_keepalive_enabled(false)
+            .unwrap();
+        stack.run_keepalives(at(300));
+        assert_eq!(counts(&stack), (0, 1, 1));
+        stack
+            .get_socket(id)
+            .unwrap()
+            .set_keepalive_enabled(true)
+            .unwrap();
+        stack.run_keepalives(at(400));
+        assert_eq!(counts(&stack), (0, 1, 2));
+
+        stack.set_keepalive(id, None).unwrap();
+        assert!(!stack.get_socket(id).unwrap().keepalive_enabled());
+    }
+}
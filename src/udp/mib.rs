@@ -0,0 +1,280 @@
+//! MIB-style protocol counters
+//!
+//! [`ProtocolMib`] keeps interface, IPv4 and UDP counters in the spirit of
+//! IF-MIB, IP-MIB and UDP-MIB. The RX handlers record every frame once it
+//! has been classified and again once its delivery is settled, the TX path
+//! records every frame sent. [`MibSnapshot`] is the structured view, and its
+//! `Display` output is the `name value` text used for telemetry export.
+
+use super::{classify, Delivery, DropReason, ETHERTYPE_IPV4};
+use crate::memory::{Mbuf, OffloadFlags, PacketType};
+use std::fmt;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Ethernet layer counters
+#[derive(Debug, Default)]
+pub struct EthernetMib {
+    pub in_frames: AtomicUsize,
+    pub in_octets: AtomicUsize,
+    pub out_frames: AtomicUsize,
+    pub out_octets: AtomicUsize,
+}
+
+/// IPv4 layer counters
+#[derive(Debug, Default)]
+pub struct Ipv4Mib {
+    pub in_receives: AtomicUsize,
+    /// Datagrams with a broken or truncated header
+    pub in_hdr_errors: AtomicUsize,
+    /// Datagrams that are fragments
+    pub in_fragments: AtomicUsize,
+    pub out_requests: AtomicUsize,
+}
+
+/// UDP layer counters
+#[derive(Debug, Default)]
+pub struct UdpMib {
+    pub in_datagrams: AtomicUsize,
+    /// Datagrams for a port nobody listens on
+    pub no_ports: AtomicUsize,
+    /// Datagrams dropped for any other reason
+    pub in_errors: AtomicUsize,
+    /// Datagrams captured short of their UDP length
+    pub in_truncated: AtomicUsize,
+    pub out_datagrams: AtomicUsize,
+}
+
+/// Per-layer protocol counters
+#[derive(Debug, Default)]
+pub struct ProtocolMib {
+    pub ethernet: EthernetMib,
+    pub ipv4: Ipv4Mib,
+    pub udp: UdpMib,
+}
+
+impl ProtocolMib {
+    /// Classify a received frame if needed and count it at L2 and L3
+    ///
+    /// Returns whether the frame is an IPv4/UDP frame.
+    pub(crate) fn record_ingress(&self, mbuf: *mut Mbuf) -> bool {
+        if mbuf.is_null() {
+            return false;
+        }
+        let mbuf = unsafe { &mut *mbuf };
+        if mbuf.packet_type == PacketType::Unknown {
+            classify(mbuf);
+        }
+
+        let data = mbuf.data();
+        self.ethernet.in_frames.fetch_add(1, Ordering::Relaxed);
+        self.ethernet
+            .in_octets
+            .fetch_add(data.len(), Ordering::Relaxed);
+
+        let l3 = size_of::<super::EthernetHeader>();
+        if data.len() < l3 || u16::from_be_bytes([data[12], data[13]]) != ETHERTYPE_IPV4 {
+            return false;
+        }
+        self.ipv4.in_receives.fetch_add(1, Ordering::Relaxed);
+        if mbuf.packet_type == PacketType::Ethernet {
+            // IPv4 EtherType the classifier could not parse
+            self.ipv4.in_hdr_errors.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let flags_fragment = u16::from_be_bytes([data[l3 + 6], data[l3 + 7]]);
+        if flags_fragment & 0x3FFF != 0 {
+            self.ipv4.in_fragments.fetch_add(1, Ordering::Relaxed);
+        }
+        if mbuf.offload_flags.contains(OffloadFlags::RX_TRUNCATED) {
+            self.udp.in_truncated.fetch_add(1, Ordering::Relaxed);
+        }
+        mbuf.packet_type == PacketType::Udp
+    }
+
+    /// Count the outcome of handing a received frame to the UDP layer
+    pub(crate) fn record_delivery(&self, udp: bool, delivery: Delivery) {
+        let counter = match delivery {
+            Delivery::Delivered => &self.udp.in_datagrams,
+            Delivery::Dropped(DropReason::NoSocket | DropReason::Unmatched) => &self.udp.no_ports,
+            Delivery::Dropped(_) if udp => &self.udp.in_errors,
+            Delivery::Dropped(_) => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a transmitted IPv4/UDP frame of `frame_len` bytes
+    pub(crate) fn record_tx(&self, frame_len: usize) {
+        self.ethernet.out_frames.fetch_add(1, Ordering::Relaxed);
+        self.ethernet
+            .out_octets
+            .fetch_add(frame_len, Ordering::Relaxed);
+        self.ipv4.out_requests.fetch_add(1, Ordering::Relaxed);
+        self.udp.out_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get a snapshot of all counters
+    pub fn snapshot(&self) -> MibSnapshot {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        MibSnapshot {
+            ethernet: EthernetCounters {
+                in_frames: load(&self.ethernet.in_frames),
+                in_octets: load(&self.ethernet.in_octets),
+                out_frames: load(&self.ethernet.out_frames),
+                out_octets: load(&self.ethernet.out_octets),
+            },
+            ipv4: Ipv4Counters {
+                in_receives: load(&self.ipv4.in_receives),
+                in_hdr_errors: load(&self.ipv4.in_hdr_errors),
+                in_fragments: load(&self.ipv4.in_fragments),
+                out_requests: load(&self.ipv4.out_requests),
+            },
+            udp: UdpCounters {
+                in_datagrams: load(&self.udp.in_datagrams),
+                no_ports: load(&self.udp.no_ports),
+                in_errors: load(&self.udp.in_errors),
+                in_truncated: load(&self.udp.in_truncated),
+                out_datagrams: load(&self.udp.out_datagrams),
+            },
+        }
+    }
+}
+
+/// Ethernet counters view
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EthernetCounters {
+    pub in_frames: usize,
+    pub in_octets: usize,
+    pub out_frames: usize,
+    pub out_octets: usize,
+}
+
+/// IPv4 counters view
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ipv4Counters {
+    pub in_receives: usize,
+    pub in_hdr_errors: usize,
+    pub in_fragments: usize,
+    pub out_requests: usize,
+}
+
+/// UDP counters view
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpCounters {
+    pub in_datagrams: usize,
+    pub no_ports: usize,
+    pub in_errors: usize,
+    pub in_truncated: usize,
+    pub out_datagrams: usize,
+}
+
+/// Point-in-time copy of a [`ProtocolMib`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MibSnapshot {
+    pub ethernet: EthernetCounters,
+    pub ipv4: Ipv4Counters,
+    pub udp: UdpCounters,
+}
+
+impl MibSnapshot {
+    /// Counters under their MIB object names, in export order
+    pub fn counters(&self) -> [(&'static str, usize); 13] {
+        [
+            ("ifInFrames", self.ethernet.in_frames),
+            ("ifInOctets", self.ethernet.in_octets),
+            ("ifOutFrames", self.ethernet.out_frames),
+            ("ifOutOctets", self.ethernet.out_octets),
+            ("ipInReceives", self.ipv4.in_receives),
+            ("ipInHdrErrors", self.ipv4.in_hdr_errors),
+            ("ipReasmReqds", self.ipv4.in_fragments),
+            ("ipOutRequests", self.ipv4.out_requests),
+            ("udpInDatagrams", self.udp.in_datagrams),
+            ("udpNoPorts", self.udp.no_ports),
+            ("udpInErrors", self.udp.in_errors),
+            ("udpInTruncated", self.udp.in_truncated),
+            ("udpOutDatagrams", self.udp.out_datagrams),
+        ]
+    }
+}
+
+impl fmt::Display for MibSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.counters() {
+            writeln!(f, "{} {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::testing::{load, FrameBuilder, Malformation};
+    use crate::udp::UdpStack;
+    use crate::Config;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn test_rx_counters_per_layer() {
+        let pool = MbufPool::new("rx".to_string(), 8, 2048).unwrap();
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let id = stack.create_socket(local).unwrap();
+        stack.start().unwrap();
+
+        let builder = FrameBuilder::to_port(5000).payload(b"mib");
+        let frames = [
+            builder.build(),
+            FrameBuilder::to_port(5999).build(),
+            builder.malformed(Malformation::ShortIhl),
+            builder.malformed(Malformation::Fragment),
+            builder.malformed(Malformation::UdpLengthOverrun),
+            builder.malformed(Malformation::NotIpv4),
+            builder.malformed(Malformation::Snapped),
+        ];
+        let octets: usize = frames.iter().map(Vec::len).sum();
+        for frame in &frames {
+            let mbuf = load(&pool, frame);
+            if !stack.dispatch(mbuf).is_delivered() {
+                pool.free(mbuf).unwrap();
+            }
+        }
+        let socket = stack.get_socket(id).unwrap();
+        pool.free(socket.recv().unwrap().mbuf).unwrap();
+        // The snapped datagram is delivered with what was captured of it
+        let snapped = socket.recv().unwrap();
+        assert!(snapped.is_truncated());
+        assert_eq!(snapped.payload(), b"m");
+        assert_eq!(snapped.claimed_payload_len(), 3);
+        pool.free(snapped.mbuf).unwrap();
+
+        let mib = stack.protocol_stats();
+        assert_eq!(mib.ethernet.in_frames, 7);
+        assert_eq!(mib.ethernet.in_octets, octets);
+        assert_eq!(mib.ipv4.in_receives, 6);
+        assert_eq!(mib.ipv4.in_hdr_errors, 1);
+        assert_eq!(mib.ipv4.in_fragments, 1);
+        assert_eq!(mib.udp.in_datagrams, 2);
+        assert_eq!(mib.udp.no_ports, 1);
+        assert_eq!(mib.udp.in_errors, 1);
+        assert_eq!(mib.udp.in_truncated, 1);
+    }
+
+    #[test]
+    fn test_tx_counters_and_export() {
+        let mib = ProtocolMib::default();
+        mib.record_tx(60);
+        mib.record_tx(100);
+
+        let snapshot = mib.snapshot();
+        assert_eq!(snapshot.ethernet.out_octets, 160);
+        assert_eq!(snapshot.ipv4.out_requests, 2);
+
+        let text = snapshot.to_string();
+        assert!(text.contains("udpOutDatagrams 2\n"));
+        assert!(text.starts_with("ifInFrames 0\n"));
+        assert_eq!(text.lines().count(), snapshot.counters().len());
+    }
+}
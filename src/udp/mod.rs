@@ -4,12 +4,189 @@
 //! hardware offloading support, and efficient packet processing.
 
 use crate::poll::{RxQueue, TxQueue};
-use crate::{memory::Mbuf, Config, Error, Result};
+use crate::utils::capture::CaptureTrigger;
+use crate::utils::color::{PacketColorer, UNCOLORED};
+use crate::utils::counter::Counter;
+use crate::utils::logging::PacketLog;
+use crate::utils::pattern::{MatchVerdict, PatternSet};
+use crate::utils::rand::Rng;
+use crate::utils::time::{monotonic_now, Timestamp};
+use crate::utils::trace::{PacketTracer, TraceStage};
+use crate::{
+    memory::{
+        AllocClass, BudgetCharge, ChecksumStatus, FreeBatch, Mbuf, MbufHandle, MbufPool,
+        MemoryBudget, OffloadFlags, PacketType, Subsystem,
+    },
+    queue::{self, QueuePlacement},
+    utils::persist::SocketCounters,
+    Config, Error, Result,
+};
+use completion::{CompletionQueue, CompletionToken, Departure};
+use copy::CopyBufferPool;
+use delivery::{CopyRing, DeliveryControl};
+use demux::Demux;
+use fair::FairScheduler;
+use handler::ProtocolHandlers;
+use idle::SocketTimers;
+use keepalive::KeepAlive;
 use lockfree_ringbuf::SpscRingBuffer;
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use neighbor::NeighborOutput;
+use parking_lot::Mutex;
+use pmtu::SendPlan;
+use priority::BandedQueue;
+use reorder::Reordered;
+use shed::Shed;
+use spread::Spreader;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use table::SocketTable;
+use template::TemplateCache;
+
+mod bridge;
+mod checksum;
+mod completion;
+mod compress;
+mod copy;
+mod delivery;
+mod demux;
+mod dns;
+mod droplog;
+mod egress;
+mod ephemeral;
+mod fair;
+mod forward;
+mod handler;
+mod idle;
+mod keepalive;
+mod mib;
+mod neighbor;
+mod options;
+mod pmtu;
+mod pressure;
+mod priority;
+mod quarantine;
+mod relay;
+mod reorder;
+mod replay;
+mod rxfilter;
+mod shed;
+mod sniffer;
+mod spread;
+mod table;
+mod template;
+#[cfg(test)]
+pub(crate) mod testing;
+mod transform;
+
+pub use bridge::{
+    Bridge, BridgeConfig, BridgeEntry, BridgePortStats, BridgeStats, BridgeVerdict,
+    BRIDGE_ENTRY_BYTES, DEFAULT_BRIDGE_AGING, DEFAULT_BRIDGE_TABLE_SIZE,
+};
+pub use checksum::{
+    checksum_update_u16, checksum_update_u32, ChecksumPolicy, ChecksumSource, ChecksumStats,
+    ChecksumTrust, ChecksumValidator,
+};
+pub(crate) use checksum::{fill_tx_checksums, ones_complement};
+pub use completion::{CompletionStats, CompletionStatus, SendCompletion};
+pub use compress::{
+    CompressionConfig, CompressionStats, PayloadCompressor, MAX_OVERHEAD, TAG_LZ4, TAG_RAW,
+};
+pub use copy::{CopyBufferStats, DEFAULT_COPY_BUFFERS};
+pub use delivery::{DeliveryMode, DeliveryStats, DEFAULT_COPY_RING_BYTES};
+pub use demux::{ChannelStats, DemuxConfig, MAX_DEMUX_TAG_LEN};
+pub use dns::{DnsConfig, DnsQueryId, DnsRecordType, DnsResolver};
+pub(crate) use droplog::FlowKey;
+pub use droplog::{DropEvent, DropLog, DEFAULT_DROP_LOG_SIZE};
+pub use egress::{ClassCounters, EgressStats, TrafficClass, TrafficClassMap, TRAFFIC_CLASSES};
+pub use ephemeral::{
+    EphemeralPorts, DEFAULT_EPHEMERAL_FLOW_IDLE, EPHEMERAL_FLOW_BYTES, EPHEMERAL_PORTS,
+};
+pub use fair::FAIR_QUANTUM;
+pub use forward::{ForwardStats, ForwardVerdict, Forwarder, ForwardingConfig, Route};
+pub use handler::{HandlerMatch, HandlerStats, HandlerVerdict, ProtocolHandler};
+pub use idle::{IdleAction, IdleCallback, IDLE_TIMER_TICK};
+pub use keepalive::{KeepAliveConfig, KeepAliveStats};
+pub use mib::{
+    EthernetCounters, EthernetMib, Ipv4Counters, Ipv4Mib, MibSnapshot, ProtocolMib, UdpCounters,
+    UdpMib,
+};
+pub use neighbor::{
+    NeighborConfig, NeighborStats, NeighborTable, DEFAULT_MAX_NEIGHBORS, DEFAULT_MAX_PENDING,
+    DEFAULT_REACHABLE_TIME, DEFAULT_RESOLVE_TIMEOUT, DEFAULT_SOLICIT_INTERVAL,
+    NEIGHBOR_ENTRY_BYTES,
+};
+pub use options::{Ipv4Options, IPOPT_EOL, IPOPT_NOP, IPOPT_ROUTER_ALERT, IPOPT_TIMESTAMP};
+pub use pmtu::{PmtuCache, MIN_IPV4_MTU, PMTU_CACHE_CAPACITY, PMTU_ENTRY_BYTES, PMTU_EXPIRY};
+pub(crate) use pressure::validate as validate_early_drop;
+pub use pressure::{EarlyDropConfig, EarlyDropStats, DEFAULT_EARLY_DROP_THRESHOLD};
+pub use priority::{BandStats, PriorityBands, DSCP_EF};
+pub use quarantine::{
+    Quarantine, QuarantineConfig, QuarantineStats, QuarantinedFrame, DEFAULT_QUARANTINE_CAPACITY,
+    DEFAULT_QUARANTINE_RATE,
+};
+pub use relay::{RelayConfig, RelayStats, RelayTable, RelayVerdict, RELAY_SESSION_BYTES};
+pub use reorder::{
+    ReorderBuffer, ReorderConfig, ReorderStats, DEFAULT_REORDER_DELAY, DEFAULT_REORDER_FLOWS,
+    DEFAULT_REORDER_FLOW_IDLE, DEFAULT_REORDER_GAP, DEFAULT_REORDER_MISORDER, MAX_REORDER_GAP,
+    REORDER_FLOW_BYTES,
+};
+pub use replay::{ReplayGuard, ReplayStats, SequenceCheck, SequenceExtractor, SequenceWindow};
+pub use rxfilter::{FilterProgram, RxFilter, RxPredicate};
+pub use shed::{dscp_share, ShedPolicy, ShedStats, DSCP_CLASSES};
+pub use sniffer::{
+    SniffedFrame, Sniffer, SnifferConfig, SnifferFilter, SnifferStats, DEFAULT_SNIFFER_CAPACITY,
+    DEFAULT_SNIFFER_RATE,
+};
+pub use spread::{BurstSpread, SpreadStats};
+pub use template::{HeaderTemplate, TemplateStats, MAX_HEADER_TEMPLATES};
+pub use transform::{PayloadTransform, TransformStats};
+
+/// EtherType for IPv4
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+
+/// IPv4 TTL of sent datagrams unless set per socket
+pub const DEFAULT_TTL: u8 = 64;
+
+/// EtherType for IPv6
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+/// EtherType of an 802.1Q VLAN tag
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// IP protocol number for ICMP
+pub const IPPROTO_ICMP: u8 = 1;
+
+/// IP protocol number for TCP
+pub const IPPROTO_TCP: u8 = 6;
+
+/// IP protocol number for UDP
+pub const IPPROTO_UDP: u8 = 17;
+
+/// IP protocol number for ICMPv6
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+/// ICMP destination unreachable message type
+pub const ICMP_DEST_UNREACHABLE: u8 = 3;
+
+/// IPv4 "don't fragment" flag
+pub const IPV4_DF: u16 = 0x4000;
+
+/// IPv4 "more fragments" flag
+pub const IPV4_MF: u16 = 0x2000;
+
+/// Fixed IPv6 header length
+pub const IPV6_HEADER_LEN: usize = 40;
+
+/// Bytes reserved in front of the payload for Ethernet, IPv4 and UDP headers
+pub const TX_HEADROOM: usize = std::mem::size_of::<EthernetHeader>()
+    + std::mem::size_of::<Ipv4Header>()
+    + std::mem::size_of::<UdpHeader>();
+
+/// Fragments a fragmented send takes mbufs for at once
+const FRAGMENT_CHUNK: usize = 8;
 
 /// UDP header structure
 #[repr(C, packed)]
@@ -26,6 +203,9 @@ pub struct UdpHeader {
 }
 
 impl UdpHeader {
+    /// Serialized length
+    pub const LEN: usize = 8;
+
     /// Create a new UDP header
     pub fn new(src_port: u16, dst_port: u16, length: u16) -> Self {
         Self {
@@ -55,6 +235,40 @@ impl UdpHeader {
     pub fn checksum(&self) -> u16 {
         u16::from_be(self.checksum)
     }
+
+    /// Serialize in network byte order
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..2].copy_from_slice(&self.src_port().to_be_bytes());
+        bytes[2..4].copy_from_slice(&self.dst_port().to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.length().to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.checksum().to_be_bytes());
+        bytes
+    }
+
+    /// Deserialize from the start of `bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = header_bytes::<{ Self::LEN }>(bytes, "UDP")?;
+        Ok(Self {
+            src_port: be16(bytes, 0).to_be(),
+            dst_port: be16(bytes, 2).to_be(),
+            length: be16(bytes, 4).to_be(),
+            checksum: be16(bytes, 6).to_be(),
+        })
+    }
+}
+
+/// First `N` bytes of `bytes`, failing with the header name if too short
+fn header_bytes<const N: usize>(bytes: &[u8], name: &str) -> Result<[u8; N]> {
+    bytes
+        .get(..N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::NetworkError(format!("Too few bytes for {} header", name)))
+}
+
+/// Big-endian `u16` at `offset`
+fn be16<const N: usize>(bytes: [u8; N], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
 }
 
 /// IPv4 header structure (simplified)
@@ -84,6 +298,9 @@ pub struct Ipv4Header {
 }
 
 impl Ipv4Header {
+    /// Serialized length without options
+    pub const LEN: usize = 20;
+
     /// Create a new IPv4 header
     pub fn new(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, payload_length: u16) -> Self {
         let total_length = (std::mem::size_of::<Ipv4Header>() + payload_length as usize) as u16;
@@ -116,6 +333,57 @@ impl Ipv4Header {
     pub fn protocol(&self) -> u8 {
         self.protocol
     }
+
+    /// Serialize in network byte order
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0] = self.version_ihl;
+        bytes[1] = self.tos;
+        bytes[2..4].copy_from_slice(&u16::from_be(self.total_length).to_be_bytes());
+        bytes[4..6].copy_from_slice(&u16::from_be(self.identification).to_be_bytes());
+        bytes[6..8].copy_from_slice(&u16::from_be(self.flags_fragment).to_be_bytes());
+        bytes[8] = self.ttl;
+        bytes[9] = self.protocol;
+        bytes[10..12].copy_from_slice(&u16::from_be(self.checksum).to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.src_addr);
+        bytes[16..20].copy_from_slice(&self.dst_addr);
+        bytes
+    }
+
+    /// Deserialize the fixed part of the header from the start of `bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = header_bytes::<{ Self::LEN }>(bytes, "IPv4")?;
+        Ok(Self {
+            version_ihl: bytes[0],
+            tos: bytes[1],
+            total_length: be16(bytes, 2).to_be(),
+            identification: be16(bytes, 4).to_be(),
+            flags_fragment: be16(bytes, 6).to_be(),
+            ttl: bytes[8],
+            protocol: bytes[9],
+            checksum: be16(bytes, 10).to_be(),
+            src_addr: [bytes[12], bytes[13], bytes[14], bytes[15]],
+            dst_addr: [bytes[16], bytes[17], bytes[18], bytes[19]],
+        })
+    }
+
+    /// Compute the header checksum (host byte order), ignoring the stored checksum
+    pub fn compute_checksum(&self) -> u16 {
+        let mut header = *self;
+        header.checksum = 0;
+        let bytes = header.to_bytes();
+
+        let mut sum = 0u32;
+        for chunk in bytes.chunks_exact(2) {
+            sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        }
+
+        while sum >> 16 != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
 }
 
 /// Ethernet header structure
@@ -131,6 +399,9 @@ pub struct EthernetHeader {
 }
 
 impl EthernetHeader {
+    /// Serialized length
+    pub const LEN: usize = 14;
+
     /// Create a new Ethernet header
     pub fn new(src_mac: [u8; 6], dst_mac: [u8; 6], ether_type: u16) -> Self {
         Self {
@@ -144,9 +415,31 @@ impl EthernetHeader {
     pub fn ether_type(&self) -> u16 {
         u16::from_be(self.ether_type)
     }
+
+    /// Serialize in network byte order
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..6].copy_from_slice(&self.dst_mac);
+        bytes[6..12].copy_from_slice(&self.src_mac);
+        bytes[12..14].copy_from_slice(&self.ether_type().to_be_bytes());
+        bytes
+    }
+
+    /// Deserialize from the start of `bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = header_bytes::<{ Self::LEN }>(bytes, "Ethernet")?;
+        let mut header = Self::new([0; 6], [0; 6], be16(bytes, 12));
+        header.dst_mac.copy_from_slice(&bytes[0..6]);
+        header.src_mac.copy_from_slice(&bytes[6..12]);
+        Ok(header)
+    }
 }
 
 /// UDP packet structure
+///
+/// A packet from [`UdpSocket::recv`] counts against the socket's zero-copy
+/// limit until it is dropped, whether it went back through
+/// [`UdpSocket::release`], the pool or not at all.
 pub struct UdpPacket {
     /// Mbuf containing the packet data
     pub mbuf: *mut Mbuf,
@@ -158,64 +451,86 @@ pub struct UdpPacket {
     pub udp_offset: usize,
     /// Payload offset
     pub payload_offset: usize,
+    /// Zero-copy borrow on the receiving socket
+    borrow: Option<ZeroCopyBorrow>,
+}
+
+/// One outstanding zero-copy packet of a socket, given back on drop
+struct ZeroCopyBorrow(Arc<AtomicUsize>);
+
+impl ZeroCopyBorrow {
+    fn take(outstanding: &Arc<AtomicUsize>) -> Self {
+        outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(outstanding.clone())
+    }
+}
+
+impl Drop for ZeroCopyBorrow {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl UdpPacket {
     /// Create a new UDP packet from an mbuf
+    ///
+    /// Mbufs already classified on receive are used as-is; unclassified
+    /// mbufs are classified first.
     pub fn from_mbuf(mbuf: *mut Mbuf) -> Result<Self> {
         if mbuf.is_null() {
             return Err(Error::NetworkError("Null mbuf".to_string()));
         }
 
-        let mbuf_ref = unsafe { &*mbuf };
-        let data = unsafe { std::slice::from_raw_parts(mbuf_ref.data, mbuf_ref.len) };
-
-        // Parse Ethernet header
-        if data.len() < std::mem::size_of::<EthernetHeader>() {
-            return Err(Error::NetworkError(
-                "Packet too small for Ethernet header".to_string(),
-            ));
-        }
-
-        let eth_offset = 0;
-        let eth_header = unsafe { &*(data.as_ptr().add(eth_offset) as *const EthernetHeader) };
-
-        // Check for IPv4
-        if eth_header.ether_type() != 0x0800 {
-            return Err(Error::NetworkError("Not an IPv4 packet".to_string()));
+        let mbuf_ref = unsafe { &mut *mbuf };
+        if mbuf_ref.packet_type == PacketType::Unknown {
+            classify(mbuf_ref);
         }
 
-        // Parse IPv4 header
-        let ip_offset = eth_offset + std::mem::size_of::<EthernetHeader>();
-        if data.len() < ip_offset + std::mem::size_of::<Ipv4Header>() {
-            return Err(Error::NetworkError(
-                "Packet too small for IPv4 header".to_string(),
-            ));
+        match mbuf_ref.packet_type {
+            PacketType::Udp => Self::from_classified(mbuf_ref),
+            PacketType::Unknown => Err(Error::NetworkError(
+                "Packet too small for Ethernet header".to_string(),
+            )),
+            PacketType::Ethernet | PacketType::Ipv6 => {
+                Err(Error::NetworkError("Not an IPv4 packet".to_string()))
+            }
+            _ => Err(Error::NetworkError("Not a UDP packet".to_string())),
         }
+    }
 
-        let ip_header = unsafe { &*(data.as_ptr().add(ip_offset) as *const Ipv4Header) };
-
-        // Check for UDP
-        if ip_header.protocol() != 17 {
-            return Err(Error::NetworkError("Not a UDP packet".to_string()));
-        }
+    /// Build the packet from offsets recorded by [`classify`]
+    fn from_classified(mbuf: &mut Mbuf) -> Result<Self> {
+        let udp_offset = mbuf.l4_offset as usize;
+        let payload_offset = mbuf.payload_offset as usize;
 
-        // Parse UDP header
-        let udp_offset = ip_offset + ((ip_header.version_ihl & 0x0F) as usize) * 4;
-        if data.len() < udp_offset + std::mem::size_of::<UdpHeader>() {
+        if payload_offset < udp_offset + std::mem::size_of::<UdpHeader>()
+            || payload_offset > mbuf.len
+        {
             return Err(Error::NetworkError(
                 "Packet too small for UDP header".to_string(),
             ));
         }
 
-        let payload_offset = udp_offset + std::mem::size_of::<UdpHeader>();
+        let data = mbuf.data();
+        let udp_len = u16::from_be_bytes([data[udp_offset + 4], data[udp_offset + 5]]) as usize;
+        // Datagrams cut short by the capture are kept, with what was captured
+        let truncated = mbuf.offload_flags.contains(OffloadFlags::RX_TRUNCATED);
+        if udp_len < std::mem::size_of::<UdpHeader>()
+            || (udp_offset + udp_len > mbuf.len && !truncated)
+        {
+            return Err(Error::NetworkError(format!(
+                "UDP length {} does not fit the frame",
+                udp_len
+            )));
+        }
 
         Ok(Self {
             mbuf,
-            eth_offset,
-            ip_offset,
+            eth_offset: 0,
+            ip_offset: mbuf.l3_offset as usize,
             udp_offset,
             payload_offset,
+            borrow: None,
         })
     }
 
@@ -240,17 +555,36 @@ impl UdpPacket {
         unsafe { &*(data.as_ptr().add(self.eth_offset) as *const EthernetHeader) }
     }
 
+    /// Get the IPv4 options, empty for a 20-byte header
+    pub fn ipv4_options(&self) -> Ipv4Options {
+        let data = unsafe { (*self.mbuf).data() };
+        let options = &data[self.ip_offset + std::mem::size_of::<Ipv4Header>()..self.udp_offset];
+        // Classification only accepts well-formed options
+        Ipv4Options::parse(options).unwrap_or_default()
+    }
+
     /// Get the payload data
+    ///
+    /// Only the bytes captured are returned, fewer than the UDP length
+    /// claims if the packet [`UdpPacket::is_truncated`].
     pub fn payload(&self) -> &[u8] {
         let mbuf_ref = unsafe { &*self.mbuf };
         let data = unsafe { std::slice::from_raw_parts(mbuf_ref.data, mbuf_ref.len) };
-        let udp_header = self.udp_header();
-        let payload_len = udp_header.length() as usize - std::mem::size_of::<UdpHeader>();
+        let end = self.payload_offset + self.claimed_payload_len();
+        &data[self.payload_offset.min(data.len())..end.min(data.len())]
+    }
 
-        if self.payload_offset + payload_len <= data.len() {
-            &data[self.payload_offset..self.payload_offset + payload_len]
-        } else {
-            &[]
+    /// Payload length the UDP header claims, captured or not
+    pub fn claimed_payload_len(&self) -> usize {
+        (self.udp_header().length() as usize).saturating_sub(std::mem::size_of::<UdpHeader>())
+    }
+
+    /// Whether the capture ended before the end of the datagram
+    pub fn is_truncated(&self) -> bool {
+        unsafe {
+            (*self.mbuf)
+                .offload_flags
+                .contains(OffloadFlags::RX_TRUNCATED)
         }
     }
 
@@ -269,17 +603,286 @@ impl UdpPacket {
 
         SocketAddr::new(IpAddr::V4(ip_header.dst_addr()), udp_header.dst_port())
     }
+
+    /// Trace ID assigned on receive, 0 if not traced
+    pub fn trace_id(&self) -> u32 {
+        unsafe { (*self.mbuf).trace_id }
+    }
+
+    /// Whether either checksum was marked bad on receive
+    pub fn checksum_bad(&self) -> bool {
+        let mbuf = unsafe { &*self.mbuf };
+        mbuf.l3_checksum() == ChecksumStatus::Bad || mbuf.l4_checksum() == ChecksumStatus::Bad
+    }
+}
+
+/// Classify a received frame, recording its packet type and header offsets
+///
+/// Runs once on receive so that later stages can rely on `Mbuf::packet_type`
+/// and the offset fields instead of parsing headers again. `PacketType::Udp`
+/// is only reported for unfragmented IPv4/UDP with a complete UDP header, so
+/// it can be used as a fast-path check by consumers.
+pub fn classify(mbuf: &mut Mbuf) -> PacketType {
+    mbuf.l3_offset = 0;
+    mbuf.l4_offset = 0;
+    mbuf.payload_offset = 0;
+
+    let data = mbuf.data();
+    let l3_offset = std::mem::size_of::<EthernetHeader>();
+    let mut l4_offset = 0;
+    let mut payload_offset = 0;
+
+    let packet_type = if data.len() < l3_offset {
+        PacketType::Unknown
+    } else {
+        match u16::from_be_bytes([data[12], data[13]]) {
+            ETHERTYPE_IPV4 => {
+                let ihl = (data.get(l3_offset).copied().unwrap_or(0) & 0x0F) as usize * 4;
+                if data.len() < l3_offset + std::mem::size_of::<Ipv4Header>()
+                    || data[l3_offset] >> 4 != 4
+                    || ihl < std::mem::size_of::<Ipv4Header>()
+                    || data.len() < l3_offset + ihl
+                    || !options::validate(
+                        &data[l3_offset + std::mem::size_of::<Ipv4Header>()..l3_offset + ihl],
+                    )
+                {
+                    PacketType::Ethernet
+                } else {
+                    let flags_fragment =
+                        u16::from_be_bytes([data[l3_offset + 6], data[l3_offset + 7]]);
+                    l4_offset = l3_offset + ihl;
+
+                    if flags_fragment & 0x1FFF != 0 {
+                        // Non-first fragments carry no L4 header
+                        PacketType::Ipv4
+                    } else {
+                        classify_l4(data, data[l3_offset + 9], l4_offset, &mut payload_offset)
+                            .unwrap_or(PacketType::Ipv4)
+                    }
+                }
+            }
+            ETHERTYPE_IPV6 => {
+                if data.len() < l3_offset + IPV6_HEADER_LEN {
+                    PacketType::Ethernet
+                } else {
+                    l4_offset = l3_offset + IPV6_HEADER_LEN;
+                    match classify_l4(data, data[l3_offset + 6], l4_offset, &mut payload_offset) {
+                        // Only IPv4/UDP is delivered by the stack
+                        Some(PacketType::Udp) => PacketType::Ipv6,
+                        Some(packet_type) => packet_type,
+                        None => PacketType::Ipv6,
+                    }
+                }
+            }
+            _ => PacketType::Ethernet,
+        }
+    };
+
+    let truncated = packet_type == PacketType::Udp && snapped(data, l3_offset, l4_offset);
+    mbuf.offload_flags
+        .set(OffloadFlags::RX_TRUNCATED, truncated);
+    if packet_type != PacketType::Unknown && packet_type != PacketType::Ethernet {
+        mbuf.l3_offset = l3_offset as u16;
+        mbuf.l4_offset = l4_offset as u16;
+        mbuf.payload_offset = payload_offset as u16;
+    }
+    mbuf.packet_type = packet_type;
+
+    packet_type
+}
+
+/// Whether an IPv4/UDP frame was captured short of a UDP length its IPv4 length agrees with
+///
+/// A UDP length past the IPv4 total length is a broken header instead.
+fn snapped(data: &[u8], l3_offset: usize, l4_offset: usize) -> bool {
+    let ip_end =
+        l3_offset + u16::from_be_bytes([data[l3_offset + 2], data[l3_offset + 3]]) as usize;
+    let udp_len = u16::from_be_bytes([data[l4_offset + 4], data[l4_offset + 5]]) as usize;
+    let udp_end = l4_offset + udp_len;
+    udp_len >= std::mem::size_of::<UdpHeader>() && udp_end > data.len() && udp_end <= ip_end
+}
+
+/// Classify the L4 protocol, returning `None` for unknown or truncated headers
+fn classify_l4(
+    data: &[u8],
+    protocol: u8,
+    l4_offset: usize,
+    payload_offset: &mut usize,
+) -> Option<PacketType> {
+    match protocol {
+        IPPROTO_UDP if data.len() >= l4_offset + std::mem::size_of::<UdpHeader>() => {
+            *payload_offset = l4_offset + std::mem::size_of::<UdpHeader>();
+            Some(PacketType::Udp)
+        }
+        IPPROTO_TCP => Some(PacketType::Tcp),
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => Some(PacketType::Icmp),
+        _ => None,
+    }
+}
+
+/// Writable transmit buffer handed out by [`UdpSocket::alloc_tx_buffer`]
+///
+/// The payload area starts after [`TX_HEADROOM`] bytes reserved for the
+/// Ethernet, IPv4 and UDP headers, which are filled in place when the buffer
+/// is passed to `send_prepared`. Dropping an unsent buffer returns its mbuf
+/// to the pool.
+pub struct TxBuffer {
+    /// Mbuf holding the frame
+    mbuf: *mut Mbuf,
+    /// Pool the mbuf was allocated from
+    pool: Arc<MbufPool>,
+    /// Owning socket ID
+    socket_id: u16,
+    /// Payload length
+    payload_len: usize,
+    /// Completion slot of a send made with a cookie
+    completion: Option<CompletionToken>,
+    /// DSCP marking instead of the socket's
+    dscp: Option<u8>,
+}
+
+impl TxBuffer {
+    /// Get the payload area
+    pub fn payload(&self) -> &[u8] {
+        &self.frame()[TX_HEADROOM..]
+    }
+
+    /// Get the writable payload area
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.frame_mut()[TX_HEADROOM..]
+    }
+
+    /// Get payload length
+    pub fn payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    /// Resize the payload to `len` bytes, within the mbuf
+    fn set_payload_len(&mut self, len: usize) -> Result<()> {
+        if TX_HEADROOM + len > self.pool.buf_size()
+            || std::mem::size_of::<UdpHeader>() + len > u16::MAX as usize
+        {
+            return Err(Error::NetworkError(
+                "Payload too large for mbuf".to_string(),
+            ));
+        }
+        self.payload_len = len;
+        unsafe {
+            (*self.mbuf).len = TX_HEADROOM + len;
+        }
+        Ok(())
+    }
+
+    /// Shrink the payload to `len` bytes
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.payload_len {
+            self.payload_len = len;
+            unsafe {
+                (*self.mbuf).len = TX_HEADROOM + len;
+            }
+        }
+    }
+
+    /// Get the ID of the socket this buffer was allocated for
+    pub fn socket_id(&self) -> u16 {
+        self.socket_id
+    }
+
+    /// Mark this datagram with `dscp` instead of the socket's DSCP
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.dscp = Some(dscp & 0x3F);
+    }
+
+    /// Get the DSCP this datagram is marked with, if not the socket's
+    pub fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    /// Continue the trace of a received packet when this buffer is sent
+    pub fn set_trace_id(&mut self, trace_id: u32) {
+        unsafe { (*self.mbuf).trace_id = trace_id };
+    }
+
+    /// Get the underlying mbuf
+    pub fn mbuf(&self) -> *mut Mbuf {
+        self.mbuf
+    }
+
+    /// Get the whole frame, headers included
+    fn frame(&self) -> &[u8] {
+        unsafe { (*self.mbuf).data() }
+    }
+
+    /// Get the whole frame, headers included (mutable)
+    fn frame_mut(&mut self) -> &mut [u8] {
+        unsafe { (*self.mbuf).data_mut() }
+    }
+}
+
+impl Drop for TxBuffer {
+    fn drop(&mut self) {
+        let _ = self.pool.free(self.mbuf);
+    }
 }
 
 /// UDP socket statistics
 #[derive(Debug, Default)]
 pub struct UdpSocketStats {
-    pub packets_received: AtomicUsize,
-    pub bytes_received: AtomicUsize,
-    pub packets_sent: AtomicUsize,
-    pub bytes_sent: AtomicUsize,
-    pub packets_dropped: AtomicUsize,
-    pub errors: AtomicUsize,
+    pub packets_received: Counter,
+    pub bytes_received: Counter,
+    pub packets_sent: Counter,
+    pub bytes_sent: Counter,
+    pub packets_dropped: Counter,
+    pub errors: Counter,
+    /// Datagrams rejected by the receive filter
+    pub filtered: Counter,
+    /// Datagrams dropped early while the pool ran low, see [`EarlyDropConfig`]
+    pub early_dropped: Counter,
+}
+
+/// Names and counters of a stack's open sockets, readable from other threads
+///
+/// Snapshot writers running off the stack's thread take the socket
+/// counters from here, see [`crate::Xpdk::spawn_stats_persister`].
+#[derive(Clone, Default)]
+pub(crate) struct SocketDirectory(Arc<parking_lot::RwLock<SocketEntries>>);
+
+/// Name and counters of each open socket by ID
+type SocketEntries = BTreeMap<u16, (String, Arc<UdpSocketStats>)>;
+
+impl SocketDirectory {
+    fn insert(&self, socket: &UdpSocket) {
+        self.0
+            .write()
+            .insert(socket.id, (socket.name.clone(), socket.stats.clone()));
+    }
+
+    fn rename(&self, socket_id: u16, name: &str) {
+        if let Some(entry) = self.0.write().get_mut(&socket_id) {
+            entry.0 = name.to_string();
+        }
+    }
+
+    fn remove(&self, socket_id: u16) {
+        self.0.write().remove(&socket_id);
+    }
+
+    /// Counters of every open socket, in ID order
+    pub(crate) fn counters(&self) -> Vec<SocketCounters> {
+        self.0
+            .read()
+            .values()
+            .map(|(name, stats)| SocketCounters {
+                name: name.clone(),
+                packets_received: stats.packets_received.get(),
+                bytes_received: stats.bytes_received.get(),
+                packets_sent: stats.packets_sent.get(),
+                bytes_sent: stats.bytes_sent.get(),
+                packets_dropped: stats.packets_dropped.get(),
+                errors: stats.errors.get(),
+            })
+            .collect()
+    }
 }
 
 /// UDP socket implementation
@@ -287,29 +890,172 @@ pub struct UdpSocket {
     /// Local socket address
     local_addr: SocketAddr,
     /// Receive queue for incoming packets
-    recv_queue: Arc<SpscRingBuffer<*mut Mbuf>>,
+    recv_queue: SpscRingBuffer<*mut Mbuf>,
+    /// What the receive queue gives up when full
+    shed_policy: ShedPolicy,
+    /// Datagrams shed by the receive queue
+    shed_stats: ShedStats,
+    /// Where the receive queue slots were placed
+    queue_placement: QueuePlacement,
     /// Transmit queue for outgoing packets
     tx_queue: Option<Arc<TxQueue>>,
+    /// Memory pool for outgoing packets
+    tx_pool: Option<Arc<MbufPool>>,
+    /// Source MAC address of outgoing frames
+    src_mac: [u8; 6],
+    /// Destination MAC address of outgoing frames
+    dst_mac: [u8; 6],
+    /// Datagrams the socket accepts, checked before the replay guard
+    rx_filter: Option<RxFilter>,
+    /// Replay protection applied before packets are queued
+    replay_guard: Option<ReplayGuard>,
+    /// Puts each peer's datagrams in sequence order before they are queued
+    reorder: Option<ReorderBuffer>,
+    /// DSCP priority bands, used instead of `recv_queue` when set
+    priority: Option<BandedQueue>,
+    /// Sub-channels by payload tag, tried before the receive queue
+    demux: Option<Demux>,
+    /// Memory pool received mbufs are returned to
+    rx_pool: Option<Arc<MbufPool>>,
+    /// Payload vectors handed out by `recv_copied`
+    copy_buffers: CopyBufferPool,
+    /// Zero-copy packets the application may hold at once
+    zero_copy_limit: Option<usize>,
+    /// Zero-copy packets received and not yet dropped
+    outstanding: Arc<AtomicUsize>,
+    /// Delivery mode and copy fallback, switchable while running
+    delivery: DeliveryControl,
+    /// Payloads delivered by copy
+    copy_ring: CopyRing,
+    /// Protocol counters shared with the owning stack
+    mib: Arc<ProtocolMib>,
+    /// Close or report the socket after this long without RX/TX
+    idle_timeout: Option<Duration>,
+    /// Monotonic time of the last packet received or sent
+    last_activity: AtomicU64,
+    /// Keep-alive sent after a quiet interval, set through [`UdpStack::set_keepalive`]
+    keepalive: Option<KeepAlive>,
+    /// Path MTU cache shared with the owning stack
+    pmtu: Arc<PmtuCache>,
+    /// Set DF on sent datagrams and reject those above the path MTU
+    dont_fragment: bool,
+    /// IPv4 TTL of sent datagrams
+    ttl: u8,
+    /// DSCP of sent datagrams
+    dscp: u8,
+    /// Traffic class of sent datagrams by DSCP
+    traffic_classes: TrafficClassMap,
+    /// TX queue of each traffic class, instead of `tx_queue`
+    class_queues: [Option<Arc<TxQueue>>; TRAFFIC_CLASSES],
+    /// Egress counters shared with the owning stack
+    egress: Arc<EgressStats>,
+    /// IPv4 identification of the next fragmented datagram, starting at a random value
+    next_ip_id: AtomicU16,
+    /// Port ID of the only interface used for RX and TX, `None` for any
+    bound_device: Option<u16>,
+    /// Header templates of recent destinations
+    templates: TemplateCache,
+    /// Source ports of flows, shared with the owning stack
+    ephemeral: Arc<EphemeralPorts>,
+    /// Next hop addresses looked up on send instead of `dst_mac`
+    neighbors: Option<Arc<NeighborTable>>,
+    /// Outcomes of sends made with a cookie
+    completions: Option<Arc<CompletionQueue>>,
+    /// Departure times of batches sent with `send_batch_spread`
+    spreader: Spreader,
+    /// Budget of the stack, charged for the socket's reorder and replay tables
+    budget: Option<Arc<MemoryBudget>>,
+    /// Memory budget held for the receive queue
+    #[allow(dead_code)]
+    queue_charge: Option<BudgetCharge>,
+    /// Send each destination from its own random source port
+    randomize_source_port: bool,
+    /// Decrypts received and encrypts sent payloads in place
+    transform: Option<Box<dyn PayloadTransform>>,
+    /// Payload transform counters
+    transform_stats: TransformStats,
+    /// Compresses sent and decompresses received payloads of matching ports
+    compression: Option<Arc<PayloadCompressor>>,
     /// Socket statistics
-    stats: UdpSocketStats,
+    stats: Arc<UdpSocketStats>,
     /// Running flag
     running: AtomicBool,
     /// Socket ID
     id: u16,
+    /// Name in logs and telemetry
+    name: String,
 }
 
 impl UdpSocket {
     /// Create a new UDP socket
     pub fn new(local_addr: SocketAddr, queue_size: usize, id: u16) -> Result<Self> {
-        let recv_queue = Arc::new(SpscRingBuffer::new(queue_size));
+        Self::with_placement(local_addr, queue_size, id, QueuePlacement::Heap)
+    }
+
+    /// Create a UDP socket with its receive queue slots placed as requested
+    pub fn with_placement(
+        local_addr: SocketAddr,
+        queue_size: usize,
+        id: u16,
+        placement: QueuePlacement,
+    ) -> Result<Self> {
+        let (memory, queue_placement) = queue::slot_memory(queue_size, placement)?;
+        let ephemeral = Arc::new(EphemeralPorts::default());
+        ephemeral.reserve_bound(local_addr.port());
+        let recv_queue = match memory {
+            Some(memory) => SpscRingBuffer::with_memory(memory),
+            None => SpscRingBuffer::new(queue_size),
+        };
 
         Ok(Self {
             local_addr,
             recv_queue,
+            shed_policy: ShedPolicy::default(),
+            shed_stats: ShedStats::default(),
+            queue_placement,
             tx_queue: None,
-            stats: UdpSocketStats::default(),
+            tx_pool: None,
+            src_mac: [0; 6],
+            dst_mac: [0xFF; 6],
+            rx_filter: None,
+            replay_guard: None,
+            reorder: None,
+            priority: None,
+            demux: None,
+            rx_pool: None,
+            copy_buffers: CopyBufferPool::new(DEFAULT_COPY_BUFFERS),
+            zero_copy_limit: None,
+            delivery: DeliveryControl::default(),
+            copy_ring: CopyRing::new(DEFAULT_COPY_RING_BYTES),
+            outstanding: Arc::new(AtomicUsize::new(0)),
+            mib: Arc::new(ProtocolMib::default()),
+            idle_timeout: None,
+            last_activity: AtomicU64::new(monotonic_now()),
+            keepalive: None,
+            pmtu: Arc::new(PmtuCache::new(Config::default().mtu)),
+            dont_fragment: false,
+            ttl: DEFAULT_TTL,
+            dscp: 0,
+            traffic_classes: TrafficClassMap::default(),
+            class_queues: Default::default(),
+            egress: Arc::new(EgressStats::default()),
+            next_ip_id: AtomicU16::new(Rng::for_component("ip-id").next_u32() as u16),
+            bound_device: None,
+            templates: TemplateCache::default(),
+            ephemeral,
+            neighbors: None,
+            completions: None,
+            spreader: Spreader::default(),
+            budget: None,
+            queue_charge: None,
+            randomize_source_port: false,
+            transform: None,
+            transform_stats: TransformStats::default(),
+            compression: None,
+            stats: Arc::default(),
             running: AtomicBool::new(false),
             id,
+            name: format!("socket{}", id),
         })
     }
 
@@ -318,221 +1064,2369 @@ impl UdpSocket {
         self.tx_queue = Some(tx_queue);
     }
 
-    /// Receive a packet
-    pub fn recv(&self) -> Result<UdpPacket> {
-        match self.recv_queue.pop() {
-            Ok(mbuf) => {
-                let packet = UdpPacket::from_mbuf(mbuf)?;
-                self.stats.packets_received.fetch_add(1, Ordering::Relaxed);
-                self.stats
-                    .bytes_received
-                    .fetch_add(packet.payload().len(), Ordering::Relaxed);
-                Ok(packet)
-            }
-            Err(_) => Err(Error::NetworkError("No packet available".to_string())),
-        }
+    /// Bind the socket to the memory pool used for outgoing packets
+    pub fn bind_tx_pool(&mut self, pool: Arc<MbufPool>) {
+        self.tx_pool = Some(pool);
     }
 
-    /// Receive multiple packets in batch
-    pub fn recv_batch(&self, packets: &mut [UdpPacket], max_count: usize) -> Result<usize> {
-        let mut received = 0;
+    /// Bind the memory pool received mbufs are returned to
+    pub fn bind_rx_pool(&mut self, pool: Arc<MbufPool>) {
+        self.rx_pool = Some(pool);
+    }
 
-        for i in 0..max_count.min(packets.len()) {
-            match self.recv() {
-                Ok(packet) => {
-                    packets[i] = packet;
-                    received += 1;
-                }
-                Err(Error::NetworkError(_)) => break,
-                Err(e) => return Err(e),
-            }
-        }
+    /// Count transmitted frames in `mib`
+    pub(crate) fn bind_mib(&mut self, mib: Arc<ProtocolMib>) {
+        self.mib = mib;
+    }
 
-        Ok(received)
+    /// Allocate source ports from the stack-wide `ephemeral`
+    pub(crate) fn bind_ephemeral(&mut self, ephemeral: Arc<EphemeralPorts>) {
+        self.ephemeral = ephemeral;
     }
 
-    /// Send a packet
-    pub fn send(&self, dst_addr: SocketAddr, data: &[u8]) -> Result<()> {
-        let tx_queue = self
-            .tx_queue
-            .as_ref()
-            .ok_or_else(|| Error::NetworkError("No transmit queue bound".to_string()))?;
+    /// Resolve destination MACs through `neighbors`, `None` to use the static one
+    pub(crate) fn bind_neighbors(&mut self, neighbors: Option<Arc<NeighborTable>>) {
+        self.neighbors = neighbors;
+    }
 
-        // Create packet
-        let mbuf = self.create_packet(dst_addr, data)?;
+    /// Compress the payloads of ports matched by `compression`
+    pub(crate) fn bind_compression(&mut self, compression: Option<Arc<PayloadCompressor>>) {
+        self.compression = compression;
+    }
 
-        // Send packet
-        tx_queue.send(mbuf)?;
+    /// Size sends by the path MTUs in `pmtu`
+    pub(crate) fn bind_pmtu(&mut self, pmtu: Arc<PmtuCache>) {
+        self.pmtu = pmtu;
+    }
 
-        self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
-        self.stats
-            .bytes_sent
-            .fetch_add(data.len(), Ordering::Relaxed);
+    /// Where the receive queue slots were placed
+    pub fn queue_placement(&self) -> QueuePlacement {
+        self.queue_placement
+    }
 
-        Ok(())
+    /// Port ID of the interface the socket is bound to, `None` if wildcard
+    pub fn bound_device(&self) -> Option<u16> {
+        self.bound_device
     }
 
-    /// Send multiple packets in batch
-    pub fn send_batch(&self, packets: &[(SocketAddr, &[u8])]) -> Result<usize> {
-        let mut sent = 0;
+    /// Set DF on sent datagrams; oversized sends then fail instead of fragmenting
+    pub fn set_dont_fragment(&mut self, dont_fragment: bool) {
+        self.dont_fragment = dont_fragment;
+        self.templates.invalidate(None);
+    }
 
-        for (dst_addr, data) in packets.iter().take(packets.len()) {
-            match self.send(*dst_addr, data) {
-                Ok(_) => sent += 1,
-                Err(_) => break,
-            }
-        }
+    /// Whether sent datagrams carry DF
+    pub fn dont_fragment(&self) -> bool {
+        self.dont_fragment
+    }
 
-        Ok(sent)
+    /// Set the IPv4 TTL of sent datagrams
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+        self.templates.invalidate(None);
     }
 
-    /// Create a UDP packet
-    fn create_packet(&self, _dst_addr: SocketAddr, _data: &[u8]) -> Result<*mut Mbuf> {
-        // This is a simplified implementation
-        // In a real implementation, we would need to allocate an mbuf and build the packet
+    /// IPv4 TTL of sent datagrams
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
 
-        // For now, return an error to indicate this needs proper implementation
-        Err(Error::NetworkError(
-            "Packet creation not implemented".to_string(),
-        ))
+    /// Mark sent datagrams with `dscp`, best effort (0) by default
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.dscp = dscp & 0x3F;
+        self.templates.invalidate(None);
     }
 
-    /// Start the socket
-    pub fn start(&self) -> Result<()> {
-        self.running.store(true, Ordering::Relaxed);
-        Ok(())
+    /// DSCP of sent datagrams
+    pub fn dscp(&self) -> u8 {
+        self.dscp
     }
 
-    /// Stop the socket
-    pub fn stop(&self) -> Result<()> {
-        self.running.store(false, Ordering::Relaxed);
-        Ok(())
+    /// Map the DSCP of sent datagrams to traffic classes with `map`
+    pub fn set_traffic_classes(&mut self, map: TrafficClassMap) {
+        self.traffic_classes = map;
     }
 
-    /// Get socket statistics
-    pub fn stats(&self) -> &UdpSocketStats {
-        &self.stats
+    /// Get the DSCP to traffic class map of sent datagrams
+    pub fn traffic_classes(&self) -> &TrafficClassMap {
+        &self.traffic_classes
     }
 
-    /// Get local address
-    pub fn local_addr(&self) -> SocketAddr {
-        self.local_addr
+    /// Send datagrams of `class` on `tx_queue` instead of the socket's TX queue
+    pub fn bind_class_queue(&mut self, class: TrafficClass, tx_queue: Arc<TxQueue>) {
+        self.class_queues[class.index()] = Some(tx_queue);
     }
 
-    /// Get socket ID
-    pub fn id(&self) -> u16 {
-        self.id
+    /// Get sent datagrams by traffic class, counted across the owning stack
+    pub fn egress_stats(&self) -> &EgressStats {
+        &self.egress
     }
-}
 
-/// UDP stack implementation
-pub struct UdpStack {
-    /// Stack configuration
-    #[allow(dead_code)]
-    config: Config,
-    /// UDP sockets
-    sockets: HashMap<u16, UdpSocket>,
-    /// Next socket ID
-    next_socket_id: AtomicUsize,
-    /// Running flag
-    running: AtomicBool,
-    /// Stack statistics
-    stats: UdpStackStats,
-}
+    pub(crate) fn bind_egress(&mut self, egress: Arc<EgressStats>) {
+        self.egress = egress;
+    }
 
-/// UDP stack statistics
-#[derive(Debug, Default)]
-pub struct UdpStackStats {
-    pub total_sockets: AtomicUsize,
-    pub active_sockets: AtomicUsize,
-    pub total_packets_received: AtomicUsize,
-    pub total_packets_sent: AtomicUsize,
-    pub total_bytes_received: AtomicUsize,
-    pub total_bytes_sent: AtomicUsize,
-    pub total_errors: AtomicUsize,
-}
+    /// Current path MTU towards `dst_addr`; the link MTU for IPv6 destinations
+    pub fn path_mtu(&self, dst_addr: SocketAddr) -> u16 {
+        match dst_addr.ip() {
+            IpAddr::V4(dst_ip) => self.pmtu.pmtu(dst_ip),
+            IpAddr::V6(_) => self.pmtu.link_mtu(),
+        }
+    }
 
-impl UdpStack {
-    /// Create a new UDP stack
-    pub fn new(config: &Config) -> Result<Self> {
-        Ok(Self {
-            config: config.clone(),
-            sockets: HashMap::new(),
-            next_socket_id: AtomicUsize::new(1),
-            running: AtomicBool::new(false),
-            stats: UdpStackStats::default(),
-        })
+    /// Largest payload sent to `dst_addr` as a single frame
+    pub fn max_payload(&self, dst_addr: SocketAddr) -> usize {
+        (self.path_mtu(dst_addr) as usize)
+            .saturating_sub(std::mem::size_of::<Ipv4Header>() + std::mem::size_of::<UdpHeader>())
     }
 
-    /// Create a new UDP socket
-    pub fn create_socket(&mut self, local_addr: SocketAddr) -> Result<u16> {
-        let socket_id = self.next_socket_id.fetch_add(1, Ordering::Relaxed) as u16;
-        let queue_size = 1024; // Default queue size
+    /// Cap the zero-copy packets held by the application, `None` for no cap
+    ///
+    /// With a cap, packets from [`UdpSocket::recv`] must be handed back with
+    /// [`UdpSocket::release`] for the socket to deliver more.
+    pub fn set_zero_copy_limit(&mut self, limit: Option<usize>) {
+        self.zero_copy_limit = limit;
+    }
 
-        let socket = UdpSocket::new(local_addr, queue_size, socket_id)?;
+    /// Zero-copy packets received and not yet dropped
+    pub fn outstanding_zero_copy(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
 
-        self.sockets.insert(socket_id, socket);
-        self.stats.total_sockets.fetch_add(1, Ordering::Relaxed);
+    /// Fault in the receive queue storage, returning the bytes touched
+    pub fn prefault_queue(&self) -> usize {
+        let bands = self.priority.as_ref().map_or(0, BandedQueue::prefault);
+        let channels = self.demux.as_ref().map_or(0, Demux::prefault);
+        self.recv_queue.prefault() + bands + channels
+    }
+
+    /// Pool buffers the socket holds, queued or handed out without copying
+    pub fn held_buffers(&self) -> usize {
+        let queued = match &self.priority {
+            Some(bands) => bands.len(),
+            None => self.recv_queue.len(),
+        };
+        let channels = self.demux.as_ref().map_or(0, Demux::len);
+        queued + channels + self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Switch between queueing mbufs and copying payloads on arrival
+    ///
+    /// Takes effect for the next datagram; packets already queued are still
+    /// received as before.
+    pub fn set_delivery_mode(&self, mode: DeliveryMode) {
+        self.delivery.set_mode(mode);
+    }
+
+    /// Get the configured delivery mode
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery.mode()
+    }
+
+    /// Copy datagrams instead while `threshold` zero-copy packets are outstanding
+    ///
+    /// `None` never falls back.
+    pub fn set_copy_fallback(&self, threshold: Option<usize>) {
+        self.delivery.set_fallback_threshold(threshold);
+    }
+
+    /// Get the copy fallback threshold
+    pub fn copy_fallback(&self) -> Option<usize> {
+        self.delivery.fallback_threshold()
+    }
+
+    /// Get delivery mode counters
+    pub fn delivery_stats(&self) -> &DeliveryStats {
+        &self.delivery.stats
+    }
+
+    /// Bytes held by copied datagrams not yet received
+    pub fn copy_ring_used(&self) -> usize {
+        self.copy_ring.used()
+    }
+
+    /// Set the MAC addresses written into outgoing frames
+    pub fn set_mac_addresses(&mut self, src_mac: [u8; 6], dst_mac: [u8; 6]) {
+        self.src_mac = src_mac;
+        self.dst_mac = dst_mac;
+        self.templates.invalidate(None);
+    }
+
+    /// Drop cached header templates towards `dst_addr`, or all if `None`
+    ///
+    /// Call when the neighbor or route towards a destination changed.
+    pub fn invalidate_header_templates(&self, dst_addr: Option<SocketAddr>) {
+        match dst_addr {
+            Some(SocketAddr::V4(dst_addr)) => self.templates.invalidate(Some(dst_addr)),
+            Some(SocketAddr::V6(_)) => {}
+            None => self.templates.invalidate(None),
+        }
+    }
+
+    /// Header template cache counters
+    pub fn template_stats(&self) -> TemplateStats {
+        self.templates.stats()
+    }
+
+    /// Send each destination from its own random source port
+    ///
+    /// Disabling returns to the bound port and frees every source port of
+    /// the socket, pinned ones included.
+    pub fn set_source_port_randomization(&mut self, enabled: bool) {
+        self.randomize_source_port = enabled;
+        if !enabled {
+            self.ephemeral.release_socket(self.id);
+        }
+        self.templates.invalidate(None);
+    }
+
+    /// Check whether source ports are randomized
+    pub fn source_port_randomization(&self) -> bool {
+        self.randomize_source_port
+    }
+
+    /// Source port datagrams to `dst_addr` are sent from
+    pub fn source_port(&self, dst_addr: SocketAddr) -> Result<u16> {
+        let dst = ipv4_destination(dst_addr)?;
+        if self.randomize_source_port {
+            return self.ephemeral.get_or_allocate(self.id, dst);
+        }
+        Ok(self
+            .ephemeral
+            .get(self.id, dst)
+            .unwrap_or(self.local_addr.port()))
+    }
+
+    /// Send to `dst_addr` from `port` until cycled or randomization is disabled
+    ///
+    /// Fails if another socket is bound to `port` or another flow uses it.
+    pub fn pin_source_port(&self, dst_addr: SocketAddr, port: u16) -> Result<()> {
+        let dst = ipv4_destination(dst_addr)?;
+        self.ephemeral.pin(self.id, dst, port)?;
+        self.templates.invalidate(Some(dst));
+        Ok(())
+    }
+
+    /// Move the flow towards `dst_addr` to a fresh random source port
+    pub fn cycle_source_port(&self, dst_addr: SocketAddr) -> Result<u16> {
+        let dst = ipv4_destination(dst_addr)?;
+        let port = self.ephemeral.cycle(self.id, dst)?;
+        self.templates.invalidate(Some(dst));
+        Ok(port)
+    }
+
+    /// Drop received datagrams `filter` rejects, `None` to accept all
+    pub fn set_rx_filter(&mut self, filter: Option<RxFilter>) {
+        self.rx_filter = filter;
+    }
+
+    /// Get the receive filter, if any
+    pub fn rx_filter(&self) -> Option<&RxFilter> {
+        self.rx_filter.as_ref()
+    }
+
+    /// Reject replayed or late packets before they are queued
+    ///
+    /// On a stack, a guard not charged to a budget yet is charged to the
+    /// stack's, see [`ReplayGuard::with_budget`].
+    pub fn set_replay_guard(&mut self, guard: ReplayGuard) {
+        self.replay_guard = Some(match &self.budget {
+            Some(budget) if !guard.is_budgeted() => guard.with_budget(budget.clone()),
+            _ => guard,
+        });
+    }
+
+    /// Get the replay guard, if any
+    pub fn replay_guard(&self) -> Option<&ReplayGuard> {
+        self.replay_guard.as_ref()
+    }
+
+    /// Queue each peer's datagrams in the order of the sequence numbers `extractor` reads
+    ///
+    /// See [`ReorderBuffer`]. Fails without a receive pool, which held
+    /// datagrams are freed to when the buffer goes, or with a config out of
+    /// range; a buffer replaced frees what it held.
+    pub fn set_reorder_buffer<F>(&mut self, config: ReorderConfig, extractor: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Option<u64> + Send + Sync + 'static,
+    {
+        let pool = self.rx_pool()?.clone();
+        let mut reorder = ReorderBuffer::new(config, Box::new(extractor), pool)?;
+        if let Some(budget) = &self.budget {
+            reorder = reorder.with_budget(budget);
+        }
+        self.reorder = Some(reorder);
+        Ok(())
+    }
+
+    /// Get the reorder buffer, if any
+    pub fn reorder_buffer(&self) -> Option<&ReorderBuffer> {
+        self.reorder.as_ref()
+    }
+
+    /// Decrypt received and encrypt sent payloads with `transform`, `None` to stop
+    pub fn set_transform(&mut self, transform: Option<Box<dyn PayloadTransform>>) {
+        self.transform = transform;
+    }
+
+    /// Check if the socket has a payload transform
+    pub fn has_transform(&self) -> bool {
+        self.transform.is_some()
+    }
+
+    /// Get payload transform counters
+    pub fn transform_stats(&self) -> &TransformStats {
+        &self.transform_stats
+    }
+
+    /// Decrypt the payload of a received packet in place
+    ///
+    /// The UDP length is shortened to the plaintext and the UDP checksum,
+    /// which covered the ciphertext, is cleared.
+    fn decrypt(&self, packet: &UdpPacket) -> Result<()> {
+        let Some(transform) = &self.transform else {
+            return Ok(());
+        };
+        let start = packet.payload_offset;
+        let end = start + packet.payload().len();
+        let data = unsafe { (*packet.mbuf).data_mut() };
+
+        let result = transform
+            .decrypt(&mut data[start..end])
+            .and_then(|len| check_transformed(len, end - start));
+        self.transform_stats.record_decrypt(&result);
+        let udp_length = (UdpHeader::LEN + result?) as u16;
+        let udp_offset = packet.udp_offset;
+        data[udp_offset + 4..udp_offset + 6].copy_from_slice(&udp_length.to_be_bytes());
+        data[udp_offset + 6..udp_offset + 8].fill(0);
+        Ok(())
+    }
+
+    /// Encrypt the payload of a buffer about to be sent in place
+    fn encrypt(&self, buffer: &mut TxBuffer) -> Result<()> {
+        let Some(transform) = &self.transform else {
+            return Ok(());
+        };
+        let len = buffer.payload_len();
+        let room = len + transform.overhead();
+        buffer.set_payload_len(room)?;
+
+        let result = transform
+            .encrypt(&mut buffer.payload_mut()[..room], len)
+            .and_then(|len| check_transformed(len, room));
+        self.transform_stats.record_encrypt(&result);
+        buffer.set_payload_len(result?)
+    }
+
+    /// Decompress the payload of a received packet into the tailroom of its mbuf
+    ///
+    /// Like [`UdpSocket::decrypt`], this rewrites the UDP length and clears
+    /// the UDP checksum.
+    fn decompress(&self, packet: &UdpPacket) -> Result<()> {
+        let Some(compressor) = self
+            .compression
+            .as_ref()
+            .filter(|c| c.applies(self.local_addr.port(), packet.src_addr().port()))
+        else {
+            return Ok(());
+        };
+        let start = packet.payload_offset;
+        let len = packet.payload().len();
+        let mbuf = unsafe { &mut *packet.mbuf };
+        let room = (mbuf.buf_len - start).min(u16::MAX as usize - UdpHeader::LEN);
+        let payload = unsafe { std::slice::from_raw_parts_mut(mbuf.data.add(start), room) };
+
+        let len = compressor.decompress(payload, len)?;
+        mbuf.len = start + len;
+        let udp_length = (UdpHeader::LEN + len) as u16;
+        let data = mbuf.data_mut();
+        let udp_offset = packet.udp_offset;
+        data[udp_offset + 4..udp_offset + 6].copy_from_slice(&udp_length.to_be_bytes());
+        data[udp_offset + 6..udp_offset + 8].fill(0);
+        Ok(())
+    }
+
+    /// Compress the payload of a buffer about to be sent to `dst_addr` in place
+    fn compress(&self, buffer: &mut TxBuffer, dst_addr: SocketAddr) -> Result<()> {
+        let Some(compressor) = self
+            .compression
+            .as_ref()
+            .filter(|c| c.applies(self.local_addr.port(), dst_addr.port()))
+        else {
+            return Ok(());
+        };
+        let len = buffer.payload_len();
+        buffer.set_payload_len(len + MAX_OVERHEAD)?;
+        let len = compressor.compress(buffer.payload_mut(), len);
+        buffer.set_payload_len(len)
+    }
+
+    /// Deliver received packets in DSCP priority bands
+    ///
+    /// Packets already queued before the call are still received after
+    /// the bands are drained.
+    pub fn set_priority_bands(&mut self, bands: PriorityBands) {
+        self.priority = Some(BandedQueue::new(bands));
+    }
+
+    /// Get the priority band configuration, if any
+    pub fn priority_bands(&self) -> Option<&PriorityBands> {
+        self.priority.as_ref().map(BandedQueue::bands)
+    }
+
+    /// Get per-band counters, if priority bands are enabled
+    pub fn band_stats(&self) -> Option<Vec<BandStats>> {
+        self.priority.as_ref().map(BandedQueue::stats)
+    }
+
+    /// Split delivery into sub-channels by payload tag, see [`DemuxConfig`]
+    ///
+    /// Replaces any channels added before, freeing the datagrams still
+    /// queued on them to the receive pool. Fails, keeping the channels, if
+    /// they hold datagrams and the socket has no receive pool.
+    pub fn set_demux(&mut self, config: DemuxConfig) -> Result<()> {
+        if let Some(previous) = &self.demux {
+            if previous.len() != 0 {
+                let pool = self.rx_pool()?;
+                for mbuf in previous.drain() {
+                    pool.free(mbuf)?;
+                }
+            }
+        }
+        self.demux = Some(Demux::new(config));
+        Ok(())
+    }
+
+    /// Get where the demultiplexing tag is, if sub-channels are enabled
+    pub fn demux_config(&self) -> Option<&DemuxConfig> {
+        self.demux.as_ref().map(Demux::config)
+    }
+
+    /// Add the sub-channel receiving datagrams tagged `tag`
+    pub fn add_channel(&mut self, tag: &[u8]) -> Result<()> {
+        self.demux
+            .as_mut()
+            .ok_or_else(|| Error::InvalidConfig(format!("Socket {} has no demux", self.id)))?
+            .add(tag)
+    }
+
+    /// Get the counters of the sub-channel for `tag`
+    pub fn channel_stats(&self, tag: &[u8]) -> Result<&ChannelStats> {
+        self.demux()?.stats(tag)
+    }
+
+    /// Datagrams whose tag matched no sub-channel, queued on the socket itself
+    pub fn demux_unmatched(&self) -> u64 {
+        self.demux.as_ref().map_or(0, Demux::unmatched)
+    }
+
+    fn demux(&self) -> Result<&Demux> {
+        self.demux
+            .as_ref()
+            .ok_or_else(|| Error::InvalidConfig(format!("Socket {} has no demux", self.id)))
+    }
+
+    /// Choose what the receive queue gives up when full, see [`ShedPolicy`]
+    ///
+    /// Head-drop frees evicted mbufs to the receive pool and tail-drops
+    /// until one is bound.
+    pub fn set_shed_policy(&mut self, policy: ShedPolicy) {
+        self.recv_queue
+            .set_overwrite(policy == ShedPolicy::HeadDrop);
+        self.shed_policy = policy;
+    }
+
+    /// Get the shed policy of the receive queue
+    pub fn shed_policy(&self) -> ShedPolicy {
+        self.shed_policy
+    }
+
+    /// Get counters of datagrams shed by the receive queue
+    pub fn shed_stats(&self) -> &ShedStats {
+        &self.shed_stats
+    }
+
+    /// Queue a received packet; on failure the caller keeps the mbuf
+    fn enqueue(&self, packet: &UdpPacket) -> bool {
+        if let Some(demux) = &self.demux {
+            if let Some(queued) = demux.push(packet.mbuf, packet.payload()) {
+                if queued {
+                    self.touch();
+                }
+                return queued;
+            }
+        }
+        if let (Some(pool), SocketAddr::V4(src_addr)) = (&self.rx_pool, packet.src_addr()) {
+            if self
+                .delivery
+                .copies(self.outstanding.load(Ordering::Relaxed))
+            {
+                return self.enqueue_copy(pool, packet, src_addr);
+            }
+        }
+        let dscp = packet.ipv4_header().tos >> 2;
+        let shed = match &self.priority {
+            Some(bands) => (!bands.push(packet.mbuf, dscp)).then_some(Shed::Tail),
+            None => self.push_shedding(packet.mbuf, dscp),
+        };
+        match shed {
+            None => self.touch(),
+            Some(shed) => self.shed_stats.record(shed, dscp),
+        }
+        shed.is_none()
+    }
+
+    /// Push on the receive queue under the shed policy, returning how the mbuf was shed if it was
+    ///
+    /// A shed mbuf stays with the caller.
+    fn push_shedding(&self, mbuf: *mut Mbuf, dscp: u8) -> Option<Shed> {
+        let queue = &self.recv_queue;
+        match (self.shed_policy, &self.rx_pool) {
+            (ShedPolicy::Dscp, _) if queue.len() >= dscp_share(queue.capacity(), dscp) => {
+                Some(if queue.is_full() {
+                    Shed::Tail
+                } else {
+                    Shed::Dscp
+                })
+            }
+            (ShedPolicy::HeadDrop, Some(pool)) => match queue.push_evict(mbuf) {
+                Ok(Some(oldest)) => {
+                    let dscp = UdpPacket::from_mbuf(oldest)
+                        .map_or(0, |packet| packet.ipv4_header().tos >> 2);
+                    self.shed_stats.record(Shed::Head, dscp);
+                    self.stats.packets_dropped.inc();
+                    let _ = pool.free(oldest);
+                    None
+                }
+                Ok(None) => None,
+                Err(_) => Some(Shed::Tail),
+            },
+            // Without a pool to free them to, evicted mbufs would leak
+            _ => (queue.is_full() || queue.push(mbuf).is_err()).then_some(Shed::Tail),
+        }
+    }
+
+    /// Queue a datagram the reorder buffer released, freeing it if that fails
+    fn enqueue_released(&self, mbuf: *mut Mbuf) {
+        if UdpPacket::from_mbuf(mbuf).is_ok_and(|packet| self.enqueue(&packet)) {
+            return;
+        }
+        self.stats.packets_dropped.inc();
+        if let Ok(pool) = self.rx_pool() {
+            let _ = pool.free(mbuf);
+        }
+    }
+
+    /// Copy a received payload into the copy ring and recycle its mbuf
+    fn enqueue_copy(&self, pool: &MbufPool, packet: &UdpPacket, src_addr: SocketAddrV4) -> bool {
+        // Mbufs of other pools are left to the caller to free
+        if !pool.contains(packet.mbuf) {
+            return false;
+        }
+        if !self.copy_ring.push(src_addr, packet.payload()) {
+            self.delivery
+                .stats
+                .ring_full
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if pool.free(packet.mbuf).is_err() {
+            return false;
+        }
+        self.delivery.stats.copied.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+        true
+    }
+
+    /// Record RX/TX activity now
+    fn touch(&self) {
+        self.last_activity.store(monotonic_now(), Ordering::Relaxed);
+    }
+
+    /// Monotonic time of the last packet received or sent, see [`monotonic_now`]
+    pub fn last_activity(&self) -> Timestamp {
+        self.last_activity.load(Ordering::Relaxed)
+    }
+
+    /// Get the idle timeout, set through [`UdpStack::set_idle_timeout`]
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Get the keep-alive configuration, if any
+    pub fn keepalive(&self) -> Option<&KeepAliveConfig> {
+        self.keepalive.as_ref().map(|keepalive| &keepalive.config)
+    }
+
+    /// Get keep-alive counters, if keep-alives are configured
+    pub fn keepalive_stats(&self) -> Option<&KeepAliveStats> {
+        self.keepalive.as_ref().map(|keepalive| &keepalive.stats)
+    }
+
+    /// Pause or resume keep-alives without dropping their configuration
+    pub fn set_keepalive_enabled(&self, enabled: bool) -> Result<()> {
+        let keepalive = self
+            .keepalive
+            .as_ref()
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} has no keep-alive", self.id)))?;
+        keepalive.enabled.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Check if keep-alives are configured and enabled
+    pub fn keepalive_enabled(&self) -> bool {
+        self.keepalive.as_ref().is_some_and(KeepAlive::is_enabled)
+    }
+
+    /// Send an ARP request for `next_hop` on the socket's transmit queue
+    fn solicit(&self, next_hop: Ipv4Addr) -> Result<()> {
+        let pool = self
+            .tx_pool
+            .as_ref()
+            .ok_or_else(|| Error::NetworkError("No transmit pool bound".to_string()))?;
+        let tx_queue = self.class_queues[TrafficClass::Control.index()]
+            .as_ref()
+            .or(self.tx_queue.as_ref())
+            .ok_or_else(|| Error::NetworkError("No transmit queue bound".to_string()))?;
+        let sender = match self.local_addr {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let frame = neighbor::arp_request(self.src_mac, sender, next_hop);
+        let mbuf = pool.alloc_with_class(AllocClass::Arp)?;
+        let mbuf_ref = unsafe { &mut *mbuf };
+        mbuf_ref.reset();
+        let result = mbuf_ref.append(&frame).and_then(|()| tx_queue.send(mbuf));
+        pool.free(mbuf)?;
+        result
+    }
+
+    /// Send the keep-alive datagram, without counting it as activity
+    fn send_keepalive(&self, keepalive: &KeepAlive) -> Result<()> {
+        let payload = &keepalive.config.payload;
+        let mut buffer = self.alloc_tx_buffer_for(payload.len(), AllocClass::Keepalive)?;
+        buffer.payload_mut()[..payload.len()].copy_from_slice(payload);
+        self.transmit(buffer, keepalive.config.destination, None)
+    }
+
+    /// Receive a packet without copying
+    ///
+    /// The packet borrows its mbuf until it is freed or passed to
+    /// [`UdpSocket::release`]. Fails with [`Error::QueueError`] while the
+    /// zero-copy limit is reached. Datagrams delivered by copy, see
+    /// [`UdpSocket::set_delivery_mode`], are only received with
+    /// [`UdpSocket::recv_copied`].
+    pub fn recv(&self) -> Result<UdpPacket> {
+        self.check_zero_copy_limit()?;
+        let mut packet = self.pop()?;
+        packet.borrow = Some(ZeroCopyBorrow::take(&self.outstanding));
+        Ok(packet)
+    }
+
+    /// Receive the next datagram of the sub-channel for `tag` without copying
+    ///
+    /// Like [`UdpSocket::recv`], the packet borrows its mbuf until released.
+    pub fn recv_channel(&self, tag: &[u8]) -> Result<UdpPacket> {
+        self.check_zero_copy_limit()?;
+        let mbuf = self
+            .demux()?
+            .pop(tag)?
+            .ok_or_else(|| Error::NetworkError("No packet available".to_string()))?;
+        let mut packet = self.received(mbuf)?;
+        packet.borrow = Some(ZeroCopyBorrow::take(&self.outstanding));
+        Ok(packet)
+    }
+
+    /// Fail with [`Error::QueueError`] while the zero-copy limit is reached
+    fn check_zero_copy_limit(&self) -> Result<()> {
+        if let Some(limit) = self.zero_copy_limit {
+            let outstanding = self.outstanding.load(Ordering::Relaxed);
+            if outstanding >= limit {
+                return Err(Error::QueueError(format!(
+                    "Socket {} holds {} of {} zero-copy packets",
+                    self.id, outstanding, limit
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand a packet from [`UdpSocket::recv`] back, recycling its mbuf
+    pub fn release(&self, packet: UdpPacket) -> Result<()> {
+        // Dropping the packet gives its borrow back
+        self.rx_pool()?.free(packet.mbuf)
+    }
+
+    /// Receive a packet as an owned copy of its payload
+    ///
+    /// The mbuf is recycled before returning. Passing the vector to
+    /// [`UdpSocket::recycle_buffer`] once done lets later calls reuse it.
+    /// Queued packets are received before the datagrams delivered by copy.
+    pub fn recv_copied(&self) -> Result<(SocketAddr, Vec<u8>)> {
+        let pool = self.rx_pool()?;
+        let packet = match self.pop() {
+            Ok(packet) => packet,
+            Err(Error::NetworkError(_)) if !self.copy_ring.is_empty() => {
+                return self.pop_copied();
+            }
+            Err(e) => return Err(e),
+        };
+        let src_addr = packet.src_addr();
+        let payload = self.copy_buffers.copy(packet.payload());
+        pool.free(packet.mbuf)?;
+        Ok((src_addr, payload))
+    }
+
+    /// Take the oldest datagram of the copy ring
+    fn pop_copied(&self) -> Result<(SocketAddr, Vec<u8>)> {
+        let mut payload = self.copy_buffers.take(0);
+        let src_addr = self
+            .copy_ring
+            .pop(&mut payload)
+            .ok_or_else(|| Error::NetworkError("No packet available".to_string()))?;
+        self.stats.packets_received.inc();
+        self.stats.bytes_received.add(payload.len() as u64);
+        Ok((src_addr, payload))
+    }
+
+    /// Return a payload vector from [`UdpSocket::recv_copied`] for reuse
+    pub fn recycle_buffer(&self, buffer: Vec<u8>) {
+        self.copy_buffers.recycle(buffer);
+    }
+
+    /// Get copy-out buffer cache counters
+    pub fn copy_buffer_stats(&self) -> &CopyBufferStats {
+        self.copy_buffers.stats()
+    }
+
+    fn rx_pool(&self) -> Result<&Arc<MbufPool>> {
+        self.rx_pool
+            .as_ref()
+            .ok_or_else(|| Error::NetworkError("No receive pool bound".to_string()))
+    }
+
+    /// Take the next queued packet
+    fn pop(&self) -> Result<UdpPacket> {
+        let next = self
+            .priority
+            .as_ref()
+            .and_then(BandedQueue::pop)
+            .or_else(|| self.recv_queue.pop().ok());
+
+        match next {
+            Some(mbuf) => self.received(mbuf),
+            None => Err(Error::NetworkError("No packet available".to_string())),
+        }
+    }
+
+    /// Count a dequeued mbuf as received
+    fn received(&self, mbuf: *mut Mbuf) -> Result<UdpPacket> {
+        let packet = UdpPacket::from_mbuf(mbuf)?;
+        PacketTracer::global().record(packet.trace_id(), TraceStage::SocketPop);
+        self.stats.packets_received.inc();
+        self.stats.bytes_received.add(packet.payload().len() as u64);
+        Ok(packet)
+    }
+
+    /// Receive multiple packets in batch
+    pub fn recv_batch(&self, packets: &mut [UdpPacket], max_count: usize) -> Result<usize> {
+        let mut received = 0;
+
+        for i in 0..max_count.min(packets.len()) {
+            match self.recv() {
+                Ok(packet) => {
+                    packets[i] = packet;
+                    received += 1;
+                }
+                Err(Error::NetworkError(_)) => break,
+                // Zero-copy limit reached part way through the batch
+                Err(Error::QueueError(_)) if received > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(received)
+    }
+
+    /// Send a packet
+    pub fn send(&self, dst_addr: SocketAddr, data: &[u8]) -> Result<()> {
+        let mut buffer = self.alloc_tx_buffer(data.len())?;
+        buffer.payload_mut().copy_from_slice(data);
+        self.send_prepared(buffer, dst_addr)
+    }
+
+    /// Allocate a transmit buffer with `len` bytes of payload space
+    ///
+    /// The application writes the payload directly into the returned buffer
+    /// and hands it to [`UdpSocket::send_prepared`], avoiding the copy done
+    /// by [`UdpSocket::send`].
+    pub fn alloc_tx_buffer(&self, len: usize) -> Result<TxBuffer> {
+        self.alloc_tx_buffer_for(len, AllocClass::Data)
+    }
+
+    fn alloc_tx_buffer_for(&self, len: usize, class: AllocClass) -> Result<TxBuffer> {
+        let pool = self
+            .tx_pool
+            .as_ref()
+            .ok_or_else(|| Error::NetworkError("No transmit pool bound".to_string()))?;
+
+        // Leave tailroom for what compression and the payload transform add
+        let overhead = self.compression.as_ref().map_or(0, |_| MAX_OVERHEAD)
+            + self.transform.as_ref().map_or(0, |t| t.overhead());
+        if TX_HEADROOM + len + overhead > pool.buf_size()
+            || std::mem::size_of::<UdpHeader>() + len + overhead > u16::MAX as usize
+        {
+            return Err(Error::NetworkError(
+                "Payload too large for mbuf".to_string(),
+            ));
+        }
+
+        let mbuf = pool.alloc_with_class(class)?;
+        unsafe {
+            (*mbuf).len = TX_HEADROOM + len;
+        }
+
+        Ok(TxBuffer {
+            mbuf,
+            pool: pool.clone(),
+            socket_id: self.id,
+            payload_len: len,
+            completion: None,
+            dscp: None,
+        })
+    }
+
+    /// Fill in the headers of a prepared buffer and transmit it
+    pub fn send_prepared(&self, buffer: TxBuffer, dst_addr: SocketAddr) -> Result<()> {
+        self.transmit(buffer, dst_addr, None)?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Send a packet at `at` on the [`monotonic_now`] clock, see [`TxQueue::send_at`]
+    ///
+    /// The datagram is built at once and held by the transmit queue until
+    /// its departure; a send waiting for its next hop to resolve leaves
+    /// when it resolves instead.
+    pub fn send_at(&self, dst_addr: SocketAddr, data: &[u8], at: Timestamp) -> Result<()> {
+        let mut buffer = self.alloc_tx_buffer(data.len())?;
+        buffer.payload_mut().copy_from_slice(data);
+        self.send_prepared_at(buffer, dst_addr, at)
+    }
+
+    /// Send a prepared buffer at `at`, see [`UdpSocket::send_at`]
+    pub fn send_prepared_at(
+        &self,
+        buffer: TxBuffer,
+        dst_addr: SocketAddr,
+        at: Timestamp,
+    ) -> Result<()> {
+        self.transmit(buffer, dst_addr, Some(at))?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Queue completion events of up to `capacity` sends, or stop with `None`
+    ///
+    /// Events not yet taken are discarded.
+    pub fn set_completion_queue(&mut self, capacity: Option<usize>) {
+        self.completions = capacity.map(|capacity| Arc::new(CompletionQueue::new(capacity)));
+    }
+
+    /// Send a packet and report its outcome with `cookie` on the completion queue
+    ///
+    /// Fails with [`Error::QueueError`] while the completion queue is full.
+    pub fn send_with_cookie(&self, dst_addr: SocketAddr, data: &[u8], cookie: u64) -> Result<()> {
+        let mut buffer = self.alloc_tx_buffer(data.len())?;
+        buffer.payload_mut().copy_from_slice(data);
+        self.send_prepared_with_cookie(buffer, dst_addr, cookie)
+    }
+
+    /// Send a prepared buffer and report its outcome with `cookie`
+    pub fn send_prepared_with_cookie(
+        &self,
+        mut buffer: TxBuffer,
+        dst_addr: SocketAddr,
+        cookie: u64,
+    ) -> Result<()> {
+        buffer.completion = Some(self.reserve_completion(cookie)?);
+        self.send_prepared(buffer, dst_addr)
+    }
+
+    fn reserve_completion(&self, cookie: u64) -> Result<CompletionToken> {
+        self.completions
+            .as_ref()
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!("Socket {} has no completion queue", self.id))
+            })?
+            .reserve(cookie)
+    }
+
+    /// Take the oldest send completion
+    pub fn poll_completion(&self) -> Option<SendCompletion> {
+        self.completions.as_ref()?.pop()
+    }
+
+    /// Completion events waiting plus cookie sends in flight
+    pub fn pending_completions(&self) -> usize {
+        self.completions.as_ref().map_or(0, |queue| queue.len())
+    }
+
+    /// Get completion queue counters
+    pub fn completion_stats(&self) -> Option<&CompletionStats> {
+        self.completions.as_ref().map(|queue| queue.stats())
+    }
+
+    /// Transmit `buffer` now or at `departure`, completing its cookie send unless it waits for a next hop or departs later
+    fn transmit(
+        &self,
+        mut buffer: TxBuffer,
+        dst_addr: SocketAddr,
+        departure: Option<Timestamp>,
+    ) -> Result<()> {
+        let mut completion = buffer.completion.take();
+        let result = self.transmit_frame(buffer, dst_addr, departure, &mut completion);
+        if let Some(completion) = completion {
+            completion.complete(match &result {
+                Ok(()) => CompletionStatus::Sent,
+                Err(e) => CompletionStatus::Failed(e.to_string()),
+            });
+        }
+        result
+    }
+
+    fn transmit_frame(
+        &self,
+        mut buffer: TxBuffer,
+        dst_addr: SocketAddr,
+        departure: Option<Timestamp>,
+        completion: &mut Option<CompletionToken>,
+    ) -> Result<()> {
+        let class = self
+            .traffic_classes
+            .class_for(buffer.dscp.unwrap_or(self.dscp));
+        let tx_queue = self.class_queues[class.index()]
+            .as_ref()
+            .or(self.tx_queue.as_ref())
+            .ok_or_else(|| Error::NetworkError("No transmit queue bound".to_string()))?;
+        if let Some(device) = self.bound_device {
+            if tx_queue.port_id() != device {
+                return Err(Error::NetworkError(format!(
+                    "Socket {} is bound to port {} but its transmit queue sends on port {}",
+                    self.id,
+                    device,
+                    tx_queue.port_id()
+                )));
+            }
+        }
+
+        // Destinations are on-link, so each is its own next hop
+        let next_hop_mac = match (&self.neighbors, dst_addr) {
+            (Some(neighbors), SocketAddr::V4(dst)) => {
+                // A held send completes once flushed or expired
+                buffer.completion = completion.take();
+                match neighbors.output(*dst.ip(), buffer, dst_addr)? {
+                    NeighborOutput::Resolved(mac, mut resolved) => {
+                        *completion = resolved.completion.take();
+                        buffer = resolved;
+                        Some(mac)
+                    }
+                    NeighborOutput::Queued => return Ok(()),
+                }
+            }
+            _ => None,
+        };
+
+        self.compress(&mut buffer, dst_addr)?;
+        self.encrypt(&mut buffer)?;
+        let plan = pmtu::plan(
+            std::mem::size_of::<UdpHeader>() + buffer.payload_len(),
+            self.path_mtu(dst_addr),
+            self.dont_fragment,
+        )?;
+        self.write_headers(&mut buffer, dst_addr)?;
+        if let Some(mac) = next_hop_mac {
+            buffer.frame_mut()[..6].copy_from_slice(&mac);
+        }
+        let trace_id = unsafe { (*buffer.mbuf()).trace_id };
+        if let SocketAddr::V4(dst) = dst_addr {
+            PacketColorer::global().color_tx(
+                unsafe { &mut *buffer.mbuf() },
+                self.id,
+                self.local_addr.port(),
+                *dst.ip(),
+            );
+        }
+
+        PacketLog::global().log("tx", unsafe { (*buffer.mbuf()).data() });
+
+        // A scheduled datagram is accounted and completes as its frames leave
+        let departure =
+            departure.map(|at| (at, Departure::new(self.mib.clone(), completion.take())));
+        // libpcap copies the frame, so the buffer can be recycled right away
+        match plan {
+            SendPlan::Single => {
+                self.send_or_schedule(tx_queue, &buffer.pool, buffer.mbuf(), &departure, true)?
+            }
+            SendPlan::Fragments(fragment_len) => {
+                self.send_fragments(tx_queue, &buffer, fragment_len, &departure)?
+            }
+        }
+        PacketTracer::global().record(trace_id, TraceStage::Tx);
+
+        self.stats.packets_sent.inc();
+        self.stats.bytes_sent.add(buffer.payload_len() as u64);
+        self.egress.record(class, buffer.payload_len());
+
+        Ok(())
+    }
+
+    /// Send multiple packets in batch
+    pub fn send_batch(&self, packets: &[(SocketAddr, &[u8])]) -> Result<usize> {
+        let mut sent = 0;
+
+        for (dst_addr, data) in packets.iter().take(packets.len()) {
+            match self.send(*dst_addr, data) {
+                Ok(_) => sent += 1,
+                Err(_) => break,
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Send multiple packets spaced out as `spread` says, see [`BurstSpread`]
+    ///
+    /// Each packet is scheduled on the transmit queue with its own departure
+    /// time, the first at once. Returns the number of packets sent or
+    /// scheduled, stopping at the first failure.
+    pub fn send_batch_spread(
+        &self,
+        packets: &[(SocketAddr, &[u8])],
+        spread: BurstSpread,
+    ) -> Result<usize> {
+        let plan = self.spreader.plan(spread, packets.len(), monotonic_now());
+        let mut sent = 0;
+
+        for ((dst_addr, data), at) in packets.iter().zip(plan.departures()) {
+            match self.send_at(*dst_addr, data, at) {
+                Ok(_) => sent += 1,
+                Err(_) => break,
+            }
+        }
+        self.spreader.complete(&plan, sent);
+
+        Ok(sent)
+    }
+
+    /// Get counters of the batches sent with [`UdpSocket::send_batch_spread`]
+    pub fn spread_stats(&self) -> &SpreadStats {
+        self.spreader.stats()
+    }
+
+    /// Send `mbuf` now, or at `departure` holding a reference of its own
+    ///
+    /// The frame is counted in the MIB once sent; `last` marks the final
+    /// frame of a scheduled datagram, whose departure completes it.
+    fn send_or_schedule(
+        &self,
+        tx_queue: &TxQueue,
+        pool: &Arc<MbufPool>,
+        mbuf: *mut Mbuf,
+        departure: &Option<(Timestamp, Arc<Departure>)>,
+        last: bool,
+    ) -> Result<()> {
+        let frame_len = unsafe { (*mbuf).len };
+        let Some((at, departure)) = departure else {
+            tx_queue.send(mbuf)?;
+            self.mib.record_tx(frame_len);
+            return Ok(());
+        };
+        let on_departure = departure.frame(frame_len, last);
+        match MbufHandle::share(pool, mbuf) {
+            Ok(handle) => tx_queue.send_at_then(handle, *at, on_departure),
+            Err(e) => {
+                let result = Err(e);
+                on_departure(&result);
+                result
+            }
+        }
+    }
+
+    /// Send the datagram in `buffer` as IPv4 fragments of `fragment_len` bytes
+    fn send_fragments(
+        &self,
+        tx_queue: &TxQueue,
+        buffer: &TxBuffer,
+        fragment_len: usize,
+        departure: &Option<(Timestamp, Arc<Departure>)>,
+    ) -> Result<()> {
+        let ip_offset = std::mem::size_of::<EthernetHeader>();
+        let data_offset = ip_offset + std::mem::size_of::<Ipv4Header>();
+        let frame = buffer.frame();
+        // The identification written with the headers is shared by every fragment
+        let mut ip_header = Ipv4Header::from_bytes(&frame[ip_offset..])?;
+
+        let datagram = &frame[data_offset..];
+        // Take the mbufs from the pool a few fragments at a time, so a large
+        // datagram never needs more than FRAGMENT_CHUNK free buffers at once
+        let mut mbufs = [std::ptr::null_mut(); FRAGMENT_CHUNK];
+        let chunk_len = FRAGMENT_CHUNK * fragment_len;
+        for (first, fragments) in datagram.chunks(chunk_len).enumerate() {
+            let mbufs = &mut mbufs[..fragments.len().div_ceil(fragment_len)];
+            buffer.pool.alloc_bulk(mbufs)?;
+            let mut sent = Ok(());
+            for ((index, chunk), &mbuf) in fragments.chunks(fragment_len).enumerate().zip(&*mbufs) {
+                let offset = first * chunk_len + index * fragment_len;
+                let more = if offset + chunk.len() < datagram.len() {
+                    IPV4_MF
+                } else {
+                    0
+                };
+                ip_header.total_length = ((data_offset - ip_offset + chunk.len()) as u16).to_be();
+                ip_header.flags_fragment = (more | (offset / 8) as u16).to_be();
+                ip_header.checksum = ip_header.compute_checksum().to_be();
+
+                let fragment = unsafe { &mut *mbuf };
+                sent = fragment
+                    .append(&frame[..ip_offset])
+                    .and_then(|_| fragment.append(&ip_header.to_bytes()))
+                    .and_then(|_| fragment.append(chunk))
+                    .and_then(|_| {
+                        let last = more == 0;
+                        self.send_or_schedule(tx_queue, &buffer.pool, mbuf, departure, last)
+                    });
+                if sent.is_err() {
+                    break;
+                }
+            }
+            buffer.pool.free_bulk(mbufs)?;
+            sent?;
+        }
+        Ok(())
+    }
+
+    /// Write Ethernet, IPv4 and UDP headers in front of the payload
+    ///
+    /// The headers come from the cached template towards `dst_addr`.
+    /// Datagrams that may be fragmented take a fresh identification; DF
+    /// datagrams are atomic and carry 0 (RFC 6864).
+    fn write_headers(&self, buffer: &mut TxBuffer, dst_addr: SocketAddr) -> Result<()> {
+        let (src, dst) = match (self.local_addr, dst_addr) {
+            (SocketAddr::V4(src), SocketAddr::V4(dst)) => (src, dst),
+            _ => {
+                return Err(Error::NetworkError(
+                    "Only IPv4 destinations are supported".to_string(),
+                ))
+            }
+        };
+
+        let template = self.templates.get_or_build(dst, || {
+            let src = SocketAddrV4::new(*src.ip(), self.source_port(dst_addr)?);
+            Ok(
+                HeaderTemplate::new(self.src_mac, self.dst_mac, src, dst, self.dont_fragment)
+                    .with_ttl(self.ttl)
+                    .with_dscp(self.dscp),
+            )
+        })?;
+        let template = match buffer.dscp {
+            Some(dscp) if dscp != self.dscp => template.with_dscp(dscp),
+            _ => template,
+        };
+        let identification = if self.dont_fragment {
+            0
+        } else {
+            self.next_ip_id.fetch_add(1, Ordering::Relaxed)
+        };
+        let payload_len = buffer.payload_len();
+        template.write(buffer.frame_mut(), payload_len, identification);
+
+        Ok(())
+    }
+
+    /// Start the socket
+    pub fn start(&self) -> Result<()> {
+        self.running.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stop the socket
+    pub fn stop(&self) -> Result<()> {
+        self.running.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Get socket statistics
+    pub fn stats(&self) -> &UdpSocketStats {
+        &self.stats
+    }
+
+    /// Get local address
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get socket ID
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Get the socket name, `socket<id>` unless set
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name the socket in logs and telemetry, see [`UdpStack::set_socket_name`]
+    fn set_name(&mut self, name: impl Into<String>) {
+        self.name = name.into();
+    }
+}
+
+// Queued mbufs are owned by the socket until received, so the raw pointers
+// can move between the dispatching and receiving threads.
+unsafe impl Send for UdpSocket {}
+unsafe impl Sync for UdpSocket {}
+
+/// Why a received frame was not delivered to a socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// Not a parseable IPv4/UDP frame
+    Malformed,
+    /// No stack registered for the destination port
+    Unmatched,
+    /// Owning stack is not running
+    StackStopped,
+    /// No socket bound to the destination port
+    NoSocket,
+    /// Rejected by the socket's replay guard
+    Replay,
+    /// Socket receive queue full
+    QueueFull,
+    /// IPv4 or UDP checksum marked bad
+    BadChecksum,
+    /// Rejected by the socket's payload transform
+    Decrypt,
+    /// Forwarded frame whose TTL ran out
+    TtlExceeded,
+    /// Forwarded frame whose route has no usable port
+    Unroutable,
+    /// Behind its flow in the socket's reorder buffer, or already held there
+    Late,
+    /// Compressed payload that did not decompress
+    Decompress,
+    /// Rejected by the socket's receive filter
+    Filtered,
+    /// Dropped early for a socket holding too many buffers of a low pool
+    PoolPressure,
+    /// Rejected by a protocol handler
+    Handler,
+    /// Matched a drop pattern of the stack's pattern set
+    Pattern,
+}
+
+impl DropReason {
+    /// Number of drop reasons
+    pub const COUNT: usize = 16;
+
+    /// All drop reasons, in index order
+    pub const ALL: [DropReason; Self::COUNT] = [
+        DropReason::Malformed,
+        DropReason::Unmatched,
+        DropReason::StackStopped,
+        DropReason::NoSocket,
+        DropReason::Replay,
+        DropReason::QueueFull,
+        DropReason::BadChecksum,
+        DropReason::Decrypt,
+        DropReason::TtlExceeded,
+        DropReason::Unroutable,
+        DropReason::Late,
+        DropReason::Decompress,
+        DropReason::Filtered,
+        DropReason::PoolPressure,
+        DropReason::Handler,
+        DropReason::Pattern,
+    ];
+
+    /// Stable index for per-reason counters
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Short name for logs and metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DropReason::Malformed => "malformed",
+            DropReason::Unmatched => "unmatched",
+            DropReason::StackStopped => "stack_stopped",
+            DropReason::NoSocket => "no_socket",
+            DropReason::Replay => "replay",
+            DropReason::QueueFull => "queue_full",
+            DropReason::BadChecksum => "bad_checksum",
+            DropReason::Decrypt => "decrypt",
+            DropReason::TtlExceeded => "ttl_exceeded",
+            DropReason::Unroutable => "unroutable",
+            DropReason::Late => "late",
+            DropReason::Decompress => "decompress",
+            DropReason::Filtered => "filtered",
+            DropReason::PoolPressure => "pool_pressure",
+            DropReason::Handler => "handler",
+            DropReason::Pattern => "pattern",
+        }
+    }
+}
+
+/// IPv4 destination of a send
+fn ipv4_destination(dst_addr: SocketAddr) -> Result<SocketAddrV4> {
+    match dst_addr {
+        SocketAddr::V4(dst) => Ok(dst),
+        SocketAddr::V6(_) => Err(Error::NetworkError(
+            "Only IPv4 destinations are supported".to_string(),
+        )),
+    }
+}
+
+/// Fail if a transform reported more bytes than its buffer holds
+fn check_transformed(len: usize, room: usize) -> Result<usize> {
+    if len > room {
+        return Err(Error::NetworkError(format!(
+            "Transform produced {} bytes in a {}-byte buffer",
+            len, room
+        )));
+    }
+    Ok(len)
+}
+
+/// Outcome of handing a received mbuf to a stack
+///
+/// `Delivered` moves ownership of the mbuf to a socket queue; the caller
+/// must not touch it again. `Dropped` leaves ownership with the caller,
+/// which must free the mbuf exactly once.
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Delivered,
+    Dropped(DropReason),
+}
+
+impl Delivery {
+    /// Whether ownership moved to a socket
+    pub fn is_delivered(self) -> bool {
+        self == Delivery::Delivered
+    }
+}
+
+/// UDP stack implementation
+pub struct UdpStack {
+    /// Stack configuration
+    #[allow(dead_code)]
+    config: Config,
+    /// UDP sockets
+    sockets: HashMap<u16, UdpSocket>,
+    /// Socket IDs by local address, for delivery
+    table: SocketTable,
+    /// Next socket ID
+    next_socket_id: AtomicUsize,
+    /// Memory pool bound to new sockets for outgoing packets
+    tx_pool: Option<Arc<MbufPool>>,
+    /// Priority bands applied to new sockets
+    default_bands: Option<PriorityBands>,
+    /// Receive queue placement of new sockets
+    queue_placement: QueuePlacement,
+    /// Memory pool bound to sockets for recycling received mbufs
+    rx_pool: Option<Arc<MbufPool>>,
+    /// Zero-copy limit applied to every socket
+    zero_copy_limit: Option<usize>,
+    /// Running flag
+    running: AtomicBool,
+    /// Per-layer protocol counters
+    mib: Arc<ProtocolMib>,
+    /// Sent datagrams by traffic class
+    egress: Arc<EgressStats>,
+    /// Socket inactivity timers
+    idle: SocketTimers,
+    /// What happens to sockets that reach their idle timeout
+    idle_action: IdleAction,
+    /// Socket keep-alive timers
+    keepalives: SocketTimers,
+    /// Weights and credits of `recv_fair`
+    fair: FairScheduler,
+    /// Source ports of the sockets' flows
+    ephemeral: Arc<EphemeralPorts>,
+    /// Next hop addresses bound to every socket
+    neighbors: Option<Arc<NeighborTable>>,
+    /// Payload compression bound to every socket
+    compression: Option<Arc<PayloadCompressor>>,
+    /// Budget socket receive queues are charged to
+    budget: Arc<MemoryBudget>,
+    /// Path MTUs of the destinations sent to
+    pmtu: Arc<PmtuCache>,
+    /// Receive-all sockets shown every dispatched frame
+    sniffers: Vec<Arc<Sniffer>>,
+    /// Payload patterns every dispatched frame is scanned for
+    patterns: Option<Arc<PatternSet>>,
+    /// Queue frames matching a mirror pattern are copied to
+    mirror_queue: Option<Arc<TxQueue>>,
+    /// Anomaly capture shown delivered frames and told of drops
+    capture: Option<Arc<Mutex<CaptureTrigger>>>,
+    /// Drop for sockets holding the most buffers while the receive pool runs low
+    early_drop: Option<EarlyDropConfig>,
+    early_drop_stats: EarlyDropStats,
+    /// User protocols served before the sockets
+    handlers: ProtocolHandlers,
+    /// Next sniffer ID
+    next_sniffer_id: u16,
+    /// Names and counters of the open sockets
+    directory: SocketDirectory,
+    /// Stack statistics
+    stats: UdpStackStats,
+}
+
+/// UDP stack statistics
+#[derive(Debug, Default)]
+pub struct UdpStackStats {
+    pub total_sockets: AtomicUsize,
+    pub active_sockets: AtomicUsize,
+    pub total_packets_received: Counter,
+    pub total_packets_sent: Counter,
+    pub total_bytes_received: Counter,
+    pub total_bytes_sent: Counter,
+    pub total_errors: Counter,
+}
+
+impl UdpStack {
+    /// Create a new UDP stack
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut stack = Self {
+            config: config.clone(),
+            sockets: HashMap::new(),
+            table: SocketTable::default(),
+            next_socket_id: AtomicUsize::new(1),
+            tx_pool: None,
+            default_bands: None,
+            queue_placement: QueuePlacement::Heap,
+            rx_pool: None,
+            zero_copy_limit: None,
+            running: AtomicBool::new(false),
+            mib: Arc::new(ProtocolMib::default()),
+            egress: Arc::new(EgressStats::default()),
+            idle: SocketTimers::new(monotonic_now()),
+            idle_action: IdleAction::default(),
+            keepalives: SocketTimers::new(monotonic_now()),
+            fair: FairScheduler::default(),
+            ephemeral: Arc::new(EphemeralPorts::default()),
+            neighbors: None,
+            compression: None,
+            budget: Arc::new(MemoryBudget::new(config.memory_budget)),
+            pmtu: Arc::new(PmtuCache::new(config.mtu)),
+            sniffers: Vec::new(),
+            patterns: None,
+            mirror_queue: None,
+            capture: None,
+            next_sniffer_id: 1,
+            early_drop: config.early_drop,
+            early_drop_stats: EarlyDropStats::default(),
+            handlers: ProtocolHandlers::default(),
+            directory: SocketDirectory::default(),
+            stats: UdpStackStats::default(),
+        };
+        let budget = stack.budget.clone();
+        stack.set_memory_budget(budget)?;
+        Ok(stack)
+    }
+
+    /// Create a new UDP socket
+    pub fn create_socket(&mut self, local_addr: SocketAddr) -> Result<u16> {
+        self.create_bound_socket(local_addr, None)
+    }
+
+    /// Create a UDP socket bound to one interface, see [`UdpStack::bind_to_device`]
+    pub fn create_socket_on_device(&mut self, local_addr: SocketAddr, device: u16) -> Result<u16> {
+        self.create_bound_socket(local_addr, Some(device))
+    }
+
+    fn create_bound_socket(&mut self, local_addr: SocketAddr, device: Option<u16>) -> Result<u16> {
+        self.check_binding(local_addr, device, None)?;
+        let socket_id = self.next_socket_id.fetch_add(1, Ordering::Relaxed) as u16;
+        let queue_size = 1024; // Default queue size
+
+        let charge = self.budget.charge(
+            Subsystem::Queues,
+            queue_size * std::mem::size_of::<*mut Mbuf>(),
+        )?;
+        let mut socket =
+            UdpSocket::with_placement(local_addr, queue_size, socket_id, self.queue_placement)?;
+        socket.queue_charge = Some(charge);
+        socket.budget = Some(self.budget.clone());
+        if let Some(pool) = &self.tx_pool {
+            socket.bind_tx_pool(pool.clone());
+        }
+        if let Some(bands) = &self.default_bands {
+            socket.set_priority_bands(bands.clone());
+        }
+        if let Some(pool) = &self.rx_pool {
+            socket.bind_rx_pool(pool.clone());
+        }
+        socket.set_zero_copy_limit(self.zero_copy_limit);
+        socket.bind_mib(self.mib.clone());
+        socket.bind_egress(self.egress.clone());
+        socket.set_traffic_classes(self.config.traffic_classes);
+        socket.bind_pmtu(self.pmtu.clone());
+        socket.bind_ephemeral(self.ephemeral.clone());
+        socket.bind_neighbors(self.neighbors.clone());
+        socket.bind_compression(self.compression.clone());
+        self.ephemeral.reserve_bound(local_addr.port());
+        socket.bound_device = device;
+
+        self.table.insert(local_addr, device, socket_id)?;
+        self.directory.insert(&socket);
+        self.sockets.insert(socket_id, socket);
+        self.stats.total_sockets.fetch_add(1, Ordering::Relaxed);
         self.stats.active_sockets.fetch_add(1, Ordering::Relaxed);
 
-        Ok(socket_id)
+        Ok(socket_id)
+    }
+
+    /// Restrict a socket to the interface of `device`, `None` for any interface
+    ///
+    /// A bound socket only receives frames stamped with that port ID and
+    /// only sends through a transmit queue of that interface. A bound and a
+    /// wildcard socket may share a local address: the bound socket takes
+    /// the frames of its interface, the wildcard one those of every other.
+    /// Two sockets with the same local address and binding conflict.
+    pub fn bind_to_device(&mut self, socket_id: u16, device: Option<u16>) -> Result<()> {
+        let socket = self
+            .get_socket(socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        let (local_addr, previous) = (socket.local_addr(), socket.bound_device);
+        self.check_binding(local_addr, device, Some(socket_id))?;
+        self.table.remove(local_addr, previous, socket_id);
+        self.table.insert(local_addr, device, socket_id)?;
+        if let Some(socket) = self.sockets.get_mut(&socket_id) {
+            socket.bound_device = device;
+            socket.templates.invalidate(None);
+        }
+        Ok(())
+    }
+
+    /// Drop the header templates of every socket towards `dst_addr`, or all if `None`
+    pub fn invalidate_header_templates(&self, dst_addr: Option<SocketAddr>) {
+        for socket in self.sockets.values() {
+            socket.invalidate_header_templates(dst_addr);
+        }
+    }
+
+    /// Fail if a socket other than `except` holds `port` with the same binding
+    fn check_binding(
+        &self,
+        local_addr: SocketAddr,
+        device: Option<u16>,
+        except: Option<u16>,
+    ) -> Result<()> {
+        if let Some(owner) = self.ephemeral.owner(local_addr.port()) {
+            return Err(Error::NetworkError(format!(
+                "Port {} is in use as a source port by socket {}",
+                local_addr.port(),
+                owner
+            )));
+        }
+        let conflict = self
+            .table
+            .holder(local_addr, device)
+            .filter(|&holder| Some(holder) != except);
+        match (conflict, device) {
+            (None, _) => Ok(()),
+            (Some(holder), Some(device)) => Err(Error::NetworkError(format!(
+                "{} on device {} is already bound by socket {}",
+                local_addr, device, holder
+            ))),
+            (Some(holder), None) => Err(Error::NetworkError(format!(
+                "{} is already bound by socket {}",
+                local_addr, holder
+            ))),
+        }
+    }
+
+    /// Charge the receive queues and tables of sockets created from now on to `budget`
+    ///
+    /// The stack's own source port flows and path MTU cache move to
+    /// `budget` at once; fails with [`Error::BudgetExceeded`] if it cannot
+    /// hold them.
+    pub fn set_memory_budget(&mut self, budget: Arc<MemoryBudget>) -> Result<()> {
+        self.ephemeral.set_budget(&budget)?;
+        self.pmtu.set_budget(&budget)?;
+        self.budget = budget;
+        Ok(())
+    }
+
+    /// Place the receive queues of sockets created from now on
+    pub fn set_queue_placement(&mut self, placement: QueuePlacement) {
+        self.queue_placement = placement;
+    }
+
+    /// Enable priority bands on every socket created from now on
+    pub fn set_default_priority_bands(&mut self, bands: Option<PriorityBands>) {
+        self.default_bands = bands;
+    }
+
+    /// Get a socket by ID
+    pub fn get_socket(&self, socket_id: u16) -> Option<&UdpSocket> {
+        self.sockets.get(&socket_id)
+    }
+
+    /// Get a mutable socket by ID
+    pub fn get_socket_mut(&mut self, socket_id: u16) -> Option<&mut UdpSocket> {
+        self.sockets.get_mut(&socket_id)
+    }
+
+    /// Get a socket by name
+    pub fn socket_by_name(&self, name: &str) -> Option<&UdpSocket> {
+        self.sockets.values().find(|socket| socket.name() == name)
+    }
+
+    /// Name a socket in logs and telemetry, failing if another socket has the name
+    pub fn set_socket_name(&mut self, socket_id: u16, name: &str) -> Result<()> {
+        if let Some(owner) = self.socket_by_name(name).filter(|s| s.id() != socket_id) {
+            return Err(Error::NetworkError(format!(
+                "Socket name '{}' is taken by socket {}",
+                name,
+                owner.id()
+            )));
+        }
+        let socket = self
+            .sockets
+            .get_mut(&socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        socket.set_name(name);
+        self.directory.rename(socket_id, name);
+        Ok(())
+    }
+
+    /// Names and counters of the open sockets, kept current as sockets come and go
+    pub(crate) fn socket_directory(&self) -> SocketDirectory {
+        self.directory.clone()
+    }
+
+    /// Set the memory pool all sockets return received mbufs to
+    pub fn set_rx_pool(&mut self, pool: Arc<MbufPool>) {
+        for socket in self.sockets.values_mut() {
+            socket.bind_rx_pool(pool.clone());
+        }
+        self.rx_pool = Some(pool);
+    }
+
+    /// Cap the zero-copy packets each socket may hold, see [`UdpSocket::set_zero_copy_limit`]
+    pub fn set_zero_copy_limit(&mut self, limit: Option<usize>) {
+        for socket in self.sockets.values_mut() {
+            socket.set_zero_copy_limit(limit);
+        }
+        self.zero_copy_limit = limit;
+    }
+
+    /// Set the memory pool used by all sockets for outgoing packets
+    pub fn set_tx_pool(&mut self, pool: Arc<MbufPool>) {
+        for socket in self.sockets.values_mut() {
+            socket.bind_tx_pool(pool.clone());
+        }
+        self.tx_pool = Some(pool);
+    }
+
+    /// Resolve the destination MAC of every socket's sends through `neighbors`
+    ///
+    /// `None` sends to each socket's static destination MAC again.
+    pub fn set_neighbor_table(&mut self, neighbors: Option<Arc<NeighborTable>>) {
+        for socket in self.sockets.values_mut() {
+            socket.bind_neighbors(neighbors.clone());
+        }
+        self.neighbors = neighbors;
+    }
+
+    /// Get the neighbor table bound to the sockets
+    pub fn neighbor_table(&self) -> Option<&Arc<NeighborTable>> {
+        self.neighbors.as_ref()
+    }
+
+    /// Compress the payloads of the ports `compression` matches, `None` to stop
+    ///
+    /// Both ends of a link need the same ports compressed.
+    pub fn set_compression(&mut self, compression: Option<Arc<PayloadCompressor>>) {
+        for socket in self.sockets.values_mut() {
+            socket.bind_compression(compression.clone());
+        }
+        self.compression = compression;
+    }
+
+    /// Get the compression stage bound to the sockets
+    pub fn compression(&self) -> Option<&Arc<PayloadCompressor>> {
+        self.compression.as_ref()
+    }
+
+    /// Record the MAC of `next_hop` and send the datagrams queued for it
+    ///
+    /// Returns how many queued datagrams were sent. Datagrams of sockets
+    /// closed in the meantime are dropped.
+    pub fn resolve_neighbor(&self, next_hop: Ipv4Addr, mac: [u8; 6]) -> Result<usize> {
+        let neighbors = self
+            .neighbors
+            .as_ref()
+            .ok_or_else(|| Error::NetworkError("No neighbor table set".to_string()))?;
+        self.flush_neighbor(neighbors, neighbors.resolve(next_hop, mac, false)?)
+    }
+
+    fn flush_neighbor(
+        &self,
+        neighbors: &NeighborTable,
+        waiting: Vec<(TxBuffer, SocketAddr)>,
+    ) -> Result<usize> {
+        let mut sent = 0;
+        for (buffer, dst_addr) in waiting {
+            let Some(socket) = self.sockets.get(&buffer.socket_id()) else {
+                continue;
+            };
+            if socket.send_prepared(buffer, dst_addr).is_ok() {
+                sent += 1;
+            }
+        }
+        neighbors.stats().flushed.fetch_add(sent, Ordering::Relaxed);
+        Ok(sent)
+    }
+
+    /// Expire the neighbor table and send the ARP requests due
+    ///
+    /// Call this periodically like [`UdpStack::expire_idle`]; without it,
+    /// sends held for a next hop that is never resolved keep their mbufs
+    /// and completion slots. Returns the ARP requests sent.
+    pub fn run_neighbor_timers(&self) -> usize {
+        let Some(neighbors) = &self.neighbors else {
+            return 0;
+        };
+        neighbors.expire();
+        let mut sent = 0;
+        for (next_hop, socket_id) in neighbors.due_solicitations() {
+            let Some(socket) = self.sockets.get(&socket_id) else {
+                continue;
+            };
+            match socket.solicit(next_hop) {
+                Ok(()) => sent += 1,
+                Err(e) => log::debug!("ARP request for {} not sent: {}", next_hop, e),
+            }
+        }
+        neighbors
+            .stats()
+            .solicitations
+            .fetch_add(sent, Ordering::Relaxed);
+        sent
+    }
+
+    /// Resolve the next hops an ARP frame tells the address of
+    fn learn_neighbor(&self, mbuf: *mut Mbuf) {
+        let Some(neighbors) = &self.neighbors else {
+            return;
+        };
+        let Some((next_hop, mac)) = neighbor::parse_arp(unsafe { (*mbuf).data() }) else {
+            return;
+        };
+        if let Ok(waiting) = neighbors.resolve(next_hop, mac, true) {
+            let _ = self.flush_neighbor(neighbors, waiting);
+        }
+    }
+
+    /// Allocate a zero-copy transmit buffer for a socket
+    pub fn alloc_tx_buffer(&self, socket_id: u16, len: usize) -> Result<TxBuffer> {
+        self.get_socket(socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?
+            .alloc_tx_buffer(len)
+    }
+
+    /// Send a buffer obtained from [`UdpStack::alloc_tx_buffer`]
+    pub fn send_prepared(&self, buffer: TxBuffer, dst_addr: SocketAddr) -> Result<()> {
+        let socket_id = buffer.socket_id();
+        self.get_socket(socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?
+            .send_prepared(buffer, dst_addr)
+    }
+
+    /// Close a socket
+    pub fn close_socket(&mut self, socket_id: u16) -> Result<()> {
+        self.idle.disarm(socket_id);
+        self.keepalives.disarm(socket_id);
+        self.fair.remove(socket_id);
+        self.ephemeral.release_socket(socket_id);
+        if let Some(socket) = self.sockets.remove(&socket_id) {
+            self.directory.remove(socket_id);
+            self.table
+                .remove(socket.local_addr(), socket.bound_device, socket_id);
+            self.ephemeral.release_bound(socket.local_addr().port());
+            socket.stop()?;
+            self.stats.active_sockets.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Close or report a socket after `timeout` without RX/TX, `None` to disable
+    pub fn set_idle_timeout(&mut self, socket_id: u16, timeout: Option<Duration>) -> Result<()> {
+        let socket = self
+            .sockets
+            .get_mut(&socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        socket.idle_timeout = timeout;
+
+        match timeout {
+            Some(timeout) => {
+                let deadline = socket.last_activity() + timeout.as_nanos() as u64;
+                self.idle.arm(socket_id, deadline);
+            }
+            None => self.idle.disarm(socket_id),
+        }
+        Ok(())
+    }
+
+    /// Choose what happens to sockets that reach their idle timeout
+    pub fn set_idle_action(&mut self, action: IdleAction) {
+        self.idle_action = action;
+    }
+
+    /// Apply the idle action to every socket idle past its timeout at `now`
+    ///
+    /// `now` is a [`monotonic_now`] timestamp; call this periodically, at
+    /// least every [`IDLE_TIMER_TICK`] for full timer resolution. Returns the
+    /// IDs of the sockets that timed out.
+    pub fn expire_idle(&mut self, now: Timestamp) -> Result<Vec<u16>> {
+        let mut expired = Vec::new();
+        for socket_id in self.idle.fired(now) {
+            let Some(socket) = self.sockets.get(&socket_id) else {
+                continue;
+            };
+            let Some(timeout) = socket.idle_timeout else {
+                continue;
+            };
+
+            let timeout = timeout.as_nanos() as u64;
+            let deadline = socket.last_activity() + timeout;
+            if deadline > now {
+                // Traffic since the timer was armed
+                self.idle.arm(socket_id, deadline);
+                continue;
+            }
+
+            match &mut self.idle_action {
+                IdleAction::Close => {}
+                IdleAction::Notify(callback) => {
+                    callback(socket_id, socket.local_addr());
+                    self.idle.arm(socket_id, now + timeout);
+                }
+            }
+            expired.push(socket_id);
+        }
+
+        if matches!(self.idle_action, IdleAction::Close) {
+            for &socket_id in &expired {
+                self.close_socket(socket_id)?;
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Send keep-alives from a socket while it is quiet, `None` to stop
+    ///
+    /// The first keep-alive goes out one interval from now unless traffic
+    /// flows in the meantime.
+    pub fn set_keepalive(&mut self, socket_id: u16, config: Option<KeepAliveConfig>) -> Result<()> {
+        if config
+            .as_ref()
+            .is_some_and(|config| config.interval.is_zero())
+        {
+            return Err(Error::InvalidConfig(
+                "Keep-alive interval must be positive".to_string(),
+            ));
+        }
+        let socket = self
+            .sockets
+            .get_mut(&socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        socket.keepalive = config.map(KeepAlive::new);
+
+        match &socket.keepalive {
+            Some(keepalive) => {
+                let deadline = monotonic_now() + keepalive.interval_nanos();
+                self.keepalives.arm(socket_id, deadline);
+            }
+            None => self.keepalives.disarm(socket_id),
+        }
+        Ok(())
+    }
+
+    /// Queue the datagrams reorder buffers held past their maximum delay at `now`
+    ///
+    /// `now` is a [`monotonic_now`] timestamp; call this periodically like
+    /// [`UdpStack::expire_idle`], or held datagrams of a quiet flow wait
+    /// for its next one.
+    pub fn flush_reorder_buffers(&self, now: Timestamp) {
+        for socket in self.sockets.values() {
+            if let Some(reorder) = &socket.reorder {
+                reorder.flush_expired(now, |mbuf| socket.enqueue_released(mbuf));
+            }
+        }
+    }
+
+    /// Free the random source ports of flows idle at `now`, returning how many
+    ///
+    /// `now` is a [`monotonic_now`] timestamp; call this periodically like
+    /// [`UdpStack::expire_idle`]. A later send to the destination of an
+    /// expired flow draws a fresh port.
+    pub fn expire_source_ports(&self, now: Timestamp) -> usize {
+        let expired = self.ephemeral.expire(now);
+        for (socket_id, dst) in &expired {
+            if let Some(socket) = self.sockets.get(socket_id) {
+                socket.templates.invalidate(Some(*dst));
+            }
+        }
+        expired.len()
+    }
+
+    /// Send the keep-alives due at `now`, returning how many were sent
+    ///
+    /// `now` is a [`monotonic_now`] timestamp; call this periodically like
+    /// [`UdpStack::expire_idle`]. Send failures are counted in the socket's
+    /// [`KeepAliveStats`] and retried one interval later.
+    pub fn run_keepalives(&mut self, now: Timestamp) -> usize {
+        let mut sent = 0;
+        for socket_id in self.keepalives.fired(now) {
+            let Some(socket) = self.sockets.get(&socket_id) else {
+                continue;
+            };
+            let Some(keepalive) = &socket.keepalive else {
+                continue;
+            };
+
+            let interval = keepalive.interval_nanos();
+            let quiet_until = socket.last_activity() + interval;
+            if !keepalive.is_enabled() {
+                self.keepalives.arm(socket_id, now + interval);
+            } else if quiet_until > now {
+                // Real traffic keeps the path open
+                keepalive.stats.suppressed.fetch_add(1, Ordering::Relaxed);
+                self.keepalives.arm(socket_id, quiet_until);
+            } else {
+                match socket.send_keepalive(keepalive) {
+                    Ok(()) => {
+                        keepalive.stats.sent.fetch_add(1, Ordering::Relaxed);
+                        sent += 1;
+                    }
+                    Err(_) => {
+                        keepalive.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                self.keepalives.arm(socket_id, now + interval);
+            }
+        }
+        sent
+    }
+
+    /// Set the share of `recv_fair` batches a socket gets; weight 0 skips it
+    ///
+    /// Sockets default to weight 1.
+    pub fn set_fair_weight(&mut self, socket_id: u16, weight: u32) -> Result<()> {
+        if !self.sockets.contains_key(&socket_id) {
+            return Err(Error::NetworkError(format!(
+                "Socket {} not found",
+                socket_id
+            )));
+        }
+        self.fair.set_weight(socket_id, weight);
+        Ok(())
+    }
+
+    /// Set what a socket's full receive queue gives up, see [`UdpSocket::set_shed_policy`]
+    pub fn set_shed_policy(&mut self, socket_id: u16, policy: ShedPolicy) -> Result<()> {
+        let socket = self
+            .sockets
+            .get_mut(&socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        socket.set_shed_policy(policy);
+        Ok(())
+    }
+
+    /// Drop datagrams for a socket that `filter` rejects, `None` to accept all
+    ///
+    /// See [`RxFilter`] for when the filter runs.
+    pub fn set_rx_filter(&mut self, socket_id: u16, filter: Option<RxFilter>) -> Result<()> {
+        let socket = self
+            .sockets
+            .get_mut(&socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        socket.set_rx_filter(filter);
+        Ok(())
+    }
+
+    /// Switch a socket between copy and zero-copy delivery, see [`UdpSocket::set_delivery_mode`]
+    pub fn set_delivery_mode(&self, socket_id: u16, mode: DeliveryMode) -> Result<()> {
+        let socket = self
+            .sockets
+            .get(&socket_id)
+            .ok_or_else(|| Error::NetworkError(format!("Socket {} not found", socket_id)))?;
+        socket.set_delivery_mode(mode);
+        Ok(())
+    }
+
+    /// Receive up to `batch` packets across all sockets in weighted fair order
+    ///
+    /// Packets are tagged with the ID of their socket and are released there
+    /// as with [`UdpSocket::recv`]. See [`FAIR_QUANTUM`] for the weighting.
+    pub fn recv_fair(&mut self, batch: usize) -> Vec<(u16, UdpPacket)> {
+        let socket_ids: Vec<u16> = self.sockets.keys().copied().collect();
+        let sockets = &self.sockets;
+        self.fair.run(
+            &socket_ids,
+            batch,
+            |socket_id| sockets.get(&socket_id)?.recv().ok(),
+            |packet| packet.payload().len(),
+        )
+    }
+
+    /// Socket IDs and last activity times, least recently active first
+    pub fn sockets_by_activity(&self) -> Vec<(u16, Timestamp)> {
+        let mut sockets: Vec<_> = self
+            .sockets
+            .values()
+            .map(|socket| (socket.id(), socket.last_activity()))
+            .collect();
+        sockets.sort_by_key(|&(socket_id, last_activity)| (last_activity, socket_id));
+        sockets
+    }
+
+    /// Open a receive-all socket shown every frame dispatched, see [`Sniffer`]
+    ///
+    /// Only frames `filter` accepts are captured. The returned handle can
+    /// be moved to a monitoring thread.
+    pub fn open_sniffer(
+        &mut self,
+        config: SnifferConfig,
+        filter: Option<SnifferFilter>,
+    ) -> Arc<Sniffer> {
+        let sniffer = Arc::new(Sniffer::new(self.next_sniffer_id, config, filter));
+        self.next_sniffer_id = self.next_sniffer_id.wrapping_add(1);
+        self.sniffers.push(sniffer.clone());
+        sniffer
+    }
+
+    /// Stop capturing for the sniffer `sniffer_id`
+    pub fn close_sniffer(&mut self, sniffer_id: u16) -> Result<()> {
+        let count = self.sniffers.len();
+        self.sniffers.retain(|sniffer| sniffer.id() != sniffer_id);
+        if self.sniffers.len() == count {
+            return Err(Error::NetworkError(format!(
+                "Sniffer {} not found",
+                sniffer_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Deliver a received frame to the socket bound to its destination port
+    ///
+    /// See [`Delivery`] for who owns the mbuf afterwards.
+    pub fn dispatch(&self, mbuf: *mut Mbuf) -> Delivery {
+        // Before delivery, after which the mbuf may be gone
+        self.sniff(mbuf);
+        let udp = self.mib.record_ingress(mbuf);
+        if !udp && !mbuf.is_null() {
+            self.learn_pmtu(mbuf);
+        }
+        let delivery = self
+            .match_patterns(mbuf)
+            .unwrap_or_else(|| self.deliver(mbuf));
+        self.mib.record_delivery(udp, delivery);
+        if let (Some(capture), Delivery::Dropped(reason)) = (&self.capture, delivery) {
+            let mut capture = capture.lock();
+            if reason == DropReason::BadChecksum {
+                capture.record_checksum_failure();
+            }
+            capture.record_drop(reason);
+        }
+        delivery
+    }
+
+    /// Feed `capture` with the frames reaching a socket and every drop
+    ///
+    /// Frames are offered with their color, so a capture keeping only some
+    /// [`crate::utils::capture::CaptureConfig::colors`] skips the others.
+    /// The caller keeps a handle to finish the capture or read its stats.
+    pub fn set_capture_trigger(&mut self, capture: Option<Arc<Mutex<CaptureTrigger>>>) {
+        self.capture = capture;
+    }
+
+    /// Scan every dispatched frame for `patterns` and apply their actions
+    ///
+    /// UDP frames are scanned from the payload as received, anything else
+    /// from the start of the frame. Frames matching a drop pattern are
+    /// dropped with [`DropReason::Pattern`]; frames matching a mirror
+    /// pattern are sent as they are on the queue set with
+    /// [`UdpStack::set_mirror_queue`] and delivered as usual. See
+    /// [`crate::utils::pattern`].
+    pub fn set_patterns(&mut self, patterns: Option<Arc<PatternSet>>) {
+        self.patterns = patterns;
+    }
+
+    /// Send frames matching a mirror pattern on `mirror_queue`, see [`UdpStack::set_patterns`]
+    ///
+    /// Mirror copies that fail to send are counted in the queue's errors.
+    pub fn set_mirror_queue(&mut self, mirror_queue: Option<Arc<TxQueue>>) {
+        self.mirror_queue = mirror_queue;
+    }
+
+    /// Apply the pattern stage, returning the delivery of a frame it drops
+    fn match_patterns(&self, mbuf: *mut Mbuf) -> Option<Delivery> {
+        let patterns = self.patterns.as_ref().filter(|_| !mbuf.is_null())?;
+        let mbuf_ref = unsafe { &mut *mbuf };
+        if mbuf_ref.packet_type == PacketType::Unknown {
+            classify(mbuf_ref);
+        }
+        match patterns.process(mbuf_ref) {
+            MatchVerdict::Drop => return Some(Delivery::Dropped(DropReason::Pattern)),
+            MatchVerdict::Mirror => {
+                if let Some(mirror_queue) = &self.mirror_queue {
+                    // libpcap copies the frame, which goes on to its socket
+                    let _ = mirror_queue.send(mbuf);
+                }
+            }
+            MatchVerdict::Pass | MatchVerdict::Mark(_) => {}
+        }
+        None
+    }
+
+    fn sniff(&self, mbuf: *mut Mbuf) {
+        if mbuf.is_null() {
+            return;
+        }
+        PacketLog::global().log("rx", unsafe { (*mbuf).data() });
+        for sniffer in &self.sniffers {
+            sniffer.capture(unsafe { &*mbuf });
+        }
+    }
+
+    fn deliver(&self, mbuf: *mut Mbuf) -> Delivery {
+        let packet = match UdpPacket::from_mbuf(mbuf) {
+            Ok(packet) => packet,
+            Err(_) => return Delivery::Dropped(DropReason::Malformed),
+        };
+        if packet.checksum_bad() {
+            return Delivery::Dropped(DropReason::BadChecksum);
+        }
+        if let Some(handler) = self.handlers.lookup(&packet) {
+            // Without a pool to recycle a consumed mbuf to, the caller frees it
+            let Some(pool) = &self.rx_pool else {
+                return Delivery::Dropped(DropReason::Handler);
+            };
+            match handler.handle(&packet) {
+                HandlerVerdict::Consumed => {
+                    let _ = pool.free(mbuf);
+                    self.stats.total_packets_received.inc();
+                    return Delivery::Delivered;
+                }
+                HandlerVerdict::Reject => return Delivery::Dropped(DropReason::Handler),
+                HandlerVerdict::Pass => {}
+            }
+        }
+
+        let dst_addr = packet.dst_addr();
+        let device = unsafe { (*mbuf).port_id };
+
+        // The most specific bind wins, see `table`; replies to a randomized
+        // source port go to the socket that sent from it
+        let socket = match self
+            .table
+            .lookup(dst_addr, device)
+            .or_else(|| self.ephemeral.receive(dst_addr.port()))
+            .and_then(|id| self.sockets.get(&id))
+        {
+            Some(socket) => socket,
+            None => return Delivery::Dropped(DropReason::NoSocket),
+        };
+
+        if self.drops_early(socket) {
+            socket.stats.early_dropped.inc();
+            socket.stats.packets_dropped.inc();
+            return Delivery::Dropped(DropReason::PoolPressure);
+        }
+
+        if socket
+            .rx_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.accepts(&packet))
+        {
+            socket.stats.filtered.inc();
+            socket.stats.packets_dropped.inc();
+            return Delivery::Dropped(DropReason::Filtered);
+        }
+
+        // Authenticate before the replay guard moves its window
+        if socket.decrypt(&packet).is_err() {
+            socket.stats.packets_dropped.inc();
+            return Delivery::Dropped(DropReason::Decrypt);
+        }
+        if socket.decompress(&packet).is_err() {
+            socket.stats.packets_dropped.inc();
+            return Delivery::Dropped(DropReason::Decompress);
+        }
+        // Recorded once queued, so a datagram dropped on the way can be resent
+        let sequence = match &socket.replay_guard {
+            Some(guard) => match guard.validate(&packet) {
+                Ok(seq) => Some((guard, seq)),
+                Err(_) => {
+                    socket.stats.packets_dropped.inc();
+                    return Delivery::Dropped(DropReason::Replay);
+                }
+            },
+            None => None,
+        };
+
+        let colorer = PacketColorer::global();
+        let color = colorer.color_rx(
+            unsafe { &mut *mbuf },
+            socket.id,
+            dst_addr.port(),
+            packet.ipv4_header().src_addr(),
+        );
+
+        // Once pushed the socket owns the mbuf; a failed push leaves it with us
+        let trace_id = packet.trace_id();
+        if color != UNCOLORED {
+            PacketTracer::global().set_color(trace_id, color);
+        }
+        if let Some(capture) = &self.capture {
+            capture
+                .lock()
+                .observe_colored(unsafe { (*mbuf).data() }, color);
+        }
+        let queued = match &socket.reorder {
+            Some(reorder) => {
+                let mut queued = true;
+                let verdict = reorder.push(&packet, monotonic_now(), |released| {
+                    if released == mbuf {
+                        queued = socket.enqueue(&packet);
+                    } else {
+                        socket.enqueue_released(released);
+                    }
+                });
+                match verdict {
+                    Reordered::Late => {
+                        socket.stats.packets_dropped.inc();
+                        return Delivery::Dropped(DropReason::Late);
+                    }
+                    Reordered::Unsequenced => socket.enqueue(&packet),
+                    Reordered::Released | Reordered::Held => queued,
+                }
+            }
+            None => socket.enqueue(&packet),
+        };
+        if !queued {
+            socket.stats.packets_dropped.inc();
+            self.stats.total_errors.inc();
+            colorer.log(
+                color,
+                log::Level::Debug,
+                format_args!("socket {} queue full, dropped packet", socket.name),
+            );
+            return Delivery::Dropped(DropReason::QueueFull);
+        }
+        if let Some((guard, seq)) = sequence {
+            // A socket has one delivering thread, so nothing was recorded since
+            guard.record(packet.src_addr(), seq);
+        }
+        PacketTracer::global().record(trace_id, TraceStage::Enqueue);
+
+        self.stats.total_packets_received.inc();
+        Delivery::Delivered
     }
 
-    /// Get a socket by ID
-    pub fn get_socket(&self, socket_id: u16) -> Option<&UdpSocket> {
-        self.sockets.get(&socket_id)
+    /// Whether the receive pool is low enough and `socket` holds enough to drop for it
+    fn drops_early(&self, socket: &UdpSocket) -> bool {
+        let (Some(early_drop), Some(pool)) = (&self.early_drop, &self.rx_pool) else {
+            return false;
+        };
+        let (available, size) = (pool.available(), pool.size());
+        if !early_drop.pressured(available, size) {
+            return false;
+        }
+        self.early_drop_stats.pressured.inc();
+        let drops = early_drop.drops(available, size, self.sockets.len(), socket.held_buffers());
+        if drops {
+            self.early_drop_stats.dropped.inc();
+        }
+        drops
     }
 
-    /// Get a mutable socket by ID
-    pub fn get_socket_mut(&mut self, socket_id: u16) -> Option<&mut UdpSocket> {
-        self.sockets.get_mut(&socket_id)
+    /// Serve the datagrams `matcher` selects with `handler` instead of a socket
+    ///
+    /// See [`ProtocolHandler`]. The handler is started at once if the stack
+    /// is running. Fails if another handler already serves the port.
+    pub fn register_handler(
+        &mut self,
+        matcher: HandlerMatch,
+        handler: Arc<dyn ProtocolHandler>,
+    ) -> Result<u16> {
+        let id = self.handlers.register(matcher, handler.clone())?;
+        if self.is_running() {
+            handler.on_start();
+        }
+        Ok(id)
     }
 
-    /// Close a socket
-    pub fn close_socket(&mut self, socket_id: u16) -> Result<()> {
-        if let Some(socket) = self.sockets.remove(&socket_id) {
-            socket.stop()?;
-            self.stats.active_sockets.fetch_sub(1, Ordering::Relaxed);
+    /// Remove a protocol handler, stopping it if the stack is running
+    pub fn unregister_handler(&mut self, handler_id: u16) -> Result<()> {
+        let handler = self
+            .handlers
+            .remove(handler_id)
+            .ok_or_else(|| Error::NetworkError(format!("Handler {} not found", handler_id)))?;
+        if self.is_running() {
+            handler.on_stop();
         }
         Ok(())
     }
 
+    /// Get the counters of a protocol handler
+    pub fn handler_stats(&self, handler_id: u16) -> Option<Arc<HandlerStats>> {
+        self.handlers.stats(handler_id)
+    }
+
+    /// Set early drop under pool pressure, or turn it off with `None`
+    pub fn set_early_drop(&mut self, early_drop: Option<EarlyDropConfig>) {
+        self.early_drop = early_drop;
+    }
+
+    /// Get the early drop counters
+    pub fn early_drop_stats(&self) -> &EarlyDropStats {
+        &self.early_drop_stats
+    }
+
     /// Process incoming packets from RX queue
     pub fn process_rx_packets(&mut self, rx_queue: &RxQueue) -> Result<usize> {
         let mut processed = 0;
-        let max_batch = 32;
+        let max_batch = rx_queue.batch_size();
+        let mut poller = match rx_queue.poller() {
+            Ok(poller) => poller,
+            // Polled elsewhere, nothing for us
+            Err(Error::QueueError(_)) => return Ok(0),
+            Err(e) => return Err(e),
+        };
 
+        let mut dropped = FreeBatch::new(rx_queue.get_pool());
         for _ in 0..max_batch {
-            match rx_queue.recv() {
+            match poller.recv() {
                 Ok(mbuf) => {
-                    if let Ok(packet) = UdpPacket::from_mbuf(mbuf) {
-                        // Find matching socket
-                        let dst_addr = packet.dst_addr();
-
-                        for socket in self.sockets.values() {
-                            if socket.local_addr().port() == dst_addr.port() {
-                                // Add packet to socket's receive queue
-                                if let Err(_) = socket.recv_queue.push(mbuf) {
-                                    // Queue full, drop packet
-                                    self.stats.total_errors.fetch_add(1, Ordering::Relaxed);
-                                }
-                                break;
-                            }
-                        }
-
+                    if self.dispatch(mbuf).is_delivered() {
                         processed += 1;
-                        self.stats
-                            .total_packets_received
-                            .fetch_add(1, Ordering::Relaxed);
                     } else {
-                        // Not a UDP packet, drop it
-                        rx_queue.get_pool().free(mbuf)?;
+                        // Not for any of our sockets, drop it
+                        dropped.push(mbuf)?;
                     }
                 }
                 Err(Error::NetworkError(_)) => break, // No more packets
                 Err(e) => return Err(e),
             }
         }
+        dropped.flush()?;
 
         Ok(processed)
     }
@@ -545,6 +3439,9 @@ impl UdpStack {
         for socket in self.sockets.values() {
             socket.start()?;
         }
+        for handler in self.handlers.handlers() {
+            handler.on_start();
+        }
 
         Ok(())
     }
@@ -557,10 +3454,152 @@ impl UdpStack {
         for socket in self.sockets.values() {
             socket.stop()?;
         }
+        for handler in self.handlers.handlers() {
+            handler.on_stop();
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the stack is running
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Get the source ports allocated to the stack's sockets
+    pub fn ephemeral_ports(&self) -> &Arc<EphemeralPorts> {
+        &self.ephemeral
+    }
+
+    /// Get every port the stack's sockets receive on, bound or allocated
+    pub fn local_ports(&self) -> BTreeSet<u16> {
+        self.ephemeral.local_ports()
+    }
+
+    /// Fault in the receive queues of every socket
+    ///
+    /// Returns the queues and the bytes touched.
+    pub fn prefault_queues(&self) -> (usize, usize) {
+        let bytes = self.sockets.values().map(UdpSocket::prefault_queue).sum();
+        (self.sockets.len(), bytes)
+    }
+
+    /// Pass one datagram through the receive path to a temporary loopback socket
+    ///
+    /// The frame is built in a buffer of the receive pool, parsed, looked up
+    /// and queued to the socket, then received back, exercising parsing,
+    /// checksums, the bind table and the socket queue before real traffic
+    /// does. It skips the rest of delivery, so the stack's counters, its
+    /// handlers and the packet tracer never see it. The socket takes a free
+    /// ephemeral port and is closed afterwards.
+    pub fn self_test(&mut self) -> Result<()> {
+        let pool = self
+            .rx_pool
+            .clone()
+            .ok_or_else(|| Error::NetworkError("No receive pool bound".to_string()))?;
+        let bound = self.local_ports();
+        let port = EPHEMERAL_PORTS
+            .rev()
+            .find(|port| !bound.contains(port))
+            .ok_or_else(|| Error::NetworkError("No free port for the self-test".to_string()))?;
+        let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let socket_id = self.create_socket(local.into())?;
+        let result = self.loopback(&pool, socket_id, local);
+        self.close_socket(socket_id)?;
+        result
+    }
+
+    fn loopback(&self, pool: &MbufPool, socket_id: u16, local: SocketAddrV4) -> Result<()> {
+        const PAYLOAD: &[u8] = b"xpdk self-test";
+        let mut frame = [0u8; TX_HEADROOM + PAYLOAD.len()];
+        HeaderTemplate::new([0; 6], [0; 6], local, local, false).write(
+            &mut frame,
+            PAYLOAD.len(),
+            0,
+        );
+        frame[TX_HEADROOM..].copy_from_slice(PAYLOAD);
 
+        let mbuf = pool.alloc()?;
+        if let Err(e) = unsafe { (*mbuf).append(&frame) } {
+            pool.free(mbuf)?;
+            return Err(e);
+        }
+        let dropped = |reason: DropReason| {
+            pool.free(mbuf)?;
+            Err(Error::NetworkError(format!(
+                "Self-test datagram dropped: {}",
+                reason.as_str()
+            )))
+        };
+        let Ok(packet) = UdpPacket::from_mbuf(mbuf) else {
+            return dropped(DropReason::Malformed);
+        };
+        if packet.checksum_bad() {
+            return dropped(DropReason::BadChecksum);
+        }
+        let Some(socket) = self
+            .table
+            .lookup(packet.dst_addr(), unsafe { (*mbuf).port_id })
+            .filter(|&id| id == socket_id)
+            .and_then(|id| self.sockets.get(&id))
+        else {
+            return dropped(DropReason::NoSocket);
+        };
+        if !socket.enqueue(&packet) {
+            return dropped(DropReason::QueueFull);
+        }
+        let packet = socket.recv()?;
+        let echoed = packet.payload() == PAYLOAD;
+        socket.release(packet)?;
+        if !echoed {
+            return Err(Error::NetworkError(
+                "Self-test datagram came back altered".to_string(),
+            ));
+        }
         Ok(())
     }
 
+    /// Get the path MTU cache shared by the stack's sockets
+    pub fn pmtu_cache(&self) -> &Arc<PmtuCache> {
+        &self.pmtu
+    }
+
+    /// Learn a path MTU from a classified ICMP frame
+    ///
+    /// Returns the destination and its new path MTU if the frame was a
+    /// "fragmentation needed" message that lowered it. The message must
+    /// quote a datagram sent from a socket of the stack: a bound address
+    /// and port, or a randomized source port towards the quoted destination.
+    pub fn handle_icmp(&self, mbuf: &Mbuf) -> Option<(Ipv4Addr, u16)> {
+        if mbuf.packet_type != PacketType::Icmp || mbuf.l3_offset == 0 {
+            return None;
+        }
+        let device = mbuf.port_id;
+        self.pmtu
+            .handle_icmp(mbuf.data().get(mbuf.l4_offset as usize..)?, |src, dst| {
+                self.table.lookup(SocketAddr::V4(src), device).is_some()
+                    || self
+                        .ephemeral
+                        .owner(src.port())
+                        .is_some_and(|id| self.ephemeral.get(id, dst) == Some(src.port()))
+            })
+    }
+
+    fn learn_pmtu(&self, mbuf: *mut Mbuf) {
+        self.handle_icmp(unsafe { &*mbuf });
+        self.learn_neighbor(mbuf);
+    }
+
+    /// Get the per-layer protocol counters
+    pub fn protocol_stats(&self) -> MibSnapshot {
+        self.mib.snapshot()
+    }
+
+    /// Get datagrams sent by traffic class across the sockets
+    pub fn egress_stats(&self) -> &EgressStats {
+        &self.egress
+    }
+
     /// Get stack statistics
     pub fn stats(&self) -> UdpStackStatsView {
         let mut total_rx_packets = 0;
@@ -570,21 +3609,21 @@ impl UdpStack {
         let mut total_errors = 0;
 
         for socket in self.sockets.values() {
-            total_rx_packets += socket.stats.packets_received.load(Ordering::Relaxed);
-            total_rx_bytes += socket.stats.bytes_received.load(Ordering::Relaxed);
-            let _ = socket.stats.packets_sent.load(Ordering::Relaxed);
-            total_tx_bytes += socket.stats.bytes_sent.load(Ordering::Relaxed);
-            total_errors += socket.stats.errors.load(Ordering::Relaxed);
+            total_rx_packets += socket.stats.packets_received.get();
+            total_rx_bytes += socket.stats.bytes_received.get();
+            let _ = socket.stats.packets_sent.get();
+            total_tx_bytes += socket.stats.bytes_sent.get();
+            total_errors += socket.stats.errors.get();
         }
 
         UdpStackStatsView {
             total_sockets: self.stats.total_sockets.load(Ordering::Relaxed),
             active_sockets: self.stats.active_sockets.load(Ordering::Relaxed),
-            total_packets_received: self.stats.total_packets_received.load(Ordering::Relaxed),
-            total_packets_sent: self.stats.total_packets_sent.load(Ordering::Relaxed),
-            total_bytes_received: self.stats.total_bytes_received.load(Ordering::Relaxed),
-            total_bytes_sent: self.stats.total_bytes_sent.load(Ordering::Relaxed),
-            total_errors: self.stats.total_errors.load(Ordering::Relaxed),
+            total_packets_received: self.stats.total_packets_received.get(),
+            total_packets_sent: self.stats.total_packets_sent.get(),
+            total_bytes_received: self.stats.total_bytes_received.get(),
+            total_bytes_sent: self.stats.total_bytes_sent.get(),
+            total_errors: self.stats.total_errors.get(),
             socket_stats: total_rx_packets,
             socket_bytes_rx: total_rx_bytes,
             socket_bytes_tx: total_tx_bytes,
@@ -598,15 +3637,15 @@ impl UdpStack {
 pub struct UdpStackStatsView {
     pub total_sockets: usize,
     pub active_sockets: usize,
-    pub total_packets_received: usize,
-    pub total_packets_sent: usize,
-    pub total_bytes_received: usize,
-    pub total_bytes_sent: usize,
-    pub total_errors: usize,
-    pub socket_stats: usize,
-    pub socket_bytes_rx: usize,
-    pub socket_bytes_tx: usize,
-    pub socket_errors: usize,
+    pub total_packets_received: u64,
+    pub total_packets_sent: u64,
+    pub total_bytes_received: u64,
+    pub total_bytes_sent: u64,
+    pub total_errors: u64,
+    pub socket_stats: u64,
+    pub socket_bytes_rx: u64,
+    pub socket_bytes_tx: u64,
+    pub socket_errors: u64,
 }
 
 #[cfg(test)]
@@ -632,6 +3671,41 @@ mod tests {
         assert_eq!(header.protocol(), 17);
     }
 
+    #[test]
+    fn test_header_serialization_is_host_independent() {
+        let udp = UdpHeader::new(8080, 53, 512);
+        assert_eq!(
+            udp.to_bytes(),
+            [0x1F, 0x90, 0x00, 0x35, 0x02, 0x00, 0x00, 0x00]
+        );
+
+        let mut ip = Ipv4Header::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 8);
+        ip.identification = 0x1234u16.to_be();
+        ip.checksum = ip.compute_checksum().to_be();
+        let ip_bytes = ip.to_bytes();
+        assert_eq!(&ip_bytes[..8], &[0x45, 0, 0, 28, 0x12, 0x34, 0, 0]);
+        assert_eq!(&ip_bytes[12..], &[10, 0, 0, 1, 10, 0, 0, 2]);
+        // The stored checksum makes the header sum to zero
+        assert_eq!(checksum::ones_complement(0, &ip_bytes), 0xFFFF);
+
+        let eth = EthernetHeader::new([1; 6], [2; 6], ETHERTYPE_IPV4);
+        let eth_bytes = eth.to_bytes();
+        assert_eq!(&eth_bytes[..12], &[[2; 6], [1; 6]].concat()[..]);
+        assert_eq!(&eth_bytes[12..], &[0x08, 0x00]);
+
+        // Round trips keep the in-memory network byte order
+        let parsed = Ipv4Header::from_bytes(&ip_bytes).unwrap();
+        assert_eq!(parsed.to_bytes(), ip_bytes);
+        assert_eq!(parsed.src_addr(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(
+            UdpHeader::from_bytes(&udp.to_bytes()).unwrap().dst_port(),
+            53
+        );
+        let parsed = EthernetHeader::from_bytes(&eth_bytes).unwrap();
+        assert_eq!(parsed.ether_type(), ETHERTYPE_IPV4);
+        assert!(UdpHeader::from_bytes(&[0; 7]).is_err());
+    }
+
     #[test]
     fn test_udp_stack_creation() {
         let config = Config::default();
@@ -650,4 +3724,916 @@ mod tests {
         assert!(socket_id > 0);
         assert_eq!(stack.stats().total_sockets, 1);
     }
+
+    #[test]
+    fn test_prepared_tx_buffer_headers() {
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 8, 2048).unwrap());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+
+        let mut socket = UdpSocket::new(local_addr, 16, 1).unwrap();
+        socket.bind_tx_pool(pool.clone());
+
+        let mut buffer = socket.alloc_tx_buffer(5).unwrap();
+        assert_eq!(buffer.payload_mut().len(), 5);
+        buffer.payload_mut().copy_from_slice(b"hello");
+        socket.write_headers(&mut buffer, dst_addr).unwrap();
+
+        let packet = UdpPacket::from_mbuf(buffer.mbuf()).unwrap();
+        assert_eq!(packet.payload(), b"hello");
+        assert_eq!(packet.src_addr(), local_addr);
+        assert_eq!(packet.dst_addr(), dst_addr);
+
+        let ip_header = packet.ipv4_header();
+        assert_eq!(
+            ip_header.compute_checksum(),
+            u16::from_be(ip_header.checksum)
+        );
+        drop(buffer);
+
+        // Later sends reuse the template until the MAC addresses change
+        let mut buffer = socket.alloc_tx_buffer(3).unwrap();
+        socket.write_headers(&mut buffer, dst_addr).unwrap();
+        assert_eq!(
+            UdpPacket::from_mbuf(buffer.mbuf()).unwrap().payload().len(),
+            3
+        );
+        socket.set_mac_addresses([2; 6], [4; 6]);
+        socket.write_headers(&mut buffer, dst_addr).unwrap();
+        assert_eq!(&buffer.frame()[..6], &[4; 6]);
+        let stats = socket.template_stats();
+        assert_eq!((stats.hits, stats.misses, stats.invalidations), (1, 2, 1));
+
+        drop(buffer);
+        assert_eq!(pool.stats().available, 8);
+    }
+
+    #[test]
+    fn test_fragments_take_mbufs_in_chunks() {
+        // One mbuf holds the datagram, fewer than its fragments are left
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 10, 2048).unwrap());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+        let mut socket = UdpSocket::new(local_addr, 16, 1).unwrap();
+        let tx_queue = Arc::new(TxQueue::in_memory(0));
+        socket.bind_tx_pool(pool.clone());
+        socket.bind_tx_queue(tx_queue.clone());
+        socket
+            .pmtu
+            .seed(Ipv4Addr::new(10, 0, 0, 2), MIN_IPV4_MTU)
+            .unwrap();
+
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        socket.send(dst_addr, &payload).unwrap();
+        let frames = tx_queue.sent_frames();
+        let fragment_len = (MIN_IPV4_MTU as usize - 20) & !7;
+        assert_eq!(frames.len(), (8 + payload.len()).div_ceil(fragment_len));
+        let mut datagram = Vec::new();
+        for (index, frame) in frames.iter().enumerate() {
+            let ip_header = Ipv4Header::from_bytes(&frame[14..]).unwrap();
+            let flags_fragment = u16::from_be(ip_header.flags_fragment);
+            assert_eq!(flags_fragment & IPV4_MF != 0, index + 1 < frames.len());
+            assert_eq!((flags_fragment & 0x1FFF) as usize * 8, datagram.len());
+            datagram.extend_from_slice(&frame[34..]);
+        }
+        assert_eq!(&datagram[8..], &payload[..]);
+        assert_eq!(pool.stats().in_use, 0);
+
+        // A failed fragment ends the send and gives its chunk back
+        tx_queue.set_failing(true);
+        assert!(socket.send(dst_addr, &payload).is_err());
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_send_prepared_without_tx_queue() {
+        let config = Config::default();
+        let mut stack = UdpStack::new(&config).unwrap();
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 4, 2048).unwrap());
+        stack.set_tx_pool(pool.clone());
+
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let socket_id = stack.create_socket(local_addr).unwrap();
+
+        assert!(stack.alloc_tx_buffer(socket_id, 4096).is_err());
+
+        let buffer = stack.alloc_tx_buffer(socket_id, 64).unwrap();
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+        assert!(stack.send_prepared(buffer, dst_addr).is_err());
+        assert_eq!(pool.stats().available, 4);
+    }
+
+    #[test]
+    fn test_socket_names() {
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let addr = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+        let dns = stack.create_socket(addr(53)).unwrap();
+        let ntp = stack.create_socket(addr(123)).unwrap();
+        assert_eq!(
+            stack.get_socket(ntp).unwrap().name(),
+            format!("socket{}", ntp)
+        );
+
+        stack.set_socket_name(dns, "dns").unwrap();
+        assert_eq!(stack.socket_by_name("dns").unwrap().id(), dns);
+        // Renaming to its own name is fine, taking another socket's is not
+        stack.set_socket_name(dns, "dns").unwrap();
+        assert!(stack.set_socket_name(ntp, "dns").is_err());
+        assert!(stack.set_socket_name(u16::MAX, "spare").is_err());
+        assert!(stack.socket_by_name("spare").is_none());
+
+        // Snapshot writers see renames and closes
+        let directory = stack.socket_directory();
+        stack.get_socket(dns).unwrap().stats.packets_sent.inc();
+        let names: Vec<_> = directory
+            .counters()
+            .into_iter()
+            .map(|socket| (socket.name, socket.packets_sent))
+            .collect();
+        assert_eq!(
+            names,
+            [("dns".to_string(), 1), (format!("socket{}", ntp), 0)]
+        );
+        stack.close_socket(dns).unwrap();
+        assert_eq!(directory.counters().len(), 1);
+    }
+
+    #[test]
+    fn test_rx_filter_drops_before_enqueue() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let id = stack.create_socket(local_addr).unwrap();
+        stack
+            .set_rx_filter(id, Some(RxFilter::compile("payload[0] = 0x17").unwrap()))
+            .unwrap();
+        assert!(stack.set_rx_filter(u16::MAX, None).is_err());
+
+        let mbuf = load(
+            &pool,
+            &FrameBuilder::to_port(5000).payload(b"\x17ok").build(),
+        );
+        assert!(stack.dispatch(mbuf).is_delivered());
+        let mbuf = load(&pool, &FrameBuilder::to_port(5000).payload(b"junk").build());
+        assert_eq!(
+            stack.dispatch(mbuf),
+            Delivery::Dropped(DropReason::Filtered)
+        );
+        pool.free(mbuf).unwrap();
+
+        let socket = stack.get_socket(id).unwrap();
+        assert_eq!(socket.recv().unwrap().payload(), b"\x17ok");
+        assert!(socket.recv().is_err());
+        assert_eq!(socket.stats().filtered.get(), 1);
+        assert_eq!(socket.stats().packets_dropped.get(), 1);
+
+        /// Cipher refusing everything
+        struct Refuse;
+        impl PayloadTransform for Refuse {
+            fn overhead(&self) -> usize {
+                0
+            }
+            fn decrypt(&self, _: &mut [u8]) -> Result<usize> {
+                Err(Error::NetworkError("Bad tag".to_string()))
+            }
+            fn encrypt(&self, _: &mut [u8], len: usize) -> Result<usize> {
+                Ok(len)
+            }
+        }
+        // Filtered out before any decryption is tried
+        let socket = stack.get_socket_mut(id).unwrap();
+        socket.set_transform(Some(Box::new(Refuse)));
+        let mbuf = load(&pool, &FrameBuilder::to_port(5000).payload(b"junk").build());
+        assert_eq!(
+            stack.dispatch(mbuf),
+            Delivery::Dropped(DropReason::Filtered)
+        );
+        pool.free(mbuf).unwrap();
+    }
+
+    /// "ping" to port 5000 with an IPv4 header of `ihl` words, zeroed past
+    /// the first 5, sent as `protocol` with `flags_fragment`
+    fn frame(protocol: u8, ihl: u8, flags_fragment: u16) -> Vec<u8> {
+        use testing::{fill_checksums, FrameBuilder};
+        let mut frame = FrameBuilder::to_port(5000).payload(b"ping").build();
+        let options = (ihl as usize - 5) * 4;
+        frame.splice(34..34, std::iter::repeat_n(0, options));
+        frame[14] = 0x40 | ihl;
+        let total_length = u16::from_be_bytes([frame[16], frame[17]]) + options as u16;
+        frame[16..18].copy_from_slice(&total_length.to_be_bytes());
+        frame[20..22].copy_from_slice(&flags_fragment.to_be_bytes());
+        frame[23] = protocol;
+        fill_checksums(&mut frame);
+        frame
+    }
+
+    #[test]
+    fn test_classify_packet_types() {
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        let mbuf_ref = unsafe { &mut *mbuf };
+
+        mbuf_ref.append(&frame(IPPROTO_UDP, 6, 0)).unwrap();
+        assert_eq!(classify(mbuf_ref), PacketType::Udp);
+        assert_eq!(mbuf_ref.l3_offset, 14);
+        assert_eq!(mbuf_ref.l4_offset, 14 + 24);
+        assert_eq!(mbuf_ref.payload_offset, 14 + 24 + 8);
+
+        // Options running past the header make it invalid IPv4
+        let mut bad_options = frame(IPPROTO_UDP, 6, 0);
+        bad_options[34..36].copy_from_slice(&[7, 8]);
+        mbuf_ref.reset();
+        mbuf_ref.append(&bad_options).unwrap();
+        assert_eq!(classify(mbuf_ref), PacketType::Ethernet);
+
+        mbuf_ref.reset();
+        mbuf_ref.append(&frame(IPPROTO_UDP, 5, 0x0010)).unwrap();
+        assert_eq!(classify(mbuf_ref), PacketType::Ipv4);
+        assert!(UdpPacket::from_mbuf(mbuf).is_err());
+
+        mbuf_ref.reset();
+        mbuf_ref.append(&frame(IPPROTO_TCP, 5, 0)).unwrap();
+        assert_eq!(classify(mbuf_ref), PacketType::Tcp);
+
+        mbuf_ref.reset();
+        mbuf_ref.append(&[0u8; 10]).unwrap();
+        assert_eq!(classify(mbuf_ref), PacketType::Unknown);
+        assert_eq!(mbuf_ref.payload_offset, 0);
+
+        pool.free(mbuf).unwrap();
+    }
+
+    #[test]
+    fn test_from_mbuf_uses_classified_offsets() {
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        let mbuf_ref = unsafe { &mut *mbuf };
+
+        let mut with_options = frame(IPPROTO_UDP, 7, 0);
+        with_options[34..42].copy_from_slice(&[IPOPT_NOP, IPOPT_ROUTER_ALERT, 4, 0, 1, 0, 0, 0]);
+        mbuf_ref.append(&with_options).unwrap();
+        let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+        assert_eq!(mbuf_ref.packet_type, PacketType::Udp);
+        assert_eq!(packet.payload(), b"ping");
+        assert_eq!(packet.dst_addr().port(), 5000);
+        assert_eq!(packet.ipv4_options().router_alert, Some(1));
+
+        pool.free(mbuf).unwrap();
+    }
+
+    #[test]
+    fn test_priority_bands_reorder_recv() {
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_default_priority_bands(Some(PriorityBands::telco(8)));
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let socket_id = stack.create_socket(local_addr).unwrap();
+
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let best_effort = pool.alloc().unwrap();
+        let expedited = pool.alloc().unwrap();
+        let ef_frame = testing::FrameBuilder::to_port(5000)
+            .payload(b"ping")
+            .dscp(DSCP_EF)
+            .build();
+        unsafe {
+            (*best_effort).append(&frame(IPPROTO_UDP, 5, 0)).unwrap();
+            (*expedited).append(&ef_frame).unwrap();
+        }
+
+        assert!(stack.dispatch(best_effort).is_delivered());
+        assert!(stack.dispatch(expedited).is_delivered());
+
+        let socket = stack.get_socket(socket_id).unwrap();
+        assert_eq!(socket.recv().unwrap().mbuf, expedited);
+        assert_eq!(socket.recv().unwrap().mbuf, best_effort);
+        assert!(socket.recv().is_err());
+
+        let stats = socket.band_stats().unwrap();
+        assert_eq!(stats[0].dequeued, 1);
+        assert_eq!(stats[2].dequeued, 1);
+        pool.free(best_effort).unwrap();
+        pool.free(expedited).unwrap();
+    }
+
+    #[test]
+    fn test_shed_policies() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 16, 256).unwrap());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let mut socket = UdpSocket::new(local_addr, 4, 1).unwrap();
+        socket.bind_rx_pool(pool.clone());
+        let offer = |socket: &UdpSocket, seq: u8, dscp: u8| {
+            let frame = FrameBuilder::to_port(5000)
+                .payload(&[seq])
+                .dscp(dscp)
+                .build();
+            let packet = UdpPacket::from_mbuf(load(&pool, &frame)).unwrap();
+            let queued = socket.enqueue(&packet);
+            if !queued {
+                pool.free(packet.mbuf).unwrap();
+            }
+            queued
+        };
+        let drain = |socket: &UdpSocket| {
+            let mut seqs = Vec::new();
+            while let Ok(packet) = socket.recv() {
+                seqs.push(packet.payload()[0]);
+                socket.release(packet).unwrap();
+            }
+            seqs
+        };
+
+        // Head-drop keeps the newest four
+        socket.set_shed_policy(ShedPolicy::HeadDrop);
+        for seq in 0..6 {
+            assert!(offer(&socket, seq, 0));
+        }
+        assert_eq!(drain(&socket), [2, 3, 4, 5]);
+
+        // DSCP refuses best effort past half the queue, not EF
+        socket.set_shed_policy(ShedPolicy::Dscp);
+        assert!(offer(&socket, 0, 0));
+        assert!(offer(&socket, 1, 0));
+        assert!(!offer(&socket, 2, 0));
+        assert!(offer(&socket, 3, DSCP_EF));
+        assert!(offer(&socket, 4, DSCP_EF));
+        assert!(!offer(&socket, 5, DSCP_EF));
+        assert_eq!(drain(&socket), [0, 1, 3, 4]);
+
+        let stats = socket.shed_stats();
+        assert_eq!(stats.head_dropped.get(), 2);
+        assert_eq!(stats.dscp_dropped.get(), 1);
+        assert_eq!(stats.tail_dropped.get(), 1);
+        assert_eq!((stats.class_dropped(0), stats.class_dropped(5)), (3, 1));
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_dispatch_drops_replays() {
+        let config = Config::default();
+        let mut stack = UdpStack::new(&config).unwrap();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let socket_id = stack.create_socket(local_addr).unwrap();
+        stack
+            .get_socket_mut(socket_id)
+            .unwrap()
+            .set_replay_guard(ReplayGuard::new(64, |payload| {
+                Some(u32::from_be_bytes(payload.get(..4)?.try_into().ok()?) as u64)
+            }));
+
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let first = pool.alloc().unwrap();
+        let replay = pool.alloc().unwrap();
+        unsafe {
+            (*first).append(&frame(IPPROTO_UDP, 5, 0)).unwrap();
+            (*replay).append(&frame(IPPROTO_UDP, 5, 0)).unwrap();
+        }
+
+        assert_eq!(stack.dispatch(first), Delivery::Delivered);
+        assert_eq!(
+            stack.dispatch(replay),
+            Delivery::Dropped(DropReason::Replay)
+        );
+
+        let socket = stack.get_socket(socket_id).unwrap();
+        let guard = socket.replay_guard().unwrap();
+        assert_eq!(guard.stats().replays.load(Ordering::Relaxed), 1);
+        assert_eq!(guard.peer_count(), 1);
+        assert_eq!(socket.stats().packets_dropped.get(), 1);
+
+        pool.free(replay).unwrap();
+    }
+
+    #[test]
+    fn test_device_binding_precedence() {
+        use testing::{load, FrameBuilder};
+
+        let pool = MbufPool::new("rx".to_string(), 8, 2048).unwrap();
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let wildcard = stack.create_socket(local_addr).unwrap();
+        assert!(stack.create_socket(local_addr).is_err());
+
+        let bound = stack.create_socket_on_device(local_addr, 1).unwrap();
+        let other = stack.create_socket_on_device(local_addr, 2).unwrap();
+        // One socket per port and device
+        assert!(stack.create_socket_on_device(local_addr, 1).is_err());
+        let duplicate = stack.create_socket_on_device(local_addr, 3).unwrap();
+        assert!(stack.bind_to_device(duplicate, Some(1)).is_err());
+        assert!(stack.bind_to_device(duplicate, None).is_err());
+        stack.close_socket(duplicate).unwrap();
+
+        let received = |stack: &UdpStack, device: u16| {
+            let mbuf = load(&pool, &FrameBuilder::to_port(5000).build());
+            unsafe { (*mbuf).port_id = device };
+            assert!(stack.dispatch(mbuf).is_delivered());
+            let receiver = [wildcard, bound, other]
+                .into_iter()
+                .find(|&id| stack.get_socket(id).unwrap().recv().is_ok())
+                .unwrap();
+            pool.free(mbuf).unwrap();
+            receiver
+        };
+        assert_eq!(received(&stack, 1), bound);
+        assert_eq!(received(&stack, 2), other);
+        assert_eq!(received(&stack, 0), wildcard);
+
+        // Without a wildcard socket, other interfaces get nothing
+        stack.close_socket(wildcard).unwrap();
+        let mbuf = load(&pool, &FrameBuilder::to_port(5000).build());
+        assert_eq!(
+            stack.dispatch(mbuf),
+            Delivery::Dropped(DropReason::NoSocket)
+        );
+        pool.free(mbuf).unwrap();
+        assert_eq!(pool.stats().available, 8);
+    }
+
+    #[test]
+    fn test_early_drop_under_pool_pressure() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("early".to_string(), 20, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        stack.set_early_drop(Some(EarlyDropConfig {
+            threshold: 0.5,
+            min_held: 2,
+        }));
+        let slow = stack
+            .create_socket("0.0.0.0:5000".parse().unwrap())
+            .unwrap();
+        let fast = stack
+            .create_socket("0.0.0.0:5001".parse().unwrap())
+            .unwrap();
+
+        // The slow socket takes half the pool before the pressure starts
+        for _ in 0..10 {
+            let mbuf = load(&pool, &FrameBuilder::to_port(5000).build());
+            assert!(stack.dispatch(mbuf).is_delivered());
+        }
+        let slow_socket = stack.get_socket(slow).unwrap();
+        assert_eq!(slow_socket.held_buffers(), 10);
+        let mbuf = load(&pool, &FrameBuilder::to_port(5000).build());
+        assert_eq!(
+            stack.dispatch(mbuf),
+            Delivery::Dropped(DropReason::PoolPressure)
+        );
+        pool.free(mbuf).unwrap();
+        // The socket holding nothing is still served
+        let mbuf = load(&pool, &FrameBuilder::to_port(5001).build());
+        assert!(stack.dispatch(mbuf).is_delivered());
+
+        assert_eq!(slow_socket.stats().early_dropped.get(), 1);
+        assert_eq!(stack.early_drop_stats().pressured.get(), 2);
+        assert_eq!(stack.early_drop_stats().dropped.get(), 1);
+
+        let packet = slow_socket.recv().unwrap();
+        assert_eq!(slow_socket.held_buffers(), 10);
+        slow_socket.release(packet).unwrap();
+        assert_eq!(slow_socket.held_buffers(), 9);
+        let fast_socket = stack.get_socket(fast).unwrap();
+        fast_socket.release(fast_socket.recv().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_payload_transform() {
+        use testing::{load, FrameBuilder};
+
+        /// XOR cipher with a two-byte tag
+        struct Xor;
+        impl PayloadTransform for Xor {
+            fn overhead(&self) -> usize {
+                2
+            }
+            fn decrypt(&self, payload: &mut [u8]) -> Result<usize> {
+                let len = payload
+                    .len()
+                    .checked_sub(2)
+                    .filter(|&len| payload[len..] == [0xAB, 0xCD]);
+                let len = len.ok_or_else(|| Error::NetworkError("Bad tag".to_string()))?;
+                payload[..len].iter_mut().for_each(|byte| *byte ^= 0x5A);
+                Ok(len)
+            }
+            fn encrypt(&self, buffer: &mut [u8], len: usize) -> Result<usize> {
+                buffer[..len].iter_mut().for_each(|byte| *byte ^= 0x5A);
+                buffer[len..len + 2].copy_from_slice(&[0xAB, 0xCD]);
+                Ok(len + 2)
+            }
+        }
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let id = stack.create_socket(local_addr).unwrap();
+        let socket = stack.get_socket_mut(id).unwrap();
+        socket.set_transform(Some(Box::new(Xor)));
+        socket.bind_tx_pool(pool.clone());
+
+        // Sent payloads grow by the tag within the reserved tailroom
+        let mut buffer = socket.alloc_tx_buffer(4).unwrap();
+        buffer.payload_mut().copy_from_slice(b"ping");
+        socket.encrypt(&mut buffer).unwrap();
+        let ciphertext = buffer.payload().to_vec();
+        assert_eq!(ciphertext.len(), 6);
+        drop(buffer);
+
+        let mbuf = load(
+            &pool,
+            &FrameBuilder::to_port(5000).payload(&ciphertext).build(),
+        );
+        assert!(stack.dispatch(mbuf).is_delivered());
+        let socket = stack.get_socket(id).unwrap();
+        assert_eq!(socket.recv().unwrap().payload(), b"ping");
+        pool.free(mbuf).unwrap();
+
+        let mbuf = load(&pool, &FrameBuilder::to_port(5000).payload(b"ping").build());
+        assert_eq!(stack.dispatch(mbuf), Delivery::Dropped(DropReason::Decrypt));
+        pool.free(mbuf).unwrap();
+
+        let stats = socket.transform_stats();
+        assert_eq!(stats.encrypted.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.decrypted.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.decrypt_errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_payload_compression() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let id = stack.create_socket(local_addr).unwrap();
+        stack.set_compression(Some(Arc::new(PayloadCompressor::new(
+            CompressionConfig::default().with_ports(5000..=5000),
+        ))));
+        let socket = stack.get_socket_mut(id).unwrap();
+        socket.bind_tx_pool(pool.clone());
+
+        // Sent payloads shrink; received ones grow back into the mbuf tailroom
+        let text = [b"sensor=12 value=0.0 ".as_slice(); 40].concat();
+        let mut buffer = socket.alloc_tx_buffer(text.len()).unwrap();
+        buffer.payload_mut().copy_from_slice(&text);
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+        socket.compress(&mut buffer, peer).unwrap();
+        let compressed = buffer.payload().to_vec();
+        assert_eq!(compressed[0], TAG_LZ4);
+        assert!(compressed.len() < text.len() / 4);
+        drop(buffer);
+
+        let mbuf = load(
+            &pool,
+            &FrameBuilder::to_port(5000).payload(&compressed).build(),
+        );
+        assert!(stack.dispatch(mbuf).is_delivered());
+        let socket = stack.get_socket(id).unwrap();
+        assert_eq!(socket.recv().unwrap().payload(), &text[..]);
+        pool.free(mbuf).unwrap();
+
+        // Untagged payloads on a compressed port are dropped
+        let mbuf = load(&pool, &FrameBuilder::to_port(5000).payload(b"ping").build());
+        assert_eq!(
+            stack.dispatch(mbuf),
+            Delivery::Dropped(DropReason::Decompress)
+        );
+        pool.free(mbuf).unwrap();
+        let stats = stack.compression().unwrap().stats();
+        assert_eq!((stats.compressed.get(), stats.errors.get()), (1, 1));
+    }
+
+    #[test]
+    fn test_recv_copied_and_zero_copy_limit() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let socket_id = stack.create_socket(local_addr).unwrap();
+        stack.set_zero_copy_limit(Some(1));
+        stack.start().unwrap();
+
+        let socket = stack.get_socket(socket_id).unwrap();
+        let builder = FrameBuilder::to_port(5000).payload(b"copy me");
+        assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        assert!(socket.recv_copied().is_err());
+
+        stack.set_rx_pool(pool.clone());
+        let socket = stack.get_socket(socket_id).unwrap();
+        let (src_addr, payload) = socket.recv_copied().unwrap();
+        assert_eq!(src_addr, builder.src_addr());
+        assert_eq!(payload, b"copy me");
+        assert_eq!(pool.stats().available, 4);
+        socket.recycle_buffer(payload);
+
+        for _ in 0..2 {
+            assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        }
+        let held = socket.recv().unwrap();
+        assert!(matches!(socket.recv(), Err(Error::QueueError(_))));
+        assert_eq!(socket.outstanding_zero_copy(), 1);
+
+        socket.release(held).unwrap();
+        let (_, payload) = socket.recv_copied().unwrap();
+        assert_eq!(payload, b"copy me");
+        assert_eq!(socket.copy_buffer_stats().reused.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.stats().available, 4);
+    }
+
+    #[test]
+    fn test_send_completions_report_and_push_back() {
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 4, 2048).unwrap());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+        let mut socket = UdpSocket::new(local_addr, 16, 1).unwrap();
+        socket.bind_tx_pool(pool.clone());
+        assert!(socket.send_with_cookie(dst_addr, b"x", 7).is_err());
+        socket.set_completion_queue(Some(1));
+
+        // Without a transmit queue the send fails and says so
+        assert!(socket.send_with_cookie(dst_addr, b"x", 7).is_err());
+        assert!(matches!(
+            socket.send_with_cookie(dst_addr, b"y", 8),
+            Err(Error::QueueError(_))
+        ));
+        let completion = socket.poll_completion().unwrap();
+        assert_eq!(completion.cookie, 7);
+        assert!(matches!(completion.status, CompletionStatus::Failed(_)));
+        assert!(completion.timestamp > 0);
+        assert!(socket.poll_completion().is_none());
+        assert_eq!(socket.pending_completions(), 0);
+        assert_eq!(pool.stats().in_use, 0);
+
+        // A new queue starts with fresh counters
+        socket.set_completion_queue(Some(2));
+        socket.send_with_cookie(dst_addr, b"z", 9).unwrap_err();
+        let stats = socket.completion_stats().unwrap();
+        assert_eq!(stats.failed.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.backpressured.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_scheduled_sends_complete_at_departure() {
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 8, 2048).unwrap());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+        let tx_queue = Arc::new(TxQueue::in_memory(0));
+        let mut socket = UdpSocket::new(local_addr, 16, 1).unwrap();
+        socket.bind_tx_pool(pool.clone());
+        socket.bind_tx_queue(tx_queue.clone());
+        socket.set_completion_queue(Some(2));
+
+        let send = |cookie| {
+            let mut buffer = socket.alloc_tx_buffer(4).unwrap();
+            buffer.payload_mut().copy_from_slice(b"late");
+            buffer.completion = Some(socket.reserve_completion(cookie).unwrap());
+            let at = monotonic_now() + 1_000_000;
+            socket.send_prepared_at(buffer, dst_addr, at).unwrap();
+        };
+        send(1);
+        // Nothing is sent or counted before the departure
+        assert!(socket.poll_completion().is_none());
+        assert_eq!(socket.mib.snapshot().udp.out_datagrams, 0);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(tx_queue.flush_scheduled(), 1);
+        let completion = socket.poll_completion().unwrap();
+        assert_eq!(
+            (completion.cookie, completion.status),
+            (1, CompletionStatus::Sent)
+        );
+        assert_eq!(socket.mib.snapshot().udp.out_datagrams, 1);
+
+        // A frame the device refuses at departure fails its send
+        send(2);
+        tx_queue.set_failing(true);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(tx_queue.flush_scheduled(), 0);
+        let completion = socket.poll_completion().unwrap();
+        assert!(matches!(completion.status, CompletionStatus::Failed(_)));
+        assert_eq!(socket.mib.snapshot().udp.out_datagrams, 1);
+        assert_eq!(tx_queue.schedule_stats().dropped.get(), 1);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_copy_delivery_toggle_and_fallback() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("rx".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let socket_id = stack.create_socket(local_addr).unwrap();
+        stack.start().unwrap();
+        let builder = FrameBuilder::to_port(5000).payload(b"ring");
+
+        // Copy mode recycles every mbuf on arrival
+        stack
+            .set_delivery_mode(socket_id, DeliveryMode::Copy)
+            .unwrap();
+        for _ in 0..6 {
+            assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        }
+        assert_eq!(pool.stats().available, 4);
+        let socket = stack.get_socket(socket_id).unwrap();
+        assert!(socket.recv().is_err());
+        for _ in 0..6 {
+            let (src_addr, payload) = socket.recv_copied().unwrap();
+            assert_eq!(
+                (src_addr, payload.as_slice()),
+                (builder.src_addr(), &b"ring"[..])
+            );
+        }
+        assert_eq!(socket.copy_ring_used(), 0);
+
+        // Back to zero-copy, copying while two packets are held
+        socket.set_delivery_mode(DeliveryMode::ZeroCopy);
+        socket.set_copy_fallback(Some(2));
+        for _ in 0..2 {
+            assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        }
+        let held = [socket.recv().unwrap(), socket.recv().unwrap()];
+        assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        assert_eq!(pool.stats().available, 2);
+        assert_eq!(socket.recv_copied().unwrap().1, b"ring");
+        for packet in held {
+            socket.release(packet).unwrap();
+        }
+        assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        let freed = socket.recv().unwrap();
+
+        // Packets handed back through the pool or dropped end their borrow too
+        assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        let dropped = socket.recv().unwrap();
+        assert_eq!(socket.outstanding_zero_copy(), 2);
+        pool.free(freed.mbuf).unwrap();
+        drop(freed);
+        pool.free(dropped.mbuf).unwrap();
+        drop(dropped);
+        assert_eq!(socket.outstanding_zero_copy(), 0);
+        assert!(stack.dispatch(load(&pool, &builder.build())).is_delivered());
+        socket.release(socket.recv().unwrap()).unwrap();
+        assert_eq!(pool.stats().available, 4);
+
+        let stats = socket.delivery_stats();
+        assert_eq!(stats.copied.load(Ordering::Relaxed), 7);
+        assert_eq!(stats.fallbacks.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_randomized_source_ports() {
+        use testing::{load, FrameBuilder};
+
+        let pool = Arc::new(MbufPool::new("tx".to_string(), 4, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_tx_pool(pool.clone());
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 5000);
+        let socket_id = stack.create_socket(local_addr).unwrap();
+        stack.start().unwrap();
+        let dst_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6000);
+
+        let socket = stack.get_socket_mut(socket_id).unwrap();
+        assert_eq!(socket.source_port(dst_addr).unwrap(), 5000);
+        socket.set_source_port_randomization(true);
+        let socket = stack.get_socket(socket_id).unwrap();
+        let mut buffer = socket.alloc_tx_buffer(4).unwrap();
+        socket.write_headers(&mut buffer, dst_addr).unwrap();
+        let port = UdpPacket::from_mbuf(buffer.mbuf())
+            .unwrap()
+            .src_addr()
+            .port();
+        assert!(EPHEMERAL_PORTS.contains(&port));
+        assert_eq!(socket.source_port(dst_addr).unwrap(), port);
+
+        // Replies reach the socket and nobody else may bind the port
+        let reply = FrameBuilder::new(
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6000),
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port),
+        );
+        assert!(stack.dispatch(load(&pool, &reply.build())).is_delivered());
+        let bind = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+        assert!(stack.create_socket(bind).is_err());
+
+        let socket = stack.get_socket(socket_id).unwrap();
+        let cycled = socket.cycle_source_port(dst_addr).unwrap();
+        assert_ne!(cycled, port);
+        socket.write_headers(&mut buffer, dst_addr).unwrap();
+        assert_eq!(
+            UdpPacket::from_mbuf(buffer.mbuf())
+                .unwrap()
+                .src_addr()
+                .port(),
+            cycled
+        );
+        assert!(socket.pin_source_port(dst_addr, 5000).is_err());
+        socket.pin_source_port(dst_addr, 7000).unwrap();
+        assert_eq!(socket.source_port(dst_addr).unwrap(), 7000);
+
+        drop(buffer);
+        stack.close_socket(socket_id).unwrap();
+        assert_eq!(stack.ephemeral_ports().in_use(), 0);
+    }
+
+    #[test]
+    fn test_socket_queues_charge_memory_budget() {
+        let queue_bytes = 1024 * std::mem::size_of::<*mut Mbuf>();
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let budget = Arc::new(MemoryBudget::new(Some(queue_bytes)));
+        stack.set_memory_budget(budget.clone()).unwrap();
+
+        let first = stack
+            .create_socket("0.0.0.0:5000".parse().unwrap())
+            .unwrap();
+        assert!(matches!(
+            stack.create_socket("0.0.0.0:5001".parse().unwrap()),
+            Err(Error::BudgetExceeded(_))
+        ));
+        assert_eq!(budget.usage().queues, queue_bytes);
+
+        stack.close_socket(first).unwrap();
+        assert_eq!(budget.usage().queues, 0);
+        stack
+            .create_socket("0.0.0.0:5001".parse().unwrap())
+            .unwrap();
+
+        // The stack's tables are charged too, up to what is left
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let budget = Arc::new(MemoryBudget::new(Some(queue_bytes + EPHEMERAL_FLOW_BYTES)));
+        stack.set_memory_budget(budget.clone()).unwrap();
+        let id = stack
+            .create_socket("0.0.0.0:5000".parse().unwrap())
+            .unwrap();
+        let socket = stack.get_socket_mut(id).unwrap();
+        socket.set_source_port_randomization(true);
+        socket.source_port("10.0.0.2:53".parse().unwrap()).unwrap();
+        assert!(matches!(
+            socket.source_port("10.0.0.3:53".parse().unwrap()),
+            Err(Error::BudgetExceeded(_))
+        ));
+        assert!(stack
+            .pmtu_cache()
+            .seed(Ipv4Addr::new(10, 0, 0, 2), 1400)
+            .is_err());
+        let tables = budget.tables();
+        let ports = tables
+            .iter()
+            .find(|table| table.name == "ephemeral-ports")
+            .unwrap();
+        assert_eq!((ports.entries, ports.bytes), (1, EPHEMERAL_FLOW_BYTES));
+        stack.close_socket(id).unwrap();
+        assert_eq!(budget.usage().total(), 0);
+    }
+
+    #[test]
+    fn test_capture_trigger_sees_delivered_frames_and_drops() {
+        use crate::utils::capture::{CaptureConfig, TriggerRule};
+        use testing::{load, FrameBuilder};
+
+        let directory =
+            std::env::temp_dir().join(format!("xpdk-stack-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let pool = Arc::new(MbufPool::new("capture".to_string(), 8, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        let id = stack
+            .create_socket("0.0.0.0:5000".parse().unwrap())
+            .unwrap();
+
+        // No rules color the traffic, so a capture of color 5 keeps none of it
+        for (colors, written) in [(vec![], 1), (vec![5], 0)] {
+            let capture = Arc::new(Mutex::new(
+                CaptureTrigger::new(CaptureConfig {
+                    directory: directory.clone(),
+                    rules: vec![TriggerRule::DropReason(DropReason::NoSocket)],
+                    colors,
+                    ..CaptureConfig::default()
+                })
+                .unwrap(),
+            ));
+            stack.set_capture_trigger(Some(capture.clone()));
+            let mbuf = load(&pool, &FrameBuilder::to_port(5000).build());
+            assert!(stack.dispatch(mbuf).is_delivered());
+            let mbuf = load(&pool, &FrameBuilder::to_port(5001).build());
+            assert_eq!(
+                stack.dispatch(mbuf),
+                Delivery::Dropped(DropReason::NoSocket)
+            );
+            pool.free(mbuf).unwrap();
+
+            let mut capture = capture.lock();
+            assert!(capture.is_capturing());
+            capture.finish();
+            assert_eq!(
+                capture.stats().packets_written.load(Ordering::Relaxed),
+                written
+            );
+            let socket = stack.get_socket(id).unwrap();
+            socket.release(socket.recv().unwrap()).unwrap();
+        }
+        stack.set_capture_trigger(None);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -0,0 +1,556 @@
+//! Neighbor output queues
+//!
+//! A [`NeighborTable`] maps on-link next hops to MAC addresses for the
+//! sockets it is bound to with [`super::UdpStack::set_neighbor_table`]. A
+//! send towards a next hop without a known address is held in a small queue
+//! of its own while the address resolves, instead of going out to the
+//! socket's static MAC. Each queue keeps up to
+//! [`NeighborConfig::max_pending`] datagrams; further sends fail with
+//! [`Error::NeighborUnresolved`]. [`super::UdpStack::resolve_neighbor`]
+//! records an address, e.g. from an ARP reply, and sends the queue in order.
+//! Queues still unresolved after [`NeighborConfig::resolve_timeout`] are
+//! dropped by [`NeighborTable::expire`]. [`NeighborTable::incomplete`] lists
+//! the next hops a resolver has to solicit.
+//!
+//! [`super::UdpStack::run_neighbor_timers`], called from the poll loop,
+//! expires the table and sends an ARP request for each unresolved next hop
+//! every [`NeighborConfig::solicit_interval`], from the socket whose send
+//! waits for it. ARP frames the stack is given resolve the next hops the
+//! table waits for or knows. Addresses unused for
+//! [`NeighborConfig::reachable_time`] are forgotten, and the table holds at
+//! most [`NeighborConfig::max_entries`] next hops: sends towards a new one
+//! fail with [`Error::NeighborUnresolved`] while it is full. With
+//! [`NeighborTable::with_budget`], next hops are charged to the memory
+//! budget as the `neighbor` table, and those it cannot hold are refused the
+//! same way.
+
+use super::TxBuffer;
+use crate::memory::{MemoryBudget, TableAccount};
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Datagrams held per unresolved next hop by default
+pub const DEFAULT_MAX_PENDING: usize = 8;
+
+/// Time a next hop may take to resolve by default
+pub const DEFAULT_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Next hops a table holds by default
+pub const DEFAULT_MAX_NEIGHBORS: usize = 1024;
+
+/// Time an unused address is kept by default
+pub const DEFAULT_REACHABLE_TIME: Duration = Duration::from_secs(30);
+
+/// Time between ARP requests for an unresolved next hop by default
+pub const DEFAULT_SOLICIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Budgeted bytes of one next hop, without the sends it holds
+pub const NEIGHBOR_ENTRY_BYTES: usize =
+    std::mem::size_of::<Entry>() + std::mem::size_of::<Ipv4Addr>();
+
+/// Length of an Ethernet ARP frame for IPv4
+pub(crate) const ARP_FRAME_LEN: usize = 42;
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// Neighbor table settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeighborConfig {
+    /// Datagrams held per unresolved next hop
+    pub max_pending: usize,
+    /// Unresolved next hops are given up on after this long
+    pub resolve_timeout: Duration,
+    /// Next hops held, resolved or not
+    pub max_entries: usize,
+    /// Resolved addresses are forgotten after this long without a send
+    pub reachable_time: Duration,
+    /// Time between ARP requests for an unresolved next hop
+    pub solicit_interval: Duration,
+}
+
+impl Default for NeighborConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: DEFAULT_MAX_PENDING,
+            resolve_timeout: DEFAULT_RESOLVE_TIMEOUT,
+            max_entries: DEFAULT_MAX_NEIGHBORS,
+            reachable_time: DEFAULT_REACHABLE_TIME,
+            solicit_interval: DEFAULT_SOLICIT_INTERVAL,
+        }
+    }
+}
+
+/// Neighbor table statistics
+#[derive(Debug, Default)]
+pub struct NeighborStats {
+    /// Datagrams queued while their next hop resolved
+    pub queued: AtomicUsize,
+    /// Queued datagrams sent once resolved
+    pub flushed: AtomicUsize,
+    /// Datagrams refused because their queue was full
+    pub overflow_drops: AtomicUsize,
+    /// Queued datagrams dropped when resolution timed out
+    pub timeout_drops: AtomicUsize,
+    /// Next hops that failed to resolve in time
+    pub timeouts: AtomicUsize,
+    /// ARP requests sent for unresolved next hops
+    pub solicitations: AtomicUsize,
+    /// Sends refused because the table was full of other next hops
+    pub table_full_drops: AtomicUsize,
+    /// Resolved addresses forgotten after going unused
+    pub stale: AtomicUsize,
+    /// Next hops not added for lack of budget
+    pub budget_exceeded: AtomicUsize,
+}
+
+/// How a send towards a next hop proceeds
+pub(crate) enum NeighborOutput {
+    /// Address known, send the buffer to it now
+    Resolved([u8; 6], TxBuffer),
+    /// Held until the next hop resolves
+    Queued,
+}
+
+enum Entry {
+    Resolved {
+        mac: [u8; 6],
+        last_used: Instant,
+    },
+    Incomplete {
+        since: Instant,
+        /// Last ARP request sent
+        solicited: Option<Instant>,
+        pending: VecDeque<(TxBuffer, SocketAddr)>,
+    },
+}
+
+/// Next hop addresses and the sends waiting for them
+pub struct NeighborTable {
+    config: NeighborConfig,
+    entries: Mutex<HashMap<Ipv4Addr, Entry>>,
+    account: Option<Arc<TableAccount>>,
+    stats: NeighborStats,
+}
+
+// Queued buffers are owned by the table and only touched under its lock
+unsafe impl Send for NeighborTable {}
+unsafe impl Sync for NeighborTable {}
+
+impl NeighborTable {
+    /// Create an empty table
+    pub fn new(config: NeighborConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            account: None,
+            stats: NeighborStats::default(),
+        }
+    }
+
+    /// Charge each next hop [`NEIGHBOR_ENTRY_BYTES`] of `budget`, as the `neighbor` table
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.account = Some(budget.register_table("neighbor"));
+        self
+    }
+
+    /// MAC address of a resolved next hop
+    pub fn lookup(&self, next_hop: Ipv4Addr) -> Option<[u8; 6]> {
+        match self.entries.lock().get(&next_hop) {
+            Some(Entry::Resolved { mac, .. }) => Some(*mac),
+            _ => None,
+        }
+    }
+
+    /// Next hops with sends waiting for an address
+    pub fn incomplete(&self) -> Vec<Ipv4Addr> {
+        self.entries
+            .lock()
+            .iter()
+            .filter(|(_, entry)| matches!(entry, Entry::Incomplete { .. }))
+            .map(|(&next_hop, _)| next_hop)
+            .collect()
+    }
+
+    /// Datagrams waiting for `next_hop`
+    pub fn pending(&self, next_hop: Ipv4Addr) -> usize {
+        match self.entries.lock().get(&next_hop) {
+            Some(Entry::Incomplete { pending, .. }) => pending.len(),
+            _ => 0,
+        }
+    }
+
+    /// Forget a next hop, dropping the sends waiting for it
+    pub fn remove(&self, next_hop: Ipv4Addr) -> bool {
+        let removed = self.entries.lock().remove(&next_hop).is_some();
+        if removed {
+            self.release_entries(1);
+        }
+        removed
+    }
+
+    /// Number of next hops held, resolved or not
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Give up on next hops unresolved past the timeout and forget unused
+    /// addresses, returning the datagrams dropped
+    ///
+    /// Dropped sends made with a cookie complete as
+    /// [`super::CompletionStatus::Dropped`].
+    pub fn expire(&self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&self, now: Instant) -> usize {
+        let timeout = self.config.resolve_timeout;
+        let reachable_time = self.config.reachable_time;
+        let mut dropped = 0;
+        let mut timeouts = 0;
+        let mut stale = 0;
+        self.entries.lock().retain(|_, entry| match entry {
+            Entry::Incomplete { since, pending, .. } if now.duration_since(*since) >= timeout => {
+                dropped += pending.len();
+                timeouts += 1;
+                false
+            }
+            Entry::Resolved { last_used, .. }
+                if now.duration_since(*last_used) >= reachable_time =>
+            {
+                stale += 1;
+                false
+            }
+            _ => true,
+        });
+        self.stats
+            .timeout_drops
+            .fetch_add(dropped, Ordering::Relaxed);
+        self.stats.timeouts.fetch_add(timeouts, Ordering::Relaxed);
+        self.stats.stale.fetch_add(stale, Ordering::Relaxed);
+        self.release_entries(timeouts + stale);
+        dropped
+    }
+
+    /// Make room for a next hop the table does not hold yet
+    fn admit(&self, entries: &HashMap<Ipv4Addr, Entry>, next_hop: Ipv4Addr) -> Result<()> {
+        if entries.len() >= self.config.max_entries {
+            self.stats.table_full_drops.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::NeighborUnresolved(format!(
+                "Neighbor table full with {} next hops, {} not added",
+                self.config.max_entries, next_hop
+            )));
+        }
+        if let Some(account) = &self.account {
+            if let Err(e) = account.grow(1, NEIGHBOR_ENTRY_BYTES) {
+                self.stats.budget_exceeded.fetch_add(1, Ordering::Relaxed);
+                return Err(Error::NeighborUnresolved(format!(
+                    "{} not added: {}",
+                    next_hop, e
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn release_entries(&self, count: usize) {
+        if let Some(account) = &self.account {
+            account.shrink(count, count * NEIGHBOR_ENTRY_BYTES);
+        }
+    }
+
+    /// Next hops due an ARP request, with the socket whose send waits longest
+    pub(crate) fn due_solicitations(&self) -> Vec<(Ipv4Addr, u16)> {
+        self.due_solicitations_at(Instant::now())
+    }
+
+    fn due_solicitations_at(&self, now: Instant) -> Vec<(Ipv4Addr, u16)> {
+        let interval = self.config.solicit_interval;
+        let mut entries = self.entries.lock();
+        entries
+            .iter_mut()
+            .filter_map(|(&next_hop, entry)| match entry {
+                Entry::Incomplete {
+                    solicited, pending, ..
+                } if solicited.is_none_or(|at| now.duration_since(at) >= interval) => {
+                    let (buffer, _) = pending.front()?;
+                    *solicited = Some(now);
+                    Some((next_hop, buffer.socket_id()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Get neighbor statistics
+    pub fn stats(&self) -> &NeighborStats {
+        &self.stats
+    }
+
+    /// Send `buffer` to `next_hop` now if it is resolved, queue it otherwise
+    pub(crate) fn output(
+        &self,
+        next_hop: Ipv4Addr,
+        buffer: TxBuffer,
+        dst_addr: SocketAddr,
+    ) -> Result<NeighborOutput> {
+        self.output_at(next_hop, buffer, dst_addr, Instant::now())
+    }
+
+    fn output_at(
+        &self,
+        next_hop: Ipv4Addr,
+        buffer: TxBuffer,
+        dst_addr: SocketAddr,
+        now: Instant,
+    ) -> Result<NeighborOutput> {
+        let mut entries = self.entries.lock();
+        if !entries.contains_key(&next_hop) {
+            self.admit(&entries, next_hop)?;
+        }
+        let entry = entries
+            .entry(next_hop)
+            .or_insert_with(|| Entry::Incomplete {
+                since: now,
+                solicited: None,
+                pending: VecDeque::new(),
+            });
+        match entry {
+            Entry::Resolved { mac, last_used } => {
+                *last_used = now;
+                Ok(NeighborOutput::Resolved(*mac, buffer))
+            }
+            Entry::Incomplete { pending, .. } if pending.len() < self.config.max_pending => {
+                pending.push_back((buffer, dst_addr));
+                self.stats.queued.fetch_add(1, Ordering::Relaxed);
+                Ok(NeighborOutput::Queued)
+            }
+            Entry::Incomplete { .. } => {
+                self.stats.overflow_drops.fetch_add(1, Ordering::Relaxed);
+                Err(Error::NeighborUnresolved(format!(
+                    "{} has {} sends waiting for its address",
+                    next_hop, self.config.max_pending
+                )))
+            }
+        }
+    }
+
+    /// Record the address of `next_hop`, returning the sends that waited for it
+    ///
+    /// With `learn_only`, next hops the table does not hold are ignored;
+    /// otherwise they are added unless the table is full.
+    pub(crate) fn resolve(
+        &self,
+        next_hop: Ipv4Addr,
+        mac: [u8; 6],
+        learn_only: bool,
+    ) -> Result<Vec<(TxBuffer, SocketAddr)>> {
+        let mut entries = self.entries.lock();
+        if !entries.contains_key(&next_hop) {
+            if learn_only {
+                return Ok(Vec::new());
+            }
+            self.admit(&entries, next_hop)?;
+        }
+        let entry = Entry::Resolved {
+            mac,
+            last_used: Instant::now(),
+        };
+        Ok(match entries.insert(next_hop, entry) {
+            Some(Entry::Incomplete { pending, .. }) => pending.into(),
+            _ => Vec::new(),
+        })
+    }
+}
+
+/// ARP request from `sender` at `src_mac` for the address of `target`
+pub(crate) fn arp_request(
+    src_mac: [u8; 6],
+    sender: Ipv4Addr,
+    target: Ipv4Addr,
+) -> [u8; ARP_FRAME_LEN] {
+    let mut frame = [0; ARP_FRAME_LEN];
+    frame[..6].fill(0xFF);
+    frame[6..12].copy_from_slice(&src_mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    // Ethernet hardware, IPv4 protocol, 6-byte and 4-byte addresses
+    frame[14..20].copy_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4]);
+    frame[20..22].copy_from_slice(&ARP_REQUEST.to_be_bytes());
+    frame[22..28].copy_from_slice(&src_mac);
+    frame[28..32].copy_from_slice(&sender.octets());
+    frame[38..42].copy_from_slice(&target.octets());
+    frame
+}
+
+/// Sender address and MAC of an Ethernet ARP request or reply for IPv4
+pub(crate) fn parse_arp(frame: &[u8]) -> Option<(Ipv4Addr, [u8; 6])> {
+    let frame = frame.get(..ARP_FRAME_LEN)?;
+    if frame[12..14] != ETHERTYPE_ARP.to_be_bytes() || frame[14..20] != [0, 1, 8, 0, 6, 4] {
+        return None;
+    }
+    let operation = u16::from_be_bytes([frame[20], frame[21]]);
+    if operation != ARP_REQUEST && operation != ARP_REPLY {
+        return None;
+    }
+    let sender = Ipv4Addr::new(frame[28], frame[29], frame[30], frame[31]);
+    if sender.is_unspecified() {
+        return None;
+    }
+    Some((sender, frame[22..28].try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::poll::TxQueue;
+    use crate::udp::testing::load;
+    use crate::udp::{CompletionStatus, UdpStack};
+    use crate::Config;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_queue_until_resolved_or_timeout() {
+        let pool = Arc::new(MbufPool::new("neighbor".to_string(), 8, 256).unwrap());
+        let buffer = || TxBuffer {
+            mbuf: pool.alloc().unwrap(),
+            pool: pool.clone(),
+            socket_id: 1,
+            payload_len: 0,
+            completion: None,
+            dscp: None,
+        };
+        let table = NeighborTable::new(NeighborConfig {
+            max_pending: 2,
+            resolve_timeout: Duration::from_secs(1),
+            ..NeighborConfig::default()
+        });
+        let (gateway, peer) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let dst: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            let output = table.output_at(gateway, buffer(), dst, now).unwrap();
+            assert!(matches!(output, NeighborOutput::Queued));
+        }
+        assert!(matches!(
+            table.output_at(gateway, buffer(), dst, now),
+            Err(Error::NeighborUnresolved(_))
+        ));
+        table.output_at(peer, buffer(), dst, now).unwrap();
+        let mut incomplete = table.incomplete();
+        incomplete.sort();
+        assert_eq!(incomplete, vec![gateway, peer]);
+
+        // Resolution hands back the queue in order
+        let flushed = table.resolve(gateway, [2; 6], false).unwrap();
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(table.lookup(gateway), Some([2; 6]));
+        drop(flushed);
+        let output = table.output_at(gateway, buffer(), dst, now).unwrap();
+        assert!(matches!(
+            output,
+            NeighborOutput::Resolved([2, 2, 2, 2, 2, 2], _)
+        ));
+        drop(output);
+
+        assert_eq!(table.expire_at(now + Duration::from_millis(500)), 0);
+        assert_eq!(table.expire_at(now + Duration::from_secs(1)), 1);
+        assert!(table.incomplete().is_empty());
+        assert_eq!(table.stats().overflow_drops.load(Ordering::Relaxed), 1);
+        assert_eq!(table.stats().timeouts.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_stack_solicits_learns_and_caps() {
+        let pool = Arc::new(MbufPool::new("arp".to_string(), 8, 256).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_tx_pool(pool.clone());
+        stack.set_rx_pool(pool.clone());
+        let table = Arc::new(NeighborTable::new(NeighborConfig {
+            max_entries: 1,
+            ..NeighborConfig::default()
+        }));
+        stack.set_neighbor_table(Some(table.clone()));
+        let socket_id = stack
+            .create_socket("10.0.0.1:5000".parse().unwrap())
+            .unwrap();
+        let tx_queue = Arc::new(TxQueue::in_memory(0));
+        let socket = stack.get_socket_mut(socket_id).unwrap();
+        socket.bind_tx_queue(tx_queue.clone());
+        socket.set_completion_queue(Some(2));
+        let (peer, other) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+
+        let socket = stack.get_socket(socket_id).unwrap();
+        socket
+            .send_with_cookie("10.0.0.2:6000".parse().unwrap(), b"held", 1)
+            .unwrap();
+        assert_eq!(stack.run_neighbor_timers(), 1);
+        assert_eq!(stack.run_neighbor_timers(), 0);
+        let request = &tx_queue.sent_frames()[0];
+        assert_eq!(request[..6], [0xFF; 6]);
+        assert_eq!(
+            parse_arp(request),
+            Some((Ipv4Addr::new(10, 0, 0, 1), [0; 6]))
+        );
+        assert_eq!(request[38..42], peer.octets());
+
+        // The reply sends the held datagram; unsolicited senders are not added
+        let arp = |sender: Ipv4Addr, mac| {
+            let mut reply = arp_request(mac, sender, Ipv4Addr::new(10, 0, 0, 1));
+            reply[20..22].copy_from_slice(&ARP_REPLY.to_be_bytes());
+            let mbuf = load(&pool, &reply);
+            assert!(!stack.dispatch(mbuf).is_delivered());
+            pool.free(mbuf).unwrap();
+        };
+        arp(Ipv4Addr::new(10, 0, 0, 9), [9; 6]);
+        arp(peer, [6; 6]);
+        let frames = tx_queue.sent_frames();
+        assert_eq!((frames.len(), frames[1][..6].to_vec()), (2, vec![6; 6]));
+        let completion = socket.poll_completion().unwrap();
+        assert_eq!(
+            (completion.cookie, completion.status),
+            (1, CompletionStatus::Sent)
+        );
+        assert_eq!((table.len(), table.lookup(peer)), (1, Some([6; 6])));
+
+        assert!(matches!(
+            socket.send("10.0.0.3:6000".parse().unwrap(), b"full"),
+            Err(Error::NeighborUnresolved(_))
+        ));
+        assert_eq!(table.stats().table_full_drops.load(Ordering::Relaxed), 1);
+        assert!(stack.resolve_neighbor(other, [3; 6]).is_err());
+
+        // Sends given up on hand back their mbufs and completion slots
+        let table = Arc::new(NeighborTable::new(NeighborConfig {
+            resolve_timeout: Duration::ZERO,
+            ..NeighborConfig::default()
+        }));
+        stack.set_neighbor_table(Some(table.clone()));
+        let socket = stack.get_socket(socket_id).unwrap();
+        socket
+            .send_with_cookie("10.0.0.3:6000".parse().unwrap(), b"lost", 2)
+            .unwrap();
+        assert_eq!(socket.pending_completions(), 1);
+        assert_eq!(stack.run_neighbor_timers(), 0);
+        let completion = socket.poll_completion().unwrap();
+        assert_eq!(
+            (completion.cookie, completion.status),
+            (2, CompletionStatus::Dropped)
+        );
+        assert_eq!(socket.pending_completions(), 0);
+        assert_eq!(table.stats().timeout_drops.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
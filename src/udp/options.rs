@@ -0,0 +1,152 @@
+//! IPv4 header options
+//!
+//! Headers with an IHL above 5 carry options between the fixed 20-byte
+//! header and the L4 header. [`super::classify`] only accepts IPv4 headers
+//! whose options are well formed, so later stages skip them by using the
+//! recorded L4 offset. The router alert and timestamp options are decoded
+//! for the application; others are only walked over.
+
+use crate::{Error, Result};
+
+/// End of option list
+pub const IPOPT_EOL: u8 = 0;
+
+/// No operation, used for padding
+pub const IPOPT_NOP: u8 = 1;
+
+/// Internet timestamp (RFC 791)
+pub const IPOPT_TIMESTAMP: u8 = 68;
+
+/// Router alert (RFC 2113)
+pub const IPOPT_ROUTER_ALERT: u8 = 148;
+
+/// Timestamp option flag for timestamps without addresses
+const TIMESTAMP_ONLY: u8 = 0;
+
+/// Decoded IPv4 options of a packet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ipv4Options {
+    /// Router alert value, if the option is present
+    pub router_alert: Option<u16>,
+    /// Timestamps recorded by the timestamp option, in hop order
+    pub timestamps: Vec<u32>,
+    /// Types of the other options present, in header order
+    pub other: Vec<u8>,
+}
+
+impl Ipv4Options {
+    /// Parse the options area of an IPv4 header, the bytes after its first 20
+    pub fn parse(options: &[u8]) -> Result<Self> {
+        let mut parsed = Self::default();
+        walk(options, |kind, body| {
+            match kind {
+                IPOPT_ROUTER_ALERT => {
+                    let value: [u8; 2] = body
+                        .try_into()
+                        .map_err(|_| malformed("router alert length"))?;
+                    parsed.router_alert = Some(u16::from_be_bytes(value));
+                }
+                IPOPT_TIMESTAMP => parsed.timestamps = timestamps(body)?,
+                _ => parsed.other.push(kind),
+            }
+            Ok(())
+        })?;
+        Ok(parsed)
+    }
+}
+
+/// Check that an options area is well formed without decoding it
+pub(crate) fn validate(options: &[u8]) -> bool {
+    walk(options, |kind, body| match kind {
+        IPOPT_ROUTER_ALERT if body.len() != 2 => Err(malformed("router alert length")),
+        IPOPT_TIMESTAMP => timestamps(body).map(|_| ()),
+        _ => Ok(()),
+    })
+    .is_ok()
+}
+
+/// Call `visit` with the type and body of each option up to the end of list
+fn walk<F>(options: &[u8], mut visit: F) -> Result<()>
+where
+    F: FnMut(u8, &[u8]) -> Result<()>,
+{
+    let mut offset = 0;
+    while let Some(&kind) = options.get(offset) {
+        match kind {
+            IPOPT_EOL => break,
+            IPOPT_NOP => offset += 1,
+            _ => {
+                let len = *options
+                    .get(offset + 1)
+                    .ok_or_else(|| malformed("option length missing"))?
+                    as usize;
+                if len < 2 || offset + len > options.len() {
+                    return Err(malformed("option length out of range"));
+                }
+                visit(kind, &options[offset + 2..offset + len])?;
+                offset += len;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Timestamps filled in so far, from a timestamp option body
+fn timestamps(body: &[u8]) -> Result<Vec<u32>> {
+    let [pointer, flags, entries @ ..] = body else {
+        return Err(malformed("timestamp option too short"));
+    };
+    // The pointer is 1-based from the option type and starts past the flags
+    let recorded = (*pointer as usize)
+        .checked_sub(5)
+        .ok_or_else(|| malformed("timestamp pointer"))?
+        .min(entries.len());
+    let entry_len = if flags & 0x0F == TIMESTAMP_ONLY { 4 } else { 8 };
+
+    Ok(entries[..recorded]
+        .chunks_exact(entry_len)
+        .map(|entry| {
+            let ts = &entry[entry_len - 4..];
+            u32::from_be_bytes([ts[0], ts[1], ts[2], ts[3]])
+        })
+        .collect())
+}
+
+fn malformed(what: &str) -> Error {
+    Error::NetworkError(format!("Malformed IPv4 options: {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        let router_alert = [IPOPT_ROUTER_ALERT, 4, 0, 0];
+        // Two of two timestamp slots used
+        let timestamp = [IPOPT_TIMESTAMP, 12, 13, 0, 0, 0, 0, 7, 0, 0, 0, 9];
+        let record_route = [7, 3, 4];
+        // Bytes after the end of list are ignored
+        let options = [
+            &router_alert[..],
+            &[IPOPT_NOP],
+            &timestamp,
+            &[IPOPT_NOP, IPOPT_NOP],
+            &record_route,
+            &[IPOPT_EOL, 0xFF],
+        ]
+        .concat();
+        let parsed = Ipv4Options::parse(&options).unwrap();
+        assert_eq!(parsed.router_alert, Some(0));
+        assert_eq!(parsed.timestamps, vec![7, 9]);
+        assert_eq!(parsed.other, vec![7]);
+        assert!(validate(&options));
+
+        // Lengths running past the header, below 2, or missing
+        assert!(!validate(&[IPOPT_TIMESTAMP, 12, 5, 0]));
+        assert!(!validate(&[7, 1, 0, 0]));
+        assert!(!validate(&[IPOPT_NOP, 7]));
+        assert!(!validate(&[IPOPT_ROUTER_ALERT, 3, 0, IPOPT_EOL]));
+        assert!(validate(&[IPOPT_NOP; 4]));
+    }
+}
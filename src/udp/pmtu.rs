@@ -0,0 +1,396 @@
+//! Path MTU cache and send-size decisions
+//!
+//! [`PmtuCache`] holds the path MTU towards each IPv4 destination. Entries
+//! are seeded by hand or learned from ICMP "fragmentation needed" messages
+//! (RFC 1191); learned entries expire so that a path that grew again is
+//! picked up. Destinations without an entry use the link MTU from
+//! [`crate::Config::mtu`].
+//!
+//! An ICMP message is only believed if the datagram it quotes is one of
+//! ours (RFC 5927): UDP, from a local address and port with a socket
+//! behind it, so that a spoofed message cannot shrink the path to an
+//! arbitrary destination. At most [`PMTU_CACHE_CAPACITY`] destinations are
+//! held, unless set otherwise with [`PmtuCache::with_capacity`]; with the
+//! cache full of live entries, new destinations are not learned. The
+//! stack charges its cache to the memory budget as the `pmtu` table, see
+//! [`PmtuCache::set_budget`], and destinations the budget cannot hold are
+//! not learned either.
+//!
+//! On send, a datagram that fits the path MTU goes out as one frame. A
+//! larger one is rejected when the socket sets DF, and split into IPv4
+//! fragments otherwise.
+
+use super::{Ipv4Header, UdpHeader, ICMP_DEST_UNREACHABLE, IPPROTO_UDP};
+use crate::memory::{MemoryBudget, TableAccount};
+use crate::{Error, Result};
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Smallest MTU every IPv4 link must support
+pub const MIN_IPV4_MTU: u16 = 68;
+
+/// Lifetime of a learned path MTU
+pub const PMTU_EXPIRY: Duration = Duration::from_secs(600);
+
+/// Destinations a path MTU cache holds by default
+pub const PMTU_CACHE_CAPACITY: usize = 4096;
+
+/// Budgeted bytes of one destination
+pub const PMTU_ENTRY_BYTES: usize = size_of::<(Ipv4Addr, PmtuEntry)>();
+
+/// ICMP destination unreachable code for "fragmentation needed and DF set"
+const ICMP_FRAG_NEEDED: u8 = 4;
+
+/// Common MTU plateaus of RFC 1191, for routers that do not report one
+const MTU_PLATEAUS: [u16; 9] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296];
+
+/// How a datagram of a given size is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SendPlan {
+    /// One frame
+    Single,
+    /// IPv4 fragments carrying this many bytes of IP payload each
+    Fragments(usize),
+}
+
+/// Decide how to send `ip_payload_len` bytes of IP payload over `mtu`
+pub(crate) fn plan(ip_payload_len: usize, mtu: u16, dont_fragment: bool) -> Result<SendPlan> {
+    let ip_header_len = size_of::<Ipv4Header>();
+    if ip_header_len + ip_payload_len <= mtu as usize {
+        return Ok(SendPlan::Single);
+    }
+    if dont_fragment {
+        return Err(Error::NetworkError(format!(
+            "Datagram of {} bytes exceeds path MTU {} with DF set",
+            ip_header_len + ip_payload_len,
+            mtu
+        )));
+    }
+
+    // Fragment offsets are in 8-byte units
+    Ok(SendPlan::Fragments((mtu as usize - ip_header_len) & !7))
+}
+
+/// Path MTU of one destination
+#[derive(Debug, Clone, Copy)]
+struct PmtuEntry {
+    mtu: u16,
+    /// Expiry of a learned entry; seeded entries never expire
+    expires: Option<Instant>,
+}
+
+impl PmtuEntry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+/// Per-destination path MTU cache
+#[derive(Debug)]
+pub struct PmtuCache {
+    link_mtu: u16,
+    capacity: usize,
+    entries: RwLock<HashMap<Ipv4Addr, PmtuEntry>>,
+    /// Taken under the entries lock
+    account: Mutex<Option<Arc<TableAccount>>>,
+}
+
+impl PmtuCache {
+    /// Create a cache for a link of `link_mtu` bytes
+    pub fn new(link_mtu: u16) -> Self {
+        Self::with_capacity(link_mtu, PMTU_CACHE_CAPACITY)
+    }
+
+    /// Create a cache holding at most `capacity` destinations
+    pub fn with_capacity(link_mtu: u16, capacity: usize) -> Self {
+        Self {
+            link_mtu: link_mtu.max(MIN_IPV4_MTU),
+            capacity,
+            entries: RwLock::new(HashMap::new()),
+            account: Mutex::new(None),
+        }
+    }
+
+    /// Charge each destination [`PMTU_ENTRY_BYTES`] of `budget`, as the `pmtu` table
+    ///
+    /// The entries already held move to the new budget; fails with
+    /// [`Error::BudgetExceeded`] and keeps the old one if it cannot hold them.
+    pub fn set_budget(&self, budget: &Arc<MemoryBudget>) -> Result<()> {
+        let entries = self.entries.write();
+        let account = budget.register_table("pmtu");
+        account.grow(entries.len(), entries.len() * PMTU_ENTRY_BYTES)?;
+        *self.account.lock() = Some(account);
+        Ok(())
+    }
+
+    fn grow(&self) -> Result<()> {
+        match &*self.account.lock() {
+            Some(account) => account.grow(1, PMTU_ENTRY_BYTES),
+            None => Ok(()),
+        }
+    }
+
+    fn shrink(&self, entries: usize) {
+        if let Some(account) = &*self.account.lock() {
+            account.shrink(entries, entries * PMTU_ENTRY_BYTES);
+        }
+    }
+
+    /// MTU of the local link
+    pub fn link_mtu(&self) -> u16 {
+        self.link_mtu
+    }
+
+    /// Current path MTU towards `dst`
+    pub fn pmtu(&self, dst: Ipv4Addr) -> u16 {
+        match self.entries.read().get(&dst) {
+            Some(entry) if entry.is_live(Instant::now()) => entry.mtu,
+            _ => self.link_mtu,
+        }
+    }
+
+    /// Destinations with an entry, live or expired
+    pub fn len(&self) -> usize {
+        self.entries.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().is_empty()
+    }
+
+    /// Fix the path MTU towards `dst`, clamped to the link MTU
+    ///
+    /// Seeded entries are kept even with the cache full. Fails with
+    /// [`Error::BudgetExceeded`] if the budget cannot hold a new destination.
+    pub fn seed(&self, dst: Ipv4Addr, mtu: u16) -> Result<()> {
+        let mtu = mtu.clamp(MIN_IPV4_MTU, self.link_mtu);
+        let mut entries = self.entries.write();
+        if !entries.contains_key(&dst) {
+            self.grow()?;
+        }
+        entries.insert(dst, PmtuEntry { mtu, expires: None });
+        Ok(())
+    }
+
+    /// Record a path MTU reported by the network; only decreases are taken
+    ///
+    /// Returns whether the cached value changed.
+    pub fn learn(&self, dst: Ipv4Addr, mtu: u16) -> bool {
+        if mtu.max(MIN_IPV4_MTU) >= self.pmtu(dst) {
+            return false;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.write();
+        if !entries.contains_key(&dst) {
+            if entries.len() >= self.capacity {
+                // Make room from expired entries, if there are any
+                let before = entries.len();
+                entries.retain(|_, entry| entry.is_live(now));
+                self.shrink(before - entries.len());
+                if entries.len() >= self.capacity {
+                    return false;
+                }
+            }
+            if self.grow().is_err() {
+                return false;
+            }
+        }
+        let mtu = mtu.clamp(MIN_IPV4_MTU, self.link_mtu);
+        let expires = Some(now + PMTU_EXPIRY);
+        entries.insert(dst, PmtuEntry { mtu, expires });
+        true
+    }
+
+    /// Learn from an ICMP message, given from its type byte on
+    ///
+    /// `is_local_flow` is shown the source and destination of the quoted
+    /// datagram and says whether a local socket sent it; messages about
+    /// other datagrams, or quoting too little to tell, are ignored.
+    /// Returns the destination and new path MTU if the message was a
+    /// "fragmentation needed" that lowered it.
+    pub fn handle_icmp(
+        &self,
+        icmp: &[u8],
+        is_local_flow: impl FnOnce(SocketAddrV4, SocketAddrV4) -> bool,
+    ) -> Option<(Ipv4Addr, u16)> {
+        if icmp.len() < 8 + size_of::<Ipv4Header>()
+            || icmp[0] != ICMP_DEST_UNREACHABLE
+            || icmp[1] != ICMP_FRAG_NEEDED
+        {
+            return None;
+        }
+
+        // The quoted header of the datagram that did not fit, and the
+        // first 8 bytes of its payload, which every router must include
+        let quoted = &icmp[8..];
+        let ihl = (quoted[0] & 0x0F) as usize * 4;
+        let udp = quoted.get(ihl..ihl + size_of::<UdpHeader>())?;
+        if ihl < size_of::<Ipv4Header>() || quoted[9] != IPPROTO_UDP {
+            return None;
+        }
+        let src = Ipv4Addr::new(quoted[12], quoted[13], quoted[14], quoted[15]);
+        let dst = Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]);
+        let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+        let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+        if !is_local_flow(
+            SocketAddrV4::new(src, src_port),
+            SocketAddrV4::new(dst, dst_port),
+        ) {
+            return None;
+        }
+
+        let mtu = match u16::from_be_bytes([icmp[6], icmp[7]]) {
+            0 => {
+                let sent = u16::from_be_bytes([quoted[2], quoted[3]]);
+                MTU_PLATEAUS
+                    .into_iter()
+                    .find(|&plateau| plateau < sent)
+                    .unwrap_or(MIN_IPV4_MTU)
+            }
+            mtu => mtu,
+        };
+
+        self.learn(dst, mtu).then(|| (dst, self.pmtu(dst)))
+    }
+
+    /// Forget every seeded and learned entry
+    pub fn flush(&self) {
+        let mut entries = self.entries.write();
+        self.shrink(entries.len());
+        entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::poll::TxQueue;
+    use crate::udp::testing::load;
+    use crate::udp::{EthernetHeader, UdpStack, ETHERTYPE_IPV4, IPPROTO_ICMP};
+    use crate::Config;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000);
+
+    fn frag_needed(dst: Ipv4Addr, next_hop_mtu: u16, sent_len: u16) -> Vec<u8> {
+        quoting(LOCAL, SocketAddrV4::new(dst, 6000), next_hop_mtu, sent_len)
+    }
+
+    fn quoting(src: SocketAddrV4, dst: SocketAddrV4, next_hop_mtu: u16, sent_len: u16) -> Vec<u8> {
+        let mut icmp = vec![ICMP_DEST_UNREACHABLE, ICMP_FRAG_NEEDED, 0, 0, 0, 0];
+        icmp.extend_from_slice(&next_hop_mtu.to_be_bytes());
+        let mut quoted = Ipv4Header::new(*src.ip(), *dst.ip(), 0);
+        quoted.total_length = sent_len.to_be();
+        icmp.extend_from_slice(&quoted.to_bytes());
+        icmp.extend_from_slice(&src.port().to_be_bytes());
+        icmp.extend_from_slice(&dst.port().to_be_bytes());
+        icmp.extend_from_slice(&[0; 4]);
+        icmp
+    }
+
+    #[test]
+    fn test_seed_and_learn_from_icmp() {
+        let cache = PmtuCache::new(1500);
+        let far = Ipv4Addr::new(192, 0, 2, 1);
+        let seeded = Ipv4Addr::new(192, 0, 2, 2);
+        let ours = |src: SocketAddrV4, _| src == LOCAL;
+        assert_eq!(cache.pmtu(far), 1500);
+
+        cache.seed(seeded, 9000).unwrap();
+        assert_eq!(cache.pmtu(seeded), 1500);
+        cache.seed(seeded, 1400).unwrap();
+        assert_eq!(cache.pmtu(seeded), 1400);
+
+        assert_eq!(
+            cache.handle_icmp(&frag_needed(far, 1280, 1500), ours),
+            Some((far, 1280))
+        );
+        // Increases are ignored
+        assert_eq!(cache.handle_icmp(&frag_needed(far, 1400, 1500), ours), None);
+        // No next-hop MTU reported: next plateau below the datagram size
+        assert_eq!(
+            cache.handle_icmp(&frag_needed(far, 0, 1280), ours),
+            Some((far, 1006))
+        );
+        assert_eq!(cache.handle_icmp(&[3, 3, 0, 0], ours), None);
+
+        // Datagrams we did not send, or quoted without their UDP header
+        let spoofed = SocketAddrV4::new(*LOCAL.ip(), 5001);
+        let dst = SocketAddrV4::new(seeded, 6000);
+        assert_eq!(
+            cache.handle_icmp(&quoting(spoofed, dst, 576, 1400), ours),
+            None
+        );
+        let short = frag_needed(seeded, 576, 1400);
+        assert_eq!(cache.handle_icmp(&short[..short.len() - 1], ours), None);
+        assert_eq!(cache.pmtu(seeded), 1400);
+
+        cache.flush();
+        assert_eq!(cache.pmtu(far), 1500);
+
+        // A full cache keeps its destinations and takes no new ones
+        let cache = PmtuCache::with_capacity(1500, 1);
+        assert!(cache.learn(far, 1280));
+        assert!(!cache.learn(seeded, 1280));
+        assert!(cache.learn(far, 1006));
+        assert_eq!((cache.len(), cache.pmtu(seeded)), (1, 1500));
+    }
+
+    #[test]
+    fn test_send_plan() {
+        assert_eq!(plan(1480, 1500, true).unwrap(), SendPlan::Single);
+        assert!(plan(1481, 1500, true).is_err());
+        assert_eq!(plan(1481, 1500, false).unwrap(), SendPlan::Fragments(1480));
+        assert_eq!(plan(2000, 1006, false).unwrap(), SendPlan::Fragments(984));
+    }
+
+    #[test]
+    fn test_learned_mtu_fragments_sends() {
+        let pool = Arc::new(MbufPool::new("pmtu".to_string(), 8, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_tx_pool(pool.clone());
+        let socket_id = stack.create_socket(SocketAddr::V4(LOCAL)).unwrap();
+        let tx_queue = Arc::new(TxQueue::in_memory(0));
+        let socket = stack.get_socket_mut(socket_id).unwrap();
+        socket.bind_tx_queue(tx_queue.clone());
+
+        let router = Ipv4Addr::new(10, 0, 0, 254);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 6000);
+        let icmp_frame = |icmp: Vec<u8>| {
+            let mut ip_header = Ipv4Header::new(router, *LOCAL.ip(), icmp.len() as u16);
+            ip_header.protocol = IPPROTO_ICMP;
+            let mut frame = EthernetHeader::new([2; 6], [4; 6], ETHERTYPE_IPV4)
+                .to_bytes()
+                .to_vec();
+            frame.extend_from_slice(&ip_header.to_bytes());
+            frame.extend_from_slice(&icmp);
+            let mbuf = load(&pool, &frame);
+            crate::udp::classify(unsafe { &mut *mbuf });
+            let learned = stack.handle_icmp(unsafe { &*mbuf });
+            pool.free(mbuf).unwrap();
+            learned
+        };
+
+        // A message about a port nobody sends from changes nothing
+        let other = SocketAddrV4::new(*LOCAL.ip(), 5001);
+        assert_eq!(icmp_frame(quoting(other, dst, 576, 1500)), None);
+        assert_eq!(stack.pmtu_cache().pmtu(*dst.ip()), 1500);
+        assert_eq!(
+            icmp_frame(quoting(LOCAL, dst, 576, 1500)),
+            Some((*dst.ip(), 576))
+        );
+
+        let socket = stack.get_socket(socket_id).unwrap();
+        socket.send(SocketAddr::V4(dst), &[7; 1000]).unwrap();
+        let frames = tx_queue.sent_frames();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.len() - 14 <= 576));
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Early drop under pool pressure
+//!
+//! Every datagram a socket has queued or handed out without copying holds
+//! an mbuf, so one socket that stops reading drains the pool the RX queues
+//! receive into and starves every other socket of buffers. With
+//! [`crate::Config::early_drop`] set, the stack watches the free fraction
+//! of its receive pool while dispatching: below
+//! [`EarlyDropConfig::threshold`], datagrams for a socket holding more than
+//! its share of the buffers in use are dropped before delivery with
+//! [`super::DropReason::PoolPressure`]. The share is the buffers in use
+//! split evenly among the sockets, shrinking towards
+//! [`EarlyDropConfig::min_held`] as the pool empties, so the sockets
+//! holding the most lose their traffic first while those keeping up are
+//! still served.
+//!
+//! A socket's held buffers are its queued datagrams plus its zero-copy
+//! packets not yet released, both counters the socket keeps anyway, so the
+//! check costs a few loads per datagram.
+
+use crate::utils::counter::Counter;
+use crate::{Config, Error, Result};
+
+/// Free fraction of the pool below which early drop starts by default
+pub const DEFAULT_EARLY_DROP_THRESHOLD: f64 = 0.1;
+
+/// Early drop settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EarlyDropConfig {
+    /// Free fraction of the receive pool below which datagrams are dropped early
+    pub threshold: f64,
+    /// Buffers a socket may always hold, however low the pool
+    pub min_held: usize,
+}
+
+impl Default for EarlyDropConfig {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_EARLY_DROP_THRESHOLD,
+            min_held: 8,
+        }
+    }
+}
+
+impl EarlyDropConfig {
+    /// Whether a pool with `available` of `size` buffers free is below the threshold
+    pub(crate) fn pressured(&self, available: usize, size: usize) -> bool {
+        (available as f64) < self.threshold * size as f64
+    }
+
+    /// Whether a socket holding `held` buffers loses its next datagram
+    ///
+    /// `available` of the `size` buffers of the pool are free and `sockets`
+    /// sockets share the rest.
+    pub(crate) fn drops(&self, available: usize, size: usize, sockets: usize, held: usize) -> bool {
+        if !self.pressured(available, size) {
+            return false;
+        }
+        let floor = self.threshold * size as f64;
+        let in_use = size.saturating_sub(available) as f64;
+        let share = in_use / sockets.max(1) as f64 * (available as f64 / floor);
+        held as f64 > share.max(self.min_held as f64)
+    }
+}
+
+/// Early drop counters of a stack
+#[derive(Debug, Default)]
+pub struct EarlyDropStats {
+    /// Datagrams dispatched while the pool was below the threshold
+    pub pressured: Counter,
+    /// Datagrams dropped for sockets over their share
+    pub dropped: Counter,
+}
+
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    match &config.early_drop {
+        Some(early_drop) if !(early_drop.threshold > 0.0 && early_drop.threshold <= 1.0) => {
+            Err(Error::InvalidConfig(format!(
+                "Early drop threshold {} is not a fraction of the pool",
+                early_drop.threshold
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drops_sockets_over_their_share() {
+        let early_drop = EarlyDropConfig {
+            threshold: 0.2,
+            min_held: 4,
+        };
+        // Plenty free: nobody is dropped
+        assert!(!early_drop.drops(500, 1000, 4, 900));
+
+        // 150 free of 1000: 850 in use among 4 sockets, a share of 212
+        // scaled by 150/200 to 159
+        assert!(early_drop.drops(150, 1000, 4, 700));
+        assert!(!early_drop.drops(150, 1000, 4, 100));
+
+        // Nearly empty, only the minimum is left to each socket
+        assert!(early_drop.drops(1, 1000, 4, 5));
+        assert!(!early_drop.drops(1, 1000, 4, 4));
+        assert!(!early_drop.drops(0, 1000, 0, 0));
+
+        let config = Config {
+            early_drop: Some(EarlyDropConfig {
+                threshold: 1.5,
+                ..EarlyDropConfig::default()
+            }),
+            ..Config::default()
+        };
+        assert!(validate(&config).is_err());
+        assert!(validate(&Config::default()).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+//! DSCP-based receive priority bands
+//!
+//! A socket with priority bands keeps one receive ring per band and picks
+//! the ring from the DSCP bits of the IPv4 header. `recv` always drains the
+//! highest-priority non-empty band first, so EF traffic overtakes best effort
+//! queued before it.
+
+use crate::memory::Mbuf;
+use crate::{Error, Result};
+use lockfree_ringbuf::SpscRingBuffer;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Expedited Forwarding (RFC 3246)
+pub const DSCP_EF: u8 = 46;
+/// Number of DSCP code points
+const DSCP_COUNT: usize = 64;
+
+/// Mapping from DSCP code point to band, band 0 being served first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriorityBands {
+    map: [u8; DSCP_COUNT],
+    band_count: usize,
+    queue_size: usize,
+}
+
+impl PriorityBands {
+    /// Create `band_count` bands of `queue_size` packets, all DSCPs in the last band
+    pub fn new(band_count: usize, queue_size: usize) -> Result<Self> {
+        if band_count == 0 || band_count > u8::MAX as usize {
+            return Err(Error::InvalidConfig(format!(
+                "Band count {} out of range",
+                band_count
+            )));
+        }
+
+        Ok(Self {
+            map: [(band_count - 1) as u8; DSCP_COUNT],
+            band_count,
+            queue_size,
+        })
+    }
+
+    /// Three bands: EF and CS5-CS7, then the AF classes, then the rest
+    pub fn telco(queue_size: usize) -> Self {
+        let mut bands = Self::new(3, queue_size).expect("three bands are valid");
+        for dscp in [DSCP_EF, 40, 48, 56] {
+            bands.map[dscp as usize] = 0;
+        }
+        for class in 1..=4u8 {
+            for drop_precedence in 1..=3u8 {
+                bands.map[(class << 3 | drop_precedence << 1) as usize] = 1;
+            }
+        }
+        bands
+    }
+
+    /// Assign a DSCP code point to a band
+    pub fn with_dscp(mut self, dscp: u8, band: usize) -> Result<Self> {
+        if dscp as usize >= DSCP_COUNT || band >= self.band_count {
+            return Err(Error::InvalidConfig(format!(
+                "Cannot map DSCP {} to band {} of {}",
+                dscp, band, self.band_count
+            )));
+        }
+        self.map[dscp as usize] = band as u8;
+        Ok(self)
+    }
+
+    /// Band a DSCP code point is delivered in
+    pub fn band_for(&self, dscp: u8) -> usize {
+        self.map[(dscp & 0x3F) as usize] as usize
+    }
+
+    /// Number of bands
+    pub fn band_count(&self) -> usize {
+        self.band_count
+    }
+
+    /// Ring size of each band
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+}
+
+/// Per-band counters snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandStats {
+    pub enqueued: usize,
+    pub dequeued: usize,
+    pub dropped: usize,
+}
+
+#[derive(Debug, Default)]
+struct BandCounters {
+    enqueued: AtomicUsize,
+    dequeued: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+/// Receive rings of a socket, one per band
+pub(crate) struct BandedQueue {
+    bands: PriorityBands,
+    queues: Vec<SpscRingBuffer<*mut Mbuf>>,
+    counters: Vec<BandCounters>,
+}
+
+impl BandedQueue {
+    pub(crate) fn new(bands: PriorityBands) -> Self {
+        Self {
+            queues: (0..bands.band_count())
+                .map(|_| SpscRingBuffer::new(bands.queue_size()))
+                .collect(),
+            counters: (0..bands.band_count())
+                .map(|_| BandCounters::default())
+                .collect(),
+            bands,
+        }
+    }
+
+    pub(crate) fn bands(&self) -> &PriorityBands {
+        &self.bands
+    }
+
+    /// Fault in the rings of every band, returning the bytes touched
+    pub(crate) fn prefault(&self) -> usize {
+        self.queues.iter().map(SpscRingBuffer::prefault).sum()
+    }
+
+    /// Queue an mbuf in the band of its DSCP; on failure the caller keeps it
+    pub(crate) fn push(&self, mbuf: *mut Mbuf, dscp: u8) -> bool {
+        let band = self.bands.band_for(dscp);
+        let counters = &self.counters[band];
+
+        if self.queues[band].push(mbuf).is_err() {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        counters.enqueued.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Mbufs queued across the bands
+    pub(crate) fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    /// Take the next mbuf from the highest-priority non-empty band
+    pub(crate) fn pop(&self) -> Option<*mut Mbuf> {
+        self.queues
+            .iter()
+            .zip(&self.counters)
+            .find_map(|(queue, counters)| {
+                let mbuf = queue.pop().ok()?;
+                counters.dequeued.fetch_add(1, Ordering::Relaxed);
+                Some(mbuf)
+            })
+    }
+
+    pub(crate) fn stats(&self) -> Vec<BandStats> {
+        self.counters
+            .iter()
+            .map(|counters| BandStats {
+                enqueued: counters.enqueued.load(Ordering::Relaxed),
+                dequeued: counters.dequeued.load(Ordering::Relaxed),
+                dropped: counters.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_telco_mapping() {
+        let bands = PriorityBands::telco(16);
+        assert_eq!(bands.band_for(DSCP_EF), 0);
+        assert_eq!(bands.band_for(48), 0);
+        assert_eq!(bands.band_for(10), 1); // AF11
+        assert_eq!(bands.band_for(38), 1); // AF43
+        assert_eq!(bands.band_for(0), 2);
+        assert_eq!(bands.band_for(8), 2); // CS1
+
+        let custom = PriorityBands::new(2, 16).unwrap().with_dscp(8, 0).unwrap();
+        assert_eq!(custom.band_for(8), 0);
+        assert!(custom.clone().with_dscp(8, 2).is_err());
+        assert!(PriorityBands::new(0, 16).is_err());
+    }
+
+    #[test]
+    fn test_pop_drains_in_priority_order() {
+        let queue = BandedQueue::new(PriorityBands::telco(2));
+        let mut mbufs: Vec<Mbuf> = (0..4).map(|_| Mbuf::new(std::ptr::null_mut(), 0)).collect();
+        let ptrs: Vec<*mut Mbuf> = mbufs.iter_mut().map(|m| m as *mut Mbuf).collect();
+
+        assert!(queue.push(ptrs[0], 0));
+        assert!(queue.push(ptrs[1], 10));
+        assert!(queue.push(ptrs[2], DSCP_EF));
+        assert!(queue.push(ptrs[3], DSCP_EF));
+
+        assert_eq!(queue.pop(), Some(ptrs[2]));
+        assert_eq!(queue.pop(), Some(ptrs[3]));
+        assert_eq!(queue.pop(), Some(ptrs[1]));
+        assert_eq!(queue.pop(), Some(ptrs[0]));
+        assert_eq!(queue.pop(), None);
+
+        let stats = queue.stats();
+        assert_eq!(stats[0].enqueued, 2);
+        assert_eq!(stats[2].dequeued, 1);
+    }
+}
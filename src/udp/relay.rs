@@ -0,0 +1,445 @@
+//! UDP relay sessions for load balancers
+//!
+//! A [`RelayTable`] rewrites frames in place: client traffic to a virtual
+//! address is sent on to a backend from a relay port, and backend replies to
+//! that port are sent back to the client as if they came from the virtual
+//! address. IPv4 and UDP checksums are patched incrementally (RFC 1624), so
+//! the table works as a standalone pipeline stage without sockets.
+//! Sessions can be charged to a [`MemoryBudget`]; when it is spent, new
+//! clients are missed until sessions expire.
+
+use super::checksum::{checksum_update_u16, checksum_update_u32};
+use super::classify;
+use crate::memory::{Mbuf, MemoryBudget, PacketType, TableAccount};
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Relay table configuration
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Virtual address clients send to
+    pub vip: SocketAddrV4,
+    /// Address backends see as the source of relayed traffic
+    pub relay_addr: Ipv4Addr,
+    /// Local ports used for relay sessions
+    pub ports: RangeInclusive<u16>,
+    /// Sessions idle this long are expired
+    pub idle_timeout: Duration,
+}
+
+/// What the relay did with a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayVerdict {
+    /// Client frame rewritten towards a backend
+    Forwarded(SocketAddrV4),
+    /// Backend reply rewritten towards the client
+    Returned(SocketAddrV4),
+    /// Frame does not belong to the relay
+    Miss,
+}
+
+/// Relay statistics
+#[derive(Debug, Default)]
+pub struct RelayStats {
+    pub hits: AtomicUsize,
+    pub misses: AtomicUsize,
+    pub sessions_created: AtomicUsize,
+    pub sessions_expired: AtomicUsize,
+    pub port_exhausted: AtomicUsize,
+    pub budget_exceeded: AtomicUsize,
+}
+
+/// Budgeted bytes of one session and its index entries
+pub const RELAY_SESSION_BYTES: usize = std::mem::size_of::<RelaySession>()
+    + std::mem::size_of::<SocketAddrV4>()
+    + 2 * std::mem::size_of::<u16>();
+
+/// State of one client flow
+#[derive(Debug, Clone, Copy)]
+struct RelaySession {
+    client: SocketAddrV4,
+    backend: SocketAddrV4,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct RelayState {
+    /// Relay port of each client
+    by_client: HashMap<SocketAddrV4, u16>,
+    /// Sessions keyed by relay port
+    by_port: HashMap<u16, RelaySession>,
+    /// Next port to try when allocating
+    next_port: u16,
+    /// Next backend for round-robin selection
+    next_backend: usize,
+}
+
+/// Client to backend session table with in-place header rewriting
+pub struct RelayTable {
+    config: RelayConfig,
+    backends: Vec<SocketAddrV4>,
+    state: Mutex<RelayState>,
+    account: Option<Arc<TableAccount>>,
+    stats: RelayStats,
+}
+
+impl RelayTable {
+    /// Create a relay spreading new sessions round-robin over `backends`
+    pub fn new(config: RelayConfig, backends: Vec<SocketAddrV4>) -> Result<Self> {
+        if backends.is_empty() {
+            return Err(Error::InvalidConfig(
+                "Relay needs at least one backend".to_string(),
+            ));
+        }
+        if config.ports.is_empty() {
+            return Err(Error::InvalidConfig(
+                "Relay port range is empty".to_string(),
+            ));
+        }
+
+        let state = RelayState {
+            next_port: *config.ports.start(),
+            ..Default::default()
+        };
+
+        Ok(Self {
+            config,
+            backends,
+            state: Mutex::new(state),
+            account: None,
+            stats: RelayStats::default(),
+        })
+    }
+
+    /// Charge each session [`RELAY_SESSION_BYTES`] of `budget`, as the `relay` table
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.account = Some(budget.register_table("relay"));
+        self
+    }
+
+    /// Rewrite a frame if it belongs to a relay session
+    pub fn process(&self, mbuf: &mut Mbuf) -> RelayVerdict {
+        self.process_at(mbuf, Instant::now())
+    }
+
+    fn process_at(&self, mbuf: &mut Mbuf, now: Instant) -> RelayVerdict {
+        if mbuf.packet_type == PacketType::Unknown {
+            classify(mbuf);
+        }
+        let l3 = mbuf.l3_offset as usize;
+        let l4 = mbuf.l4_offset as usize;
+        if mbuf.packet_type != PacketType::Udp {
+            return self.miss();
+        }
+
+        let frame = mbuf.data_mut();
+        let src = read_addr(frame, l3 + 12, l4);
+        let dst = read_addr(frame, l3 + 16, l4 + 2);
+
+        let verdict = if dst == self.config.vip {
+            match self.forward(src, now) {
+                Some((relay_port, backend)) => {
+                    let relay = SocketAddrV4::new(self.config.relay_addr, relay_port);
+                    rewrite(frame, l3, l4, relay, backend);
+                    RelayVerdict::Forwarded(backend)
+                }
+                None => return RelayVerdict::Miss,
+            }
+        } else if *dst.ip() == self.config.relay_addr {
+            match self.reply(dst.port(), src, now) {
+                Some(client) => {
+                    rewrite(frame, l3, l4, self.config.vip, client);
+                    RelayVerdict::Returned(client)
+                }
+                None => return self.miss(),
+            }
+        } else {
+            return self.miss();
+        };
+
+        self.stats.hits.fetch_add(1, Ordering::Relaxed);
+        verdict
+    }
+
+    /// Find or create the session of a client
+    fn forward(&self, client: SocketAddrV4, now: Instant) -> Option<(u16, SocketAddrV4)> {
+        let mut state = self.state.lock();
+
+        if let Some(&port) = state.by_client.get(&client) {
+            let session = state.by_port.get_mut(&port)?;
+            session.last_seen = now;
+            return Some((port, session.backend));
+        }
+
+        let port = match self.allocate_port(&mut state) {
+            Some(port) => port,
+            None => {
+                self.stats.port_exhausted.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        if let Some(account) = &self.account {
+            if account.grow(1, RELAY_SESSION_BYTES).is_err() {
+                self.stats.budget_exceeded.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+        let backend = self.backends[state.next_backend % self.backends.len()];
+        state.next_backend = state.next_backend.wrapping_add(1);
+
+        state.by_client.insert(client, port);
+        state.by_port.insert(
+            port,
+            RelaySession {
+                client,
+                backend,
+                last_seen: now,
+            },
+        );
+        self.stats.sessions_created.fetch_add(1, Ordering::Relaxed);
+
+        Some((port, backend))
+    }
+
+    /// Match a backend reply to its session
+    fn reply(&self, port: u16, backend: SocketAddrV4, now: Instant) -> Option<SocketAddrV4> {
+        let mut state = self.state.lock();
+        let session = state.by_port.get_mut(&port)?;
+        if session.backend != backend {
+            return None;
+        }
+        session.last_seen = now;
+        Some(session.client)
+    }
+
+    fn allocate_port(&self, state: &mut RelayState) -> Option<u16> {
+        let (first, last) = (*self.config.ports.start(), *self.config.ports.end());
+        let span = last as usize - first as usize + 1;
+
+        for _ in 0..span {
+            let port = state.next_port;
+            state.next_port = if port == last { first } else { port + 1 };
+            if !state.by_port.contains_key(&port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn miss(&self) -> RelayVerdict {
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        RelayVerdict::Miss
+    }
+
+    /// Drop sessions idle longer than the timeout, returning how many
+    pub fn expire_idle(&self) -> usize {
+        self.expire_idle_at(Instant::now())
+    }
+
+    fn expire_idle_at(&self, now: Instant) -> usize {
+        let mut state = self.state.lock();
+        let timeout = self.config.idle_timeout;
+        let expired: Vec<(u16, SocketAddrV4)> = state
+            .by_port
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.last_seen) >= timeout)
+            .map(|(&port, session)| (port, session.client))
+            .collect();
+
+        for (port, client) in &expired {
+            state.by_port.remove(port);
+            state.by_client.remove(client);
+        }
+        self.release_sessions(expired.len());
+        self.stats
+            .sessions_expired
+            .fetch_add(expired.len(), Ordering::Relaxed);
+        expired.len()
+    }
+
+    /// Backend serving a client, if it has a session
+    pub fn backend_for(&self, client: &SocketAddrV4) -> Option<SocketAddrV4> {
+        let state = self.state.lock();
+        let port = state.by_client.get(client)?;
+        state.by_port.get(port).map(|session| session.backend)
+    }
+
+    /// Number of live sessions
+    pub fn session_count(&self) -> usize {
+        self.state.lock().by_port.len()
+    }
+
+    /// Get relay statistics
+    pub fn stats(&self) -> &RelayStats {
+        &self.stats
+    }
+
+    fn release_sessions(&self, count: usize) {
+        if let Some(account) = &self.account {
+            account.shrink(count, count * RELAY_SESSION_BYTES);
+        }
+    }
+}
+
+/// Read an IPv4 address and UDP port from a frame
+fn read_addr(frame: &[u8], ip_offset: usize, port_offset: usize) -> SocketAddrV4 {
+    let ip: [u8; 4] = frame[ip_offset..ip_offset + 4].try_into().unwrap();
+    let port = u16::from_be_bytes([frame[port_offset], frame[port_offset + 1]]);
+    SocketAddrV4::new(Ipv4Addr::from(ip), port)
+}
+
+/// Replace the addresses and ports of a frame, patching both checksums
+fn rewrite(frame: &mut [u8], l3: usize, l4: usize, src: SocketAddrV4, dst: SocketAddrV4) {
+    let be32 = |frame: &[u8], at: usize| u32::from_be_bytes(frame[at..at + 4].try_into().unwrap());
+    let be16 = |frame: &[u8], at: usize| u16::from_be_bytes([frame[at], frame[at + 1]]);
+    let addrs = [
+        (be32(frame, l3 + 12), u32::from(*src.ip())),
+        (be32(frame, l3 + 16), u32::from(*dst.ip())),
+    ];
+    let ports = [
+        (be16(frame, l4), src.port()),
+        (be16(frame, l4 + 2), dst.port()),
+    ];
+
+    frame[l3 + 12..l3 + 16].copy_from_slice(&src.ip().octets());
+    frame[l3 + 16..l3 + 20].copy_from_slice(&dst.ip().octets());
+    frame[l4..l4 + 2].copy_from_slice(&src.port().to_be_bytes());
+    frame[l4 + 2..l4 + 4].copy_from_slice(&dst.port().to_be_bytes());
+
+    // The IPv4 checksum covers the addresses, the UDP checksum covers
+    // them through the pseudo header as well as the ports
+    let patch_addrs = |checksum: u16| {
+        addrs.iter().fold(checksum, |checksum, &(old, new)| {
+            checksum_update_u32(checksum, old, new)
+        })
+    };
+    let ip_checksum = patch_addrs(be16(frame, l3 + 10));
+    frame[l3 + 10..l3 + 12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    let udp_checksum = be16(frame, l4 + 6);
+    if udp_checksum != 0 {
+        let udp_checksum = ports
+            .iter()
+            .fold(patch_addrs(udp_checksum), |checksum, &(old, new)| {
+                checksum_update_u16(checksum, old, new)
+            });
+        // Zero means no checksum; a computed zero is sent as all ones
+        let udp_checksum = match udp_checksum {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        frame[l4 + 6..l4 + 8].copy_from_slice(&udp_checksum.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::EthernetBuilder;
+    use crate::udp::testing::FrameBuilder;
+
+    fn frame(src: SocketAddrV4, dst: SocketAddrV4) -> Vec<u8> {
+        FrameBuilder::new(src, dst).payload(b"hello").build()
+    }
+
+    fn relay() -> RelayTable {
+        RelayTable::new(
+            RelayConfig {
+                vip: "192.0.2.1:53".parse().unwrap(),
+                relay_addr: Ipv4Addr::new(10, 0, 0, 1),
+                ports: 40000..=40001,
+                idle_timeout: Duration::from_secs(30),
+            },
+            vec![
+                "10.0.1.1:5353".parse().unwrap(),
+                "10.0.1.2:5353".parse().unwrap(),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn run(relay: &RelayTable, frame: &mut Vec<u8>, now: Instant) -> RelayVerdict {
+        let mut mbuf = Mbuf::new(frame.as_mut_ptr(), frame.len());
+        mbuf.len = frame.len();
+        relay.process_at(&mut mbuf, now)
+    }
+
+    #[test]
+    fn test_forward_and_return_rewrite_headers() {
+        let relay = relay();
+        let now = Instant::now();
+        let client: SocketAddrV4 = "198.51.100.7:1234".parse().unwrap();
+        let vip: SocketAddrV4 = "192.0.2.1:53".parse().unwrap();
+
+        let mut request = frame(client, vip);
+        let backend = match run(&relay, &mut request, now) {
+            RelayVerdict::Forwarded(backend) => backend,
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        };
+        assert_eq!(read_addr(&request, 30, 36), backend);
+        assert_eq!(
+            read_addr(&request, 26, 34),
+            "10.0.0.1:40000".parse().unwrap()
+        );
+        // Both checksums still verify
+        assert!(EthernetBuilder::parse(&request).is_ok());
+
+        let mut reply = frame(backend, "10.0.0.1:40000".parse().unwrap());
+        assert_eq!(run(&relay, &mut reply, now), RelayVerdict::Returned(client));
+        assert_eq!(read_addr(&reply, 26, 34), vip);
+        assert_eq!(read_addr(&reply, 30, 36), client);
+        assert!(EthernetBuilder::parse(&reply).is_ok());
+
+        // Replies from the wrong backend or to unknown ports are misses
+        let mut stray = frame(
+            "10.0.9.9:5353".parse().unwrap(),
+            "10.0.0.1:40000".parse().unwrap(),
+        );
+        assert_eq!(run(&relay, &mut stray, now), RelayVerdict::Miss);
+        assert_eq!(relay.stats().hits.load(Ordering::Relaxed), 2);
+        assert_eq!(relay.stats().misses.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_sessions_expire_and_ports_run_out() {
+        let relay = relay();
+        let now = Instant::now();
+        let vip: SocketAddrV4 = "192.0.2.1:53".parse().unwrap();
+
+        for port in 1..=3 {
+            let mut request = frame(SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 7), port), vip);
+            let verdict = run(&relay, &mut request, now);
+            assert_eq!(matches!(verdict, RelayVerdict::Forwarded(_)), port <= 2);
+        }
+        assert_eq!(relay.session_count(), 2);
+        assert_eq!(relay.stats().port_exhausted.load(Ordering::Relaxed), 1);
+        assert_ne!(
+            relay.backend_for(&"198.51.100.7:1".parse().unwrap()),
+            relay.backend_for(&"198.51.100.7:2".parse().unwrap())
+        );
+
+        assert_eq!(relay.expire_idle_at(now + Duration::from_secs(10)), 0);
+        assert_eq!(relay.expire_idle_at(now + Duration::from_secs(30)), 2);
+        assert_eq!(relay.session_count(), 0);
+
+        // A spent budget refuses sessions until one expires
+        let budget = Arc::new(MemoryBudget::new(Some(RELAY_SESSION_BYTES)));
+        let relay = relay.with_budget(budget.clone());
+        for port in 1..=2 {
+            let mut request = frame(SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 7), port), vip);
+            let verdict = run(&relay, &mut request, now);
+            assert_eq!(matches!(verdict, RelayVerdict::Forwarded(_)), port == 1);
+        }
+        assert_eq!(relay.stats().budget_exceeded.load(Ordering::Relaxed), 1);
+        assert_eq!(budget.usage().tables, RELAY_SESSION_BYTES);
+        assert_eq!(budget.tables()[0].entries, 1);
+        drop(relay);
+        assert_eq!(budget.usage().tables, 0);
+    }
+}
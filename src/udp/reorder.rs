@@ -0,0 +1,552 @@
+//! Per-flow reordering of received datagrams
+//!
+//! Media flows number their datagrams and want them in order even when the
+//! network swaps a few. A [`ReorderBuffer`] attached with
+//! [`super::UdpSocket::set_reorder_buffer`] reads each datagram's sequence
+//! number with a [`SequenceExtractor`] and queues it on the socket only
+//! once every earlier number of its flow was queued, holding datagrams
+//! that arrive early. The wait for a missing number is bounded two ways:
+//! a flow holds at most [`ReorderConfig::max_gap`] numbers ahead of the
+//! next expected one, and a datagram is held at most
+//! [`ReorderConfig::max_delay`]. Numbers given up on are counted as lost,
+//! and datagrams arriving after their number was passed are dropped as
+//! [`super::DropReason::Late`]. Datagrams without a sequence number are
+//! queued right away. Held datagrams keep their mbufs until released to
+//! the socket or until the buffer is dropped.
+//!
+//! Sequence numbers are [`ReorderConfig::sequence_bits`] wide and compared
+//! in serial number arithmetic (RFC 1982), so a 16-bit RTP counter that
+//! wraps keeps its order. A datagram more than
+//! [`ReorderConfig::max_misorder`] numbers behind is taken as its peer
+//! restarting rather than as late, and the flow starts over from it.
+//!
+//! At most [`ReorderConfig::max_flows`] peers are tracked, and a peer
+//! silent for [`ReorderConfig::flow_idle`] is forgotten; datagrams of
+//! peers beyond the limit are queued unordered. On a stack, the flows are
+//! charged to its memory budget as the `reorder` table, and peers the
+//! budget cannot hold are queued unordered as well. Held datagrams go out
+//! when due on every received datagram and from
+//! [`super::UdpStack::flush_reorder_buffers`], which the poll loops of
+//! [`crate::Xpdk`] and [`crate::dispatch::Dispatcher`] run.
+
+use super::{SequenceExtractor, UdpPacket};
+use crate::memory::{Mbuf, MbufPool, MemoryBudget, TableAccount};
+use crate::utils::time::Timestamp;
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sequence numbers a flow waits ahead by default
+pub const DEFAULT_REORDER_GAP: u64 = 64;
+
+/// Largest gap a flow may wait ahead, which bounds the datagrams each peer holds
+pub const MAX_REORDER_GAP: u64 = 1024;
+
+/// Time a datagram is held at most by default
+pub const DEFAULT_REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Sequence numbers behind a flow from which its peer restarted, by default
+pub const DEFAULT_REORDER_MISORDER: u64 = 3000;
+
+/// Peers a reorder buffer tracks by default
+pub const DEFAULT_REORDER_FLOWS: usize = 1024;
+
+/// Time after which a silent peer is forgotten by default
+pub const DEFAULT_REORDER_FLOW_IDLE: Duration = Duration::from_secs(30);
+
+/// Budgeted bytes of one flow, without the datagrams it holds
+pub const REORDER_FLOW_BYTES: usize = std::mem::size_of::<(SocketAddr, FlowState)>();
+
+/// Reordering policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderConfig {
+    /// Longest a datagram is held waiting for earlier ones
+    pub max_delay: Duration,
+    /// Sequence numbers past the next expected one a flow holds at most,
+    /// up to [`MAX_REORDER_GAP`]
+    pub max_gap: u64,
+    /// Width of the sequence numbers, which wrap around past `2^bits - 1`
+    pub sequence_bits: u32,
+    /// Sequence numbers behind the next expected one from which a datagram
+    /// restarts its flow instead of being late
+    pub max_misorder: u64,
+    /// Peers tracked at once
+    pub max_flows: usize,
+    /// Time without a datagram after which a peer is forgotten
+    pub flow_idle: Duration,
+}
+
+impl Default for ReorderConfig {
+    fn default() -> Self {
+        Self {
+            max_delay: DEFAULT_REORDER_DELAY,
+            max_gap: DEFAULT_REORDER_GAP,
+            sequence_bits: 64,
+            max_misorder: DEFAULT_REORDER_MISORDER,
+            max_flows: DEFAULT_REORDER_FLOWS,
+            flow_idle: DEFAULT_REORDER_FLOW_IDLE,
+        }
+    }
+}
+
+impl ReorderConfig {
+    /// Sequence numbers for RTP and other 16-bit counters
+    pub fn sixteen_bit() -> Self {
+        Self {
+            sequence_bits: 16,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !(2..=64).contains(&self.sequence_bits) {
+            return Err(Error::InvalidConfig(format!(
+                "Sequence numbers of {} bits",
+                self.sequence_bits
+            )));
+        }
+        let half = 1u64 << (self.sequence_bits - 1);
+        if self.max_gap > MAX_REORDER_GAP || self.max_gap >= half {
+            return Err(Error::InvalidConfig(format!(
+                "Reorder gap {} above {} or half the sequence space",
+                self.max_gap, MAX_REORDER_GAP
+            )));
+        }
+        if self.max_misorder >= half || self.max_flows == 0 {
+            return Err(Error::InvalidConfig(format!(
+                "Reorder misorder {} or flow limit {} out of range",
+                self.max_misorder, self.max_flows
+            )));
+        }
+        Ok(())
+    }
+
+    fn sequence_mask(&self) -> u64 {
+        u64::MAX >> (64 - self.sequence_bits)
+    }
+}
+
+/// Reordering counters
+#[derive(Debug, Default)]
+pub struct ReorderStats {
+    /// Datagrams queued as they arrived
+    pub in_order: AtomicUsize,
+    /// Datagrams held and queued once their turn came
+    pub reordered: AtomicUsize,
+    /// Datagrams dropped because their number was already passed
+    pub late: AtomicUsize,
+    /// Datagrams dropped because their number was already held
+    pub duplicates: AtomicUsize,
+    /// Sequence numbers given up on
+    pub lost: AtomicUsize,
+    /// Datagrams without a sequence number, queued right away
+    pub unsequenced: AtomicUsize,
+    /// Flows started over after a large backward jump
+    pub restarts: AtomicUsize,
+    /// Datagrams of peers past the flow limit, queued right away
+    pub untracked: AtomicUsize,
+    /// Peers forgotten after going silent
+    pub idle_flows: AtomicUsize,
+    /// Peers queued unordered for lack of budget, also counted as untracked
+    pub budget_exceeded: AtomicUsize,
+}
+
+/// What the buffer did with a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reordered {
+    /// Handed to the delivery callback, possibly after held ones
+    Released,
+    /// Kept until earlier datagrams arrive; the buffer owns the mbuf
+    Held,
+    /// Behind its flow or a duplicate; the caller keeps the mbuf
+    Late,
+    /// No sequence number or no room for its peer; the caller queues it as usual
+    Unsequenced,
+}
+
+/// Where a sequence number lies relative to the next expected one
+enum Position {
+    /// At or past it, as an unwrapped number
+    Ahead(u64),
+    /// This many numbers before it
+    Behind(u64),
+}
+
+struct FlowState {
+    /// Next sequence number to release, unwrapped past the sequence width
+    next: u64,
+    /// Datagrams waiting for their turn, with the time they arrived
+    held: BTreeMap<u64, (*mut Mbuf, Timestamp)>,
+    /// Arrival of the last datagram
+    last_seen: Timestamp,
+}
+
+/// Reorder buffer of a socket, one window per peer
+pub struct ReorderBuffer {
+    extractor: SequenceExtractor,
+    config: ReorderConfig,
+    /// Pool held datagrams are freed to when the buffer is dropped
+    pool: Arc<MbufPool>,
+    flows: Mutex<HashMap<SocketAddr, FlowState>>,
+    account: Option<Arc<TableAccount>>,
+    stats: ReorderStats,
+}
+
+// Held mbufs are owned by the buffer, like the queued ones of a socket
+unsafe impl Send for ReorderBuffer {}
+unsafe impl Sync for ReorderBuffer {}
+
+impl ReorderBuffer {
+    pub(crate) fn new(
+        config: ReorderConfig,
+        extractor: SequenceExtractor,
+        pool: Arc<MbufPool>,
+    ) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            extractor,
+            config,
+            pool,
+            flows: Mutex::new(HashMap::new()),
+            account: None,
+            stats: ReorderStats::default(),
+        })
+    }
+
+    /// Charge each flow [`REORDER_FLOW_BYTES`] of `budget`, as the `reorder` table
+    pub(crate) fn with_budget(mut self, budget: &Arc<MemoryBudget>) -> Self {
+        self.account = Some(budget.register_table("reorder"));
+        self
+    }
+
+    pub fn config(&self) -> &ReorderConfig {
+        &self.config
+    }
+
+    /// Get reordering counters
+    pub fn stats(&self) -> &ReorderStats {
+        &self.stats
+    }
+
+    /// Datagrams held across all flows
+    pub fn held(&self) -> usize {
+        self.flows.lock().values().map(|flow| flow.held.len()).sum()
+    }
+
+    /// Peers tracked
+    pub fn flows(&self) -> usize {
+        self.flows.lock().len()
+    }
+
+    /// Take a received datagram, calling `deliver` with every mbuf now due in order
+    pub(crate) fn push(
+        &self,
+        packet: &UdpPacket,
+        now: Timestamp,
+        mut deliver: impl FnMut(*mut Mbuf),
+    ) -> Reordered {
+        let Some(seq) = (self.extractor)(packet.payload()) else {
+            self.stats.unsequenced.fetch_add(1, Ordering::Relaxed);
+            return Reordered::Unsequenced;
+        };
+        let seq = seq & self.config.sequence_mask();
+        let peer = packet.src_addr();
+        let mut flows = self.flows.lock();
+        if !flows.contains_key(&peer) {
+            if flows.len() >= self.config.max_flows {
+                self.forget_idle(&mut flows, now, &mut deliver);
+            }
+            if flows.len() >= self.config.max_flows {
+                self.stats.untracked.fetch_add(1, Ordering::Relaxed);
+                return Reordered::Unsequenced;
+            }
+            if let Some(account) = &self.account {
+                if account.grow(1, REORDER_FLOW_BYTES).is_err() {
+                    self.stats.budget_exceeded.fetch_add(1, Ordering::Relaxed);
+                    self.stats.untracked.fetch_add(1, Ordering::Relaxed);
+                    return Reordered::Unsequenced;
+                }
+            }
+        }
+        let flow = flows.entry(peer).or_insert_with(|| FlowState {
+            next: seq,
+            held: BTreeMap::new(),
+            last_seen: now,
+        });
+        flow.last_seen = now;
+
+        let seq = match self.position(flow.next, seq) {
+            Position::Behind(distance) if distance > self.config.max_misorder => {
+                // The peer started over: what it sent before goes out first
+                self.stats.restarts.fetch_add(1, Ordering::Relaxed);
+                for (_, (mbuf, _)) in std::mem::take(&mut flow.held) {
+                    deliver(mbuf);
+                    self.stats.reordered.fetch_add(1, Ordering::Relaxed);
+                }
+                flow.next = seq;
+                seq
+            }
+            Position::Behind(_) => {
+                self.stats.late.fetch_add(1, Ordering::Relaxed);
+                self.expire(flow, now, &mut deliver);
+                return Reordered::Late;
+            }
+            Position::Ahead(seq) => seq,
+        };
+
+        let verdict = if seq == flow.next || seq - flow.next > self.config.max_gap {
+            // Its turn, or too far ahead to keep waiting for those before it
+            self.skip_to(flow, seq, &mut deliver);
+            deliver(packet.mbuf);
+            self.stats.in_order.fetch_add(1, Ordering::Relaxed);
+            flow.next = seq + 1;
+            self.release_ready(flow, &mut deliver);
+            Reordered::Released
+        } else {
+            match flow.held.entry(seq) {
+                Entry::Occupied(_) => {
+                    self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    Reordered::Late
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert((packet.mbuf, now));
+                    Reordered::Held
+                }
+            }
+        };
+        self.expire(flow, now, &mut deliver);
+        verdict
+    }
+
+    /// Release datagrams held longer than the maximum delay, with those before them
+    ///
+    /// Peers silent for longer than the flow idle time are forgotten.
+    pub(crate) fn flush_expired(&self, now: Timestamp, mut deliver: impl FnMut(*mut Mbuf)) {
+        let mut flows = self.flows.lock();
+        for flow in flows.values_mut() {
+            self.expire(flow, now, &mut deliver);
+        }
+        self.forget_idle(&mut flows, now, &mut deliver);
+    }
+
+    /// Drop the flows of silent peers, releasing what they held in order
+    fn forget_idle(
+        &self,
+        flows: &mut HashMap<SocketAddr, FlowState>,
+        now: Timestamp,
+        deliver: &mut impl FnMut(*mut Mbuf),
+    ) {
+        let flow_idle = self.config.flow_idle.as_nanos() as u64;
+        let before = flows.len();
+        flows.retain(|_, flow| {
+            if now.saturating_sub(flow.last_seen) < flow_idle {
+                return true;
+            }
+            for (_, (mbuf, _)) in std::mem::take(&mut flow.held) {
+                deliver(mbuf);
+                self.stats.reordered.fetch_add(1, Ordering::Relaxed);
+            }
+            self.stats.idle_flows.fetch_add(1, Ordering::Relaxed);
+            false
+        });
+        if let Some(account) = &self.account {
+            let forgotten = before - flows.len();
+            account.shrink(forgotten, forgotten * REORDER_FLOW_BYTES);
+        }
+    }
+
+    /// Place `seq` against `next` in serial number arithmetic
+    fn position(&self, next: u64, seq: u64) -> Position {
+        let mask = self.config.sequence_mask();
+        let ahead = seq.wrapping_sub(next) & mask;
+        if ahead <= mask >> 1 {
+            Position::Ahead(next.saturating_add(ahead))
+        } else {
+            Position::Behind((mask - ahead) + 1)
+        }
+    }
+
+    fn expire(&self, flow: &mut FlowState, now: Timestamp, deliver: &mut impl FnMut(*mut Mbuf)) {
+        let max_delay = self.config.max_delay.as_nanos() as u64;
+        let expired = flow
+            .held
+            .iter()
+            .rev()
+            .find(|(_, &(_, held_at))| now.saturating_sub(held_at) >= max_delay)
+            .map(|(&seq, _)| seq);
+        if let Some(seq) = expired {
+            self.skip_to(flow, seq + 1, deliver);
+            self.release_ready(flow, deliver);
+        }
+    }
+
+    /// Give up on the numbers before `seq`, releasing those held in order
+    fn skip_to(&self, flow: &mut FlowState, seq: u64, deliver: &mut impl FnMut(*mut Mbuf)) {
+        let mut lost = 0;
+        while let Some(entry) = flow.held.first_entry() {
+            if *entry.key() >= seq {
+                break;
+            }
+            lost += entry.key() - flow.next;
+            flow.next = entry.key() + 1;
+            deliver(entry.remove().0);
+            self.stats.reordered.fetch_add(1, Ordering::Relaxed);
+        }
+        if seq > flow.next {
+            lost += seq - flow.next;
+            flow.next = seq;
+        }
+        self.stats.lost.fetch_add(lost as usize, Ordering::Relaxed);
+    }
+
+    /// Release held datagrams that continue the flow
+    fn release_ready(&self, flow: &mut FlowState, deliver: &mut impl FnMut(*mut Mbuf)) {
+        while let Some(entry) = flow.held.first_entry() {
+            if *entry.key() != flow.next {
+                break;
+            }
+            flow.next += 1;
+            deliver(entry.remove().0);
+            self.stats.reordered.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ReorderBuffer {
+    fn drop(&mut self) {
+        for flow in self.flows.get_mut().values_mut() {
+            for (_, (mbuf, _)) in std::mem::take(&mut flow.held) {
+                let _ = self.pool.free(mbuf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::testing::{load, FrameBuilder};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_releases_in_order_within_gap_and_delay() {
+        let pool = Arc::new(MbufPool::new("reorder".to_string(), 16, 256).unwrap());
+        let buffer = ReorderBuffer::new(
+            ReorderConfig {
+                max_delay: Duration::from_nanos(1000),
+                max_gap: 4,
+                ..ReorderConfig::default()
+            },
+            Box::new(|payload| Some(payload[0] as u64)),
+            pool.clone(),
+        )
+        .unwrap();
+        let mut released = Vec::new();
+        let mut push = |seq: u8, now: Timestamp| {
+            let mbuf = load(&pool, &FrameBuilder::to_port(5004).payload(&[seq]).build());
+            let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+            let verdict = buffer.push(&packet, now, |mbuf| {
+                released.push(UdpPacket::from_mbuf(mbuf).unwrap().payload()[0]);
+                pool.free(mbuf).unwrap();
+            });
+            if verdict == Reordered::Late {
+                pool.free(mbuf).unwrap();
+            }
+            verdict
+        };
+
+        // 2 waits for 1, then both go out; 1 again is late
+        assert_eq!(push(0, 0), Reordered::Released);
+        assert_eq!(push(2, 10), Reordered::Held);
+        assert_eq!(push(2, 11), Reordered::Late);
+        assert_eq!(push(1, 20), Reordered::Released);
+        assert_eq!(push(1, 30), Reordered::Late);
+        // 9 is more than 4 past 3: 4 and 6 waiting go out, 3, 5, 7 and 8 are lost
+        assert_eq!(push(4, 40), Reordered::Held);
+        assert_eq!(push(6, 50), Reordered::Held);
+        assert_eq!(push(9, 60), Reordered::Released);
+        // 11 waits for 10 until the delay runs out
+        assert_eq!(push(11, 100), Reordered::Held);
+        assert_eq!(push(13, 1100), Reordered::Held);
+        assert_eq!(released, [0, 1, 2, 4, 6, 9, 11]);
+
+        let stats = buffer.stats();
+        assert_eq!(stats.in_order.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.reordered.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.late.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.duplicates.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.lost.load(Ordering::Relaxed), 5);
+
+        // Dropping the buffer frees what it still holds
+        assert_eq!(buffer.held(), 1);
+        drop(buffer);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_wrapping_restarts_and_flow_limits() {
+        let pool = Arc::new(MbufPool::new("reorder".to_string(), 16, 256).unwrap());
+        let config = ReorderConfig {
+            max_misorder: 100,
+            max_flows: 1,
+            flow_idle: Duration::from_nanos(1000),
+            ..ReorderConfig::sixteen_bit()
+        };
+        assert!(ReorderConfig {
+            max_gap: MAX_REORDER_GAP + 1,
+            ..config
+        }
+        .validate()
+        .is_err());
+        let extractor = |payload: &[u8]| Some(u16::from_be_bytes([payload[0], payload[1]]) as u64);
+        let buffer = ReorderBuffer::new(config, Box::new(extractor), pool.clone()).unwrap();
+        let mut released = Vec::new();
+        let mut push = |seq: u16, src_port: u16, now: Timestamp| {
+            let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), src_port);
+            let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5004);
+            let frame = FrameBuilder::new(src, dst)
+                .payload(&seq.to_be_bytes())
+                .build();
+            let mbuf = load(&pool, &frame);
+            let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+            let verdict = buffer.push(&packet, now, |mbuf| {
+                let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+                released.push(u16::from_be_bytes([
+                    packet.payload()[0],
+                    packet.payload()[1],
+                ]));
+                pool.free(mbuf).unwrap();
+            });
+            if matches!(verdict, Reordered::Late | Reordered::Unsequenced) {
+                pool.free(mbuf).unwrap();
+            }
+            verdict
+        };
+
+        // 0 waits for 65535 across the wrap; 65534 is late
+        assert_eq!(push(65534, 1, 0), Reordered::Released);
+        assert_eq!(push(0, 1, 1), Reordered::Held);
+        assert_eq!(push(65535, 1, 2), Reordered::Released);
+        assert_eq!(push(65534, 1, 3), Reordered::Late);
+        // Far behind: the peer restarted
+        assert_eq!(push(60_000, 1, 4), Reordered::Released);
+        assert_eq!(push(60_001, 1, 5), Reordered::Released);
+        // A second peer finds no room until the first goes silent
+        assert_eq!(push(7, 2, 6), Reordered::Unsequenced);
+        assert_eq!(push(9, 2, 2000), Reordered::Released);
+        assert_eq!(released, [65534, 65535, 0, 60_000, 60_001, 9]);
+
+        let stats = buffer.stats();
+        assert_eq!(stats.restarts.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.untracked.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.idle_flows.load(Ordering::Relaxed), 1);
+        assert_eq!(buffer.flows(), 1);
+        buffer.flush_expired(4000, |_| unreachable!());
+        assert_eq!(buffer.flows(), 0);
+    }
+}
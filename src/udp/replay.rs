@@ -0,0 +1,369 @@
+//! Replay protection for UDP flows
+//!
+//! Implements the RFC 6479 sliding window: a ring of 64-bit blocks where
+//! advancing the window clears whole blocks instead of shifting bits.
+//!
+//! A guard tracks up to [`ReplayGuard::with_max_peers`] peers. With
+//! [`ReplayGuard::with_budget`], which a socket does on its stack's
+//! budget, each peer window is also charged to the memory budget as the
+//! `replay` table. Datagrams of new peers the table or the budget cannot
+//! hold are rejected.
+//!
+//! A socket records a sequence number only once the datagram is queued,
+//! so a datagram dropped on a full queue is still accepted when resent.
+
+use super::UdpPacket;
+use crate::memory::{MemoryBudget, TableAccount};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Bits per bitmap block
+const BLOCK_BITS: u64 = 64;
+
+/// Default number of peers a guard tracks
+pub const DEFAULT_REPLAY_PEERS: usize = 4096;
+
+/// Outcome of checking a sequence number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceCheck {
+    /// First time seen and inside the window
+    Accepted,
+    /// Already seen inside the window
+    Replay,
+    /// Older than the window can track
+    TooOld,
+}
+
+/// Sliding-window sequence tracker for a single flow
+#[derive(Debug, Clone)]
+pub struct SequenceWindow {
+    /// Bitmap blocks, one bit per sequence number
+    blocks: Vec<u64>,
+    /// Number of sequence numbers tracked behind the highest one
+    window_size: u64,
+    /// Highest sequence number accepted so far
+    highest: u64,
+    /// Whether any sequence number was accepted yet
+    initialized: bool,
+}
+
+impl SequenceWindow {
+    /// Create a window tracking at least `window_size` sequence numbers
+    pub fn new(window_size: u64) -> Self {
+        // One spare block so the window never clears bits it still needs
+        let block_count = (window_size.max(1).div_ceil(BLOCK_BITS) + 1).next_power_of_two();
+
+        Self {
+            blocks: vec![0; block_count as usize],
+            window_size: (block_count - 1) * BLOCK_BITS,
+            highest: 0,
+            initialized: false,
+        }
+    }
+
+    /// Number of sequence numbers tracked behind the highest one
+    pub fn window_size(&self) -> u64 {
+        self.window_size
+    }
+
+    /// Highest sequence number accepted so far
+    pub fn highest(&self) -> Option<u64> {
+        self.initialized.then_some(self.highest)
+    }
+
+    /// Check a sequence number without recording it
+    pub fn check(&self, seq: u64) -> SequenceCheck {
+        if !self.initialized || seq > self.highest {
+            return SequenceCheck::Accepted;
+        }
+        if self.highest - seq >= self.window_size {
+            return SequenceCheck::TooOld;
+        }
+
+        let (block, bit) = self.position(seq);
+        if self.blocks[block] & (1 << bit) != 0 {
+            SequenceCheck::Replay
+        } else {
+            SequenceCheck::Accepted
+        }
+    }
+
+    /// Check a sequence number and record it if accepted
+    pub fn check_and_record(&mut self, seq: u64) -> SequenceCheck {
+        let result = self.check(seq);
+        if result != SequenceCheck::Accepted {
+            return result;
+        }
+
+        if !self.initialized || seq > self.highest {
+            self.advance(seq);
+        }
+
+        let (block, bit) = self.position(seq);
+        self.blocks[block] |= 1 << bit;
+        result
+    }
+
+    /// Move the window forward so `seq` becomes the highest number
+    fn advance(&mut self, seq: u64) {
+        let block_count = self.blocks.len() as u64;
+
+        if self.initialized {
+            let current = self.highest / BLOCK_BITS;
+            let target = seq / BLOCK_BITS;
+            let cleared = (target - current).min(block_count);
+
+            for i in 1..=cleared {
+                let index = ((current + i) % block_count) as usize;
+                self.blocks[index] = 0;
+            }
+        }
+
+        self.highest = seq;
+        self.initialized = true;
+    }
+
+    /// Block index and bit for a sequence number
+    fn position(&self, seq: u64) -> (usize, u64) {
+        let block = (seq / BLOCK_BITS) % self.blocks.len() as u64;
+        (block as usize, seq % BLOCK_BITS)
+    }
+}
+
+/// Replay protection statistics
+#[derive(Debug, Default)]
+pub struct ReplayStats {
+    pub accepted: AtomicUsize,
+    pub replays: AtomicUsize,
+    pub late: AtomicUsize,
+    pub unparsed: AtomicUsize,
+    /// Datagrams of new peers rejected for lack of budget
+    pub budget_exceeded: AtomicUsize,
+    /// Datagrams of new peers rejected with the peer table full
+    pub peer_limit: AtomicUsize,
+}
+
+/// Callback extracting a sequence number from a UDP payload
+pub type SequenceExtractor = Box<dyn Fn(&[u8]) -> Option<u64> + Send + Sync>;
+
+/// Per-peer replay protection attachable to a socket
+pub struct ReplayGuard {
+    /// Sequence number extractor
+    extractor: SequenceExtractor,
+    /// Window size used for new peers
+    window_size: u64,
+    max_peers: usize,
+    peer_bytes: usize,
+    /// Windows keyed by peer address
+    windows: Mutex<HashMap<SocketAddr, SequenceWindow>>,
+    account: Option<Arc<TableAccount>>,
+    /// Guard statistics
+    stats: ReplayStats,
+}
+
+impl ReplayGuard {
+    /// Create a guard using `extractor` to read sequence numbers
+    pub fn new<F>(window_size: u64, extractor: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<u64> + Send + Sync + 'static,
+    {
+        let blocks = SequenceWindow::new(window_size).blocks.len();
+        Self {
+            extractor: Box::new(extractor),
+            window_size,
+            max_peers: DEFAULT_REPLAY_PEERS,
+            peer_bytes: std::mem::size_of::<(SocketAddr, SequenceWindow)>()
+                + blocks * std::mem::size_of::<u64>(),
+            windows: Mutex::new(HashMap::new()),
+            account: None,
+            stats: ReplayStats::default(),
+        }
+    }
+
+    /// Charge each peer window [`ReplayGuard::peer_bytes`] of `budget`, as the `replay` table
+    ///
+    /// Peers tracked so far are forgotten.
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.windows.get_mut().clear();
+        self.account = Some(budget.register_table("replay"));
+        self
+    }
+
+    /// Track at most `max_peers` peers, [`DEFAULT_REPLAY_PEERS`] by default
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    /// Budgeted bytes of one peer and its window
+    pub fn peer_bytes(&self) -> usize {
+        self.peer_bytes
+    }
+
+    /// Whether the guard is charged to a budget
+    pub(crate) fn is_budgeted(&self) -> bool {
+        self.account.is_some()
+    }
+
+    /// Validate and record a packet's sequence number
+    ///
+    /// Packets without a parseable sequence number are treated as too old.
+    pub fn check(&self, packet: &UdpPacket) -> SequenceCheck {
+        match self.validate(packet) {
+            Ok(seq) => self.record(packet.src_addr(), seq),
+            Err(result) => result,
+        }
+    }
+
+    /// Check a packet's sequence number without recording it
+    ///
+    /// Returns the number to [`ReplayGuard::record`] once the datagram is
+    /// taken. A new peer gets an empty window if the table and the budget
+    /// have room for it.
+    pub(crate) fn validate(&self, packet: &UdpPacket) -> Result<u64, SequenceCheck> {
+        let Some(seq) = (self.extractor)(packet.payload()) else {
+            self.stats.unparsed.fetch_add(1, Ordering::Relaxed);
+            return Err(SequenceCheck::TooOld);
+        };
+
+        let mut windows = self.windows.lock();
+        let peer = packet.src_addr();
+        let result = match windows.get(&peer) {
+            Some(window) => window.check(seq),
+            None => {
+                if windows.len() >= self.max_peers {
+                    self.stats.peer_limit.fetch_add(1, Ordering::Relaxed);
+                    return Err(SequenceCheck::TooOld);
+                }
+                if let Some(account) = &self.account {
+                    if account.grow(1, self.peer_bytes()).is_err() {
+                        self.stats.budget_exceeded.fetch_add(1, Ordering::Relaxed);
+                        return Err(SequenceCheck::TooOld);
+                    }
+                }
+                windows.insert(peer, SequenceWindow::new(self.window_size));
+                SequenceCheck::Accepted
+            }
+        };
+        match result {
+            SequenceCheck::Accepted => return Ok(seq),
+            SequenceCheck::Replay => &self.stats.replays,
+            SequenceCheck::TooOld => &self.stats.late,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        Err(result)
+    }
+
+    /// Record a sequence number [`ReplayGuard::validate`] accepted
+    pub(crate) fn record(&self, peer: SocketAddr, seq: u64) -> SequenceCheck {
+        let result = self
+            .windows
+            .lock()
+            .get_mut(&peer)
+            .map_or(SequenceCheck::TooOld, |window| window.check_and_record(seq));
+        match result {
+            SequenceCheck::Accepted => &self.stats.accepted,
+            SequenceCheck::Replay => &self.stats.replays,
+            SequenceCheck::TooOld => &self.stats.late,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    /// Forget the window of a peer
+    pub fn reset_peer(&self, peer: &SocketAddr) {
+        if self.windows.lock().remove(peer).is_some() {
+            if let Some(account) = &self.account {
+                account.shrink(1, self.peer_bytes());
+            }
+        }
+    }
+
+    /// Number of tracked peers
+    pub fn peer_count(&self) -> usize {
+        self.windows.lock().len()
+    }
+
+    /// Get guard statistics
+    pub fn stats(&self) -> &ReplayStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::testing::{load, FrameBuilder};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn test_window_rejects_replays() {
+        let mut window = SequenceWindow::new(128);
+        assert!(window.window_size() >= 128);
+
+        assert_eq!(window.check_and_record(10), SequenceCheck::Accepted);
+        assert_eq!(window.check_and_record(10), SequenceCheck::Replay);
+        assert_eq!(window.check_and_record(8), SequenceCheck::Accepted);
+        assert_eq!(window.check_and_record(8), SequenceCheck::Replay);
+        assert_eq!(window.check_and_record(11), SequenceCheck::Accepted);
+        assert_eq!(window.highest(), Some(11));
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let mut window = SequenceWindow::new(128);
+        let size = window.window_size();
+
+        assert_eq!(window.check_and_record(1), SequenceCheck::Accepted);
+        assert_eq!(window.check_and_record(1 + size), SequenceCheck::Accepted);
+        assert_eq!(window.check(1), SequenceCheck::TooOld);
+        assert_eq!(window.check_and_record(2), SequenceCheck::Accepted);
+
+        // Jumping far ahead clears every block
+        assert_eq!(window.check_and_record(100 * size), SequenceCheck::Accepted);
+        assert_eq!(
+            window.check_and_record(100 * size - 1),
+            SequenceCheck::Accepted
+        );
+        assert_eq!(
+            window.check_and_record(100 * size - 1),
+            SequenceCheck::Replay
+        );
+    }
+
+    #[test]
+    fn test_guard_records_on_delivery_and_bounds_peers() {
+        let pool = MbufPool::new("replay".to_string(), 2, 2048).unwrap();
+        let guard =
+            ReplayGuard::new(64, |payload| Some(u64::from(*payload.first()?))).with_max_peers(1);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000);
+        let from = |host, seq| {
+            let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, host), 6000);
+            load(&pool, &FrameBuilder::new(src, dst).payload(&[seq]).build())
+        };
+
+        let mbuf = from(2, 7);
+        let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+        // Validated but not delivered, the number stays acceptable
+        assert_eq!(guard.validate(&packet), Ok(7));
+        assert_eq!(guard.validate(&packet), Ok(7));
+        assert_eq!(guard.record(packet.src_addr(), 7), SequenceCheck::Accepted);
+        assert_eq!(guard.validate(&packet), Err(SequenceCheck::Replay));
+        pool.free(mbuf).unwrap();
+
+        // A second peer does not fit
+        let mbuf = from(3, 1);
+        let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+        assert_eq!(guard.check(&packet), SequenceCheck::TooOld);
+        pool.free(mbuf).unwrap();
+        assert_eq!(guard.peer_count(), 1);
+        let stats = guard.stats();
+        assert_eq!(stats.peer_limit.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.accepted.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.replays.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,270 @@
+//! Per-socket receive filters
+//!
+//! Port matching decides which socket a datagram is for; a receive filter
+//! set with [`super::UdpStack::set_rx_filter`] decides whether the socket
+//! wants it. Datagrams the filter rejects are dropped by the dispatcher
+//! with [`super::DropReason::Filtered`] and counted in the socket's
+//! `filtered` counter before they reach decryption, the replay guard, the
+//! reorder buffer or the receive queue, so unwanted datagrams cost no
+//! crypto work. Filters see the payload as it arrived on the wire.
+//!
+//! A filter is either a Rust predicate or a [`FilterProgram`] compiled
+//! from an expression of `or`-separated clauses of `and`-separated terms,
+//! each optionally negated with `not`:
+//!
+//! - `src host 10.0.0.1`, `src net 10.0.0.0/8`
+//! - `src port 5000`, `dst port 49152-65535`
+//! - `len >= 12` on the payload length, with `=`, `<`, `<=`, `>`, `>=`
+//! - `payload[4] = 0xdeadbeef`, the payload bytes at an offset
+//!
+//! `and` binds tighter than `or`, so
+//! `src net 10.0.0.0/8 and payload[0] = 0x17 or src host 192.0.2.1`
+//! accepts records from the 10/8 net or anything from one host.
+
+use super::UdpPacket;
+use crate::{Error, Result};
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+
+/// Predicate deciding which datagrams a socket accepts
+pub type RxPredicate = Box<dyn Fn(&UdpPacket) -> bool + Send + Sync>;
+
+/// Receive filter of a socket
+pub enum RxFilter {
+    /// Rust predicate registered by the application
+    Predicate(RxPredicate),
+    /// Compiled filter expression
+    Program(FilterProgram),
+}
+
+impl RxFilter {
+    /// Filter accepting the datagrams `predicate` returns true for
+    pub fn predicate<F>(predicate: F) -> Self
+    where
+        F: Fn(&UdpPacket) -> bool + Send + Sync + 'static,
+    {
+        RxFilter::Predicate(Box::new(predicate))
+    }
+
+    /// Filter accepting the datagrams `expression` matches, see [`FilterProgram::compile`]
+    pub fn compile(expression: &str) -> Result<Self> {
+        FilterProgram::compile(expression).map(RxFilter::Program)
+    }
+
+    pub fn accepts(&self, packet: &UdpPacket) -> bool {
+        match self {
+            RxFilter::Predicate(predicate) => predicate(packet),
+            RxFilter::Program(program) => program.matches(packet),
+        }
+    }
+}
+
+/// One test of a filter expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Match {
+    SrcNet { network: u32, mask: u32 },
+    SrcPort(RangeInclusive<u16>),
+    DstPort(RangeInclusive<u16>),
+    Len(RangeInclusive<usize>),
+    Payload { offset: usize, bytes: Vec<u8> },
+}
+
+impl Match {
+    fn matches(&self, packet: &UdpPacket) -> bool {
+        match self {
+            Match::SrcNet { network, mask } => {
+                u32::from(packet.ipv4_header().src_addr()) & mask == *network
+            }
+            Match::SrcPort(ports) => ports.contains(&packet.src_addr().port()),
+            Match::DstPort(ports) => ports.contains(&packet.dst_addr().port()),
+            Match::Len(lens) => lens.contains(&packet.payload().len()),
+            Match::Payload { offset, bytes } => packet
+                .payload()
+                .get(*offset..)
+                .is_some_and(|rest| rest.starts_with(bytes)),
+        }
+    }
+}
+
+/// Compiled filter expression, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterProgram {
+    expression: String,
+    /// Clauses of negatable terms, any of which must match all of its terms
+    clauses: Vec<Vec<(bool, Match)>>,
+}
+
+impl FilterProgram {
+    /// Compile `expression`, failing with [`Error::InvalidConfig`] on a syntax error
+    pub fn compile(expression: &str) -> Result<Self> {
+        let invalid =
+            |what: &str| Error::InvalidConfig(format!("Receive filter '{}': {}", expression, what));
+        let tokens: Vec<&str> = expression.split_whitespace().collect();
+        if tokens.is_empty() {
+            return Err(invalid("empty expression"));
+        }
+
+        let mut clauses = Vec::new();
+        for clause in tokens.split(|&token| token == "or") {
+            let mut terms = Vec::new();
+            for term in clause.split(|&token| token == "and") {
+                let (negated, term) = match term {
+                    ["not", rest @ ..] => (true, rest),
+                    _ => (false, term),
+                };
+                terms.push((negated, parse_term(term).map_err(|what| invalid(&what))?));
+            }
+            clauses.push(terms);
+        }
+        Ok(Self {
+            expression: expression.to_string(),
+            clauses,
+        })
+    }
+
+    /// Expression the program was compiled from
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    pub fn matches(&self, packet: &UdpPacket) -> bool {
+        self.clauses.iter().any(|clause| {
+            clause
+                .iter()
+                .all(|(negated, test)| test.matches(packet) != *negated)
+        })
+    }
+}
+
+fn parse_term(term: &[&str]) -> std::result::Result<Match, String> {
+    match term {
+        ["src", "host", addr] => Ok(Match::SrcNet {
+            network: u32::from(parse_addr(addr)?),
+            mask: u32::MAX,
+        }),
+        ["src", "net", net] => {
+            let (addr, len) = net
+                .split_once('/')
+                .ok_or_else(|| format!("'{}' is not a prefix", net))?;
+            let len: u32 = match len.parse() {
+                Ok(len) if len <= 32 => len,
+                _ => return Err(format!("bad prefix length in '{}'", net)),
+            };
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            Ok(Match::SrcNet {
+                network: u32::from(parse_addr(addr)?) & mask,
+                mask,
+            })
+        }
+        ["src", "port", ports] => parse_ports(ports).map(Match::SrcPort),
+        ["dst", "port", ports] => parse_ports(ports).map(Match::DstPort),
+        ["len", op, len] => {
+            let len: usize = len.parse().map_err(|_| format!("bad length '{}'", len))?;
+            let lens = match *op {
+                "=" => len..=len,
+                "<" if len > 0 => 0..=len - 1,
+                "<=" => 0..=len,
+                ">" => len.saturating_add(1)..=usize::MAX,
+                ">=" => len..=usize::MAX,
+                _ => return Err(format!("bad comparison 'len {} {}'", op, len)),
+            };
+            Ok(Match::Len(lens))
+        }
+        [field, "=", value] if field.starts_with("payload[") && field.ends_with(']') => {
+            let offset = field["payload[".len()..field.len() - 1]
+                .parse()
+                .map_err(|_| format!("bad payload offset in '{}'", field))?;
+            Ok(Match::Payload {
+                offset,
+                bytes: parse_hex(value)?,
+            })
+        }
+        [] => Err("missing term".to_string()),
+        _ => Err(format!("unknown term '{}'", term.join(" "))),
+    }
+}
+
+fn parse_addr(addr: &str) -> std::result::Result<Ipv4Addr, String> {
+    addr.parse()
+        .map_err(|_| format!("'{}' is not an IPv4 address", addr))
+}
+
+fn parse_ports(ports: &str) -> std::result::Result<RangeInclusive<u16>, String> {
+    let bad = || format!("bad port range '{}'", ports);
+    let (low, high) = ports.split_once('-').unwrap_or((ports, ports));
+    let low: u16 = low.parse().map_err(|_| bad())?;
+    let high: u16 = high.parse().map_err(|_| bad())?;
+    if low > high {
+        return Err(bad());
+    }
+    Ok(low..=high)
+}
+
+fn parse_hex(value: &str) -> std::result::Result<Vec<u8>, String> {
+    let bad = || format!("'{}' is not a 0x-prefixed byte string", value);
+    let digits = value.strip_prefix("0x").ok_or_else(bad)?;
+    // Sliced by byte below, so multi-byte characters never get that far
+    if digits.is_empty() || digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err(bad());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| bad()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::testing::{load, FrameBuilder};
+
+    #[test]
+    fn test_filter_programs() {
+        let pool = MbufPool::new("filter".to_string(), 4, 256).unwrap();
+        let frame = FrameBuilder::to_port(5000)
+            .payload(&[0x17, 0xFE, 0xFD, 0x00, 0x01])
+            .build();
+        let mbuf = load(&pool, &frame);
+        let packet = UdpPacket::from_mbuf(mbuf).unwrap();
+        let src = packet.ipv4_header().src_addr();
+        let src_port = packet.src_addr().port();
+
+        let matches =
+            |expression: &str| FilterProgram::compile(expression).unwrap().matches(&packet);
+        assert!(matches(&format!("src host {}", src)));
+        assert!(matches(&format!("src net {}/24", src)));
+        assert!(!matches("src net 192.0.2.0/24"));
+        assert!(matches("src net 0.0.0.0/0"));
+        assert!(matches(&format!("src port {}", src_port)));
+        assert!(matches("dst port 4000-6000 and len = 5"));
+        assert!(!matches("len < 5") && matches("len <= 5") && !matches("len > 5"));
+        assert!(matches("payload[0] = 0x17fefd"));
+        assert!(!matches("payload[3] = 0x000102"));
+        assert!(matches("not payload[0] = 0x16"));
+        assert!(matches("src net 192.0.2.0/24 or payload[1] = 0xfe"));
+        assert!(!matches(
+            "src net 192.0.2.0/24 and payload[1] = 0xfe or len > 100"
+        ));
+
+        for bad in [
+            "",
+            "src net 10.0.0.0/33",
+            "src host 10.0.0",
+            "dst port 9-1",
+            "len ~ 4",
+            "len < 0",
+            "payload[x] = 0x17",
+            "payload[0] = 0x1",
+            "payload[0] = 0xé1",
+            "src port 53 and",
+            "ttl 64",
+        ] {
+            assert!(FilterProgram::compile(bad).is_err(), "{:?}", bad);
+        }
+
+        let filter = RxFilter::predicate(|packet| packet.payload().len() > 4);
+        assert!(filter.accepts(&packet));
+        pool.free(mbuf).unwrap();
+    }
+}
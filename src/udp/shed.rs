@@ -0,0 +1,108 @@
+//! Receive queue shedding under overload
+//!
+//! A socket whose receive queue fills has to give something up, and the
+//! newest datagram is not always the right choice. The [`ShedPolicy`] set
+//! with [`super::UdpSocket::set_shed_policy`] picks what goes: the arriving
+//! datagram (tail-drop, the default), the oldest queued one (head-drop), so
+//! a reader that fell behind catches up on fresh data, or low-priority
+//! traffic ahead of time (DSCP): each class selector may only fill the
+//! queue up to its share, so best effort is refused while room is left for
+//! the classes above it. Head-drop evicts in the ring itself and DSCP
+//! shedding only compares the queue depth, so neither costs a lock.
+//!
+//! Every shed datagram is counted in [`ShedStats`] by the action that
+//! sacrificed it and by its class selector. Sockets with priority bands
+//! tail-drop within each band whatever the policy.
+
+use crate::utils::counter::Counter;
+
+/// Number of DSCP class selectors
+pub const DSCP_CLASSES: usize = 8;
+
+/// What a full receive queue gives up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShedPolicy {
+    /// Drop the arriving datagram
+    #[default]
+    TailDrop,
+    /// Evict the oldest queued datagram to make room
+    HeadDrop,
+    /// Refuse datagrams once the queue is past the share of their class selector
+    Dscp,
+}
+
+/// Queue depth datagrams with `dscp` may fill up to under [`ShedPolicy::Dscp`]
+///
+/// Best effort may fill half the queue and each class selector above it a
+/// seventh more of the rest, CS7 the whole queue.
+pub fn dscp_share(capacity: usize, dscp: u8) -> usize {
+    let class = (dscp as usize >> 3) & (DSCP_CLASSES - 1);
+    capacity - capacity / 2 * (DSCP_CLASSES - 1 - class) / (DSCP_CLASSES - 1)
+}
+
+/// Action that shed a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shed {
+    Tail,
+    Head,
+    Dscp,
+}
+
+/// Shedding counters of a socket
+#[derive(Debug, Default)]
+pub struct ShedStats {
+    /// Arriving datagrams dropped on a full queue
+    pub tail_dropped: Counter,
+    /// Queued datagrams evicted for newer ones
+    pub head_dropped: Counter,
+    /// Arriving datagrams refused over the share of their class selector
+    pub dscp_dropped: Counter,
+    by_class: [Counter; DSCP_CLASSES],
+}
+
+impl ShedStats {
+    /// Datagrams shed with class selector `class`, whatever the action
+    pub fn class_dropped(&self, class: usize) -> u64 {
+        self.by_class.get(class).map_or(0, Counter::get)
+    }
+
+    pub(crate) fn record(&self, shed: Shed, dscp: u8) {
+        match shed {
+            Shed::Tail => &self.tail_dropped,
+            Shed::Head => &self.head_dropped,
+            Shed::Dscp => &self.dscp_dropped,
+        }
+        .inc();
+        self.by_class[(dscp as usize >> 3) & (DSCP_CLASSES - 1)].inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::DSCP_EF;
+
+    #[test]
+    fn test_dscp_share_and_attribution() {
+        assert_eq!(dscp_share(64, 0), 32);
+        assert_eq!(dscp_share(64, 10), 37); // AF11
+        assert_eq!(dscp_share(64, DSCP_EF), 55);
+        assert_eq!(dscp_share(64, 56), 64); // CS7
+        assert_eq!(dscp_share(1, 0), 1);
+
+        let stats = ShedStats::default();
+        stats.record(Shed::Head, 0);
+        stats.record(Shed::Dscp, 10);
+        stats.record(Shed::Tail, DSCP_EF);
+        stats.record(Shed::Tail, DSCP_EF);
+        assert_eq!(stats.tail_dropped.get(), 2);
+        assert_eq!(stats.head_dropped.get(), 1);
+        assert_eq!(
+            (0..DSCP_CLASSES)
+                .map(|class| stats.class_dropped(class))
+                .collect::<Vec<_>>(),
+            [1, 1, 0, 0, 0, 2, 0, 0]
+        );
+        assert_eq!(stats.class_dropped(DSCP_CLASSES), 0);
+    }
+}
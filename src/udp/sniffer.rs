@@ -0,0 +1,257 @@
+//! Receive-all sniffer sockets
+//!
+//! A [`Sniffer`] opened with [`super::UdpStack::open_sniffer`] sees every
+//! frame the stack dispatches, UDP or not, before it is delivered, so a
+//! monitoring thread can watch the wire without taking frames from the
+//! sockets. Capture never blocks dispatch: a frame is skipped when the
+//! sniffer's queue is full or the monitoring thread holds it, when the
+//! optional filter rejects it, and beyond [`SnifferConfig::rate`] frames
+//! per second.
+//!
+//! Frames are copied on demand, only once they pass all of these, rather
+//! than shared: after dispatch the stack decrypts and decompresses payloads
+//! in place and hands the mbuf to the application, which a monitor reading
+//! the same buffer would race with. The copy also leaves the RX pool alone,
+//! so a slow monitor never holds mbufs the stack needs.
+
+use crate::memory::{Mbuf, PacketType};
+use crate::utils::time::{monotonic_now, Timestamp};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Frames a sniffer queues by default
+pub const DEFAULT_SNIFFER_CAPACITY: usize = 1024;
+
+/// Frames per second a sniffer captures by default
+pub const DEFAULT_SNIFFER_RATE: u64 = 10_000;
+
+/// Predicate deciding which classified frames a sniffer captures
+pub type SnifferFilter = Box<dyn Fn(&Mbuf) -> bool + Send + Sync>;
+
+/// Sniffer settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnifferConfig {
+    /// Frames queued for the monitor at most
+    pub capacity: usize,
+    /// Frames captured per second at most, 0 for no limit
+    pub rate: u64,
+}
+
+impl Default for SnifferConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_SNIFFER_CAPACITY,
+            rate: DEFAULT_SNIFFER_RATE,
+        }
+    }
+}
+
+/// Sniffer counters
+#[derive(Debug, Default)]
+pub struct SnifferStats {
+    /// Frames queued for the monitor
+    pub captured: AtomicUsize,
+    /// Bytes copied for the monitor
+    pub bytes: AtomicUsize,
+    /// Frames the filter rejected
+    pub filtered: AtomicUsize,
+    /// Frames skipped over the rate limit
+    pub rate_limited: AtomicUsize,
+    /// Frames skipped because the queue was full or busy
+    pub dropped: AtomicUsize,
+}
+
+/// Frame captured by a sniffer
+pub struct SniffedFrame {
+    data: Vec<u8>,
+    packet_type: PacketType,
+    port_id: u16,
+    queue_id: u16,
+    timestamp: Timestamp,
+}
+
+impl SniffedFrame {
+    /// Frame bytes from the Ethernet header
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Frame bytes, without copying them again
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        self.packet_type
+    }
+
+    /// Interface the frame was received on
+    pub fn port_id(&self) -> u16 {
+        self.port_id
+    }
+
+    /// RX queue the frame was received on
+    pub fn queue_id(&self) -> u16 {
+        self.queue_id
+    }
+
+    /// Receive timestamp of the frame
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+}
+
+struct SnifferState {
+    frames: VecDeque<SniffedFrame>,
+    /// Start of the current one-second rate window
+    window_start: Timestamp,
+    /// Frames captured in the current window
+    window_count: u64,
+}
+
+/// Receive-all socket of a stack
+pub struct Sniffer {
+    id: u16,
+    config: SnifferConfig,
+    filter: Option<SnifferFilter>,
+    state: Mutex<SnifferState>,
+    stats: SnifferStats,
+}
+
+impl Sniffer {
+    pub(crate) fn new(id: u16, config: SnifferConfig, filter: Option<SnifferFilter>) -> Self {
+        Self {
+            id,
+            config,
+            filter,
+            state: Mutex::new(SnifferState {
+                frames: VecDeque::with_capacity(config.capacity),
+                window_start: 0,
+                window_count: 0,
+            }),
+            stats: SnifferStats::default(),
+        }
+    }
+
+    /// Sniffer ID within its stack
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Take the oldest captured frame
+    pub fn recv(&self) -> Option<SniffedFrame> {
+        self.state.lock().frames.pop_front()
+    }
+
+    /// Frames waiting
+    pub fn len(&self) -> usize {
+        self.state.lock().frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get sniffer statistics
+    pub fn stats(&self) -> &SnifferStats {
+        &self.stats
+    }
+
+    /// Capture a copy of `mbuf`
+    pub(crate) fn capture(&self, mbuf: &Mbuf) {
+        self.capture_at(mbuf, monotonic_now());
+    }
+
+    fn capture_at(&self, mbuf: &Mbuf, now: Timestamp) {
+        if self.filter.as_ref().is_some_and(|filter| !filter(mbuf)) {
+            self.stats.filtered.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // The monitor holding the queue must not stall dispatch
+        let Some(mut state) = self.state.try_lock() else {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        if state.frames.len() >= self.config.capacity {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.config.rate > 0 {
+            if now.saturating_sub(state.window_start) >= 1_000_000_000 {
+                state.window_start = now;
+                state.window_count = 0;
+            }
+            if state.window_count >= self.config.rate {
+                self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            state.window_count += 1;
+        }
+
+        let data = mbuf.data().to_vec();
+        self.stats.bytes.fetch_add(data.len(), Ordering::Relaxed);
+        state.frames.push_back(SniffedFrame {
+            data,
+            packet_type: mbuf.packet_type,
+            port_id: mbuf.port_id,
+            queue_id: mbuf.queue_id,
+            timestamp: mbuf.timestamp,
+        });
+        self.stats.captured.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::classify;
+    use crate::udp::testing::{load, FrameBuilder};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_capture_copies_filters_and_limits() {
+        let pool = Arc::new(MbufPool::new("sniff".to_string(), 8, 256).unwrap());
+        let sniffer = Sniffer::new(
+            1,
+            SnifferConfig {
+                capacity: 2,
+                rate: 2,
+            },
+            Some(Box::new(|mbuf| mbuf.packet_type == PacketType::Udp)),
+        );
+        let mbuf = load(&pool, &FrameBuilder::to_port(53).payload(b"query").build());
+        unsafe { classify(&mut *mbuf) };
+
+        // The copy stays as captured while the stack rewrites and frees the mbuf
+        sniffer.capture_at(unsafe { &*mbuf }, 0);
+        assert_eq!(pool.share_count(mbuf), 0);
+        unsafe { (*mbuf).data_mut().fill(0) };
+        pool.free(mbuf).unwrap();
+        assert_eq!(pool.stats().in_use, 0);
+        let frame = sniffer.recv().unwrap();
+        assert!(frame.data().ends_with(b"query"));
+        let captured = frame.data().len();
+
+        // Two a second fit, and two in the queue
+        let mbuf = load(&pool, &FrameBuilder::to_port(53).build());
+        unsafe { classify(&mut *mbuf) };
+        for now in [10, 20, 1_000_000_000, 1_000_000_001] {
+            sniffer.capture_at(unsafe { &*mbuf }, now);
+        }
+        assert_eq!(sniffer.len(), 2);
+        let frame = sniffer.recv().unwrap();
+        let captured = captured + 2 * frame.data().len();
+
+        unsafe { (*mbuf).packet_type = PacketType::Ipv4 };
+        sniffer.capture_at(unsafe { &*mbuf }, 1_000_000_002);
+        let stats = sniffer.stats();
+        assert_eq!(stats.captured.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.bytes.load(Ordering::Relaxed), captured);
+        assert_eq!(stats.rate_limited.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.filtered.load(Ordering::Relaxed), 1);
+        pool.free(mbuf).unwrap();
+    }
+}
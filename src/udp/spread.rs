@@ -0,0 +1,217 @@
+//! Spreading bursts over time
+//!
+//! A batch handed to [`super::UdpSocket::send_batch_spread`] leaves as one
+//! line-rate burst unless it is paced, and the microburst overflows
+//! shallow switch buffers downstream. A [`BurstSpread`] gives each packet
+//! of the batch its own departure time on the deadline-scheduled TX path,
+//! see [`crate::TxQueue::send_at`], either a fixed rate or the batch spread
+//! evenly over an interval. The first packet leaves at once; a batch sent
+//! while the previous one is still departing starts after its last packet,
+//! so back-to-back batches keep their spacing. Packets of a batch that
+//! fail to be scheduled give their time back to the next batch.
+//!
+//! [`SpreadStats`] counts the bursts spread and the spacing applied to the
+//! packets actually scheduled.
+
+use crate::utils::counter::Counter;
+use crate::utils::time::Timestamp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How the packets of a batch are spaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BurstSpread {
+    /// Spread each batch evenly over this interval
+    Interval(Duration),
+    /// Send at most this many packets per second
+    Rate(u64),
+}
+
+impl BurstSpread {
+    /// Gap between consecutive packets of a batch of `count`
+    pub fn spacing(self, count: usize) -> Duration {
+        match self {
+            BurstSpread::Interval(interval) => interval / count.max(1) as u32,
+            BurstSpread::Rate(0) => Duration::ZERO,
+            BurstSpread::Rate(pps) => Duration::from_nanos(1_000_000_000 / pps),
+        }
+    }
+}
+
+/// Counters of a socket's spread batches
+#[derive(Debug, Default)]
+pub struct SpreadStats {
+    pub bursts: Counter,
+    /// Packets given a departure time
+    pub packets: Counter,
+    /// Sum of the gaps applied between packets
+    pub spacing_total_ns: Counter,
+    /// Sum of the time bursts waited for the previous one to depart
+    pub deferred_total_ns: Counter,
+}
+
+impl SpreadStats {
+    /// Mean gap applied between consecutive packets
+    pub fn mean_spacing(&self) -> Duration {
+        match self.packets.get().saturating_sub(self.bursts.get()) {
+            0 => Duration::ZERO,
+            gaps => Duration::from_nanos(self.spacing_total_ns.get() / gaps),
+        }
+    }
+}
+
+/// Departure times reserved for one batch
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SpreadPlan {
+    now: Timestamp,
+    start: Timestamp,
+    gap: u64,
+    count: usize,
+}
+
+impl SpreadPlan {
+    /// Departure times of the packets, in order
+    pub(crate) fn departures(&self) -> impl Iterator<Item = Timestamp> {
+        let (start, gap) = (self.start, self.gap);
+        (0..self.count as u64).map(move |i| start + i * gap)
+    }
+}
+
+/// Departure times of one socket's spread batches
+#[derive(Debug, Default)]
+pub(crate) struct Spreader {
+    /// Earliest departure of the next batch
+    next: AtomicU64,
+    stats: SpreadStats,
+}
+
+impl Spreader {
+    /// Reserve departure times for a batch of `count` sent at `now`
+    ///
+    /// Pass the plan to [`Spreader::complete`] once the batch is scheduled.
+    pub(crate) fn plan(&self, spread: BurstSpread, count: usize, now: Timestamp) -> SpreadPlan {
+        if count == 0 {
+            return SpreadPlan {
+                now,
+                start: now,
+                gap: 0,
+                count,
+            };
+        }
+        let gap = spread.spacing(count).as_nanos() as u64;
+        let span = gap * count as u64;
+        // Reserve the span after whatever batch is still departing
+        let previous = self
+            .next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                Some(next.max(now) + span)
+            })
+            .unwrap_or(now);
+        SpreadPlan {
+            now,
+            start: previous.max(now),
+            gap,
+            count,
+        }
+    }
+
+    /// Account the first `scheduled` packets of `plan` as sent
+    ///
+    /// The time reserved for the rest is given back unless a later batch
+    /// was planned meanwhile, and only scheduled packets are counted.
+    pub(crate) fn complete(&self, plan: &SpreadPlan, scheduled: usize) {
+        let scheduled = scheduled.min(plan.count);
+        if scheduled < plan.count {
+            let end = plan.start + plan.gap * plan.count as u64;
+            let used = plan.start + plan.gap * scheduled as u64;
+            let _ = self
+                .next
+                .compare_exchange(end, used, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        if scheduled == 0 {
+            return;
+        }
+        self.stats.bursts.inc();
+        self.stats.packets.add(scheduled as u64);
+        self.stats
+            .spacing_total_ns
+            .add(plan.gap * (scheduled as u64 - 1));
+        self.stats.deferred_total_ns.add(plan.start - plan.now);
+    }
+
+    pub(crate) fn stats(&self) -> &SpreadStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spread_plans_and_chains_bursts() {
+        assert_eq!(
+            BurstSpread::Interval(Duration::from_micros(100)).spacing(4),
+            Duration::from_micros(25)
+        );
+        assert_eq!(
+            BurstSpread::Rate(100_000).spacing(4),
+            Duration::from_micros(10)
+        );
+
+        let spreader = Spreader::default();
+        let spread = BurstSpread::Rate(1_000_000);
+        let send = |count, now| {
+            let plan = spreader.plan(spread, count, now);
+            spreader.complete(&plan, count);
+            plan.departures().collect::<Vec<_>>()
+        };
+        assert_eq!(send(3, 10_000), [10_000, 11_000, 12_000]);
+        // The next batch waits for the first to leave
+        assert_eq!(send(2, 10_500), [13_000, 14_000]);
+        // Later batches start at once
+        assert_eq!(send(1, 50_000), [50_000]);
+        assert!(send(0, 60_000).is_empty());
+
+        let stats = spreader.stats();
+        assert_eq!((stats.bursts.get(), stats.packets.get()), (3, 6));
+        assert_eq!(stats.mean_spacing(), Duration::from_micros(1));
+        assert_eq!(stats.deferred_total_ns.get(), 2_500);
+
+        // Unscheduled packets give their time back and are not counted
+        let plan = spreader.plan(spread, 4, 70_000);
+        spreader.complete(&plan, 1);
+        assert_eq!(send(1, 70_500), [71_000]);
+        assert_eq!((stats.bursts.get(), stats.packets.get()), (5, 8));
+    }
+
+    #[test]
+    fn test_failed_sends_do_not_defer_later_batches() {
+        use crate::memory::MbufPool;
+        use crate::poll::TxQueue;
+        use crate::udp::UdpSocket;
+        use std::sync::Arc;
+
+        let pool = Arc::new(MbufPool::new("spread".to_string(), 8, 2048).unwrap());
+        let tx_queue = Arc::new(TxQueue::in_memory(0));
+        let mut socket = UdpSocket::new("10.0.0.1:5000".parse().unwrap(), 16, 1).unwrap();
+        socket.bind_tx_pool(pool.clone());
+        socket.bind_tx_queue(tx_queue.clone());
+        let dst = "10.0.0.2:5000".parse().unwrap();
+        let batch: [(std::net::SocketAddr, &[u8]); 3] = [(dst, b"a"), (dst, b"b"), (dst, b"c")];
+        let spread = BurstSpread::Interval(Duration::from_secs(30));
+
+        // The first packet leaves at once and fails with the device
+        tx_queue.set_failing(true);
+        assert_eq!(socket.send_batch_spread(&batch, spread).unwrap(), 0);
+        let stats = socket.spread_stats();
+        assert_eq!((stats.bursts.get(), stats.packets.get()), (0, 0));
+
+        tx_queue.set_failing(false);
+        assert_eq!(socket.send_batch_spread(&batch[..1], spread).unwrap(), 1);
+        assert_eq!(tx_queue.sent_frames().len(), 1);
+        assert_eq!(stats.deferred_total_ns.get(), 0);
+        assert_eq!((stats.bursts.get(), stats.packets.get()), (1, 1));
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
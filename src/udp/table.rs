@@ -0,0 +1,145 @@
+//! Socket lookup by local address
+//!
+//! Every received datagram is matched to a socket by its destination
+//! address and the interface it arrived on. The [`SocketTable`] keys
+//! sockets by the full local address they bound, so sockets on the same
+//! port but different addresses coexist, and finds the most specific one
+//! in at most four hash probes whatever the number of sockets: the
+//! destination address on the receiving interface, the address on any
+//! interface, the wildcard address on the receiving interface, then the
+//! wildcard address on any interface.
+//!
+//! Lookups take the table by shared reference, so dispatch threads holding
+//! the stack's read lock look sockets up concurrently; binds and closes
+//! change the table under the stack's write lock.
+
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Local address a socket is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BindKey {
+    /// `None` for the wildcard address
+    ip: Option<IpAddr>,
+    port: u16,
+    /// `None` for any interface
+    device: Option<u16>,
+}
+
+impl BindKey {
+    fn new(addr: SocketAddr, device: Option<u16>) -> Self {
+        Self {
+            ip: Some(addr.ip()).filter(|ip| !ip.is_unspecified()),
+            port: addr.port(),
+            device,
+        }
+    }
+}
+
+/// Sockets of a stack by local address and interface
+#[derive(Default)]
+pub(crate) struct SocketTable {
+    binds: HashMap<BindKey, u16>,
+}
+
+impl SocketTable {
+    /// Socket bound to exactly `addr` on `device`
+    pub(crate) fn holder(&self, addr: SocketAddr, device: Option<u16>) -> Option<u16> {
+        let key = BindKey::new(addr, device);
+        self.binds.get(&key).copied()
+    }
+
+    /// Bind `socket_id` to `addr` on `device`, failing if another socket holds both
+    pub(crate) fn insert(
+        &mut self,
+        addr: SocketAddr,
+        device: Option<u16>,
+        socket_id: u16,
+    ) -> Result<()> {
+        let key = BindKey::new(addr, device);
+        match self.binds.insert(key, socket_id) {
+            Some(holder) if holder != socket_id => {
+                self.binds.insert(key, holder);
+                Err(Error::NetworkError(format!(
+                    "{} is already bound by socket {}",
+                    addr, holder
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Unbind `socket_id` from `addr` on `device`
+    pub(crate) fn remove(&mut self, addr: SocketAddr, device: Option<u16>, socket_id: u16) {
+        let key = BindKey::new(addr, device);
+        if self.binds.get(&key) == Some(&socket_id) {
+            self.binds.remove(&key);
+        }
+    }
+
+    /// Socket receiving datagrams to `dst` arriving on `device`
+    pub(crate) fn lookup(&self, dst: SocketAddr, device: u16) -> Option<u16> {
+        let ip = Some(dst.ip()).filter(|ip| !ip.is_unspecified());
+        [
+            (ip, Some(device)),
+            (ip, None),
+            (None, Some(device)),
+            (None, None),
+        ]
+        .into_iter()
+        .find_map(|(ip, device)| {
+            self.binds
+                .get(&BindKey {
+                    ip,
+                    port: dst.port(),
+                    device,
+                })
+                .copied()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_lookup_prefers_the_most_specific_bind() {
+        let mut table = SocketTable::default();
+        table.insert(addr("0.0.0.0:53"), None, 1).unwrap();
+        table.insert(addr("10.0.0.1:53"), None, 2).unwrap();
+        table.insert(addr("10.0.0.2:53"), None, 3).unwrap();
+        table.insert(addr("0.0.0.0:53"), Some(1), 4).unwrap();
+        table.insert(addr("10.0.0.1:53"), Some(1), 5).unwrap();
+        assert!(table.insert(addr("10.0.0.2:53"), None, 6).is_err());
+        assert_eq!(table.holder(addr("10.0.0.2:53"), None), Some(3));
+
+        assert_eq!(table.lookup(addr("10.0.0.1:53"), 1), Some(5));
+        assert_eq!(table.lookup(addr("10.0.0.1:53"), 0), Some(2));
+        assert_eq!(table.lookup(addr("10.0.0.2:53"), 1), Some(3));
+        assert_eq!(table.lookup(addr("10.0.0.9:53"), 1), Some(4));
+        assert_eq!(table.lookup(addr("10.0.0.9:53"), 0), Some(1));
+        assert_eq!(table.lookup(addr("10.0.0.1:54"), 0), None);
+
+        table.remove(addr("0.0.0.0:53"), None, 1);
+        assert_eq!(table.lookup(addr("10.0.0.9:53"), 0), None);
+        // Only the holder unbinds an address
+        table.remove(addr("10.0.0.1:53"), None, 9);
+        assert_eq!(table.holder(addr("10.0.0.1:53"), None), Some(2));
+
+        // Thousands of sockets
+        for port in 10_000..20_000 {
+            table
+                .insert(addr(&format!("10.0.0.1:{}", port)), None, port)
+                .unwrap();
+        }
+        assert_eq!(table.lookup(addr("10.0.0.1:15000"), 0), Some(15_000));
+        assert_eq!(table.lookup(addr("10.0.0.2:19999"), 3), None);
+        assert_eq!(table.binds.len(), 10_004);
+    }
+}
@@ -0,0 +1,184 @@
+//! Precomputed TX header templates
+//!
+//! Sending a small datagram is dominated by building its Ethernet, IPv4 and
+//! UDP headers. A [`HeaderTemplate`] holds those headers for one destination
+//! with the per-packet fields zeroed, along with the IPv4 checksum over the
+//! fixed fields, so that a send only copies the template and patches the
+//! lengths, identification and header checksum. Sockets cache a template per
+//! destination and drop them when the MAC addresses, DF setting or device
+//! binding change, or when told the neighbor or route towards a destination
+//! changed.
+
+use super::checksum::ones_complement;
+use super::{EthernetHeader, Ipv4Header, UdpHeader, ETHERTYPE_IPV4, IPV4_DF, TX_HEADROOM};
+use crate::Result;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Destinations a socket keeps templates for before starting over
+pub const MAX_HEADER_TEMPLATES: usize = 256;
+
+const IP_OFFSET: usize = EthernetHeader::LEN;
+const UDP_OFFSET: usize = IP_OFFSET + Ipv4Header::LEN;
+
+/// Ethernet, IPv4 and UDP headers towards one destination
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderTemplate {
+    headers: [u8; TX_HEADROOM],
+    /// Ones' complement sum of the IPv4 header with the patched fields zeroed
+    ip_sum: u16,
+}
+
+impl HeaderTemplate {
+    /// Build the template for datagrams from `src` to `dst`
+    pub fn new(
+        src_mac: [u8; 6],
+        dst_mac: [u8; 6],
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        dont_fragment: bool,
+    ) -> Self {
+        let mut ip_header = Ipv4Header::new(*src.ip(), *dst.ip(), 0);
+        ip_header.total_length = 0;
+        if dont_fragment {
+            ip_header.flags_fragment = IPV4_DF.to_be();
+        }
+        let ip_bytes = ip_header.to_bytes();
+
+        let mut headers = [0; TX_HEADROOM];
+        headers[..IP_OFFSET]
+            .copy_from_slice(&EthernetHeader::new(src_mac, dst_mac, ETHERTYPE_IPV4).to_bytes());
+        headers[IP_OFFSET..UDP_OFFSET].copy_from_slice(&ip_bytes);
+        headers[UDP_OFFSET..]
+            .copy_from_slice(&UdpHeader::new(src.port(), dst.port(), 0).to_bytes());
+
+        Self {
+            headers,
+            ip_sum: ones_complement(0, &ip_bytes),
+        }
+    }
+
+    /// Send with IPv4 TTL `ttl` instead of the default 64
+    pub fn with_ttl(mut self, ttl: u8) -> Self {
+        self.headers[IP_OFFSET + 8] = ttl;
+        self.ip_sum = ones_complement(0, &self.headers[IP_OFFSET..UDP_OFFSET]);
+        self
+    }
+
+    /// Mark sent datagrams with `dscp`, keeping the ECN bits clear
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        self.headers[IP_OFFSET + 1] = (dscp & 0x3F) << 2;
+        self.ip_sum = ones_complement(0, &self.headers[IP_OFFSET..UDP_OFFSET]);
+        self
+    }
+
+    /// Write the headers of a datagram with `payload_len` bytes of payload
+    ///
+    /// `frame` starts at the Ethernet header and must hold at least
+    /// [`TX_HEADROOM`] bytes.
+    pub fn write(&self, frame: &mut [u8], payload_len: usize, identification: u16) {
+        let udp_length = (UdpHeader::LEN + payload_len) as u16;
+        let total_length = (Ipv4Header::LEN as u16).wrapping_add(udp_length);
+        let [len_hi, len_lo] = total_length.to_be_bytes();
+        let [id_hi, id_lo] = identification.to_be_bytes();
+        let checksum = !ones_complement(self.ip_sum, &[len_hi, len_lo, id_hi, id_lo]);
+
+        let frame = &mut frame[..TX_HEADROOM];
+        frame.copy_from_slice(&self.headers);
+        frame[IP_OFFSET + 2..IP_OFFSET + 4].copy_from_slice(&total_length.to_be_bytes());
+        frame[IP_OFFSET + 4..IP_OFFSET + 6].copy_from_slice(&identification.to_be_bytes());
+        frame[IP_OFFSET + 10..IP_OFFSET + 12].copy_from_slice(&checksum.to_be_bytes());
+        frame[UDP_OFFSET + 4..UDP_OFFSET + 6].copy_from_slice(&udp_length.to_be_bytes());
+    }
+}
+
+/// Template cache hit and miss counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TemplateStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+}
+
+/// Header templates of one socket, by destination
+#[derive(Debug, Default)]
+pub(crate) struct TemplateCache {
+    templates: Mutex<HashMap<SocketAddrV4, HeaderTemplate>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl TemplateCache {
+    /// Template towards `dst`, built with `build` on a miss
+    pub(crate) fn get_or_build<F>(&self, dst: SocketAddrV4, build: F) -> Result<HeaderTemplate>
+    where
+        F: FnOnce() -> Result<HeaderTemplate>,
+    {
+        let mut templates = self.templates.lock();
+        if let Some(template) = templates.get(&dst) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(*template);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if templates.len() >= MAX_HEADER_TEMPLATES {
+            templates.clear();
+        }
+        let template = build()?;
+        templates.insert(dst, template);
+        Ok(template)
+    }
+
+    /// Drop the template towards `dst`, or every template if `None`
+    pub(crate) fn invalidate(&self, dst: Option<SocketAddrV4>) {
+        let mut templates = self.templates.lock();
+        match dst {
+            Some(dst) => {
+                templates.remove(&dst);
+            }
+            None => templates.clear(),
+        }
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> TemplateStats {
+        TemplateStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_template_matches_built_headers() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6000);
+        let template = HeaderTemplate::new([1; 6], [2; 6], src, dst, true);
+
+        let mut frame = [0; TX_HEADROOM];
+        template.write(&mut frame, 100, 0x1234);
+
+        let mut ip_header = Ipv4Header::new(*src.ip(), *dst.ip(), 108);
+        ip_header.identification = 0x1234u16.to_be();
+        ip_header.flags_fragment = IPV4_DF.to_be();
+        ip_header.checksum = ip_header.compute_checksum().to_be();
+        assert_eq!(frame[IP_OFFSET..UDP_OFFSET], ip_header.to_bytes());
+        assert_eq!(
+            frame[UDP_OFFSET..],
+            UdpHeader::new(5000, 6000, 108).to_bytes()
+        );
+        assert_eq!(
+            frame[..IP_OFFSET],
+            EthernetHeader::new([1; 6], [2; 6], ETHERTYPE_IPV4).to_bytes()
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! Test support: crafted Ethernet/IPv4/UDP frames
+//!
+//! [`FrameBuilder`] produces wire-correct frames with valid checksums, and
+//! [`Malformation`] variants of them that the receive path must reject. The
+//! tests at the bottom drive whole frames from an mbuf through stack
+//! dispatch to socket `recv`.
+
+use super::checksum::ones_complement;
+use super::IPPROTO_UDP;
+use crate::memory::{Mbuf, MbufPool};
+use crate::packet::builder::{EthernetBuilder, Ipv4Builder, UdpBuilder};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+const ETH_LEN: usize = 14;
+const IPV4_LEN: usize = 20;
+const UDP_LEN: usize = 8;
+
+/// Ways a crafted frame can be broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Malformation {
+    /// Cut off inside the UDP header
+    Truncated,
+    /// EtherType other than IPv4
+    NotIpv4,
+    /// IHL below the 5-word minimum
+    ShortIhl,
+    /// IP protocol other than UDP
+    NotUdp,
+    /// Non-first fragment
+    Fragment,
+    /// UDP length pointing past the end of the frame
+    UdpLengthOverrun,
+    /// Cut off inside the payload, as by a short snaplen
+    Snapped,
+    /// Corrupted IPv4 header checksum
+    BadIpChecksum,
+    /// Corrupted UDP checksum
+    BadUdpChecksum,
+}
+
+/// Builder for a single IPv4/UDP frame
+#[derive(Debug, Clone)]
+pub(crate) struct FrameBuilder {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    dscp: u8,
+    ttl: u8,
+    payload: Vec<u8>,
+}
+
+impl FrameBuilder {
+    /// Frame from `src` to `dst` with an empty payload
+    pub(crate) fn new(src: SocketAddrV4, dst: SocketAddrV4) -> Self {
+        Self {
+            src,
+            dst,
+            dscp: 0,
+            ttl: 64,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Frame from 10.0.0.2:6000 to 10.0.0.1 at `dst_port`
+    pub(crate) fn to_port(dst_port: u16) -> Self {
+        Self::new(
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6000),
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), dst_port),
+        )
+    }
+
+    pub(crate) fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    pub(crate) fn dscp(mut self, dscp: u8) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    pub(crate) fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Source address as the receiver reports it
+    pub(crate) fn src_addr(&self) -> SocketAddr {
+        SocketAddr::V4(self.src)
+    }
+
+    /// Well-formed frame with valid checksums
+    pub(crate) fn build(&self) -> Vec<u8> {
+        EthernetBuilder::default()
+            .dst([0x02, 0, 0, 0, 0, 0x01])
+            .src([0x02, 0, 0, 0, 0, 0x02])
+            .ipv4(
+                Ipv4Builder::new(*self.src.ip(), *self.dst.ip())
+                    .dscp(self.dscp)
+                    .ttl(self.ttl)
+                    .udp(UdpBuilder::new(self.src.port(), self.dst.port()).payload(&self.payload)),
+            )
+            .build()
+            .expect("payload too large for a frame")
+    }
+
+    /// Frame broken in one specific way
+    pub(crate) fn malformed(&self, malformation: Malformation) -> Vec<u8> {
+        let mut frame = self.build();
+        let ip = ETH_LEN;
+        let udp = ETH_LEN + IPV4_LEN;
+
+        match malformation {
+            Malformation::Truncated => frame.truncate(udp + UDP_LEN / 2),
+            Malformation::NotIpv4 => frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes()),
+            Malformation::ShortIhl => frame[ip] = 0x44,
+            Malformation::NotUdp => frame[ip + 9] = super::IPPROTO_TCP,
+            Malformation::Fragment => {
+                frame[ip + 6..ip + 8].copy_from_slice(&0x0010u16.to_be_bytes())
+            }
+            Malformation::UdpLengthOverrun => {
+                let overrun = (frame.len() - udp + 16) as u16;
+                frame[udp + 4..udp + 6].copy_from_slice(&overrun.to_be_bytes());
+            }
+            Malformation::Snapped => frame.truncate(udp + UDP_LEN + 1),
+            Malformation::BadIpChecksum => frame[ip + 10] ^= 0xFF,
+            Malformation::BadUdpChecksum => frame[udp + 6] ^= 0xFF,
+        }
+        if !matches!(
+            malformation,
+            Malformation::BadIpChecksum
+                | Malformation::BadUdpChecksum
+                | Malformation::Truncated
+                | Malformation::Snapped
+        ) {
+            fill_checksums(&mut frame);
+        }
+        frame
+    }
+}
+
+/// Recompute the IPv4 and UDP checksums of a frame in place
+pub(crate) fn fill_checksums(frame: &mut [u8]) {
+    let ip = ETH_LEN;
+    let ihl = ((frame[ip] & 0x0F) as usize * 4).max(IPV4_LEN);
+    frame[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+    let ip_sum = !ones_complement(0, &frame[ip..ip + ihl]);
+    frame[ip + 10..ip + 12].copy_from_slice(&ip_sum.to_be_bytes());
+
+    let udp = ip + ihl;
+    let udp_len = frame.len() - udp;
+    frame[udp + 6..udp + 8].copy_from_slice(&[0, 0]);
+    let mut pseudo = [0u8; 12];
+    pseudo[..8].copy_from_slice(&frame[ip + 12..ip + 20]);
+    pseudo[9] = IPPROTO_UDP;
+    pseudo[10..].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    let udp_sum = !ones_complement(ones_complement(0, &pseudo), &frame[udp..]);
+    frame[udp + 6..udp + 8].copy_from_slice(&udp_sum.to_be_bytes());
+}
+
+/// Copy a frame into a fresh mbuf from `pool`
+pub(crate) fn load(pool: &MbufPool, frame: &[u8]) -> *mut Mbuf {
+    let mbuf = pool.alloc().expect("test pool exhausted");
+    unsafe { (*mbuf).append(frame).expect("frame larger than mbuf") };
+    mbuf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::{
+        classify, ChecksumTrust, ChecksumValidator, Delivery, DropReason, UdpPacket, UdpStack,
+    };
+    use crate::Config;
+    use std::net::IpAddr;
+
+    fn running_stack(ports: &[u16]) -> (UdpStack, Vec<u16>) {
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        let ids = ports
+            .iter()
+            .map(|&port| {
+                let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port);
+                stack.create_socket(local).unwrap()
+            })
+            .collect();
+        stack.start().unwrap();
+        (stack, ids)
+    }
+
+    #[test]
+    fn test_dispatch_to_recv_preserves_payload() {
+        let pool = MbufPool::new("rx".to_string(), 8, 2048).unwrap();
+        let (stack, ids) = running_stack(&[5000, 5001]);
+
+        let first = FrameBuilder::to_port(5000).payload(b"first datagram");
+        let second = FrameBuilder::to_port(5001).payload(&[0xA5; 1200]);
+        assert!(stack.dispatch(load(&pool, &first.build())).is_delivered());
+        assert!(stack.dispatch(load(&pool, &second.build())).is_delivered());
+
+        let packet = stack.get_socket(ids[0]).unwrap().recv().unwrap();
+        assert_eq!(packet.payload(), b"first datagram");
+        assert_eq!(packet.src_addr(), first.src_addr());
+        assert_eq!(packet.dst_addr().port(), 5000);
+        pool.free(packet.mbuf).unwrap();
+
+        let socket = stack.get_socket(ids[1]).unwrap();
+        let packet = socket.recv().unwrap();
+        assert_eq!(packet.payload(), &[0xA5; 1200][..]);
+        pool.free(packet.mbuf).unwrap();
+        assert!(socket.recv().is_err());
+
+        assert_eq!(stack.stats().total_packets_received, 2);
+        assert_eq!(pool.stats().available, 8);
+    }
+
+    #[test]
+    fn test_unmatched_and_malformed_frames_are_dropped() {
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let (stack, ids) = running_stack(&[5000]);
+
+        let mbuf = load(&pool, &FrameBuilder::to_port(5999).build());
+        assert_eq!(
+            stack.dispatch(mbuf),
+            Delivery::Dropped(DropReason::NoSocket)
+        );
+        pool.free(mbuf).unwrap();
+
+        let builder = FrameBuilder::to_port(5000).payload(b"payload");
+        for malformation in [
+            Malformation::Truncated,
+            Malformation::NotIpv4,
+            Malformation::ShortIhl,
+            Malformation::NotUdp,
+            Malformation::Fragment,
+            Malformation::UdpLengthOverrun,
+        ] {
+            let mbuf = load(&pool, &builder.malformed(malformation));
+            assert_eq!(
+                stack.dispatch(mbuf),
+                Delivery::Dropped(DropReason::Malformed),
+                "{:?}",
+                malformation
+            );
+            pool.free(mbuf).unwrap();
+        }
+
+        let socket = stack.get_socket(ids[0]).unwrap();
+        assert!(socket.recv().is_err());
+        assert_eq!(socket.stats().packets_received.get(), 0);
+        assert_eq!(stack.stats().total_packets_received, 0);
+        assert_eq!(pool.stats().available, 4);
+    }
+
+    #[test]
+    fn test_crafted_checksums() {
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let validator = ChecksumValidator::default();
+        let builder = FrameBuilder::to_port(5000).payload(b"odd").dscp(46);
+
+        let cases = [
+            (builder.build(), true),
+            (builder.malformed(Malformation::BadIpChecksum), false),
+            (builder.malformed(Malformation::BadUdpChecksum), false),
+        ];
+        for (frame, good) in cases {
+            let mbuf = load(&pool, &frame);
+            let mbuf_ref = unsafe { &mut *mbuf };
+            classify(mbuf_ref);
+            assert!(UdpPacket::from_mbuf(mbuf).is_ok());
+            assert_eq!(validator.validate(mbuf_ref, ChecksumTrust::Verify), good);
+            pool.free(mbuf).unwrap();
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Per-socket payload transforms
+//!
+//! A [`PayloadTransform`] decrypts UDP payloads in place before they are
+//! queued on a socket and encrypts them in place before they are sent, so
+//! that applications terminating DTLS or WireGuard-style protocols only see
+//! plaintext. Transmit buffers of a socket with a transform reserve
+//! [`PayloadTransform::overhead`] bytes of tailroom for headers and tags,
+//! keeping sends zero-copy. Frames that fail to decrypt are dropped with
+//! [`super::DropReason::Decrypt`] and counted in [`TransformStats`].
+
+use crate::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// In-place payload decryption and encryption
+pub trait PayloadTransform: Send + Sync {
+    /// Bytes a ciphertext is longer than its plaintext at most
+    fn overhead(&self) -> usize;
+
+    /// Decrypt `payload` in place, returning the plaintext length
+    ///
+    /// The plaintext is left at the start of `payload`.
+    fn decrypt(&self, payload: &mut [u8]) -> Result<usize>;
+
+    /// Encrypt the first `len` bytes of `buffer` in place, returning the ciphertext length
+    ///
+    /// `buffer` holds `len + overhead()` bytes.
+    fn encrypt(&self, buffer: &mut [u8], len: usize) -> Result<usize>;
+}
+
+/// Payload transform counters
+#[derive(Debug, Default)]
+pub struct TransformStats {
+    pub decrypted: AtomicUsize,
+    pub decrypt_errors: AtomicUsize,
+    pub encrypted: AtomicUsize,
+    pub encrypt_errors: AtomicUsize,
+}
+
+impl TransformStats {
+    /// Count the outcome of a decryption
+    pub(crate) fn record_decrypt<T>(&self, result: &Result<T>) {
+        let counter = match result {
+            Ok(_) => &self.decrypted,
+            Err(_) => &self.decrypt_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of an encryption
+    pub(crate) fn record_encrypt<T>(&self, result: &Result<T>) {
+        let counter = match result {
+            Ok(_) => &self.encrypted,
+            Err(_) => &self.encrypt_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,326 @@
+//! Threshold alarms over stats summaries
+//!
+//! An [`AlarmRule`] watches one metric of the [`StatsSummary`] a
+//! [`super::sampler::StatsSampler`] computes every interval, such as the
+//! drop rate or the fraction of the pool free. The alarm triggers once the
+//! metric has been past its threshold for the rule's duration, counted from
+//! the start of the first interval past it, and clears once the metric is
+//! back past the threshold by more than the rule's hysteresis, so a metric
+//! hovering around the threshold does not flap. Intervals in which no
+//! counter moved are evaluated too, so a rate falling to zero is seen.
+//!
+//! Transitions are logged, triggers at WARN and clears at INFO under the
+//! `xpdk::alarm` target, and handed to the listeners of the [`AlarmSet`].
+
+use super::sampler::StatsSummary;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Quantity an alarm watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmMetric {
+    PacketsPerSec,
+    MbitsPerSec,
+    /// Fraction of the packets of an interval that were dropped
+    DropRate,
+    /// Errors in one interval
+    Errors,
+    /// Fraction of the mbuf pool not in use
+    PoolAvailable,
+}
+
+impl AlarmMetric {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlarmMetric::PacketsPerSec => "pps",
+            AlarmMetric::MbitsPerSec => "mbps",
+            AlarmMetric::DropRate => "drop_rate",
+            AlarmMetric::Errors => "errors",
+            AlarmMetric::PoolAvailable => "pool_available",
+        }
+    }
+
+    /// Value of the metric over the interval of `summary`
+    pub fn value(self, summary: &StatsSummary) -> f64 {
+        match self {
+            AlarmMetric::PacketsPerSec => summary.packets_per_sec,
+            AlarmMetric::MbitsPerSec => summary.mbits_per_sec,
+            AlarmMetric::DropRate => {
+                let offered = summary.packets + summary.drops;
+                match offered {
+                    0 => 0.0,
+                    _ => summary.drops as f64 / offered as f64,
+                }
+            }
+            AlarmMetric::Errors => summary.errors as f64,
+            AlarmMetric::PoolAvailable => match summary.pool_size {
+                0 => 1.0,
+                size => size.saturating_sub(summary.pool_in_use) as f64 / size as f64,
+            },
+        }
+    }
+}
+
+impl fmt::Display for AlarmMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Condition of an alarm rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmRule {
+    /// Name in events and logs
+    pub name: String,
+    pub metric: AlarmMetric,
+    /// Trigger on values above the threshold, or below it if false
+    pub above: bool,
+    pub threshold: f64,
+    /// Time the metric must stay past the threshold to trigger
+    pub duration: Duration,
+    /// Distance back past the threshold at which the alarm clears
+    pub hysteresis: f64,
+}
+
+impl AlarmRule {
+    /// Alarm named `name` on `metric` rising above `threshold`
+    pub fn above(name: &str, metric: AlarmMetric, threshold: f64) -> Self {
+        Self {
+            name: name.to_string(),
+            metric,
+            above: true,
+            threshold,
+            duration: Duration::ZERO,
+            hysteresis: 0.0,
+        }
+    }
+
+    /// Alarm named `name` on `metric` falling below `threshold`
+    pub fn below(name: &str, metric: AlarmMetric, threshold: f64) -> Self {
+        Self {
+            above: false,
+            ..Self::above(name, metric, threshold)
+        }
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn with_hysteresis(mut self, hysteresis: f64) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    fn breached(&self, value: f64) -> bool {
+        match self.above {
+            true => value > self.threshold,
+            false => value < self.threshold,
+        }
+    }
+
+    fn cleared(&self, value: f64) -> bool {
+        match self.above {
+            true => value <= self.threshold - self.hysteresis,
+            false => value >= self.threshold + self.hysteresis,
+        }
+    }
+}
+
+/// Transition of an alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmState {
+    Triggered,
+    Cleared,
+}
+
+/// Alarm triggering or clearing
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmEvent {
+    /// Name of the rule
+    pub rule: String,
+    pub state: AlarmState,
+    pub metric: AlarmMetric,
+    /// Value of the metric in the interval that caused the transition
+    pub value: f64,
+    pub threshold: f64,
+}
+
+impl fmt::Display for AlarmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            AlarmState::Triggered => "triggered",
+            AlarmState::Cleared => "cleared",
+        };
+        write!(
+            f,
+            "alarm {} {}: {}={} threshold={}",
+            self.rule, state, self.metric, self.value, self.threshold
+        )
+    }
+}
+
+/// Callback invoked on every alarm transition
+pub type AlarmListener = Box<dyn Fn(&AlarmEvent) + Send + Sync>;
+
+/// Rule and where it stands
+struct RuleState {
+    rule: AlarmRule,
+    /// Start of the run of intervals past the threshold
+    breached_since: Option<Instant>,
+    active: bool,
+}
+
+/// Alarm rules evaluated together over the same summaries
+#[derive(Default)]
+pub struct AlarmSet {
+    rules: Vec<RuleState>,
+    listeners: Vec<AlarmListener>,
+}
+
+impl AlarmSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(mut self, rule: AlarmRule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    pub fn add_rule(&mut self, rule: AlarmRule) {
+        self.rules.push(RuleState {
+            rule,
+            breached_since: None,
+            active: false,
+        });
+    }
+
+    /// Call `listener` on every transition
+    pub fn on_event<F>(&mut self, listener: F)
+    where
+        F: Fn(&AlarmEvent) + Send + Sync + 'static,
+    {
+        self.listeners.push(Box::new(listener));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Names of the alarms currently triggered
+    pub fn active(&self) -> Vec<&str> {
+        self.rules
+            .iter()
+            .filter(|state| state.active)
+            .map(|state| state.rule.name.as_str())
+            .collect()
+    }
+
+    /// Evaluate every rule over `summary` of the interval ending `now`, returning the transitions
+    pub fn evaluate(&mut self, summary: &StatsSummary, now: Instant) -> Vec<AlarmEvent> {
+        let start = now.checked_sub(summary.interval).unwrap_or(now);
+        let mut events = Vec::new();
+        for state in &mut self.rules {
+            let rule = &state.rule;
+            let value = rule.metric.value(summary);
+            let transition = if state.active {
+                rule.cleared(value).then_some(AlarmState::Cleared)
+            } else if rule.breached(value) {
+                let since = *state.breached_since.get_or_insert(start);
+                (now.duration_since(since) >= rule.duration).then_some(AlarmState::Triggered)
+            } else {
+                state.breached_since = None;
+                None
+            };
+            let Some(transition) = transition else {
+                continue;
+            };
+            state.active = transition == AlarmState::Triggered;
+            state.breached_since = None;
+            events.push(AlarmEvent {
+                rule: rule.name.clone(),
+                state: transition,
+                metric: rule.metric,
+                value,
+                threshold: rule.threshold,
+            });
+        }
+
+        for event in &events {
+            match event.state {
+                AlarmState::Triggered => log::warn!(target: "xpdk::alarm", "{}", event),
+                AlarmState::Cleared => log::info!(target: "xpdk::alarm", "{}", event),
+            }
+            for listener in &self.listeners {
+                listener(event);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn summary(packets: u64, drops: u64, pool_in_use: usize) -> StatsSummary {
+        StatsSummary {
+            packets_per_sec: packets as f64,
+            mbits_per_sec: 0.0,
+            packets,
+            drops,
+            errors: 0,
+            pool_in_use,
+            pool_size: 100,
+            interval: Duration::from_secs(1),
+            level: Level::Info,
+        }
+    }
+
+    #[test]
+    fn test_alarms_trigger_after_duration_and_clear_with_hysteresis() {
+        let mut alarms = AlarmSet::new()
+            .with_rule(
+                AlarmRule::above("drops", AlarmMetric::DropRate, 0.01)
+                    .with_duration(Duration::from_secs(3))
+                    .with_hysteresis(0.005),
+            )
+            .with_rule(AlarmRule::below("pool", AlarmMetric::PoolAvailable, 0.1));
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        alarms.on_event(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        // 2% dropped for two intervals is not yet three seconds
+        assert!(alarms.evaluate(&summary(980, 20, 0), at(1)).is_empty());
+        assert!(alarms.evaluate(&summary(980, 20, 0), at(2)).is_empty());
+        let events = alarms.evaluate(&summary(980, 20, 95), at(3));
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].rule.as_str(), events[0].state),
+            ("drops", AlarmState::Triggered)
+        );
+        assert_eq!(alarms.active(), ["drops", "pool"]);
+
+        // 0.8% is under the threshold but within the hysteresis
+        assert!(alarms.evaluate(&summary(992, 8, 95), at(4)).is_empty());
+        let events = alarms.evaluate(&summary(1000, 0, 50), at(5));
+        assert!(events.iter().all(|e| e.state == AlarmState::Cleared));
+        assert!(alarms.active().is_empty());
+        assert_eq!(seen.load(Ordering::Relaxed), 4);
+
+        // A run broken by one good interval starts over
+        alarms.evaluate(&summary(980, 20, 0), at(6));
+        alarms.evaluate(&summary(1000, 0, 0), at(7));
+        alarms.evaluate(&summary(980, 20, 0), at(8));
+        assert!(alarms.evaluate(&summary(980, 20, 0), at(9)).is_empty());
+    }
+}
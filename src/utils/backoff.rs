@@ -0,0 +1,228 @@
+//! Idle strategies for polling loops
+//!
+//! A loop polling an empty queue chooses between latency and CPU: spinning
+//! picks up the next packet at once, sleeping gives the core back. An
+//! [`IdleStrategy`] sets the trade-off per queue, see
+//! [`crate::QueueConfig::with_idle_strategy`]: after the last packet the
+//! loop spins for [`IdleStrategy::spin`], then yields its time slice
+//! [`IdleStrategy::yields`] times, then sleeps, doubling the sleep from
+//! [`MIN_SLEEP`] up to [`IdleStrategy::max_sleep`]. A [`Backoff`] walks
+//! these steps for one loop and starts over when work turns up.
+//!
+//! With [`IdleStrategy::adaptive`] the spin follows recent traffic: the
+//! loop keeps an average of the gaps after which packets arrived and spins
+//! for twice that, within the configured spin, or hardly at all when
+//! packets arrive further apart than the loop would spin.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// First sleep once spinning and yielding are done
+pub const MIN_SLEEP: Duration = Duration::from_micros(10);
+
+/// Shortest spin of an adaptive strategy
+pub const MIN_ADAPTIVE_SPIN: Duration = Duration::from_micros(1);
+
+/// How a polling loop waits for work
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStrategy {
+    /// Time spent spinning on an empty queue before yielding
+    pub spin: Duration,
+    /// Time slices yielded before sleeping
+    pub yields: u32,
+    /// Longest sleep between polls
+    pub max_sleep: Duration,
+    /// Scale the spin to the gaps between recent packets
+    pub adaptive: bool,
+}
+
+impl Default for IdleStrategy {
+    fn default() -> Self {
+        Self {
+            spin: Duration::from_micros(20),
+            yields: 10,
+            max_sleep: Duration::from_micros(100),
+            adaptive: false,
+        }
+    }
+}
+
+impl IdleStrategy {
+    /// Spin without ever giving up the core
+    pub fn busy_poll() -> Self {
+        Self {
+            spin: Duration::MAX,
+            yields: 0,
+            max_sleep: Duration::ZERO,
+            adaptive: false,
+        }
+    }
+
+    /// Sleep up to `max_sleep` as soon as the queue is empty
+    pub fn sleep(max_sleep: Duration) -> Self {
+        Self {
+            spin: Duration::ZERO,
+            yields: 0,
+            max_sleep,
+            adaptive: false,
+        }
+    }
+
+    pub fn with_adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Strategy of a loop serving queues of both strategies, as eager as the more eager
+    pub fn eager(self, other: IdleStrategy) -> Self {
+        Self {
+            spin: self.spin.max(other.spin),
+            yields: self.yields.max(other.yields),
+            max_sleep: self.max_sleep.min(other.max_sleep),
+            adaptive: self.adaptive || other.adaptive,
+        }
+    }
+}
+
+/// Where a [`Backoff`] is in its strategy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleStage {
+    Spin,
+    Yield,
+    Sleep(Duration),
+}
+
+/// Idle state of one polling loop
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    strategy: IdleStrategy,
+    /// Start of the current run of empty polls
+    idle_since: Option<Instant>,
+    yielded: u32,
+    sleep: Duration,
+    /// Average gap after which work arrived, for adaptive spinning
+    average_gap: Duration,
+}
+
+impl Backoff {
+    pub fn new(strategy: IdleStrategy) -> Self {
+        Self {
+            strategy,
+            idle_since: None,
+            yielded: 0,
+            sleep: MIN_SLEEP,
+            average_gap: Duration::ZERO,
+        }
+    }
+
+    pub fn strategy(&self) -> IdleStrategy {
+        self.strategy
+    }
+
+    /// Account for a poll that found `work` items, waiting if it found none
+    pub fn after_poll(&mut self, work: usize) {
+        if work > 0 {
+            self.reset();
+        } else {
+            self.idle();
+        }
+    }
+
+    /// Work turned up: start the strategy over
+    pub fn reset(&mut self) {
+        if let Some(since) = self.idle_since.take() {
+            // Moving average over the last 8 gaps
+            self.average_gap = (self.average_gap * 7 + since.elapsed()) / 8;
+        }
+        self.yielded = 0;
+        self.sleep = MIN_SLEEP;
+    }
+
+    /// Wait as the strategy says after an empty poll
+    pub fn idle(&mut self) {
+        match self.next_stage() {
+            IdleStage::Spin => std::hint::spin_loop(),
+            IdleStage::Yield => thread::yield_now(),
+            IdleStage::Sleep(sleep) => thread::sleep(sleep),
+        }
+    }
+
+    /// Advance to the stage of the next wait without waiting
+    pub fn next_stage(&mut self) -> IdleStage {
+        let since = *self.idle_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < self.spin() {
+            return IdleStage::Spin;
+        }
+        if self.yielded < self.strategy.yields {
+            self.yielded += 1;
+            return IdleStage::Yield;
+        }
+        if self.strategy.max_sleep.is_zero() {
+            return IdleStage::Spin;
+        }
+        let sleep = self.sleep.min(self.strategy.max_sleep);
+        self.sleep = sleep.saturating_mul(2);
+        IdleStage::Sleep(sleep)
+    }
+
+    /// Time to spin after the last work
+    pub fn spin(&self) -> Duration {
+        let spin = self.strategy.spin;
+        if !self.strategy.adaptive {
+            return spin;
+        }
+        if self.average_gap > spin {
+            // Work comes later than spinning would wait
+            return MIN_ADAPTIVE_SPIN.min(spin);
+        }
+        self.average_gap
+            .saturating_mul(2)
+            .clamp(MIN_ADAPTIVE_SPIN.min(spin), spin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_stages() {
+        let strategy = IdleStrategy {
+            spin: Duration::ZERO,
+            yields: 2,
+            max_sleep: Duration::from_micros(30),
+            adaptive: false,
+        };
+        let mut backoff = Backoff::new(strategy);
+        let stages: Vec<_> = (0..6).map(|_| backoff.next_stage()).collect();
+        let sleep = |us| IdleStage::Sleep(Duration::from_micros(us));
+        assert_eq!(
+            stages,
+            [
+                IdleStage::Yield,
+                IdleStage::Yield,
+                sleep(10),
+                sleep(20),
+                sleep(30),
+                sleep(30)
+            ]
+        );
+        backoff.reset();
+        assert_eq!(backoff.next_stage(), IdleStage::Yield);
+
+        let mut busy = Backoff::new(IdleStrategy::busy_poll());
+        assert!((0..100).all(|_| busy.next_stage() == IdleStage::Spin));
+
+        // Packets a few microseconds apart keep an adaptive loop spinning
+        // for a little longer than that, sparse ones stop it spinning
+        let mut adaptive = Backoff::new(IdleStrategy::default().with_adaptive(true));
+        adaptive.average_gap = Duration::from_micros(3);
+        assert_eq!(adaptive.spin(), Duration::from_micros(6));
+        adaptive.average_gap = Duration::from_millis(5);
+        assert_eq!(adaptive.spin(), MIN_ADAPTIVE_SPIN);
+
+        let eager = IdleStrategy::sleep(Duration::from_millis(1)).eager(IdleStrategy::default());
+        assert_eq!(eager.spin, Duration::from_micros(20));
+        assert_eq!(eager.max_sleep, Duration::from_micros(100));
+    }
+}
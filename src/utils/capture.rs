@@ -0,0 +1,506 @@
+//! Packet capture triggered by anomalies
+//!
+//! Keeps a short ring of recent frames and, when a trigger rule fires,
+//! writes them plus the following frames to a pcapng file. Captures are
+//! bounded in time and packet count, and a cooldown keeps repeated triggers
+//! from flooding the disk.
+
+use crate::udp::DropReason;
+use crate::{Error, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// pcapng Section Header Block type
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+/// pcapng Interface Description Block type
+const BLOCK_IDB: u32 = 0x0000_0001;
+/// pcapng Enhanced Packet Block type
+const BLOCK_EPB: u32 = 0x0000_0006;
+/// pcapng byte-order magic
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Ethernet link type
+const LINKTYPE_ETHERNET: u16 = 1;
+
+/// Byte order of the block fields of a capture file
+///
+/// Packet data is always stored as received; only the pcapng framing
+/// follows this order. Readers detect it from the byte-order magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// Byte order of the host
+    pub fn native() -> Self {
+        if cfg!(target_endian = "big") {
+            ByteOrder::Big
+        } else {
+            ByteOrder::Little
+        }
+    }
+
+    fn u16(self, value: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn u32(self, value: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn i64(self, value: i64) -> [u8; 8] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// Minimal pcapng writer for a single Ethernet interface
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    order: ByteOrder,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Write the section and interface headers in little-endian order
+    pub fn new(writer: W) -> Result<Self> {
+        Self::with_byte_order(writer, ByteOrder::Little)
+    }
+
+    /// Write the section and interface headers, with every block in `order`
+    pub fn with_byte_order(mut writer: W, order: ByteOrder) -> Result<Self> {
+        let mut shb = Vec::with_capacity(28);
+        shb.extend_from_slice(&order.u32(BLOCK_SHB));
+        shb.extend_from_slice(&order.u32(28));
+        shb.extend_from_slice(&order.u32(BYTE_ORDER_MAGIC));
+        shb.extend_from_slice(&order.u16(1));
+        shb.extend_from_slice(&order.u16(0));
+        shb.extend_from_slice(&order.i64(-1));
+        shb.extend_from_slice(&order.u32(28));
+        writer.write_all(&shb)?;
+
+        let mut idb = Vec::with_capacity(20);
+        idb.extend_from_slice(&order.u32(BLOCK_IDB));
+        idb.extend_from_slice(&order.u32(20));
+        idb.extend_from_slice(&order.u16(LINKTYPE_ETHERNET));
+        idb.extend_from_slice(&order.u16(0));
+        idb.extend_from_slice(&order.u32(0));
+        idb.extend_from_slice(&order.u32(20));
+        writer.write_all(&idb)?;
+
+        Ok(Self { writer, order })
+    }
+
+    /// Write one frame with a wall-clock timestamp
+    pub fn write_packet(&mut self, timestamp: SystemTime, frame: &[u8]) -> Result<()> {
+        let order = self.order;
+        let micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let padded = frame.len().div_ceil(4) * 4;
+        let total_len = (32 + padded) as u32;
+
+        let mut block = Vec::with_capacity(total_len as usize);
+        block.extend_from_slice(&order.u32(BLOCK_EPB));
+        block.extend_from_slice(&order.u32(total_len));
+        block.extend_from_slice(&order.u32(0));
+        block.extend_from_slice(&order.u32((micros >> 32) as u32));
+        block.extend_from_slice(&order.u32(micros as u32));
+        block.extend_from_slice(&order.u32(frame.len() as u32));
+        block.extend_from_slice(&order.u32(frame.len() as u32));
+        block.extend_from_slice(frame);
+        block.resize(28 + padded, 0);
+        block.extend_from_slice(&order.u32(total_len));
+
+        self.writer.write_all(&block)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Condition that starts a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriggerRule {
+    /// More than this many drops within one second
+    DropsPerSecond(u64),
+    /// More than this many checksum failures within one second
+    ChecksumFailuresPerSecond(u64),
+    /// Any drop with this reason
+    DropReason(DropReason),
+}
+
+/// Capture trigger configuration
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    /// Directory capture files are written to
+    pub directory: PathBuf,
+    /// Rules that start a capture
+    pub rules: Vec<TriggerRule>,
+    /// Frames kept from before the trigger
+    pub history_packets: usize,
+    /// Longest capture duration
+    pub max_duration: Duration,
+    /// Most frames written per capture, including history
+    pub max_packets: usize,
+    /// Minimum time between the end of one capture and the next trigger
+    pub cooldown: Duration,
+    /// Byte order of the capture file framing
+    pub byte_order: ByteOrder,
+    /// Packet colors kept by [`CaptureTrigger::observe_colored`], all when empty
+    pub colors: Vec<u8>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            directory: std::env::temp_dir(),
+            rules: Vec::new(),
+            history_packets: 256,
+            max_duration: Duration::from_secs(5),
+            max_packets: 10_000,
+            cooldown: Duration::from_secs(60),
+            byte_order: ByteOrder::default(),
+            colors: Vec::new(),
+        }
+    }
+}
+
+/// Capture trigger statistics
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    pub triggers: AtomicUsize,
+    pub suppressed: AtomicUsize,
+    pub captures_written: AtomicUsize,
+    pub packets_written: AtomicUsize,
+    pub errors: AtomicUsize,
+}
+
+/// Event counter over a one second window
+#[derive(Debug)]
+struct RateWindow {
+    start: Instant,
+    count: u64,
+}
+
+impl RateWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            count: 0,
+        }
+    }
+
+    /// Count an event and return the count within the current second
+    fn record(&mut self, now: Instant) -> u64 {
+        if now.duration_since(self.start) >= Duration::from_secs(1) {
+            self.start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count
+    }
+}
+
+/// Capture in progress
+struct ActiveCapture {
+    writer: PcapngWriter<BufWriter<File>>,
+    path: PathBuf,
+    started: Instant,
+    packets: usize,
+}
+
+/// Anomaly-triggered capture manager
+pub struct CaptureTrigger {
+    config: CaptureConfig,
+    history: VecDeque<(SystemTime, Vec<u8>)>,
+    drops: RateWindow,
+    checksum_failures: RateWindow,
+    active: Option<ActiveCapture>,
+    cooldown_until: Option<Instant>,
+    last_capture: Option<PathBuf>,
+    stats: CaptureStats,
+}
+
+impl CaptureTrigger {
+    /// Create a trigger manager
+    pub fn new(config: CaptureConfig) -> Result<Self> {
+        if config.max_packets == 0 {
+            return Err(Error::InvalidConfig(
+                "Capture max_packets must be non-zero".to_string(),
+            ));
+        }
+
+        let now = Instant::now();
+        Ok(Self {
+            history: VecDeque::with_capacity(config.history_packets),
+            config,
+            drops: RateWindow::new(now),
+            checksum_failures: RateWindow::new(now),
+            active: None,
+            cooldown_until: None,
+            last_capture: None,
+            stats: CaptureStats::default(),
+        })
+    }
+
+    /// Report a dropped packet
+    pub fn record_drop(&mut self, reason: DropReason) {
+        self.record_drop_at(reason, Instant::now());
+    }
+
+    /// Report a checksum failure
+    pub fn record_checksum_failure(&mut self) {
+        self.record_checksum_failure_at(Instant::now());
+    }
+
+    /// Offer a received or transmitted frame to the capture
+    pub fn observe(&mut self, frame: &[u8]) {
+        self.observe_at(frame, Instant::now());
+    }
+
+    /// Offer a frame of a colored packet, skipped unless its color is captured
+    pub fn observe_colored(&mut self, frame: &[u8], color: u8) {
+        if self.config.colors.is_empty() || self.config.colors.contains(&color) {
+            self.observe_at(frame, Instant::now());
+        }
+    }
+
+    /// Check whether a capture is in progress
+    pub fn is_capturing(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Path of the most recent capture file
+    pub fn last_capture(&self) -> Option<&PathBuf> {
+        self.last_capture.as_ref()
+    }
+
+    /// Stop the current capture, if any
+    pub fn finish(&mut self) {
+        self.finish_at(Instant::now());
+    }
+
+    /// Get trigger statistics
+    pub fn stats(&self) -> &CaptureStats {
+        &self.stats
+    }
+
+    fn record_drop_at(&mut self, reason: DropReason, now: Instant) {
+        let rate = self.drops.record(now);
+        let fired = self.config.rules.iter().any(|rule| match rule {
+            TriggerRule::DropsPerSecond(limit) => rate > *limit,
+            TriggerRule::DropReason(expected) => *expected == reason,
+            TriggerRule::ChecksumFailuresPerSecond(_) => false,
+        });
+        if fired {
+            self.trigger(now);
+        }
+    }
+
+    fn record_checksum_failure_at(&mut self, now: Instant) {
+        let rate = self.checksum_failures.record(now);
+        let fired = self.config.rules.iter().any(
+            |rule| matches!(rule, TriggerRule::ChecksumFailuresPerSecond(limit) if rate > *limit),
+        );
+        if fired {
+            self.trigger(now);
+        }
+    }
+
+    fn observe_at(&mut self, frame: &[u8], now: Instant) {
+        self.expire(now);
+
+        if let Some(active) = &mut self.active {
+            if active.writer.write_packet(SystemTime::now(), frame).is_ok() {
+                active.packets += 1;
+                self.stats.packets_written.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            self.expire(now);
+            return;
+        }
+
+        if self.config.history_packets > 0 {
+            if self.history.len() == self.config.history_packets {
+                self.history.pop_front();
+            }
+            self.history.push_back((SystemTime::now(), frame.to_vec()));
+        }
+    }
+
+    /// Start a capture unless one is running or the cooldown is active
+    fn trigger(&mut self, now: Instant) {
+        if self.active.is_some() {
+            return;
+        }
+        if self.cooldown_until.is_some_and(|until| now < until) {
+            self.stats.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.stats.triggers.fetch_add(1, Ordering::Relaxed);
+        match self.open_capture(now) {
+            Ok(active) => {
+                self.last_capture = Some(active.path.clone());
+                self.active = Some(active);
+                self.expire(now);
+            }
+            Err(_) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn open_capture(&mut self, now: Instant) -> Result<ActiveCapture> {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = self.config.directory.join(format!(
+            "xpdk-{}-{:06}.pcapng",
+            stamp.as_secs(),
+            stamp.subsec_micros()
+        ));
+
+        let mut writer = PcapngWriter::with_byte_order(
+            BufWriter::new(File::create(&path)?),
+            self.config.byte_order,
+        )?;
+        let mut packets = 0;
+        for (timestamp, frame) in self.history.drain(..) {
+            if packets == self.config.max_packets {
+                break;
+            }
+            writer.write_packet(timestamp, &frame)?;
+            packets += 1;
+        }
+        self.stats
+            .packets_written
+            .fetch_add(packets, Ordering::Relaxed);
+
+        Ok(ActiveCapture {
+            writer,
+            path,
+            started: now,
+            packets,
+        })
+    }
+
+    /// Close the capture once it hits its time or packet limit
+    fn expire(&mut self, now: Instant) {
+        let done = self.active.as_ref().is_some_and(|active| {
+            active.packets >= self.config.max_packets
+                || now.duration_since(active.started) >= self.config.max_duration
+        });
+        if done {
+            self.finish_at(now);
+        }
+    }
+
+    fn finish_at(&mut self, now: Instant) {
+        if let Some(active) = self.active.take() {
+            match active.writer.into_inner() {
+                Ok(_) => self.stats.captures_written.fetch_add(1, Ordering::Relaxed),
+                Err(_) => self.stats.errors.fetch_add(1, Ordering::Relaxed),
+            };
+            self.cooldown_until = Some(now + self.config.cooldown);
+        }
+    }
+}
+
+impl Drop for CaptureTrigger {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcapng_block_layout() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        writer.write_packet(UNIX_EPOCH, &[1, 2, 3, 4, 5]).unwrap();
+        let bytes = writer.into_inner().unwrap();
+
+        // 28 byte SHB, 20 byte IDB, 32 + 8 byte EPB
+        assert_eq!(bytes.len(), 28 + 20 + 40);
+        assert_eq!(&bytes[..4], &BLOCK_SHB.to_le_bytes());
+        assert_eq!(&bytes[48..52], &BLOCK_EPB.to_le_bytes());
+        assert_eq!(&bytes[bytes.len() - 4..], &40u32.to_le_bytes());
+
+        let mut writer = PcapngWriter::with_byte_order(Vec::new(), ByteOrder::Big).unwrap();
+        writer.write_packet(UNIX_EPOCH, &[1, 2, 3, 4, 5]).unwrap();
+        let big = writer.into_inner().unwrap();
+        assert_eq!(&big[8..12], &[0x1A, 0x2B, 0x3C, 0x4D]);
+        assert_eq!(&big[48..52], &BLOCK_EPB.to_be_bytes());
+        // Frame bytes are copied unchanged
+        assert_eq!(&big[76..81], &bytes[76..81]);
+    }
+
+    #[test]
+    fn test_trigger_captures_history_and_respects_cooldown() {
+        let directory = std::env::temp_dir().join(format!("xpdk-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut trigger = CaptureTrigger::new(CaptureConfig {
+            directory: directory.clone(),
+            rules: vec![
+                TriggerRule::DropsPerSecond(2),
+                TriggerRule::DropReason(DropReason::NoSocket),
+            ],
+            history_packets: 2,
+            max_packets: 4,
+            ..CaptureConfig::default()
+        })
+        .unwrap();
+
+        let now = Instant::now();
+        for frame in [[1u8; 60], [2u8; 60], [3u8; 60]] {
+            trigger.observe_at(&frame, now);
+        }
+
+        trigger.record_drop_at(DropReason::QueueFull, now);
+        trigger.record_drop_at(DropReason::QueueFull, now);
+        assert!(!trigger.is_capturing());
+        trigger.record_drop_at(DropReason::QueueFull, now);
+        assert!(trigger.is_capturing());
+
+        // Two history frames plus two live frames hit the packet limit
+        trigger.observe_at(&[4u8; 60], now);
+        trigger.observe_at(&[5u8; 60], now);
+        assert!(!trigger.is_capturing());
+        assert_eq!(trigger.stats().packets_written.load(Ordering::Relaxed), 4);
+
+        trigger.record_drop_at(DropReason::NoSocket, now);
+        assert!(!trigger.is_capturing());
+        assert_eq!(trigger.stats().suppressed.load(Ordering::Relaxed), 1);
+
+        let path = trigger.last_capture().unwrap().clone();
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(size, 28 + 20 + 4 * (32 + 60));
+
+        drop(trigger);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+//! Packet coloring
+//!
+//! Processes shared by several teams tag each packet with a color so that
+//! tracing, capture, logging and telemetry can be narrowed to one team's
+//! traffic. [`PacketColorer`] rules color a packet by the socket it belongs
+//! to, its local port or its remote address prefix; the first matching rule
+//! wins and unmatched packets stay [`UNCOLORED`]. The color is kept in
+//! [`Mbuf::color`] and in the packet's trace record, and while any rule
+//! exists every color counts the packets and bytes it received and sent;
+//! without rules packets pass uncolored and uncounted. A color filter
+//! selects the colors that [`PacketColorer::log`] lets through, and the
+//! colors of [`crate::utils::capture::CaptureConfig::colors`] those that a
+//! capture set with [`crate::udp::UdpStack::set_capture_trigger`] keeps.
+
+use crate::memory::Mbuf;
+use crate::utils::display::StackStr;
+use crate::{Error, Result};
+use log::Level;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Packet tag, 0 for packets no rule matched
+pub type Color = u8;
+
+/// Color of packets no rule matched
+pub const UNCOLORED: Color = 0;
+
+/// Number of distinct colors
+const COLORS: usize = Color::MAX as usize + 1;
+
+/// Longest color label written by [`PacketColorer::log`]
+pub const MAX_LOG_LABEL: usize = 32;
+
+/// Packets a coloring rule applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColorMatch {
+    /// Packets of one socket
+    Socket(u16),
+    /// Packets to or from a local port in the range
+    PortRange(RangeInclusive<u16>),
+    /// Packets from or to a remote address within `addr/len`
+    Prefix(Ipv4Addr, u8),
+}
+
+impl ColorMatch {
+    fn matches(&self, socket_id: u16, local_port: u16, remote: Ipv4Addr) -> bool {
+        match self {
+            ColorMatch::Socket(id) => *id == socket_id,
+            ColorMatch::PortRange(ports) => ports.contains(&local_port),
+            ColorMatch::Prefix(addr, len) => {
+                let mask = u32::MAX.checked_shl(32 - *len as u32).unwrap_or(0);
+                u32::from(remote) & mask == u32::from(*addr) & mask
+            }
+        }
+    }
+}
+
+/// Rule giving matching packets a color
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorRule {
+    pub color: Color,
+    pub matcher: ColorMatch,
+}
+
+/// Packets and bytes counted for one color
+#[derive(Debug, Default)]
+struct ColorCounters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+/// Point-in-time counters of one color
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColorSnapshot {
+    pub color: Color,
+    /// Label of the color, `color<N>` if none was set
+    pub label: String,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+}
+
+impl fmt::Display for ColorSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "colorRxPackets.{} {}", self.label, self.rx_packets)?;
+        writeln!(f, "colorRxOctets.{} {}", self.label, self.rx_bytes)?;
+        writeln!(f, "colorTxPackets.{} {}", self.label, self.tx_packets)?;
+        writeln!(f, "colorTxOctets.{} {}", self.label, self.tx_bytes)
+    }
+}
+
+/// Rules, labels and counters of packet colors
+pub struct PacketColorer {
+    rules: RwLock<Vec<ColorRule>>,
+    /// Set while any rule exists, so uncolored traffic skips the rules
+    enabled: AtomicBool,
+    labels: RwLock<HashMap<Color, String>>,
+    /// Colors let through by the filter, all when empty
+    filter: RwLock<Vec<Color>>,
+    counters: Box<[ColorCounters]>,
+}
+
+impl PacketColorer {
+    /// Create a colorer without rules
+    pub fn new() -> Self {
+        Self {
+            rules: RwLock::new(Vec::new()),
+            enabled: AtomicBool::new(false),
+            labels: RwLock::new(HashMap::new()),
+            filter: RwLock::new(Vec::new()),
+            counters: (0..COLORS).map(|_| ColorCounters::default()).collect(),
+        }
+    }
+
+    /// Colorer used by the socket RX and TX paths
+    pub fn global() -> &'static PacketColorer {
+        static COLORER: OnceLock<PacketColorer> = OnceLock::new();
+        COLORER.get_or_init(PacketColorer::new)
+    }
+
+    /// Append a rule, checked after the existing ones
+    pub fn add_rule(&self, color: Color, matcher: ColorMatch) -> Result<()> {
+        if color == UNCOLORED {
+            return Err(Error::InvalidConfig(
+                "Color 0 is reserved for uncolored packets".to_string(),
+            ));
+        }
+        match &matcher {
+            ColorMatch::PortRange(ports) if ports.is_empty() => {
+                return Err(Error::InvalidConfig("Empty color port range".to_string()));
+            }
+            ColorMatch::Prefix(_, len) if *len > 32 => {
+                return Err(Error::InvalidConfig(format!(
+                    "Invalid color prefix length {}",
+                    len
+                )));
+            }
+            _ => {}
+        }
+        self.rules.write().push(ColorRule { color, matcher });
+        self.enabled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove every rule
+    pub fn clear_rules(&self) {
+        self.rules.write().clear();
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Current rules in match order
+    pub fn rules(&self) -> Vec<ColorRule> {
+        self.rules.read().clone()
+    }
+
+    /// Name a color in logs and telemetry
+    pub fn set_label(&self, color: Color, label: &str) {
+        self.labels.write().insert(color, label.to_string());
+    }
+
+    /// Name of a color, `color<N>` if none was set
+    pub fn label(&self, color: Color) -> String {
+        self.labels
+            .read()
+            .get(&color)
+            .cloned()
+            .unwrap_or_else(|| format!("color{}", color))
+    }
+
+    /// Let only `colors` through the filter, every color if empty
+    pub fn set_filter(&self, colors: &[Color]) {
+        *self.filter.write() = colors.to_vec();
+    }
+
+    /// Whether the filter lets `color` through
+    pub fn is_selected(&self, color: Color) -> bool {
+        let filter = self.filter.read();
+        filter.is_empty() || filter.contains(&color)
+    }
+
+    /// Color of a packet of `socket_id` with a local port and remote address
+    pub fn classify(&self, socket_id: u16, local_port: u16, remote: Ipv4Addr) -> Color {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return UNCOLORED;
+        }
+        self.rules
+            .read()
+            .iter()
+            .find(|rule| rule.matcher.matches(socket_id, local_port, remote))
+            .map_or(UNCOLORED, |rule| rule.color)
+    }
+
+    /// Color a received mbuf and count it
+    ///
+    /// Nothing is counted while there are no rules.
+    pub fn color_rx(
+        &self,
+        mbuf: &mut Mbuf,
+        socket_id: u16,
+        local_port: u16,
+        remote: Ipv4Addr,
+    ) -> Color {
+        if !self.enabled.load(Ordering::Relaxed) {
+            mbuf.color = UNCOLORED;
+            return UNCOLORED;
+        }
+        let color = self.classify(socket_id, local_port, remote);
+        mbuf.color = color;
+        let counters = &self.counters[color as usize];
+        counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+        counters
+            .rx_bytes
+            .fetch_add(mbuf.len as u64, Ordering::Relaxed);
+        color
+    }
+
+    /// Color a transmitted mbuf and count it
+    ///
+    /// Nothing is counted while there are no rules.
+    pub fn color_tx(
+        &self,
+        mbuf: &mut Mbuf,
+        socket_id: u16,
+        local_port: u16,
+        remote: Ipv4Addr,
+    ) -> Color {
+        if !self.enabled.load(Ordering::Relaxed) {
+            mbuf.color = UNCOLORED;
+            return UNCOLORED;
+        }
+        let color = self.classify(socket_id, local_port, remote);
+        mbuf.color = color;
+        let counters = &self.counters[color as usize];
+        counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+        counters
+            .tx_bytes
+            .fetch_add(mbuf.len as u64, Ordering::Relaxed);
+        color
+    }
+
+    /// Log a packet event of `color` if the filter lets it through
+    ///
+    /// Labels are cut off at [`MAX_LOG_LABEL`] bytes.
+    pub fn log(&self, color: Color, level: Level, args: fmt::Arguments) {
+        if self.is_selected(color) && log::log_enabled!(level) {
+            let label: StackStr<MAX_LOG_LABEL> = match self.labels.read().get(&color) {
+                Some(label) => StackStr::format(format_args!("{}", label)),
+                None => StackStr::format(format_args!("color{}", color)),
+            };
+            log::log!(level, "[{}] {}", label, args);
+        }
+    }
+
+    /// Counters of one color
+    pub fn snapshot(&self, color: Color) -> ColorSnapshot {
+        let counters = &self.counters[color as usize];
+        ColorSnapshot {
+            color,
+            label: self.label(color),
+            rx_packets: counters.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: counters.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: counters.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: counters.tx_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counters of every color that saw traffic and the filter lets through
+    pub fn snapshots(&self) -> Vec<ColorSnapshot> {
+        (0..COLORS)
+            .map(|color| self.snapshot(color as Color))
+            .filter(|snapshot| {
+                (snapshot.rx_packets > 0 || snapshot.tx_packets > 0)
+                    && self.is_selected(snapshot.color)
+            })
+            .collect()
+    }
+}
+
+impl Default for PacketColorer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_color_and_count_packets() {
+        let colorer = PacketColorer::new();
+        let remote = Ipv4Addr::new(10, 1, 2, 3);
+        assert_eq!(colorer.classify(1, 53, remote), UNCOLORED);
+
+        assert!(colorer.add_rule(UNCOLORED, ColorMatch::Socket(1)).is_err());
+        assert!(colorer
+            .add_rule(1, ColorMatch::Prefix(Ipv4Addr::UNSPECIFIED, 33))
+            .is_err());
+        colorer.add_rule(1, ColorMatch::Socket(7)).unwrap();
+        colorer
+            .add_rule(2, ColorMatch::PortRange(5000..=5099))
+            .unwrap();
+        colorer
+            .add_rule(3, ColorMatch::Prefix(Ipv4Addr::new(10, 1, 0, 0), 16))
+            .unwrap();
+        colorer.set_label(2, "team-a");
+
+        // The first matching rule wins
+        assert_eq!(colorer.classify(7, 5000, remote), 1);
+        assert_eq!(colorer.classify(1, 5000, remote), 2);
+        assert_eq!(colorer.classify(1, 53, remote), 3);
+        assert_eq!(
+            colorer.classify(1, 53, Ipv4Addr::new(10, 2, 0, 1)),
+            UNCOLORED
+        );
+
+        let mut data = [0u8; 64];
+        let mut mbuf = Mbuf::new(data.as_mut_ptr(), data.len());
+        mbuf.len = 64;
+        assert_eq!(colorer.color_rx(&mut mbuf, 1, 5001, remote), 2);
+        assert_eq!(mbuf.color, 2);
+        colorer.color_tx(&mut mbuf, 1, 5001, remote);
+        let snapshot = colorer.snapshot(2);
+        assert_eq!((snapshot.rx_packets, snapshot.tx_bytes), (1, 64));
+        assert!(snapshot.to_string().contains("colorRxPackets.team-a 1"));
+
+        colorer.color_rx(&mut mbuf, 1, 53, Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(colorer.snapshots().len(), 2);
+        colorer.set_filter(&[2]);
+        assert!(!colorer.is_selected(UNCOLORED));
+        assert_eq!(colorer.snapshots()[0].label, "team-a");
+
+        colorer.clear_rules();
+        assert_eq!(colorer.classify(7, 5000, remote), UNCOLORED);
+        // Without rules nothing is counted
+        let before = colorer.snapshot(UNCOLORED).rx_packets;
+        assert_eq!(colorer.color_rx(&mut mbuf, 7, 5000, remote), UNCOLORED);
+        assert_eq!(mbuf.color, UNCOLORED);
+        assert_eq!(colorer.snapshot(UNCOLORED).rx_packets, before);
+    }
+}
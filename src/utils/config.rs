@@ -1,9 +1,18 @@
 //! Configuration utilities for XPDK
-
-use crate::{Error, Result};
+//!
+//! [`LayeredConfig`] resolves a [`Config`] from layers for deployments
+//! tuned through their environment: built-in defaults, a
+//! [`ConfigProfile`], a config file, `XPDK_*` environment variables and
+//! overrides set in code, each over the last. The [`ResolvedConfig`] keeps
+//! the layer every value came from, for the dump logged at startup by
+//! [`crate::Xpdk::from_layers`].
+
+use crate::udp::TrafficClass;
+use crate::{CaptureDirection, Config, Error, ResetPolicy, Result};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
 /// Configuration format
 #[derive(Debug, Clone, Copy)]
@@ -380,9 +389,495 @@ impl Default for ConfigValidator {
     }
 }
 
+/// Preset tuned for one kind of workload, applied over the defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigProfile {
+    /// Busy-polling loops and short queues
+    LowLatency,
+    /// Deep queues and pools, loops that sleep when idle
+    HighThroughput,
+}
+
+impl ConfigProfile {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConfigProfile::LowLatency => "low_latency",
+            ConfigProfile::HighThroughput => "high_throughput",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "low_latency" => Ok(ConfigProfile::LowLatency),
+            "high_throughput" => Ok(ConfigProfile::HighThroughput),
+            _ => Err(Error::InvalidConfig(format!("Unknown profile '{}'", name))),
+        }
+    }
+
+    /// Settings of the profile as `(key, value)` pairs
+    fn settings(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            ConfigProfile::LowLatency => &[
+                ("rx_queue_size", "1024"),
+                ("tx_queue_size", "1024"),
+                ("idle_spin_us", "max"),
+                ("idle_yields", "0"),
+                ("idle_max_sleep_us", "0"),
+            ],
+            ConfigProfile::HighThroughput => &[
+                ("pool_size", "32768"),
+                ("rx_queue_size", "8192"),
+                ("tx_queue_size", "8192"),
+                ("idle_adaptive", "true"),
+            ],
+        }
+    }
+}
+
+/// Layer a configuration value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    Profile(ConfigProfile),
+    File(String),
+    /// Environment variable of this name
+    Env(String),
+    Override,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => f.write_str("default"),
+            ConfigSource::Profile(profile) => write!(f, "profile {}", profile.as_str()),
+            ConfigSource::File(path) => write!(f, "file {}", path),
+            ConfigSource::Env(var) => write!(f, "env {}", var),
+            ConfigSource::Override => f.write_str("override"),
+        }
+    }
+}
+
+/// Prefix of the environment variables read by [`LayeredConfig`]
+pub const ENV_PREFIX: &str = "XPDK_";
+
+/// Keys [`LayeredConfig`] resolves, in dump order
+pub const CONFIG_KEYS: [&str; 23] = [
+    "interface",
+    "port_id",
+    "pool_count",
+    "pool_size",
+    "rx_queue_count",
+    "tx_queue_count",
+    "rx_queue_size",
+    "tx_queue_size",
+    "enable_hugepages",
+    "enable_numa",
+    "mbuf_reset",
+    "enable_offload",
+    "cpu_affinity",
+    "mtu",
+    "vlan_strip",
+    "hw_timestamps",
+    "capture_direction",
+    "drop_log_size",
+    "memory_budget",
+    "idle_spin_us",
+    "idle_yields",
+    "idle_max_sleep_us",
+    "idle_adaptive",
+];
+
+/// Fields of [`Config`] only set in code, dumped after [`CONFIG_KEYS`]
+pub const CODE_ONLY_KEYS: [&str; 10] = [
+    "queues",
+    "forwarding",
+    "bridge",
+    "early_drop",
+    "quarantine",
+    "traffic_classes",
+    "warmup",
+    "placement",
+    "stats_persistence",
+    "capture_coordination",
+];
+
+/// Configuration resolved from layers
+///
+/// Each layer overrides the ones before it: the built-in defaults, then a
+/// [`ConfigProfile`], then a config file, then `XPDK_*` environment
+/// variables named after the keys in upper case (`XPDK_POOL_SIZE`), then
+/// overrides set in code. The profile is the one set with
+/// [`LayeredConfig::with_profile`], else the one named by `XPDK_PROFILE`.
+/// Config files hold the keys of [`CONFIG_KEYS`] either as a flat JSON
+/// object or as `key = value` lines, with `#` comments outside double
+/// quotes. An unknown key is an error in every layer, so is an `XPDK_*`
+/// variable naming none, other than `XPDK_PROFILE` and `XPDK_RNG_SEED`.
+#[derive(Debug, Clone, Default)]
+pub struct LayeredConfig {
+    profile: Option<ConfigProfile>,
+    file: Option<std::path::PathBuf>,
+    overrides: Vec<(String, String)>,
+}
+
+impl LayeredConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_profile(mut self, profile: ConfigProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    pub fn with_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set `key` to `value` over every other layer
+    pub fn with_override(mut self, key: &str, value: &str) -> Self {
+        self.overrides.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Resolve the layers against the process environment
+    pub fn resolve(&self) -> Result<ResolvedConfig> {
+        self.resolve_with_env(std::env::vars())
+    }
+
+    fn resolve_with_env<I>(&self, env: I) -> Result<ResolvedConfig>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let env: HashMap<String, String> = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        let known = |var: &str| {
+            // The seed is read by the deterministic-rng feature
+            var == "XPDK_PROFILE"
+                || var == "XPDK_RNG_SEED"
+                || var
+                    .strip_prefix(ENV_PREFIX)
+                    .is_some_and(|name| CONFIG_KEYS.iter().any(|key| key.to_uppercase() == name))
+        };
+        let mut unknown: Vec<&String> = env.keys().filter(|var| !known(var)).collect();
+        unknown.sort();
+        if let Some(var) = unknown.first() {
+            return Err(Error::InvalidConfig(format!(
+                "Unknown config variable {}",
+                var
+            )));
+        }
+        let mut resolved = ResolvedConfig {
+            config: Config::default(),
+            sources: CONFIG_KEYS
+                .iter()
+                .chain(&CODE_ONLY_KEYS)
+                .map(|&key| (key, ConfigSource::Default))
+                .collect(),
+        };
+
+        let profile = match (self.profile, env.get("XPDK_PROFILE")) {
+            (Some(profile), _) => Some(profile),
+            (None, Some(name)) => Some(ConfigProfile::parse(name)?),
+            (None, None) => None,
+        };
+        if let Some(profile) = profile {
+            for (key, value) in profile.settings() {
+                resolved.set(key, value, ConfigSource::Profile(profile))?;
+            }
+        }
+        if let Some(path) = &self.file {
+            let content = fs::read_to_string(path)?;
+            let source = ConfigSource::File(path.display().to_string());
+            for (key, value) in parse_settings(&content)? {
+                resolved.set(&key, &value, source.clone())?;
+            }
+        }
+        for key in CONFIG_KEYS {
+            let var = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Some(value) = env.get(&var) {
+                resolved.set(key, value, ConfigSource::Env(var))?;
+            }
+        }
+        for (key, value) in &self.overrides {
+            resolved.set(key, value, ConfigSource::Override)?;
+        }
+
+        let config = crate::ConfigBuilder::from_config(resolved.config).build()?;
+        resolved.config = config;
+        Ok(resolved)
+    }
+}
+
+/// Settings of a config file, as a flat JSON object or `key = value` lines
+fn parse_settings(content: &str) -> Result<Vec<(String, String)>> {
+    if content.trim_start().starts_with('{') {
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(content)
+            .map_err(|e| Error::InvalidConfig(format!("Bad config file: {}", e)))?;
+        return Ok(object
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => s,
+                    serde_json::Value::Null => "none".to_string(),
+                    serde_json::Value::Array(items) => items
+                        .iter()
+                        .map(|item| item.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    other => other.to_string(),
+                };
+                (key, value)
+            })
+            .collect());
+    }
+
+    let mut settings = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            Error::InvalidConfig(format!("Bad config line {}: {}", number + 1, line))
+        })?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|quoted| quoted.strip_suffix('"'))
+            .unwrap_or(value);
+        settings.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(settings)
+}
+
+/// `line` up to a `#` outside double quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn bad_value(key: &str, value: &str) -> Error {
+    Error::InvalidConfig(format!("Bad value '{}' for {}", value, key))
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| bad_value(key, value))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(bad_value(key, value)),
+    }
+}
+
+/// Microseconds, or `max` for no limit
+fn parse_micros(key: &str, value: &str) -> Result<Duration> {
+    match value {
+        "max" => Ok(Duration::MAX),
+        _ => Ok(Duration::from_micros(parse_value(key, value)?)),
+    }
+}
+
+/// `none`, or the debug form of the value
+fn format_option<T: std::fmt::Debug>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map_or("none".to_string(), |value| format!("{:?}", value))
+}
+
+fn format_micros(duration: Duration) -> String {
+    match duration {
+        Duration::MAX => "max".to_string(),
+        _ => duration.as_micros().to_string(),
+    }
+}
+
+/// Effective configuration and where each value came from
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub config: Config,
+    sources: HashMap<&'static str, ConfigSource>,
+}
+
+impl ResolvedConfig {
+    /// Layer `key` was last set by
+    pub fn source(&self, key: &str) -> Option<&ConfigSource> {
+        self.sources.get(key)
+    }
+
+    /// Effective value of `key` as it would be written in a config file
+    pub fn value(&self, key: &str) -> Option<String> {
+        let config = &self.config;
+        let idle = &config.idle_strategy;
+        Some(match key {
+            "interface" => config.interface.clone(),
+            "port_id" => config.port_id.to_string(),
+            "pool_count" => config.pool_count.to_string(),
+            "pool_size" => config.pool_size.to_string(),
+            "rx_queue_count" => config.rx_queue_count.to_string(),
+            "tx_queue_count" => config.tx_queue_count.to_string(),
+            "rx_queue_size" => config.rx_queue_size.to_string(),
+            "tx_queue_size" => config.tx_queue_size.to_string(),
+            "enable_hugepages" => config.enable_hugepages.to_string(),
+            "enable_numa" => config.enable_numa.to_string(),
+            "mbuf_reset" => match config.mbuf_reset {
+                ResetPolicy::None => "none".to_string(),
+                ResetPolicy::Metadata => "metadata".to_string(),
+                ResetPolicy::Zero => "zero".to_string(),
+            },
+            "enable_offload" => config.enable_offload.to_string(),
+            "cpu_affinity" => match &config.cpu_affinity {
+                Some(cpus) => cpus
+                    .iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                None => "none".to_string(),
+            },
+            "mtu" => config.mtu.to_string(),
+            "vlan_strip" => config.vlan_strip.to_string(),
+            "hw_timestamps" => config.hw_timestamps.to_string(),
+            "capture_direction" => match config.capture_direction {
+                CaptureDirection::Inbound => "inbound".to_string(),
+                CaptureDirection::Both => "both".to_string(),
+            },
+            "drop_log_size" => config.drop_log_size.to_string(),
+            "memory_budget" => config
+                .memory_budget
+                .map_or("none".to_string(), |bytes| bytes.to_string()),
+            "idle_spin_us" => format_micros(idle.spin),
+            "idle_yields" => idle.yields.to_string(),
+            "idle_max_sleep_us" => format_micros(idle.max_sleep),
+            "idle_adaptive" => idle.adaptive.to_string(),
+            "queues" => format!("{:?}", config.queues),
+            "forwarding" => format_option(&config.forwarding),
+            "bridge" => format_option(&config.bridge),
+            "early_drop" => format_option(&config.early_drop),
+            "quarantine" => format_option(&config.quarantine),
+            // DSCPs outside best effort
+            "traffic_classes" => (0..64u8)
+                .map(|dscp| (dscp, config.traffic_classes.class_for(dscp)))
+                .filter(|&(_, class)| class != TrafficClass::BestEffort)
+                .map(|(dscp, class)| format!("{}:{}", dscp, class.as_str()))
+                .collect::<Vec<_>>()
+                .join(","),
+            "warmup" => format_option(&config.warmup),
+            "placement" => format_option(&config.placement),
+            "stats_persistence" => format_option(&config.stats_persistence),
+            "capture_coordination" => format_option(&config.capture_coordination),
+            _ => return None,
+        })
+    }
+
+    fn set(&mut self, key: &str, value: &str, source: ConfigSource) -> Result<()> {
+        if CODE_ONLY_KEYS.contains(&key) {
+            return Err(Error::InvalidConfig(format!(
+                "Config key '{}' from {} is only set in code",
+                key, source
+            )));
+        }
+        let Some(&key) = CONFIG_KEYS.iter().find(|&&known| known == key) else {
+            return Err(Error::InvalidConfig(format!(
+                "Unknown config key '{}' from {}",
+                key, source
+            )));
+        };
+        let config = &mut self.config;
+        match key {
+            "interface" => config.interface = value.to_string(),
+            "port_id" => config.port_id = parse_value(key, value)?,
+            "pool_count" => config.pool_count = parse_value(key, value)?,
+            "pool_size" => config.pool_size = parse_value(key, value)?,
+            "rx_queue_count" => config.rx_queue_count = parse_value(key, value)?,
+            "tx_queue_count" => config.tx_queue_count = parse_value(key, value)?,
+            "rx_queue_size" => config.rx_queue_size = parse_value(key, value)?,
+            "tx_queue_size" => config.tx_queue_size = parse_value(key, value)?,
+            "enable_hugepages" => config.enable_hugepages = parse_bool(key, value)?,
+            "enable_numa" => config.enable_numa = parse_bool(key, value)?,
+            "mbuf_reset" => {
+                config.mbuf_reset = match value {
+                    "none" => ResetPolicy::None,
+                    "metadata" => ResetPolicy::Metadata,
+                    "zero" => ResetPolicy::Zero,
+                    _ => return Err(bad_value(key, value)),
+                }
+            }
+            "enable_offload" => config.enable_offload = parse_bool(key, value)?,
+            "cpu_affinity" => {
+                config.cpu_affinity = match value {
+                    "none" | "" => None,
+                    _ => Some(
+                        value
+                            .split(',')
+                            .map(|cpu| parse_value(key, cpu.trim()))
+                            .collect::<Result<_>>()?,
+                    ),
+                }
+            }
+            "mtu" => config.mtu = parse_value(key, value)?,
+            "vlan_strip" => config.vlan_strip = parse_bool(key, value)?,
+            "hw_timestamps" => config.hw_timestamps = parse_bool(key, value)?,
+            "capture_direction" => {
+                config.capture_direction = match value {
+                    "inbound" => CaptureDirection::Inbound,
+                    "both" => CaptureDirection::Both,
+                    _ => return Err(bad_value(key, value)),
+                }
+            }
+            "drop_log_size" => config.drop_log_size = parse_value(key, value)?,
+            "memory_budget" => {
+                config.memory_budget = match value {
+                    "none" => None,
+                    _ => Some(parse_value(key, value)?),
+                }
+            }
+            "idle_spin_us" => config.idle_strategy.spin = parse_micros(key, value)?,
+            "idle_yields" => config.idle_strategy.yields = parse_value(key, value)?,
+            "idle_max_sleep_us" => config.idle_strategy.max_sleep = parse_micros(key, value)?,
+            "idle_adaptive" => config.idle_strategy.adaptive = parse_bool(key, value)?,
+            _ => unreachable!("every key of CONFIG_KEYS is handled"),
+        }
+        self.sources.insert(key, source);
+        Ok(())
+    }
+
+    /// Log every value with its source at INFO
+    pub fn log(&self) {
+        for line in self.to_string().lines() {
+            log::info!("config {}", line);
+        }
+    }
+}
+
+impl std::fmt::Display for ResolvedConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for key in CONFIG_KEYS.iter().chain(&CODE_ONLY_KEYS) {
+            let value = self.value(key).unwrap_or_default();
+            match self.sources.get(key) {
+                Some(source) => writeln!(f, "{} = {} ({})", key, value, source)?,
+                None => writeln!(f, "{} = {}", key, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::backoff::IdleStrategy;
 
     #[test]
     fn test_config_manager() {
@@ -428,4 +923,84 @@ mod tests {
         config.set("test_int", ConfigValue::Integer(150));
         assert!(validator.validate(&config).is_err());
     }
+
+    #[test]
+    fn test_layered_config_precedence() {
+        let path = std::env::temp_dir().join(format!("xpdk-layers-{}.conf", std::process::id()));
+        fs::write(
+            &path,
+            "# tuned for the lab\npool_size = 16384\nmtu = 9000 # jumbo\ninterface = \"eth#1\"\n",
+        )
+        .unwrap();
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let layers = LayeredConfig::new()
+            .with_file(&path)
+            .with_override("interface", "eth2");
+        let resolved = layers
+            .resolve_with_env(env(&[
+                ("XPDK_PROFILE", "high_throughput"),
+                ("XPDK_MTU", "1500"),
+                ("XPDK_CPU_AFFINITY", "2,3"),
+                ("HOME", "/root"),
+            ]))
+            .unwrap();
+        let config = &resolved.config;
+        // Profile, then file, then environment, then override
+        assert_eq!(config.rx_queue_size, 8192);
+        assert_eq!(config.pool_size, 16384);
+        assert_eq!(config.mtu, 1500);
+        assert_eq!(config.cpu_affinity, Some(vec![2, 3]));
+        assert_eq!(config.interface, "eth2");
+        assert_eq!(
+            parse_settings("interface = \"eth#1\" # quoted").unwrap(),
+            [("interface".to_string(), "eth#1".to_string())]
+        );
+        assert_eq!(
+            resolved.source("rx_queue_size"),
+            Some(&ConfigSource::Profile(ConfigProfile::HighThroughput))
+        );
+        assert_eq!(
+            resolved.source("mtu"),
+            Some(&ConfigSource::Env("XPDK_MTU".to_string()))
+        );
+        assert_eq!(resolved.source("port_id"), Some(&ConfigSource::Default));
+        let dump = resolved.to_string();
+        assert!(dump.contains("interface = eth2 (override)"));
+        assert!(dump.contains(&format!("pool_size = 16384 (file {})", path.display())));
+        // Every field is dumped, the ones set in code included
+        assert_eq!(
+            dump.lines().count(),
+            CONFIG_KEYS.len() + CODE_ONLY_KEYS.len()
+        );
+        assert!(dump.contains("bridge = none (default)"));
+
+        // A profile set in code wins over XPDK_PROFILE
+        let resolved = LayeredConfig::new()
+            .with_profile(ConfigProfile::LowLatency)
+            .resolve_with_env(env(&[("XPDK_PROFILE", "high_throughput")]))
+            .unwrap();
+        assert_eq!(resolved.config.idle_strategy, IdleStrategy::busy_poll());
+        assert_eq!(resolved.value("idle_spin_us").unwrap(), "max");
+
+        let bad = |vars: &[(&str, &str)]| LayeredConfig::new().resolve_with_env(env(vars));
+        assert!(bad(&[("XPDK_PROFILE", "fastest")]).is_err());
+        assert!(bad(&[("XPDK_ENABLE_NUMA", "maybe")]).is_err());
+        // Unknown variables fail as unknown overrides do
+        assert!(bad(&[("XPDK_POOL_SISE", "1")]).is_err());
+        assert!(bad(&[("XPDK_RNG_SEED", "7"), ("XPDK_MBUF_RESET", "zero")]).is_ok());
+        assert!(LayeredConfig::new()
+            .with_override("bridge", "on")
+            .resolve_with_env(Vec::new())
+            .is_err());
+        assert!(LayeredConfig::new()
+            .with_override("pool_sise", "1")
+            .resolve_with_env(Vec::new())
+            .is_err());
+        fs::remove_file(&path).unwrap();
+    }
 }
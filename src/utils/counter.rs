@@ -0,0 +1,143 @@
+//! Statistics counters that do not wrap
+//!
+//! Queue, socket and pool statistics count in [`Counter`]s: 64 bits on
+//! every target, so a 32-bit build does not wrap after four billion
+//! packets, and saturating at `u64::MAX` with [`Counter::overflowed`] set
+//! rather than wrapping back to small values a rate computation would
+//! misread. Each counter remembers when it was created or last reset, so a
+//! rate over its lifetime needs no separate bookkeeping.
+
+use super::time::{monotonic_now, Timestamp};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Monotonic, saturating 64-bit counter
+pub struct Counter {
+    value: AtomicU64,
+    overflowed: AtomicBool,
+    /// Monotonic time of creation or the last reset
+    reset_at: AtomicU64,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Self {
+            value: AtomicU64::new(0),
+            overflowed: AtomicBool::new(false),
+            reset_at: AtomicU64::new(monotonic_now()),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Add `value`, saturating at `u64::MAX`
+    pub fn add(&self, value: u64) {
+        // Never stores a wrapped value, so readers only ever see it rise
+        let previous = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(current.saturating_add(value))
+            })
+            .unwrap_or_else(|current| current);
+        if previous.checked_add(value).is_none() {
+            self.overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Whether the counter saturated since it was last reset
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Start again from zero, returning the value reached
+    pub fn reset(&self) -> u64 {
+        self.reset_at(monotonic_now())
+    }
+
+    fn reset_at(&self, now: Timestamp) -> u64 {
+        self.overflowed.store(false, Ordering::Relaxed);
+        self.reset_at.store(now, Ordering::Relaxed);
+        self.value.swap(0, Ordering::Relaxed)
+    }
+
+    /// Monotonic time the counter was created or last reset, see [`monotonic_now`]
+    pub fn last_reset(&self) -> Timestamp {
+        self.reset_at.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Counter")
+            .field("value", &self.get())
+            .field("overflowed", &self.overflowed())
+            .field("last_reset", &self.last_reset())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saturates_and_resets() {
+        let counter = Counter::new();
+        counter.inc();
+        counter.add(u64::MAX - 2);
+        assert_eq!(counter.get(), u64::MAX - 1);
+        assert!(!counter.overflowed());
+
+        counter.add(5);
+        assert_eq!(counter.get(), u64::MAX);
+        assert!(counter.overflowed());
+        counter.inc();
+        assert_eq!(counter.get(), u64::MAX);
+
+        assert_eq!(counter.reset_at(counter.last_reset() + 10), u64::MAX);
+        assert_eq!(counter.get(), 0);
+        assert!(!counter.overflowed());
+        counter.add(3);
+        assert_eq!(counter.get(), 3);
+    }
+
+    #[test]
+    fn test_concurrent_adds_saturate_without_wrapping() {
+        use std::sync::Arc;
+
+        let counter = Arc::new(Counter::new());
+        counter.add(u64::MAX - 1000);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..1000 {
+                        counter.inc();
+                        // A reader never sees the count fall back
+                        let value = counter.get();
+                        assert!(value >= last && value > u64::MAX - 1000);
+                        last = value;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(counter.get(), u64::MAX);
+        assert!(counter.overflowed());
+    }
+}
@@ -0,0 +1,230 @@
+//! Allocation-free formatting for hot-path logging
+//!
+//! Formatting a packet for a log record should not cost an allocation per
+//! packet. [`MacAddr`] and [`HeaderSummary`] are display adapters that
+//! write straight from the frame bytes into the formatter, and
+//! [`StackStr`] is a fixed-size string on the stack for text that has to
+//! be formatted ahead of the record, such as a label copied out from
+//! under a lock. Text beyond a `StackStr`'s capacity is cut off rather
+//! than grown into. The sampled packet log in
+//! [`super::logging::PacketLog`] is built on these.
+
+use std::fmt::{self, Write};
+use std::net::Ipv4Addr;
+use std::ops::Deref;
+
+/// Ethernet header length
+const ETH_LEN: usize = 14;
+
+/// String of at most `N` bytes held inline
+#[derive(Clone, Copy)]
+pub struct StackStr<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> StackStr<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// Format `args`, cut off at `N` bytes
+    pub fn format(args: fmt::Arguments<'_>) -> Self {
+        let mut text = Self::new();
+        let _ = text.write_fmt(args);
+        text
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: only whole UTF-8 sequences are ever copied in
+        unsafe { std::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    /// Check if formatted text was cut off
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Default for StackStr<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for StackStr<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = N - self.len;
+        let mut take = s.len().min(room);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        if take < s.len() {
+            // Stops the rest of the formatting
+            self.truncated = true;
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Deref for StackStr<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Display for StackStr<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackStr<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// MAC address shown as `aa:bb:cc:dd:ee:ff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// One-line summary of the Ethernet, IPv4 and UDP headers of a frame
+///
+/// UDP datagrams show as
+/// `02:00:00:00:00:01 > ff:ff:ff:ff:ff:ff 10.0.0.2:40000 > 10.0.0.1:5000 udp len 12 ttl 64`,
+/// other IPv4 packets with their protocol and total length and other
+/// frames with their EtherType. Headers cut short end the summary with
+/// `truncated`.
+#[derive(Clone, Copy)]
+pub struct HeaderSummary<'a>(pub &'a [u8]);
+
+impl fmt::Display for HeaderSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.0;
+        let be16 = |at: usize| u16::from_be_bytes([frame[at], frame[at + 1]]);
+        if frame.len() < ETH_LEN {
+            return write!(f, "truncated frame of {} bytes", frame.len());
+        }
+        let mac = |at: usize| MacAddr(frame[at..at + 6].try_into().unwrap());
+        write!(f, "{} > {}", mac(6), mac(0))?;
+
+        let mut ip = ETH_LEN;
+        let mut ether_type = be16(12);
+        if ether_type == 0x8100 {
+            if frame.len() < ETH_LEN + 4 {
+                return f.write_str(" vlan truncated");
+            }
+            write!(f, " vlan {}", be16(14) & 0x0FFF)?;
+            ether_type = be16(16);
+            ip += 4;
+        }
+        if ether_type != 0x0800 {
+            return write!(f, " ethertype 0x{:04x} len {}", ether_type, frame.len());
+        }
+
+        let ihl = frame.get(ip).map_or(0, |byte| (byte & 0x0F) as usize * 4);
+        if ihl < 20 || frame.len() < ip + ihl {
+            return f.write_str(" ipv4 truncated");
+        }
+        let addr =
+            |at: usize| Ipv4Addr::new(frame[at], frame[at + 1], frame[at + 2], frame[at + 3]);
+        let (src, dst) = (addr(ip + 12), addr(ip + 16));
+        let ttl = frame[ip + 8];
+        let protocol = frame[ip + 9];
+        // Only the first fragment carries the UDP header
+        let first_fragment = be16(ip + 6) & 0x1FFF == 0;
+        if protocol != 17 || !first_fragment {
+            return write!(
+                f,
+                " {} > {} proto {} len {} ttl {}",
+                src,
+                dst,
+                protocol,
+                be16(ip + 2),
+                ttl
+            );
+        }
+
+        let udp = ip + ihl;
+        if frame.len() < udp + 8 {
+            return write!(f, " {} > {} udp truncated", src, dst);
+        }
+        write!(
+            f,
+            " {}:{} > {}:{} udp len {} ttl {}",
+            src,
+            be16(udp),
+            dst,
+            be16(udp + 2),
+            be16(udp + 4).saturating_sub(8),
+            ttl
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::testing::FrameBuilder;
+
+    #[test]
+    fn test_stack_formatting() {
+        let frame = FrameBuilder::to_port(5000).payload(b"hello").build();
+        let summary = StackStr::<128>::format(format_args!("{}", HeaderSummary(&frame)));
+        assert!(!summary.is_truncated());
+        let (macs, rest) = summary.split_at(37);
+        assert_eq!(
+            macs,
+            format!(
+                "{} > {}",
+                MacAddr(frame[6..12].try_into().unwrap()),
+                MacAddr(frame[..6].try_into().unwrap())
+            )
+        );
+        assert!(rest.starts_with(' ') && rest.contains(":5000 udp len 5 ttl "));
+
+        assert_eq!(
+            HeaderSummary(&frame[..30]).to_string(),
+            format!("{} ipv4 truncated", macs)
+        );
+        assert_eq!(
+            HeaderSummary(&frame[..8]).to_string(),
+            "truncated frame of 8 bytes"
+        );
+
+        // Cut off at a character boundary
+        let mut text = StackStr::<4>::format(format_args!("ab{}", 'é'));
+        assert_eq!(text.as_str(), "abé");
+        assert!(!text.is_truncated());
+        text.clear();
+        assert!(write!(text, "xyzé").is_err());
+        assert_eq!((text.as_str(), text.is_truncated()), ("xyz", true));
+    }
+}
@@ -0,0 +1,313 @@
+//! Kernel interface counter cross-check
+//!
+//! Frames the kernel drops before libpcap sees them never reach the PMD's
+//! counters. An [`InterfaceMonitor`] reads the interface's counters from
+//! `/sys/class/net/<interface>/statistics` every interval, together with
+//! the RX counters of the PMD, and compares what each saw since the
+//! previous read. A [`CrossCheck`] flags kernel drops, frames the kernel
+//! received that no RX queue accounted for (a small capture buffer, or
+//! BPF filters not covering all traffic), and queues accounting for more
+//! frames than the kernel received (captures overlapping on the same
+//! interface, or counters of another interface). Intervals with a
+//! discrepancy are logged at WARN.
+
+use crate::utils::shutdown::ShutdownToken;
+use crate::Result;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Default time between cross-checks
+pub const DEFAULT_IFSTATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default fraction of kernel frames the PMD may differ by before it is flagged
+pub const DEFAULT_IFSTATS_TOLERANCE: f64 = 0.01;
+
+/// Kernel RX counters of an interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelCounters {
+    pub rx_packets: u64,
+    /// Frames dropped by the kernel for lack of buffers
+    pub rx_dropped: u64,
+    pub rx_errors: u64,
+    /// Frames the NIC missed for lack of descriptors
+    pub rx_missed_errors: u64,
+}
+
+impl KernelCounters {
+    /// Read the counters of `interface` from sysfs
+    pub fn read(interface: &str) -> Result<Self> {
+        Self::read_dir(&Self::dir(interface))
+    }
+
+    /// Read counters from a sysfs-style `statistics` directory
+    pub fn read_dir(dir: &Path) -> Result<Self> {
+        let read =
+            |name: &str| -> Result<u64> { Ok(fs::read_to_string(dir.join(name))?.trim().parse()?) };
+
+        Ok(Self {
+            rx_packets: read("rx_packets")?,
+            rx_dropped: read("rx_dropped")?,
+            rx_errors: read("rx_errors")?,
+            // Not every driver reports missed frames
+            rx_missed_errors: read("rx_missed_errors").unwrap_or(0),
+        })
+    }
+
+    /// Statistics directory of `interface`
+    pub fn dir(interface: &str) -> PathBuf {
+        Path::new("/sys/class/net")
+            .join(interface)
+            .join("statistics")
+    }
+
+    /// Frames lost inside the host or NIC
+    pub fn lost(&self) -> u64 {
+        self.rx_dropped
+            .saturating_add(self.rx_errors)
+            .saturating_add(self.rx_missed_errors)
+    }
+}
+
+/// RX counters of the PMD, summed over the queues of one interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PmdCounters {
+    /// Frames delivered by the captures
+    pub packets: u64,
+    /// Frames the captures saw and the PMD dropped
+    pub drops: u64,
+}
+
+impl PmdCounters {
+    /// Frames the captures accounted for
+    pub fn seen(&self) -> u64 {
+        self.packets.saturating_add(self.drops)
+    }
+}
+
+/// Disagreement between kernel and PMD counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The kernel or NIC dropped frames
+    KernelDrops,
+    /// The kernel received frames no RX queue accounted for
+    Unseen,
+    /// RX queues accounted for more frames than the kernel received
+    Overcounted,
+}
+
+impl Discrepancy {
+    /// Short name for logs
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Discrepancy::KernelDrops => "kernel_drops",
+            Discrepancy::Unseen => "unseen",
+            Discrepancy::Overcounted => "overcounted",
+        }
+    }
+}
+
+/// Kernel and PMD counters over one interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossCheck {
+    pub kernel: KernelCounters,
+    pub pmd: PmdCounters,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl CrossCheck {
+    /// Frames the kernel received beyond those the RX queues accounted for
+    pub fn unseen(&self) -> u64 {
+        self.kernel.rx_packets.saturating_sub(self.pmd.seen())
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+impl fmt::Display for CrossCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kernel_rx={} kernel_lost={} pmd_rx={} pmd_drops={} unseen={}",
+            self.kernel.rx_packets,
+            self.kernel.lost(),
+            self.pmd.packets,
+            self.pmd.drops,
+            self.unseen()
+        )?;
+        for discrepancy in &self.discrepancies {
+            write!(f, " {}", discrepancy.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Periodically compares kernel interface counters with the PMD's
+pub struct InterfaceMonitor<F> {
+    dir: PathBuf,
+    tolerance: f64,
+    source: F,
+    last: Option<(KernelCounters, PmdCounters)>,
+}
+
+impl<F> InterfaceMonitor<F>
+where
+    F: FnMut() -> PmdCounters,
+{
+    /// Monitor `interface`, reading PMD counters from `source`
+    pub fn new(interface: &str, source: F) -> Self {
+        Self::with_dir(KernelCounters::dir(interface), source)
+    }
+
+    /// Monitor counters read from `dir` instead of sysfs
+    pub fn with_dir(dir: PathBuf, source: F) -> Self {
+        Self {
+            dir,
+            tolerance: DEFAULT_IFSTATS_TOLERANCE,
+            source,
+            last: None,
+        }
+    }
+
+    /// Set the fraction of kernel frames the PMD may differ by
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Read both counters now, returning the cross-check since the previous read
+    ///
+    /// The first read only sets the baseline and returns `None`.
+    pub fn check(&mut self) -> Result<Option<CrossCheck>> {
+        let kernel = KernelCounters::read_dir(&self.dir)?;
+        let pmd = (self.source)();
+        Ok(self
+            .last
+            .replace((kernel, pmd))
+            .map(|last| self.compare(&last, &(kernel, pmd))))
+    }
+
+    /// Cross-check of the change from `last` to `now`
+    pub fn compare(
+        &self,
+        last: &(KernelCounters, PmdCounters),
+        now: &(KernelCounters, PmdCounters),
+    ) -> CrossCheck {
+        let (kernel_last, pmd_last) = last;
+        let (kernel_now, pmd_now) = now;
+        let kernel = KernelCounters {
+            rx_packets: kernel_now.rx_packets.saturating_sub(kernel_last.rx_packets),
+            rx_dropped: kernel_now.rx_dropped.saturating_sub(kernel_last.rx_dropped),
+            rx_errors: kernel_now.rx_errors.saturating_sub(kernel_last.rx_errors),
+            rx_missed_errors: kernel_now
+                .rx_missed_errors
+                .saturating_sub(kernel_last.rx_missed_errors),
+        };
+        let pmd = PmdCounters {
+            packets: pmd_now.packets.saturating_sub(pmd_last.packets),
+            drops: pmd_now.drops.saturating_sub(pmd_last.drops),
+        };
+
+        let slack = (kernel.rx_packets as f64 * self.tolerance) as u64;
+        let mut discrepancies = Vec::new();
+        if kernel.lost() > 0 {
+            discrepancies.push(Discrepancy::KernelDrops);
+        }
+        if kernel.rx_packets.saturating_sub(pmd.seen()) > slack {
+            discrepancies.push(Discrepancy::Unseen);
+        }
+        if pmd.seen().saturating_sub(kernel.rx_packets) > slack {
+            discrepancies.push(Discrepancy::Overcounted);
+        }
+
+        CrossCheck {
+            kernel,
+            pmd,
+            discrepancies,
+        }
+    }
+}
+
+impl<F> InterfaceMonitor<F>
+where
+    F: FnMut() -> PmdCounters + Send + 'static,
+{
+    /// Cross-check and log every `interval` on a thread until `shutdown` is cancelled
+    pub fn spawn(
+        mut self,
+        interval: Duration,
+        shutdown: ShutdownToken,
+    ) -> std::io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name("xpdk-ifstats".to_string())
+            .spawn(move || {
+                let _ = self.check();
+                while !shutdown.wait_timeout(interval) {
+                    match self.check() {
+                        Ok(Some(check)) if !check.is_consistent() => {
+                            log::warn!(target: "xpdk::ifstats", "{}", check)
+                        }
+                        Ok(Some(check)) => log::debug!(target: "xpdk::ifstats", "{}", check),
+                        Ok(None) => {}
+                        Err(e) => log::debug!(
+                            target: "xpdk::ifstats",
+                            "Cannot read {}: {}",
+                            self.dir.display(),
+                            e
+                        ),
+                    }
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_cross_check_flags_discrepancies() {
+        let dir = std::env::temp_dir().join(format!("xpdk-ifstats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |rx_packets: u64, rx_dropped: u64| {
+            fs::write(dir.join("rx_packets"), format!("{}\n", rx_packets)).unwrap();
+            fs::write(dir.join("rx_dropped"), format!("{}\n", rx_dropped)).unwrap();
+            fs::write(dir.join("rx_errors"), "0\n").unwrap();
+        };
+        let pmd = Cell::new(PmdCounters::default());
+        let mut monitor = InterfaceMonitor::with_dir(dir.clone(), || pmd.get());
+        let mut step = |rx_packets, rx_dropped, packets, drops| {
+            write(rx_packets, rx_dropped);
+            pmd.set(PmdCounters { packets, drops });
+            monitor.check().unwrap()
+        };
+
+        assert!(step(1000, 0, 0, 0).is_none());
+        // Within tolerance
+        assert!(step(2000, 0, 995, 0).unwrap().is_consistent());
+
+        // Lost before the captures saw them
+        let check = step(3000, 4, 1900, 10).unwrap();
+        assert_eq!(
+            check.discrepancies,
+            [Discrepancy::KernelDrops, Discrepancy::Unseen]
+        );
+        assert_eq!(check.unseen(), 85);
+        assert_eq!(
+            check.to_string(),
+            "kernel_rx=1000 kernel_lost=4 pmd_rx=905 pmd_drops=10 unseen=85 kernel_drops unseen"
+        );
+
+        // Overlapping captures count frames twice
+        let check = step(3100, 4, 2100, 10).unwrap();
+        assert_eq!(check.discrepancies, [Discrepancy::Overcounted]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(monitor.check().is_err());
+    }
+}
@@ -1,12 +1,21 @@
 //! Logging utilities for XPDK
+//!
+//! Per-packet records go through [`PacketLog`], which logs a sample of the
+//! frames with their [`HeaderSummary`] and does nothing, not even count,
+//! while its target is disabled.
 
+use super::counter::Counter;
+use super::display::HeaderSummary;
 use log::{Level, LevelFilter, Log, Metadata, Record};
 use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Write};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Target of packet log records
+pub const PACKET_LOG_TARGET: &str = "xpdk::packet";
+
 /// XPDK logger
 pub struct XpdkLogger {
     /// Log writers
@@ -410,13 +419,77 @@ impl PerfLogger {
     /// Log with custom level
     pub fn log_with_level(self, level: Level) {
         let elapsed = self.start_time.elapsed();
-        let message = format!(
+        log::log!(
+            level,
             "Performance: {} took {} microseconds",
             self.operation,
             elapsed.as_micros()
         );
+    }
+}
+
+/// Sampled debug log of frames on the datapath
+///
+/// One in [`PacketLog::set_rate`] frames is logged at DEBUG under
+/// [`PACKET_LOG_TARGET`] with its header summary, formatted without
+/// allocating.
+#[derive(Debug, Default)]
+pub struct PacketLog {
+    /// Log one in this many frames, 0 for none
+    rate: AtomicU32,
+    seen: AtomicU64,
+    logged: Counter,
+}
+
+impl PacketLog {
+    /// Create a packet log logging nothing until a rate is set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the process-wide packet log
+    pub fn global() -> &'static PacketLog {
+        static GLOBAL: OnceLock<PacketLog> = OnceLock::new();
+        GLOBAL.get_or_init(PacketLog::new)
+    }
+
+    /// Log one in `rate` frames, 0 for none
+    pub fn set_rate(&self, rate: u32) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Frames logged so far
+    pub fn logged(&self) -> u64 {
+        self.logged.get()
+    }
+
+    /// Log `frame`, seen in `direction`, if it is sampled
+    pub fn log(&self, direction: &str, frame: &[u8]) {
+        if self.rate() == 0 || !log::log_enabled!(target: PACKET_LOG_TARGET, Level::Debug) {
+            return;
+        }
+        if self.sampled() {
+            log::debug!(target: PACKET_LOG_TARGET, "{} {}", direction, HeaderSummary(frame));
+        }
+    }
 
-        log::log!(level, "{}", message);
+    /// Count a frame, returning whether it is the one in `rate` to log
+    fn sampled(&self) -> bool {
+        let rate = self.rate() as u64;
+        if rate == 0
+            || !self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(rate)
+        {
+            return false;
+        }
+        self.logged.inc();
+        true
     }
 }
 
@@ -467,6 +540,20 @@ mod tests {
         assert!(writer.flush().is_ok());
     }
 
+    #[test]
+    fn test_packet_log_sampling() {
+        let packet_log = PacketLog::new();
+        // Off by default, and no logger is installed
+        packet_log.log("rx", &[0; 14]);
+        assert!(!packet_log.sampled());
+
+        packet_log.set_rate(3);
+        packet_log.log("rx", &[0; 14]);
+        let sampled: Vec<bool> = (0..6).map(|_| packet_log.sampled()).collect();
+        assert_eq!(sampled, [true, false, false, true, false, false]);
+        assert_eq!(packet_log.logged(), 2);
+    }
+
     #[test]
     fn test_logger_stats() {
         let stats = LoggerStats::default();
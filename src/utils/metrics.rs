@@ -0,0 +1,364 @@
+//! Application metrics alongside the stack's own
+//!
+//! Layers running above the stack, such as a DTLS record layer counting
+//! the bytes it encrypts, register named counters and gauges with the
+//! [`MetricsRegistry`] and update them directly. Registered metrics are
+//! carried in every [`StatsSnapshot`] the persister writes and rendered
+//! after the interface counters by [`render_prometheus`], so one scrape
+//! or snapshot holds both. Queue counters are rendered with the queue
+//! name as the `queue` label. Names follow the Prometheus rules and may not
+//! take the `xpdk_` prefix, which is kept for internal metrics.
+//! [`render_tables`] renders the entries and bytes of the tables
+//! registered with the memory budget, labelled by table name.
+//!
+//! [`StatsSnapshot`]: super::persist::StatsSnapshot
+
+pub use super::counter::Counter;
+use super::persist::{InterfaceStats, QueueCounters};
+use crate::memory::TableUsage;
+use crate::{Error, Result};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Prefix of the stack's own metric names
+pub const INTERNAL_PREFIX: &str = "xpdk_";
+
+/// Application value that goes up and down
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, value: i64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value of a metric when read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(i64),
+}
+
+/// Application metric as read for a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalMetric {
+    pub name: String,
+    pub help: String,
+    pub value: MetricValue,
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+}
+
+#[derive(Debug)]
+struct Registered {
+    help: String,
+    metric: Metric,
+}
+
+/// Named application counters and gauges
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    metrics: RwLock<BTreeMap<String, Registered>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process-wide registry read by snapshots and [`crate::Xpdk::prometheus_metrics`]
+    pub fn global() -> &'static MetricsRegistry {
+        static REGISTRY: OnceLock<MetricsRegistry> = OnceLock::new();
+        REGISTRY.get_or_init(MetricsRegistry::new)
+    }
+
+    /// Register the counter `name`, or get it if already registered
+    ///
+    /// Fails with [`Error::InvalidConfig`] on an invalid or reserved name,
+    /// a name or help over `u16::MAX` bytes, or if `name` is registered as
+    /// a gauge.
+    pub fn register_counter(&self, name: &str, help: &str) -> Result<Arc<Counter>> {
+        match self.register(name, help, || Metric::Counter(Arc::default()))? {
+            Metric::Counter(counter) => Ok(counter),
+            Metric::Gauge(_) => Err(Error::InvalidConfig(format!(
+                "Metric {} is registered as a gauge",
+                name
+            ))),
+        }
+    }
+
+    /// Register the gauge `name`, or get it if already registered
+    pub fn register_gauge(&self, name: &str, help: &str) -> Result<Arc<Gauge>> {
+        match self.register(name, help, || Metric::Gauge(Arc::default()))? {
+            Metric::Gauge(gauge) => Ok(gauge),
+            Metric::Counter(_) => Err(Error::InvalidConfig(format!(
+                "Metric {} is registered as a counter",
+                name
+            ))),
+        }
+    }
+
+    fn register(&self, name: &str, help: &str, create: impl FnOnce() -> Metric) -> Result<Metric> {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidConfig(format!(
+                "Invalid metric name '{}'",
+                name
+            )));
+        }
+        if name.starts_with(INTERNAL_PREFIX) {
+            return Err(Error::InvalidConfig(format!(
+                "Metric names starting with {} are reserved",
+                INTERNAL_PREFIX
+            )));
+        }
+        // Snapshots store both with a 16-bit length
+        if name.len().max(help.len()) > u16::MAX as usize {
+            return Err(Error::InvalidConfig(format!(
+                "Name or help of metric {} longer than {} bytes",
+                name,
+                u16::MAX
+            )));
+        }
+        let mut metrics = self.metrics.write();
+        let registered = metrics
+            .entry(name.to_string())
+            .or_insert_with(|| Registered {
+                help: help.to_string(),
+                metric: create(),
+            });
+        Ok(registered.metric.clone())
+    }
+
+    /// Drop the metric `name`, returning whether it was registered
+    pub fn unregister(&self, name: &str) -> bool {
+        self.metrics.write().remove(name).is_some()
+    }
+
+    /// Current values of every metric, ordered by name
+    pub fn snapshot(&self) -> Vec<ExternalMetric> {
+        self.metrics
+            .read()
+            .iter()
+            .map(|(name, registered)| ExternalMetric {
+                name: name.clone(),
+                help: registered.help.clone(),
+                value: match &registered.metric {
+                    Metric::Counter(counter) => MetricValue::Counter(counter.get()),
+                    Metric::Gauge(gauge) => MetricValue::Gauge(gauge.get()),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Prometheus metric names: `[a-zA-Z_:][a-zA-Z0-9_:]*`
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Name, help and reader of an interface counter
+type InterfaceCounter = (&'static str, &'static str, fn(&InterfaceStats) -> u64);
+
+/// Name, help and reader of a queue counter
+type QueueCounter = (&'static str, &'static str, fn(&QueueCounters) -> u64);
+
+/// Render interface counters and the metrics of `registry` in the Prometheus text format
+pub fn render_prometheus(interfaces: &[InterfaceStats], registry: &MetricsRegistry) -> String {
+    let mut out = String::new();
+    let counters: [InterfaceCounter; 8] = [
+        ("rx_packets", "Frames received", |s| s.rx_packets),
+        ("rx_bytes", "Bytes received", |s| s.rx_bytes),
+        ("rx_drops", "Frames dropped on receive", |s| s.rx_drops),
+        ("rx_errors", "Receive errors", |s| s.rx_errors),
+        ("tx_packets", "Frames sent", |s| s.tx_packets),
+        ("tx_bytes", "Bytes sent", |s| s.tx_bytes),
+        ("tx_drops", "Frames dropped on send", |s| s.tx_drops),
+        ("tx_errors", "Send errors", |s| s.tx_errors),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {}{}_total {}", INTERNAL_PREFIX, name, help);
+        let _ = writeln!(out, "# TYPE {}{}_total counter", INTERNAL_PREFIX, name);
+        for stats in interfaces {
+            let _ = writeln!(
+                out,
+                "{}{}_total{{interface=\"{}\",port=\"{}\"}} {}",
+                INTERNAL_PREFIX,
+                name,
+                escape(&stats.interface),
+                stats.port_id,
+                value(stats)
+            );
+        }
+    }
+    let counters: [QueueCounter; 4] = [
+        ("queue_packets", "Frames through the queue", |q| q.packets),
+        ("queue_bytes", "Bytes through the queue", |q| q.bytes),
+        ("queue_drops", "Frames dropped by the queue", |q| q.drops),
+        ("queue_errors", "Queue errors", |q| q.errors),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {}{}_total {}", INTERNAL_PREFIX, name, help);
+        let _ = writeln!(out, "# TYPE {}{}_total counter", INTERNAL_PREFIX, name);
+        for stats in interfaces {
+            for queue in &stats.queues {
+                let _ = writeln!(
+                    out,
+                    "{}{}_total{{interface=\"{}\",port=\"{}\",queue=\"{}\"}} {}",
+                    INTERNAL_PREFIX,
+                    name,
+                    escape(&stats.interface),
+                    stats.port_id,
+                    escape(&queue.name),
+                    value(queue)
+                );
+            }
+        }
+    }
+    for metric in registry.snapshot() {
+        let (kind, value) = match metric.value {
+            MetricValue::Counter(value) => ("counter", value.to_string()),
+            MetricValue::Gauge(value) => ("gauge", value.to_string()),
+        };
+        let _ = writeln!(out, "# HELP {} {}", metric.name, escape(&metric.help));
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+        let _ = writeln!(out, "{} {}", metric.name, value);
+    }
+    out
+}
+
+/// Name, help and reader of a table gauge
+type TableGauge = (&'static str, &'static str, fn(&TableUsage) -> usize);
+
+/// Render the usage of budgeted tables as Prometheus gauges
+pub fn render_tables(tables: &[TableUsage]) -> String {
+    let mut out = String::new();
+    let gauges: [TableGauge; 2] = [
+        ("table_entries", "Entries in the table", |t| t.entries),
+        ("table_bytes", "Budgeted bytes of the table", |t| t.bytes),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {}{} {}", INTERNAL_PREFIX, name, help);
+        let _ = writeln!(out, "# TYPE {}{} gauge", INTERNAL_PREFIX, name);
+        for table in tables {
+            let _ = writeln!(
+                out,
+                "{}{}{{table=\"{}\"}} {}",
+                INTERNAL_PREFIX,
+                name,
+                escape(&table.name),
+                value(table)
+            );
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registered_metrics_render_after_internal() {
+        let registry = MetricsRegistry::new();
+        let encrypted = registry
+            .register_counter("dtls_encrypted_bytes_total", "Bytes encrypted")
+            .unwrap();
+        let sessions = registry
+            .register_gauge("dtls_sessions", "Open DTLS sessions")
+            .unwrap();
+        encrypted.add(1200);
+        sessions.set(3);
+        sessions.add(-1);
+
+        // Registering again shares the metric, another kind or a bad name fails
+        registry
+            .register_counter("dtls_encrypted_bytes_total", "")
+            .unwrap()
+            .inc();
+        assert!(registry
+            .register_gauge("dtls_encrypted_bytes_total", "")
+            .is_err());
+        assert!(registry.register_counter("xpdk_rx_packets", "").is_err());
+        assert!(registry.register_counter("dtls bytes", "").is_err());
+        assert!(registry.register_counter("9lives", "").is_err());
+        let help = "h".repeat(u16::MAX as usize + 1);
+        assert!(registry.register_gauge("dtls_peers", &help).is_err());
+        assert!(registry.register_gauge("dtls_peers", &help[1..]).is_ok());
+        assert!(registry.unregister("dtls_peers"));
+
+        assert_eq!(
+            registry.snapshot(),
+            vec![
+                ExternalMetric {
+                    name: "dtls_encrypted_bytes_total".to_string(),
+                    help: "Bytes encrypted".to_string(),
+                    value: MetricValue::Counter(1201),
+                },
+                ExternalMetric {
+                    name: "dtls_sessions".to_string(),
+                    help: "Open DTLS sessions".to_string(),
+                    value: MetricValue::Gauge(2),
+                },
+            ]
+        );
+
+        let stats = InterfaceStats {
+            interface: "eth0".to_string(),
+            rx_packets: 7,
+            queues: vec![QueueCounters {
+                name: "dns".to_string(),
+                drops: 3,
+                ..QueueCounters::default()
+            }],
+            ..InterfaceStats::default()
+        };
+        let text = render_prometheus(&[stats], &registry);
+        assert!(text.contains("xpdk_rx_packets_total{interface=\"eth0\",port=\"0\"} 7\n"));
+        assert!(text
+            .contains("xpdk_queue_drops_total{interface=\"eth0\",port=\"0\",queue=\"dns\"} 3\n"));
+        assert!(text.contains("# TYPE dtls_sessions gauge\ndtls_sessions 2\n"));
+        assert!(text.find("xpdk_tx_errors_total").unwrap() < text.find("dtls_").unwrap());
+
+        let tables = [TableUsage {
+            name: "relay".to_string(),
+            entries: 4,
+            bytes: 256,
+        }];
+        let text = render_tables(&tables);
+        assert!(text.contains("xpdk_table_entries{table=\"relay\"} 4\n"));
+        assert!(
+            text.contains("# TYPE xpdk_table_bytes gauge\nxpdk_table_bytes{table=\"relay\"} 256\n")
+        );
+
+        assert!(registry.unregister("dtls_sessions"));
+        assert!(!registry.unregister("dtls_sessions"));
+        assert_eq!(registry.snapshot().len(), 1);
+    }
+}
@@ -2,10 +2,30 @@
 //!
 //! This module provides various utility functions and helpers for the XPDK system.
 
+pub mod alarm;
+pub mod backoff;
+pub mod capture;
+pub mod color;
 pub mod config;
+pub mod counter;
 pub mod cpu;
+pub mod display;
+pub mod ifstats;
 pub mod logging;
+pub mod metrics;
+pub mod pattern;
+pub mod persist;
+pub mod placement;
+pub mod preflight;
+pub mod profile;
+pub mod rand;
+pub mod sampler;
+pub mod sflow;
+pub mod shutdown;
+pub mod sizing;
 pub mod time;
+pub mod trace;
+pub mod warmup;
 
 #[cfg(feature = "numa")]
 pub mod numa;
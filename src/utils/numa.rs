@@ -5,7 +5,7 @@ use libc::{c_void, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE
 use parking_lot::Mutex;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -18,12 +18,32 @@ pub struct NumaNode {
     pub total_memory: u64,
     /// Free memory in bytes
     pub free_memory: u64,
+    /// Huge page pools of this node, one entry per page size
+    pub hugepages: Vec<NodeHugepages>,
     /// CPU cores belonging to this node
     pub cpu_cores: Vec<usize>,
     /// Distance to other nodes
     pub distances: HashMap<usize, u8>,
 }
 
+/// Huge page counts of one page size on a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeHugepages {
+    /// Page size in bytes
+    pub page_size: u64,
+    /// Reserved pages
+    pub total: u64,
+    /// Unused pages
+    pub free: u64,
+}
+
+/// Memory figures parsed from a node's meminfo
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct NodeMemInfo {
+    total: u64,
+    free: u64,
+}
+
 /// NUMA topology information
 #[derive(Debug, Default)]
 pub struct NumaTopology {
@@ -37,6 +57,52 @@ pub struct NumaTopology {
     pub numa_available: bool,
 }
 
+impl NumaTopology {
+    /// Get a node by ID
+    pub fn node(&self, node_id: usize) -> Option<&NumaNode> {
+        self.nodes.get(&node_id)
+    }
+
+    /// Total memory of a node in bytes
+    pub fn total_memory(&self, node_id: usize) -> Option<u64> {
+        self.node(node_id).map(|node| node.total_memory)
+    }
+
+    /// Free memory of a node in bytes
+    pub fn free_memory(&self, node_id: usize) -> Option<u64> {
+        self.node(node_id).map(|node| node.free_memory)
+    }
+
+    /// Free huge pages of a given size on a node
+    pub fn free_hugepages(&self, node_id: usize, page_size: u64) -> Option<u64> {
+        self.node(node_id)?
+            .hugepages
+            .iter()
+            .find(|pages| pages.page_size == page_size)
+            .map(|pages| pages.free)
+    }
+
+    /// Node with the most free memory, lowest ID on ties
+    pub fn node_with_most_free_memory(&self) -> Option<usize> {
+        self.nodes
+            .values()
+            .max_by(|a, b| a.free_memory.cmp(&b.free_memory).then(b.id.cmp(&a.id)))
+            .map(|node| node.id)
+    }
+
+    /// Re-read memory and huge page figures of every node
+    pub fn refresh(&mut self) -> Result<()> {
+        for (node_id, node) in self.nodes.iter_mut() {
+            let node_path = numa_node_path(*node_id);
+            let meminfo = read_numa_memory_info(&node_path, "meminfo")?;
+            node.total_memory = meminfo.total;
+            node.free_memory = meminfo.free;
+            node.hugepages = read_node_hugepages(&node_path);
+        }
+        Ok(())
+    }
+}
+
 /// NUMA memory allocator
 pub struct NumaAllocator {
     /// NUMA node ID
@@ -297,6 +363,32 @@ impl NumaAffinity {
         Ok(())
     }
 
+    /// Restrict future allocations of the current thread to a node
+    pub fn set_memory_policy(&self, node_id: usize) -> Result<()> {
+        if !self.topology.nodes.contains_key(&node_id) {
+            return Err(Error::NumaError(format!("NUMA node {} not found", node_id)));
+        }
+
+        let mask = node_mask(node_id);
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_set_mempolicy,
+                MPOL_BIND,
+                mask.as_ptr(),
+                mask.len() * NODEMASK_WORD_BITS + 1,
+            )
+        };
+        if result != 0 {
+            return Err(Error::NumaError(format!(
+                "set_mempolicy to NUMA node {} failed: {}",
+                node_id,
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Set NUMA affinity for the current process
     pub fn set_process_affinity(&self, node_id: usize) -> Result<()> {
         if !self.topology.numa_available {
@@ -326,6 +418,11 @@ impl NumaAffinity {
         &self.topology
     }
 
+    /// Re-read per-node memory figures
+    pub fn refresh(&mut self) -> Result<()> {
+        self.topology.refresh()
+    }
+
     /// Get optimal NUMA node for a given CPU core
     pub fn get_optimal_node_for_core(&self, core_id: usize) -> Option<usize> {
         self.topology.core_to_node.get(&core_id).copied()
@@ -333,17 +430,7 @@ impl NumaAffinity {
 
     /// Get NUMA node with most free memory
     pub fn get_node_with_most_memory(&self) -> Option<usize> {
-        let mut best_node = None;
-        let mut max_free = 0u64;
-
-        for (node_id, node) in &self.topology.nodes {
-            if node.free_memory > max_free {
-                max_free = node.free_memory;
-                best_node = Some(*node_id);
-            }
-        }
-
-        best_node
+        self.topology.node_with_most_free_memory()
     }
 }
 
@@ -400,14 +487,18 @@ fn detect_numa_topology() -> Result<NumaTopology> {
     })
 }
 
+/// Sysfs directory of a NUMA node
+fn numa_node_path(node_id: usize) -> PathBuf {
+    PathBuf::from(format!("/sys/devices/system/node/node{}", node_id))
+}
+
 /// Detect information for a specific NUMA node
 fn detect_numa_node_info(node_id: usize) -> Result<NumaNode> {
-    let node_path_str = format!("/sys/devices/system/node/node{}", node_id);
-    let node_path = Path::new(&node_path_str);
+    let node_path = numa_node_path(node_id);
 
     // Get memory information
-    let total_memory = read_numa_memory_info(node_path, "meminfo")?;
-    let free_memory = read_numa_memory_info(node_path, "meminfo")?; // Simplified
+    let meminfo = read_numa_memory_info(&node_path, "meminfo")?;
+    let hugepages = read_node_hugepages(&node_path);
 
     // Get CPU cores
     let cpu_cores = get_numa_cpu_cores(node_id)?;
@@ -417,21 +508,85 @@ fn detect_numa_node_info(node_id: usize) -> Result<NumaNode> {
 
     Ok(NumaNode {
         id: node_id,
-        total_memory,
-        free_memory,
+        total_memory: meminfo.total,
+        free_memory: meminfo.free,
+        hugepages,
         cpu_cores,
         distances,
     })
 }
 
 /// Read NUMA memory information
-fn read_numa_memory_info(node_path: &Path, file: &str) -> Result<u64> {
+fn read_numa_memory_info(node_path: &Path, file: &str) -> Result<NodeMemInfo> {
     let meminfo_path = node_path.join(file);
-    let _content = fs::read_to_string(meminfo_path)?;
+    let content = fs::read_to_string(meminfo_path)?;
+    parse_node_meminfo(&content)
+}
+
+/// Parse a node meminfo file ("Node 0 MemTotal:  32768 kB")
+fn parse_node_meminfo(content: &str) -> Result<NodeMemInfo> {
+    let mut total = None;
+    let mut free = None;
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace().skip(2);
+        let (key, value) = match (fields.next(), fields.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue,
+        };
+        let bytes = value.parse::<u64>()? * 1024;
+
+        match key {
+            "MemTotal:" => total = Some(bytes),
+            "MemFree:" => free = Some(bytes),
+            _ => {}
+        }
+    }
 
-    // Parse memory information (simplified)
-    // In a real implementation, you would parse the actual format
-    Ok(8 * 1024 * 1024 * 1024) // Default to 8GB
+    match (total, free) {
+        (Some(total), Some(free)) => Ok(NodeMemInfo { total, free }),
+        _ => Err(Error::NumaError(
+            "MemTotal or MemFree missing from node meminfo".to_string(),
+        )),
+    }
+}
+
+/// Read per-size huge page counts of a node
+fn read_node_hugepages(node_path: &Path) -> Vec<NodeHugepages> {
+    let mut hugepages = Vec::new();
+    let entries = match fs::read_dir(node_path.join("hugepages")) {
+        Ok(entries) => entries,
+        Err(_) => return hugepages,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let page_size = match parse_hugepage_dir(&name.to_string_lossy()) {
+            Some(page_size) => page_size,
+            None => continue,
+        };
+        let read_count = |file: &str| {
+            fs::read_to_string(entry.path().join(file))
+                .ok()
+                .and_then(|count| count.trim().parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+
+        hugepages.push(NodeHugepages {
+            page_size,
+            total: read_count("nr_hugepages"),
+            free: read_count("free_hugepages"),
+        });
+    }
+
+    hugepages.sort_by_key(|pages| pages.page_size);
+    hugepages
+}
+
+/// Parse a huge page directory name ("hugepages-2048kB") into bytes
+fn parse_hugepage_dir(name: &str) -> Option<u64> {
+    let size_kb = name.strip_prefix("hugepages-")?.strip_suffix("kB")?;
+    Some(size_kb.parse::<u64>().ok()? * 1024)
 }
 
 /// Get CPU cores for a NUMA node
@@ -512,13 +667,34 @@ fn get_current_numa_node() -> Option<usize> {
     None
 }
 
+/// Strict binding memory policy
+const MPOL_BIND: libc::c_int = 2;
+/// Fail mbind if existing pages do not follow the policy
+const MPOL_MF_STRICT: libc::c_uint = 1;
+/// Move existing pages to follow the policy
+const MPOL_MF_MOVE: libc::c_uint = 2;
+
+/// Bits per nodemask word
+const NODEMASK_WORD_BITS: usize = libc::c_ulong::BITS as usize;
+
+/// Build a nodemask with a single node set
+fn node_mask(node_id: usize) -> Vec<libc::c_ulong> {
+    let mut mask = vec![0; node_id / NODEMASK_WORD_BITS + 1];
+    mask[node_id / NODEMASK_WORD_BITS] |= 1 << (node_id % NODEMASK_WORD_BITS);
+    mask
+}
+
+/// System page size
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
 /// Bind memory to NUMA node
-fn bind_memory_to_node(_ptr: *mut c_void, _size: usize, _node_id: usize) -> Result<()> {
+fn bind_memory_to_node(ptr: *mut c_void, size: usize, node_id: usize) -> Result<()> {
     #[cfg(feature = "libnuma")]
     unsafe {
         if numa_available() != -1 {
-            let result =
-                numa_tonode_memory(_ptr as *mut libc::c_void, _size, _node_id as libc::c_int);
+            let result = numa_tonode_memory(ptr as *mut libc::c_void, size, node_id as libc::c_int);
             if result != 0 {
                 return Err(Error::NumaError(
                     "Failed to bind memory to NUMA node".to_string(),
@@ -528,11 +704,105 @@ fn bind_memory_to_node(_ptr: *mut c_void, _size: usize, _node_id: usize) -> Resu
         }
     }
 
-    // Fallback: use mbind with libnuma if available
-    // For now, just return Ok as the memory was allocated with the correct affinity
+    let mask = node_mask(node_id);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            size,
+            MPOL_BIND,
+            mask.as_ptr(),
+            mask.len() * NODEMASK_WORD_BITS + 1,
+            MPOL_MF_STRICT | MPOL_MF_MOVE,
+        )
+    };
+    if result != 0 {
+        return Err(Error::NumaError(format!(
+            "mbind to NUMA node {} failed: {}",
+            node_id,
+            std::io::Error::last_os_error()
+        )));
+    }
+
+    // Fault the pages in so their placement can be checked
+    let page_size = page_size();
+    for offset in (0..size).step_by(page_size) {
+        unsafe { ptr::write_volatile((ptr as *mut u8).add(offset), 0) };
+    }
+
+    verify_node_placement(ptr, size, node_id)
+}
+
+/// Check that every page of a region resides on `node_id`
+fn verify_node_placement(ptr: *mut c_void, size: usize, node_id: usize) -> Result<()> {
+    let page_size = page_size();
+    let pages: Vec<*mut c_void> = (0..size)
+        .step_by(page_size)
+        .map(|offset| unsafe { (ptr as *mut u8).add(offset) as *mut c_void })
+        .collect();
+    let nodes = query_page_nodes(&pages)?;
+
+    let misplaced = nodes
+        .iter()
+        .filter(|&&node| node != node_id as libc::c_int)
+        .count();
+    if misplaced > 0 {
+        return Err(Error::NumaError(format!(
+            "{} of {} pages did not land on NUMA node {} (first on {})",
+            misplaced,
+            pages.len(),
+            node_id,
+            nodes
+                .iter()
+                .find(|&&node| node != node_id as libc::c_int)
+                .copied()
+                .unwrap_or_default()
+        )));
+    }
+
     Ok(())
 }
 
+/// Query the node of each page with move_pages
+///
+/// Negative entries are errno values for pages that are not resident.
+fn query_page_nodes(pages: &[*mut c_void]) -> Result<Vec<libc::c_int>> {
+    let mut status: Vec<libc::c_int> = vec![0; pages.len()];
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_move_pages,
+            0,
+            pages.len(),
+            pages.as_ptr(),
+            ptr::null::<libc::c_int>(),
+            status.as_mut_ptr(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(Error::NumaError(format!(
+            "move_pages query failed: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+
+    Ok(status)
+}
+
+/// NUMA node holding the page at `addr`
+pub fn memory_node(addr: *const c_void) -> Result<usize> {
+    let page = (addr as usize & !(page_size() - 1)) as *mut c_void;
+    let node = query_page_nodes(&[page])?[0];
+    if node < 0 {
+        return Err(Error::NumaError(format!(
+            "page at {:p} is not resident: {}",
+            addr,
+            std::io::Error::from_raw_os_error(-node)
+        )));
+    }
+    Ok(node as usize)
+}
+
 /// NUMA-aware memory manager
 pub struct NumaMemoryManager {
     /// NUMA affinity manager
@@ -667,12 +937,73 @@ mod tests {
         println!("Current affinity: {:?}", affinity.get_current_affinity());
     }
 
+    #[test]
+    fn test_parse_node_meminfo() {
+        let content = "Node 0 MemTotal:       32768000 kB\n\
+                       Node 0 MemFree:        1024000 kB\n\
+                       Node 0 HugePages_Total:     0\n";
+        let meminfo = parse_node_meminfo(content).unwrap();
+        assert_eq!(meminfo.total, 32768000 * 1024);
+        assert_eq!(meminfo.free, 1024000 * 1024);
+
+        assert!(parse_node_meminfo("Node 0 MemTotal: 1 kB\n").is_err());
+        assert_eq!(
+            parse_hugepage_dir("hugepages-2048kB"),
+            Some(2 * 1024 * 1024)
+        );
+        assert_eq!(parse_hugepage_dir("power"), None);
+    }
+
+    #[test]
+    fn test_node_with_most_free_memory() {
+        let node = |id, free_memory| NumaNode {
+            id,
+            total_memory: 64 << 30,
+            free_memory,
+            hugepages: vec![NodeHugepages {
+                page_size: 2 << 20,
+                total: 8,
+                free: id as u64,
+            }],
+            cpu_cores: Vec::new(),
+            distances: HashMap::new(),
+        };
+        let topology = NumaTopology {
+            num_nodes: 3,
+            nodes: [
+                (0, node(0, 1 << 30)),
+                (1, node(1, 4 << 30)),
+                (2, node(2, 4 << 30)),
+            ]
+            .into_iter()
+            .collect(),
+            core_to_node: HashMap::new(),
+            numa_available: true,
+        };
+
+        assert_eq!(topology.node_with_most_free_memory(), Some(1));
+        assert_eq!(topology.free_memory(0), Some(1 << 30));
+        assert_eq!(topology.free_hugepages(2, 2 << 20), Some(2));
+        assert_eq!(topology.free_hugepages(2, 1 << 30), None);
+    }
+
+    #[test]
+    fn test_node_mask() {
+        assert_eq!(node_mask(0), vec![1]);
+        assert_eq!(node_mask(3), vec![8]);
+
+        let mask = node_mask(NODEMASK_WORD_BITS + 1);
+        assert_eq!(mask.len(), 2);
+        assert_eq!(mask[1], 2);
+    }
+
     #[test]
     fn test_numa_allocator() {
         if is_numa_available() {
             let allocator = NumaAllocator::new(0).unwrap();
             let ptr = allocator.allocate(1024).unwrap();
             assert!(!ptr.is_null());
+            assert_eq!(memory_node(ptr).unwrap(), 0);
             allocator.deallocate(ptr, 1024).unwrap();
         }
     }
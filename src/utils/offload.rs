@@ -4,8 +4,8 @@
 //! calculation, TCP segmentation, RSS hashing, and other network optimizations.
 
 use crate::{
-    memory::{Mbuf, OffloadFlags},
-    Error, Result,
+    memory::{Mbuf, OffloadFlags, PacketType},
+    udp, Error, Result,
 };
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -53,6 +53,8 @@ pub struct ChecksumStats {
     pub calculated: AtomicUsize,
     pub hardware_offloaded: AtomicUsize,
     pub software_fallback: AtomicUsize,
+    /// Checksums patched for changed fields instead of recalculated
+    pub incremental: AtomicUsize,
     pub errors: AtomicUsize,
 }
 
@@ -113,6 +115,18 @@ impl ChecksumCalculator {
         }
     }
 
+    /// Update an existing checksum for a 16-bit field changed from `old` to `new`
+    pub fn update_u16(&self, checksum: u16, old: u16, new: u16) -> u16 {
+        self.stats.incremental.fetch_add(1, Ordering::Relaxed);
+        udp::checksum_update_u16(checksum, old, new)
+    }
+
+    /// Update an existing checksum for a 32-bit field changed from `old` to `new`, e.g. an address
+    pub fn update_u32(&self, checksum: u16, old: u32, new: u32) -> u16 {
+        self.stats.incremental.fetch_add(1, Ordering::Relaxed);
+        udp::checksum_update_u32(checksum, old, new)
+    }
+
     /// Software IPv4 checksum calculation
     fn calculate_ipv4_checksum_software(&self, header: &[u8]) -> Result<u16> {
         if header.len() < 20 {
@@ -521,15 +535,14 @@ impl OffloadManager {
 
         // Calculate RSS hash if enabled
         if self.capabilities.rss {
-            let _hash = self.rss_calculator.calculate(data)?;
-            mbuf_ref.offload_flags |= OffloadFlags::RSS_HASH;
-            // Store hash in mbuf (simplified)
+            let hash = self.rss_calculator.calculate(data)?;
+            mbuf_ref.set_rss_hash(hash);
         }
 
         // Add timestamp if enabled
         if self.capabilities.timestamp {
             mbuf_ref.timestamp = self.get_hardware_timestamp();
-            mbuf_ref.offload_flags |= OffloadFlags::TIMESTAMP;
+            mbuf_ref.offload_flags |= OffloadFlags::RX_TIMESTAMP;
             self.stats
                 .timestamp_operations
                 .fetch_add(1, Ordering::Relaxed);
@@ -544,31 +557,31 @@ impl OffloadManager {
             return Err(Error::OffloadError("Null mbuf".to_string()));
         }
 
-        let mbuf_ref = unsafe { &*mbuf };
-        let data = unsafe { std::slice::from_raw_parts(mbuf_ref.data, mbuf_ref.len) };
+        let mbuf_ref = unsafe { &mut *mbuf };
+        if mbuf_ref.packet_type == PacketType::Unknown {
+            udp::classify(mbuf_ref);
+        }
 
         self.stats
             .checksum_operations
             .fetch_add(1, Ordering::Relaxed);
 
+        // Offsets come from classification, so IPv4 options are skipped
+        let data = mbuf_ref.data();
+        let l3 = mbuf_ref.l3_offset as usize;
+        let l4 = mbuf_ref.l4_offset as usize;
+        if l3 == 0 || data[l3] >> 4 != 4 {
+            return Err(Error::OffloadError("Not an IPv4 packet".to_string()));
+        }
+        let src_ip = [data[l3 + 12], data[l3 + 13], data[l3 + 14], data[l3 + 15]];
+        let dst_ip = [data[l3 + 16], data[l3 + 17], data[l3 + 18], data[l3 + 19]];
+
         match checksum_type {
-            ChecksumType::IPv4 => {
-                // Extract IPv4 header (simplified)
-                if data.len() >= 20 {
-                    self.checksum_calculator.ipv4_checksum(&data[..20])
-                } else {
-                    Err(Error::OffloadError(
-                        "Packet too small for IPv4 header".to_string(),
-                    ))
-                }
-            }
+            ChecksumType::IPv4 => self.checksum_calculator.ipv4_checksum(&data[l3..l4]),
             ChecksumType::UDP => {
-                // Extract UDP header and data (simplified)
-                if data.len() >= 28 {
-                    let src_ip = [data[26], data[27], data[28], data[29]];
-                    let dst_ip = [data[30], data[31], data[32], data[33]];
+                if mbuf_ref.packet_type == PacketType::Udp {
                     self.checksum_calculator
-                        .udp_checksum(&data[34..], src_ip, dst_ip)
+                        .udp_checksum(&data[l4..], src_ip, dst_ip)
                 } else {
                     Err(Error::OffloadError(
                         "Packet too small for UDP header".to_string(),
@@ -576,12 +589,9 @@ impl OffloadManager {
                 }
             }
             ChecksumType::TCP => {
-                // Extract TCP header and data (simplified)
-                if data.len() >= 40 {
-                    let src_ip = [data[26], data[27], data[28], data[29]];
-                    let dst_ip = [data[30], data[31], data[32], data[33]];
+                if mbuf_ref.packet_type == PacketType::Tcp && data.len() >= l4 + 20 {
                     self.checksum_calculator
-                        .tcp_checksum(&data[34..], src_ip, dst_ip)
+                        .tcp_checksum(&data[l4..], src_ip, dst_ip)
                 } else {
                     Err(Error::OffloadError(
                         "Packet too small for TCP header".to_string(),
@@ -660,6 +670,15 @@ mod tests {
 
         let checksum = calculator.ipv4_checksum(&ipv4_header).unwrap();
         assert!(checksum > 0);
+
+        // Rewriting the source address patches to the recalculated checksum
+        let mut rewritten = ipv4_header.clone();
+        rewritten[12..16].copy_from_slice(&[10, 0, 0, 7]);
+        assert_eq!(
+            calculator.update_u32(checksum, 0x7f00_0001, 0x0a00_0007),
+            calculator.ipv4_checksum(&rewritten).unwrap()
+        );
+        assert_eq!(calculator.stats.incremental.load(Ordering::Relaxed), 1);
     }
 
     #[test]
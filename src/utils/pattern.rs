@@ -0,0 +1,469 @@
+//! Multi-pattern payload matching
+//!
+//! Pattern sets are compiled once at startup into an Aho-Corasick automaton
+//! and applied to packets as a processing stage, installed on a stack with
+//! [`crate::udp::UdpStack::set_patterns`]. On x86_64 the scan skips ahead
+//! with SSE2 while no partial match is in progress.
+
+use crate::memory::{Mbuf, PacketType};
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Root state of the automaton
+const ROOT: u32 = 0;
+
+/// Largest first-byte set the SIMD prefilter handles
+const SIMD_MAX_FIRST_BYTES: usize = 3;
+
+/// Action taken when a pattern matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchAction {
+    /// Set `Mbuf::mark` to the given value
+    Mark(u32),
+    /// Drop the packet
+    Drop,
+    /// Hand a copy of the packet to a mirror port
+    Mirror,
+}
+
+/// Outcome of running a packet through a pattern set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchVerdict {
+    /// No pattern matched
+    Pass,
+    /// Packet was marked
+    Mark(u32),
+    /// Packet should be dropped
+    Drop,
+    /// Packet should be mirrored
+    Mirror,
+}
+
+/// A single pattern occurrence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternMatch {
+    /// Pattern ID as returned by [`PatternSetBuilder::add`]
+    pub pattern: usize,
+    /// Offset of the first matched byte
+    pub start: usize,
+    /// Offset one past the last matched byte
+    pub end: usize,
+}
+
+/// Builder for a compiled pattern set
+#[derive(Debug, Clone)]
+pub struct PatternSetBuilder {
+    patterns: Vec<(Vec<u8>, MatchAction)>,
+    simd: bool,
+}
+
+impl PatternSetBuilder {
+    /// Create an empty builder
+    pub fn new() -> Self {
+        Self {
+            patterns: Vec::new(),
+            simd: true,
+        }
+    }
+
+    /// Add a pattern, returning its ID
+    pub fn add(&mut self, pattern: &[u8], action: MatchAction) -> usize {
+        self.patterns.push((pattern.to_vec(), action));
+        self.patterns.len() - 1
+    }
+
+    /// Enable or disable the SIMD prefilter
+    pub fn simd(&mut self, enabled: bool) -> &mut Self {
+        self.simd = enabled;
+        self
+    }
+
+    /// Compile the automaton
+    pub fn build(&self) -> Result<PatternSet> {
+        if self.patterns.is_empty() {
+            return Err(Error::InvalidConfig("Pattern set is empty".to_string()));
+        }
+        if self.patterns.iter().any(|(pattern, _)| pattern.is_empty()) {
+            return Err(Error::InvalidConfig("Empty pattern".to_string()));
+        }
+
+        // Trie with unresolved transitions
+        let mut transitions: Vec<[Option<u32>; 256]> = vec![[None; 256]];
+        let mut outputs: Vec<Vec<usize>> = vec![Vec::new()];
+
+        for (id, (pattern, _)) in self.patterns.iter().enumerate() {
+            let mut state = ROOT as usize;
+            for &byte in pattern {
+                state = match transitions[state][byte as usize] {
+                    Some(next) => next as usize,
+                    None => {
+                        transitions.push([None; 256]);
+                        outputs.push(Vec::new());
+                        let next = transitions.len() - 1;
+                        transitions[state][byte as usize] = Some(next as u32);
+                        next
+                    }
+                };
+            }
+            outputs[state].push(id);
+        }
+
+        // Resolve failure links breadth-first into a full DFA
+        let state_count = transitions.len();
+        let mut dfa = vec![ROOT; state_count * 256];
+        let mut fail = vec![ROOT; state_count];
+        let mut queue = std::collections::VecDeque::new();
+
+        for byte in 0..256 {
+            if let Some(next) = transitions[ROOT as usize][byte] {
+                dfa[byte] = next;
+                queue.push_back(next as usize);
+            }
+        }
+
+        while let Some(state) = queue.pop_front() {
+            let fallback = fail[state] as usize;
+            let inherited = outputs[fallback].clone();
+            outputs[state].extend(inherited);
+
+            for byte in 0..256 {
+                match transitions[state][byte] {
+                    Some(next) => {
+                        fail[next as usize] = dfa[fallback * 256 + byte];
+                        dfa[state * 256 + byte] = next;
+                        queue.push_back(next as usize);
+                    }
+                    None => dfa[state * 256 + byte] = dfa[fallback * 256 + byte],
+                }
+            }
+        }
+
+        let mut first_bytes: Vec<u8> = self
+            .patterns
+            .iter()
+            .map(|(pattern, _)| pattern[0])
+            .collect();
+        first_bytes.sort_unstable();
+        first_bytes.dedup();
+
+        Ok(PatternSet {
+            dfa,
+            outputs,
+            lengths: self
+                .patterns
+                .iter()
+                .map(|(pattern, _)| pattern.len())
+                .collect(),
+            actions: self.patterns.iter().map(|(_, action)| *action).collect(),
+            hits: self.patterns.iter().map(|_| AtomicUsize::new(0)).collect(),
+            simd: self.simd && first_bytes.len() <= SIMD_MAX_FIRST_BYTES,
+            first_bytes,
+            stats: PatternStats::default(),
+        })
+    }
+}
+
+impl Default for PatternSetBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Pattern matching statistics
+#[derive(Debug, Default)]
+pub struct PatternStats {
+    pub packets_scanned: AtomicUsize,
+    pub bytes_scanned: AtomicUsize,
+    pub packets_matched: AtomicUsize,
+    pub packets_dropped: AtomicUsize,
+    pub packets_mirrored: AtomicUsize,
+}
+
+/// Compiled multi-pattern matcher
+pub struct PatternSet {
+    /// Transition table, 256 entries per state
+    dfa: Vec<u32>,
+    /// Patterns ending in each state
+    outputs: Vec<Vec<usize>>,
+    /// Pattern lengths
+    lengths: Vec<usize>,
+    /// Pattern actions
+    actions: Vec<MatchAction>,
+    /// Per-pattern occurrence counters
+    hits: Vec<AtomicUsize>,
+    /// Distinct first bytes of all patterns
+    first_bytes: Vec<u8>,
+    /// Whether the SIMD prefilter is used
+    simd: bool,
+    /// Matcher statistics
+    stats: PatternStats,
+}
+
+impl PatternSet {
+    /// Number of patterns
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    /// Check whether the set has no patterns
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    /// Call `f` for every occurrence until it returns `false`
+    pub fn for_each_match<F>(&self, haystack: &[u8], mut f: F)
+    where
+        F: FnMut(PatternMatch) -> bool,
+    {
+        let mut state = ROOT;
+        let mut pos = 0;
+
+        while pos < haystack.len() {
+            if state == ROOT && self.simd {
+                pos = self.skip_to_candidate(haystack, pos);
+                if pos == haystack.len() {
+                    break;
+                }
+            }
+
+            state = self.dfa[state as usize * 256 + haystack[pos] as usize];
+            pos += 1;
+
+            for &pattern in &self.outputs[state as usize] {
+                let found = PatternMatch {
+                    pattern,
+                    start: pos - self.lengths[pattern],
+                    end: pos,
+                };
+                if !f(found) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Collect all occurrences
+    pub fn find_all(&self, haystack: &[u8]) -> Vec<PatternMatch> {
+        let mut matches = Vec::new();
+        self.for_each_match(haystack, |found| {
+            matches.push(found);
+            true
+        });
+        matches
+    }
+
+    /// Check whether any pattern occurs
+    pub fn is_match(&self, haystack: &[u8]) -> bool {
+        let mut matched = false;
+        self.for_each_match(haystack, |_| {
+            matched = true;
+            false
+        });
+        matched
+    }
+
+    /// Scan a packet and apply the strongest matching action
+    ///
+    /// Classified UDP packets are scanned from the payload, anything else
+    /// from the start of the frame. Drop wins over mirror, mirror over mark;
+    /// among marks the first occurrence wins.
+    pub fn process(&self, mbuf: &mut Mbuf) -> MatchVerdict {
+        let start = if mbuf.packet_type == PacketType::Udp {
+            mbuf.payload_offset as usize
+        } else {
+            0
+        };
+        let data = &mbuf.data()[start.min(mbuf.len)..];
+
+        let mut verdict = MatchVerdict::Pass;
+        self.for_each_match(data, |found| {
+            self.hits[found.pattern].fetch_add(1, Ordering::Relaxed);
+            verdict = match (verdict, self.actions[found.pattern]) {
+                (_, MatchAction::Drop) => MatchVerdict::Drop,
+                (MatchVerdict::Drop, _) => MatchVerdict::Drop,
+                (_, MatchAction::Mirror) => MatchVerdict::Mirror,
+                (MatchVerdict::Pass, MatchAction::Mark(mark)) => MatchVerdict::Mark(mark),
+                (current, MatchAction::Mark(_)) => current,
+            };
+            true
+        });
+
+        self.stats.packets_scanned.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_scanned
+            .fetch_add(data.len(), Ordering::Relaxed);
+
+        match verdict {
+            MatchVerdict::Pass => return verdict,
+            MatchVerdict::Mark(mark) => mbuf.mark = mark,
+            MatchVerdict::Drop => {
+                self.stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            MatchVerdict::Mirror => {
+                self.stats.packets_mirrored.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.stats.packets_matched.fetch_add(1, Ordering::Relaxed);
+
+        verdict
+    }
+
+    /// Occurrences counted for a pattern
+    pub fn hits(&self, pattern: usize) -> usize {
+        self.hits
+            .get(pattern)
+            .map(|hits| hits.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Get matcher statistics
+    pub fn stats(&self) -> &PatternStats {
+        &self.stats
+    }
+
+    /// Find the next position that can start a match
+    fn skip_to_candidate(&self, haystack: &[u8], pos: usize) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            use std::arch::x86_64::*;
+
+            let mut pos = pos;
+            // SSE2 is part of the x86_64 baseline
+            unsafe {
+                let needles: Vec<__m128i> = self
+                    .first_bytes
+                    .iter()
+                    .map(|&byte| _mm_set1_epi8(byte as i8))
+                    .collect();
+
+                while pos + 16 <= haystack.len() {
+                    let chunk = _mm_loadu_si128(haystack.as_ptr().add(pos) as *const __m128i);
+                    let mut mask = 0;
+                    for needle in &needles {
+                        mask |= _mm_movemask_epi8(_mm_cmpeq_epi8(chunk, *needle));
+                    }
+                    if mask != 0 {
+                        return pos + mask.trailing_zeros() as usize;
+                    }
+                    pos += 16;
+                }
+            }
+
+            self.skip_scalar(haystack, pos)
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            self.skip_scalar(haystack, pos)
+        }
+    }
+
+    /// Scalar fallback for [`PatternSet::skip_to_candidate`]
+    fn skip_scalar(&self, haystack: &[u8], pos: usize) -> usize {
+        haystack[pos..]
+            .iter()
+            .position(|byte| self.first_bytes.contains(byte))
+            .map(|offset| pos + offset)
+            .unwrap_or(haystack.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+
+    fn build(simd: bool) -> PatternSet {
+        let mut builder = PatternSetBuilder::new();
+        builder.add(b"he", MatchAction::Mark(1));
+        builder.add(b"she", MatchAction::Mark(2));
+        builder.add(b"his", MatchAction::Mirror);
+        builder.add(b"hers", MatchAction::Drop);
+        builder.simd(simd).build().unwrap()
+    }
+
+    #[test]
+    fn test_overlapping_matches() {
+        for simd in [true, false] {
+            let set = build(simd);
+            let haystack = b"..............ushers and his";
+            let found: Vec<(usize, usize)> = set
+                .find_all(haystack)
+                .iter()
+                .map(|found| (found.pattern, found.start))
+                .collect();
+
+            assert_eq!(found, vec![(1, 15), (0, 16), (3, 16), (2, 25)]);
+            assert!(!set.is_match(b"nothing to see"));
+        }
+    }
+
+    #[test]
+    fn test_process_applies_actions() {
+        let set = build(true);
+        let pool = MbufPool::new("dpi".to_string(), 4, 2048).unwrap();
+        let mbuf = pool.alloc().unwrap();
+        let mbuf_ref = unsafe { &mut *mbuf };
+
+        mbuf_ref.append(b"say he").unwrap();
+        assert_eq!(set.process(mbuf_ref), MatchVerdict::Mark(1));
+        assert_eq!(mbuf_ref.mark, 1);
+
+        mbuf_ref.append(b" and hers").unwrap();
+        assert_eq!(set.process(mbuf_ref), MatchVerdict::Drop);
+        assert_eq!(set.hits(0), 3);
+        assert_eq!(set.hits(3), 1);
+        assert_eq!(set.stats().packets_matched.load(Ordering::Relaxed), 2);
+
+        pool.free(mbuf).unwrap();
+    }
+
+    #[test]
+    fn test_stack_stage_drops_and_mirrors() {
+        use crate::poll::TxQueue;
+        use crate::udp::testing::{load, FrameBuilder};
+        use crate::udp::{Delivery, DropReason, UdpStack};
+        use crate::Config;
+        use std::sync::Arc;
+
+        let pool = Arc::new(MbufPool::new("dpi".to_string(), 8, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        let id = stack
+            .create_socket("0.0.0.0:7000".parse().unwrap())
+            .unwrap();
+        let patterns = Arc::new(build(true));
+        let mirror = Arc::new(TxQueue::in_memory(0));
+        stack.set_patterns(Some(patterns.clone()));
+        stack.set_mirror_queue(Some(mirror.clone()));
+        stack.start().unwrap();
+
+        let frame = |payload: &[u8]| FrameBuilder::to_port(7000).payload(payload).build();
+        let dropped = load(&pool, &frame(b"for hers"));
+        assert_eq!(
+            stack.dispatch(dropped),
+            Delivery::Dropped(DropReason::Pattern)
+        );
+        pool.free(dropped).unwrap();
+
+        // A mirrored frame is copied out as received and still delivered
+        let mirrored = frame(b"not his");
+        assert!(stack.dispatch(load(&pool, &mirrored)).is_delivered());
+        assert!(stack.dispatch(load(&pool, &frame(b"plain"))).is_delivered());
+        assert_eq!(mirror.sent_frames(), [mirrored]);
+        let socket = stack.get_socket(id).unwrap();
+        assert_eq!(socket.recv_copied().unwrap().1, b"not his");
+        assert_eq!(socket.recv_copied().unwrap().1, b"plain");
+        assert_eq!(patterns.stats().packets_mirrored.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_rejects_empty_patterns() {
+        assert!(PatternSetBuilder::new().build().is_err());
+
+        let mut builder = PatternSetBuilder::new();
+        builder.add(b"", MatchAction::Drop);
+        assert!(builder.build().is_err());
+    }
+}
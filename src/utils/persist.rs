@@ -0,0 +1,650 @@
+//! Statistics persistence across restarts
+//!
+//! Counters live in memory and start from zero in every process, so an
+//! upgrade restart resets long-term dashboards. A [`StatsPersister`] writes
+//! a [`StatsSnapshot`] of every interface's counters to a file each
+//! interval and once more on shutdown, in a compact binary form or as
+//! JSON. The file is replaced atomically, so a crash leaves the previous
+//! snapshot intact. On startup with [`StatsPersistence::restore`] set, the
+//! counters found for the interface become a baseline that
+//! [`crate::Xpdk::interface_stats`] adds to the live counters, and the next
+//! snapshot carries the totals forward. Each interface also carries the
+//! counters of its queues under their names, restored like the totals, so
+//! a queue keeps its counters across restarts as long as it keeps its
+//! name. Snapshots also carry the counters of the stack's sockets and of
+//! the registered workers, see [`crate::Xpdk::register_worker`], under
+//! their names, and the application metrics of
+//! [`MetricsRegistry::global`], all recorded but not restored.
+//!
+//! Snapshots carry [`SNAPSHOT_VERSION`]. Loading accepts every version up
+//! to the current one and rejects files written by a later release with
+//! [`Error::InvalidConfig`], in which case counters start from zero.
+
+use crate::poll::{RxQueue, TxQueue};
+use crate::utils::metrics::{ExternalMetric, MetricValue, MetricsRegistry};
+use crate::utils::shutdown::ShutdownToken;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Schema version of snapshots written by this release
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// Leading bytes of a binary snapshot
+const SNAPSHOT_MAGIC: &[u8; 4] = b"XPST";
+
+/// Default time between snapshots
+pub const DEFAULT_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Encoding of a snapshot file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotFormat {
+    /// Fixed little-endian layout after a magic and version header
+    #[default]
+    Binary,
+    /// Human-readable JSON object
+    Json,
+}
+
+/// Where and how often counters are persisted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsPersistence {
+    pub path: PathBuf,
+    pub format: SnapshotFormat,
+    /// Time between snapshots
+    pub interval: Duration,
+    /// Start from the counters in `path` instead of zero
+    pub restore: bool,
+}
+
+impl StatsPersistence {
+    /// Persist to `path` in binary every [`DEFAULT_PERSIST_INTERVAL`], restoring on startup
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            format: SnapshotFormat::default(),
+            interval: DEFAULT_PERSIST_INTERVAL,
+            restore: true,
+        }
+    }
+}
+
+/// Cumulative counters of one interface
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceStats {
+    /// Interface name the counters belong to
+    pub interface: String,
+    pub port_id: u16,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_drops: u64,
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_drops: u64,
+    pub tx_errors: u64,
+    /// Counters of each RX and TX queue, since version 3
+    #[serde(default)]
+    pub queues: Vec<QueueCounters>,
+}
+
+/// Cumulative counters of one named queue
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueCounters {
+    /// Queue name, see [`RxQueue::name`] and [`TxQueue::name`]
+    pub name: String,
+    pub packets: u64,
+    pub bytes: u64,
+    pub drops: u64,
+    pub errors: u64,
+}
+
+/// Counters of one named socket
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketCounters {
+    /// Socket name, see [`crate::udp::UdpSocket::name`]
+    pub name: String,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_dropped: u64,
+    pub errors: u64,
+}
+
+/// Counters of one named worker
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerCounters {
+    /// Worker name, see [`crate::queue::QueueWorker::name`]
+    pub name: String,
+    pub processed: u64,
+    pub errors: u64,
+}
+
+impl InterfaceStats {
+    /// Counters of `self` and `other` summed, named after `self`
+    pub fn combined(&self, other: &InterfaceStats) -> InterfaceStats {
+        InterfaceStats {
+            interface: self.interface.clone(),
+            port_id: self.port_id,
+            rx_packets: self.rx_packets.saturating_add(other.rx_packets),
+            rx_bytes: self.rx_bytes.saturating_add(other.rx_bytes),
+            rx_drops: self.rx_drops.saturating_add(other.rx_drops),
+            rx_errors: self.rx_errors.saturating_add(other.rx_errors),
+            tx_packets: self.tx_packets.saturating_add(other.tx_packets),
+            tx_bytes: self.tx_bytes.saturating_add(other.tx_bytes),
+            tx_drops: self.tx_drops.saturating_add(other.tx_drops),
+            tx_errors: self.tx_errors.saturating_add(other.tx_errors),
+            queues: self.queues.clone(),
+        }
+        .with_queue_counters(&other.queues)
+    }
+
+    /// Counters of `self` with those of `queues` added to the queues of the same name
+    fn with_queue_counters(mut self, queues: &[QueueCounters]) -> Self {
+        for queue in queues {
+            match self.queues.iter_mut().find(|q| q.name == queue.name) {
+                Some(q) => {
+                    q.packets = q.packets.saturating_add(queue.packets);
+                    q.bytes = q.bytes.saturating_add(queue.bytes);
+                    q.drops = q.drops.saturating_add(queue.drops);
+                    q.errors = q.errors.saturating_add(queue.errors);
+                }
+                None => self.queues.push(queue.clone()),
+            }
+        }
+        self
+    }
+
+    /// Counters of `self` plus those of the RX and TX queues of a driver
+    pub fn with_queues(&self, rx_queues: &[Arc<RxQueue>], tx_queues: &[Arc<TxQueue>]) -> Self {
+        let mut stats = self.clone();
+        let mut queues = Vec::new();
+        for rx_queue in rx_queues {
+            let queue = rx_queue.stats();
+            stats.rx_packets = stats
+                .rx_packets
+                .saturating_add(queue.packets_received.get());
+            stats.rx_bytes = stats.rx_bytes.saturating_add(queue.bytes_received.get());
+            stats.rx_drops = stats.rx_drops.saturating_add(queue.drops.get());
+            stats.rx_errors = stats.rx_errors.saturating_add(queue.errors.get());
+            queues.push(QueueCounters {
+                name: rx_queue.name().to_string(),
+                packets: queue.packets_received.get(),
+                bytes: queue.bytes_received.get(),
+                drops: queue.drops.get(),
+                errors: queue.errors.get(),
+            });
+        }
+        for tx_queue in tx_queues {
+            let queue = tx_queue.stats();
+            stats.tx_packets = stats.tx_packets.saturating_add(queue.packets_sent.get());
+            stats.tx_bytes = stats.tx_bytes.saturating_add(queue.bytes_sent.get());
+            stats.tx_drops = stats.tx_drops.saturating_add(queue.drops.get());
+            stats.tx_errors = stats.tx_errors.saturating_add(queue.errors.get());
+            queues.push(QueueCounters {
+                name: tx_queue.name().to_string(),
+                packets: queue.packets_sent.get(),
+                bytes: queue.bytes_sent.get(),
+                drops: queue.drops.get(),
+                errors: queue.errors.get(),
+            });
+        }
+        stats.with_queue_counters(&queues)
+    }
+
+    fn counters(&self) -> [u64; 8] {
+        [
+            self.rx_packets,
+            self.rx_bytes,
+            self.rx_drops,
+            self.rx_errors,
+            self.tx_packets,
+            self.tx_bytes,
+            self.tx_drops,
+            self.tx_errors,
+        ]
+    }
+}
+
+/// Counters of every interface at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    /// Schema version the snapshot was written with
+    pub version: u32,
+    /// Milliseconds since the Unix epoch
+    pub taken_at_ms: u64,
+    pub interfaces: Vec<InterfaceStats>,
+    /// Application metrics, since version 2
+    #[serde(default)]
+    pub external: Vec<ExternalMetric>,
+    /// Socket counters, since version 4
+    #[serde(default)]
+    pub sockets: Vec<SocketCounters>,
+    /// Worker counters, since version 4
+    #[serde(default)]
+    pub workers: Vec<WorkerCounters>,
+}
+
+impl From<Vec<InterfaceStats>> for StatsSnapshot {
+    fn from(interfaces: Vec<InterfaceStats>) -> Self {
+        Self::new(interfaces)
+    }
+}
+
+impl StatsSnapshot {
+    /// Snapshot of `interfaces` taken now
+    pub fn new(interfaces: Vec<InterfaceStats>) -> Self {
+        let taken_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Self {
+            version: SNAPSHOT_VERSION,
+            taken_at_ms,
+            interfaces,
+            external: Vec::new(),
+            sockets: Vec::new(),
+            workers: Vec::new(),
+        }
+    }
+
+    /// Record the counters of `sockets` and `workers` with the interface counters
+    pub fn with_entities(
+        mut self,
+        sockets: Vec<SocketCounters>,
+        workers: Vec<WorkerCounters>,
+    ) -> Self {
+        self.sockets = sockets;
+        self.workers = workers;
+        self
+    }
+
+    /// Record application metrics `external` with the interface counters
+    pub fn with_external(mut self, external: Vec<ExternalMetric>) -> Self {
+        self.external = external;
+        self
+    }
+
+    /// Counters of the interface named `interface`
+    pub fn interface(&self, interface: &str) -> Option<&InterfaceStats> {
+        self.interfaces
+            .iter()
+            .find(|stats| stats.interface == interface)
+    }
+
+    /// Encode the snapshot
+    pub fn encode(&self, format: SnapshotFormat) -> Result<Vec<u8>> {
+        match format {
+            SnapshotFormat::Json => serde_json::to_vec_pretty(self)
+                .map_err(|e| Error::InvalidConfig(format!("Stats snapshot encoding: {}", e))),
+            SnapshotFormat::Binary => {
+                let mut out = Vec::new();
+                out.extend_from_slice(SNAPSHOT_MAGIC);
+                out.extend_from_slice(&self.version.to_le_bytes());
+                out.extend_from_slice(&self.taken_at_ms.to_le_bytes());
+                out.extend_from_slice(&(self.interfaces.len() as u32).to_le_bytes());
+                for stats in &self.interfaces {
+                    put_string(&mut out, &stats.interface)?;
+                    out.extend_from_slice(&stats.port_id.to_le_bytes());
+                    for counter in stats.counters() {
+                        out.extend_from_slice(&counter.to_le_bytes());
+                    }
+                }
+                out.extend_from_slice(&(self.external.len() as u32).to_le_bytes());
+                for metric in &self.external {
+                    put_string(&mut out, &metric.name)?;
+                    put_string(&mut out, &metric.help)?;
+                    let (kind, value) = match metric.value {
+                        MetricValue::Counter(value) => (0u8, value.to_le_bytes()),
+                        MetricValue::Gauge(value) => (1u8, value.to_le_bytes()),
+                    };
+                    out.push(kind);
+                    out.extend_from_slice(&value);
+                }
+                for stats in &self.interfaces {
+                    out.extend_from_slice(&(stats.queues.len() as u32).to_le_bytes());
+                    for queue in &stats.queues {
+                        put_string(&mut out, &queue.name)?;
+                        for counter in [queue.packets, queue.bytes, queue.drops, queue.errors] {
+                            out.extend_from_slice(&counter.to_le_bytes());
+                        }
+                    }
+                }
+                out.extend_from_slice(&(self.sockets.len() as u32).to_le_bytes());
+                for socket in &self.sockets {
+                    put_string(&mut out, &socket.name)?;
+                    for counter in [
+                        socket.packets_received,
+                        socket.bytes_received,
+                        socket.packets_sent,
+                        socket.bytes_sent,
+                        socket.packets_dropped,
+                        socket.errors,
+                    ] {
+                        out.extend_from_slice(&counter.to_le_bytes());
+                    }
+                }
+                out.extend_from_slice(&(self.workers.len() as u32).to_le_bytes());
+                for worker in &self.workers {
+                    put_string(&mut out, &worker.name)?;
+                    out.extend_from_slice(&worker.processed.to_le_bytes());
+                    out.extend_from_slice(&worker.errors.to_le_bytes());
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    /// Decode a snapshot in either format
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let snapshot = if bytes.starts_with(SNAPSHOT_MAGIC) {
+            decode_binary(&bytes[SNAPSHOT_MAGIC.len()..])?
+        } else {
+            serde_json::from_slice(bytes)
+                .map_err(|e| Error::InvalidConfig(format!("Bad stats snapshot: {}", e)))?
+        };
+        if snapshot.version == 0 || snapshot.version > SNAPSHOT_VERSION {
+            return Err(Error::InvalidConfig(format!(
+                "Stats snapshot version {} is not supported (at most {})",
+                snapshot.version, SNAPSHOT_VERSION
+            )));
+        }
+        Ok(snapshot)
+    }
+
+    /// Replace the file at `path` with the snapshot
+    pub fn save(&self, path: &Path, format: SnapshotFormat) -> Result<()> {
+        let bytes = self.encode(format)?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Read the snapshot at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::decode(&fs::read(path)?)
+    }
+}
+
+/// Append `text` with its length, failing with [`Error::InvalidConfig`] past `u16::MAX` bytes
+fn put_string(out: &mut Vec<u8>, text: &str) -> Result<()> {
+    let len = u16::try_from(text.len()).map_err(|_| {
+        Error::InvalidConfig(format!(
+            "Name of {} bytes too long for a stats snapshot",
+            text.len()
+        ))
+    })?;
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(text.as_bytes());
+    Ok(())
+}
+
+/// Reads little-endian fields off the front of a binary snapshot
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(Error::InvalidConfig("Truncated stats snapshot".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| Error::InvalidConfig("Bad string in stats snapshot".to_string()))
+    }
+}
+
+fn decode_binary(bytes: &[u8]) -> Result<StatsSnapshot> {
+    let mut reader = Reader(bytes);
+    let version = reader.u32()?;
+    if version > SNAPSHOT_VERSION {
+        // Newer layouts may differ beyond the version field
+        return Ok(StatsSnapshot {
+            version,
+            ..StatsSnapshot::default()
+        });
+    }
+    let taken_at_ms = reader.u64()?;
+    let count = reader.u32()?;
+    let mut interfaces = Vec::new();
+    for _ in 0..count {
+        interfaces.push(InterfaceStats {
+            interface: reader.string()?,
+            port_id: reader.u16()?,
+            rx_packets: reader.u64()?,
+            rx_bytes: reader.u64()?,
+            rx_drops: reader.u64()?,
+            rx_errors: reader.u64()?,
+            tx_packets: reader.u64()?,
+            tx_bytes: reader.u64()?,
+            tx_drops: reader.u64()?,
+            tx_errors: reader.u64()?,
+            queues: Vec::new(),
+        });
+    }
+    let mut external = Vec::new();
+    if version >= 2 {
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let help = reader.string()?;
+            let value = match reader.u8()? {
+                0 => MetricValue::Counter(reader.u64()?),
+                1 => MetricValue::Gauge(reader.u64()? as i64),
+                kind => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Bad metric kind {} in stats snapshot",
+                        kind
+                    )))
+                }
+            };
+            external.push(ExternalMetric { name, help, value });
+        }
+    }
+    if version >= 3 {
+        for stats in &mut interfaces {
+            for _ in 0..reader.u32()? {
+                stats.queues.push(QueueCounters {
+                    name: reader.string()?,
+                    packets: reader.u64()?,
+                    bytes: reader.u64()?,
+                    drops: reader.u64()?,
+                    errors: reader.u64()?,
+                });
+            }
+        }
+    }
+    let (mut sockets, mut workers) = (Vec::new(), Vec::new());
+    if version >= 4 {
+        for _ in 0..reader.u32()? {
+            sockets.push(SocketCounters {
+                name: reader.string()?,
+                packets_received: reader.u64()?,
+                bytes_received: reader.u64()?,
+                packets_sent: reader.u64()?,
+                bytes_sent: reader.u64()?,
+                packets_dropped: reader.u64()?,
+                errors: reader.u64()?,
+            });
+        }
+        for _ in 0..reader.u32()? {
+            workers.push(WorkerCounters {
+                name: reader.string()?,
+                processed: reader.u64()?,
+                errors: reader.u64()?,
+            });
+        }
+    }
+    Ok(StatsSnapshot {
+        version,
+        taken_at_ms,
+        interfaces,
+        external,
+        sockets,
+        workers,
+    })
+}
+
+/// Writes snapshots of the counters a source reports
+pub struct StatsPersister<F> {
+    persistence: StatsPersistence,
+    source: F,
+}
+
+impl<S: Into<StatsSnapshot>, F: FnMut() -> S + Send + 'static> StatsPersister<F> {
+    /// Persist what `source` returns, interface counters or a whole
+    /// snapshot, as configured by `persistence`
+    pub fn new(persistence: StatsPersistence, source: F) -> Self {
+        Self {
+            persistence,
+            source,
+        }
+    }
+
+    /// Write one snapshot now
+    pub fn persist(&mut self) -> Result<()> {
+        let snapshot: StatsSnapshot = (self.source)().into();
+        snapshot
+            .with_external(MetricsRegistry::global().snapshot())
+            .save(&self.persistence.path, self.persistence.format)
+    }
+
+    /// Persist every interval on a background thread until `shutdown`, then once more
+    pub fn spawn(mut self, shutdown: ShutdownToken) -> std::io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name("xpdk-persist".to_string())
+            .spawn(move || loop {
+                let stopping = shutdown.wait_timeout(self.persistence.interval);
+                if let Err(e) = self.persist() {
+                    log::warn!(
+                        "Persisting stats to {} failed: {}",
+                        self.persistence.path.display(),
+                        e
+                    );
+                }
+                if stopping {
+                    break;
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_versioning() {
+        let stats = InterfaceStats {
+            interface: "eth0".to_string(),
+            port_id: 1,
+            rx_packets: 10,
+            rx_bytes: 1500,
+            tx_errors: 2,
+            queues: vec![QueueCounters {
+                name: "rx0".to_string(),
+                packets: 10,
+                bytes: 1500,
+                ..QueueCounters::default()
+            }],
+            ..InterfaceStats::default()
+        };
+        let snapshot = StatsSnapshot::new(vec![stats.clone()])
+            .with_external(vec![
+                ExternalMetric {
+                    name: "dtls_records_total".to_string(),
+                    help: "DTLS records sealed".to_string(),
+                    value: MetricValue::Counter(4),
+                },
+                ExternalMetric {
+                    name: "dtls_sessions".to_string(),
+                    help: String::new(),
+                    value: MetricValue::Gauge(-1),
+                },
+            ])
+            .with_entities(
+                vec![SocketCounters {
+                    name: "dns".to_string(),
+                    packets_received: 3,
+                    bytes_sent: 120,
+                    ..SocketCounters::default()
+                }],
+                vec![WorkerCounters {
+                    name: "decoder".to_string(),
+                    processed: 5,
+                    errors: 1,
+                }],
+            );
+        for format in [SnapshotFormat::Binary, SnapshotFormat::Json] {
+            let bytes = snapshot.encode(format).unwrap();
+            assert_eq!(StatsSnapshot::decode(&bytes).unwrap(), snapshot);
+        }
+        let binary = snapshot.encode(SnapshotFormat::Binary).unwrap();
+        assert!(StatsSnapshot::decode(&binary[..binary.len() - 1]).is_err());
+        // Names too long for their length field are refused, not cut
+        let mut long = snapshot.clone();
+        long.workers[0].name = "w".repeat(u16::MAX as usize + 1);
+        assert!(long.encode(SnapshotFormat::Binary).is_err());
+
+        // Snapshots from a later release are refused
+        let newer = StatsSnapshot {
+            version: SNAPSHOT_VERSION + 1,
+            ..snapshot.clone()
+        };
+        for format in [SnapshotFormat::Binary, SnapshotFormat::Json] {
+            assert!(StatsSnapshot::decode(&newer.encode(format).unwrap()).is_err());
+        }
+
+        let path = std::env::temp_dir().join(format!("xpdk-stats-{}.bin", std::process::id()));
+        let mut persister =
+            StatsPersister::new(StatsPersistence::new(&path), move || vec![stats.clone()]);
+        persister.persist().unwrap();
+        let restored = StatsSnapshot::load(&path).unwrap();
+        let baseline = restored.interface("eth0").unwrap();
+        let doubled = baseline.combined(baseline);
+        assert_eq!(doubled.rx_bytes, 3000);
+        assert_eq!(doubled.queues.len(), 1);
+        assert_eq!(doubled.queues[0].packets, 20);
+        // Counters restored near the top of their range stick there
+        let mut high = baseline.clone();
+        high.rx_bytes = u64::MAX - 1;
+        high.queues[0].bytes = u64::MAX;
+        let summed = high.combined(baseline);
+        assert_eq!(
+            (summed.rx_bytes, summed.queues[0].bytes),
+            (u64::MAX, u64::MAX)
+        );
+        assert!(restored.interface("eth1").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
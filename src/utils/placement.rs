@@ -0,0 +1,228 @@
+//! NUMA placement audit of RX queues
+//!
+//! NUMA-aware pools only pay off if the thread polling a queue runs on the
+//! node holding the queue's buffers, and that node is the one the NIC is
+//! attached to: otherwise every frame crosses the interconnect once on DMA
+//! and again when the poller reads it. A [`PlacementReport`] lists, for
+//! every RX queue, the node of its pool memory, of the CPU it is polled on
+//! and of the NIC, and flags queues where known nodes differ. Nodes the
+//! system does not report, such as the CPU of an unpinned queue or the
+//! node of a virtual NIC, are never counted as mismatches.
+//!
+//! With [`crate::Config::placement`] set, [`crate::Xpdk::start`] audits the
+//! queues after the warm-up and either logs mismatches or refuses to start,
+//! see [`PlacementEnforcement`]; [`crate::Xpdk::placement_report`] audits on
+//! demand.
+
+use super::preflight::PreflightCheck;
+use crate::poll::PollModeDriver;
+use crate::{Error, Result};
+use std::fmt;
+use std::fs;
+
+/// What starting does about misplaced queues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementEnforcement {
+    /// Log mismatches and start anyway
+    Warn,
+    /// Fail the start with [`Error::NumaError`]
+    Refuse,
+}
+
+/// NUMA nodes involved in serving one RX queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueNodes {
+    pub queue_id: u16,
+    /// CPU the queue is polled on, if pinned
+    pub cpu: Option<usize>,
+    /// Node of the queue's pool memory
+    pub pool_node: Option<usize>,
+    /// Node of the polling CPU
+    pub cpu_node: Option<usize>,
+    /// Node the NIC is attached to
+    pub nic_node: Option<usize>,
+}
+
+impl QueueNodes {
+    /// Whether two of the known nodes differ
+    pub fn is_mismatched(&self) -> bool {
+        let mut known = [self.pool_node, self.cpu_node, self.nic_node]
+            .into_iter()
+            .flatten();
+        known
+            .next()
+            .is_some_and(|first| known.any(|node| node != first))
+    }
+}
+
+impl fmt::Display for QueueNodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node = |node: Option<usize>| node.map_or("?".to_string(), |node| node.to_string());
+        write!(
+            f,
+            "queue {}: pool node {}, cpu {} node {}, nic node {}",
+            self.queue_id,
+            node(self.pool_node),
+            node(self.cpu),
+            node(self.cpu_node),
+            node(self.nic_node)
+        )
+    }
+}
+
+/// Placement of every RX queue of a driver
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlacementReport {
+    pub interface: String,
+    pub queues: Vec<QueueNodes>,
+}
+
+impl PlacementReport {
+    /// Audit the RX queues of `pmd`, whose NIC is `interface`
+    pub fn audit(pmd: &PollModeDriver, interface: &str) -> Self {
+        let nic_node = nic_node(interface);
+        Self {
+            interface: interface.to_string(),
+            queues: pmd
+                .rx_queues()
+                .map(|queue| QueueNodes {
+                    queue_id: queue.id(),
+                    cpu: queue.cpu(),
+                    pool_node: queue.get_pool().memory_node(),
+                    cpu_node: queue.cpu().and_then(cpu_node),
+                    nic_node,
+                })
+                .collect(),
+        }
+    }
+
+    /// Queues whose known nodes differ
+    pub fn mismatches(&self) -> impl Iterator<Item = &QueueNodes> {
+        self.queues.iter().filter(|queue| queue.is_mismatched())
+    }
+
+    /// Entry for the startup report, a warning on any mismatch
+    pub fn check(&self) -> PreflightCheck {
+        let mismatched: Vec<String> = self.mismatches().map(ToString::to_string).collect();
+        if mismatched.is_empty() {
+            PreflightCheck::pass(
+                "numa placement",
+                format!("{} queues of {} placed", self.queues.len(), self.interface),
+            )
+        } else {
+            PreflightCheck::warn(
+                "numa placement",
+                mismatched.join("; "),
+                "pin each queue to a CPU on the NIC's node and allocate its pool there",
+            )
+        }
+    }
+
+    /// Log or refuse mismatches according to `enforcement`
+    pub fn enforce(&self, enforcement: PlacementEnforcement) -> Result<()> {
+        let mismatched: Vec<String> = self.mismatches().map(ToString::to_string).collect();
+        if mismatched.is_empty() {
+            return Ok(());
+        }
+        match enforcement {
+            PlacementEnforcement::Warn => {
+                for queue in &mismatched {
+                    log::warn!("NUMA placement mismatch on {}: {}", self.interface, queue);
+                }
+                Ok(())
+            }
+            PlacementEnforcement::Refuse => Err(Error::NumaError(format!(
+                "Misplaced queues on {}: {}",
+                self.interface,
+                mismatched.join("; ")
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PlacementReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for queue in &self.queues {
+            let flag = if queue.is_mismatched() {
+                " MISMATCH"
+            } else {
+                ""
+            };
+            writeln!(f, "{}{}", queue, flag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Node the NIC behind `interface` is attached to, if it reports one
+pub fn nic_node(interface: &str) -> Option<usize> {
+    // -1 for devices without NUMA affinity
+    fs::read_to_string(format!("/sys/class/net/{}/device/numa_node", interface))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Node of `cpu`, from the node link in its sysfs directory
+pub fn cpu_node(cpu: usize) -> Option<usize> {
+    fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+        .ok()?
+        .flatten()
+        .find_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatches_ignore_unknown_nodes() {
+        let placed = QueueNodes {
+            queue_id: 0,
+            cpu: Some(2),
+            pool_node: Some(0),
+            cpu_node: Some(0),
+            nic_node: None,
+        };
+        let remote = QueueNodes {
+            queue_id: 1,
+            cpu_node: Some(1),
+            ..placed
+        };
+        let unpinned = QueueNodes {
+            queue_id: 2,
+            cpu: None,
+            cpu_node: None,
+            ..placed
+        };
+        assert!(!placed.is_mismatched());
+        assert!(remote.is_mismatched());
+        assert!(!unpinned.is_mismatched());
+
+        let report = PlacementReport {
+            interface: "eth0".to_string(),
+            queues: vec![placed, remote, unpinned],
+        };
+        let ids: Vec<u16> = report.mismatches().map(|queue| queue.queue_id).collect();
+        assert_eq!(ids, [1]);
+        assert!(report
+            .to_string()
+            .contains("queue 1: pool node 0, cpu 2 node 1"));
+        assert!(report.enforce(PlacementEnforcement::Warn).is_ok());
+        assert!(report.enforce(PlacementEnforcement::Refuse).is_err());
+        assert_eq!(
+            report.check().status,
+            crate::utils::preflight::CheckStatus::Warn
+        );
+        // Loopback has no device and thus no node
+        assert_eq!(nic_node("lo"), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Environment checks run before initialization
+//!
+//! Verifies privileges, huge pages, interface state and memlock limits up
+//! front so misconfiguration is reported with an actionable hint instead of
+//! surfacing as a libpcap error on the first receive.
+
+use crate::poll::DEFAULT_PACKET_SIZE;
+use crate::{Config, Error, Result};
+use std::fmt;
+use std::fs;
+
+/// Linux capability bit for raw sockets
+const CAP_NET_RAW: u32 = 13;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Result of a single preflight check
+#[derive(Debug, Clone)]
+pub struct PreflightCheck {
+    /// Short check name
+    pub name: &'static str,
+    /// Check outcome
+    pub status: CheckStatus,
+    /// What was found
+    pub detail: String,
+    /// How to fix a warning or failure
+    pub hint: Option<String>,
+}
+
+impl PreflightCheck {
+    pub(crate) fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    pub(crate) fn warn(name: &'static str, detail: String, hint: &str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Warn,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: String, hint: &str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Collected preflight results
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    /// Individual check results
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Run all checks for a configuration
+    pub fn run(config: &Config) -> Self {
+        Self {
+            checks: vec![
+                check_privileges(),
+                check_hugepages(config),
+                check_interface(&config.interface),
+                check_memlock(config),
+            ],
+        }
+    }
+
+    /// Check whether no check failed
+    pub fn is_ok(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Iterate over failed checks
+    pub fn failures(&self) -> impl Iterator<Item = &PreflightCheck> {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+    }
+
+    /// Convert failed checks into an error
+    pub fn check(&self) -> Result<()> {
+        let failures: Vec<String> = self
+            .failures()
+            .map(|check| match &check.hint {
+                Some(hint) => format!("{}: {} ({})", check.name, check.detail, hint),
+                None => format!("{}: {}", check.name, check.detail),
+            })
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PreflightError(failures.join("; ")))
+        }
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Pass => "ok",
+                CheckStatus::Warn => "warn",
+                CheckStatus::Fail => "FAIL",
+            };
+            writeln!(f, "[{:>4}] {}: {}", status, check.name, check.detail)?;
+            if let Some(hint) = &check.hint {
+                writeln!(f, "       hint: {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check for root or CAP_NET_RAW
+fn check_privileges() -> PreflightCheck {
+    if nix::unistd::geteuid().is_root() {
+        return PreflightCheck::pass("privileges", "running as root".to_string());
+    }
+
+    let has_net_raw = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_cap_eff(&status))
+        .map(|caps| caps & (1 << CAP_NET_RAW) != 0)
+        .unwrap_or(false);
+
+    if has_net_raw {
+        PreflightCheck::pass("privileges", "CAP_NET_RAW is effective".to_string())
+    } else {
+        PreflightCheck::fail(
+            "privileges",
+            "not root and CAP_NET_RAW is missing".to_string(),
+            "run as root or grant it with `setcap cap_net_raw,cap_net_admin+ep <binary>`",
+        )
+    }
+}
+
+/// Parse the effective capability mask from /proc/self/status
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+}
+
+/// Check that huge pages are reserved when enabled
+fn check_hugepages(config: &Config) -> PreflightCheck {
+    if !config.enable_hugepages {
+        return PreflightCheck::pass("hugepages", "disabled in configuration".to_string());
+    }
+
+    match read_u64("/proc/sys/vm/nr_hugepages") {
+        Some(0) => PreflightCheck::warn(
+            "hugepages",
+            "no huge pages reserved, falling back to regular pages".to_string(),
+            "reserve pages with `echo 1024 > /proc/sys/vm/nr_hugepages`",
+        ),
+        Some(count) => PreflightCheck::pass("hugepages", format!("{} huge pages reserved", count)),
+        None => PreflightCheck::warn(
+            "hugepages",
+            "cannot read /proc/sys/vm/nr_hugepages".to_string(),
+            "huge pages may be unsupported on this system",
+        ),
+    }
+}
+
+/// Check that the interface exists and is up
+fn check_interface(interface: &str) -> PreflightCheck {
+    let operstate = format!("/sys/class/net/{}/operstate", interface);
+
+    match fs::read_to_string(operstate) {
+        Ok(state) if state.trim() == "up" || state.trim() == "unknown" => {
+            PreflightCheck::pass("interface", format!("'{}' is {}", interface, state.trim()))
+        }
+        Ok(state) => PreflightCheck::warn(
+            "interface",
+            format!("'{}' is {}", interface, state.trim()),
+            &format!("bring it up with `ip link set {} up`", interface),
+        ),
+        Err(_) => PreflightCheck::fail(
+            "interface",
+            format!("'{}' not found", interface),
+            "set Config::interface to a device listed by `ip link`",
+        ),
+    }
+}
+
+/// Check that the memlock limit covers the configured pools
+fn check_memlock(config: &Config) -> PreflightCheck {
+    // Memory manager pools plus the PMD pool
+    let required = (config.pool_count + 1) * config.pool_size * DEFAULT_PACKET_SIZE;
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return PreflightCheck::warn(
+            "memlock",
+            "cannot query RLIMIT_MEMLOCK".to_string(),
+            "check `ulimit -l`",
+        );
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        PreflightCheck::pass("memlock", "unlimited".to_string())
+    } else if limit.rlim_cur as usize >= required {
+        PreflightCheck::pass(
+            "memlock",
+            format!(
+                "{} KiB available, {} KiB needed",
+                limit.rlim_cur / 1024,
+                required / 1024
+            ),
+        )
+    } else {
+        PreflightCheck::warn(
+            "memlock",
+            format!(
+                "{} KiB available, {} KiB needed",
+                limit.rlim_cur / 1024,
+                required / 1024
+            ),
+            "raise it with `ulimit -l unlimited` or in /etc/security/limits.conf",
+        )
+    }
+}
+
+/// Read a single integer from a procfs file
+fn read_u64(path: &str) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\nCapEff:\t0000000000002000\n";
+        assert_eq!(parse_cap_eff(status), Some(1 << CAP_NET_RAW));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn test_missing_interface_fails() {
+        let config = Config {
+            interface: "xpdk-nonexistent0".to_string(),
+            ..Config::default()
+        };
+        let report = PreflightReport::run(&config);
+
+        assert_eq!(report.checks.len(), 4);
+        assert!(!report.is_ok());
+        assert!(matches!(report.check(), Err(Error::PreflightError(_))));
+        assert!(report.to_string().contains("xpdk-nonexistent0"));
+    }
+}
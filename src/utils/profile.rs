@@ -0,0 +1,315 @@
+//! Sampled traffic mix profiling
+//!
+//! One received packet in N is profiled: its frame size goes into a size
+//! bucket, its classified type into the protocol mix, and its destination
+//! port and IPv4 source /24 into space-bounded heavy-hitter sketches. The
+//! [`TrafficProfile`] snapshot prints as `name value` lines for export, and
+//! the profiler can be reset at runtime to start a new measurement window.
+
+use crate::memory::{Mbuf, PacketType};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Keys tracked by each heavy-hitter sketch of the global profiler
+pub const DEFAULT_HEAVY_HITTERS: usize = 64;
+
+/// Inclusive upper bounds of the frame size buckets; larger frames go in a last bucket
+pub const SIZE_BUCKET_BOUNDS: [usize; 6] = [64, 128, 256, 512, 1024, 1518];
+
+/// Number of frame size buckets
+pub const SIZE_BUCKETS: usize = SIZE_BUCKET_BOUNDS.len() + 1;
+
+/// Protocol mix entries, in [`PacketType`] declaration order
+const PROTOCOLS: [PacketType; 7] = [
+    PacketType::Unknown,
+    PacketType::Ethernet,
+    PacketType::Ipv4,
+    PacketType::Ipv6,
+    PacketType::Udp,
+    PacketType::Tcp,
+    PacketType::Icmp,
+];
+
+/// Space-Saving heavy-hitters sketch
+///
+/// Tracks at most `capacity` keys. A new key arriving when full replaces the
+/// smallest one and inherits its count, so counts are over-estimates by at
+/// most the recorded error.
+#[derive(Debug, Clone)]
+pub struct HeavyHitters<K> {
+    capacity: usize,
+    /// Count and overestimation error per key
+    counts: HashMap<K, (u64, u64)>,
+}
+
+impl<K: Copy + Eq + Hash> HeavyHitters<K> {
+    /// Create a sketch tracking up to `capacity` keys
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            counts: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Count one occurrence of `key`
+    pub fn offer(&mut self, key: K) {
+        if let Some((count, _)) = self.counts.get_mut(&key) {
+            *count += 1;
+            return;
+        }
+        if self.counts.len() < self.capacity {
+            self.counts.insert(key, (1, 0));
+            return;
+        }
+
+        let (&evicted, &(min, _)) = self
+            .counts
+            .iter()
+            .min_by_key(|(_, (count, _))| *count)
+            .expect("sketch is full");
+        self.counts.remove(&evicted);
+        self.counts.insert(key, (min + 1, min));
+    }
+
+    /// The `n` most frequent keys with their estimated counts, largest first
+    pub fn top(&self, n: usize) -> Vec<(K, u64)> {
+        let mut top: Vec<(K, u64)> = self
+            .counts
+            .iter()
+            .map(|(&key, &(count, _))| (key, count))
+            .collect();
+        top.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        top.truncate(n);
+        top
+    }
+
+    /// Overestimation bound of a tracked key
+    pub fn error(&self, key: &K) -> Option<u64> {
+        self.counts.get(key).map(|&(_, error)| error)
+    }
+
+    /// Forget every key
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+}
+
+/// Point-in-time copy of the profiler
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrafficProfile {
+    /// Packets seen, sampled or not
+    pub seen: u64,
+    /// Packets profiled
+    pub sampled: u64,
+    /// Sampled frames by [`SIZE_BUCKET_BOUNDS`] bucket
+    pub size_buckets: [u64; SIZE_BUCKETS],
+    /// Sampled frames by classified type
+    pub protocols: Vec<(PacketType, u64)>,
+    /// Most frequent UDP/TCP destination ports
+    pub top_ports: Vec<(u16, u64)>,
+    /// Most frequent IPv4 source /24 prefixes
+    pub top_prefixes: Vec<(Ipv4Addr, u64)>,
+}
+
+impl fmt::Display for TrafficProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "profile_seen {}", self.seen)?;
+        writeln!(f, "profile_sampled {}", self.sampled)?;
+        for (bucket, count) in self.size_buckets.iter().enumerate() {
+            match SIZE_BUCKET_BOUNDS.get(bucket) {
+                Some(bound) => writeln!(f, "profile_size_le_{} {}", bound, count)?,
+                None => writeln!(f, "profile_size_jumbo {}", count)?,
+            }
+        }
+        for (packet_type, count) in &self.protocols {
+            writeln!(
+                f,
+                "profile_protocol_{} {}",
+                format!("{:?}", packet_type).to_lowercase(),
+                count
+            )?;
+        }
+        for (port, count) in &self.top_ports {
+            writeln!(f, "profile_port_{} {}", port, count)?;
+        }
+        for (prefix, count) in &self.top_prefixes {
+            writeln!(f, "profile_prefix_{}/24 {}", prefix, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sampled traffic mix profiler
+pub struct TrafficProfiler {
+    /// Profile one packet in this many, 0 disables profiling
+    sample_rate: AtomicU32,
+    seen: AtomicU64,
+    sampled: AtomicU64,
+    size_buckets: [AtomicU64; SIZE_BUCKETS],
+    protocols: [AtomicU64; PROTOCOLS.len()],
+    ports: Mutex<HeavyHitters<u16>>,
+    prefixes: Mutex<HeavyHitters<Ipv4Addr>>,
+}
+
+impl TrafficProfiler {
+    /// Create a disabled profiler tracking `heavy_hitters` ports and prefixes
+    pub fn new(heavy_hitters: usize) -> Self {
+        Self {
+            sample_rate: AtomicU32::new(0),
+            seen: AtomicU64::new(0),
+            sampled: AtomicU64::new(0),
+            size_buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            protocols: std::array::from_fn(|_| AtomicU64::new(0)),
+            ports: Mutex::new(HeavyHitters::new(heavy_hitters)),
+            prefixes: Mutex::new(HeavyHitters::new(heavy_hitters)),
+        }
+    }
+
+    /// Profiler fed by the RX path
+    pub fn global() -> &'static TrafficProfiler {
+        static PROFILER: OnceLock<TrafficProfiler> = OnceLock::new();
+        PROFILER.get_or_init(|| TrafficProfiler::new(DEFAULT_HEAVY_HITTERS))
+    }
+
+    /// Profile one packet in `rate`; 0 disables profiling
+    pub fn set_sample_rate(&self, rate: u32) {
+        self.sample_rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Current sample rate
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Profile a classified mbuf if it is sampled
+    pub fn sample(&self, mbuf: &Mbuf) {
+        let rate = self.sample_rate.load(Ordering::Relaxed) as u64;
+        if rate == 0
+            || !self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(rate)
+        {
+            return;
+        }
+        self.sampled.fetch_add(1, Ordering::Relaxed);
+
+        let bucket = SIZE_BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| mbuf.len <= bound)
+            .unwrap_or(SIZE_BUCKET_BOUNDS.len());
+        self.size_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        if let Some(index) = PROTOCOLS.iter().position(|&p| p == mbuf.packet_type) {
+            self.protocols[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        let data = mbuf.data();
+        let l3 = mbuf.l3_offset as usize;
+        let l4 = mbuf.l4_offset as usize;
+        if matches!(mbuf.packet_type, PacketType::Udp | PacketType::Tcp) {
+            if let Some(port) = data.get(l4 + 2..l4 + 4) {
+                self.ports
+                    .lock()
+                    .offer(u16::from_be_bytes([port[0], port[1]]));
+            }
+        }
+        // Only IPv4 classifications record an L3 offset with an IPv4 header
+        if l3 != 0 && data.get(l3).is_some_and(|version| version >> 4 == 4) {
+            if let Some(src) = data.get(l3 + 12..l3 + 15) {
+                self.prefixes
+                    .lock()
+                    .offer(Ipv4Addr::new(src[0], src[1], src[2], 0));
+            }
+        }
+    }
+
+    /// Get a snapshot with the `top` heaviest ports and prefixes
+    pub fn snapshot(&self, top: usize) -> TrafficProfile {
+        TrafficProfile {
+            seen: self.seen.load(Ordering::Relaxed),
+            sampled: self.sampled.load(Ordering::Relaxed),
+            size_buckets: std::array::from_fn(|i| self.size_buckets[i].load(Ordering::Relaxed)),
+            protocols: PROTOCOLS
+                .iter()
+                .zip(&self.protocols)
+                .map(|(&packet_type, count)| (packet_type, count.load(Ordering::Relaxed)))
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+            top_ports: self.ports.lock().top(top),
+            top_prefixes: self.prefixes.lock().top(top),
+        }
+    }
+
+    /// Clear every counter and sketch, keeping the sample rate
+    pub fn reset(&self) {
+        self.seen.store(0, Ordering::Relaxed);
+        self.sampled.store(0, Ordering::Relaxed);
+        for counter in self.size_buckets.iter().chain(&self.protocols) {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.ports.lock().clear();
+        self.prefixes.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MbufPool;
+    use crate::udp::classify;
+    use crate::udp::testing::{load, FrameBuilder};
+
+    #[test]
+    fn test_heavy_hitters_bound_space() {
+        let mut sketch = HeavyHitters::new(2);
+        for key in [1, 1, 1, 1, 2, 2, 3] {
+            sketch.offer(key);
+        }
+        // 3 replaced 2 and inherited its count
+        assert_eq!(sketch.top(2), vec![(1, 4), (3, 3)]);
+        assert_eq!(sketch.error(&3), Some(2));
+        assert_eq!(sketch.error(&2), None);
+    }
+
+    #[test]
+    fn test_sampled_profile_and_reset() {
+        let pool = MbufPool::new("rx".to_string(), 4, 2048).unwrap();
+        let profiler = TrafficProfiler::new(8);
+        profiler.set_sample_rate(2);
+
+        let frames = [
+            FrameBuilder::to_port(53).build(),
+            FrameBuilder::to_port(9999).build(),
+            FrameBuilder::to_port(53).payload(&[0; 200]).build(),
+            FrameBuilder::to_port(8080).build(),
+        ];
+        for frame in &frames {
+            let mbuf = load(&pool, frame);
+            let mbuf_ref = unsafe { &mut *mbuf };
+            classify(mbuf_ref);
+            profiler.sample(mbuf_ref);
+            pool.free(mbuf).unwrap();
+        }
+
+        let profile = profiler.snapshot(4);
+        assert_eq!((profile.seen, profile.sampled), (4, 2));
+        // 42-byte and 242-byte frames
+        assert_eq!(profile.size_buckets[0], 1);
+        assert_eq!(profile.size_buckets[2], 1);
+        assert_eq!(profile.protocols, vec![(PacketType::Udp, 2)]);
+        assert_eq!(profile.top_ports, vec![(53, 2)]);
+        assert_eq!(profile.top_prefixes, vec![(Ipv4Addr::new(10, 0, 0, 0), 2)]);
+        assert!(profile.to_string().contains("profile_port_53 2\n"));
+
+        profiler.reset();
+        let profile = profiler.snapshot(4);
+        assert_eq!(profile.sampled, 0);
+        assert!(profile.top_ports.is_empty());
+        assert_eq!(profiler.sample_rate(), 2);
+    }
+}
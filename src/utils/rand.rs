@@ -0,0 +1,179 @@
+//! Pseudo-random numbers for port and ID selection
+//!
+//! Source port randomization, IPv4 identifications, DNS query IDs, sFlow
+//! sampling and test data all draw from [`Rng`], a xoshiro256** generator: fast, small enough to keep per
+//! component behind its lock, and not cryptographic. A component takes its
+//! generator from [`Rng::for_component`], which seeds it from the kernel's
+//! random source, so ports and identifications are not predictable across
+//! runs or processes.
+//!
+//! Reproducible runs are a test mode behind the `deterministic-rng`
+//! feature: [`set_global_seed`], or the `XPDK_RNG_SEED` environment
+//! variable, fixes the seed every component derives its own from, mixed
+//! with the component's name so components still draw different
+//! sequences. Generators created before the seed is set are not affected.
+//! Source ports can also be reseeded alone with
+//! [`crate::udp::EphemeralPorts::reseed`].
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+#[cfg(any(test, feature = "deterministic-rng"))]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Environment variable read for the global seed in the deterministic test mode
+#[cfg(feature = "deterministic-rng")]
+pub const SEED_ENV: &str = "XPDK_RNG_SEED";
+
+#[cfg(any(test, feature = "deterministic-rng"))]
+static GLOBAL_SEED: AtomicU64 = AtomicU64::new(0);
+#[cfg(any(test, feature = "deterministic-rng"))]
+static GLOBAL_SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Make every component created from now on derive its seed from `seed`, or from entropy if `None`
+#[cfg(any(test, feature = "deterministic-rng"))]
+pub fn set_global_seed(seed: Option<u64>) {
+    GLOBAL_SEED.store(seed.unwrap_or(0), Ordering::Relaxed);
+    GLOBAL_SEEDED.store(seed.is_some(), Ordering::Release);
+}
+
+/// Seed components derive theirs from, `None` outside the deterministic test mode
+pub fn global_seed() -> Option<u64> {
+    #[cfg(any(test, feature = "deterministic-rng"))]
+    if GLOBAL_SEEDED.load(Ordering::Acquire) {
+        return Some(GLOBAL_SEED.load(Ordering::Relaxed));
+    }
+    #[cfg(feature = "deterministic-rng")]
+    if let Some(seed) = std::env::var(SEED_ENV).ok().and_then(|s| s.parse().ok()) {
+        return Some(seed);
+    }
+    None
+}
+
+/// splitmix64, used to expand seeds
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// 64 bits from the kernel's random source
+fn entropy() -> u64 {
+    let mut seed = [0u8; 8];
+    // SAFETY: the buffer is valid for its length
+    let read = unsafe { libc::getrandom(seed.as_mut_ptr().cast(), seed.len(), 0) };
+    if read == seed.len() as isize {
+        u64::from_ne_bytes(seed)
+    } else {
+        // Hash keys are randomly seeded per process
+        RandomState::new().hash_one(0u64)
+    }
+}
+
+/// xoshiro256** generator
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    /// Generator drawing the same sequence for the same `seed`
+    pub fn seeded(seed: u64) -> Self {
+        let mut seed = seed;
+        Self {
+            state: [(); 4].map(|_| splitmix64(&mut seed)),
+        }
+    }
+
+    /// Generator seeded from the kernel's random source
+    pub fn from_entropy() -> Self {
+        Self::seeded(entropy())
+    }
+
+    /// Generator of `component`, seeded from the global seed if set and from entropy otherwise
+    pub fn for_component(component: &str) -> Self {
+        match global_seed() {
+            Some(seed) => {
+                // FNV-1a of the name, so components draw different sequences
+                let name = component
+                    .bytes()
+                    .fold(0xCBF2_9CE4_8422_2325u64, |hash, byte| {
+                        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+                    });
+                Self::seeded(seed ^ name)
+            }
+            None => Self::from_entropy(),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+        result
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Uniform value in `0..bound`, 0 if `bound` is 0
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Multiply-shift, biased by at most bound / 2^64
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// Fill `buf` with random bytes
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeding_and_ranges() {
+        // Reference output of xoshiro256** for state [1, 2, 3, 4]
+        let mut rng = Rng {
+            state: [1, 2, 3, 4],
+        };
+        assert_eq!(rng.next_u64(), 11520);
+        assert_eq!(rng.next_u64(), 0);
+        assert_eq!(rng.next_u64(), 1509978240);
+
+        let draw = |mut rng: Rng| (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>();
+        assert_eq!(draw(Rng::seeded(7)), draw(Rng::seeded(7)));
+        assert_ne!(draw(Rng::seeded(7)), draw(Rng::seeded(8)));
+
+        set_global_seed(Some(42));
+        assert_eq!(global_seed(), Some(42));
+        assert_eq!(
+            draw(Rng::for_component("ports")),
+            draw(Rng::for_component("ports"))
+        );
+        assert_ne!(
+            draw(Rng::for_component("ports")),
+            draw(Rng::for_component("ip-id"))
+        );
+        set_global_seed(None);
+        assert_eq!(global_seed(), None);
+
+        let mut rng = Rng::seeded(1);
+        assert!((0..1000).all(|_| rng.below(10) < 10));
+        assert_eq!(rng.below(0), 0);
+        let mut buf = [0u8; 13];
+        rng.fill(&mut buf);
+        assert!(buf.iter().any(|&b| b != 0));
+    }
+}
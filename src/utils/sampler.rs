@@ -0,0 +1,256 @@
+//! Periodic stats summaries
+//!
+//! A [`StatsSampler`] reads cumulative counters every interval, turns the
+//! difference from the previous read into rates, and logs one compact
+//! summary line through the `log` facade. Intervals where nothing moved are
+//! not logged. The line is raised from INFO to WARN when packets were
+//! dropped or the mbuf pool ran close to exhaustion, so that those show up
+//! in logs filtered at WARN. Alarm rules attached with
+//! [`StatsSampler::with_alarms`] are evaluated over every summary, see
+//! [`super::alarm`].
+
+use crate::utils::alarm::AlarmSet;
+use crate::utils::shutdown::ShutdownToken;
+use log::Level;
+use std::fmt;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Default time between samples
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Cumulative counters read at each sample
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSample {
+    pub packets: u64,
+    pub bytes: u64,
+    pub drops: u64,
+    pub errors: u64,
+    /// Mbufs in use at the time of the sample
+    pub pool_in_use: usize,
+    /// Mbufs in the pool
+    pub pool_size: usize,
+}
+
+/// When a summary is logged at WARN
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerThresholds {
+    /// Drops in one interval above which the summary warns
+    pub drops: u64,
+    /// Pool usage fraction at or above which the summary warns
+    pub pool_usage: f64,
+}
+
+impl Default for SamplerThresholds {
+    fn default() -> Self {
+        Self {
+            drops: 0,
+            pool_usage: 0.9,
+        }
+    }
+}
+
+/// Sampler settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerConfig {
+    /// Time between samples
+    pub interval: Duration,
+    /// When summaries escalate to WARN
+    pub thresholds: SamplerThresholds,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_SAMPLE_INTERVAL,
+            thresholds: SamplerThresholds::default(),
+        }
+    }
+}
+
+/// Rates over one sampling interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSummary {
+    pub packets_per_sec: f64,
+    pub mbits_per_sec: f64,
+    /// Packets received during the interval
+    pub packets: u64,
+    /// Drops during the interval
+    pub drops: u64,
+    /// Errors during the interval
+    pub errors: u64,
+    pub pool_in_use: usize,
+    pub pool_size: usize,
+    /// Length of the interval
+    pub interval: Duration,
+    /// Level the summary is logged at
+    pub level: Level,
+}
+
+impl fmt::Display for StatsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pps={:.0} mbps={:.2} drops={} errors={} pool={}/{}",
+            self.packets_per_sec,
+            self.mbits_per_sec,
+            self.drops,
+            self.errors,
+            self.pool_in_use,
+            self.pool_size
+        )
+    }
+}
+
+/// Turns periodic counter reads into logged summaries
+pub struct StatsSampler<F> {
+    config: SamplerConfig,
+    source: F,
+    last: Option<(StatsSample, Instant)>,
+    alarms: AlarmSet,
+}
+
+impl<F> StatsSampler<F>
+where
+    F: FnMut() -> StatsSample,
+{
+    /// Create a sampler reading its counters from `source`
+    pub fn new(config: SamplerConfig, source: F) -> Self {
+        Self {
+            config,
+            source,
+            last: None,
+            alarms: AlarmSet::new(),
+        }
+    }
+
+    /// Evaluate `alarms` over every summary
+    pub fn with_alarms(mut self, alarms: AlarmSet) -> Self {
+        self.alarms = alarms;
+        self
+    }
+
+    /// Get the alarms evaluated over the summaries
+    pub fn alarms(&self) -> &AlarmSet {
+        &self.alarms
+    }
+
+    /// Read the counters now, returning the summary since the previous read
+    ///
+    /// The first read only sets the baseline. Returns `None` then and when
+    /// nothing changed; alarms are evaluated on every read after the first.
+    pub fn sample(&mut self) -> Option<StatsSummary> {
+        let sample = (self.source)();
+        let now = Instant::now();
+        let (last, at) = self.last.replace((sample, now))?;
+        let summary = self.rates(&last, &sample, now.duration_since(at));
+        self.alarms.evaluate(&summary, now);
+        (last != sample).then_some(summary)
+    }
+
+    /// Summary of the change from `last` to `sample` over `elapsed`
+    pub fn summarize(
+        &self,
+        last: &StatsSample,
+        sample: &StatsSample,
+        elapsed: Duration,
+    ) -> Option<StatsSummary> {
+        (last != sample).then(|| self.rates(last, sample, elapsed))
+    }
+
+    fn rates(&self, last: &StatsSample, sample: &StatsSample, elapsed: Duration) -> StatsSummary {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let drops = sample.drops.saturating_sub(last.drops);
+        let thresholds = &self.config.thresholds;
+        let exhausted = sample.pool_size > 0
+            && sample.pool_in_use as f64 >= sample.pool_size as f64 * thresholds.pool_usage;
+        let level = if drops > thresholds.drops || exhausted {
+            Level::Warn
+        } else {
+            Level::Info
+        };
+
+        let packets = sample.packets.saturating_sub(last.packets);
+        StatsSummary {
+            packets_per_sec: packets as f64 / secs,
+            mbits_per_sec: sample.bytes.saturating_sub(last.bytes) as f64 * 8.0 / secs / 1e6,
+            packets,
+            drops,
+            errors: sample.errors.saturating_sub(last.errors),
+            pool_in_use: sample.pool_in_use,
+            pool_size: sample.pool_size,
+            interval: elapsed,
+            level,
+        }
+    }
+}
+
+impl<F> StatsSampler<F>
+where
+    F: FnMut() -> StatsSample + Send + 'static,
+{
+    /// Sample and log every interval on a thread until `shutdown` is cancelled
+    pub fn spawn(mut self, shutdown: ShutdownToken) -> std::io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name("xpdk-stats".to_string())
+            .spawn(move || {
+                self.sample();
+                while !shutdown.wait_timeout(self.config.interval) {
+                    if let Some(summary) = self.sample() {
+                        log::log!(target: "xpdk::stats", summary.level, "{}", summary);
+                    }
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summaries_escalate_on_drops_and_exhaustion() {
+        let sampler = StatsSampler::new(SamplerConfig::default(), StatsSample::default);
+        let last = StatsSample {
+            packets: 1000,
+            bytes: 1_000_000,
+            pool_size: 100,
+            ..StatsSample::default()
+        };
+        assert!(sampler
+            .summarize(&last, &last, Duration::from_secs(1))
+            .is_none());
+
+        let busy = StatsSample {
+            packets: 3000,
+            bytes: 3_500_000,
+            pool_in_use: 10,
+            ..last
+        };
+        let summary = sampler
+            .summarize(&last, &busy, Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(summary.packets_per_sec, 1000.0);
+        assert_eq!(summary.mbits_per_sec, 10.0);
+        assert_eq!(summary.level, Level::Info);
+        assert_eq!(
+            summary.to_string(),
+            "pps=1000 mbps=10.00 drops=0 errors=0 pool=10/100"
+        );
+
+        let dropping = StatsSample { drops: 5, ..busy };
+        let summary = sampler
+            .summarize(&busy, &dropping, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!((summary.drops, summary.level), (5, Level::Warn));
+
+        let exhausted = StatsSample {
+            pool_in_use: 95,
+            ..busy
+        };
+        let summary = sampler
+            .summarize(&busy, &exhausted, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(summary.level, Level::Warn);
+    }
+}
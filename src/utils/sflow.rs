@@ -0,0 +1,539 @@
+//! sFlow export
+//!
+//! The RX path offers every received frame to the global [`FlowSampler`],
+//! which takes one in N per queue on average and keeps the first bytes of
+//! each sampled frame. As sFlow asks, the number of frames skipped between
+//! samples is drawn at random from [`Rng`], so traffic with a period of its
+//! own is not sampled in step with it. An [`SflowExporter`] periodically drains those samples,
+//! adds interface counters read from the PMD statistics, and sends them to
+//! a collector as sFlow version 5 datagrams through a socket of the stack.
+//!
+//! Each RX queue samples at its own rate and keeps its own sample pool and
+//! sequence numbers; every flow sample carries the rate it was taken at, so
+//! collectors scale each one on its own.
+
+use crate::memory::Mbuf;
+use crate::poll::PollModeDriver;
+use crate::udp::UdpSocket;
+use crate::utils::rand::Rng;
+use crate::{Error, Result};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// RX queues that can be sampled, by queue ID
+pub const MAX_SAMPLED_QUEUES: usize = 64;
+
+/// Bytes of each sampled frame exported by default
+pub const DEFAULT_HEADER_BYTES: usize = 128;
+
+/// Samples held between exports; further samples are counted as drops
+pub const MAX_PENDING_SAMPLES: usize = 1024;
+
+/// sFlow datagram version
+const SFLOW_VERSION: u32 = 5;
+
+/// Sample and record formats (enterprise 0)
+const FLOW_SAMPLE: u32 = 1;
+const COUNTERS_SAMPLE: u32 = 2;
+const RAW_PACKET_HEADER: u32 = 1;
+const GENERIC_INTERFACE_COUNTERS: u32 = 1;
+
+/// Header protocol of raw packet header records
+const HEADER_PROTOCOL_ETHERNET: u32 = 1;
+
+/// Bytes of a generic interface counters record
+const INTERFACE_COUNTERS_LEN: u32 = 88;
+
+/// Bytes of the datagram header before its samples
+const DATAGRAM_HEADER_LEN: usize = 28;
+
+/// First bytes of a sampled frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowSample {
+    pub queue_id: u16,
+    pub port_id: u16,
+    /// Sequence number of the sample on its queue
+    pub sequence: u32,
+    /// One frame in this many was sampled
+    pub sampling_rate: u32,
+    /// Frames seen on the queue, sampled or not
+    pub sample_pool: u32,
+    /// Samples of the queue lost because the pending buffer was full
+    pub drops: u32,
+    /// Length of the whole frame
+    pub frame_len: usize,
+    /// Leading bytes of the frame
+    pub header: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct QueueSampling {
+    /// Sample one frame in this many, 0 disables sampling
+    rate: AtomicU32,
+    /// Frames until the next sample, the next one if 0
+    skip: AtomicU32,
+    seen: AtomicU64,
+    sequence: AtomicU32,
+    drops: AtomicU32,
+}
+
+/// 1-in-N frame sampler fed by the RX path
+#[derive(Debug)]
+pub struct FlowSampler {
+    queues: [QueueSampling; MAX_SAMPLED_QUEUES],
+    header_bytes: AtomicUsize,
+    pending: Mutex<VecDeque<FlowSample>>,
+    /// Source of the skips between samples
+    rng: Mutex<Rng>,
+}
+
+impl FlowSampler {
+    /// Create a sampler with sampling disabled on every queue
+    pub fn new() -> Self {
+        Self {
+            queues: std::array::from_fn(|_| QueueSampling::default()),
+            header_bytes: AtomicUsize::new(DEFAULT_HEADER_BYTES),
+            pending: Mutex::new(VecDeque::new()),
+            rng: Mutex::new(Rng::for_component("sflow")),
+        }
+    }
+
+    /// Sampler fed by the RX path
+    pub fn global() -> &'static FlowSampler {
+        static SAMPLER: OnceLock<FlowSampler> = OnceLock::new();
+        SAMPLER.get_or_init(FlowSampler::new)
+    }
+
+    /// Sample one frame in `rate` on `queue_id`; 0 disables sampling
+    pub fn set_sampling_rate(&self, queue_id: u16, rate: u32) -> Result<()> {
+        let queue = self.queues.get(queue_id as usize).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "Queue {} is above the {} sampled queues",
+                queue_id, MAX_SAMPLED_QUEUES
+            ))
+        })?;
+        queue.rate.store(rate, Ordering::Relaxed);
+        queue.skip.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sampling rate of `queue_id`, 0 if it is not sampled
+    pub fn sampling_rate(&self, queue_id: u16) -> u32 {
+        self.queues
+            .get(queue_id as usize)
+            .map_or(0, |queue| queue.rate.load(Ordering::Relaxed))
+    }
+
+    /// Keep the first `bytes` of each sampled frame
+    pub fn set_header_bytes(&self, bytes: usize) {
+        self.header_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Sample a received mbuf if its queue's count comes up
+    pub fn sample(&self, mbuf: &Mbuf) {
+        let Some(queue) = self.queues.get(mbuf.queue_id as usize) else {
+            return;
+        };
+        let rate = queue.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return;
+        }
+        let seen = queue.seen.fetch_add(1, Ordering::Relaxed);
+        // A queue is sampled from its polling thread, so this need not be atomic
+        let skip = queue.skip.load(Ordering::Relaxed);
+        if skip > 1 {
+            queue.skip.store(skip - 1, Ordering::Relaxed);
+            return;
+        }
+        queue.skip.store(self.next_skip(rate), Ordering::Relaxed);
+
+        let mut pending = self.pending.lock();
+        if pending.len() >= MAX_PENDING_SAMPLES {
+            queue.drops.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let data = mbuf.data();
+        let header_bytes = self.header_bytes.load(Ordering::Relaxed).min(data.len());
+        pending.push_back(FlowSample {
+            queue_id: mbuf.queue_id,
+            port_id: mbuf.port_id,
+            sequence: queue
+                .sequence
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1),
+            sampling_rate: rate,
+            sample_pool: (seen + 1) as u32,
+            drops: queue.drops.load(Ordering::Relaxed),
+            frame_len: data.len(),
+            header: data[..header_bytes].to_vec(),
+        });
+    }
+
+    /// Frames from one sample to the next, `rate` on average
+    fn next_skip(&self, rate: u32) -> u32 {
+        1 + self.rng.lock().below(2 * rate as u64 - 1) as u32
+    }
+
+    /// Take up to `max` pending samples, oldest first
+    pub fn drain(&self, max: usize) -> Vec<FlowSample> {
+        let mut pending = self.pending.lock();
+        let count = max.min(pending.len());
+        pending.drain(..count).collect()
+    }
+
+    /// Number of samples waiting for export
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+}
+
+impl Default for FlowSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counters of one interface, reported as sFlow generic interface counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceCounters {
+    /// sFlow interface index, the port ID plus one
+    pub if_index: u32,
+    /// Link speed in bits per second, 0 if unknown
+    pub speed: u64,
+    pub in_octets: u64,
+    pub in_packets: u32,
+    pub in_discards: u32,
+    pub in_errors: u32,
+    pub out_octets: u64,
+    pub out_packets: u32,
+    pub out_discards: u32,
+    pub out_errors: u32,
+}
+
+impl InterfaceCounters {
+    /// Sum the RX and TX queue statistics of a driver
+    pub fn from_pmd(pmd: &PollModeDriver) -> Self {
+        let mut counters = Self {
+            if_index: pmd.port_id() as u32 + 1,
+            ..Self::default()
+        };
+        for rx_queue in pmd.rx_queues() {
+            let stats = rx_queue.stats();
+            counters.in_octets += stats.bytes_received.get();
+            counters.in_packets = counters
+                .in_packets
+                .wrapping_add(stats.packets_received.get() as u32);
+            counters.in_discards = counters.in_discards.wrapping_add(stats.drops.get() as u32);
+            counters.in_errors = counters.in_errors.wrapping_add(stats.errors.get() as u32);
+        }
+        for tx_queue in pmd.tx_queues() {
+            let stats = tx_queue.stats();
+            counters.out_octets += stats.bytes_sent.get();
+            counters.out_packets = counters
+                .out_packets
+                .wrapping_add(stats.packets_sent.get() as u32);
+            counters.out_discards = counters.out_discards.wrapping_add(stats.drops.get() as u32);
+            counters.out_errors = counters.out_errors.wrapping_add(stats.errors.get() as u32);
+        }
+        counters
+    }
+}
+
+/// sFlow exporter settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct SflowConfig {
+    /// Address the agent reports itself as
+    pub agent: Ipv4Addr,
+    /// Collector datagrams are sent to
+    pub collector: SocketAddr,
+    /// Sub-agent ID, distinguishing exporters of one agent
+    pub sub_agent_id: u32,
+    /// Sampling rate applied to every queue, 0 to leave sampling off
+    pub sampling_rate: u32,
+    /// Bytes of each sampled frame exported
+    pub header_bytes: usize,
+    /// Largest datagram payload sent
+    pub max_datagram: usize,
+}
+
+impl SflowConfig {
+    /// Export to `collector` as `agent`, sampling one frame in 1000
+    pub fn new(agent: Ipv4Addr, collector: SocketAddr) -> Self {
+        Self {
+            agent,
+            collector,
+            sub_agent_id: 0,
+            sampling_rate: 1000,
+            header_bytes: DEFAULT_HEADER_BYTES,
+            max_datagram: 1400,
+        }
+    }
+}
+
+/// Sends sampled frames and interface counters to an sFlow collector
+pub struct SflowExporter {
+    config: SflowConfig,
+    sampler: &'static FlowSampler,
+    started: Instant,
+    datagram_sequence: AtomicU32,
+    counter_sequence: AtomicU32,
+}
+
+impl SflowExporter {
+    /// Create an exporter and start sampling every queue at `config.sampling_rate`
+    pub fn new(config: SflowConfig) -> Result<Self> {
+        Self::with_sampler(config, FlowSampler::global())
+    }
+
+    fn with_sampler(config: SflowConfig, sampler: &'static FlowSampler) -> Result<Self> {
+        if config.max_datagram < DATAGRAM_HEADER_LEN + 64 + config.header_bytes.next_multiple_of(4)
+        {
+            return Err(Error::InvalidConfig(format!(
+                "sFlow datagrams of {} bytes cannot hold {}-byte headers",
+                config.max_datagram, config.header_bytes
+            )));
+        }
+        sampler.set_header_bytes(config.header_bytes);
+        for queue_id in 0..MAX_SAMPLED_QUEUES as u16 {
+            sampler.set_sampling_rate(queue_id, config.sampling_rate)?;
+        }
+        Ok(Self {
+            config,
+            sampler,
+            started: Instant::now(),
+            datagram_sequence: AtomicU32::new(0),
+            counter_sequence: AtomicU32::new(0),
+        })
+    }
+
+    /// Get the configuration
+    pub fn config(&self) -> &SflowConfig {
+        &self.config
+    }
+
+    /// Sample one frame in `rate` on `queue_id`; 0 stops sampling it
+    pub fn set_sampling_rate(&self, queue_id: u16, rate: u32) -> Result<()> {
+        self.sampler.set_sampling_rate(queue_id, rate)
+    }
+
+    /// Stop sampling every queue
+    ///
+    /// Rates live in the sampler shared by every exporter, so dropping an
+    /// exporter leaves them as they are.
+    pub fn stop(&self) {
+        for queue_id in 0..MAX_SAMPLED_QUEUES as u16 {
+            let _ = self.sampler.set_sampling_rate(queue_id, 0);
+        }
+    }
+
+    /// Encode pending samples and `counters` into datagram payloads
+    pub fn datagrams(&self, counters: &[InterfaceCounters]) -> Vec<Vec<u8>> {
+        let mut samples: Vec<Vec<u8>> = counters
+            .iter()
+            .map(|counters| self.encode_counters(counters))
+            .collect();
+        samples.extend(
+            self.sampler
+                .drain(MAX_PENDING_SAMPLES)
+                .iter()
+                .map(encode_flow),
+        );
+
+        let mut datagrams = Vec::new();
+        let mut samples = samples.into_iter().peekable();
+        while samples.peek().is_some() {
+            let mut datagram = self.datagram_header();
+            let mut count = 0u32;
+            while let Some(sample) = samples.next_if(|sample| {
+                count == 0 || datagram.len() + sample.len() <= self.config.max_datagram
+            }) {
+                datagram.extend_from_slice(&sample);
+                count += 1;
+            }
+            datagram[DATAGRAM_HEADER_LEN - 4..DATAGRAM_HEADER_LEN]
+                .copy_from_slice(&count.to_be_bytes());
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    /// Send pending samples and `counters` to the collector, returning the datagrams sent
+    pub fn export(&self, socket: &UdpSocket, counters: &[InterfaceCounters]) -> Result<usize> {
+        let datagrams = self.datagrams(counters);
+        for datagram in &datagrams {
+            socket.send(self.config.collector, datagram)?;
+        }
+        Ok(datagrams.len())
+    }
+
+    fn datagram_header(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.config.max_datagram);
+        put_u32(&mut out, SFLOW_VERSION);
+        put_u32(&mut out, 1); // IPv4 agent address
+        out.extend_from_slice(&self.config.agent.octets());
+        put_u32(&mut out, self.config.sub_agent_id);
+        put_u32(
+            &mut out,
+            self.datagram_sequence
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1),
+        );
+        put_u32(&mut out, self.started.elapsed().as_millis() as u32);
+        put_u32(&mut out, 0); // Sample count, patched once known
+        out
+    }
+
+    fn encode_counters(&self, counters: &InterfaceCounters) -> Vec<u8> {
+        let mut out = Vec::with_capacity(28 + INTERFACE_COUNTERS_LEN as usize);
+        put_u32(&mut out, COUNTERS_SAMPLE);
+        put_u32(&mut out, 20 + INTERFACE_COUNTERS_LEN);
+        put_u32(
+            &mut out,
+            self.counter_sequence
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_add(1),
+        );
+        put_u32(&mut out, counters.if_index);
+        put_u32(&mut out, 1); // One record
+        put_u32(&mut out, GENERIC_INTERFACE_COUNTERS);
+        put_u32(&mut out, INTERFACE_COUNTERS_LEN);
+        put_u32(&mut out, counters.if_index);
+        put_u32(&mut out, 6); // ifType ethernetCsmacd
+        put_u64(&mut out, counters.speed);
+        put_u32(&mut out, 1); // Full duplex
+        put_u32(&mut out, 3); // Admin and operationally up
+        put_u64(&mut out, counters.in_octets);
+        put_u32(&mut out, counters.in_packets);
+        put_u32(&mut out, 0); // Multicast and broadcast are not counted apart
+        put_u32(&mut out, 0);
+        put_u32(&mut out, counters.in_discards);
+        put_u32(&mut out, counters.in_errors);
+        put_u32(&mut out, 0); // Unknown protocols
+        put_u64(&mut out, counters.out_octets);
+        put_u32(&mut out, counters.out_packets);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, counters.out_discards);
+        put_u32(&mut out, counters.out_errors);
+        put_u32(&mut out, 1); // Promiscuous, as every capture is opened
+        out
+    }
+}
+
+fn encode_flow(sample: &FlowSample) -> Vec<u8> {
+    let padded = sample.header.len().next_multiple_of(4);
+    let record_len = 16 + padded as u32;
+    let mut out = Vec::with_capacity(48 + record_len as usize);
+    put_u32(&mut out, FLOW_SAMPLE);
+    put_u32(&mut out, 40 + record_len);
+    put_u32(&mut out, sample.sequence);
+    let if_index = sample.port_id as u32 + 1;
+    put_u32(&mut out, if_index); // Data source: the receiving interface
+    put_u32(&mut out, sample.sampling_rate);
+    put_u32(&mut out, sample.sample_pool);
+    put_u32(&mut out, sample.drops);
+    put_u32(&mut out, if_index);
+    put_u32(&mut out, 0); // Output interface unknown
+    put_u32(&mut out, 1); // One record
+    put_u32(&mut out, RAW_PACKET_HEADER);
+    put_u32(&mut out, record_len);
+    put_u32(&mut out, HEADER_PROTOCOL_ETHERNET);
+    put_u32(&mut out, sample.frame_len as u32);
+    put_u32(&mut out, 0); // Nothing stripped
+    put_u32(&mut out, sample.header.len() as u32);
+    out.extend_from_slice(&sample.header);
+    out.resize(out.len() + padded - sample.header.len(), 0);
+    out
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_samples_and_counters_encode_as_sflow() {
+        let sampler: &'static FlowSampler = Box::leak(Box::default());
+        let mut config =
+            SflowConfig::new(Ipv4Addr::new(10, 0, 0, 1), "10.0.0.9:6343".parse().unwrap());
+        config.sampling_rate = 4;
+        config.header_bytes = 18;
+        config.max_datagram = 350;
+        let exporter = SflowExporter::with_sampler(config, sampler).unwrap();
+        exporter.set_sampling_rate(1, 0).unwrap();
+        exporter.set_sampling_rate(2, 1).unwrap();
+
+        // The first frame after enabling is sampled, then every frame at rate 1
+        let mut frame: Vec<u8> = (0..100).collect();
+        for (queue_id, frames) in [(0, 1), (1, 12), (2, 2)] {
+            for _ in 0..frames {
+                let mut mbuf = Mbuf::new(frame.as_mut_ptr(), frame.len());
+                mbuf.len = frame.len();
+                mbuf.queue_id = queue_id;
+                mbuf.port_id = 2;
+                sampler.sample(&mbuf);
+            }
+        }
+        assert_eq!(sampler.pending(), 3);
+
+        let counters = InterfaceCounters {
+            if_index: 3,
+            in_octets: 1200,
+            in_packets: 24,
+            ..InterfaceCounters::default()
+        };
+        let datagrams = exporter.datagrams(&[counters]);
+        // 28 header + 116 counters + 84 per flow sample, split at 350 bytes
+        assert_eq!(datagrams.len(), 2);
+        let first = &datagrams[0];
+        assert_eq!(first.len(), 28 + 116 + 2 * 84);
+        assert_eq!((be32(first, 0), be32(first, 8)), (5, 0x0A00_0001));
+        assert_eq!((be32(first, 16), be32(first, 24)), (1, 3));
+        assert_eq!((be32(first, 28), be32(first, 32)), (COUNTERS_SAMPLE, 108));
+        assert_eq!((be32(first, 28 + 56), be32(first, 28 + 60)), (1200, 24));
+
+        let flow = &first[28 + 116..];
+        assert_eq!((be32(flow, 0), be32(flow, 4)), (FLOW_SAMPLE, 76));
+        // Sequence, source, rate, pool, drops, input
+        assert_eq!(
+            (0..6).map(|i| be32(flow, 8 + 4 * i)).collect::<Vec<_>>(),
+            vec![1, 3, 4, 1, 0, 3]
+        );
+        assert_eq!((be32(flow, 52), be32(flow, 60)), (100, 18));
+        assert_eq!(&flow[64..82], &frame[..18]);
+        assert_eq!(be32(&datagrams[1], 24), 1);
+        assert_eq!(sampler.pending(), 0);
+
+        // Skips average out to the rate
+        for _ in 0..3000 {
+            let mut mbuf = Mbuf::new(frame.as_mut_ptr(), frame.len());
+            mbuf.len = frame.len();
+            sampler.sample(&mbuf);
+        }
+        let samples = sampler.drain(MAX_PENDING_SAMPLES).len();
+        assert!((650..850).contains(&samples), "{} samples", samples);
+
+        // Dropping an exporter leaves the shared rates as they are
+        {
+            let _other = SflowExporter::with_sampler(exporter.config().clone(), sampler).unwrap();
+        }
+        assert_eq!(sampler.sampling_rate(0), 4);
+        exporter.stop();
+        assert_eq!(sampler.sampling_rate(0), 0);
+    }
+}
@@ -0,0 +1,214 @@
+//! Cooperative shutdown signalling
+//!
+//! A [`ShutdownToken`] is a cloneable cancellation flag. Tokens form a tree:
+//! cancelling a token cancels every child created from it, while cancelling
+//! a child leaves its parent running. The [`crate::Xpdk`] context owns the
+//! root and hands children to the driver, dispatcher and workers, so a
+//! single `shutdown()` reaches every loop, and applications can observe or
+//! wait for the same signal.
+
+use crate::{Error, Result};
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Interval at which [`join_with_deadline`] checks a stopping thread
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+struct Inner {
+    cancelled: AtomicBool,
+    parent: Option<Arc<Inner>>,
+    children: Mutex<Vec<Weak<Inner>>>,
+    lock: Mutex<()>,
+    cancelled_cond: Condvar,
+}
+
+impl Inner {
+    fn new(parent: Option<Arc<Inner>>) -> Arc<Self> {
+        Arc::new(Self {
+            cancelled: AtomicBool::new(false),
+            parent,
+            children: Mutex::new(Vec::new()),
+            lock: Mutex::new(()),
+            cancelled_cond: Condvar::new(),
+        })
+    }
+
+    fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        {
+            let _guard = self.lock.lock();
+            self.cancelled_cond.notify_all();
+        }
+
+        let children = std::mem::take(&mut *self.children.lock());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+
+    fn child(self: &Arc<Self>) -> Arc<Inner> {
+        let child = Inner::new(Some(self.clone()));
+        {
+            let mut children = self.children.lock();
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&child));
+        }
+        // Registered before the check, so a concurrent cancel reaches us either way
+        if self.cancelled.load(Ordering::Acquire) {
+            child.cancel();
+        }
+        child
+    }
+}
+
+/// Cloneable, hierarchical shutdown signal
+#[derive(Clone)]
+pub struct ShutdownToken {
+    inner: Arc<Inner>,
+}
+
+impl ShutdownToken {
+    /// Create a root token
+    pub fn new() -> Self {
+        Self {
+            inner: Inner::new(None),
+        }
+    }
+
+    /// Create a token cancelled together with this one
+    pub fn child(&self) -> Self {
+        Self {
+            inner: self.inner.child(),
+        }
+    }
+
+    /// Fresh token under the same parent, for restarting after a stop
+    pub fn renew(&self) -> Self {
+        match &self.inner.parent {
+            Some(parent) => Self {
+                inner: parent.child(),
+            },
+            None => Self::new(),
+        }
+    }
+
+    /// Request shutdown of this token and all of its children
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    /// Whether shutdown was requested
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Block until shutdown is requested
+    pub fn wait_for_shutdown(&self) {
+        let mut guard = self.inner.lock.lock();
+        while !self.is_cancelled() {
+            self.inner.cancelled_cond.wait(&mut guard);
+        }
+    }
+
+    /// Block until shutdown is requested or `timeout` passes
+    ///
+    /// Returns whether shutdown was requested.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.inner.lock.lock();
+        while !self.is_cancelled() {
+            if self
+                .inner
+                .cancelled_cond
+                .wait_until(&mut guard, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+        self.is_cancelled()
+    }
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for ShutdownToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShutdownToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Join a thread that was asked to stop, giving up at `deadline`
+///
+/// A thread still running at the deadline is detached and an error returned.
+pub fn join_with_deadline<T>(handle: JoinHandle<T>, deadline: Instant) -> Result<T> {
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return Err(Error::ShutdownError(format!(
+                "Thread {:?} did not stop before the deadline",
+                handle.thread().name().unwrap_or("<unnamed>")
+            )));
+        }
+        thread::sleep(JOIN_POLL_INTERVAL);
+    }
+
+    handle
+        .join()
+        .map_err(|_| Error::ShutdownError("Thread panicked while stopping".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_propagates_to_children() {
+        let root = ShutdownToken::new();
+        let child = root.child();
+        let grandchild = child.child();
+        let sibling = root.child();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(!root.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        // A renewed token is back under the parent
+        let renewed = child.renew();
+        assert!(!renewed.is_cancelled());
+
+        root.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(renewed.is_cancelled());
+        assert!(root.child().is_cancelled());
+    }
+
+    #[test]
+    fn test_wait_and_deadline_join() {
+        let token = ShutdownToken::new();
+        assert!(!token.wait_timeout(Duration::from_millis(1)));
+
+        let worker_token = token.child();
+        let worker = thread::spawn(move || worker_token.wait_for_shutdown());
+        token.cancel();
+        assert!(token.wait_timeout(Duration::from_millis(1)));
+        join_with_deadline(worker, Instant::now() + Duration::from_secs(5)).unwrap();
+
+        let stuck = thread::spawn(|| thread::sleep(Duration::from_millis(200)));
+        assert!(join_with_deadline(stuck, Instant::now()).is_err());
+    }
+}
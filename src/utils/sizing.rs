@@ -0,0 +1,205 @@
+//! Pool and queue sizing from traffic targets
+//!
+//! Instead of guessing `pool_size` and `rx_queue_size`, applications declare
+//! the traffic they expect in [`SizingTargets`] and [`PoolSizing::derive`]
+//! works out rings deep enough to absorb a burst and a pool large enough for
+//! every mbuf that can be in flight: filled RX rings, full socket queues,
+//! and a quarter on top. The derived memory is checked against the free
+//! huge pages when huge pages are enabled. [`crate::ConfigBuilder::auto_size`]
+//! applies the result to a configuration.
+
+use crate::poll::DEFAULT_PACKET_SIZE;
+use crate::udp::EthernetHeader;
+use crate::{Config, Error, Result};
+use std::fs;
+use std::time::Duration;
+
+/// Smallest derived RX ring
+pub const MIN_QUEUE_SIZE: usize = 64;
+
+/// Largest derived RX ring
+pub const MAX_QUEUE_SIZE: usize = 1 << 16;
+
+/// Traffic the configuration has to carry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizingTargets {
+    /// Expected peak receive rate
+    pub packets_per_sec: u64,
+    /// Longest burst at that rate the RX rings must absorb
+    pub burst: Duration,
+    /// Receive queue depth of each socket
+    pub socket_queue_depth: usize,
+    /// Number of sockets
+    pub sockets: usize,
+    /// Link MTU
+    pub mtu: u16,
+}
+
+impl Default for SizingTargets {
+    fn default() -> Self {
+        Self {
+            packets_per_sec: 1_000_000,
+            burst: Duration::from_millis(1),
+            socket_queue_depth: 1024,
+            sockets: 1,
+            mtu: 1500,
+        }
+    }
+}
+
+/// Sizes derived from [`SizingTargets`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSizing {
+    /// Mbufs per pool
+    pub pool_size: usize,
+    /// Slots per RX queue
+    pub rx_queue_size: usize,
+    /// Memory taken by the pools of the configuration, in bytes
+    pub memory_bytes: usize,
+}
+
+impl PoolSizing {
+    /// Derive sizes for `targets` over the queue and pool counts of `config`
+    pub fn derive(targets: &SizingTargets, config: &Config) -> Result<Self> {
+        if EthernetHeader::LEN + targets.mtu as usize > DEFAULT_PACKET_SIZE {
+            return Err(Error::InvalidConfig(format!(
+                "MTU {} does not fit {}-byte packet buffers",
+                targets.mtu, DEFAULT_PACKET_SIZE
+            )));
+        }
+
+        let rx_queues = config.rx_queue_count.max(1);
+        let burst_packets = (targets.packets_per_sec as f64 * targets.burst.as_secs_f64()).ceil();
+        let rx_queue_size = ((burst_packets as usize).div_ceil(rx_queues))
+            .next_power_of_two()
+            .max(MIN_QUEUE_SIZE);
+        if rx_queue_size > MAX_QUEUE_SIZE {
+            return Err(Error::InvalidConfig(format!(
+                "A burst of {} packets needs RX rings of {} slots, above {}; add RX queues",
+                burst_packets, rx_queue_size, MAX_QUEUE_SIZE
+            )));
+        }
+
+        let too_large = || {
+            Error::InvalidConfig(format!(
+                "{} sockets of {} slots over {} RX rings of {} slots need more mbufs than fit in memory",
+                targets.sockets, targets.socket_queue_depth, rx_queues, rx_queue_size
+            ))
+        };
+        let in_flight = targets
+            .sockets
+            .checked_mul(targets.socket_queue_depth)
+            .and_then(|queued| queued.checked_add(rx_queue_size * rx_queues))
+            .ok_or_else(too_large)?;
+        let pool_size = in_flight
+            .checked_add(in_flight.div_ceil(4))
+            .ok_or_else(too_large)?;
+        // Memory manager pools plus the PMD pool
+        let memory_bytes = config
+            .pool_count
+            .checked_add(1)
+            .and_then(|pools| pools.checked_mul(pool_size))
+            .and_then(|mbufs| mbufs.checked_mul(DEFAULT_PACKET_SIZE))
+            .ok_or_else(too_large)?;
+
+        Ok(Self {
+            pool_size,
+            rx_queue_size,
+            memory_bytes,
+        })
+    }
+
+    /// Fail if huge pages are enabled and fewer than the derived memory are free
+    ///
+    /// `free_hugepage_bytes` of `None` means the amount is unknown, which passes.
+    pub fn check_hugepages(&self, config: &Config, free_hugepage_bytes: Option<u64>) -> Result<()> {
+        match free_hugepage_bytes {
+            Some(free) if config.enable_hugepages && free < self.memory_bytes as u64 => {
+                Err(Error::InvalidConfig(format!(
+                    "Pools need {} KiB but only {} KiB of huge pages are free; \
+                     reserve more in /proc/sys/vm/nr_hugepages or disable huge pages",
+                    self.memory_bytes / 1024,
+                    free / 1024
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the derived sizes into `config`
+    pub fn apply(&self, config: &mut Config) {
+        config.pool_size = self.pool_size;
+        config.rx_queue_size = self.rx_queue_size;
+    }
+}
+
+/// Free huge page memory from /proc/meminfo
+pub fn free_hugepage_bytes() -> Option<u64> {
+    parse_free_hugepages(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn parse_free_hugepages(meminfo: &str) -> Option<u64> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))?
+            .split_whitespace()
+            .next()?
+            .parse::<u64>()
+            .ok()
+    };
+    field("HugePages_Free:")?
+        .checked_mul(field("Hugepagesize:")?)?
+        .checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_sizes() {
+        let config = Config {
+            rx_queue_count: 2,
+            pool_count: 1,
+            ..Config::default()
+        };
+        // 1000 packets per burst over 2 queues
+        let targets = SizingTargets {
+            packets_per_sec: 1_000_000,
+            burst: Duration::from_millis(1),
+            socket_queue_depth: 256,
+            sockets: 4,
+            mtu: 1500,
+        };
+        let sizing = PoolSizing::derive(&targets, &config).unwrap();
+        assert_eq!(sizing.rx_queue_size, 512);
+        assert_eq!(sizing.pool_size, 2048 + 512);
+        assert_eq!(sizing.memory_bytes, 2 * 2560 * DEFAULT_PACKET_SIZE);
+
+        assert!(sizing.check_hugepages(&config, None).is_ok());
+        assert!(sizing.check_hugepages(&config, Some(1 << 20)).is_err());
+        let without = Config {
+            enable_hugepages: false,
+            ..config.clone()
+        };
+        assert!(sizing.check_hugepages(&without, Some(0)).is_ok());
+
+        let jumbo = SizingTargets {
+            mtu: 9000,
+            ..targets
+        };
+        assert!(PoolSizing::derive(&jumbo, &config).is_err());
+        // Sizes past the address space are refused rather than wrapped
+        let huge = SizingTargets {
+            sockets: usize::MAX / 2,
+            ..targets
+        };
+        assert!(PoolSizing::derive(&huge, &config).is_err());
+
+        let meminfo = "MemFree: 100 kB\nHugePages_Free:      3\nHugepagesize:    2048 kB\n";
+        assert_eq!(parse_free_hugepages(meminfo), Some(3 * 2048 * 1024));
+        let bogus = "HugePages_Free: 18446744073709551615\nHugepagesize: 2048 kB\n";
+        assert_eq!(parse_free_hugepages(bogus), None);
+    }
+}
@@ -1,6 +1,10 @@
 //! Time utilities for high-performance timestamping and timing
 
-use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// High-resolution timestamp
@@ -14,29 +18,142 @@ pub enum TimestampSource {
     TscClock,
 }
 
+/// Length of one TSC calibration window
+pub const TSC_CALIBRATION_WINDOW: Duration = Duration::from_millis(10);
+
+/// Calibration windows measured per calibration
+pub const TSC_CALIBRATION_ROUNDS: usize = 7;
+
+/// Deviation from the median beyond which a calibration window is discarded
+pub const TSC_OUTLIER_PPM: f64 = 1000.0;
+
+/// Drift beyond which [`HighResTimer::revalidate`] recalibrates
+pub const TSC_DRIFT_TOLERANCE_PPM: f64 = 100.0;
+
+/// Baseline [`HighResTimer::revalidate`] needs to measure drift
+pub const TSC_MIN_DRIFT_BASELINE: Duration = Duration::from_millis(100);
+
+/// Frequency assumed when the TSC cannot be measured
+const DEFAULT_TSC_FREQUENCY: u64 = 2_400_000_000;
+
+/// Where a TSC frequency came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationMethod {
+    /// Reported by CPUID leaf 0x15
+    Cpuid,
+    /// Measured against the monotonic clock
+    Measured,
+    /// Nothing usable, the default frequency is assumed
+    Default,
+}
+
+/// Quality of a TSC calibration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TscCalibration {
+    /// TSC ticks per second
+    pub frequency: u64,
+    pub method: CalibrationMethod,
+    /// Whether the TSC ticks at a constant rate across frequency and power states
+    pub invariant: bool,
+    /// Calibration windows kept after outlier rejection
+    pub samples: usize,
+    /// Calibration windows discarded as outliers
+    pub rejected: usize,
+    /// Spread of the kept windows in parts per million
+    pub spread_ppm: f64,
+    /// Drift measured by the last revalidation in parts per million
+    pub drift_ppm: f64,
+    /// Times revalidation found drift beyond tolerance and recalibrated
+    pub recalibrations: u64,
+}
+
+/// Words read together without a lock, written by one writer at a time
+///
+/// A sequence number, odd while a write is underway, tells a reader that
+/// saw a write in between to read again.
+struct SeqWords<const N: usize> {
+    seq: AtomicU64,
+    words: [AtomicU64; N],
+}
+
+impl<const N: usize> SeqWords<N> {
+    fn new(words: [u64; N]) -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            words: words.map(AtomicU64::new),
+        }
+    }
+
+    fn load(&self) -> [u64; N] {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                let words = std::array::from_fn(|i| self.words[i].load(Ordering::Relaxed));
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == before {
+                    return words;
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Callers serialize writes
+    fn store(&self, words: [u64; N]) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in self.words.iter().zip(words) {
+            word.store(value, Ordering::Relaxed);
+        }
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+}
+
+/// TSC and monotonic clock read together, the base drift is measured from
+#[derive(Debug, Clone, Copy)]
+struct TscReference {
+    tsc: u64,
+    at: Instant,
+}
+
+impl TscReference {
+    fn now() -> Self {
+        let (tsc, at) = read_tsc_bracketed();
+        Self { tsc, at }
+    }
+}
+
 /// High-resolution timer
 pub struct HighResTimer {
     /// Timestamp source
     source: TimestampSource,
-    /// TSC frequency (if using TSC)
-    tsc_frequency: u64,
-    /// TSC offset calibration
-    tsc_offset: AtomicU64,
+    /// TSC frequency (if using TSC) and the TSC value timestamps count from,
+    /// changed together so a reader never mixes the two
+    tsc_scale: SeqWords<2>,
+    /// Calibration quality and drift reference (if using TSC)
+    calibration: Mutex<Option<(TscCalibration, TscReference)>>,
 }
 
 impl HighResTimer {
     /// Create a new high-resolution timer
     pub fn new(source: TimestampSource) -> Self {
-        let tsc_frequency = if matches!(source, TimestampSource::TscClock) {
-            calibrate_tsc()
-        } else {
-            0
-        };
+        let calibration = matches!(source, TimestampSource::TscClock).then(|| {
+            static CALIBRATION: std::sync::OnceLock<TscCalibration> = std::sync::OnceLock::new();
+            let calibration = *CALIBRATION.get_or_init(|| {
+                let calibration = calibrate_tsc();
+                if !calibration.invariant {
+                    log::warn!("TSC is not invariant, latencies may drift with CPU frequency");
+                }
+                calibration
+            });
+            (calibration, TscReference::now())
+        });
 
         Self {
             source,
-            tsc_frequency,
-            tsc_offset: AtomicU64::new(0),
+            tsc_scale: SeqWords::new([calibration.map_or(0, |(c, _)| c.frequency), 0]),
+            calibration: Mutex::new(calibration),
         }
     }
 
@@ -47,12 +164,7 @@ impl HighResTimer {
                 .duration_since(UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_nanos() as Timestamp,
-            TimestampSource::MonotonicClock => {
-                // Use a base instant to ensure monotonic increasing values
-                static BASE_INSTANT: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
-                let base = BASE_INSTANT.get_or_init(Instant::now);
-                base.elapsed().as_nanos() as Timestamp
-            }
+            TimestampSource::MonotonicClock => monotonic_now(),
             TimestampSource::TscClock => self.tsc_to_nanos(read_tsc()),
         }
     }
@@ -75,7 +187,10 @@ impl HighResTimer {
     /// Calibrate TSC clock
     pub fn calibrate(&mut self) -> Result<(), crate::Error> {
         if matches!(self.source, TimestampSource::TscClock) {
-            self.tsc_frequency = calibrate_tsc();
+            let calibration = calibrate_tsc();
+            let reference = TscReference::now();
+            self.rescale(calibration.frequency, reference.tsc);
+            *self.calibration.lock() = Some((calibration, reference));
             Ok(())
         } else {
             Err(crate::Error::InvalidConfig(
@@ -86,19 +201,90 @@ impl HighResTimer {
 
     /// Get TSC frequency
     pub fn tsc_frequency(&self) -> u64 {
-        self.tsc_frequency
+        self.tsc_scale.load()[0]
+    }
+
+    /// Switch to `frequency` at TSC value `tsc` without a jump in timestamps
+    ///
+    /// The offset moves so that `tsc` converts to the same timestamp at
+    /// either frequency. Callers serialize rescaling.
+    fn rescale(&self, frequency: u64, tsc: u64) {
+        let [old_frequency, offset] = self.tsc_scale.load();
+        let offset = if old_frequency == 0 || frequency == 0 {
+            offset
+        } else {
+            let timestamp =
+                tsc.wrapping_sub(offset) as u128 * 1_000_000_000 / old_frequency as u128;
+            // Wraps below zero if the new frequency is higher, which the
+            // wrapping conversion undoes
+            tsc.wrapping_sub((timestamp * frequency as u128 / 1_000_000_000) as u64)
+        };
+        self.tsc_scale.store([frequency, offset]);
+    }
+
+    /// Quality of the TSC calibration, `None` unless using TSC
+    pub fn calibration(&self) -> Option<TscCalibration> {
+        self.calibration.lock().map(|(calibration, _)| calibration)
+    }
+
+    /// Whether the TSC ticks at a constant rate
+    pub fn is_invariant(&self) -> bool {
+        self.calibration().is_some_and(|c| c.invariant)
+    }
+
+    /// Measure the TSC frequency since the last calibration and return the drift in ppm
+    ///
+    /// Drift beyond [`TSC_DRIFT_TOLERANCE_PPM`] adopts the measured frequency
+    /// and restarts the baseline; call this periodically from a housekeeping
+    /// thread.
+    pub fn revalidate(&self) -> Result<f64, crate::Error> {
+        self.revalidate_at(TscReference::now())
+    }
+
+    fn revalidate_at(&self, now: TscReference) -> Result<f64, crate::Error> {
+        let mut guard = self.calibration.lock();
+        let Some((calibration, reference)) = guard.as_mut() else {
+            return Err(crate::Error::InvalidConfig(
+                "TSC calibration not applicable".to_string(),
+            ));
+        };
+        let baseline = now.at.saturating_duration_since(reference.at);
+        if baseline < TSC_MIN_DRIFT_BASELINE {
+            return Err(crate::Error::InvalidConfig(format!(
+                "Drift baseline of {:?} is shorter than {:?}",
+                baseline, TSC_MIN_DRIFT_BASELINE
+            )));
+        }
+
+        let measured = ticks_per_second(now.tsc.wrapping_sub(reference.tsc), baseline);
+        let drift_ppm =
+            (measured - calibration.frequency as f64) / calibration.frequency as f64 * 1e6;
+        calibration.drift_ppm = drift_ppm;
+        if drift_ppm.abs() > TSC_DRIFT_TOLERANCE_PPM {
+            log::warn!(
+                "TSC drifted {:.0} ppm from {} Hz, recalibrating to {:.0} Hz",
+                drift_ppm,
+                calibration.frequency,
+                measured
+            );
+            calibration.frequency = measured as u64;
+            calibration.method = CalibrationMethod::Measured;
+            calibration.recalibrations += 1;
+            *reference = now;
+            self.rescale(calibration.frequency, now.tsc);
+        }
+        Ok(drift_ppm)
     }
 
     /// Convert TSC cycles to nanoseconds
     fn tsc_to_nanos(&self, tsc: u64) -> Timestamp {
-        let freq = self.tsc_frequency;
+        let [freq, offset] = self.tsc_scale.load();
         if freq > 0 {
             // Convert TSC cycles to nanoseconds
-            let offset = self.tsc_offset.load(Ordering::Relaxed);
             ((tsc.wrapping_sub(offset)) as u128 * 1_000_000_000 / freq as u128) as Timestamp
         } else {
             // Fallback to monotonic clock
-            Instant::now().elapsed().as_nanos() as Timestamp
+            monotonic_now()
         }
     }
 }
@@ -135,41 +321,97 @@ fn read_tsc() -> u64 {
     }
 }
 
+/// Read the TSC with the monotonic clock, taking the midpoint of two clock reads
+fn read_tsc_bracketed() -> (u64, Instant) {
+    let before = Instant::now();
+    let tsc = read_tsc();
+    let after = Instant::now();
+    (tsc, before + after.saturating_duration_since(before) / 2)
+}
+
+fn ticks_per_second(ticks: u64, elapsed: Duration) -> f64 {
+    ticks as f64 / elapsed.as_secs_f64()
+}
+
 /// Calibrate TSC frequency
-fn calibrate_tsc() -> u64 {
-    // Use CPUID to get TSC frequency if available
+fn calibrate_tsc() -> TscCalibration {
+    let mut calibration = TscCalibration {
+        frequency: DEFAULT_TSC_FREQUENCY,
+        method: CalibrationMethod::Default,
+        invariant: tsc_is_invariant(),
+        samples: 0,
+        rejected: 0,
+        spread_ppm: 0.0,
+        drift_ppm: 0.0,
+        recalibrations: 0,
+    };
+
     #[cfg(target_arch = "x86_64")]
     {
         if let Some(freq) = get_tsc_frequency_from_cpuid() {
-            return freq;
+            calibration.frequency = freq;
+            calibration.method = CalibrationMethod::Cpuid;
+            return calibration;
         }
     }
 
-    // Fallback: measure using system clock
-    let iterations = 1_000_000;
-    let start = read_tsc();
-    let start_time = SystemTime::now();
-
-    for _ in 0..iterations {
-        // Busy wait
+    // Measure fixed windows against the monotonic clock
+    let rates: Vec<f64> = (0..TSC_CALIBRATION_ROUNDS)
+        .filter_map(|_| {
+            let (start_tsc, start) = read_tsc_bracketed();
+            std::thread::sleep(TSC_CALIBRATION_WINDOW);
+            let (end_tsc, end) = read_tsc_bracketed();
+            let elapsed = end.saturating_duration_since(start);
+            (!elapsed.is_zero() && end_tsc > start_tsc)
+                .then(|| ticks_per_second(end_tsc - start_tsc, elapsed))
+        })
+        .collect();
+    if let Some((frequency, kept, spread_ppm)) = reject_outliers(rates.clone()) {
+        calibration.frequency = frequency as u64;
+        calibration.method = CalibrationMethod::Measured;
+        calibration.samples = kept;
+        calibration.rejected = rates.len() - kept;
+        calibration.spread_ppm = spread_ppm;
     }
+    calibration
+}
 
-    let end = read_tsc();
-    let elapsed = start_time.elapsed().unwrap_or_default();
+/// Mean of the rates within [`TSC_OUTLIER_PPM`] of their median, with their count and spread
+fn reject_outliers(mut rates: Vec<f64>) -> Option<(f64, usize, f64)> {
+    if rates.is_empty() {
+        return None;
+    }
+    rates.sort_by(f64::total_cmp);
+    let median = rates[rates.len() / 2];
+    let kept: Vec<f64> = rates
+        .into_iter()
+        .filter(|rate| ((rate - median) / median * 1e6).abs() <= TSC_OUTLIER_PPM)
+        .collect();
+    let mean = kept.iter().sum::<f64>() / kept.len() as f64;
+    let spread = kept.last()? - kept.first()?;
+    Some((mean, kept.len(), spread / mean * 1e6))
+}
 
-    if elapsed.as_nanos() > 0 {
-        ((end - start) as u128 * 1_000_000_000 / elapsed.as_nanos()) as u64
-    } else {
-        2_400_000_000 // Default to 2.4 GHz
+/// Whether CPUID reports an invariant TSC
+fn tsc_is_invariant() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        raw_cpuid::CpuId::new()
+            .get_advanced_power_mgmt_info()
+            .is_some_and(|info| info.has_invariant_tsc())
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
     }
 }
 
-/// Get TSC frequency from CPUID (simplified)
+/// Get TSC frequency from CPUID leaf 0x15
 #[cfg(target_arch = "x86_64")]
 fn get_tsc_frequency_from_cpuid() -> Option<u64> {
-    // This is a simplified implementation
-    // In a real implementation, you would use CPUID leaf 0x15
-    None
+    raw_cpuid::CpuId::new()
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
 }
 
 /// Latency tracker
@@ -443,6 +685,361 @@ impl TimeWindowCounter {
     }
 }
 
+/// Nanoseconds on the process-wide monotonic clock
+pub fn monotonic_now() -> Timestamp {
+    // Use a base instant to ensure monotonic increasing values
+    static BASE_INSTANT: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    let base = BASE_INSTANT.get_or_init(Instant::now);
+    base.elapsed().as_nanos() as Timestamp
+}
+
+/// Samples kept by an [`OffsetEstimator`] by default
+pub const DEFAULT_OFFSET_WINDOW: usize = 16;
+
+/// Age after which [`PhcSync`] takes a fresh sample before converting
+pub const PHC_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One reading of a hardware clock bracketed by two system clock readings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    /// System time halfway between the two system readings
+    pub system_ns: u64,
+    /// Hardware clock reading
+    pub phc_ns: u64,
+    /// Time between the two system readings, the sample's uncertainty
+    pub delay_ns: u64,
+}
+
+/// PTP-style estimate of a hardware clock against the system clock
+///
+/// Like a PTP delay exchange, each sample brackets a hardware clock read
+/// between two system clock reads. The offset comes from the sample with the
+/// lowest delay in the window, the one least disturbed by preemption, and the
+/// rate of the hardware clock from the oldest and newest samples.
+#[derive(Debug, Clone)]
+pub struct OffsetEstimator {
+    window: usize,
+    samples: VecDeque<ClockSample>,
+}
+
+impl OffsetEstimator {
+    /// Estimate from the last `window` samples
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add a hardware reading taken between system readings `before_ns` and `after_ns`
+    pub fn add_sample(&mut self, before_ns: u64, phc_ns: u64, after_ns: u64) -> ClockSample {
+        let delay_ns = after_ns.saturating_sub(before_ns);
+        let sample = ClockSample {
+            system_ns: before_ns + delay_ns / 2,
+            phc_ns,
+            delay_ns,
+        };
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        sample
+    }
+
+    /// Sample with the lowest delay in the window
+    pub fn best(&self) -> Option<ClockSample> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.delay_ns)
+            .copied()
+    }
+
+    /// Hardware minus system time in nanoseconds
+    pub fn offset_ns(&self) -> Option<i64> {
+        self.best()
+            .map(|best| best.phc_ns as i64 - best.system_ns as i64)
+    }
+
+    /// Hardware clock nanoseconds per system nanosecond, 1.0 until two samples apart
+    pub fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) if last.system_ns > first.system_ns => {
+                (last.phc_ns as f64 - first.phc_ns as f64)
+                    / (last.system_ns - first.system_ns) as f64
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// System time of a hardware clock reading
+    pub fn to_system(&self, phc_ns: u64) -> Option<u64> {
+        let best = self.best()?;
+        Some(phc_to_system(phc_ns, best, self.rate()))
+    }
+
+    /// Number of samples in the window
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Check if no sample was taken yet
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+impl Default for OffsetEstimator {
+    fn default() -> Self {
+        Self::new(DEFAULT_OFFSET_WINDOW)
+    }
+}
+
+/// System time of `phc_ns` from a reference sample and the hardware clock rate
+fn phc_to_system(phc_ns: u64, best: ClockSample, rate: f64) -> u64 {
+    let elapsed = (phc_ns as f64 - best.phc_ns as f64) / rate;
+    (best.system_ns as f64 + elapsed).max(0.0) as u64
+}
+
+/// PTP hardware clock (PHC) of a NIC
+pub struct PhcClock {
+    /// Open `/dev/ptp*` device, which the dynamic clock ID refers to
+    #[allow(dead_code)]
+    device: File,
+    clock_id: libc::clockid_t,
+}
+
+impl PhcClock {
+    /// Open the clock device at `path`, e.g. `/dev/ptp0`
+    pub fn open(path: &Path) -> crate::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let device = File::open(path)?;
+        // FD_TO_CLOCKID from the kernel's posix-timers
+        let clock_id = ((!device.as_raw_fd()) << 3) | 3;
+        Ok(Self { device, clock_id })
+    }
+
+    /// Open the clock of network interface `interface`
+    pub fn for_interface(interface: &str) -> crate::Result<Self> {
+        let ptp_dir = format!("/sys/class/net/{}/device/ptp", interface);
+        let entry = std::fs::read_dir(&ptp_dir)
+            .ok()
+            .and_then(|mut entries| entries.find_map(|entry| entry.ok()))
+            .ok_or_else(|| {
+                crate::Error::InvalidConfig(format!(
+                    "Interface {} has no hardware clock",
+                    interface
+                ))
+            })?;
+        Self::open(&Path::new("/dev").join(entry.file_name()))
+    }
+
+    /// Current hardware clock time in nanoseconds
+    pub fn now(&self) -> crate::Result<u64> {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        if unsafe { libc::clock_gettime(self.clock_id, &mut ts) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+    }
+}
+
+/// Conversion of NIC hardware timestamps to the system clock
+///
+/// Receive timestamps are on the system (wall) clock, like the software
+/// timestamps libpcap takes. The estimate is refreshed with a new sample
+/// once it is older than [`PHC_SYNC_INTERVAL`]. Each sample publishes the
+/// reference sample and rate of the estimate, so converting a timestamp
+/// reads them without a lock.
+pub struct PhcSync {
+    clock: PhcClock,
+    /// Serializes samples, and with them writes of `conversion`
+    estimator: Mutex<OffsetEstimator>,
+    /// Estimate present (1 or 0), reference hardware and system times,
+    /// and the bits of the rate
+    conversion: SeqWords<4>,
+    /// Monotonic time of the last sample
+    last_sync: AtomicU64,
+}
+
+impl PhcSync {
+    /// Convert timestamps of `clock`
+    pub fn new(clock: PhcClock) -> Self {
+        Self {
+            clock,
+            estimator: Mutex::new(OffsetEstimator::default()),
+            conversion: SeqWords::new([0; 4]),
+            last_sync: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a sample of the hardware clock against the system clock
+    pub fn sync(&self) -> crate::Result<ClockSample> {
+        let before = wall_clock_ns();
+        let phc_ns = self.clock.now()?;
+        let after = wall_clock_ns();
+        self.last_sync.store(monotonic_now(), Ordering::Relaxed);
+        let mut estimator = self.estimator.lock();
+        let sample = estimator.add_sample(before, phc_ns, after);
+        if let Some(best) = estimator.best() {
+            self.conversion
+                .store([1, best.phc_ns, best.system_ns, estimator.rate().to_bits()]);
+        }
+        Ok(sample)
+    }
+
+    /// System time of a hardware timestamp
+    pub fn to_system(&self, phc_ns: u64) -> Option<u64> {
+        let last_sync = self.last_sync.load(Ordering::Relaxed);
+        if last_sync == 0
+            || monotonic_now().saturating_sub(last_sync) >= PHC_SYNC_INTERVAL.as_nanos() as u64
+        {
+            // A failed read keeps converting with the previous estimate
+            let _ = self.sync();
+        }
+        let [present, best_phc_ns, best_system_ns, rate] = self.conversion.load();
+        if present == 0 {
+            return None;
+        }
+        let best = ClockSample {
+            system_ns: best_system_ns,
+            phc_ns: best_phc_ns,
+            delay_ns: 0,
+        };
+        Some(phc_to_system(phc_ns, best, f64::from_bits(rate)))
+    }
+
+    /// Current offset and rate estimate
+    pub fn estimator(&self) -> OffsetEstimator {
+        self.estimator.lock().clone()
+    }
+}
+
+/// Nanoseconds since the Unix epoch on the system clock
+fn wall_clock_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Handle of a timer scheduled on a [`TimerWheel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// Timer scheduled on a wheel slot
+struct TimerEntry<T> {
+    id: TimerId,
+    deadline_tick: u64,
+    value: T,
+}
+
+/// Hashed timer wheel
+///
+/// Timers are bucketed by tick into a fixed ring of slots; timers further
+/// out than one revolution share slots and are skipped until their tick
+/// comes. The wheel does not read a clock: callers drive it with
+/// [`TimerWheel::advance`], which makes it usable with any timestamp source.
+pub struct TimerWheel<T> {
+    /// Tick length in nanoseconds
+    tick_ns: u64,
+    /// Timestamp of tick 0
+    start: Timestamp,
+    /// Last tick processed
+    current_tick: u64,
+    slots: Vec<Vec<TimerEntry<T>>>,
+    /// Slot of each pending timer
+    pending: HashMap<TimerId, usize>,
+    next_id: u64,
+}
+
+impl<T> TimerWheel<T> {
+    /// Create a wheel of `slots` slots of one `tick` each, starting at `start`
+    pub fn new(tick: Duration, slots: usize, start: Timestamp) -> Self {
+        Self {
+            tick_ns: (tick.as_nanos() as u64).max(1),
+            start,
+            current_tick: 0,
+            slots: (0..slots.max(1)).map(|_| Vec::new()).collect(),
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Schedule `value` to expire at `deadline`
+    ///
+    /// Deadlines are rounded up to the next tick; past deadlines expire on
+    /// the next advance.
+    pub fn schedule(&mut self, deadline: Timestamp, value: T) -> TimerId {
+        let deadline_tick = self
+            .tick_of(deadline.saturating_add(self.tick_ns - 1))
+            .max(self.current_tick + 1);
+        let slot = (deadline_tick % self.slots.len() as u64) as usize;
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        self.slots[slot].push(TimerEntry {
+            id,
+            deadline_tick,
+            value,
+        });
+        self.pending.insert(id, slot);
+        id
+    }
+
+    /// Cancel a pending timer, returning its value
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let slot = self.pending.remove(&id)?;
+        let entries = &mut self.slots[slot];
+        let index = entries.iter().position(|entry| entry.id == id)?;
+        Some(entries.swap_remove(index).value)
+    }
+
+    /// Move the wheel to `now` and return every timer that expired
+    pub fn advance(&mut self, now: Timestamp) -> Vec<T> {
+        let now_tick = self.tick_of(now);
+        let mut expired = Vec::new();
+        if now_tick <= self.current_tick {
+            return expired;
+        }
+
+        // One revolution visits every slot, however far the wheel moves
+        let ticks = (now_tick - self.current_tick).min(self.slots.len() as u64);
+        for tick in self.current_tick + 1..=self.current_tick + ticks {
+            let slot = (tick % self.slots.len() as u64) as usize;
+            let mut index = 0;
+            while index < self.slots[slot].len() {
+                if self.slots[slot][index].deadline_tick <= now_tick {
+                    let entry = self.slots[slot].swap_remove(index);
+                    self.pending.remove(&entry.id);
+                    expired.push(entry.value);
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.current_tick = now_tick;
+        expired
+    }
+
+    /// Number of pending timers
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Check if no timers are pending
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn tick_of(&self, timestamp: Timestamp) -> u64 {
+        timestamp.saturating_sub(self.start) / self.tick_ns
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -460,6 +1057,52 @@ mod tests {
         assert!(elapsed.as_millis() >= 1);
     }
 
+    #[test]
+    fn test_tsc_calibration_and_drift() {
+        let (frequency, kept, spread) =
+            reject_outliers(vec![1e9, 1.0002e9, 0.9999e9, 1.5e9]).unwrap();
+        assert_eq!(kept, 3);
+        assert!((frequency - (1e9 + 1.0002e9 + 0.9999e9) / 3.0).abs() < 1.0);
+        assert!((spread - 300.0).abs() < 1.0);
+
+        let timer = HighResTimer::new(TimestampSource::TscClock);
+        let calibration = timer.calibration().unwrap();
+        assert!(calibration.frequency > 0);
+        assert!(HighResTimer::new(TimestampSource::MonotonicClock)
+            .calibration()
+            .is_none());
+
+        let reference = timer.calibration.lock().unwrap().1;
+        let after = |ticks: f64| TscReference {
+            tsc: reference.tsc + ticks as u64,
+            at: reference.at + Duration::from_secs(1),
+        };
+        assert!(timer
+            .revalidate_at(TscReference {
+                at: reference.at + Duration::from_millis(10),
+                ..reference
+            })
+            .is_err());
+
+        // Within tolerance the frequency stays, beyond it the measured one is adopted
+        let base = calibration.frequency as f64;
+        let drift = timer.revalidate_at(after(base * 1.00005)).unwrap();
+        assert!((drift - 50.0).abs() < 1.0);
+        assert_eq!(timer.tsc_frequency(), calibration.frequency);
+        let at_switch = after(base * 1.001);
+        let before = timer.tsc_to_nanos(at_switch.tsc);
+        let drift = timer.revalidate_at(at_switch).unwrap();
+        assert!((drift - 1000.0).abs() < 1.0);
+        // Timestamps carry on from where the old frequency left them
+        assert!(timer.tsc_to_nanos(at_switch.tsc).abs_diff(before) <= 1);
+        let second = timer.tsc_to_nanos(at_switch.tsc + (base * 1.001) as u64);
+        assert!((second - before).abs_diff(1_000_000_000) <= 1);
+        let recalibrated = timer.calibration().unwrap();
+        assert_eq!(recalibrated.recalibrations, 1);
+        assert_eq!(recalibrated.method, CalibrationMethod::Measured);
+        assert_eq!(timer.tsc_frequency(), (base * 1.001) as u64);
+    }
+
     #[test]
     fn test_latency_tracker() {
         let mut tracker = LatencyTracker::new(100);
@@ -500,4 +1143,46 @@ mod tests {
 
         assert_eq!(counter.count(), 100);
     }
+
+    #[test]
+    fn test_offset_estimator() {
+        let mut estimator = OffsetEstimator::new(3);
+        assert_eq!(estimator.to_system(0), None);
+
+        // Hardware clock 1s ahead and 100ppm fast; the slow exchange is ignored
+        estimator.add_sample(999_900, 1_001_000_100, 1_000_100);
+        estimator.add_sample(2_000_000_000, 3_000_300_000, 2_000_600_000);
+        estimator.add_sample(10_999_999_900, 12_001_100_000, 11_000_000_100);
+        assert_eq!(estimator.best().unwrap().system_ns, 1_000_000);
+        assert_eq!(estimator.offset_ns(), Some(1_000_000_100));
+        assert!((estimator.rate() - 1.0001).abs() < 1e-9);
+        let system = estimator.to_system(11_001_000_000).unwrap();
+        assert!(system.abs_diff(10_000_000_000) < 1_000);
+
+        // The window drops the oldest sample
+        estimator.add_sample(12_000_000_000, 13_001_200_000, 12_000_000_100);
+        assert_eq!(estimator.len(), 3);
+        assert_eq!(estimator.best().unwrap().delay_ns, 100);
+    }
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new(Duration::from_nanos(10), 4, 1_000);
+        wheel.schedule(1_015, "a");
+        let cancelled = wheel.schedule(1_020, "b");
+        // Two revolutions out, shares a slot with "a"
+        wheel.schedule(1_095, "c");
+        assert_eq!(wheel.len(), 3);
+
+        assert!(wheel.advance(1_010).is_empty());
+        assert_eq!(wheel.cancel(cancelled), Some("b"));
+        assert_eq!(wheel.advance(1_020), vec!["a"]);
+        assert!(wheel.advance(1_090).is_empty());
+        assert_eq!(wheel.advance(5_000), vec!["c"]);
+        assert!(wheel.is_empty());
+
+        // Past deadlines fire on the next tick
+        wheel.schedule(0, "late");
+        assert_eq!(wheel.advance(5_010), vec!["late"]);
+    }
 }
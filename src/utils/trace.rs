@@ -0,0 +1,387 @@
+//! Sampled packet tracing
+//!
+//! A sampled subset of received mbufs gets a trace ID. Each stage the packet
+//! passes stamps its trace record, and the time since the previous stage is
+//! added to that stage's latency histogram. Records live in a fixed ring, so
+//! the most recent traces can be dumped individually.
+
+use crate::memory::Mbuf;
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Trace records kept by the global tracer
+pub const DEFAULT_TRACE_CAPACITY: usize = 4096;
+
+/// Number of log2 histogram buckets
+const HISTOGRAM_BUCKETS: usize = 40;
+
+/// Stages a traced packet passes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceStage {
+    /// Frame captured by the driver
+    Capture,
+    /// Headers classified
+    Classify,
+    /// Queued on a socket
+    Enqueue,
+    /// Popped by the application
+    SocketPop,
+    /// Response transmitted
+    Tx,
+}
+
+impl TraceStage {
+    /// Number of stages
+    pub const COUNT: usize = 5;
+
+    /// All stages in pipeline order
+    pub const ALL: [TraceStage; Self::COUNT] = [
+        TraceStage::Capture,
+        TraceStage::Classify,
+        TraceStage::Enqueue,
+        TraceStage::SocketPop,
+        TraceStage::Tx,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Short name for reports
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TraceStage::Capture => "capture",
+            TraceStage::Classify => "classify",
+            TraceStage::Enqueue => "enqueue",
+            TraceStage::SocketPop => "socket_pop",
+            TraceStage::Tx => "tx",
+        }
+    }
+}
+
+/// Stage timestamps of one traced packet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub trace_id: u32,
+    pub queue_id: u16,
+    /// Packet color, set once the packet reaches its socket
+    pub color: u8,
+    /// Nanoseconds since the Unix epoch, by [`TraceStage`] order
+    pub timestamps: [Option<u64>; TraceStage::COUNT],
+}
+
+impl TraceRecord {
+    /// Time from the previous recorded stage to `stage`
+    pub fn hop_latency(&self, stage: TraceStage) -> Option<u64> {
+        let at = self.timestamps[stage.index()]?;
+        let previous = self.timestamps[..stage.index()]
+            .iter()
+            .rev()
+            .find_map(|timestamp| *timestamp)?;
+        Some(at.saturating_sub(previous))
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trace {} (queue {})", self.trace_id, self.queue_id)?;
+        if self.color != 0 {
+            write!(f, " color {}", self.color)?;
+        }
+        for stage in TraceStage::ALL {
+            if let Some(hop) = self.hop_latency(stage) {
+                write!(f, " {}=+{}ns", stage.as_str(), hop)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Log2-bucketed latency histogram
+#[derive(Debug)]
+struct StageHistogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl StageHistogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, nanos: u64) {
+        let bucket = (u64::BITS - nanos.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum_ns: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a stage histogram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Bucket `i` counts latencies below `2^i` ns
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_ns: u64,
+}
+
+impl HistogramSnapshot {
+    /// Mean latency in nanoseconds
+    pub fn mean_ns(&self) -> u64 {
+        self.sum_ns.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Upper bound of the bucket holding the `p`-th percentile (0-100)
+    pub fn percentile_ns(&self, p: f64) -> u64 {
+        let target = ((self.count as f64) * p / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return 1u64 << bucket;
+            }
+        }
+        0
+    }
+}
+
+/// Sampled per-stage packet tracer
+pub struct PacketTracer {
+    /// Trace one packet in this many, 0 disables tracing
+    sample_rate: AtomicU32,
+    /// Packets seen by [`PacketTracer::begin`]
+    seen: AtomicU32,
+    /// Next trace ID
+    next_id: AtomicU32,
+    /// Ring of trace records indexed by trace ID
+    records: Mutex<Vec<TraceRecord>>,
+    /// Hop latency histograms by stage
+    histograms: [StageHistogram; TraceStage::COUNT],
+}
+
+impl PacketTracer {
+    /// Create a disabled tracer keeping `capacity` records
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sample_rate: AtomicU32::new(0),
+            seen: AtomicU32::new(0),
+            next_id: AtomicU32::new(1),
+            records: Mutex::new(vec![TraceRecord::default(); capacity.max(1)]),
+            histograms: std::array::from_fn(|_| StageHistogram::new()),
+        }
+    }
+
+    /// Tracer used by the RX, socket and TX paths
+    pub fn global() -> &'static PacketTracer {
+        static TRACER: OnceLock<PacketTracer> = OnceLock::new();
+        TRACER.get_or_init(|| PacketTracer::new(DEFAULT_TRACE_CAPACITY))
+    }
+
+    /// Trace one packet in `rate`; 0 disables tracing
+    pub fn set_sample_rate(&self, rate: u32) {
+        self.sample_rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Current sample rate
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Start tracing a received mbuf if it is sampled
+    ///
+    /// `captured_ns` is the capture timestamp and becomes the first stage.
+    pub fn begin(&self, mbuf: &mut Mbuf, captured_ns: u64) -> Option<u32> {
+        let rate = self.sample_rate.load(Ordering::Relaxed);
+        if rate == 0
+            || !self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(rate)
+        {
+            return None;
+        }
+
+        let mut trace_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if trace_id == 0 {
+            trace_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut record = TraceRecord {
+            trace_id,
+            queue_id: mbuf.queue_id,
+            ..Default::default()
+        };
+        record.timestamps[TraceStage::Capture.index()] = Some(captured_ns);
+
+        let mut records = self.records.lock();
+        let slot = trace_id as usize % records.len();
+        records[slot] = record;
+
+        mbuf.trace_id = trace_id;
+        Some(trace_id)
+    }
+
+    /// Stamp a stage of a traced packet; untraced IDs (0) are ignored
+    #[inline]
+    pub fn record(&self, trace_id: u32, stage: TraceStage) {
+        if trace_id != 0 {
+            self.record_at(trace_id, stage, now_ns());
+        }
+    }
+
+    fn record_at(&self, trace_id: u32, stage: TraceStage, at_ns: u64) {
+        let hop = {
+            let mut records = self.records.lock();
+            let slot = trace_id as usize % records.len();
+            let record = &mut records[slot];
+            if record.trace_id != trace_id {
+                // Overwritten by a newer trace
+                return;
+            }
+            record.timestamps[stage.index()] = Some(at_ns);
+            record.hop_latency(stage)
+        };
+
+        if let Some(hop) = hop {
+            self.histograms[stage.index()].record(hop);
+        }
+    }
+
+    /// Label a traced packet with its color; untraced IDs (0) are ignored
+    pub fn set_color(&self, trace_id: u32, color: u8) {
+        if trace_id == 0 {
+            return;
+        }
+        let mut records = self.records.lock();
+        let slot = trace_id as usize % records.len();
+        if records[slot].trace_id == trace_id {
+            records[slot].color = color;
+        }
+    }
+
+    /// Get the record of a trace if it is still in the ring
+    pub fn dump(&self, trace_id: u32) -> Option<TraceRecord> {
+        let records = self.records.lock();
+        let record = records[trace_id as usize % records.len()];
+        (trace_id != 0 && record.trace_id == trace_id).then_some(record)
+    }
+
+    /// Most recent traces, newest first
+    pub fn recent(&self, count: usize) -> Vec<TraceRecord> {
+        let mut records: Vec<TraceRecord> = self
+            .records
+            .lock()
+            .iter()
+            .filter(|record| record.trace_id != 0)
+            .copied()
+            .collect();
+        records.sort_by_key(|record| std::cmp::Reverse(record.trace_id));
+        records.truncate(count);
+        records
+    }
+
+    /// Most recent traces of one color, newest first
+    pub fn recent_colored(&self, color: u8, count: usize) -> Vec<TraceRecord> {
+        let mut records = self.recent(usize::MAX);
+        records.retain(|record| record.color == color);
+        records.truncate(count);
+        records
+    }
+
+    /// Hop latency histogram of a stage
+    pub fn histogram(&self, stage: TraceStage) -> HistogramSnapshot {
+        self.histograms[stage.index()].snapshot()
+    }
+}
+
+/// Current wall-clock time in nanoseconds, the clock of capture timestamps
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_and_hop_latencies() {
+        let tracer = PacketTracer::new(8);
+        let mut mbuf = Mbuf::new(std::ptr::null_mut(), 0);
+        assert_eq!(tracer.begin(&mut mbuf, 0), None);
+
+        tracer.set_sample_rate(2);
+        let id = tracer.begin(&mut mbuf, 1_000).unwrap();
+        assert_eq!(mbuf.trace_id, id);
+        assert_eq!(
+            tracer.begin(&mut Mbuf::new(std::ptr::null_mut(), 0), 0),
+            None
+        );
+
+        tracer.record_at(id, TraceStage::Classify, 1_300);
+        tracer.record_at(id, TraceStage::SocketPop, 2_300);
+
+        let record = tracer.dump(id).unwrap();
+        assert_eq!(record.hop_latency(TraceStage::Classify), Some(300));
+        // Skipped stages are bridged to the previous recorded one
+        assert_eq!(record.hop_latency(TraceStage::SocketPop), Some(1_000));
+        assert_eq!(record.hop_latency(TraceStage::Enqueue), None);
+        assert!(record.to_string().contains("socket_pop=+1000ns"));
+
+        let histogram = tracer.histogram(TraceStage::Classify);
+        assert_eq!(histogram.count, 1);
+        assert_eq!(histogram.mean_ns(), 300);
+        assert_eq!(histogram.percentile_ns(99.0), 512);
+    }
+
+    #[test]
+    fn test_ring_overwrites_old_traces() {
+        let tracer = PacketTracer::new(2);
+        tracer.set_sample_rate(1);
+
+        let ids: Vec<u32> = (0..3)
+            .map(|_| {
+                tracer
+                    .begin(&mut Mbuf::new(std::ptr::null_mut(), 0), 0)
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(tracer.dump(ids[0]).is_none());
+        tracer.record_at(ids[0], TraceStage::Classify, 10);
+        assert_eq!(tracer.histogram(TraceStage::Classify).count, 0);
+
+        let recent = tracer.recent(5);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].trace_id, ids[2]);
+
+        tracer.set_color(ids[1], 4);
+        let colored = tracer.recent_colored(4, 5);
+        assert_eq!(colored.len(), 1);
+        assert_eq!(colored[0].trace_id, ids[1]);
+        assert!(colored[0].to_string().contains("color 4"));
+    }
+}
@@ -0,0 +1,122 @@
+//! Warm-up of pools and queues on start
+//!
+//! A freshly created pool is mapped but not yet backed: its buffers, like
+//! the slots of socket queues, are faulted in one page at a time as the
+//! first packets touch them, so the first packets after start take far
+//! longer than the rest. With [`crate::Config::warmup`] set,
+//! [`crate::Xpdk::start`] faults in every pool page and queue slot up front,
+//! and with [`WarmupConfig::self_test`] passes one datagram through the
+//! receive path to a loopback socket, see [`crate::UdpStack::self_test`].
+//!
+//! The [`WarmupReport`] says what was touched and how long it took; it is
+//! logged on start and listed in [`crate::Xpdk::startup_report`].
+
+use super::preflight::PreflightCheck;
+use crate::memory::MbufPool;
+use crate::udp::UdpStack;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Warm-up settings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarmupConfig {
+    /// Pass a datagram through the receive path to a loopback socket
+    pub self_test: bool,
+}
+
+/// What a warm-up touched
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmupReport {
+    pub pools: usize,
+    pub pool_bytes: usize,
+    /// Socket receive queues faulted in
+    pub queues: usize,
+    pub queue_bytes: usize,
+    /// Outcome of the self-test, if run
+    pub self_test: Option<Result<(), String>>,
+    pub duration: Duration,
+}
+
+impl WarmupReport {
+    /// Fault in `pools` and the socket queues of `stack`, then self-test if configured
+    pub fn run<'a>(
+        config: &WarmupConfig,
+        pools: impl IntoIterator<Item = &'a MbufPool>,
+        stack: &mut UdpStack,
+    ) -> Self {
+        let start = Instant::now();
+        let mut report = Self::default();
+        for pool in pools {
+            report.pools += 1;
+            report.pool_bytes += pool.prefault();
+        }
+        (report.queues, report.queue_bytes) = stack.prefault_queues();
+        if config.self_test {
+            report.self_test = Some(stack.self_test().map_err(|e| e.to_string()));
+        }
+        report.duration = start.elapsed();
+        report
+    }
+
+    /// Entry for the startup report, a warning if the self-test failed
+    pub fn check(&self) -> PreflightCheck {
+        match &self.self_test {
+            Some(Err(e)) => PreflightCheck::warn(
+                "warm-up",
+                format!("{}, self-test failed: {}", self, e),
+                "check the receive pool and that no filter drops loopback traffic",
+            ),
+            _ => PreflightCheck::pass("warm-up", self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for WarmupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pools ({} KiB) and {} queues ({} KiB) in {:?}",
+            self.pools,
+            self.pool_bytes / 1024,
+            self.queues,
+            self.queue_bytes / 1024,
+            self.duration
+        )?;
+        if let Some(Ok(())) = self.self_test {
+            write!(f, ", self-test passed")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::preflight::CheckStatus;
+    use crate::Config;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_warmup_touches_pools_and_queues() {
+        let pool = Arc::new(MbufPool::new("warm".to_string(), 16, 2048).unwrap());
+        let mut stack = UdpStack::new(&Config::default()).unwrap();
+        stack.set_rx_pool(pool.clone());
+        stack
+            .create_socket("0.0.0.0:7000".parse().unwrap())
+            .unwrap();
+        stack.start().unwrap();
+
+        let config = WarmupConfig { self_test: true };
+        let report = WarmupReport::run(&config, [pool.as_ref()], &mut stack);
+        assert_eq!(report.pools, 1);
+        assert_eq!(report.pool_bytes, MbufPool::memory_size(16, 2048));
+        assert_eq!(report.queues, 1);
+        assert_eq!(report.queue_bytes, 1024 * std::mem::size_of::<usize>());
+        assert_eq!(report.self_test, Some(Ok(())));
+        assert_eq!(report.check().status, CheckStatus::Pass);
+        // The self-test socket is closed again, and was never counted
+        assert_eq!(stack.local_ports().len(), 1);
+        assert_eq!(stack.stats().total_packets_received, 0);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+}
@@ -0,0 +1,272 @@
+//! Protocol conformance against the kernel stack
+//!
+//! Each case creates a veth pair, runs XPDK on one end and a
+//! `std::net::UdpSocket` on the other, and checks that the kernel accepts
+//! what XPDK sends and the other way round: unicast datagrams with their
+//! checksums verified, a datagram fragmented by XPDK and reassembled by the
+//! kernel, and broadcast and multicast in both directions. XPDK does not
+//! reassemble received fragments, so fragmentation is only covered from
+//! XPDK to the kernel. XPDK has no ARP either, so the kernel end gets a
+//! static neighbor entry for the XPDK address.
+//!
+//! Run as root with `cargo test --features conformance --test conformance`;
+//! without root or `ip` the cases are skipped.
+#![cfg(feature = "conformance")]
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use xpdk::dispatch::PollBudget;
+use xpdk::{Config, Xpdk};
+
+const XPDK_IF: &str = "xpdk-c0";
+const KERNEL_IF: &str = "xpdk-c1";
+const XPDK_IP: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 2);
+const KERNEL_IP: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 1);
+const BROADCAST_IP: Ipv4Addr = Ipv4Addr::new(10, 77, 0, 255);
+const GROUP_IP: Ipv4Addr = Ipv4Addr::new(239, 1, 2, 3);
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+const GROUP_MAC: [u8; 6] = [0x01, 0x00, 0x5e, 0x01, 0x02, 0x03];
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The veth names are fixed, so cases run one at a time
+static VETH: Mutex<()> = Mutex::new(());
+
+fn ip(args: &[&str]) -> bool {
+    Command::new("ip")
+        .args(args)
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn mac_of(interface: &str) -> [u8; 6] {
+    let text = std::fs::read_to_string(format!("/sys/class/net/{}/address", interface)).unwrap();
+    let mut mac = [0u8; 6];
+    for (byte, part) in mac.iter_mut().zip(text.trim().split(':')) {
+        *byte = u8::from_str_radix(part, 16).unwrap();
+    }
+    mac
+}
+
+/// UDP checksum errors counted by the kernel
+fn kernel_csum_errors() -> u64 {
+    let snmp = std::fs::read_to_string("/proc/net/snmp").unwrap();
+    let mut lines = snmp.lines().filter(|line| line.starts_with("Udp:"));
+    let (names, values) = (lines.next().unwrap(), lines.next().unwrap());
+    names
+        .split_whitespace()
+        .zip(values.split_whitespace())
+        .find(|(name, _)| *name == "InCsumErrors")
+        .map_or(0, |(_, value)| value.parse().unwrap())
+}
+
+/// Veth pair removed on drop
+struct VethPair {
+    xpdk_mac: [u8; 6],
+    kernel_mac: [u8; 6],
+}
+
+impl VethPair {
+    fn create() -> Option<Self> {
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skipping: conformance cases need root");
+            return None;
+        }
+        ip(&["link", "del", XPDK_IF]);
+        if !ip(&[
+            "link", "add", XPDK_IF, "type", "veth", "peer", "name", KERNEL_IF,
+        ]) {
+            eprintln!("skipping: cannot create a veth pair");
+            return None;
+        }
+        let pair = Self {
+            xpdk_mac: mac_of(XPDK_IF),
+            kernel_mac: mac_of(KERNEL_IF),
+        };
+        let xpdk_mac = pair
+            .xpdk_mac
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        let kernel_addr = format!("{}/24", KERNEL_IP);
+        let xpdk_ip = XPDK_IP.to_string();
+        for args in [
+            &["addr", "add", &kernel_addr, "dev", KERNEL_IF][..],
+            &["link", "set", XPDK_IF, "up"],
+            &["link", "set", KERNEL_IF, "up"],
+            &[
+                "neigh", "replace", &xpdk_ip, "lladdr", &xpdk_mac, "dev", KERNEL_IF,
+            ],
+            &["route", "replace", "239.0.0.0/8", "dev", KERNEL_IF],
+        ] {
+            assert!(ip(args), "ip {:?} failed", args);
+        }
+        // Checksums left to the veth offload would reach XPDK unfilled
+        let _ = Command::new("ethtool")
+            .args(["-K", KERNEL_IF, "tx", "off"])
+            .status();
+        Some(pair)
+    }
+}
+
+impl Drop for VethPair {
+    fn drop(&mut self) {
+        ip(&["link", "del", XPDK_IF]);
+    }
+}
+
+fn start_xpdk() -> Xpdk {
+    let config = Config::builder()
+        .with_interface(XPDK_IF)
+        .with_queues(1, 1)
+        .with_pools(1, 1024)
+        .with_hugepages(false)
+        .build()
+        .unwrap();
+    let mut xpdk = Xpdk::new(config).unwrap();
+    xpdk.start().unwrap();
+    xpdk
+}
+
+/// Open an XPDK socket on `port` sending to `dst_mac`
+fn xpdk_socket(xpdk: &mut Xpdk, pair: &VethPair, port: u16, dst_mac: [u8; 6]) -> u16 {
+    let tx_queue = xpdk.pmd().tx_queues().next().unwrap().clone();
+    let stack = xpdk.udp_stack_mut();
+    let id = stack
+        .create_socket(SocketAddr::V4(SocketAddrV4::new(XPDK_IP, port)))
+        .unwrap();
+    let socket = stack.get_socket_mut(id).unwrap();
+    socket.bind_tx_queue(tx_queue);
+    socket.set_mac_addresses(pair.xpdk_mac, dst_mac);
+    id
+}
+
+fn kernel_socket(ip: Ipv4Addr, port: u16) -> UdpSocket {
+    let socket = UdpSocket::bind((ip, port)).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    socket
+}
+
+/// Poll until the XPDK socket `id` receives a datagram
+fn xpdk_recv(xpdk: &mut Xpdk, id: u16) -> (Vec<u8>, SocketAddr, bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while Instant::now() < deadline {
+        xpdk.poll_once(&PollBudget::new(64)).unwrap();
+        if let Ok(packet) = xpdk.udp_stack().get_socket(id).unwrap().recv() {
+            return (
+                packet.payload().to_vec(),
+                packet.src_addr(),
+                packet.checksum_bad(),
+            );
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic!("XPDK socket {} received nothing", id);
+}
+
+fn kernel_recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+    let mut buf = [0u8; 65536];
+    let (len, from) = socket.recv_from(&mut buf).expect("kernel received nothing");
+    (buf[..len].to_vec(), from)
+}
+
+#[test]
+fn test_unicast_checksums() {
+    let _guard = VETH.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(pair) = VethPair::create() else {
+        return;
+    };
+    let mut xpdk = start_xpdk();
+    let id = xpdk_socket(&mut xpdk, &pair, 7001, pair.kernel_mac);
+    let peer = kernel_socket(KERNEL_IP, 7002);
+
+    // The kernel drops datagrams with a bad checksum and counts them
+    let errors = kernel_csum_errors();
+    let socket = xpdk.udp_stack().get_socket(id).unwrap();
+    for len in [0, 1, 17, 1400] {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        socket.send(peer.local_addr().unwrap(), &payload).unwrap();
+        let (received, from) = kernel_recv(&peer);
+        assert_eq!(received, payload);
+        assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(XPDK_IP, 7001)));
+    }
+    assert_eq!(kernel_csum_errors(), errors);
+
+    // XPDK verifies what the kernel computed
+    for len in [0, 1, 17, 1400] {
+        let payload: Vec<u8> = (0..len).map(|i| !(i as u8)).collect();
+        peer.send_to(&payload, (XPDK_IP, 7001)).unwrap();
+        let (received, from, checksum_bad) = xpdk_recv(&mut xpdk, id);
+        assert_eq!(received, payload);
+        assert_eq!(from, peer.local_addr().unwrap());
+        assert!(!checksum_bad);
+    }
+}
+
+#[test]
+fn test_fragments_reassembled_by_kernel() {
+    let _guard = VETH.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(pair) = VethPair::create() else {
+        return;
+    };
+    let mut xpdk = start_xpdk();
+    let id = xpdk_socket(&mut xpdk, &pair, 7011, pair.kernel_mac);
+    let peer = kernel_socket(KERNEL_IP, 7012);
+
+    let socket = xpdk.udp_stack().get_socket(id).unwrap();
+    assert!(socket.path_mtu(peer.local_addr().unwrap()) < 1900);
+    let payload: Vec<u8> = (0..1900).map(|i| (i * 7) as u8).collect();
+    socket.send(peer.local_addr().unwrap(), &payload).unwrap();
+    assert_eq!(kernel_recv(&peer).0, payload);
+}
+
+#[test]
+fn test_broadcast() {
+    let _guard = VETH.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(pair) = VethPair::create() else {
+        return;
+    };
+    let mut xpdk = start_xpdk();
+    let id = xpdk_socket(&mut xpdk, &pair, 7021, BROADCAST_MAC);
+    let peer = kernel_socket(Ipv4Addr::UNSPECIFIED, 7022);
+    peer.set_broadcast(true).unwrap();
+
+    let socket = xpdk.udp_stack().get_socket(id).unwrap();
+    socket
+        .send(
+            SocketAddr::V4(SocketAddrV4::new(BROADCAST_IP, 7022)),
+            b"to all",
+        )
+        .unwrap();
+    assert_eq!(kernel_recv(&peer).0, b"to all");
+
+    peer.send_to(b"from kernel", (BROADCAST_IP, 7021)).unwrap();
+    assert_eq!(xpdk_recv(&mut xpdk, id).0, b"from kernel");
+}
+
+#[test]
+fn test_multicast() {
+    let _guard = VETH.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(pair) = VethPair::create() else {
+        return;
+    };
+    let mut xpdk = start_xpdk();
+    let id = xpdk_socket(&mut xpdk, &pair, 7031, GROUP_MAC);
+    let peer = kernel_socket(Ipv4Addr::UNSPECIFIED, 7032);
+    peer.join_multicast_v4(&GROUP_IP, &KERNEL_IP).unwrap();
+    peer.set_multicast_loop_v4(false).unwrap();
+
+    let socket = xpdk.udp_stack().get_socket(id).unwrap();
+    socket
+        .send(
+            SocketAddr::V4(SocketAddrV4::new(GROUP_IP, 7032)),
+            b"to group",
+        )
+        .unwrap();
+    assert_eq!(kernel_recv(&peer).0, b"to group");
+
+    peer.send_to(b"group member", (GROUP_IP, 7031)).unwrap();
+    assert_eq!(xpdk_recv(&mut xpdk, id).0, b"group member");
+}